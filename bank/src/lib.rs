@@ -0,0 +1,705 @@
+pub mod node {
+    use maelstrom::error::{
+        KEY_DOES_NOT_EXIST, MALFORMED_REQUEST, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE,
+        TIMEOUT,
+    };
+    use raft::{Outbox, Raft, RaftMessage, Rejected, StateMachine};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{BTreeMap, HashMap};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// A bank: accounts whose balances clients move money between with transfers, and read
+    /// all at once. Every node starts with the same accounts, and every read and transfer
+    /// goes through a Raft log, so each takes effect at a single point between its request
+    /// and its reply, and every read sees balances between whole transfers.
+    ///
+    /// A transfer only ever moves money, and is refused if it would overdraw the account
+    /// it's from, so every read, however the network misbehaves, should add up to the total
+    /// the bank started with and find no balance below zero. That's the invariant a checker
+    /// holds the history to. Nodes that aren't leading forward requests to the leader and
+    /// relay its replies, and nothing is served while a majority is unreachable.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        node_ids: Vec<String>,
+        raft: Option<Raft<Bank>>, // Set at init, once we know the cluster
+        forwards: HashMap<u64, Waiting>, // Requests sent to the leader, by msg_id
+        proposals: HashMap<u64, Proposed>, // Requests we put in the log, by index
+    }
+
+    /// A request in the log, waiting on it to be applied.
+    struct Proposed {
+        term: u64, // The term it went in with, which the applied entry must have too
+        waiting: Waiting,
+    }
+
+    /// A client waiting on a reply to `msg_id`.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on answering
+    }
+
+    /// The balances every node applies the log to.
+    struct Bank {
+        balances: BTreeMap<u64, i64>,
+    }
+
+    impl Bank {
+        /// Accounts 0 to `accounts - 1`, each holding `initial_balance`.
+        fn new(accounts: u64, initial_balance: i64) -> Self {
+            Bank {
+                balances: (0..accounts)
+                    .map(|account| (account, initial_balance))
+                    .collect(),
+            }
+        }
+    }
+
+    /// An operation as it goes in the log.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "op")]
+    enum Op {
+        Read,
+        Transfer { from: u64, to: u64, amount: i64 },
+    }
+
+    /// Tunables, read from `BANK_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// How long a request waits on being committed, or on the leader to answer it if we
+        /// forwarded it, before we give up.
+        pub request_timeout: Duration,
+        /// Time between ticks, which drive Raft's timers and time out requests.
+        pub tick_interval: Duration,
+        /// How many accounts there are, numbered from 0. Every node needs the same number.
+        pub accounts: u64,
+        /// What each account starts with. Every node needs the same amount.
+        pub initial_balance: i64,
+        pub raft: raft::Config,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                request_timeout: Duration::from_millis(1000),
+                tick_interval: Duration::from_millis(50),
+                accounts: 5,
+                initial_balance: 20,
+                raft: raft::Config::default(),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            let millis = |name, default: Duration| {
+                Duration::from_millis(env_or(name, default.as_millis() as u64))
+            };
+            Config {
+                request_timeout: millis("BANK_REQUEST_TIMEOUT_MS", default.request_timeout),
+                tick_interval: millis("BANK_TICK_INTERVAL_MS", default.tick_interval),
+                accounts: env_or("BANK_ACCOUNTS", default.accounts),
+                initial_balance: env_or("BANK_INITIAL_BALANCE", default.initial_balance),
+                raft: raft::Config {
+                    election_timeout: millis(
+                        "BANK_ELECTION_TIMEOUT_MS",
+                        default.raft.election_timeout,
+                    ),
+                    heartbeat_interval: millis(
+                        "BANK_HEARTBEAT_INTERVAL_MS",
+                        default.raft.heartbeat_interval,
+                    ),
+                    max_batch: env_or("BANK_MAX_BATCH", default.raft.max_batch),
+                    snapshot_threshold: env_or(
+                        "BANK_SNAPSHOT_THRESHOLD",
+                        default.raft.snapshot_threshold,
+                    ),
+                },
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+        },
+        /// Every account's balance, at one point in the log.
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: BTreeMap<u64, i64>,
+        },
+        /// Moves `amount`, which must be positive, from account `from` to account `to`.
+        Transfer {
+            msg_id: u64,
+            from: u64,
+            to: u64,
+            amount: i64,
+        },
+        TransferOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        #[serde(untagged)]
+        Raft(RaftMessage<Op, BTreeMap<u64, i64>>),
+    }
+
+    impl Body {
+        /// The id of the request this replies to, if it's a reply.
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::InitOk { in_reply_to, .. }
+                | Body::ReadOk { in_reply_to, .. }
+                | Body::TransferOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::Init { msg_id, .. }
+                | Body::InitOk { msg_id, .. }
+                | Body::Read { msg_id, .. }
+                | Body::ReadOk { msg_id, .. }
+                | Body::Transfer { msg_id, .. }
+                | Body::TransferOk { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+
+        /// The request as an operation for the log.
+        fn op(self) -> Option<Op> {
+            Some(match self {
+                Body::Read { .. } => Op::Read,
+                Body::Transfer {
+                    from, to, amount, ..
+                } => Op::Transfer { from, to, amount },
+                _ => return None,
+            })
+        }
+    }
+
+    impl StateMachine for Bank {
+        type Command = Op;
+        /// The reply, with its ids still to fill in.
+        type Output = Body;
+        type Snapshot = BTreeMap<u64, i64>;
+
+        fn apply(&mut self, op: &Op) -> Body {
+            let (from, to, amount) = match *op {
+                Op::Read => {
+                    return Body::ReadOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        value: self.balances.clone(),
+                    }
+                }
+                Op::Transfer { from, to, amount } => (from, to, amount),
+            };
+            let error = |code, text| Body::Error {
+                in_reply_to: 0,
+                code,
+                text,
+            };
+            if amount <= 0 {
+                let text = format!("can't transfer {}", amount);
+                return error(MALFORMED_REQUEST, text);
+            }
+            let missing = [from, to]
+                .into_iter()
+                .find(|account| !self.balances.contains_key(account));
+            if let Some(account) = missing {
+                return error(KEY_DOES_NOT_EXIST, format!("no account {}", account));
+            }
+            let balance = self.balances[&from];
+            if balance < amount {
+                let text = format!("account {} only has {}", from, balance);
+                return error(PRECONDITION_FAILED, text);
+            }
+            *self.balances.entry(from).or_default() -= amount;
+            *self.balances.entry(to).or_default() += amount;
+            Body::TransferOk {
+                msg_id: 0,
+                in_reply_to: 0,
+            }
+        }
+
+        fn snapshot(&self) -> BTreeMap<u64, i64> {
+            self.balances.clone()
+        }
+
+        fn restore(&mut self, snapshot: BTreeMap<u64, i64>) {
+            self.balances = snapshot;
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                node_ids: Vec::new(),
+                raft: None,
+                forwards: HashMap::new(),
+                proposals: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            let raft = self.raft.as_ref().map(|raft| {
+                json!({
+                    "term": raft.term(),
+                    "leader": raft.leader(),
+                    "nodes": raft.nodes(),
+                    "last_index": raft.last_index(),
+                    "commit_index": raft.commit_index(),
+                })
+            });
+            json!({
+                "id": self.id,
+                "raft": raft,
+                "forwards": self.forwards.len(),
+                "proposals": self.proposals.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Drives Raft's timers, and gives up on requests that have waited too long.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            let mut outbox = Vec::new();
+            if let Some(raft) = &mut self.raft {
+                raft.tick(&mut outbox);
+            }
+            self.send_raft(outbox, &mut messages);
+
+            let now = Instant::now();
+            let expired_forwards: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            let expired_proposals: Vec<u64> = self
+                .proposals
+                .iter()
+                .filter(|(_, proposed)| proposed.waiting.deadline <= now)
+                .map(|(index, _)| *index)
+                .collect();
+            let mut expired: Vec<Waiting> = expired_forwards
+                .iter()
+                .filter_map(|msg_id| self.forwards.remove(msg_id))
+                .chain(
+                    expired_proposals
+                        .iter()
+                        .filter_map(|index| self.proposals.remove(index))
+                        .map(|proposed| proposed.waiting),
+                )
+                .collect();
+            expired.sort_by_key(|waiting| waiting.msg_id);
+            for waiting in expired {
+                // It may yet be committed, or have been and only the reply was lost, so this
+                // mustn't claim the transfer failed
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code: TIMEOUT,
+                    text: "timed out waiting on the log".to_string(),
+                };
+                self.reply(waiting, error, &mut messages);
+            }
+            messages
+        }
+
+        /// Wraps Raft's messages for the wire, and answers the clients whose requests it
+        /// has applied since.
+        fn send_raft(&mut self, outbox: Outbox<Bank>, messages: &mut Vec<Message>) {
+            for (dest, message) in outbox {
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest,
+                    body: Body::Raft(message),
+                });
+            }
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            for applied in raft.take_applied() {
+                let Some(proposed) = self.proposals.remove(&applied.index) else {
+                    continue;
+                };
+                let reply = match applied.output {
+                    Some(reply) if proposed.term == applied.term => reply,
+                    // Another leader's entry took its place, so it never will take effect
+                    _ => Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "lost leadership before committing".to_string(),
+                    },
+                };
+                self.reply(proposed.waiting, reply, messages);
+            }
+        }
+
+        fn reply(&mut self, waiting: Waiting, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = waiting.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: waiting.client,
+                body,
+            });
+        }
+
+        /// Proposes a client's request if we're leading, or sends it on to the leader with an
+        /// id of our own, so we can tell which client its reply is for. Requests that already
+        /// came from another node aren't forwarded again, so they can't go round in circles.
+        fn request(&mut self, src: &str, mut body: Body, messages: &mut Vec<Message>) {
+            let Some(msg_id) = body.msg_id().copied() else {
+                return;
+            };
+            let waiting = Waiting {
+                client: src.to_string(),
+                msg_id,
+                deadline: Instant::now() + self.config.request_timeout,
+            };
+            let from_node = self.node_ids.iter().any(|node| node == src);
+            let (Some(raft), Some(op)) = (&mut self.raft, body.clone().op()) else {
+                return;
+            };
+            let mut outbox = Vec::new();
+            match raft.propose(op, &mut outbox) {
+                Ok(proposal) => {
+                    let proposed = Proposed {
+                        term: proposal.term,
+                        waiting,
+                    };
+                    self.proposals.insert(proposal.index, proposed);
+                }
+                Err(Rejected::NotLeader(Some(leader))) if !from_node => {
+                    let forward_id = self.next_msg_id();
+                    if let Some(msg_id) = body.msg_id() {
+                        *msg_id = forward_id;
+                    }
+                    self.forwards.insert(forward_id, waiting);
+                    messages.push(Message {
+                        src: self.id.clone(),
+                        dest: leader,
+                        body,
+                    });
+                }
+                Err(_) => {
+                    let error = Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "no leader to serve this".to_string(),
+                    };
+                    self.reply(waiting, error, messages);
+                }
+            }
+            self.send_raft(outbox, messages);
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    let bank = Bank::new(self.config.accounts, self.config.initial_balance);
+                    self.raft = Some(Raft::new(
+                        node_id.clone(),
+                        node_ids.clone(),
+                        bank,
+                        self.config.raft.clone(),
+                    ));
+                    self.node_ids = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Read { .. } | Body::Transfer { .. } => {
+                    self.request(src, body, outbox);
+                    None
+                }
+                Body::Raft(message) => {
+                    let mut raft_outbox = Vec::new();
+                    self.raft.as_mut()?.handle(src, message, &mut raft_outbox);
+                    self.send_raft(raft_outbox, outbox);
+                    None
+                }
+                // The leader's answer to a request we forwarded, which goes on to the client
+                mut reply => {
+                    let forward = self.forwards.remove(reply.in_reply_to()?)?;
+                    self.reply(forward, reply, outbox);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        /// n1 to n`count`, with n1 elected leader.
+        fn cluster(count: usize) -> Vec<Node> {
+            let node_ids: Vec<String> = (1..=count).map(|i| format!("n{}", i)).collect();
+            let mut nodes: Vec<Node> = node_ids
+                .iter()
+                .map(|id| {
+                    let mut config = Config::default();
+                    config.raft.heartbeat_interval = Duration::ZERO;
+                    if id == "n1" {
+                        config.raft.election_timeout = Duration::ZERO;
+                    }
+                    let mut node = Node::new(config);
+                    node.handle_message(Message {
+                        src: "c1".into(),
+                        dest: id.clone(),
+                        body: Body::Init {
+                            msg_id: 1,
+                            node_id: id.clone(),
+                            node_ids: node_ids.clone(),
+                        },
+                    });
+                    node
+                })
+                .collect();
+            let messages = nodes[0].tick();
+            deliver(&mut nodes, messages, 0);
+            assert!(nodes[0].raft.as_ref().unwrap().is_leader());
+            nodes
+        }
+
+        /// Delivers `messages`, and everything sent in response, until only messages to
+        /// clients are left, which are returned. With `drop_every` set, every such message
+        /// between nodes is lost.
+        fn deliver(
+            nodes: &mut [Node],
+            mut messages: Vec<Message>,
+            drop_every: usize,
+        ) -> Vec<Message> {
+            let mut to_clients = Vec::new();
+            let mut sent = 0;
+            while !messages.is_empty() {
+                for message in std::mem::take(&mut messages) {
+                    match message.dest.strip_prefix('n') {
+                        Some(_) if message.src.starts_with('n') && drop_every > 0 => {
+                            sent += 1;
+                            if sent % drop_every == 0 {
+                                continue;
+                            }
+                            let n: usize = message.dest[1..].parse().unwrap();
+                            messages.extend(nodes[n - 1].handle_message(message));
+                        }
+                        Some(n) => {
+                            let node = &mut nodes[n.parse::<usize>().unwrap() - 1];
+                            messages.extend(node.handle_message(message));
+                        }
+                        None => to_clients.push(message),
+                    }
+                }
+            }
+            to_clients
+        }
+
+        fn request(dest: &str, body: serde_json::Value) -> Message {
+            serde_json::from_value(json!({"src": "c1", "dest": dest, "body": body})).unwrap()
+        }
+
+        fn reply(messages: &[Message]) -> serde_json::Value {
+            serde_json::to_value(&messages[0].body).unwrap()
+        }
+
+        fn transfer(msg_id: u64, from: u64, to: u64, amount: i64) -> serde_json::Value {
+            json!({"type": "transfer", "msg_id": msg_id, "from": from, "to": to, "amount": amount})
+        }
+
+        fn balances(node: &Node) -> &BTreeMap<u64, i64> {
+            &node.raft.as_ref().unwrap().state().balances
+        }
+
+        #[test]
+        fn test_transfers_move_money() {
+            let mut nodes = cluster(3);
+            let moved = deliver(&mut nodes, vec![request("n1", transfer(1, 0, 3, 15))], 0);
+            assert_eq!(reply(&moved)["type"], "transfer_ok");
+            let read = deliver(
+                &mut nodes,
+                vec![request("n2", json!({"type": "read", "msg_id": 2}))],
+                0,
+            );
+            assert_eq!(reply(&read)["in_reply_to"], 2);
+            assert_eq!(
+                reply(&read)["value"],
+                json!({"0": 5, "1": 20, "2": 20, "3": 35, "4": 20})
+            );
+        }
+
+        #[test]
+        fn test_bad_transfers_are_refused() {
+            let mut nodes = cluster(3);
+            let mut send = |body| reply(&deliver(&mut nodes, vec![request("n3", body)], 0));
+            assert_eq!(send(transfer(1, 0, 1, 21))["code"], PRECONDITION_FAILED);
+            assert_eq!(send(transfer(2, 0, 9, 1))["code"], KEY_DOES_NOT_EXIST);
+            assert_eq!(send(transfer(3, 9, 0, 1))["code"], KEY_DOES_NOT_EXIST);
+            assert_eq!(send(transfer(4, 1, 0, -5))["code"], MALFORMED_REQUEST);
+            assert_eq!(balances(&nodes[0]).values().sum::<i64>(), 100);
+            assert!(balances(&nodes[0]).values().all(|balance| *balance == 20));
+        }
+
+        #[test]
+        fn test_total_is_conserved_when_messages_are_lost() {
+            let mut nodes = cluster(3);
+            // A fixed but scattered series of transfers, sent to every node in turn
+            let mut seed: u64 = 7;
+            let mut next = |bound: u64| {
+                seed = seed
+                    .wrapping_mul(6364136223846793005)
+                    .wrapping_add(1442695040888963407);
+                (seed >> 33) % bound
+            };
+            for msg_id in 0..200 {
+                let dest = format!("n{}", next(3) + 1);
+                let body = transfer(msg_id, next(5), next(5), next(30) as i64 + 1);
+                deliver(&mut nodes, vec![request(&dest, body)], 4);
+                let heartbeats = nodes[0].tick();
+                deliver(&mut nodes, heartbeats, 4);
+            }
+            for _ in 0..10 {
+                let heartbeats = nodes[0].tick();
+                deliver(&mut nodes, heartbeats, 0);
+            }
+            for node in &nodes {
+                let held = balances(node);
+                assert_eq!(held.values().sum::<i64>(), 100);
+                assert!(held.values().all(|balance| *balance >= 0));
+                assert_eq!(held, balances(&nodes[0]));
+            }
+            assert_ne!(balances(&nodes[0]).values().max(), Some(&20));
+        }
+
+        #[test]
+        fn test_unanswered_requests_time_out() {
+            let mut nodes = cluster(2);
+            nodes[1].config.request_timeout = Duration::ZERO;
+            let forwarded = nodes[1].handle_message(request("n2", transfer(7, 0, 1, 1)));
+            assert_eq!(forwarded[0].dest, "n1");
+            let timed_out: Vec<Message> = nodes[1]
+                .tick()
+                .into_iter()
+                .filter(|message| message.dest == "c1")
+                .collect();
+            assert_eq!(reply(&timed_out)["code"], TIMEOUT);
+            assert_eq!(reply(&timed_out)["in_reply_to"], 7);
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
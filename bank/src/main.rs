@@ -0,0 +1,10 @@
+use bank::node;
+use std::error::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    logging::init()?;
+    let config = node::Config::from_env();
+    let tick_interval = config.tick_interval;
+    maelstrom::run(node::Node::new(config), tick_interval).await
+}
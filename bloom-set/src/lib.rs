@@ -0,0 +1,453 @@
+mod bloom {
+    use serde::{Deserialize, Serialize};
+    use std::collections::hash_map::DefaultHasher;
+    use std::hash::{Hash, Hasher};
+
+    /// A Bloom filter: answers whether it holds an element in a fixed number of bits, however
+    /// many elements it holds or however large they are, at the cost of sometimes claiming
+    /// one it doesn't. It never denies one it does. Which bits an element sets depends on
+    /// `seed`, so filters with different seeds are wrong about different elements.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct BloomFilter {
+        #[serde(with = "packed")]
+        bits: Vec<u64>,
+        hashes: u32, // Bits each element sets
+        seed: u64,
+    }
+
+    impl BloomFilter {
+        /// An empty filter big enough for `capacity` elements before about
+        /// `false_positive_rate` of lookups of other elements find them anyway.
+        pub fn new(capacity: usize, false_positive_rate: f64, seed: u64) -> Self {
+            let capacity = capacity.max(1) as f64;
+            let ln2 = std::f64::consts::LN_2;
+            let bits = (-capacity * false_positive_rate.ln() / (ln2 * ln2)).max(64.0);
+            let hashes = (bits / capacity * ln2).round().max(1.0) as u32;
+            BloomFilter {
+                bits: vec![0; (bits as usize).div_ceil(64)],
+                hashes,
+                seed,
+            }
+        }
+
+        pub fn insert<T: Hash>(&mut self, element: &T) {
+            for bit in self.bits_for(element) {
+                self.bits[bit / 64] |= 1 << (bit % 64);
+            }
+        }
+
+        pub fn contains<T: Hash>(&self, element: &T) -> bool {
+            self.bits_for(element)
+                .all(|bit| self.bits[bit / 64] & (1 << (bit % 64)) != 0)
+        }
+
+        /// The bits `element` sets. Two hashes are combined into as many as are needed, which
+        /// does as well as that many independent ones.
+        fn bits_for<T: Hash>(&self, element: &T) -> impl Iterator<Item = usize> {
+            let mut hasher = DefaultHasher::new();
+            (self.seed, element).hash(&mut hasher);
+            let first = hasher.finish();
+            hasher.write_u8(0);
+            let second = hasher.finish() | 1;
+            let size = self.bits.len() as u64 * 64;
+            (0..self.hashes as u64)
+                .map(move |i| (first.wrapping_add(i.wrapping_mul(second)) % size) as usize)
+        }
+    }
+
+    /// The bits go over the wire packed, which takes a fraction of the space of the JSON
+    /// array of words.
+    mod packed {
+        use serde::{de, ser, Deserialize, Deserializer, Serializer};
+
+        pub fn serialize<S: Serializer>(bits: &[u64], serializer: S) -> Result<S::Ok, S::Error> {
+            let packed = compression::pack(&bits).map_err(ser::Error::custom)?;
+            serializer.serialize_str(&packed)
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Vec<u64>, D::Error> {
+            let packed = String::deserialize(deserializer)?;
+            let bits: Vec<u64> = compression::unpack(&packed).map_err(de::Error::custom)?;
+            if bits.is_empty() {
+                return Err(de::Error::custom(
+                    "a filter needs at least one word of bits",
+                ));
+            }
+            Ok(bits)
+        }
+    }
+}
+
+pub mod node {
+    use super::bloom::BloomFilter;
+    use rand::seq::SliceRandom;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::BTreeSet;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    /// A grow-only set of integers clients can add to at any node, replicated by anti-entropy
+    /// that exchanges Bloom filters rather than sets. Each round a node sends a random peer a
+    /// filter of its set; the peer answers with the elements the filter lacks, along with a
+    /// filter of its own set, which the node answers in turn with the elements that lacks. A
+    /// round costs a few bits per element held, plus the elements actually missing, however
+    /// large the sets.
+    ///
+    /// An element a filter wrongly claims is left out of that round, but every filter is
+    /// hashed with a fresh seed, so it's rarely left out of the next.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        peers: Vec<String>,
+        set: BTreeSet<i64>,
+    }
+
+    /// Tunables, read from `BLOOM_SET_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between reconciliation rounds.
+        pub reconcile_interval: Duration,
+        /// The share of elements a peer lacks that a filter can claim it has, trading how many
+        /// rounds it takes to find them all against the size of the filters.
+        pub false_positive_rate: f64,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                reconcile_interval: Duration::from_millis(200),
+                false_positive_rate: 0.01,
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                reconcile_interval: Duration::from_millis(env_or(
+                    "BLOOM_SET_RECONCILE_INTERVAL_MS",
+                    default.reconcile_interval.as_millis() as u64,
+                )),
+                false_positive_rate: env_or(
+                    "BLOOM_SET_FALSE_POSITIVE_RATE",
+                    default.false_positive_rate,
+                ),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Add {
+            msg_id: u64,
+            element: i64,
+        },
+        AddOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+        },
+        /// The elements, in ascending order.
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: Vec<i64>,
+        },
+        /// A filter of the sender's set.
+        Reconcile {
+            msg_id: u64,
+            filter: BloomFilter,
+        },
+        /// The elements the filter lacked, and a filter of the replier's set.
+        ReconcileOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            elements: Vec<i64>,
+            filter: BloomFilter,
+        },
+        /// The elements the reconcile_ok's filter lacked. Not acknowledged, since any that are
+        /// lost are found missing again next round.
+        Push {
+            msg_id: u64,
+            elements: Vec<i64>,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                peers: Vec::new(),
+                set: BTreeSet::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "set": self.set.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Starts a round with a random peer.
+        pub fn reconcile(&mut self) -> Vec<Message> {
+            let Some(peer) = self.peers.choose(&mut rand::thread_rng()).cloned() else {
+                return Vec::new();
+            };
+            let msg_id = self.next_msg_id();
+            vec![Message {
+                src: self.id.clone(),
+                dest: peer,
+                body: Body::Reconcile {
+                    msg_id,
+                    filter: self.filter(),
+                },
+            }]
+        }
+
+        /// A filter of our set, with a fresh seed.
+        fn filter(&self) -> BloomFilter {
+            let seed = rand::random();
+            let mut filter =
+                BloomFilter::new(self.set.len(), self.config.false_positive_rate, seed);
+            for element in &self.set {
+                filter.insert(element);
+            }
+            filter
+        }
+
+        fn missing_from(&self, filter: &BloomFilter) -> Vec<i64> {
+            self.set
+                .iter()
+                .filter(|element| !filter.contains(element))
+                .copied()
+                .collect()
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            messages: &mut Vec<Message>,
+        ) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Add { msg_id, element } => {
+                    self.set.insert(element);
+                    Body::AddOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Read { msg_id } => Body::ReadOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: msg_id,
+                    value: self.set.iter().copied().collect(),
+                },
+                Body::Reconcile { msg_id, filter } => Body::ReconcileOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: msg_id,
+                    elements: self.missing_from(&filter),
+                    filter: self.filter(),
+                },
+                Body::ReconcileOk {
+                    elements, filter, ..
+                } => {
+                    // What it sent us it has, so it's in its filter, and won't be sent back
+                    self.set.extend(elements);
+                    let missing = self.missing_from(&filter);
+                    if !missing.is_empty() {
+                        let msg_id = self.next_msg_id();
+                        log::debug!("Pushing {} elements to {}", missing.len(), src);
+                        messages.push(Message {
+                            src: self.id.clone(),
+                            dest: src.to_string(),
+                            body: Body::Push {
+                                msg_id,
+                                elements: missing,
+                            },
+                        });
+                    }
+                    return None;
+                }
+                Body::Push { elements, .. } => {
+                    self.set.extend(elements);
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::Error { .. }
+                | Body::AddOk { .. }
+                | Body::ReadOk { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing;
+
+        fn init(id: &str) -> Node {
+            testing::init(Node::new(Config::default()), id, 2)
+        }
+
+        /// Runs a round from `from` to `to`, returning the bytes it sent.
+        fn round(from: &mut Node, to: &mut Node) -> usize {
+            let mut sent = 0;
+            let mut messages = from.reconcile();
+            while let Some(message) = messages.pop() {
+                sent += serde_json::to_string(&message).unwrap().len();
+                let node = if message.dest == from.id {
+                    &mut *from
+                } else {
+                    &mut *to
+                };
+                messages.extend(node.handle_message(message));
+            }
+            sent
+        }
+
+        #[test]
+        fn test_filters_never_deny_what_they_hold() {
+            let mut filter = BloomFilter::new(1000, 0.01, 7);
+            for element in 0..1000 {
+                filter.insert(&element);
+            }
+            assert!((0..1000).all(|element| filter.contains(&element)));
+            let wrongly_held = (1000..11000)
+                .filter(|element| filter.contains(element))
+                .count();
+            assert!(wrongly_held < 300, "{} false positives", wrongly_held);
+        }
+
+        #[test]
+        fn test_sets_converge_for_less_than_they_hold() {
+            let (mut n1, mut n2) = (init("n1"), init("n2"));
+            // Each is missing a hundred of the other's ten thousand
+            let element = |i: i64| i * 1_000_000_007;
+            n1.set.extend((0..10000).map(element));
+            n2.set.extend((100..10100).map(element));
+            let whole_set = serde_json::to_string(&n1.set).unwrap().len();
+
+            let sent = round(&mut n1, &mut n2);
+            assert!(sent < whole_set / 2, "{} bytes against {}", sent, whole_set);
+            // Elements a filter wrongly claimed are found in later rounds
+            for _ in 0..10 {
+                if n1.set == n2.set {
+                    break;
+                }
+                round(&mut n2, &mut n1);
+            }
+            assert_eq!(n1.set, (0..10100).map(element).collect());
+            assert_eq!(n2.set, n1.set);
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::reconcile(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
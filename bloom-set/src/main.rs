@@ -0,0 +1,10 @@
+use bloom_set::node;
+use std::error::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    logging::init()?;
+    let config = node::Config::from_env();
+    let reconcile_interval = config.reconcile_interval;
+    maelstrom::run(node::Node::new(config), reconcile_interval).await
+}
@@ -0,0 +1,66 @@
+//! Broadcast's hot paths: a client's broadcast, which the node records and forwards to its
+//! peers, and a broadcast forwarded by a peer, which it only records.
+
+use criterion::{criterion_group, criterion_main, BatchSize, Criterion, Throughput};
+use serde_json::{json, Value};
+
+#[allow(dead_code, unused_imports)]
+mod broadcast {
+    include!("../src/main.rs");
+    pub use node::{Message, Node};
+}
+
+use broadcast::{Message, Node};
+
+fn message(src: &str, body: Value) -> Message {
+    serde_json::from_value(json!({"src": src, "dest": "n1", "body": body})).unwrap()
+}
+
+/// n1 in a five node cluster, with the other four as its peers.
+fn node() -> Node {
+    let mut node = Node::new();
+    let nodes = ["n1", "n2", "n3", "n4", "n5"];
+    node.handle_message(message(
+        "c0",
+        json!({"type": "init", "msg_id": 0, "node_id": "n1", "node_ids": nodes}),
+    ));
+    node.handle_message(message(
+        "c0",
+        json!({"type": "topology", "msg_id": 1, "topology": {"n1": &nodes[1..]}}),
+    ));
+    node
+}
+
+fn bench_broadcast(c: &mut Criterion) {
+    let mut group = c.benchmark_group("broadcast");
+    group.throughput(Throughput::Elements(1));
+    // Each node's first broadcast, so every one is new to it and is forwarded
+    group.bench_function("handle", |b| {
+        b.iter_batched_ref(
+            node,
+            |node| {
+                node.handle_message(message(
+                    "c1",
+                    json!({"type": "broadcast", "msg_id": 2, "message": 7}),
+                ))
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.bench_function("forward", |b| {
+        b.iter_batched_ref(
+            node,
+            |node| {
+                node.handle_message(message(
+                    "n2",
+                    json!({"type": "broadcast", "msg_id": 2, "message": 7}),
+                ))
+            },
+            BatchSize::SmallInput,
+        )
+    });
+    group.finish();
+}
+
+criterion_group!(benches, bench_broadcast);
+criterion_main!(benches);
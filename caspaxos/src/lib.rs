@@ -0,0 +1,963 @@
+pub mod node {
+    use maelstrom::error::{
+        KEY_DOES_NOT_EXIST, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE, TIMEOUT,
+    };
+    use rand::Rng;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{HashMap, HashSet};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// A linearizable key/value store serving lin-kv's read, write and cas, where every key
+    /// is a register agreed on by CASPaxos rather than through a log. Any node can serve any
+    /// request, with no leader to elect or forward to.
+    ///
+    /// Every node is both a proposer and an acceptor. To change a register, a proposer picks
+    /// a ballot higher than any it's seen and asks every acceptor to promise to ignore lower
+    /// ones, which they answer with the value they last accepted. Once a majority has
+    /// promised, the proposer takes the value accepted under the highest ballot among them,
+    /// applies the change to it, and asks every acceptor to accept the result under its
+    /// ballot. The change takes effect once a majority has. Reads go through both rounds too,
+    /// accepting the value unchanged, so that nothing they see can be lost.
+    ///
+    /// Proposers racing on a key knock out each other's ballots. One knocked out while asking
+    /// for promises tries again after a random pause with a higher ballot, since nothing was
+    /// accepted yet; one knocked out while asking for acceptance may already have a
+    /// majority, so unless it was only reading it can't tell whether to try again, and its
+    /// clients are told the requests may or may not have taken effect. Requests arriving
+    /// while a key has a proposal out are queued and applied together, in order, in its
+    /// next one.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        node_ids: Vec<String>,
+        round: u64, // The highest round of any ballot we've seen
+        // As acceptor, by each key's JSON, since keys can be any JSON
+        slots: HashMap<String, Slot>,
+        // As proposer, by each key's JSON
+        proposals: HashMap<String, Proposal>, // At most one at a time per key
+        queued: HashMap<String, Vec<Request>>, // Waiting on a key's proposal to finish
+    }
+
+    /// Orders proposals, by round and then by proposer, so no two proposers share one.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+    struct Ballot {
+        round: u64,
+        node: String,
+    }
+
+    /// A value an acceptor accepted. `None` is a register that doesn't exist yet.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Accepted {
+        ballot: Ballot,
+        value: Option<Value>,
+    }
+
+    /// An acceptor's state for a register.
+    #[derive(Default)]
+    struct Slot {
+        promised: Ballot, // Lower ballots are rejected
+        accepted: Option<Accepted>,
+    }
+
+    /// A client's request, waiting on a proposal to apply it.
+    struct Request {
+        client: String,
+        msg_id: u64,
+        op: Op,
+    }
+
+    /// A batch of requests on one key, being proposed.
+    struct Proposal {
+        key: Value,
+        ballot: Ballot,
+        requests: Vec<Request>,
+        phase: Phase,
+        rejected: HashSet<String>, // Acceptors that promised a higher ballot
+        resend_at: Instant,        // When to ask acceptors that haven't answered again
+        deadline: Instant,         // When we give up
+    }
+
+    enum Phase {
+        /// Asking for promises, with the value each promising acceptor last accepted.
+        Preparing {
+            promises: HashMap<String, Option<Accepted>>,
+        },
+        /// Asking for `value` to be accepted, with a reply to each request waiting on it.
+        Accepting {
+            value: Option<Value>,
+            replies: Vec<Body>,
+            accepted: HashSet<String>,
+        },
+        /// Knocked out while preparing, and pausing before trying again.
+        BackingOff { until: Instant },
+    }
+
+    /// An acceptor's answer to a proposal.
+    enum Vote {
+        Promise(Option<Accepted>),
+        Accepted,
+        Reject(Ballot),
+    }
+
+    /// A change to a register.
+    #[derive(Debug, Clone, PartialEq)]
+    enum Op {
+        Read,
+        Write {
+            value: Value,
+        },
+        Cas {
+            from: Value,
+            to: Value,
+            create_if_not_exists: bool,
+        },
+    }
+
+    /// Tunables, read from `CASPAXOS_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between ticks, which resend rounds, end pauses and time out proposals.
+        pub tick_interval: Duration,
+        /// How long a proposal has to finish before its requests are given up on.
+        pub request_timeout: Duration,
+        /// How long to wait on acceptors that haven't answered before asking them again.
+        pub resend_interval: Duration,
+        /// The longest a proposer knocked out while preparing pauses before trying again.
+        pub max_backoff: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                tick_interval: Duration::from_millis(20),
+                request_timeout: Duration::from_millis(1000),
+                resend_interval: Duration::from_millis(200),
+                max_backoff: Duration::from_millis(50),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            let millis = |name, default: Duration| {
+                Duration::from_millis(env_or(name, default.as_millis() as u64))
+            };
+            Config {
+                tick_interval: millis("CASPAXOS_TICK_INTERVAL_MS", default.tick_interval),
+                request_timeout: millis("CASPAXOS_REQUEST_TIMEOUT_MS", default.request_timeout),
+                resend_interval: millis("CASPAXOS_RESEND_INTERVAL_MS", default.resend_interval),
+                max_backoff: millis("CASPAXOS_MAX_BACKOFF_MS", default.max_backoff),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+            key: Value,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: Value,
+        },
+        Write {
+            msg_id: u64,
+            key: Value,
+            value: Value,
+        },
+        WriteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Sets `key` to `to` if it's `from`. A missing key fails, unless
+        /// `create_if_not_exists` is set, in which case it's created.
+        Cas {
+            msg_id: u64,
+            key: Value,
+            from: Value,
+            to: Value,
+            #[serde(default)]
+            create_if_not_exists: bool,
+        },
+        CasOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// Asks for a promise to ignore ballots lower than `ballot` for `key`.
+        Prepare {
+            msg_id: u64,
+            key: Value,
+            ballot: Ballot,
+        },
+        /// The promise, with the value last accepted for `key`, if any.
+        Promise {
+            msg_id: u64,
+            in_reply_to: u64,
+            key: Value,
+            ballot: Ballot,
+            accepted: Option<Accepted>,
+        },
+        /// Asks for `value` to be accepted for `key` under `ballot`.
+        Accept {
+            msg_id: u64,
+            key: Value,
+            ballot: Ballot,
+            value: Option<Value>,
+        },
+        AcceptOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            key: Value,
+            ballot: Ballot,
+        },
+        /// Refuses a prepare or accept for `ballot`, having promised the higher `promised`.
+        Reject {
+            msg_id: u64,
+            in_reply_to: u64,
+            key: Value,
+            ballot: Ballot,
+            promised: Ballot,
+        },
+    }
+
+    impl Body {
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::ReadOk { in_reply_to, .. }
+                | Body::WriteOk { in_reply_to, .. }
+                | Body::CasOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::ReadOk { msg_id, .. }
+                | Body::WriteOk { msg_id, .. }
+                | Body::CasOk { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+    }
+
+    impl Op {
+        /// Applies the change to `value`, returning the reply, with its ids still to fill in.
+        fn apply(&self, value: &mut Option<Value>) -> Body {
+            match (self, value.as_ref()) {
+                (Op::Read, Some(current)) => Body::ReadOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                    value: current.clone(),
+                },
+                (Op::Write { value: new }, _) => {
+                    *value = Some(new.clone());
+                    Body::WriteOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    }
+                }
+                (Op::Cas { from, to, .. }, Some(current)) if current == from => {
+                    *value = Some(to.clone());
+                    Body::CasOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    }
+                }
+                (Op::Cas { from, .. }, Some(current)) => Body::Error {
+                    in_reply_to: 0,
+                    code: PRECONDITION_FAILED,
+                    text: format!("expected {}, but found {}", from, current),
+                },
+                (
+                    Op::Cas {
+                        to,
+                        create_if_not_exists: true,
+                        ..
+                    },
+                    None,
+                ) => {
+                    *value = Some(to.clone());
+                    Body::CasOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    }
+                }
+                (Op::Read | Op::Cas { .. }, None) => Body::Error {
+                    in_reply_to: 0,
+                    code: KEY_DOES_NOT_EXIST,
+                    text: "key doesn't exist".to_string(),
+                },
+            }
+        }
+    }
+
+    impl Slot {
+        fn prepare(&mut self, ballot: &Ballot) -> Vote {
+            if *ballot < self.promised {
+                return Vote::Reject(self.promised.clone());
+            }
+            self.promised = ballot.clone();
+            Vote::Promise(self.accepted.clone())
+        }
+
+        fn accept(&mut self, ballot: &Ballot, value: Option<Value>) -> Vote {
+            if *ballot < self.promised {
+                return Vote::Reject(self.promised.clone());
+            }
+            self.promised = ballot.clone();
+            self.accepted = Some(Accepted {
+                ballot: ballot.clone(),
+                value,
+            });
+            Vote::Accepted
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                node_ids: Vec::new(),
+                round: 0,
+                slots: HashMap::new(),
+                proposals: HashMap::new(),
+                queued: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        fn majority(&self) -> usize {
+            self.node_ids.len() / 2 + 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "round": self.round,
+                "slots": self.slots.len(),
+                "proposals": self.proposals.keys().collect::<Vec<_>>(),
+                "queued": self.queued.values().map(Vec::len).sum::<usize>(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Gives up on proposals past their deadline, retries those done pausing, and asks
+        /// again for answers that are overdue.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            let now = Instant::now();
+            let mut keys: Vec<String> = self.proposals.keys().cloned().collect();
+            keys.sort();
+            for key in keys {
+                let proposal = &self.proposals[&key];
+                if proposal.deadline <= now {
+                    // Accepting may have got a majority whose answers we haven't heard, but
+                    // anything short of that never took effect
+                    let (code, text) = match proposal.phase {
+                        Phase::Accepting { .. } => (TIMEOUT, "timed out waiting on acceptors"),
+                        _ => (
+                            TEMPORARILY_UNAVAILABLE,
+                            "couldn't get a majority to promise",
+                        ),
+                    };
+                    self.fail(&key, code, text, &mut messages);
+                    continue;
+                }
+                match proposal.phase {
+                    Phase::BackingOff { until } if until <= now => {
+                        self.prepare(&key, &mut messages)
+                    }
+                    Phase::BackingOff { .. } => {}
+                    _ if proposal.resend_at <= now => self.broadcast(&key, &mut messages),
+                    _ => {}
+                }
+            }
+            messages
+        }
+
+        /// Queues a client's request, and starts a proposal for its key if none is out.
+        fn request(&mut self, client: &str, body: Body, messages: &mut Vec<Message>) {
+            let (msg_id, key, op) = match body {
+                Body::Read { msg_id, key } => (msg_id, key, Op::Read),
+                Body::Write { msg_id, key, value } => (msg_id, key, Op::Write { value }),
+                Body::Cas {
+                    msg_id,
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                } => (
+                    msg_id,
+                    key,
+                    Op::Cas {
+                        from,
+                        to,
+                        create_if_not_exists,
+                    },
+                ),
+                _ => return,
+            };
+            let request = Request {
+                client: client.to_string(),
+                msg_id,
+                op,
+            };
+            let key_id = key.to_string();
+            self.queued.entry(key_id.clone()).or_default().push(request);
+            if !self.proposals.contains_key(&key_id) {
+                self.start(&key_id, key, messages);
+            }
+        }
+
+        /// Proposes everything queued for a key.
+        fn start(&mut self, key_id: &str, key: Value, messages: &mut Vec<Message>) {
+            let requests = self.queued.remove(key_id).unwrap_or_default();
+            if requests.is_empty() {
+                return;
+            }
+            let now = Instant::now();
+            let proposal = Proposal {
+                key,
+                ballot: Ballot::default(),
+                requests,
+                phase: Phase::BackingOff { until: now },
+                rejected: HashSet::new(),
+                resend_at: now,
+                deadline: now + self.config.request_timeout,
+            };
+            self.proposals.insert(key_id.to_string(), proposal);
+            self.prepare(key_id, messages);
+        }
+
+        /// Starts a key's proposal over under a new ballot, higher than any we've seen.
+        fn prepare(&mut self, key_id: &str, messages: &mut Vec<Message>) {
+            self.round += 1;
+            let ballot = Ballot {
+                round: self.round,
+                node: self.id.clone(),
+            };
+            let Some(proposal) = self.proposals.get_mut(key_id) else {
+                return;
+            };
+            proposal.ballot = ballot;
+            proposal.rejected.clear();
+            proposal.phase = Phase::Preparing {
+                promises: HashMap::new(),
+            };
+            self.broadcast(key_id, messages);
+        }
+
+        /// Asks every acceptor that hasn't answered the proposal's current round, our own
+        /// included, which answers straight away.
+        fn broadcast(&mut self, key_id: &str, messages: &mut Vec<Message>) {
+            let Some(proposal) = self.proposals.get_mut(key_id) else {
+                return;
+            };
+            proposal.resend_at = Instant::now() + self.config.resend_interval;
+            let (key, ballot) = (proposal.key.clone(), proposal.ballot.clone());
+            let (body, answered): (Body, Vec<&String>) = match &proposal.phase {
+                Phase::Preparing { promises } => (
+                    Body::Prepare {
+                        msg_id: 0,
+                        key,
+                        ballot: ballot.clone(),
+                    },
+                    promises.keys().collect(),
+                ),
+                Phase::Accepting {
+                    value, accepted, ..
+                } => (
+                    Body::Accept {
+                        msg_id: 0,
+                        key,
+                        ballot: ballot.clone(),
+                        value: value.clone(),
+                    },
+                    accepted.iter().collect(),
+                ),
+                Phase::BackingOff { .. } => return,
+            };
+            let targets: Vec<String> = self
+                .node_ids
+                .iter()
+                .filter(|node| !answered.contains(node) && !proposal.rejected.contains(*node))
+                .cloned()
+                .collect();
+            for dest in targets {
+                if dest == self.id {
+                    let slot = self.slots.entry(key_id.to_string()).or_default();
+                    let vote = match body.clone() {
+                        Body::Accept { value, .. } => slot.accept(&ballot, value),
+                        _ => slot.prepare(&ballot),
+                    };
+                    let id = self.id.clone();
+                    self.vote(key_id, &id, &ballot, vote, messages);
+                    continue;
+                }
+                let mut body = body.clone();
+                if let Body::Prepare { msg_id, .. } | Body::Accept { msg_id, .. } = &mut body {
+                    *msg_id = self.next_msg_id();
+                }
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest,
+                    body,
+                });
+            }
+        }
+
+        /// Counts an acceptor's answer towards the proposal it's for, moving it on once a
+        /// majority has promised or accepted, or backing off once a majority can't.
+        fn vote(
+            &mut self,
+            key_id: &str,
+            acceptor: &str,
+            ballot: &Ballot,
+            vote: Vote,
+            messages: &mut Vec<Message>,
+        ) {
+            let majority = self.majority();
+            let cluster = self.node_ids.len();
+            let Some(proposal) = self.proposals.get_mut(key_id) else {
+                return;
+            };
+            if proposal.ballot != *ballot {
+                return;
+            }
+            match (vote, &mut proposal.phase) {
+                (Vote::Reject(promised), _) => {
+                    self.round = self.round.max(promised.round);
+                    proposal.rejected.insert(acceptor.to_string());
+                    if proposal.rejected.len() > cluster - majority {
+                        self.preempted(key_id, messages);
+                    }
+                }
+                (Vote::Promise(accepted), Phase::Preparing { promises }) => {
+                    promises.insert(acceptor.to_string(), accepted);
+                    if promises.len() < majority {
+                        return;
+                    }
+                    let mut value = promises
+                        .values()
+                        .flatten()
+                        .max_by(|a, b| a.ballot.cmp(&b.ballot))
+                        .and_then(|accepted| accepted.value.clone());
+                    let replies = proposal
+                        .requests
+                        .iter()
+                        .map(|request| request.op.apply(&mut value))
+                        .collect();
+                    proposal.phase = Phase::Accepting {
+                        value,
+                        replies,
+                        accepted: HashSet::new(),
+                    };
+                    self.broadcast(key_id, messages);
+                }
+                (Vote::Accepted, Phase::Accepting { accepted, .. }) => {
+                    accepted.insert(acceptor.to_string());
+                    if accepted.len() < majority {
+                        return;
+                    }
+                    let Some(proposal) = self.proposals.remove(key_id) else {
+                        return;
+                    };
+                    let Phase::Accepting { replies, .. } = proposal.phase else {
+                        return;
+                    };
+                    for (request, reply) in proposal.requests.into_iter().zip(replies) {
+                        self.reply(request, reply, messages);
+                    }
+                    self.start(key_id, proposal.key, messages);
+                }
+                // Late answers to a round we've moved past
+                _ => {}
+            }
+        }
+
+        /// A majority has promised a higher ballot than the proposal's. Until anything's
+        /// been accepted, or if it's only reading, the proposal can pause and try again.
+        fn preempted(&mut self, key_id: &str, messages: &mut Vec<Message>) {
+            let Some(proposal) = self.proposals.get_mut(key_id) else {
+                return;
+            };
+            let reading = proposal
+                .requests
+                .iter()
+                .all(|request| request.op == Op::Read);
+            if reading || matches!(proposal.phase, Phase::Preparing { .. }) {
+                let pause = rand::thread_rng().gen_range(0..=self.config.max_backoff.as_millis());
+                log::debug!("Ballot {:?} preempted, retrying", proposal.ballot);
+                proposal.phase = Phase::BackingOff {
+                    until: Instant::now() + Duration::from_millis(pause as u64),
+                };
+                return;
+            }
+            let text = "preempted while accepting, so it may or may not have taken effect";
+            self.fail(key_id, TIMEOUT, text, messages);
+        }
+
+        /// Gives up on a key's proposal, telling its clients why, and moves on to whatever
+        /// was queued behind it.
+        fn fail(&mut self, key_id: &str, code: u64, text: &str, messages: &mut Vec<Message>) {
+            let Some(proposal) = self.proposals.remove(key_id) else {
+                return;
+            };
+            for request in proposal.requests {
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code,
+                    text: text.to_string(),
+                };
+                self.reply(request, error, messages);
+            }
+            self.start(key_id, proposal.key, messages);
+        }
+
+        fn reply(&mut self, request: Request, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = request.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: request.client,
+                body,
+            });
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            messages: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.node_ids = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Read { .. } | Body::Write { .. } | Body::Cas { .. } => {
+                    self.request(src, body, messages);
+                    None
+                }
+                Body::Prepare {
+                    msg_id,
+                    key,
+                    ballot,
+                } => {
+                    self.round = self.round.max(ballot.round);
+                    let slot = self.slots.entry(key.to_string()).or_default();
+                    Some(match slot.prepare(&ballot) {
+                        Vote::Reject(promised) => Body::Reject {
+                            msg_id: self.cur_id,
+                            in_reply_to: msg_id,
+                            key,
+                            ballot,
+                            promised,
+                        },
+                        _ => Body::Promise {
+                            msg_id: self.cur_id,
+                            in_reply_to: msg_id,
+                            key,
+                            ballot,
+                            accepted: slot.accepted.clone(),
+                        },
+                    })
+                }
+                Body::Accept {
+                    msg_id,
+                    key,
+                    ballot,
+                    value,
+                } => {
+                    self.round = self.round.max(ballot.round);
+                    let slot = self.slots.entry(key.to_string()).or_default();
+                    Some(match slot.accept(&ballot, value) {
+                        Vote::Reject(promised) => Body::Reject {
+                            msg_id: self.cur_id,
+                            in_reply_to: msg_id,
+                            key,
+                            ballot,
+                            promised,
+                        },
+                        _ => Body::AcceptOk {
+                            msg_id: self.cur_id,
+                            in_reply_to: msg_id,
+                            key,
+                            ballot,
+                        },
+                    })
+                }
+                Body::Promise {
+                    key,
+                    ballot,
+                    accepted,
+                    ..
+                } => {
+                    let vote = Vote::Promise(accepted);
+                    self.vote(&key.to_string(), src, &ballot, vote, messages);
+                    None
+                }
+                Body::AcceptOk { key, ballot, .. } => {
+                    self.vote(&key.to_string(), src, &ballot, Vote::Accepted, messages);
+                    None
+                }
+                Body::Reject {
+                    key,
+                    ballot,
+                    promised,
+                    ..
+                } => {
+                    let vote = Vote::Reject(promised);
+                    self.vote(&key.to_string(), src, &ballot, vote, messages);
+                    None
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::ReadOk { .. }
+                | Body::WriteOk { .. }
+                | Body::CasOk { .. }
+                | Body::Error { .. } => None,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, deliver, reply, request};
+        use serde_json::json;
+
+        /// n1 to n`count`.
+        fn cluster(count: usize) -> Vec<Node> {
+            testing::cluster(count, |_| Node::new(Config::default()))
+        }
+
+        #[test]
+        fn test_read_write_cas_at_any_node() {
+            let mut nodes = cluster(3);
+            let mut send = |dest, body| {
+                let messages = vec![request(dest, body)];
+                reply(&deliver(&mut nodes, messages))
+            };
+            assert_eq!(
+                send("n1", json!({"type": "read", "msg_id": 1, "key": 1}))["code"],
+                KEY_DOES_NOT_EXIST
+            );
+            send(
+                "n2",
+                json!({"type": "write", "msg_id": 2, "key": 1, "value": 3}),
+            );
+            assert_eq!(
+                send(
+                    "n3",
+                    json!({"type": "cas", "msg_id": 3, "key": 1, "from": 4, "to": 5})
+                )["code"],
+                PRECONDITION_FAILED
+            );
+            let cas = json!({"type": "cas", "msg_id": 4, "key": 1, "from": 3, "to": 5});
+            assert_eq!(send("n1", cas)["type"], "cas_ok");
+            let read = send("n3", json!({"type": "read", "msg_id": 5, "key": 1}));
+            assert_eq!(read["value"], 5);
+            assert_eq!(read["in_reply_to"], 5);
+        }
+
+        #[test]
+        fn test_acceptors_reject_lower_ballots() {
+            let mut slot = Slot::default();
+            let ballot = |round, node: &str| Ballot {
+                round,
+                node: node.into(),
+            };
+            assert!(matches!(
+                slot.prepare(&ballot(2, "n1")),
+                Vote::Promise(None)
+            ));
+            assert!(matches!(slot.prepare(&ballot(1, "n3")), Vote::Reject(_)));
+            assert!(matches!(
+                slot.prepare(&ballot(2, "n2")),
+                Vote::Promise(None)
+            ));
+            let Vote::Reject(promised) = slot.accept(&ballot(2, "n1"), Some(json!(1))) else {
+                panic!("accepted a ballot lower than promised");
+            };
+            assert_eq!(promised, ballot(2, "n2"));
+            assert!(matches!(
+                slot.accept(&ballot(2, "n2"), None),
+                Vote::Accepted
+            ));
+        }
+
+        #[test]
+        fn test_preempted_proposer_retries_with_a_higher_ballot() {
+            let mut nodes = cluster(3);
+            nodes[0].config.max_backoff = Duration::ZERO;
+            let first = nodes[0].handle_message(request(
+                "n1",
+                json!({"type": "write", "msg_id": 1, "key": "k", "value": 1}),
+            ));
+            // n2 proposes before n1's prepares arrive, and gets its write accepted
+            let second = vec![request(
+                "n2",
+                json!({"type": "cas", "msg_id": 2, "key": "k", "from": 0, "to": 2,
+                       "create_if_not_exists": true}),
+            )];
+            assert_eq!(reply(&deliver(&mut nodes, second))["type"], "cas_ok");
+            assert!(deliver(&mut nodes, first).is_empty());
+            assert!(matches!(
+                nodes[0].proposals["\"k\""].phase,
+                Phase::BackingOff { .. }
+            ));
+
+            let retried = nodes[0].tick();
+            assert_eq!(reply(&deliver(&mut nodes, retried))["type"], "write_ok");
+            let read = vec![request(
+                "n3",
+                json!({"type": "read", "msg_id": 3, "key": "k"}),
+            )];
+            assert_eq!(reply(&deliver(&mut nodes, read))["value"], 1);
+        }
+
+        #[test]
+        fn test_queued_requests_share_a_proposal() {
+            let mut nodes = cluster(3);
+            let mut messages = Vec::new();
+            for (msg_id, value) in [(1, 1), (2, 2)] {
+                let write = json!({"type": "write", "msg_id": msg_id, "key": 1, "value": value});
+                messages.extend(nodes[0].handle_message(request("n1", write)));
+            }
+            for msg_id in [3, 4] {
+                let cas = json!({"type": "cas", "msg_id": msg_id, "key": 1, "from": 2, "to": 4});
+                messages.extend(nodes[0].handle_message(request("n1", cas)));
+            }
+            let replies: Vec<serde_json::Value> = deliver(&mut nodes, messages)
+                .iter()
+                .map(|message| serde_json::to_value(&message.body).unwrap())
+                .collect();
+            let types: Vec<&str> = replies
+                .iter()
+                .map(|r| r["type"].as_str().unwrap())
+                .collect();
+            assert_eq!(types, ["write_ok", "write_ok", "cas_ok", "error"]);
+            assert_eq!(replies[3]["code"], PRECONDITION_FAILED);
+        }
+
+        #[test]
+        fn test_minority_times_out() {
+            let mut nodes = cluster(3);
+            nodes[0].config.request_timeout = Duration::ZERO;
+            let read = json!({"type": "read", "msg_id": 7, "key": 1});
+            // Its prepares never arrive
+            let prepares = nodes[0].handle_message(request("n1", read));
+            assert_eq!(prepares.len(), 2);
+            let timed_out = nodes[0].tick();
+            assert_eq!(reply(&timed_out)["code"], TEMPORARILY_UNAVAILABLE);
+            assert_eq!(reply(&timed_out)["in_reply_to"], 7);
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,444 @@
+pub mod node {
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    /// Broadcasts delivered in causal order: no node delivers a broadcast before everything
+    /// delivered at its origin when it was made. Each broadcast carries a version vector of
+    /// how many broadcasts from each node its origin had delivered, its own included, and
+    /// anything that arrives ahead of what it depends on is held back until that's delivered.
+    ///
+    /// Broadcasts that don't depend on each other can be delivered in different orders on
+    /// different nodes.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        peers: Vec<String>,
+        delivered: HashMap<String, u64>, // How many broadcasts from each node we've delivered
+        held: Vec<Causal>,               // Broadcasts waiting on ones they depend on
+        log: Vec<usize>,
+        unacked: HashMap<u64, (String, Causal)>, // Relays each peer hasn't acknowledged, by msg_id
+    }
+
+    /// Tunables, read from `CAUSAL_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between retries of unacknowledged relays.
+        pub retry_interval: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                retry_interval: Duration::from_millis(200),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                retry_interval: Duration::from_millis(env_or(
+                    "CAUSAL_RETRY_INTERVAL_MS",
+                    default.retry_interval.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// A broadcast, the node it was made at, and the version vector it depends on.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Causal {
+        origin: String,
+        clock: HashMap<String, u64>,
+        message: usize,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Broadcast {
+            msg_id: u64,
+            message: usize,
+        },
+        BroadcastOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+        },
+        /// The delivered broadcasts, in the order they were delivered here.
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            messages: Vec<usize>,
+        },
+        /// Every node relays its broadcasts to every other, so the topology is ignored.
+        Topology {
+            msg_id: u64,
+        },
+        TopologyOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// A broadcast made at the sender.
+        Relay {
+            msg_id: u64,
+            broadcast: Causal,
+        },
+        RelayOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+    }
+
+    impl Default for Node {
+        fn default() -> Self {
+            Node::new()
+        }
+    }
+
+    impl Node {
+        pub fn new() -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                peers: Vec::new(),
+                delivered: HashMap::new(),
+                held: Vec::new(),
+                log: Vec::new(),
+                unacked: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "delivered": self.delivered,
+                "held": self.held.len(),
+                "log": self.log.len(),
+                "unacked": self.unacked.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = match message.body {
+                Body::Broadcast { message, .. } => self.relay(message),
+                _ => Vec::new(),
+            };
+            if let Some(body) = self.handle_body(message.body) {
+                self.cur_id += 1;
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+            }
+            messages
+        }
+
+        /// Stamps a broadcast from a client and sends it to every peer.
+        fn relay(&mut self, message: usize) -> Vec<Message> {
+            let mut clock = self.delivered.clone();
+            *clock.entry(self.id.clone()).or_default() += 1;
+            let broadcast = Causal {
+                origin: self.id.clone(),
+                clock,
+                message,
+            };
+            let mut messages = Vec::new();
+            for peer in self.peers.clone() {
+                let msg_id = self.next_msg_id();
+                self.unacked
+                    .insert(msg_id, (peer.clone(), broadcast.clone()));
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Relay {
+                        msg_id,
+                        broadcast: broadcast.clone(),
+                    },
+                });
+            }
+            // Everything it depends on is delivered here already
+            self.held.push(broadcast);
+            self.deliver();
+            messages
+        }
+
+        /// Sends every relay a peer hasn't acknowledged again.
+        pub fn retry(&mut self) -> Vec<Message> {
+            self.unacked
+                .iter()
+                .map(|(msg_id, (peer, broadcast))| Message {
+                    src: self.id.clone(),
+                    dest: peer.clone(),
+                    body: Body::Relay {
+                        msg_id: *msg_id,
+                        broadcast: broadcast.clone(),
+                    },
+                })
+                .collect()
+        }
+
+        /// Whether everything `broadcast` depends on has been delivered, and it hasn't.
+        fn deliverable(&self, broadcast: &Causal) -> bool {
+            broadcast.clock.iter().all(|(node, count)| {
+                let delivered = self.delivered.get(node).copied().unwrap_or(0);
+                if *node == broadcast.origin {
+                    *count == delivered + 1
+                } else {
+                    *count <= delivered
+                }
+            })
+        }
+
+        /// Delivers held broadcasts until none left are deliverable, since each one delivered
+        /// can free others waiting on it.
+        fn deliver(&mut self) {
+            while let Some(i) = self.held.iter().position(|b| self.deliverable(b)) {
+                let broadcast = self.held.swap_remove(i);
+                *self.delivered.entry(broadcast.origin).or_default() += 1;
+                self.log.push(broadcast.message);
+            }
+        }
+
+        fn handle_body(&mut self, body: Body) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Broadcast { msg_id, .. } => Body::BroadcastOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: msg_id,
+                },
+                Body::Read { msg_id } => Body::ReadOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: msg_id,
+                    messages: self.log.clone(),
+                },
+                Body::Topology { msg_id } => Body::TopologyOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: msg_id,
+                },
+                Body::Relay { msg_id, broadcast } => {
+                    let sequence = broadcast.clock.get(&broadcast.origin).copied();
+                    let delivered = self.delivered.get(&broadcast.origin).copied();
+                    // Retries of what we've delivered or are holding are acknowledged again,
+                    // but not kept twice
+                    let seen = sequence <= delivered || self.held.contains(&broadcast);
+                    if !seen {
+                        self.held.push(broadcast);
+                        self.deliver();
+                    }
+                    Body::RelayOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::RelayOk { in_reply_to, .. } => {
+                    self.unacked.remove(&in_reply_to);
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::Error { .. }
+                | Body::BroadcastOk { .. }
+                | Body::ReadOk { .. }
+                | Body::TopologyOk { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn cluster(count: usize) -> Vec<Node> {
+            let ids: Vec<String> = (1..=count).map(|i| format!("n{}", i)).collect();
+            ids.iter()
+                .map(|id| {
+                    let mut node = Node::new();
+                    node.handle_message(Message {
+                        src: "c1".into(),
+                        dest: id.clone(),
+                        body: Body::Init {
+                            msg_id: 1,
+                            node_id: id.clone(),
+                            node_ids: ids.clone(),
+                        },
+                    });
+                    node
+                })
+                .collect()
+        }
+
+        /// Broadcasts `message` from `node`, returning the relays it sends.
+        fn broadcast(node: &mut Node, message: usize) -> Vec<Message> {
+            let dest = node.id.clone();
+            let mut messages = node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body: Body::Broadcast { msg_id: 2, message },
+            });
+            assert!(matches!(messages.remove(0).body, Body::BroadcastOk { .. }));
+            messages
+        }
+
+        fn read(node: &mut Node) -> Vec<usize> {
+            let dest = node.id.clone();
+            let reply = node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body: Body::Read { msg_id: 9 },
+            });
+            let Body::ReadOk { ref messages, .. } = reply[0].body else {
+                panic!("expected read_ok");
+            };
+            messages.clone()
+        }
+
+        /// Delivers `message` to the node it's for, and any replies back.
+        fn deliver(nodes: &mut [Node], message: Message) {
+            let mut messages = vec![message];
+            while let Some(message) = messages.pop() {
+                let to = nodes.iter_mut().find(|n| n.id == message.dest).unwrap();
+                messages.extend(to.handle_message(message));
+            }
+        }
+
+        fn relay_to(relays: &mut Vec<Message>, dest: &str) -> Message {
+            let i = relays.iter().position(|m| m.dest == dest).unwrap();
+            relays.remove(i)
+        }
+
+        #[test]
+        fn test_broadcasts_wait_for_what_they_depend_on() {
+            let mut nodes = cluster(3);
+            let mut first = broadcast(&mut nodes[0], 1);
+            deliver(&mut nodes, relay_to(&mut first, "n2"));
+            // n2 has seen 1, so 2 depends on it
+            let mut second = broadcast(&mut nodes[1], 2);
+            assert_eq!(read(&mut nodes[1]), vec![1, 2]);
+
+            deliver(&mut nodes, relay_to(&mut second, "n3"));
+            assert_eq!(read(&mut nodes[2]), Vec::<usize>::new());
+            assert_eq!(nodes[2].held.len(), 1);
+            deliver(&mut nodes, relay_to(&mut first, "n3"));
+            assert_eq!(read(&mut nodes[2]), vec![1, 2]);
+            assert!(nodes[2].held.is_empty());
+        }
+
+        #[test]
+        fn test_concurrent_broadcasts_are_both_delivered() {
+            let mut nodes = cluster(2);
+            let from_n1 = broadcast(&mut nodes[0], 1);
+            let from_n2 = broadcast(&mut nodes[1], 2);
+            for message in from_n1.into_iter().chain(from_n2) {
+                deliver(&mut nodes, message);
+            }
+            assert_eq!(read(&mut nodes[0]), vec![1, 2]);
+            assert_eq!(read(&mut nodes[1]), vec![2, 1]);
+        }
+
+        #[test]
+        fn test_lost_relays_are_retried_once() {
+            let mut nodes = cluster(2);
+            // The first relay is lost
+            broadcast(&mut nodes[0], 1);
+            let retries = nodes[0].retry();
+            assert_eq!(retries.len(), 1);
+            for _ in 0..2 {
+                deliver(&mut nodes, retries[0].clone());
+            }
+            assert_eq!(read(&mut nodes[1]), vec![1]);
+            assert!(nodes[0].retry().is_empty());
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::retry(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,9 @@
+use causal_broadcast::node;
+use std::error::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    logging::init()?;
+    let config = node::Config::from_env();
+    maelstrom::run(node::Node::new(), config.retry_interval).await
+}
@@ -0,0 +1,890 @@
+mod detector {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    /// Suspects a node once it's gone `timeout` without being heard from. It can't tell a
+    /// dead node from a slow or cut-off one, so a suspicion can be wrong.
+    pub struct Detector {
+        id: String,
+        last_seen: HashMap<String, Instant>,
+        timeout: Duration,
+    }
+
+    impl Detector {
+        /// Starts out having just heard from every one of `nodes`.
+        pub fn new(id: &str, nodes: &[String], timeout: Duration) -> Self {
+            let now = Instant::now();
+            Detector {
+                id: id.to_string(),
+                last_seen: nodes.iter().map(|node| (node.clone(), now)).collect(),
+                timeout,
+            }
+        }
+
+        pub fn heard_from(&mut self, node: &str) {
+            if let Some(seen) = self.last_seen.get_mut(node) {
+                *seen = Instant::now();
+            }
+        }
+
+        pub fn is_alive(&self, node: &str) -> bool {
+            node == self.id
+                || self
+                    .last_seen
+                    .get(node)
+                    .is_some_and(|seen| seen.elapsed() < self.timeout)
+        }
+    }
+}
+
+pub mod node {
+    use super::detector::Detector;
+    use maelstrom::error::{
+        KEY_DOES_NOT_EXIST, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE, TIMEOUT,
+    };
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// A key/value store replicated down a chain of nodes. Writes enter at the head, which
+    /// numbers them, and each node applies them in order and passes them to its successor.
+    /// Once the tail has applied a write it's committed, and the tail's acknowledgement
+    /// travels back up the chain to the head, which answers the client. Reads are served by
+    /// the tail, which only ever holds committed writes. Other nodes forward each request to
+    /// whichever end serves it.
+    ///
+    /// The chain is every node in id order, less any our failure detector has suspected. A
+    /// suspected node is dropped for good, since one rejoining with stale state would break
+    /// the rule that each node has every write its successor has. When a node drops out, its
+    /// predecessor resends its successor every write not yet committed, and a node that
+    /// finds a gap in what it's sent asks for its predecessor's whole state instead.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        nodes: Vec<String>, // Every node, sorted, which is their order in the chain
+        detector: Detector,
+        removed: HashSet<String>,       // Nodes dropped from the chain
+        values: HashMap<String, Value>, // Keyed by each key's JSON, since keys can be any JSON
+        applied: u64,                   // How many writes have been applied here
+        committed: u64,                 // How many writes the tail is known to have applied
+        unacked: BTreeMap<u64, (String, Value)>, // Writes applied here but not yet committed
+        forwards: HashMap<u64, Waiting>, // Requests sent to the head or tail, by msg_id
+        pending: Vec<Pending>,          // Replies the head sends once writes commit
+    }
+
+    /// A client waiting on a reply to `msg_id`.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on answering
+    }
+
+    /// A reply the head sends once write `seq` is committed.
+    struct Pending {
+        seq: u64,
+        waiting: Waiting,
+        reply: Body,
+    }
+
+    /// Tunables, read from `CHAIN_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between ticks, which heartbeat, resend writes, and time out requests.
+        pub tick_interval: Duration,
+        /// How long a node goes unheard from before it's dropped from the chain.
+        pub failure_timeout: Duration,
+        /// How long a request waits on the chain, or on the head or tail if we forwarded it.
+        pub request_timeout: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                tick_interval: Duration::from_millis(100),
+                failure_timeout: Duration::from_millis(500),
+                request_timeout: Duration::from_millis(1000),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                tick_interval: Duration::from_millis(env_or(
+                    "CHAIN_TICK_INTERVAL_MS",
+                    default.tick_interval.as_millis() as u64,
+                )),
+                failure_timeout: Duration::from_millis(env_or(
+                    "CHAIN_FAILURE_TIMEOUT_MS",
+                    default.failure_timeout.as_millis() as u64,
+                )),
+                request_timeout: Duration::from_millis(env_or(
+                    "CHAIN_REQUEST_TIMEOUT_MS",
+                    default.request_timeout.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+            key: Value,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: Value,
+        },
+        Write {
+            msg_id: u64,
+            key: Value,
+            value: Value,
+        },
+        WriteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Sets `key` to `to` if it's `from`. A missing key fails, unless
+        /// `create_if_not_exists` is set, in which case it's created.
+        Cas {
+            msg_id: u64,
+            key: Value,
+            from: Value,
+            to: Value,
+            #[serde(default)]
+            create_if_not_exists: bool,
+        },
+        CasOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        Heartbeat {
+            msg_id: u64,
+        },
+        /// Write number `seq`, from our predecessor.
+        Propagate {
+            msg_id: u64,
+            seq: u64,
+            key: String,
+            value: Value,
+        },
+        /// The tail has applied every write up to `seq`, passed up from our successor.
+        Ack {
+            msg_id: u64,
+            seq: u64,
+        },
+        /// Our successor missed writes after the `applied`th, and wants our whole state.
+        Behind {
+            msg_id: u64,
+            applied: u64,
+        },
+        /// Our predecessor's whole state, as of its `applied`th write.
+        Sync {
+            msg_id: u64,
+            applied: u64,
+            values: HashMap<String, Value>,
+        },
+    }
+
+    impl Body {
+        /// The id of the request this replies to, if it's a reply to a client request.
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::ReadOk { in_reply_to, .. }
+                | Body::WriteOk { in_reply_to, .. }
+                | Body::CasOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::Read { msg_id, .. }
+                | Body::ReadOk { msg_id, .. }
+                | Body::Write { msg_id, .. }
+                | Body::WriteOk { msg_id, .. }
+                | Body::Cas { msg_id, .. }
+                | Body::CasOk { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            let detector = Detector::new("", &[], config.failure_timeout);
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                nodes: Vec::new(),
+                detector,
+                removed: HashSet::new(),
+                values: HashMap::new(),
+                applied: 0,
+                committed: 0,
+                unacked: BTreeMap::new(),
+                forwards: HashMap::new(),
+                pending: Vec::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "nodes": self.nodes,
+                "removed": self.removed,
+                "values": self.values.len(),
+                "applied": self.applied,
+                "committed": self.committed,
+                "unacked": self.unacked.len(),
+                "forwards": self.forwards.len(),
+                "pending": self.pending.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            // Peers can finish their init and start heartbeating before we get ours
+            if !self.initialized && matches!(message.body, Body::Heartbeat { .. }) {
+                return Vec::new();
+            }
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            self.detector.heard_from(&message.src);
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Heartbeats every peer, drops suspected nodes from the chain, and resends our
+        /// successor every write not yet committed; the tail instead tells its predecessor
+        /// how far it's got, which is how a new tail commits what it has. Requests that have
+        /// waited too long are timed out.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            for peer in self.nodes.clone() {
+                if peer == self.id {
+                    continue;
+                }
+                let msg_id = self.next_msg_id();
+                messages.push(self.message(peer, Body::Heartbeat { msg_id }));
+            }
+
+            for node in &self.nodes {
+                if !self.detector.is_alive(node) && self.removed.insert(node.clone()) {
+                    log::info!("Dropping {} from the chain", node);
+                }
+            }
+
+            match self.successor() {
+                Some(successor) => {
+                    let unacked: Vec<(u64, (String, Value))> = self
+                        .unacked
+                        .iter()
+                        .map(|(seq, write)| (*seq, write.clone()))
+                        .collect();
+                    for (seq, (key, value)) in unacked {
+                        let msg_id = self.next_msg_id();
+                        let propagate = Body::Propagate {
+                            msg_id,
+                            seq,
+                            key,
+                            value,
+                        };
+                        messages.push(self.message(successor.clone(), propagate));
+                    }
+                }
+                None => self.commit(self.applied, &mut messages),
+            }
+
+            let now = Instant::now();
+            let expired_forwards: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            let mut expired: Vec<Waiting> = expired_forwards
+                .iter()
+                .filter_map(|msg_id| self.forwards.remove(msg_id))
+                .collect();
+            let (late, pending) = std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|pending| pending.waiting.deadline <= now);
+            self.pending = pending;
+            expired.extend(late.into_iter().map(|pending: Pending| pending.waiting));
+            expired.sort_by_key(|waiting| waiting.msg_id);
+            for waiting in expired {
+                // It may yet commit, so this mustn't claim the request failed
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code: TIMEOUT,
+                    text: "timed out waiting on the chain".to_string(),
+                };
+                self.reply(waiting, error, &mut messages);
+            }
+            messages
+        }
+
+        fn message(&self, dest: String, body: Body) -> Message {
+            Message {
+                src: self.id.clone(),
+                dest,
+                body,
+            }
+        }
+
+        fn chain(&self) -> impl Iterator<Item = &String> {
+            self.nodes
+                .iter()
+                .filter(|node| !self.removed.contains(*node))
+        }
+
+        fn head(&self) -> Option<&String> {
+            self.chain().next()
+        }
+
+        fn tail(&self) -> Option<&String> {
+            self.chain().last()
+        }
+
+        fn predecessor(&self) -> Option<String> {
+            self.chain()
+                .take_while(|node| **node != self.id)
+                .last()
+                .cloned()
+        }
+
+        fn successor(&self) -> Option<String> {
+            self.chain()
+                .skip_while(|node| **node != self.id)
+                .nth(1)
+                .cloned()
+        }
+
+        /// Notes that the tail has applied the first `seq` writes, and passes that on up the
+        /// chain, or as head, answers the clients waiting on them. It's passed on even if we
+        /// knew, since the tail repeats it every tick in case it was lost on the way.
+        fn commit(&mut self, seq: u64, messages: &mut Vec<Message>) {
+            if seq > self.committed {
+                self.committed = seq;
+                self.unacked = self.unacked.split_off(&(seq + 1));
+            }
+            if let Some(predecessor) = self.predecessor() {
+                let msg_id = self.next_msg_id();
+                messages.push(self.message(predecessor, Body::Ack { msg_id, seq }));
+            }
+            let (ready, pending) = std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|pending| pending.seq <= seq);
+            self.pending = pending;
+            for pending in ready {
+                let Pending { waiting, reply, .. } = pending;
+                self.reply(waiting, reply, messages);
+            }
+        }
+
+        /// Applies write number `seq` and passes it on, or commits it if we're the tail.
+        fn apply(&mut self, seq: u64, key: String, value: Value, messages: &mut Vec<Message>) {
+            self.applied = seq;
+            self.values.insert(key.clone(), value.clone());
+            match self.successor() {
+                Some(successor) => {
+                    self.unacked.insert(seq, (key.clone(), value.clone()));
+                    let msg_id = self.next_msg_id();
+                    let propagate = Body::Propagate {
+                        msg_id,
+                        seq,
+                        key,
+                        value,
+                    };
+                    messages.push(self.message(successor, propagate));
+                }
+                None => self.commit(seq, messages),
+            }
+        }
+
+        /// Handles a write as head, answering once it's committed. Failed `cas`s wait too,
+        /// since what they saw may not be committed yet.
+        fn write(&mut self, waiting: Waiting, request: Body, messages: &mut Vec<Message>) {
+            let write = |key: &Value, value: Value| Some((key.to_string(), value));
+            let (write, reply) = match &request {
+                Body::Write { key, value, .. } => (
+                    write(key, value.clone()),
+                    Body::WriteOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    },
+                ),
+                Body::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                    ..
+                } => match self.values.get(&key.to_string()) {
+                    Some(value) if value != from => (
+                        None,
+                        Body::Error {
+                            in_reply_to: 0,
+                            code: PRECONDITION_FAILED,
+                            text: format!("expected {}, but {} is {}", from, key, value),
+                        },
+                    ),
+                    None if !create_if_not_exists => (
+                        None,
+                        Body::Error {
+                            in_reply_to: 0,
+                            code: KEY_DOES_NOT_EXIST,
+                            text: format!("{} doesn't exist", key),
+                        },
+                    ),
+                    _ => (
+                        write(key, to.clone()),
+                        Body::CasOk {
+                            msg_id: 0,
+                            in_reply_to: 0,
+                        },
+                    ),
+                },
+                _ => return,
+            };
+            if let Some((key, value)) = write {
+                self.apply(self.applied + 1, key, value, messages);
+            }
+            let pending = Pending {
+                seq: self.applied,
+                waiting,
+                reply,
+            };
+            if pending.seq <= self.committed {
+                self.reply(pending.waiting, pending.reply, messages);
+            } else {
+                self.pending.push(pending);
+            }
+        }
+
+        /// Handles a read as tail.
+        fn read(&mut self, waiting: Waiting, key: Value, messages: &mut Vec<Message>) {
+            let reply = match self.values.get(&key.to_string()) {
+                Some(value) => Body::ReadOk {
+                    msg_id: 0,
+                    in_reply_to: 0,
+                    value: value.clone(),
+                },
+                None => Body::Error {
+                    in_reply_to: 0,
+                    code: KEY_DOES_NOT_EXIST,
+                    text: format!("{} doesn't exist", key),
+                },
+            };
+            self.reply(waiting, reply, messages);
+        }
+
+        fn reply(&mut self, waiting: Waiting, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = waiting.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(self.message(waiting.client, body));
+        }
+
+        /// Serves a client's request if we're the end of the chain it goes to, or sends it on
+        /// there with an id of our own, so we can tell which client its reply is for. Requests
+        /// that already came from another node aren't forwarded again, so they can't go round
+        /// in circles.
+        fn request(&mut self, src: &str, mut body: Body, messages: &mut Vec<Message>) {
+            let Some(msg_id) = body.msg_id().copied() else {
+                return;
+            };
+            let waiting = Waiting {
+                client: src.to_string(),
+                msg_id,
+                deadline: Instant::now() + self.config.request_timeout,
+            };
+            let serves = match body {
+                Body::Read { .. } => self.tail(),
+                _ => self.head(),
+            };
+            let Some(serves) = serves.cloned() else {
+                return;
+            };
+            if serves == self.id {
+                return match body {
+                    Body::Read { key, .. } => self.read(waiting, key, messages),
+                    request => self.write(waiting, request, messages),
+                };
+            }
+            if self.nodes.iter().any(|node| node == src) {
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code: TEMPORARILY_UNAVAILABLE,
+                    text: "the chain is being repaired".to_string(),
+                };
+                return self.reply(waiting, error, messages);
+            }
+            let forward_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = forward_id;
+            }
+            self.forwards.insert(forward_id, waiting);
+            messages.push(self.message(serves, body));
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            messages: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    mut node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    node_ids.sort();
+                    self.detector = Detector::new(&node_id, &node_ids, self.config.failure_timeout);
+                    self.nodes = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Read { .. } | Body::Write { .. } | Body::Cas { .. } => {
+                    self.request(src, body, messages);
+                    None
+                }
+                Body::Heartbeat { .. } => None,
+                // Only from our predecessor, since a node that's been dropped may still think
+                // it's in the chain
+                Body::Propagate {
+                    msg_id,
+                    seq,
+                    key,
+                    value,
+                } if self.predecessor().as_deref() == Some(src) => {
+                    if seq == self.applied + 1 {
+                        self.apply(seq, key, value, messages);
+                    } else if seq > self.applied + 1 {
+                        return Some(Body::Behind {
+                            msg_id,
+                            applied: self.applied,
+                        });
+                    }
+                    None
+                }
+                Body::Ack { seq, .. } if self.successor().as_deref() == Some(src) => {
+                    self.commit(seq, messages);
+                    None
+                }
+                Body::Behind { applied, .. } if applied < self.applied => {
+                    let msg_id = self.next_msg_id();
+                    let sync = Body::Sync {
+                        msg_id,
+                        applied: self.applied,
+                        values: self.values.clone(),
+                    };
+                    messages.push(self.message(src.to_string(), sync));
+                    None
+                }
+                Body::Sync {
+                    applied, values, ..
+                } if self.predecessor().as_deref() == Some(src) && applied > self.applied => {
+                    self.values = values;
+                    self.applied = applied;
+                    // Our successor can't have had more than we did, so it's behind too
+                    match self.successor() {
+                        Some(successor) => {
+                            let msg_id = self.next_msg_id();
+                            let sync = Body::Sync {
+                                msg_id,
+                                applied,
+                                values: self.values.clone(),
+                            };
+                            messages.push(self.message(successor, sync));
+                        }
+                        None => self.commit(applied, messages),
+                    }
+                    None
+                }
+                Body::Propagate { .. }
+                | Body::Ack { .. }
+                | Body::Behind { .. }
+                | Body::Sync { .. } => None,
+                // The answer to a request we forwarded, which goes on to the client
+                mut reply => {
+                    let forward = self.forwards.remove(reply.in_reply_to()?)?;
+                    self.reply(forward, reply, messages);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        /// n1 to n3, with n1 the head and n3 the tail.
+        fn cluster() -> Vec<Node> {
+            let ids: Vec<String> = (1..=3).map(|i| format!("n{}", i)).collect();
+            ids.iter()
+                .map(|id| {
+                    let mut node = Node::new(Config::default());
+                    node.handle_message(Message {
+                        src: "c1".into(),
+                        dest: id.clone(),
+                        body: Body::Init {
+                            msg_id: 1,
+                            node_id: id.clone(),
+                            node_ids: ids.clone(),
+                        },
+                    });
+                    node
+                })
+                .collect()
+        }
+
+        /// Makes every node but `node` drop it from the chain.
+        fn fail(nodes: &mut [Node], node: &str) {
+            for other in nodes.iter_mut().filter(|n| n.id != node) {
+                let alive: Vec<String> = other
+                    .nodes
+                    .iter()
+                    .filter(|n| *n != node && !other.removed.contains(*n))
+                    .cloned()
+                    .collect();
+                other.detector = Detector::new(&other.id, &alive, other.config.failure_timeout);
+            }
+        }
+
+        /// Delivers messages until none are left, dropping any to nodes in `down`, and
+        /// returns what's sent to clients.
+        fn deliver(nodes: &mut [Node], mut messages: Vec<Message>, down: &[&str]) -> Vec<Body> {
+            let mut replies = Vec::new();
+            while !messages.is_empty() {
+                let message = messages.remove(0);
+                if down.contains(&message.dest.as_str()) {
+                    continue;
+                }
+                match nodes.iter_mut().find(|n| n.id == message.dest) {
+                    Some(node) => messages.extend(node.handle_message(message)),
+                    None => replies.push(message.body),
+                }
+            }
+            replies
+        }
+
+        /// Ticks every node that isn't down, delivering what they send.
+        fn tick(nodes: &mut [Node], down: &[&str]) -> Vec<Body> {
+            let sent: Vec<Message> = nodes
+                .iter_mut()
+                .filter(|n| !down.contains(&n.id.as_str()))
+                .flat_map(|n| n.tick())
+                .collect();
+            deliver(nodes, sent, down)
+        }
+
+        /// Sends `node` a request from a client, returning what it sends.
+        fn request(node: &mut Node, body: Body) -> Vec<Message> {
+            let dest = node.id.clone();
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body,
+            })
+        }
+
+        fn write(key: Value, value: Value) -> Body {
+            Body::Write {
+                msg_id: 2,
+                key,
+                value,
+            }
+        }
+
+        fn read(key: Value) -> Body {
+            Body::Read { msg_id: 3, key }
+        }
+
+        #[test]
+        fn test_writes_commit_at_the_tail() {
+            let mut nodes = cluster();
+            // Sent on to the head, then down the chain
+            let sent = request(&mut nodes[1], write(json!(1), json!(10)));
+            assert_eq!(sent[0].dest, "n1");
+            let replies = deliver(&mut nodes, sent, &[]);
+            assert!(matches!(
+                replies[..],
+                [Body::WriteOk { in_reply_to: 2, .. }]
+            ));
+            assert!(nodes
+                .iter()
+                .all(|n| n.committed == 1 && n.unacked.is_empty()));
+
+            let sent = request(&mut nodes[0], read(json!(1)));
+            assert_eq!(sent[0].dest, "n3");
+            let replies = deliver(&mut nodes, sent, &[]);
+            assert!(matches!(&replies[..], [Body::ReadOk { value, .. }] if *value == json!(10)));
+
+            let cas = Body::Cas {
+                msg_id: 4,
+                key: json!(1),
+                from: json!(5),
+                to: json!(6),
+                create_if_not_exists: false,
+            };
+            let sent = request(&mut nodes[2], cas);
+            let replies = deliver(&mut nodes, sent, &[]);
+            assert!(matches!(
+                replies[..],
+                [Body::Error {
+                    code: PRECONDITION_FAILED,
+                    ..
+                }]
+            ));
+        }
+
+        #[test]
+        fn test_chain_repairs_around_a_failed_middle() {
+            let mut nodes = cluster();
+            // n2 dies having applied the write, but before passing it on
+            let sent = request(&mut nodes[0], write(json!(1), json!(10)));
+            nodes[1].handle_message(sent[0].clone());
+            fail(&mut nodes, "n2");
+
+            // n1 resends it to n3, its new successor
+            let replies = tick(&mut nodes, &["n2"]);
+            assert!(matches!(replies[..], [Body::WriteOk { .. }]));
+            assert_eq!(nodes[2].values[&"1".to_string()], json!(10));
+
+            let sent = request(&mut nodes[0], write(json!(2), json!(20)));
+            let replies = deliver(&mut nodes, sent, &["n2"]);
+            assert!(matches!(replies[..], [Body::WriteOk { .. }]));
+            // n2 is out of the chain for good, even once it's heard from again
+            let sent = nodes[1].tick();
+            deliver(&mut nodes, sent, &[]);
+            tick(&mut nodes, &[]);
+            assert_eq!(nodes[0].successor().as_deref(), Some("n3"));
+        }
+
+        #[test]
+        fn test_new_tail_commits_what_it_has() {
+            let mut nodes = cluster();
+            // n3 dies before the write reaches it
+            let sent = request(&mut nodes[0], write(json!(1), json!(10)));
+            assert!(deliver(&mut nodes, sent, &["n3"]).is_empty());
+            fail(&mut nodes, "n3");
+
+            let replies = tick(&mut nodes, &["n3"]);
+            assert!(matches!(replies[..], [Body::WriteOk { .. }]));
+            let sent = request(&mut nodes[0], read(json!(1)));
+            assert_eq!(sent[0].dest, "n2");
+        }
+
+        #[test]
+        fn test_successors_with_gaps_catch_up() {
+            let mut nodes = cluster();
+            // n2 misses the first write, so the second has it ask for n1's state, which goes
+            // on down the chain
+            let sent = request(&mut nodes[0], write(json!(1), json!(10)));
+            assert_eq!(sent.len(), 1);
+            let sent = request(&mut nodes[0], write(json!(2), json!(20)));
+            let replies = deliver(&mut nodes, sent, &[]);
+            assert_eq!(replies.len(), 2);
+            assert!(nodes.iter().all(|n| n.applied == 2 && n.committed == 2));
+            assert_eq!(nodes[2].values[&"1".to_string()], json!(10));
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,415 @@
+pub mod node {
+    use crdt::{Delta, Merge, Rga};
+    use maelstrom::error::PRECONDITION_FAILED;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    /// A text document clients can edit at any node, inserting and deleting characters by
+    /// position, replicated as an `Rga`. Edits are made against the document as this node has
+    /// it, and concurrent edits elsewhere never move them: an insert stays next to the
+    /// character it was made after, and a delete only ever removes the character it meant
+    /// to. Each node gossips every peer the part of the document it hasn't yet acknowledged,
+    /// so reads anywhere converge once edits stop.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        peers: Vec<String>,
+        document: Rga<char>,
+        known: HashMap<String, Rga<char>>, // What each peer is known to have merged
+        in_flight: HashMap<u64, (String, Rga<char>)>, // The latest gossip to each peer, by msg_id
+    }
+
+    /// Tunables, read from `TEXT_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between gossip rounds.
+        pub gossip_interval: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                gossip_interval: Duration::from_millis(100),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                gossip_interval: Duration::from_millis(env_or(
+                    "TEXT_GOSSIP_INTERVAL_MS",
+                    default.gossip_interval.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Inserts `text` so it starts at character `index`.
+        Insert {
+            msg_id: u64,
+            index: usize,
+            text: String,
+        },
+        InsertOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Deletes `count` characters from character `index` on.
+        Delete {
+            msg_id: u64,
+            index: usize,
+            #[serde(default = "one")]
+            count: usize,
+        },
+        DeleteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: String,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// The part of the document the sender thinks we're missing.
+        Gossip {
+            msg_id: u64,
+            document: Rga<char>,
+        },
+        /// The gossip has been merged.
+        GossipOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+    }
+
+    fn one() -> usize {
+        1
+    }
+
+    impl Default for Node {
+        fn default() -> Self {
+            Node::new()
+        }
+    }
+
+    impl Node {
+        pub fn new() -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                peers: Vec::new(),
+                document: Rga::default(),
+                known: HashMap::new(),
+                in_flight: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "document": self.document.iter().count(),
+                "in_flight": self.in_flight.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let Some(body) = self.handle_body(&message.src, message.body) else {
+                return Vec::new();
+            };
+            self.cur_id += 1;
+            vec![Message {
+                src: message.dest,
+                dest: message.src,
+                body,
+            }]
+        }
+
+        /// Sends each peer what it's missing. Anything a peer doesn't acknowledge is sent
+        /// again next round, so lost gossip only delays convergence.
+        pub fn gossip(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            for peer in self.peers.clone() {
+                let known = self.known.entry(peer.clone()).or_default();
+                let Some(delta) = self.document.delta_since(known) else {
+                    continue;
+                };
+                // Only the latest gossip needs acknowledging, since it has all the earlier ones
+                self.in_flight.retain(|_, (to, _)| *to != peer);
+                let msg_id = self.next_msg_id();
+                self.in_flight.insert(msg_id, (peer.clone(), delta.clone()));
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Gossip {
+                        msg_id,
+                        document: delta,
+                    },
+                });
+            }
+            messages
+        }
+
+        fn handle_body(&mut self, src: &str, body: Body) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Insert {
+                    msg_id,
+                    index,
+                    text,
+                } => {
+                    let len = self.document.len();
+                    if index > len {
+                        return Some(Body::Error {
+                            in_reply_to: msg_id,
+                            code: PRECONDITION_FAILED,
+                            text: format!("can't insert at {} in {} characters", index, len),
+                        });
+                    }
+                    for (i, c) in text.chars().enumerate() {
+                        self.document.insert(&self.id, index + i, c);
+                    }
+                    Body::InsertOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Delete {
+                    msg_id,
+                    index,
+                    count,
+                } => {
+                    let len = self.document.len();
+                    if index + count > len {
+                        return Some(Body::Error {
+                            in_reply_to: msg_id,
+                            code: PRECONDITION_FAILED,
+                            text: format!(
+                                "can't delete {}..{} of {} characters",
+                                index,
+                                index + count,
+                                len
+                            ),
+                        });
+                    }
+                    for _ in 0..count {
+                        self.document.delete(index);
+                    }
+                    Body::DeleteOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Read { msg_id } => Body::ReadOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: msg_id,
+                    value: self.document.iter().collect(),
+                },
+                Body::Gossip { msg_id, document } => {
+                    self.document.merge(&document);
+                    // Whatever it sent us, it has
+                    self.known
+                        .entry(src.to_string())
+                        .or_default()
+                        .merge(&document);
+                    Body::GossipOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::GossipOk { in_reply_to, .. } => {
+                    let (peer, sent) = self.in_flight.remove(&in_reply_to)?;
+                    self.known.entry(peer).or_default().merge(&sent);
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::InsertOk { .. }
+                | Body::DeleteOk { .. }
+                | Body::ReadOk { .. }
+                | Body::Error { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, exchange};
+
+        fn init(id: &str) -> Node {
+            testing::init(Node::new(), id, 2)
+        }
+
+        fn send(node: &mut Node, body: Body) -> Body {
+            let dest = node.id.clone();
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body,
+            })
+            .remove(0)
+            .body
+        }
+
+        fn read(node: &mut Node) -> String {
+            let Body::ReadOk { value, .. } = send(node, Body::Read { msg_id: 9 }) else {
+                panic!("expected read_ok");
+            };
+            value
+        }
+
+        fn insert(node: &mut Node, index: usize, text: &str) -> Body {
+            let text = text.to_string();
+            send(
+                node,
+                Body::Insert {
+                    msg_id: 2,
+                    index,
+                    text,
+                },
+            )
+        }
+
+        fn delete(node: &mut Node, index: usize, count: usize) -> Body {
+            send(
+                node,
+                Body::Delete {
+                    msg_id: 3,
+                    index,
+                    count,
+                },
+            )
+        }
+
+        #[test]
+        fn test_concurrent_edits_converge() {
+            let (mut n1, mut n2) = (init("n1"), init("n2"));
+            insert(&mut n1, 0, "helo");
+            exchange(&mut n1, &mut n2);
+
+            // n1 fixes the typo while n2 adds to the end and deletes the h
+            insert(&mut n1, 3, "l");
+            insert(&mut n2, 4, " world");
+            delete(&mut n2, 0, 1);
+            assert_eq!(read(&mut n2), "elo world");
+
+            exchange(&mut n1, &mut n2);
+            exchange(&mut n2, &mut n1);
+            assert_eq!(read(&mut n1), "ello world");
+            assert_eq!(read(&mut n2), "ello world");
+            // Both sides know the other is caught up, so there's nothing left to send
+            assert!(n1.gossip().is_empty());
+            assert!(n2.gossip().is_empty());
+        }
+
+        #[test]
+        fn test_edits_past_the_end_are_refused() {
+            let mut n1 = init("n1");
+            insert(&mut n1, 0, "ab");
+            let refused = [insert(&mut n1, 3, "c"), delete(&mut n1, 1, 2)];
+            for reply in refused {
+                assert!(matches!(
+                    reply,
+                    Body::Error {
+                        code: PRECONDITION_FAILED,
+                        ..
+                    }
+                ));
+            }
+            assert!(matches!(delete(&mut n1, 0, 2), Body::DeleteOk { .. }));
+            assert_eq!(read(&mut n1), "");
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::gossip(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,9 @@
+use collaborative_text::node;
+use std::error::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    logging::init()?;
+    let config = node::Config::from_env();
+    maelstrom::run(node::Node::new(), config.gossip_interval).await
+}
@@ -0,0 +1,49 @@
+//! Packs large payloads into compact strings, so they can ride inside a Maelstrom message
+//! without blowing up its line size.
+
+use base64::engine::general_purpose::STANDARD;
+use base64::Engine;
+use flate2::read::DeflateDecoder;
+use flate2::write::DeflateEncoder;
+use flate2::Compression;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use std::io::{self, Read, Write};
+
+/// Serializes `value` as JSON, deflates it and base64-encodes the result.
+pub fn pack<T: Serialize>(value: &T) -> io::Result<String> {
+    let mut encoder = DeflateEncoder::new(Vec::new(), Compression::default());
+    serde_json::to_writer(&mut encoder, value)?;
+    encoder.flush()?;
+    Ok(STANDARD.encode(encoder.finish()?))
+}
+
+/// Reverses `pack`.
+pub fn unpack<T: DeserializeOwned>(packed: &str) -> io::Result<T> {
+    let bytes = STANDARD
+        .decode(packed)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    let mut json = Vec::new();
+    DeflateDecoder::new(bytes.as_slice()).read_to_end(&mut json)?;
+    Ok(serde_json::from_slice(&json)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[test]
+    fn test_round_trip() {
+        let value: HashMap<String, u64> = (0..500).map(|i| (format!("n{}", i), i)).collect();
+        let packed = pack(&value).unwrap();
+        assert!(packed.len() < serde_json::to_string(&value).unwrap().len());
+        assert_eq!(unpack::<HashMap<String, u64>>(&packed).unwrap(), value);
+    }
+
+    #[test]
+    fn test_garbage_is_an_error() {
+        assert!(unpack::<Vec<u64>>("not base64!").is_err());
+        assert!(unpack::<Vec<u64>>(&STANDARD.encode(b"not deflate")).is_err());
+    }
+}
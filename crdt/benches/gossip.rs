@@ -0,0 +1,90 @@
+//! What gossip costs on the wire: serializing a full state, parsing one back, and working out
+//! and serializing the delta a peer is missing, across state sizes.
+
+use crdt::{Delta, GCounter, OrMap, OrSet};
+use criterion::{black_box, criterion_group, criterion_main, BenchmarkId, Criterion};
+
+const SIZES: [usize; 3] = [100, 1_000, 10_000];
+const NODES: [&str; 5] = ["n1", "n2", "n3", "n4", "n5"];
+
+/// An or-set of `size` elements added round-robin across the nodes, with every tenth removed.
+fn or_set(size: usize) -> OrSet<u64> {
+    let mut set = OrSet::default();
+    for i in 0..size as u64 {
+        set.add(NODES[i as usize % NODES.len()], i);
+    }
+    for i in (0..size as u64).step_by(10) {
+        set.remove(&i);
+    }
+    set
+}
+
+fn bench_or_set(c: &mut Criterion) {
+    let mut group = c.benchmark_group("or_set");
+    for size in SIZES {
+        let set = or_set(size);
+        let json = serde_json::to_vec(&set).unwrap();
+        // A peer that's missing the last tenth of the adds
+        let known = or_set(size - size / 10);
+
+        group.bench_with_input(BenchmarkId::new("serialize", size), &set, |b, set| {
+            b.iter(|| serde_json::to_vec(black_box(set)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("deserialize", size), &json, |b, json| {
+            b.iter(|| serde_json::from_slice::<OrSet<u64>>(black_box(json)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("delta", size), &set, |b, set| {
+            b.iter(|| serde_json::to_vec(&set.delta_since(black_box(&known))).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_or_map(c: &mut Criterion) {
+    let mut group = c.benchmark_group("or_map");
+    for size in SIZES {
+        // Ten items in each cart
+        let mut map = OrMap::default();
+        for i in 0..size {
+            map.add(
+                NODES[i % NODES.len()],
+                format!("cart-{}", i / 10),
+                i.to_string(),
+            );
+        }
+        let json = serde_json::to_vec(&map).unwrap();
+
+        group.bench_with_input(BenchmarkId::new("serialize", size), &map, |b, map| {
+            b.iter(|| serde_json::to_vec(black_box(map)).unwrap())
+        });
+        group.bench_with_input(BenchmarkId::new("deserialize", size), &json, |b, json| {
+            b.iter(|| serde_json::from_slice::<OrMap<String, String>>(black_box(json)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+fn bench_g_counter(c: &mut Criterion) {
+    let mut group = c.benchmark_group("g_counter");
+    // Counters only grow with the cluster, so these are node counts
+    for nodes in [5, 25, 125] {
+        let mut counter = GCounter::default();
+        for i in 0..nodes {
+            counter.increment(&format!("n{}", i), i as u64 + 1);
+        }
+        let json = serde_json::to_vec(&counter).unwrap();
+
+        group.bench_with_input(
+            BenchmarkId::new("serialize", nodes),
+            &counter,
+            |b, counter| b.iter(|| serde_json::to_vec(black_box(counter)).unwrap()),
+        );
+        group.bench_with_input(BenchmarkId::new("deserialize", nodes), &json, |b, json| {
+            b.iter(|| serde_json::from_slice::<GCounter>(black_box(json)).unwrap())
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_or_set, bench_or_map, bench_g_counter);
+criterion_main!(benches);
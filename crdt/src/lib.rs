@@ -0,0 +1,1331 @@
+//! State-based CRDTs shared by the workloads that replicate by merging state rather than
+//! ordering operations.
+
+use serde::{Deserialize, Serialize};
+use std::cmp::Ordering;
+use std::collections::{HashMap, HashSet};
+use std::hash::Hash;
+
+/// A replicated value that converges however merges are ordered or repeated: merging is
+/// commutative, associative and idempotent.
+pub trait Merge {
+    /// Folds `other` into `self`, returning whether that changed anything.
+    fn merge(&mut self, other: &Self) -> bool;
+}
+
+/// A replicated value that can tell a peer what it's missing, so gossip only has to carry
+/// the difference.
+pub trait Delta: Sized {
+    /// The part of `self` someone who knows `known` is missing, or `None` if they have it all.
+    /// Merging the result into `known` catches it up with `self`.
+    fn delta_since(&self, known: &Self) -> Option<Self>;
+}
+
+/// A grow-only counter: each node only ever increases its own entry, so merging takes the
+/// larger of each entry, and the value is their sum.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+#[serde(transparent)]
+pub struct GCounter {
+    counts: HashMap<String, u64>,
+}
+
+impl GCounter {
+    /// Adds `amount` to `node`'s entry, or returns false and leaves it alone if that would
+    /// overflow.
+    pub fn increment(&mut self, node: &str, amount: u64) -> bool {
+        let count = self.counts.entry(node.to_string()).or_default();
+        match count.checked_add(amount) {
+            Some(sum) => {
+                *count = sum;
+                true
+            }
+            None => false,
+        }
+    }
+
+    /// `node`'s entry, 0 if it has none.
+    pub fn get(&self, node: &str) -> u64 {
+        self.counts.get(node).copied().unwrap_or_default()
+    }
+
+    /// The sum of every entry, which a u128 holds for any number of nodes we'd run.
+    pub fn value(&self) -> u128 {
+        self.counts.values().map(|count| *count as u128).sum()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.counts
+            .iter()
+            .map(|(node, count)| (node.as_str(), *count))
+    }
+
+    /// Makes `node`'s entry whatever it is in `other`, even if that's lower or missing.
+    fn replace_entry(&mut self, node: &str, other: &GCounter) {
+        match other.counts.get(node) {
+            Some(count) => self.counts.insert(node.to_string(), *count),
+            None => self.counts.remove(node),
+        };
+    }
+
+    /// Whether `node`'s entry is higher here than in `known`, or only here.
+    fn ahead_of(&self, node: &str, known: &GCounter) -> bool {
+        self.counts
+            .get(node)
+            .is_some_and(|count| known.counts.get(node).is_none_or(|known| known < count))
+    }
+
+    /// Raises `node`'s entry to what it is in `other`, returning whether that changed it.
+    fn merge_entry(&mut self, node: &str, other: &GCounter) -> bool {
+        if !other.ahead_of(node, self) {
+            return false;
+        }
+        self.replace_entry(node, other);
+        true
+    }
+}
+
+impl Delta for GCounter {
+    /// The entries `known` doesn't already have at the same value or higher.
+    fn delta_since(&self, known: &Self) -> Option<Self> {
+        let delta = GCounter {
+            counts: self
+                .counts
+                .iter()
+                .filter(|(node, count)| known.counts.get(*node).is_none_or(|known| known < count))
+                .map(|(node, count)| (node.clone(), *count))
+                .collect(),
+        };
+        (!delta.is_empty()).then_some(delta)
+    }
+}
+
+impl Merge for GCounter {
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (node, count) in &other.counts {
+            let ours = self.counts.entry(node.clone()).or_default();
+            if *count > *ours {
+                *ours = *count;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// A counter that can go down too, as a pair of grow-only counters: one for increments and
+/// one for decrements. Its value is their difference.
+///
+/// Each node also stamps its entries with a version it bumps on every add, and merging takes
+/// the entries with the higher version as they are rather than the larger of each. Counts
+/// only grow, so for them that's the same thing, but it means replayed or reordered state can
+/// never take an entry backwards whatever the entries hold. Entries at the same version,
+/// such as ones saved before there were versions, are merged by taking the larger.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct PnCounter {
+    increments: GCounter,
+    decrements: GCounter,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    versions: HashMap<String, u64>,
+}
+
+impl PnCounter {
+    /// Adds `delta` to `node`'s increments or decrements, by its sign, or returns false and
+    /// leaves the counter alone if that would overflow.
+    pub fn add(&mut self, node: &str, delta: i64) -> bool {
+        let added = if delta >= 0 {
+            self.increments.increment(node, delta as u64)
+        } else {
+            self.decrements.increment(node, delta.unsigned_abs())
+        };
+        if added && delta != 0 {
+            *self.versions.entry(node.to_string()).or_default() += 1;
+        }
+        added
+    }
+
+    /// How many times `node` has changed its entries, 0 if it never has.
+    pub fn version(&self, node: &str) -> u64 {
+        self.versions.get(node).copied().unwrap_or_default()
+    }
+
+    pub fn versions(&self) -> impl Iterator<Item = (&str, u64)> {
+        self.versions
+            .iter()
+            .map(|(node, version)| (node.as_str(), *version))
+    }
+
+    pub fn increments(&self) -> &GCounter {
+        &self.increments
+    }
+
+    pub fn decrements(&self) -> &GCounter {
+        &self.decrements
+    }
+
+    /// Everything added minus everything subtracted. Can't overflow, since both sides fit in
+    /// a u128 with room to spare.
+    pub fn value(&self) -> i128 {
+        self.increments.value() as i128 - self.decrements.value() as i128
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.increments.is_empty() && self.decrements.is_empty()
+    }
+
+    /// Only `node`'s entries, e.g. to persist a node's own contribution.
+    pub fn only(&self, node: &str) -> PnCounter {
+        let mut counter = PnCounter::default();
+        counter.replace_entries(node, self);
+        counter
+    }
+
+    /// Every node with entries here.
+    fn nodes(&self) -> HashSet<&str> {
+        self.increments
+            .counts
+            .keys()
+            .chain(self.decrements.counts.keys())
+            .map(String::as_str)
+            .collect()
+    }
+
+    /// Makes `node`'s entries and version whatever they are in `other`.
+    fn replace_entries(&mut self, node: &str, other: &PnCounter) {
+        self.increments.replace_entry(node, &other.increments);
+        self.decrements.replace_entry(node, &other.decrements);
+        match other.versions.get(node) {
+            Some(version) => self.versions.insert(node.to_string(), *version),
+            None => self.versions.remove(node),
+        };
+    }
+}
+
+impl Merge for PnCounter {
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for node in other.nodes() {
+            match other.version(node).cmp(&self.version(node)) {
+                Ordering::Greater => {
+                    self.replace_entries(node, other);
+                    changed = true;
+                }
+                Ordering::Equal => {
+                    let increments = self.increments.merge_entry(node, &other.increments);
+                    let decrements = self.decrements.merge_entry(node, &other.decrements);
+                    changed |= increments || decrements;
+                }
+                Ordering::Less => {}
+            }
+        }
+        changed
+    }
+}
+
+impl Delta for PnCounter {
+    /// The entries of every node at a later version than in `known`, and at the same version,
+    /// whichever entries are larger.
+    fn delta_since(&self, known: &Self) -> Option<Self> {
+        let mut delta = PnCounter::default();
+        for node in self.nodes() {
+            match self.version(node).cmp(&known.version(node)) {
+                Ordering::Greater => delta.replace_entries(node, self),
+                Ordering::Equal => {
+                    if self.increments.ahead_of(node, &known.increments) {
+                        delta.increments.replace_entry(node, &self.increments);
+                    }
+                    if self.decrements.ahead_of(node, &known.decrements) {
+                        delta.decrements.replace_entry(node, &self.decrements);
+                    }
+                }
+                Ordering::Less => {}
+            }
+        }
+        (!delta.is_empty()).then_some(delta)
+    }
+}
+
+/// A value that can be reset everywhere by starting a new epoch. A later epoch replaces an
+/// earlier one outright, whatever either holds, and values in the same epoch merge as usual,
+/// so anything still being gossiped from before a reset is dropped rather than merged back.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq)]
+pub struct Epoch<T> {
+    epoch: u64,
+    value: T,
+}
+
+impl<T: Default> Epoch<T> {
+    pub fn new(epoch: u64, value: T) -> Self {
+        Epoch { epoch, value }
+    }
+
+    pub fn epoch(&self) -> u64 {
+        self.epoch
+    }
+
+    pub fn get(&self) -> &T {
+        &self.value
+    }
+
+    pub fn get_mut(&mut self) -> &mut T {
+        &mut self.value
+    }
+
+    /// Starts the next epoch with an empty value.
+    pub fn reset(&mut self) {
+        self.epoch += 1;
+        self.value = T::default();
+    }
+}
+
+impl<T: Merge + Clone> Merge for Epoch<T> {
+    fn merge(&mut self, other: &Self) -> bool {
+        match other.epoch.cmp(&self.epoch) {
+            Ordering::Greater => {
+                *self = other.clone();
+                true
+            }
+            Ordering::Equal => self.value.merge(&other.value),
+            Ordering::Less => false,
+        }
+    }
+}
+
+impl<T: Delta + Clone> Delta for Epoch<T> {
+    /// Everything, if `known` is from an earlier epoch; nothing, if it's from a later one.
+    fn delta_since(&self, known: &Self) -> Option<Self> {
+        match self.epoch.cmp(&known.epoch) {
+            Ordering::Greater => Some(self.clone()),
+            Ordering::Equal => self.value.delta_since(&known.value).map(|value| Epoch {
+                epoch: self.epoch,
+                value,
+            }),
+            Ordering::Less => None,
+        }
+    }
+}
+
+/// A unique tag for one add to an `OrSet`: the node that made it, and how many adds that
+/// node had made by then.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Tag(String, u64);
+
+/// An observed-remove set. Every add tags its element uniquely, and a remove tombstones only
+/// the tags it has seen, so an add concurrent with a remove survives it: the remove can't have
+/// meant to undo an add it never saw. Last-writer-wins would pick between them by timestamp
+/// instead, so with clocks skewed, a remove could undo an add made after it.
+///
+/// Tombstones are kept for good. Without them, an add arriving late, after the remove that
+/// undid it, would be merged straight back in.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(bound(deserialize = "T: Deserialize<'de> + Eq + Hash"))]
+pub struct OrSet<T> {
+    adds: HashMap<T, HashSet<Tag>>, // Each element present, by the tags of its live adds
+    tombstones: HashSet<Tag>,
+    clock: HashMap<String, u64>, // How many adds each node is known to have made
+}
+
+impl<T: Eq + Hash> PartialEq for OrSet<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.adds == other.adds && self.tombstones == other.tombstones && self.clock == other.clock
+    }
+}
+
+impl<T> Default for OrSet<T> {
+    fn default() -> Self {
+        OrSet {
+            adds: HashMap::new(),
+            tombstones: HashSet::new(),
+            clock: HashMap::new(),
+        }
+    }
+}
+
+impl<T: Eq + Hash + Clone> OrSet<T> {
+    /// Adds `element` under a new tag from `node`.
+    pub fn add(&mut self, node: &str, element: T) -> Tag {
+        let seq = self.clock.entry(node.to_string()).or_default();
+        *seq += 1;
+        let tag = Tag(node.to_string(), *seq);
+        self.adds.entry(element).or_default().insert(tag.clone());
+        tag
+    }
+
+    /// Removes `element` by tombstoning every tag it has here, returning whether it was
+    /// present.
+    pub fn remove(&mut self, element: &T) -> bool {
+        let Some(tags) = self.adds.remove(element) else {
+            return false;
+        };
+        self.tombstones.extend(tags);
+        true
+    }
+
+    pub fn contains(&self, element: &T) -> bool {
+        self.adds.contains_key(element)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        self.adds.keys()
+    }
+
+    pub fn len(&self) -> usize {
+        self.adds.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.adds.is_empty()
+    }
+
+    /// How many removed adds are remembered.
+    pub fn tombstones(&self) -> usize {
+        self.tombstones.len()
+    }
+}
+
+impl<T: Eq + Hash + Clone> Merge for OrSet<T> {
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for tag in &other.tombstones {
+            changed |= self.tombstones.insert(tag.clone());
+        }
+        if changed {
+            for tags in self.adds.values_mut() {
+                tags.retain(|tag| !self.tombstones.contains(tag));
+            }
+            self.adds.retain(|_, tags| !tags.is_empty());
+        }
+        for (element, tags) in &other.adds {
+            for tag in tags {
+                if !self.tombstones.contains(tag) {
+                    let ours = self.adds.entry(element.clone()).or_default();
+                    changed |= ours.insert(tag.clone());
+                }
+            }
+        }
+        for (node, seq) in &other.clock {
+            let ours = self.clock.entry(node.clone()).or_default();
+            if *seq > *ours {
+                *ours = *seq;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+impl<T: Eq + Hash + Clone> Delta for OrSet<T> {
+    /// The adds `known` has neither seen nor removed, and the tombstones it doesn't have.
+    fn delta_since(&self, known: &Self) -> Option<Self> {
+        let mut delta = OrSet::default();
+        for (element, tags) in &self.adds {
+            let known_tags = known.adds.get(element);
+            let missing: HashSet<Tag> = tags
+                .iter()
+                .filter(|tag| known_tags.is_none_or(|known_tags| !known_tags.contains(*tag)))
+                .filter(|tag| !known.tombstones.contains(*tag))
+                .cloned()
+                .collect();
+            if !missing.is_empty() {
+                delta.adds.insert(element.clone(), missing);
+            }
+        }
+        delta.tombstones = self
+            .tombstones
+            .difference(&known.tombstones)
+            .cloned()
+            .collect();
+        delta.clock = self
+            .clock
+            .iter()
+            .filter(|(node, seq)| known.clock.get(*node).is_none_or(|known| known < seq))
+            .map(|(node, seq)| (node.clone(), *seq))
+            .collect();
+        let empty = delta.adds.is_empty() && delta.tombstones.is_empty() && delta.clock.is_empty();
+        (!empty).then_some(delta)
+    }
+}
+
+/// An observed-remove map of sets, like a replicated shopping cart: each key holds an
+/// `OrSet`, and clearing a key removes only the elements it has seen there, so an element
+/// added concurrently elsewhere keeps the key in the map. A key is in the map for as long
+/// as its set has anything in it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+#[serde(bound(
+    serialize = "K: Serialize, T: Serialize",
+    deserialize = "K: Deserialize<'de> + Eq + Hash, T: Deserialize<'de> + Eq + Hash"
+))]
+pub struct OrMap<K, T> {
+    entries: HashMap<K, OrSet<T>>, // Kept once emptied, for their tombstones
+}
+
+impl<K: Eq + Hash, T: Eq + Hash> PartialEq for OrMap<K, T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl<K, T> Default for OrMap<K, T> {
+    fn default() -> Self {
+        OrMap {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, T: Eq + Hash + Clone> OrMap<K, T> {
+    /// Adds `element` to the set at `key` under a new tag from `node`.
+    pub fn add(&mut self, node: &str, key: K, element: T) -> Tag {
+        self.entries.entry(key).or_default().add(node, element)
+    }
+
+    /// Removes `element` from the set at `key`, returning whether it was present.
+    pub fn remove(&mut self, key: &K, element: &T) -> bool {
+        self.entries
+            .get_mut(key)
+            .is_some_and(|set| set.remove(element))
+    }
+
+    /// Removes every element seen at `key`, returning whether there were any.
+    pub fn clear(&mut self, key: &K) -> bool {
+        let Some(set) = self.entries.get_mut(key) else {
+            return false;
+        };
+        let elements: Vec<T> = set.iter().cloned().collect();
+        elements
+            .iter()
+            .fold(false, |removed, element| set.remove(element) | removed)
+    }
+
+    /// The set at `key`, if it has anything in it.
+    pub fn get(&self, key: &K) -> Option<&OrSet<T>> {
+        self.entries.get(key).filter(|set| !set.is_empty())
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &OrSet<T>)> {
+        self.entries.iter().filter(|(_, set)| !set.is_empty())
+    }
+
+    pub fn len(&self) -> usize {
+        self.iter().count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+}
+
+impl<K: Eq + Hash + Clone, T: Eq + Hash + Clone> Merge for OrMap<K, T> {
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (key, set) in &other.entries {
+            changed |= self.entries.entry(key.clone()).or_default().merge(set);
+        }
+        changed
+    }
+}
+
+impl<K: Eq + Hash + Clone, T: Eq + Hash + Clone> Delta for OrMap<K, T> {
+    /// Each key's part of its set `known` is missing.
+    fn delta_since(&self, known: &Self) -> Option<Self> {
+        let nothing = OrSet::default();
+        let entries: HashMap<K, OrSet<T>> = self
+            .entries
+            .iter()
+            .filter_map(|(key, set)| {
+                let delta = set.delta_since(known.entries.get(key).unwrap_or(&nothing))?;
+                Some((key.clone(), delta))
+            })
+            .collect();
+        (!entries.is_empty()).then_some(OrMap { entries })
+    }
+}
+
+/// A hybrid logical clock timestamp: wall-clock milliseconds, a counter for timestamps made
+/// within the same millisecond or while the wall clock lags one already seen, and the node
+/// that made it, so no two nodes ever make the same one. Ordered by those, in that order.
+#[derive(Serialize, Deserialize, Debug, Default, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Timestamp {
+    pub wall: u64,
+    pub logical: u64,
+    pub node: String,
+}
+
+/// A hybrid logical clock. Its timestamps follow the wall clock where they can, but never go
+/// backwards and always come after every timestamp the node has seen, so a write made after
+/// seeing another is later than it even if this node's wall clock is behind.
+#[derive(Debug, Clone)]
+pub struct Hlc {
+    node: String,
+    wall: u64,
+    logical: u64,
+}
+
+impl Hlc {
+    pub fn new(node: &str) -> Self {
+        Hlc {
+            node: node.to_string(),
+            wall: 0,
+            logical: 0,
+        }
+    }
+
+    /// A timestamp after every one made or seen so far, given the wall clock in milliseconds.
+    pub fn now(&mut self, wall: u64) -> Timestamp {
+        if wall > self.wall {
+            self.wall = wall;
+            self.logical = 0;
+        } else {
+            self.logical += 1;
+        }
+        Timestamp {
+            wall: self.wall,
+            logical: self.logical,
+            node: self.node.clone(),
+        }
+    }
+
+    /// Moves the clock up to a timestamp from elsewhere, so the next one comes after it.
+    pub fn observe(&mut self, seen: &Timestamp) {
+        if (seen.wall, seen.logical) > (self.wall, self.logical) {
+            self.wall = seen.wall;
+            self.logical = seen.logical;
+        }
+    }
+}
+
+/// A map of last-writer-wins registers: a write replaces a key's value only if its timestamp
+/// is later, so replicas agree on the latest write to each key whatever order writes reach
+/// them in. Of concurrent writes to a key, all but one are lost.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+#[serde(bound(
+    serialize = "K: Serialize, V: Serialize",
+    deserialize = "K: Deserialize<'de> + Eq + Hash, V: Deserialize<'de>"
+))]
+pub struct LwwMap<K, V> {
+    entries: HashMap<K, (Timestamp, V)>,
+}
+
+impl<K: Eq + Hash, V: PartialEq> PartialEq for LwwMap<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl<K, V> Default for LwwMap<K, V> {
+    fn default() -> Self {
+        LwwMap {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> LwwMap<K, V> {
+    pub fn get(&self, key: &K) -> Option<&V> {
+        self.entries.get(key).map(|(_, value)| value)
+    }
+
+    pub fn timestamp(&self, key: &K) -> Option<&Timestamp> {
+        self.entries.get(key).map(|(timestamp, _)| timestamp)
+    }
+
+    /// Writes `value` to `key` if `timestamp` is later than the write it has, returning whether
+    /// it did.
+    pub fn set(&mut self, key: K, timestamp: Timestamp, value: V) -> bool {
+        if self.timestamp(&key).is_some_and(|ours| *ours >= timestamp) {
+            return false;
+        }
+        self.entries.insert(key, (timestamp, value));
+        true
+    }
+
+    /// The latest timestamp of any write here.
+    pub fn latest(&self) -> Option<&Timestamp> {
+        self.entries.values().map(|(timestamp, _)| timestamp).max()
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &V)> {
+        self.entries.iter().map(|(key, (_, value))| (key, value))
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Merge for LwwMap<K, V> {
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (key, (timestamp, value)) in &other.entries {
+            changed |= self.set(key.clone(), timestamp.clone(), value.clone());
+        }
+        changed
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Delta for LwwMap<K, V> {
+    /// The entries `known` has an earlier write for, or none at all.
+    fn delta_since(&self, known: &Self) -> Option<Self> {
+        let entries: HashMap<K, (Timestamp, V)> = self
+            .entries
+            .iter()
+            .filter(|(key, (timestamp, _))| known.timestamp(key).is_none_or(|k| k < timestamp))
+            .map(|(key, entry)| (key.clone(), entry.clone()))
+            .collect();
+        (!entries.is_empty()).then_some(LwwMap { entries })
+    }
+}
+
+/// One write: the node that made it, and how many writes to the key that node had made by
+/// then, this one included.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Dot(String, u64);
+
+/// How many writes from each node have been seen. Covers a write if it counts at least as
+/// many from the node that made it.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+#[serde(transparent)]
+pub struct VersionVector(HashMap<String, u64>);
+
+impl VersionVector {
+    pub fn covers(&self, dot: &Dot) -> bool {
+        self.0.get(&dot.0).is_some_and(|count| *count >= dot.1)
+    }
+}
+
+impl Merge for VersionVector {
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (node, count) in &other.0 {
+            let ours = self.0.entry(node.clone()).or_default();
+            if *count > *ours {
+                *ours = *count;
+                changed = true;
+            }
+        }
+        changed
+    }
+}
+
+/// A key's values under dotted version vectors: each value is tagged with the write that
+/// made it, and the clock counts every write seen. A write replaces only the values its
+/// context had seen, so values written concurrently, none having seen the others, are all
+/// kept as siblings until a write that has seen them all replaces them.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(bound(serialize = "V: Serialize", deserialize = "V: Deserialize<'de>"))]
+pub struct Siblings<V> {
+    values: Vec<(Dot, V)>, // Ordered by dot, so equal siblings compare equal
+    clock: VersionVector,
+}
+
+impl<V> Default for Siblings<V> {
+    fn default() -> Self {
+        Siblings {
+            values: Vec::new(),
+            clock: VersionVector::default(),
+        }
+    }
+}
+
+impl<V: Clone> Siblings<V> {
+    pub fn values(&self) -> impl Iterator<Item = &V> {
+        self.values.iter().map(|(_, value)| value)
+    }
+
+    /// What a client read, to send back with its next write so that write replaces what it
+    /// has seen.
+    pub fn context(&self) -> &VersionVector {
+        &self.clock
+    }
+
+    /// Writes `value` at `node`, replacing every value `context` has seen, and returns the
+    /// write's dot.
+    pub fn write(&mut self, node: &str, context: &VersionVector, value: V) -> Dot {
+        self.values.retain(|(dot, _)| !context.covers(dot));
+        self.clock.merge(context);
+        let count = self.clock.0.entry(node.to_string()).or_default();
+        *count += 1;
+        let dot = Dot(node.to_string(), *count);
+        self.values.push((dot.clone(), value));
+        self.values.sort_by(|(a, _), (b, _)| a.cmp(b));
+        dot
+    }
+}
+
+impl<V: Clone> Merge for Siblings<V> {
+    /// Keeps each side's values unless the other has seen them and since replaced them.
+    fn merge(&mut self, other: &Self) -> bool {
+        let has = |siblings: &Self, dot: &Dot| siblings.values.iter().any(|(d, _)| d == dot);
+        let before = self.values.len();
+        self.values
+            .retain(|(dot, _)| has(other, dot) || !other.clock.covers(dot));
+        let mut changed = self.values.len() != before;
+        for (dot, value) in &other.values {
+            if !has(self, dot) && !self.clock.covers(dot) {
+                self.values.push((dot.clone(), value.clone()));
+                changed = true;
+            }
+        }
+        self.values.sort_by(|(a, _), (b, _)| a.cmp(b));
+        changed | self.clock.merge(&other.clock)
+    }
+}
+
+/// A map of keys to `Siblings`, Dynamo style: concurrent writes to a key are all kept for a
+/// reader to reconcile, rather than all but one being lost as in an `LwwMap`.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(transparent)]
+#[serde(bound(
+    serialize = "K: Serialize, V: Serialize",
+    deserialize = "K: Deserialize<'de> + Eq + Hash, V: Deserialize<'de>"
+))]
+pub struct DvvMap<K, V> {
+    entries: HashMap<K, Siblings<V>>,
+}
+
+impl<K: Eq + Hash, V: PartialEq> PartialEq for DvvMap<K, V> {
+    fn eq(&self, other: &Self) -> bool {
+        self.entries == other.entries
+    }
+}
+
+impl<K, V> Default for DvvMap<K, V> {
+    fn default() -> Self {
+        DvvMap {
+            entries: HashMap::new(),
+        }
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> DvvMap<K, V> {
+    pub fn get(&self, key: &K) -> Option<&Siblings<V>> {
+        self.entries.get(key)
+    }
+
+    /// Writes `value` to `key` at `node`, replacing the values `context` has seen, and
+    /// returns the key's siblings as that leaves them.
+    pub fn write(&mut self, node: &str, key: K, context: &VersionVector, value: V) -> &Siblings<V> {
+        let siblings = self.entries.entry(key).or_default();
+        siblings.write(node, context, value);
+        siblings
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&K, &Siblings<V>)> {
+        self.entries.iter()
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone> Merge for DvvMap<K, V> {
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (key, siblings) in &other.entries {
+            changed |= self.entries.entry(key.clone()).or_default().merge(siblings);
+        }
+        changed
+    }
+}
+
+impl<K: Eq + Hash + Clone, V: Clone + PartialEq> Delta for DvvMap<K, V> {
+    /// The keys whose siblings `known` has differently, or not at all.
+    fn delta_since(&self, known: &Self) -> Option<Self> {
+        let entries: HashMap<K, Siblings<V>> = self
+            .entries
+            .iter()
+            .filter(|(key, siblings)| known.get(key) != Some(*siblings))
+            .map(|(key, siblings)| (key.clone(), siblings.clone()))
+            .collect();
+        (!entries.is_empty()).then_some(DvvMap { entries })
+    }
+}
+
+/// Identifies an element of an `Rga`: a Lamport clock from when it was inserted, and the
+/// node that inserted it.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Id(u64, String);
+
+/// An element of an `Rga`, and the element it was inserted after, if any.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Element<T> {
+    id: Id,
+    after: Option<Id>,
+    value: T,
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    deleted: bool,
+}
+
+/// A replicated growable array: a sequence anyone can insert into and delete from at any
+/// position. Each element remembers the element it was inserted after, and elements after
+/// the same one are ordered latest first, so a node's insert lands right where it was made
+/// even if others inserted there concurrently, and every replica agrees on the order. A
+/// deleted element stays behind as a tombstone, since later inserts may be placed after it.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+#[serde(from = "Vec<Element<T>>", into = "Vec<Element<T>>")]
+#[serde(bound(
+    serialize = "T: Serialize + Clone",
+    deserialize = "T: Deserialize<'de>"
+))]
+pub struct Rga<T> {
+    elements: HashMap<Id, Element<T>>,
+    clock: u64, // The latest clock of any element here
+}
+
+impl<T> From<Vec<Element<T>>> for Rga<T> {
+    fn from(elements: Vec<Element<T>>) -> Self {
+        let clock = elements.iter().map(|element| element.id.0).max();
+        Rga {
+            elements: elements.into_iter().map(|e| (e.id.clone(), e)).collect(),
+            clock: clock.unwrap_or(0),
+        }
+    }
+}
+
+impl<T> From<Rga<T>> for Vec<Element<T>> {
+    fn from(rga: Rga<T>) -> Self {
+        let mut elements: Vec<Element<T>> = rga.elements.into_values().collect();
+        elements.sort_unstable_by(|a, b| a.id.cmp(&b.id));
+        elements
+    }
+}
+
+impl<T: PartialEq> PartialEq for Rga<T> {
+    fn eq(&self, other: &Self) -> bool {
+        self.elements == other.elements
+    }
+}
+
+impl<T> Default for Rga<T> {
+    fn default() -> Self {
+        Rga {
+            elements: HashMap::new(),
+            clock: 0,
+        }
+    }
+}
+
+impl<T: Clone> Rga<T> {
+    /// Every element, deleted or not, in order.
+    fn order(&self) -> Vec<&Element<T>> {
+        let mut children: HashMap<Option<&Id>, Vec<&Element<T>>> = HashMap::new();
+        for element in self.elements.values() {
+            children
+                .entry(element.after.as_ref())
+                .or_default()
+                .push(element);
+        }
+        for siblings in children.values_mut() {
+            // Latest last, since they come off the stack from the end
+            siblings.sort_by(|a, b| a.id.cmp(&b.id));
+        }
+        let mut order = Vec::with_capacity(self.elements.len());
+        let mut stack: Vec<&Element<T>> = children.remove(&None).unwrap_or_default();
+        while let Some(element) = stack.pop() {
+            order.push(element);
+            if let Some(after) = children.remove(&Some(&element.id)) {
+                stack.extend(after);
+            }
+        }
+        order
+    }
+
+    /// The elements not deleted, in order.
+    pub fn iter(&self) -> impl Iterator<Item = &T> {
+        let order = self.order();
+        order
+            .into_iter()
+            .filter(|element| !element.deleted)
+            .map(|element| &element.value)
+    }
+
+    pub fn len(&self) -> usize {
+        self.elements.values().filter(|e| !e.deleted).count()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    /// Inserts `value` so it's at `index` among the elements not deleted, returning its id,
+    /// or `None` if `index` is past the end.
+    pub fn insert(&mut self, node: &str, index: usize, value: T) -> Option<Id> {
+        let after = match index {
+            0 => None,
+            index => {
+                let order = self.order();
+                let mut visible = order.into_iter().filter(|element| !element.deleted);
+                Some(visible.nth(index - 1)?.id.clone())
+            }
+        };
+        self.clock += 1;
+        let id = Id(self.clock, node.to_string());
+        let element = Element {
+            id: id.clone(),
+            after,
+            value,
+            deleted: false,
+        };
+        self.elements.insert(id.clone(), element);
+        Some(id)
+    }
+
+    /// Deletes the element at `index` among those not deleted, returning whether there was
+    /// one.
+    pub fn delete(&mut self, index: usize) -> bool {
+        let order = self.order();
+        let mut visible = order.into_iter().filter(|element| !element.deleted);
+        let Some(id) = visible.nth(index).map(|element| element.id.clone()) else {
+            return false;
+        };
+        if let Some(element) = self.elements.get_mut(&id) {
+            element.deleted = true;
+        }
+        true
+    }
+}
+
+impl<T: Clone> Merge for Rga<T> {
+    fn merge(&mut self, other: &Self) -> bool {
+        let mut changed = false;
+        for (id, theirs) in &other.elements {
+            match self.elements.get_mut(id) {
+                Some(ours) => {
+                    if theirs.deleted && !ours.deleted {
+                        ours.deleted = true;
+                        changed = true;
+                    }
+                }
+                None => {
+                    self.elements.insert(id.clone(), theirs.clone());
+                    changed = true;
+                }
+            }
+        }
+        self.clock = self.clock.max(other.clock);
+        changed
+    }
+}
+
+impl<T: Clone> Delta for Rga<T> {
+    /// The elements `known` lacks, and those it has but hasn't seen deleted.
+    fn delta_since(&self, known: &Self) -> Option<Self> {
+        let elements: Vec<Element<T>> = self
+            .elements
+            .iter()
+            .filter(|(id, element)| {
+                known
+                    .elements
+                    .get(*id)
+                    .is_none_or(|theirs| element.deleted && !theirs.deleted)
+            })
+            .map(|(_, element)| element.clone())
+            .collect();
+        (!elements.is_empty()).then(|| Rga::from(elements))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_merge_converges() {
+        let mut a = PnCounter::default();
+        let mut b = PnCounter::default();
+        a.add("a", 5);
+        b.add("b", 3);
+        b.add("b", -4);
+        let mut ab = a.clone();
+        assert!(ab.merge(&b));
+        let mut ba = b.clone();
+        assert!(ba.merge(&a));
+        assert_eq!(ab, ba);
+        assert_eq!(ab.value(), 4);
+        // Merging the same state again is a no-op
+        assert!(!ab.merge(&b));
+        assert_eq!(ab.value(), 4);
+    }
+
+    #[test]
+    fn test_delta_since() {
+        let mut known = PnCounter::default();
+        known.add("a", 1);
+        known.add("b", 2);
+        let mut counter = known.clone();
+        counter.add("b", 1);
+        counter.add("b", -1);
+        let delta = counter.delta_since(&known).unwrap();
+        assert_eq!(
+            delta.increments().iter().collect::<Vec<_>>(),
+            vec![("b", 3)]
+        );
+        assert_eq!(
+            delta.decrements().iter().collect::<Vec<_>>(),
+            vec![("b", 1)]
+        );
+        known.merge(&delta);
+        assert_eq!(known, counter);
+        assert!(counter.delta_since(&known).is_none());
+    }
+
+    #[test]
+    fn test_epoch_reset_wins() {
+        let mut a = Epoch::<PnCounter>::default();
+        a.get_mut().add("a", 5);
+        let mut b = a.clone();
+        b.reset();
+        b.get_mut().add("b", 1);
+        // An add from before the reset doesn't come back
+        a.get_mut().add("a", 2);
+        assert!(!b.merge(&a));
+        assert_eq!(b.get().value(), 1);
+        assert!(a.merge(&b));
+        assert_eq!(a, b);
+
+        let known = Epoch::new(1, PnCounter::default());
+        assert_eq!(b.delta_since(&known).unwrap().epoch(), 1);
+        assert!(b.delta_since(&b).is_none());
+        // An emptied counter still has to reach peers stuck in the old epoch
+        let mut reset = Epoch::<PnCounter>::default();
+        reset.reset();
+        assert!(reset.delta_since(&Epoch::default()).is_some());
+    }
+
+    #[test]
+    fn test_overflow_is_refused() {
+        let mut counter = GCounter::default();
+        assert!(counter.increment("a", u64::MAX));
+        assert!(!counter.increment("a", 1));
+        assert_eq!(counter.get("a"), u64::MAX);
+        assert!(counter.increment("b", u64::MAX));
+        assert_eq!(counter.value(), 2 * u64::MAX as u128);
+    }
+
+    #[test]
+    fn test_serializes_as_plain_maps() {
+        let mut counter = PnCounter::default();
+        counter.add("n1", 2);
+        assert_eq!(
+            serde_json::to_string(&counter).unwrap(),
+            r#"{"increments":{"n1":2},"decrements":{},"versions":{"n1":1}}"#
+        );
+        // Counters saved before there were versions still load
+        let unversioned: PnCounter =
+            serde_json::from_str(r#"{"increments":{"n1":2},"decrements":{}}"#).unwrap();
+        assert_eq!(unversioned.version("n1"), 0);
+        assert_eq!(unversioned.value(), 2);
+    }
+
+    #[test]
+    fn test_concurrent_add_survives_remove() {
+        let mut a = OrSet::default();
+        a.add("a", 1);
+        let mut b = a.clone();
+        // b removes the add it's seen while a adds 1 again
+        assert!(b.remove(&1));
+        a.add("a", 1);
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!(ab, ba);
+        assert!(ab.contains(&1));
+        assert_eq!(ab.tombstones(), 1);
+    }
+
+    #[test]
+    fn test_removed_adds_stay_removed() {
+        let mut a = OrSet::default();
+        a.add("a", "x");
+        let stale = a.clone();
+        a.remove(&"x");
+        // The add arriving again, after the remove, doesn't bring it back
+        assert!(!a.merge(&stale));
+        assert!(a.is_empty());
+        assert_eq!(stale.delta_since(&a), None);
+
+        let mut behind = stale.clone();
+        behind.merge(&a.delta_since(&stale).unwrap());
+        assert_eq!(behind, a);
+    }
+
+    #[test]
+    fn test_concurrent_add_survives_clear() {
+        let mut a = OrMap::default();
+        a.add("a", "cart", "apple");
+        a.add("a", "cart", "pear");
+        let mut b = a.clone();
+        // b empties the cart while a puts a plum in it
+        assert!(b.clear(&"cart"));
+        assert_eq!(b.get(&"cart"), None);
+        a.add("a", "cart", "plum");
+
+        let mut ab = a.clone();
+        ab.merge(&b.delta_since(&a).unwrap());
+        let mut ba = b.clone();
+        ba.merge(&a.delta_since(&b).unwrap());
+        assert_eq!(ab, ba);
+        let items: Vec<&&str> = ab.get(&"cart").unwrap().iter().collect();
+        assert_eq!(items, vec![&"plum"]);
+        assert_eq!(ab.len(), 1);
+    }
+
+    #[test]
+    fn test_hlc_never_goes_backwards() {
+        let mut clock = Hlc::new("a");
+        let first = clock.now(100);
+        // The wall clock stepping back doesn't take timestamps with it
+        let second = clock.now(90);
+        assert!(second > first);
+        assert_eq!((second.wall, second.logical), (100, 1));
+
+        // Nor does seeing one from a node whose clock is ahead
+        let ahead = Hlc::new("b").now(500);
+        clock.observe(&ahead);
+        let third = clock.now(110);
+        assert!(third > ahead);
+        assert_eq!((third.wall, third.logical), (500, 1));
+        assert_eq!(clock.now(600).logical, 0);
+    }
+
+    #[test]
+    fn test_latest_write_wins() {
+        let (mut a, mut b) = (Hlc::new("a"), Hlc::new("b"));
+        let mut x = LwwMap::default();
+        let mut y = LwwMap::default();
+        x.set("k", a.now(10), 1);
+        y.set("k", b.now(10), 2);
+        // Ties on time go to the later node id
+        let mut xy = x.clone();
+        assert!(xy.merge(&y));
+        let mut yx = y.clone();
+        assert!(!yx.merge(&x));
+        assert_eq!(xy, yx);
+        assert_eq!(xy.get(&"k"), Some(&2));
+
+        // An older write never replaces a newer one
+        let stale = Timestamp {
+            wall: 5,
+            logical: 0,
+            node: "c".to_string(),
+        };
+        assert!(!xy.set("k", stale, 3));
+        assert_eq!(xy.delta_since(&yx), None);
+        assert!(x.delta_since(&xy).is_none());
+        assert_eq!(xy.delta_since(&x), Some(y));
+    }
+
+    #[test]
+    fn test_concurrent_writes_are_kept_as_siblings() {
+        let (mut x, mut y) = (DvvMap::default(), DvvMap::default());
+        let none = VersionVector::default();
+        x.write("a", "k", &none, 1);
+        y.write("b", "k", &none, 2);
+        let mut xy = x.clone();
+        assert!(xy.merge(&y));
+        let mut yx = y.clone();
+        assert!(yx.merge(&x));
+        assert_eq!(xy, yx);
+        let values: Vec<_> = xy.get(&"k").unwrap().values().copied().collect();
+        assert_eq!(values, [1, 2]);
+
+        // A write that has read both replaces them, and a merge with either doesn't bring it back
+        let context = xy.get(&"k").unwrap().context().clone();
+        xy.write("b", "k", &context, 3);
+        assert!(!xy.merge(&x));
+        assert!(!xy.merge(&y));
+        let values: Vec<_> = xy.get(&"k").unwrap().values().copied().collect();
+        assert_eq!(values, [3]);
+        // Whereas one that had only read a's is concurrent with b's
+        y.write("a", "k", &x.get(&"k").unwrap().context().clone(), 4);
+        let mut merged = y.clone();
+        merged.merge(&x);
+        assert_eq!(merged.get(&"k").unwrap().values().count(), 2);
+        assert_eq!(xy.delta_since(&yx).map(|delta| delta.len()), Some(1));
+    }
+
+    #[test]
+    fn test_concurrent_inserts_converge() {
+        let mut a = Rga::default();
+        for (i, c) in "ac".chars().enumerate() {
+            a.insert("a", i, c);
+        }
+        let mut b = a.clone();
+        // Both insert between a and c at once
+        a.insert("a", 1, 'x');
+        a.insert("a", 2, 'y');
+        b.insert("b", 1, 'b');
+        assert!(b.delete(2));
+        assert!(!b.delete(2));
+
+        let mut ab = a.clone();
+        ab.merge(&b);
+        let mut ba = b.clone();
+        ba.merge(&a);
+        assert_eq!(ab, ba);
+        let text: String = ab.iter().collect();
+        let other: String = ba.iter().collect();
+        assert_eq!(text, other);
+        // Each node's inserts stay together, b's first since it wins the tie on clock, and c
+        // stays deleted
+        assert_eq!(text, "abxy");
+        assert_eq!(ab.len(), 4);
+        assert_eq!(ab.insert("a", 5, 'z'), None);
+    }
+
+    #[test]
+    fn test_rga_delta() {
+        let mut a = Rga::default();
+        a.insert("a", 0, 1);
+        a.insert("a", 1, 2);
+        let known = a.clone();
+        a.insert("a", 0, 0);
+        a.delete(2);
+        let delta = a.delta_since(&known).unwrap();
+        assert_eq!(delta.elements.len(), 2);
+
+        let mut b: Rga<i32> =
+            serde_json::from_str(&serde_json::to_string(&known).unwrap()).unwrap();
+        assert_eq!(b, known);
+        b.merge(&delta);
+        assert_eq!(b.iter().copied().collect::<Vec<_>>(), vec![0, 1]);
+        assert_eq!(a.delta_since(&b), None);
+        // Its clock came along too, so its next insert sorts after everything it's seen
+        let id = b.insert("b", 0, 9).unwrap();
+        assert!(id > Id(3, "a".to_string()));
+    }
+
+    #[test]
+    fn test_later_versions_win() {
+        let older: PnCounter =
+            serde_json::from_str(r#"{"increments":{"n1":5},"decrements":{},"versions":{"n1":2}}"#)
+                .unwrap();
+        let newer: PnCounter =
+            serde_json::from_str(r#"{"increments":{"n1":1},"decrements":{},"versions":{"n1":3}}"#)
+                .unwrap();
+        let mut counter = older.clone();
+        assert!(counter.merge(&newer));
+        assert_eq!(counter, newer);
+        // Older state arriving late doesn't take the entry back, though its count is larger
+        assert!(!counter.merge(&older));
+        assert_eq!(counter.value(), 1);
+        assert_eq!(newer.delta_since(&older), Some(newer.clone()));
+        assert!(older.delta_since(&newer).is_none());
+    }
+}
@@ -0,0 +1,385 @@
+pub mod node {
+    use crdt::{Delta, DvvMap, Merge, VersionVector};
+    use maelstrom::error::KEY_DOES_NOT_EXIST;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    /// A Dynamo-style key-value store clients can read and write at any node, replicated as a
+    /// `DvvMap`. A read returns every value written concurrently to the key, along with a
+    /// context saying what it has seen, and a write given that context replaces exactly those
+    /// values, so resolving siblings is up to the client, and no write is lost to a clock.
+    /// Each node gossips every peer the keys it hasn't yet acknowledged, so reads anywhere
+    /// converge once writes stop.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        peers: Vec<String>,
+        values: DvvMap<String, Value>, // Keyed by the key's JSON, since keys can be any value
+        known: HashMap<String, DvvMap<String, Value>>, // What each peer is known to have merged
+        in_flight: HashMap<u64, (String, DvvMap<String, Value>)>, // The latest gossip to each peer, by msg_id
+    }
+
+    /// Tunables, read from `DVV_KV_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between gossip rounds.
+        pub gossip_interval: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                gossip_interval: Duration::from_millis(100),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                gossip_interval: Duration::from_millis(env_or(
+                    "DVV_KV_GOSSIP_INTERVAL_MS",
+                    default.gossip_interval.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+            key: Value,
+        },
+        /// The key's siblings, in no particular order, and what to write back with to replace
+        /// them.
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            values: Vec<Value>,
+            context: VersionVector,
+        },
+        /// Writes `value` to `key`, replacing the siblings `context` was read with. Without a
+        /// context it replaces nothing, and becomes a sibling of whatever's there.
+        Write {
+            msg_id: u64,
+            key: Value,
+            value: Value,
+            #[serde(default)]
+            context: VersionVector,
+        },
+        /// The context the write left the key with here.
+        WriteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            context: VersionVector,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// The writes the sender thinks we're missing.
+        Gossip {
+            msg_id: u64,
+            values: DvvMap<String, Value>,
+        },
+        /// The gossip has been merged.
+        GossipOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+    }
+
+    impl Default for Node {
+        fn default() -> Self {
+            Node::new()
+        }
+    }
+
+    impl Node {
+        pub fn new() -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                peers: Vec::new(),
+                values: DvvMap::default(),
+                known: HashMap::new(),
+                in_flight: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "values": self.values.len(),
+                "in_flight": self.in_flight.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let Some(body) = self.handle_body(&message.src, message.body) else {
+                return Vec::new();
+            };
+            self.cur_id += 1;
+            vec![Message {
+                src: message.dest,
+                dest: message.src,
+                body,
+            }]
+        }
+
+        /// Sends each peer what it's missing. Anything a peer doesn't acknowledge is sent
+        /// again next round, so lost gossip only delays convergence.
+        pub fn gossip(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            for peer in self.peers.clone() {
+                let known = self.known.entry(peer.clone()).or_default();
+                let Some(delta) = self.values.delta_since(known) else {
+                    continue;
+                };
+                // Only the latest gossip needs acknowledging, since it has all the earlier ones
+                self.in_flight.retain(|_, (to, _)| *to != peer);
+                let msg_id = self.next_msg_id();
+                self.in_flight.insert(msg_id, (peer.clone(), delta.clone()));
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Gossip {
+                        msg_id,
+                        values: delta,
+                    },
+                });
+            }
+            messages
+        }
+
+        fn handle_body(&mut self, src: &str, body: Body) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Read { msg_id, key } => match self.values.get(&key.to_string()) {
+                    Some(siblings) => Body::ReadOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        values: siblings.values().cloned().collect(),
+                        context: siblings.context().clone(),
+                    },
+                    None => Body::Error {
+                        in_reply_to: msg_id,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("no value for {}", key),
+                    },
+                },
+                Body::Write {
+                    msg_id,
+                    key,
+                    value,
+                    context,
+                } => {
+                    let siblings = self
+                        .values
+                        .write(&self.id, key.to_string(), &context, value);
+                    Body::WriteOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        context: siblings.context().clone(),
+                    }
+                }
+                Body::Gossip { msg_id, values } => {
+                    self.values.merge(&values);
+                    // Whatever it sent us, it has
+                    self.known
+                        .entry(src.to_string())
+                        .or_default()
+                        .merge(&values);
+                    Body::GossipOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::GossipOk { in_reply_to, .. } => {
+                    let (peer, sent) = self.in_flight.remove(&in_reply_to)?;
+                    self.known.entry(peer).or_default().merge(&sent);
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::ReadOk { .. }
+                | Body::WriteOk { .. }
+                | Body::Error { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, exchange};
+        use serde_json::json;
+
+        fn init(id: &str) -> Node {
+            testing::init(Node::new(), id, 2)
+        }
+
+        fn send(node: &mut Node, body: Body) -> Body {
+            let dest = node.id.clone();
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body,
+            })
+            .remove(0)
+            .body
+        }
+
+        /// Writes `value` to `key` with `context`, returning the context it leaves.
+        fn write(node: &mut Node, key: Value, value: Value, context: Value) -> Value {
+            let write = json!({
+                "type": "write", "msg_id": 2, "key": key, "value": value, "context": context
+            });
+            let Body::WriteOk { context, .. } = send(node, serde_json::from_value(write).unwrap())
+            else {
+                panic!("expected write_ok");
+            };
+            serde_json::to_value(context).unwrap()
+        }
+
+        /// The key's siblings, sorted, and its context.
+        fn read(node: &mut Node, key: Value) -> Option<(Vec<Value>, Value)> {
+            match send(node, Body::Read { msg_id: 9, key }) {
+                Body::ReadOk {
+                    mut values,
+                    context,
+                    ..
+                } => {
+                    values.sort_by_key(|value| value.to_string());
+                    Some((values, serde_json::to_value(context).unwrap()))
+                }
+                Body::Error {
+                    code: KEY_DOES_NOT_EXIST,
+                    ..
+                } => None,
+                body => panic!("unexpected reply {:?}", body),
+            }
+        }
+
+        #[test]
+        fn test_concurrent_writes_are_read_as_siblings() {
+            let (mut n1, mut n2) = (init("n1"), init("n2"));
+            write(&mut n1, json!("k"), json!("a"), json!({}));
+            write(&mut n2, json!("k"), json!("b"), json!({}));
+            assert_eq!(read(&mut n2, json!(1)), None);
+            exchange(&mut n1, &mut n2);
+            exchange(&mut n2, &mut n1);
+            let (values, context) = read(&mut n1, json!("k")).unwrap();
+            assert_eq!(values, [json!("a"), json!("b")]);
+            assert_eq!(context, json!({"n1": 1, "n2": 1}));
+            assert_eq!(read(&mut n2, json!("k")).unwrap().0, values);
+            // Both sides know the other is caught up, so there's nothing left to send
+            assert!(n1.gossip().is_empty());
+            assert!(n2.gossip().is_empty());
+        }
+
+        #[test]
+        fn test_writes_with_context_resolve_siblings() {
+            let (mut n1, mut n2) = (init("n1"), init("n2"));
+            write(&mut n1, json!("k"), json!(1), json!({}));
+            write(&mut n2, json!("k"), json!(2), json!({}));
+            exchange(&mut n2, &mut n1);
+            let (_, context) = read(&mut n1, json!("k")).unwrap();
+
+            // n2 hasn't seen n1's write yet, but the client that resolved it has
+            let resolved = write(&mut n2, json!("k"), json!(3), context);
+            assert_eq!(resolved, json!({"n1": 1, "n2": 2}));
+            exchange(&mut n1, &mut n2);
+            exchange(&mut n2, &mut n1);
+            for node in [&mut n1, &mut n2] {
+                assert_eq!(
+                    read(node, json!("k")),
+                    Some((vec![json!(3)], resolved.clone()))
+                );
+            }
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::gossip(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,155 @@
+//! Leader election by rendezvous hashing over a failure detector: every node ranks the
+//! nodes it has heard from recently for each key, and the top one leads it. There's no
+//! voting, so two nodes that disagree on who's alive can both think they lead a key for a
+//! while; hosts that can't afford that replicate to the key's other replicas before
+//! acknowledging, so whichever of them takes over already holds what was acknowledged.
+//!
+//! Hosts call `Election::heard_from` for every message they get from a peer, and send
+//! heartbeats often enough that a live node is never silent for the whole timeout.
+
+use std::collections::hash_map::DefaultHasher;
+use std::collections::HashMap;
+use std::hash::{Hash, Hasher};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Picks a leader per key among the nodes we've heard from recently. Leaders are chosen by
+/// rendezvous hashing, so every node agrees on the owner of a key as long as they agree on
+/// who is alive, and a dead leader's keys are spread across the survivors.
+pub struct Election {
+    id: String,
+    nodes: Vec<String>,
+    last_seen: Mutex<HashMap<String, Instant>>,
+    timeout: Duration,
+}
+
+impl Election {
+    pub fn new(id: String, nodes: Vec<String>, timeout: Duration) -> Self {
+        let now = Instant::now();
+        let last_seen = nodes.iter().map(|node| (node.clone(), now)).collect();
+        Election {
+            id,
+            nodes,
+            last_seen: Mutex::new(last_seen),
+            timeout,
+        }
+    }
+
+    pub fn heard_from(&self, node: &str) {
+        if let Some(seen) = self.last_seen.lock().unwrap().get_mut(node) {
+            *seen = Instant::now();
+        }
+    }
+
+    pub fn is_alive(&self, node: &str) -> bool {
+        self.alive(&self.last_seen.lock().unwrap(), node)
+    }
+
+    pub fn leader_for(&self, key: &str) -> &str {
+        let last_seen = self.last_seen.lock().unwrap();
+        self.nodes
+            .iter()
+            .filter(|node| self.alive(&last_seen, node))
+            .max_by_key(|node| score(node, key))
+            .map_or(self.id.as_str(), |node| node.as_str())
+    }
+
+    /// The `count` alive nodes ranked highest for `key`, leader first. These hold its log.
+    pub fn replicas_for(&self, key: &str, count: usize) -> Vec<String> {
+        let mut alive = self.alive_nodes();
+        alive.sort_by_key(|node| std::cmp::Reverse(score(node, key)));
+        alive.truncate(count);
+        alive
+    }
+
+    /// The nodes we currently consider alive, ourselves included, in cluster order.
+    pub fn alive_nodes(&self) -> Vec<String> {
+        let last_seen = self.last_seen.lock().unwrap();
+        self.nodes
+            .iter()
+            .filter(|node| self.alive(&last_seen, node))
+            .cloned()
+            .collect()
+    }
+
+    pub fn is_leader(&self, key: &str) -> bool {
+        self.leader_for(key) == self.id
+    }
+
+    /// Every other node in the cluster, alive or not.
+    pub fn peers(&self) -> Vec<String> {
+        self.nodes
+            .iter()
+            .filter(|node| **node != self.id)
+            .cloned()
+            .collect()
+    }
+
+    fn alive(&self, last_seen: &HashMap<String, Instant>, node: &str) -> bool {
+        node == self.id
+            || last_seen
+                .get(node)
+                .is_some_and(|seen| seen.elapsed() < self.timeout)
+    }
+}
+
+/// The leader `key` would have if exactly `nodes` were alive, using the same ranking as
+/// `Election::leader_for`.
+pub fn leader_among<'a>(nodes: &'a [String], key: &str) -> Option<&'a str> {
+    nodes
+        .iter()
+        .max_by_key(|node| score(node, key))
+        .map(|node| node.as_str())
+}
+
+fn score(node: &str, key: &str) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    (node, key).hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn nodes() -> Vec<String> {
+        (1..=3).map(|i| format!("n{}", i)).collect()
+    }
+
+    #[test]
+    fn test_nodes_agree_on_each_keys_leader() {
+        let elections: Vec<Election> = nodes()
+            .into_iter()
+            .map(|id| Election::new(id, nodes(), Duration::from_secs(60)))
+            .collect();
+        let keys: Vec<String> = (0..50).map(|i| format!("k{}", i)).collect();
+        for key in &keys {
+            let leader = elections[0].leader_for(key);
+            assert!(elections.iter().all(|e| e.leader_for(key) == leader));
+            assert_eq!(elections.iter().filter(|e| e.is_leader(key)).count(), 1);
+            assert_eq!(elections[0].replicas_for(key, 3)[0], leader);
+        }
+        // Keys are spread over every node
+        for node in nodes() {
+            assert!(keys.iter().any(|key| elections[0].leader_for(key) == node));
+        }
+    }
+
+    #[test]
+    fn test_silent_leader_is_taken_over() {
+        let election = Election::new("n1".into(), nodes(), Duration::from_millis(20));
+        let key = (0..)
+            .map(|i| format!("k{}", i))
+            .find(|key| election.leader_for(key) == "n2")
+            .unwrap();
+        let successor = leader_among(&["n1".into(), "n3".into()], &key)
+            .unwrap()
+            .to_string();
+        std::thread::sleep(Duration::from_millis(30));
+        election.heard_from("n3");
+        assert!(!election.is_alive("n2"));
+        // The next in line takes over, just as if n2 had never been there
+        assert_eq!(election.leader_for(&key), successor);
+        assert_eq!(election.replicas_for(&key, 2)[0], successor);
+    }
+}
@@ -0,0 +1,571 @@
+mod store {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::fs;
+    use std::io::{self, BufRead, Write};
+    use std::path::Path;
+
+    /// An append-only journal of records, one JSON line each, synced as it's written. A
+    /// crash loses at most a torn last line, which loading skips.
+    pub struct Journal {
+        file: fs::File,
+    }
+
+    impl Journal {
+        /// Opens our journal in `dir`, returning it with every record already in it.
+        pub fn open<R: DeserializeOwned>(dir: &Path, node_id: &str) -> io::Result<(Self, Vec<R>)> {
+            fs::create_dir_all(dir)?;
+            let path = dir.join(format!("{}.journal", node_id));
+            let file = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&path)?;
+            let mut records = Vec::new();
+            for line in io::BufReader::new(fs::File::open(&path)?).lines() {
+                match serde_json::from_str(&line?) {
+                    Ok(record) => records.push(record),
+                    Err(e) => log::warn!("Skipping unreadable journal record: {}", e),
+                }
+            }
+            Ok((Journal { file }, records))
+        }
+
+        /// Appends a record and syncs it, so it survives a crash once this returns.
+        pub fn append<R: Serialize>(&mut self, record: &R) -> io::Result<()> {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            self.file.write_all(&line)?;
+            self.file.sync_data()
+        }
+    }
+}
+
+pub mod node {
+    use super::store::Journal;
+    use maelstrom::error::{PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{BTreeMap, HashMap};
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    /// A counter clients can add to at any node, where each add carries an idempotency
+    /// token the client makes up, and an add whose token has been seen before, at this node
+    /// or any other, is acknowledged without counting again. A client that never heard back
+    /// can retry the same add anywhere until it does, and it counts exactly once.
+    ///
+    /// The counter is the set of adds, by token, and its value their sum. Nodes gossip each
+    /// peer the adds it hasn't acknowledged, and pass on adds they learn to everyone but
+    /// whoever they learned them from, so an add reaches every node even if the one it was
+    /// made at is gone. Reads converge once adds stop.
+    ///
+    /// With a `data_dir`, an add is journaled before it's acknowledged or gossiped, so a
+    /// restarted node still has it, and still knows its token. Not knowing which peers have
+    /// heard of what, a restarted node gossips everything again. Tokens are never
+    /// forgotten, which is what it takes to recognise a retry however late it comes.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        peers: Vec<String>,
+        adds: BTreeMap<String, i64>, // Every add's delta, by token
+        value: i128,
+        pending: HashMap<String, BTreeMap<String, i64>>, // Adds each peer hasn't acknowledged
+        in_flight: HashMap<u64, (String, BTreeMap<String, i64>)>, // The latest gossip to each peer, by msg_id
+        journal: Option<Journal>,
+    }
+
+    /// An add as it's journaled.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Record {
+        token: String,
+        delta: i64,
+    }
+
+    /// What became of an add we were told of.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    enum Learned {
+        New,
+        /// We already had it, or a smaller delta for its token.
+        Known,
+        /// It couldn't be journaled, so it's been left out.
+        Failed,
+    }
+
+    /// Tunables, read from `EXACTLY_ONCE_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between gossip rounds.
+        pub gossip_interval: Duration,
+        /// Where to journal adds across restarts; nowhere if unset.
+        pub data_dir: Option<PathBuf>,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                gossip_interval: Duration::from_millis(100),
+                data_dir: None,
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                gossip_interval: Duration::from_millis(env_or(
+                    "EXACTLY_ONCE_GOSSIP_INTERVAL_MS",
+                    default.gossip_interval.as_millis() as u64,
+                )),
+                data_dir: std::env::var_os("EXACTLY_ONCE_DATA_DIR").map(PathBuf::from),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Adds `delta`, unless an add with the same `token` already has. Without a token,
+        /// only resends of this very message are recognised.
+        Add {
+            msg_id: u64,
+            delta: i64,
+            #[serde(default)]
+            token: Option<String>,
+        },
+        AddOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: i128,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// Adds the sender thinks we're missing, by token.
+        Gossip {
+            msg_id: u64,
+            adds: BTreeMap<String, i64>,
+        },
+        /// The gossip has been merged.
+        GossipOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                peers: Vec::new(),
+                adds: BTreeMap::new(),
+                value: 0,
+                pending: HashMap::new(),
+                in_flight: HashMap::new(),
+                journal: None,
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "adds": self.adds.len(),
+                "value": self.value.to_string(),
+                "pending": self.pending.iter().map(|(peer, adds)| (peer, adds.len())).collect::<HashMap<_, _>>(),
+                "in_flight": self.in_flight.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let Some(body) = self.handle_body(&message.src, message.body) else {
+                return Vec::new();
+            };
+            self.cur_id += 1;
+            vec![Message {
+                src: message.dest,
+                dest: message.src,
+                body,
+            }]
+        }
+
+        /// Sends each peer the adds it hasn't acknowledged. Anything a peer doesn't
+        /// acknowledge is sent again next round, so lost gossip only delays convergence.
+        pub fn gossip(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            for peer in self.peers.clone() {
+                let Some(adds) = self.pending.get(&peer).filter(|adds| !adds.is_empty()) else {
+                    continue;
+                };
+                let adds = adds.clone();
+                // Only the latest gossip needs acknowledging, since it has all the earlier ones
+                self.in_flight.retain(|_, (to, _)| *to != peer);
+                let msg_id = self.next_msg_id();
+                self.in_flight.insert(msg_id, (peer.clone(), adds.clone()));
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Gossip { msg_id, adds },
+                });
+            }
+            messages
+        }
+
+        /// Replays our journal, if we keep one, and queues everything in it for every peer.
+        fn recover(&mut self) {
+            let Some(dir) = &self.config.data_dir else {
+                return;
+            };
+            let records: Vec<Record> = match Journal::open(dir, &self.id) {
+                Ok((journal, records)) => {
+                    self.journal = Some(journal);
+                    records
+                }
+                Err(e) => {
+                    log::error!("Unable to open our journal in {:?}: {}", dir, e);
+                    return;
+                }
+            };
+            for Record { token, delta } in records {
+                self.count(token, delta);
+            }
+            log::info!(
+                "Recovered {} adds totalling {}",
+                self.adds.len(),
+                self.value
+            );
+            for peer in &self.peers {
+                self.pending.insert(peer.clone(), self.adds.clone());
+            }
+        }
+
+        /// Counts an add, or swaps in a token's smaller delta, returning whether anything
+        /// changed. Keeping the smaller of two deltas for a token means every node settles
+        /// on the same one, whatever order it hears of them in.
+        fn count(&mut self, token: String, delta: i64) -> bool {
+            match self.adds.get(&token) {
+                Some(known) if *known <= delta => false,
+                known => {
+                    self.value += delta as i128 - known.copied().unwrap_or_default() as i128;
+                    self.adds.insert(token, delta);
+                    true
+                }
+            }
+        }
+
+        /// Journals and counts an add we've been told of, and queues it for every peer but
+        /// the one it came from.
+        fn learn(&mut self, token: String, delta: i64, from: &str) -> Learned {
+            if self.adds.get(&token).is_some_and(|known| *known <= delta) {
+                return Learned::Known;
+            }
+            if let Some(journal) = &mut self.journal {
+                let record = Record {
+                    token: token.clone(),
+                    delta,
+                };
+                if let Err(e) = journal.append(&record) {
+                    log::error!("Unable to journal the add for {:?}: {}", token, e);
+                    return Learned::Failed;
+                }
+            }
+            self.count(token.clone(), delta);
+            for peer in self.peers.iter().filter(|peer| *peer != from) {
+                let pending = self.pending.entry(peer.clone()).or_default();
+                pending.insert(token.clone(), delta);
+            }
+            Learned::New
+        }
+
+        fn handle_body(&mut self, src: &str, body: Body) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    self.recover();
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Add {
+                    msg_id,
+                    delta,
+                    token,
+                } => {
+                    let token = token.unwrap_or_else(|| format!("{}:{}", src, msg_id));
+                    if self.adds.get(&token).is_some_and(|known| *known != delta) {
+                        return Some(Body::Error {
+                            in_reply_to: msg_id,
+                            code: PRECONDITION_FAILED,
+                            text: format!("{:?} was already used for a different delta", token),
+                        });
+                    }
+                    let from = self.id.clone();
+                    match self.learn(token, delta, &from) {
+                        Learned::New | Learned::Known => Body::AddOk {
+                            msg_id: self.cur_id,
+                            in_reply_to: msg_id,
+                        },
+                        Learned::Failed => Body::Error {
+                            in_reply_to: msg_id,
+                            code: TEMPORARILY_UNAVAILABLE,
+                            text: "unable to journal the add".to_string(),
+                        },
+                    }
+                }
+                Body::Read { msg_id } => Body::ReadOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: msg_id,
+                    value: self.value,
+                },
+                Body::Gossip { msg_id, adds } => {
+                    for (token, delta) in adds {
+                        if self.learn(token, delta, src) == Learned::Failed {
+                            // Unacknowledged, so it's sent again
+                            return None;
+                        }
+                    }
+                    Body::GossipOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::GossipOk { in_reply_to, .. } => {
+                    let (peer, sent) = self.in_flight.remove(&in_reply_to)?;
+                    if let Some(pending) = self.pending.get_mut(&peer) {
+                        // Anything changed since it was sent still needs sending
+                        pending.retain(|token, delta| sent.get(token) != Some(delta));
+                    }
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::AddOk { .. }
+                | Body::ReadOk { .. }
+                | Body::Error { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        fn init(id: &str, config: Config) -> Node {
+            let mut node = Node::new(config);
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest: id.into(),
+                body: Body::Init {
+                    msg_id: 1,
+                    node_id: id.into(),
+                    node_ids: vec!["n1".into(), "n2".into(), "n3".into()],
+                },
+            });
+            node
+        }
+
+        fn send(node: &mut Node, body: serde_json::Value) -> serde_json::Value {
+            let message = json!({"src": "c1", "dest": node.id, "body": body});
+            let reply = node.handle_message(serde_json::from_value(message).unwrap());
+            serde_json::to_value(&reply[0].body).unwrap()
+        }
+
+        fn add(node: &mut Node, delta: i64, token: &str) -> serde_json::Value {
+            send(
+                node,
+                json!({"type": "add", "msg_id": 2, "delta": delta, "token": token}),
+            )
+        }
+
+        fn read(node: &mut Node) -> i128 {
+            let reply = send(node, json!({"type": "read", "msg_id": 9}));
+            reply["value"].as_i64().unwrap() as i128
+        }
+
+        /// Delivers one node's gossip to the others and the acknowledgements back, a round
+        /// at a time until there's nothing left to send.
+        fn gossip(nodes: &mut [Node]) {
+            loop {
+                let messages: Vec<Message> = nodes.iter_mut().flat_map(Node::gossip).collect();
+                if messages.is_empty() {
+                    return;
+                }
+                for message in messages {
+                    let n: usize = message.dest[1..].parse().unwrap();
+                    for ack in nodes[n - 1].handle_message(message) {
+                        let n: usize = ack.dest[1..].parse().unwrap();
+                        nodes[n - 1].handle_message(ack);
+                    }
+                }
+            }
+        }
+
+        #[test]
+        fn test_retries_count_once() {
+            let mut n1 = init("n1", Config::default());
+            for _ in 0..3 {
+                assert_eq!(add(&mut n1, 5, "a")["type"], "add_ok");
+            }
+            add(&mut n1, 5, "b");
+            assert_eq!(read(&mut n1), 10);
+        }
+
+        #[test]
+        fn test_retries_at_other_nodes_count_once() {
+            let mut nodes = vec![
+                init("n1", Config::default()),
+                init("n2", Config::default()),
+                init("n3", Config::default()),
+            ];
+            add(&mut nodes[0], 3, "a");
+            gossip(&mut nodes);
+            // The client never heard back from n1, so retries at n2
+            add(&mut nodes[1], 3, "a");
+            add(&mut nodes[2], 4, "b");
+            gossip(&mut nodes);
+            for node in nodes.iter_mut() {
+                assert_eq!(read(node), 7);
+            }
+        }
+
+        #[test]
+        fn test_adds_spread_without_the_node_they_were_made_at() {
+            let mut nodes = vec![
+                init("n1", Config::default()),
+                init("n2", Config::default()),
+                init("n3", Config::default()),
+            ];
+            add(&mut nodes[0], 6, "a");
+            // n1's gossip reaches n2, then n1 is gone
+            let to_n2: Vec<Message> = nodes[0]
+                .gossip()
+                .into_iter()
+                .filter(|message| message.dest == "n2")
+                .collect();
+            for message in to_n2 {
+                nodes[1].handle_message(message);
+            }
+            let (mut n2, mut n3) = (nodes.remove(1), nodes.remove(1));
+            for message in n2.gossip() {
+                if message.dest == "n3" {
+                    for ack in n3.handle_message(message) {
+                        n2.handle_message(ack);
+                    }
+                }
+            }
+            assert_eq!(read(&mut n3), 6);
+        }
+
+        #[test]
+        fn test_reused_tokens_with_other_deltas_are_refused() {
+            let mut n1 = init("n1", Config::default());
+            add(&mut n1, 2, "a");
+            assert_eq!(add(&mut n1, 3, "a")["code"], PRECONDITION_FAILED);
+            assert_eq!(read(&mut n1), 2);
+        }
+
+        #[test]
+        fn test_restarted_nodes_remember_tokens() {
+            let dir = std::env::temp_dir().join(format!("exactly-once-{}", std::process::id()));
+            let config = Config {
+                data_dir: Some(dir.clone()),
+                ..Config::default()
+            };
+            let mut n1 = init("n1", config.clone());
+            add(&mut n1, 4, "a");
+            add(&mut n1, 1, "b");
+
+            let mut n1 = init("n1", config);
+            assert_eq!(read(&mut n1), 5);
+            add(&mut n1, 4, "a");
+            assert_eq!(read(&mut n1), 5);
+            // It doesn't know what its peers heard before, so tells them everything again
+            assert_eq!(n1.gossip().len(), 2);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::gossip(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,10 @@
+use exactly_once_counter::node;
+use std::error::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    logging::init()?;
+    let config = node::Config::from_env();
+    let gossip_interval = config.gossip_interval;
+    maelstrom::run(node::Node::new(config), gossip_interval).await
+}
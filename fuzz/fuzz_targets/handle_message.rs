@@ -0,0 +1,13 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|input: (u8, bool, Vec<fuzz::Message>)| {
+    let (selector, init, messages) = input;
+    let mut lines = Vec::new();
+    for message in &messages {
+        lines.extend(serde_json::to_vec(&message.to_value()).unwrap());
+        lines.push(b'\n');
+    }
+    fuzz::run(selector, init, &lines);
+});
@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+
+// Raw bytes, read as stdin would be
+fuzz_target!(|data: &[u8]| {
+    let [selector, init, data @ ..] = data else {
+        return;
+    };
+    fuzz::run(*selector, init % 2 == 1, data);
+});
@@ -0,0 +1,292 @@
+//! Fuzzing entry points for the workloads. Each workload's node gets a
+//! `handle` function that feeds raw input to a fresh `node::Node` the way
+//! `maelstrom` feeds it stdin, line by line, answering lines that don't parse
+//! as malformed. A panic anywhere along the way is a bug: malformed input,
+//! messages before init and repeated inits must be dropped or answered with
+//! an error.
+
+use arbitrary::Arbitrary;
+use serde_json::{json, Map, Number, Value};
+
+/// A valid init, which the targets can start with so the rest of an input
+/// reaches a node that's ready to serve.
+pub fn init() -> Vec<u8> {
+    let init = json!({
+        "src": "c0",
+        "dest": "n1",
+        "body": {"type": "init", "msg_id": 0, "node_id": "n1", "node_ids": ["n1", "n2", "n3"]},
+    });
+    let mut line = serde_json::to_vec(&init).unwrap();
+    line.push(b'\n');
+    line
+}
+
+macro_rules! workloads {
+    ($($name:ident => $node:expr;)*) => {
+        $(
+            pub mod $name {
+                use ::$name::node;
+                use std::io;
+
+                pub fn handle(input: &[u8]) {
+                    // kafka-style-log's node handles messages through &self
+                    #[allow(unused_mut)]
+                    let mut node = $node;
+                    maelstrom::read_from(input, io::sink(), |message: node::Message| {
+                        for reply in node.handle_message(message) {
+                            serde_json::to_vec(&reply)?;
+                        }
+                        Ok(())
+                    })
+                    .unwrap();
+                }
+            }
+        )*
+
+        /// Every workload by name, with its `handle` function.
+        pub const WORKLOADS: &[(&str, fn(&[u8]))] = &[$((stringify!($name), $name::handle)),*];
+    };
+}
+
+workloads! {
+    bank => node::Node::new(node::Config::default());
+    bloom_set => node::Node::new(node::Config::default());
+    broadcast => node::Node::new();
+    caspaxos => node::Node::new(node::Config::default());
+    causal_broadcast => node::Node::new();
+    chain_replication => node::Node::new(node::Config::default());
+    collaborative_text => node::Node::new();
+    dvv_kv => node::Node::new();
+    exactly_once_counter => node::Node::new(node::Config::default());
+    g_counter => node::Node::new(node::Config::default());
+    kafka_style_log => node::Node::new(node::Config::default());
+    lin_kv => node::Node::new(node::Config::default());
+    lock_service => node::Node::new(node::Config::default());
+    lww_kv => node::Node::new();
+    merkle_kv => node::Node::new(node::Config::default());
+    mvcc_kv => node::Node::new(node::Config::default());
+    or_set => node::Node::new();
+    paxos_kv => node::Node::new(node::Config::default());
+    pn_counter => node::Node::new();
+    primary_backup => node::Node::new(node::Config::default());
+    pub_sub => node::Node::new(node::Config::default());
+    queue => node::Node::new(node::Config::default());
+    saga => node::Node::new(node::Config::default());
+    scheduler => node::Node::new(node::Config::default());
+    sharded_kv => node::Node::new(node::Config::default());
+    shopping_cart => node::Node::new();
+    swim => node::Node::new(node::Config::default());
+    total_order_broadcast => node::Node::new();
+    two_phase_commit => node::Node::new(node::Config::default());
+    txn_list_append => node::Node::new(node::Config::default());
+    txn_rw_register => node::Node::new();
+    unique_ids => node::Node::new();
+    vr_kv => node::Node::new(node::Config::default());
+}
+
+/// Feeds `input` to the workload `selector` picks, after a valid init if
+/// `init` is set.
+pub fn run(selector: u8, init: bool, input: &[u8]) {
+    let workload = WORKLOADS[selector as usize % WORKLOADS.len()].1;
+    if init {
+        workload(&[crate::init(), input.to_vec()].concat());
+    } else {
+        workload(input);
+    }
+}
+
+/// Message types across the workloads and the consensus libraries, so
+/// structured inputs mostly reach a real handler rather than failing on the
+/// tag.
+const TYPES: &[&str] = &[
+    "init",
+    "echo",
+    "generate",
+    "broadcast",
+    "broadcast_ok",
+    "read",
+    "read_ok",
+    "topology",
+    "add",
+    "add_ok",
+    "remove",
+    "clear",
+    "write",
+    "write_ok",
+    "cas",
+    "cas_ok",
+    "send",
+    "poll",
+    "commit_offsets",
+    "list_committed_offsets",
+    "txn",
+    "txn_ok",
+    "gossip",
+    "gossip_ok",
+    "error",
+    "transfer",
+    "schedule",
+    "job_fired",
+    "subscribe",
+    "unsubscribe",
+    "publish",
+    "deliver",
+    "deliver_ok",
+    "begin",
+    "commit",
+    "abort",
+    "apply",
+    "acquire",
+    "release",
+    "renew",
+    "enqueue",
+    "dequeue",
+    "ack",
+    "insert",
+    "delete",
+    "saga",
+    "prepare",
+    "prepare_ok",
+    "ping",
+    "ping_req",
+    "sync",
+    "relay",
+    "reconcile",
+    "replicate",
+    "replicate_ok",
+    "request_vote",
+    "request_vote_ok",
+    "append_entries",
+    "append_entries_ok",
+    "install_snapshot",
+    "promise",
+    "accept",
+    "accept_ok",
+    "learn",
+    "start_view_change",
+    "do_view_change",
+    "start_view",
+];
+
+/// Field names across the workloads' bodies.
+const FIELDS: &[&str] = &[
+    "msg_id",
+    "in_reply_to",
+    "key",
+    "value",
+    "from",
+    "to",
+    "create_if_not_exists",
+    "txn",
+    "txn_id",
+    "code",
+    "text",
+    "echo",
+    "message",
+    "messages",
+    "topology",
+    "delta",
+    "deltas",
+    "element",
+    "elements",
+    "cart",
+    "item",
+    "topic",
+    "offset",
+    "offsets",
+    "msgs",
+    "queue",
+    "client",
+    "lock",
+    "token",
+    "ttl",
+    "index",
+    "context",
+    "clock",
+    "id",
+    "amount",
+    "fire_at",
+    "payload",
+    "job_id",
+    "steps",
+    "term",
+    "ballot",
+    "view",
+    "entries",
+    "leader_commit",
+    "commit_index",
+    "first_index",
+    "last_index",
+    "success",
+    "vote_granted",
+    "data",
+    "nodes",
+    "node_id",
+    "node_ids",
+];
+
+/// A message built from the workloads' own vocabulary, so mutations land on
+/// plausible shapes with the wrong values, fields or nesting in them.
+#[derive(Arbitrary, Debug)]
+pub struct Message {
+    src: u8,
+    kind: u8,
+    fields: Vec<(u8, Json)>,
+}
+
+impl Message {
+    pub fn to_value(&self) -> Value {
+        let mut body = Map::new();
+        body.insert(
+            "type".into(),
+            TYPES[self.kind as usize % TYPES.len()].into(),
+        );
+        for (field, value) in &self.fields {
+            body.insert(
+                FIELDS[*field as usize % FIELDS.len()].into(),
+                value.to_value(),
+            );
+        }
+        let src = ["c1", "n2", "n3", "n1"][self.src as usize % 4];
+        json!({
+            "src": src,
+            "dest": "n1",
+            "body": body,
+        })
+    }
+}
+
+/// An arbitrary JSON value, biased towards the strings the workloads use.
+#[derive(Arbitrary, Debug)]
+pub enum Json {
+    Null,
+    Bool(bool),
+    Int(i64),
+    Uint(u64),
+    Float(f64),
+    Node(u8),
+    Field(u8),
+    String(String),
+    Array(Vec<Json>),
+    Object(Vec<(String, Json)>),
+}
+
+impl Json {
+    fn to_value(&self) -> Value {
+        match self {
+            Json::Null => Value::Null,
+            Json::Bool(b) => (*b).into(),
+            Json::Int(i) => (*i).into(),
+            Json::Uint(u) => (*u).into(),
+            Json::Float(f) => Number::from_f64(*f).map_or(Value::Null, Value::Number),
+            Json::Node(n) => format!("n{}", n % 4).into(),
+            Json::Field(f) => FIELDS[*f as usize % FIELDS.len()].into(),
+            Json::String(s) => s.clone().into(),
+            Json::Array(values) => values.iter().map(Json::to_value).collect(),
+            Json::Object(entries) => entries
+                .iter()
+                .map(|(key, value)| (key.clone(), value.to_value()))
+                .collect(),
+        }
+    }
+}
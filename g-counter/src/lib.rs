@@ -0,0 +1,3028 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+mod store {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::fs;
+    use std::io::{self, BufRead, Write};
+    use std::path::{Path, PathBuf};
+
+    /// A node's counter state on disk: a snapshot of everything it knew, plus a journal of
+    /// records written since. Records are appended and synced one per line, so a crash loses
+    /// at most a torn last line, which recovery skips.
+    pub struct CounterStore {
+        snapshot: PathBuf,
+        journal: fs::File,
+        journal_path: PathBuf,
+        journaled: u64, // Records appended since the last snapshot
+    }
+
+    impl CounterStore {
+        pub fn open(dir: &Path, node_id: &str) -> io::Result<Self> {
+            fs::create_dir_all(dir)?;
+            let journal_path = dir.join(format!("{}.journal", node_id));
+            let journal = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&journal_path)?;
+            Ok(CounterStore {
+                snapshot: dir.join(format!("{}.snapshot.json", node_id)),
+                journal,
+                journal_path,
+                journaled: 0,
+            })
+        }
+
+        /// Reads back the last snapshot, if there is one, and the records journaled after it.
+        pub fn load<S: DeserializeOwned, R: DeserializeOwned>(
+            &mut self,
+        ) -> io::Result<(Option<S>, Vec<R>)> {
+            let snapshot = match fs::read(&self.snapshot) {
+                Ok(bytes) => Some(serde_json::from_slice(&bytes)?),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => None,
+                Err(e) => return Err(e),
+            };
+            let mut records = Vec::new();
+            for line in io::BufReader::new(fs::File::open(&self.journal_path)?).lines() {
+                match serde_json::from_str(&line?) {
+                    Ok(record) => records.push(record),
+                    Err(e) => log::warn!("Skipping unreadable journal record: {}", e),
+                }
+            }
+            self.journaled = records.len() as u64;
+            Ok((snapshot, records))
+        }
+
+        /// Appends a record to the journal and syncs it, so it survives a crash once this
+        /// returns. Returns how many records have been journaled since the last snapshot.
+        pub fn append<R: Serialize>(&mut self, record: &R) -> io::Result<u64> {
+            let mut line = serde_json::to_vec(record)?;
+            line.push(b'\n');
+            self.journal.write_all(&line)?;
+            self.journal.sync_data()?;
+            self.journaled += 1;
+            Ok(self.journaled)
+        }
+
+        /// Replaces the snapshot and empties the journal. The snapshot goes through a temporary
+        /// file, so a crash part way leaves either the old snapshot and its journal or the new
+        /// snapshot, and replaying old journal records on top of it must be harmless.
+        pub fn snapshot<S: Serialize>(&mut self, state: &S) -> io::Result<()> {
+            let tmp = self.snapshot.with_extension("json.tmp");
+            let mut file = fs::File::create(&tmp)?;
+            serde_json::to_writer(&mut file, state)?;
+            file.sync_all()?;
+            fs::rename(tmp, &self.snapshot)?;
+            self.journal.set_len(0)?;
+            self.journal.sync_all()?;
+            self.journaled = 0;
+            Ok(())
+        }
+    }
+}
+
+pub mod node {
+    use super::store::CounterStore;
+    use crdt::{Delta, Epoch, Merge, PnCounter};
+    use maelstrom::error::{ABORT, KEY_DOES_NOT_EXIST, NOT_SUPPORTED, TEMPORARILY_UNAVAILABLE};
+    use rand::seq::SliceRandom;
+    use rand::Rng;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{HashMap, HashSet, VecDeque};
+    use std::hash::{DefaultHasher, Hash, Hasher};
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock};
+    use std::time::{Duration, Instant};
+
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        config: Config,
+        counters: Counts, // Every counter we've heard of, with what each node has added
+        peers: HashSet<String>, // Nodes we gossip with: the init list and anyone who gossips to us
+        known: HashMap<String, Counts>, // What each peer has been told or told us
+        rounds: u64,      // Gossip rounds run so far
+        idle_rounds: u32, // Gossip rounds in a row with no counts changing in between
+        changed: bool,    // Whether any count changed since the last gossip round
+        last_heard: HashMap<String, Instant>, // When each peer last sent us anything
+        last_pinged: HashMap<String, Instant>, // When we last pinged each quiet peer
+        last_gossip: HashMap<String, Instant>, // When each peer last sent us its counts
+        gossip_sent: HashMap<String, Instant>, // When unanswered gossip to each peer went out
+        in_flight: HashMap<u64, (String, Counts)>, // Gossip sent, by msg_id, until it's answered
+        gossip_misses: HashMap<String, u32>, // Gossip timeouts in a row for each peer
+        suspended: HashMap<String, Option<Instant>>, // Peers left out of rounds, to when probed
+        ping_turn: Option<String>, // The last peer pinged, to start after next round
+        digest_turn: Option<String>, // The last peer sent a digest, likewise
+        kv: SeqKv,        // Only used in the seq-kv mode
+        pulls: HashMap<u64, u64>, // Counter reads sent for a quorum read, to the read's id
+        quorum_reads: HashMap<u64, QuorumRead>, // Quorum reads waiting on peers, by the read's id
+        adds: Arc<Adds>,  // What adds touch, shared with the threads applying them
+        recovery: Option<Recovery>, // Set from init until the peers we asked send their counts
+    }
+
+    /// Shards our own counts and the adds we remember are split into.
+    const SHARDS: usize = 16;
+
+    fn shard_of(name: &str) -> usize {
+        let mut hasher = DefaultHasher::new();
+        name.hash(&mut hasher);
+        hasher.finish() as usize % SHARDS
+    }
+
+    /// Everything an add touches, shared between the node and the threads `maelstrom::run`
+    /// applies adds on, so adds in the gossip mode don't wait their turn behind the node. Our
+    /// own entries in each counter are sharded by key and the adds we remember by client, so
+    /// adds only contend when they land in the same shard. The node folds our entries into
+    /// its counts before it uses them, and shares back anything it merges about us.
+    pub struct Adds {
+        id: OnceLock<String>, // Our node id, once init has run
+        gossip: bool,         // Whether adds are applied here; in seq-kv they wait on a cas
+        dedupe_window: usize,
+        snapshot_interval: u64,
+        msg_ids: AtomicU64, // The next msg_id the node sends, replies to adds included
+        pending: AtomicBool, // Whether a shard has entries the node hasn't folded in yet
+        snapshot_due: AtomicBool, // Whether `snapshot_interval` adds have been journaled
+        shards: Vec<Mutex<Shard>>,
+        recent: Vec<Mutex<Recent>>,
+        store: Mutex<Option<CounterStore>>, // Set at init when there's a data dir
+    }
+
+    /// Our own entries in the counters that hash to one shard.
+    #[derive(Default)]
+    struct Shard {
+        own: Counts,
+        added: HashSet<String>, // Counters added to since the node last folded them in
+    }
+
+    /// The adds from the clients that hash to one shard, remembered so retries count once.
+    #[derive(Default)]
+    struct Recent {
+        adds: HashSet<(String, u64)>, // (client, msg_id) of the adds we've applied lately
+        order: VecDeque<(String, u64)>, // The same adds, oldest first, for eviction
+    }
+
+    impl Adds {
+        fn new(config: &Config) -> Self {
+            Adds {
+                id: OnceLock::new(),
+                gossip: config.mode == Mode::Gossip,
+                dedupe_window: config.dedupe_window,
+                snapshot_interval: config.snapshot_interval,
+                msg_ids: AtomicU64::new(1),
+                pending: AtomicBool::new(false),
+                snapshot_due: AtomicBool::new(false),
+                shards: (0..SHARDS).map(|_| Mutex::default()).collect(),
+                recent: (0..SHARDS).map(|_| Mutex::default()).collect(),
+                store: Mutex::new(None),
+            }
+        }
+
+        fn next_msg_id(&self) -> u64 {
+            self.msg_ids.fetch_add(1, Ordering::Relaxed)
+        }
+
+        /// Whether `message` is an add we can apply here, off the node's task.
+        pub fn claims(&self, message: &Message) -> bool {
+            self.takes(&message.body)
+        }
+
+        /// Applies an add, returning the reply.
+        pub fn handle(&self, message: Message) -> Vec<Message> {
+            let Some(body) = self.apply(&message.src, &message.body) else {
+                return Vec::new();
+            };
+            vec![Message {
+                src: message.dest,
+                dest: message.src,
+                body,
+            }]
+        }
+
+        fn takes(&self, body: &Body) -> bool {
+            self.gossip
+                && self.id.get().is_some()
+                && matches!(body, Body::Add { .. } | Body::AddBatch { .. })
+        }
+
+        /// Applies an add or a batch of them from `client`, returning the reply.
+        fn apply(&self, client: &str, body: &Body) -> Option<Body> {
+            let (msg_id, key, delta) = match body {
+                Body::Add { msg_id, key, delta } => (*msg_id, key, Some(*delta)),
+                Body::AddBatch {
+                    msg_id,
+                    key,
+                    deltas,
+                } => (
+                    *msg_id,
+                    key,
+                    deltas.iter().try_fold(0i64, |sum, d| sum.checked_add(*d)),
+                ),
+                _ => return None,
+            };
+            if !self.remember(client, msg_id) {
+                log::debug!("Ignoring repeated add {} from {}", msg_id, client);
+                return Some(Body::AddOk {
+                    in_reply_to: msg_id,
+                    msg_id: self.next_msg_id(),
+                });
+            }
+            let Some(delta) = delta else {
+                self.forget(client, msg_id);
+                return Some(overflow(msg_id));
+            };
+            Some(self.add(client, msg_id, key, delta))
+        }
+
+        /// Adds `delta` to our count for `key` and journals it, returning the reply.
+        fn add(&self, client: &str, msg_id: u64, key: &str, delta: i64) -> Body {
+            let id = self.id.get().expect("adds are only applied after init");
+            let record = {
+                let mut shard = self.shards[shard_of(key)].lock().unwrap();
+                let counter = shard.own.entry(key.to_string()).or_default();
+                if !counter.get_mut().add(id, delta) {
+                    drop(shard);
+                    self.forget(client, msg_id);
+                    return overflow(msg_id);
+                }
+                let record = Journaled {
+                    key: key.to_string(),
+                    counter: counter.clone(),
+                };
+                shard.added.insert(key.to_string());
+                record
+            };
+            self.pending.store(true, Ordering::Release);
+            self.journal(&record);
+            Body::AddOk {
+                in_reply_to: msg_id,
+                msg_id: self.next_msg_id(),
+            }
+        }
+
+        /// Journals our entries in a counter after an add, if there's a store, and flags a
+        /// snapshot for the node once `snapshot_interval` adds have been journaled.
+        fn journal(&self, record: &Journaled) {
+            let mut store = self.store.lock().unwrap();
+            let Some(store) = store.as_mut() else {
+                return;
+            };
+            match store.append(record) {
+                Ok(journaled) if journaled >= self.snapshot_interval => {
+                    self.snapshot_due.store(true, Ordering::Release);
+                }
+                Ok(_) => {}
+                Err(e) => log::error!("Unable to persist our count for {:?}: {}", record.key, e),
+            }
+        }
+
+        /// Records an add from `client`, returning false if it's one we've already applied.
+        /// Each shard of clients only remembers its last `dedupe_window` adds.
+        fn remember(&self, client: &str, msg_id: u64) -> bool {
+            let add = (client.to_string(), msg_id);
+            let mut recent = self.recent[shard_of(client)].lock().unwrap();
+            if !recent.adds.insert(add.clone()) {
+                return false;
+            }
+            recent.order.push_back(add);
+            while recent.order.len() > self.dedupe_window {
+                if let Some(oldest) = recent.order.pop_front() {
+                    recent.adds.remove(&oldest);
+                }
+            }
+            true
+        }
+
+        /// Forgets an add we refused, so a retry of it is tried again rather than acknowledged.
+        fn forget(&self, client: &str, msg_id: u64) {
+            self.recent[shard_of(client)]
+                .lock()
+                .unwrap()
+                .adds
+                .remove(&(client.to_string(), msg_id));
+        }
+    }
+
+    /// Peers asked for their counts at init, and the local reads held until they answer, so a
+    /// restarted node doesn't answer from the little it remembers.
+    struct Recovery {
+        waiting: HashSet<String>,
+        deadline: Instant, // When we stop waiting and serve what we have
+        reads: Vec<(String, u64, String)>, // (client, msg_id, key) of each held read
+    }
+
+    /// Our own entries in a counter after an add, as journaled. Recovery merges these like
+    /// gossip, so replaying one that's already in the snapshot changes nothing.
+    #[derive(Serialize, Deserialize)]
+    struct Journaled {
+        key: String,
+        counter: Epoch<PnCounter>,
+    }
+
+    /// Every counter, by name, in the epoch it was last reset into. Requests without a key use
+    /// the unnamed counter "".
+    type Counts = HashMap<String, Epoch<PnCounter>>;
+
+    /// How fresh a client's read has to be.
+    #[derive(Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+    #[serde(rename_all = "lowercase")]
+    pub enum Consistency {
+        /// Whatever this node has merged so far.
+        #[default]
+        Local,
+        /// Counts pulled from a majority of nodes, ourselves included.
+        Quorum,
+    }
+
+    /// A quorum read waiting for enough peers to send back their counts.
+    struct QuorumRead {
+        client: String,
+        msg_id: u64,
+        key: String,
+        waiting: usize,
+        deadline: Instant, // When we stop waiting and answer from what we have
+    }
+
+    /// How far one peer is from us, as far as we can tell from what it's sent.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct PeerStats {
+        /// Milliseconds since the peer last sent us its counts, if it ever has.
+        last_gossip_ms: Option<u64>,
+        /// Our value minus the value the peer last reported, for every counter they differ on.
+        lag: HashMap<String, i128>,
+    }
+
+    /// How nodes share their counts.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Mode {
+        /// Nodes gossip their counts to each other directly.
+        Gossip,
+        /// Each node keeps its running total in seq-kv, and reads sum every node's total.
+        SeqKv,
+    }
+
+    impl FromStr for Mode {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "gossip" => Ok(Mode::Gossip),
+                "seq-kv" => Ok(Mode::SeqKv),
+                _ => Err(format!("unknown mode {:?}", s)),
+            }
+        }
+    }
+
+    /// Maelstrom's sequentially consistent key/value service.
+    const SEQ_KV: &str = "seq-kv";
+
+    /// A hash of every entry in `counts`, visited in sorted order so equal states hash the
+    /// same everywhere. Every node runs the same binary, so `DefaultHasher` agrees across them.
+    fn digest(counts: &Counts) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        let mut keys: Vec<&String> = counts.keys().collect();
+        keys.sort();
+        for key in keys {
+            let counter = &counts[key];
+            key.hash(&mut hasher);
+            counter.epoch().hash(&mut hasher);
+            let counter = counter.get();
+            for mut entries in [
+                counter.increments().iter().collect::<Vec<_>>(),
+                counter.decrements().iter().collect(),
+                counter.versions().collect(),
+            ] {
+                entries.sort();
+                entries.hash(&mut hasher);
+            }
+        }
+        hasher.finish()
+    }
+
+    /// Up to `limit` of `peers`, taking turns: the list starts after `last`, the peer that
+    /// went last time, and wraps around, so a limit doesn't leave out the same peers each time.
+    fn take_turns(mut peers: Vec<String>, last: &mut Option<String>, limit: usize) -> Vec<String> {
+        peers.sort();
+        if let Some(last) = last {
+            let start = peers.partition_point(|peer| peer <= last);
+            peers.rotate_left(start);
+        }
+        peers.truncate(limit);
+        if let Some(peer) = peers.last() {
+            *last = Some(peer.clone());
+        }
+        peers
+    }
+
+    /// Unpacks the counts in gossip that arrived packed, so it can be handled like any other.
+    fn unpack(body: &mut Body) -> std::io::Result<()> {
+        if let Body::Gossip { counts, packed, .. } | Body::GossipOk { counts, packed, .. } = body {
+            if let Some(packed) = packed.take() {
+                *counts = compression::unpack(&packed)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// The reply to an add that would overflow a count, which is refused rather than wrapped.
+    fn overflow(in_reply_to: u64) -> Body {
+        Body::Error {
+            in_reply_to,
+            code: ABORT,
+            text: "add would overflow the counter".to_string(),
+        }
+    }
+
+    /// The reply to a read of `value`, or an error if it doesn't fit in the i64 clients read.
+    fn read_result(in_reply_to: u64, msg_id: u64, value: i128) -> Body {
+        match i64::try_from(value) {
+            Ok(value) => Body::ReadOk {
+                in_reply_to,
+                msg_id,
+                value,
+                stale: false,
+            },
+            Err(_) => Body::Error {
+                in_reply_to,
+                code: ABORT,
+                text: format!("counter value {} is out of range", value),
+            },
+        }
+    }
+
+    /// Bookkeeping for the seq-kv mode. Every node's total for a counter lives under its own
+    /// key (see `kv_key`) and is only ever written by that node, through a read/cas loop so a
+    /// restarted node picks up where it left off instead of overwriting its total.
+    #[derive(Default)]
+    struct SeqKv {
+        totals: HashMap<String, Total>, // Our own total for each counter, by counter name
+        syncs: HashMap<u64, u64>,       // Sync writes in flight, to the client read they serve
+        reads: HashMap<u64, u64>,       // Key reads in flight, to the client read they serve
+        sums: HashMap<u64, Sum>,        // Client reads being summed, by the read's id
+    }
+
+    /// Our total for one counter in seq-kv.
+    #[derive(Default)]
+    struct Total {
+        value: Option<i64>, // Our key's value when we last read or wrote it
+        read: Option<u64>,  // msg_id of the read of our own key, if one is in flight
+        queued: Vec<Add>,   // Adds waiting for the next cas
+        cas: Option<Cas>,   // The cas in flight, if any
+    }
+
+    /// Where `node`'s total for `counter` is kept in seq-kv. The unnamed counter keeps the
+    /// plain node id.
+    fn kv_key(counter: &str, node: &str) -> String {
+        if counter.is_empty() {
+            node.to_string()
+        } else {
+            format!("{}/{}", counter, node)
+        }
+    }
+
+    /// A client's add waiting on seq-kv: (client, msg_id, delta).
+    type Add = (String, u64, i64);
+
+    /// A cas of our total in flight: (msg_id, to, the adds it carries).
+    type Cas = (u64, i64, Vec<Add>);
+
+    /// A client read adding up every node's total from seq-kv.
+    struct Sum {
+        client: String,
+        msg_id: u64,
+        key: String,
+        pending: usize,
+        value: i128,
+        deadline: Instant, // When we stop waiting on seq-kv and answer with an error
+    }
+
+    /// Tunables, read from `COUNTER_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Peers asked for their counts at init, picked at random; 0 to serve reads straight
+        /// away from whatever we recovered from disk.
+        pub recovery_peers: usize,
+        /// How long reads are held at init waiting for those peers.
+        pub recovery_timeout: Duration,
+        /// How long a quorum read waits for a majority before answering from our own counts,
+        /// marked stale.
+        pub quorum_read_timeout: Duration,
+        /// Peers gossiped to each round, picked at random; 0 for all of them.
+        pub fanout: usize,
+        /// Rounds between sending digests to the peers we think are in sync, which repairs
+        /// anything we wrongly think they have; 0 to only ever send deltas.
+        pub digest_rounds: u64,
+        /// Time between gossip rounds while counts are changing.
+        pub gossip_interval: Duration,
+        /// Random extra delay added to every round, up to this much.
+        pub gossip_jitter: Duration,
+        /// Longest the interval backs off to while nothing changes.
+        pub max_gossip_interval: Duration,
+        /// Recent adds remembered by (client, msg_id), so a retried add isn't counted twice.
+        /// Clients are split into shards, and each shard remembers this many.
+        pub dedupe_window: usize,
+        /// Entries past which gossip is compressed; 0 to never compress it.
+        pub compress_threshold: usize,
+        /// Most messages a gossip round sends, pings and digests included, so a lot of peers
+        /// going quiet at once doesn't flood the network with retries; 0 for no limit.
+        /// Gossip to responsive peers goes first, and peers past the limit wait their turn.
+        pub max_round_messages: usize,
+        /// How long a peer can be quiet before the gossip loop pings it.
+        pub ping_interval: Duration,
+        /// How long a peer can be quiet, pings included, before we stop gossiping to it.
+        pub peer_timeout: Duration,
+        /// How long gossip can go unanswered before it counts as timed out.
+        pub gossip_timeout: Duration,
+        /// Gossip timeouts in a row after which a peer is left out of rounds and only probed,
+        /// until it answers; 0 to never suspend peers.
+        pub suspend_after: u32,
+        /// Time between probes of a suspended peer.
+        pub probe_interval: Duration,
+        pub mode: Mode,
+        /// Where to keep our counts across restarts; nothing is persisted without one. Only
+        /// used in the gossip mode, since seq-kv already holds each node's total.
+        pub data_dir: Option<PathBuf>,
+        /// Journaled adds between snapshots of every counter, which truncate the journal.
+        pub snapshot_interval: u64,
+        /// How long a read in the seq-kv mode waits on seq-kv before it fails.
+        pub kv_timeout: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                recovery_peers: 2,
+                recovery_timeout: Duration::from_millis(1000),
+                quorum_read_timeout: Duration::from_millis(1000),
+                fanout: 3,
+                digest_rounds: 20,
+                gossip_interval: Duration::from_millis(50),
+                gossip_jitter: Duration::from_millis(10),
+                max_gossip_interval: Duration::from_millis(1000),
+                dedupe_window: 10_000,
+                compress_threshold: 256,
+                max_round_messages: 16,
+                ping_interval: Duration::from_millis(500),
+                peer_timeout: Duration::from_millis(3000),
+                gossip_timeout: Duration::from_millis(500),
+                suspend_after: 3,
+                probe_interval: Duration::from_millis(2000),
+                mode: Mode::Gossip,
+                data_dir: None,
+                snapshot_interval: 1000,
+                kv_timeout: Duration::from_millis(1000),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                recovery_peers: env_or("COUNTER_RECOVERY_PEERS", default.recovery_peers),
+                recovery_timeout: Duration::from_millis(env_or(
+                    "COUNTER_RECOVERY_TIMEOUT_MS",
+                    default.recovery_timeout.as_millis() as u64,
+                )),
+                quorum_read_timeout: Duration::from_millis(env_or(
+                    "COUNTER_QUORUM_READ_TIMEOUT_MS",
+                    default.quorum_read_timeout.as_millis() as u64,
+                )),
+                fanout: env_or("COUNTER_FANOUT", default.fanout),
+                digest_rounds: env_or("COUNTER_DIGEST_ROUNDS", default.digest_rounds),
+                gossip_interval: Duration::from_millis(env_or(
+                    "COUNTER_GOSSIP_INTERVAL_MS",
+                    default.gossip_interval.as_millis() as u64,
+                )),
+                gossip_jitter: Duration::from_millis(env_or(
+                    "COUNTER_GOSSIP_JITTER_MS",
+                    default.gossip_jitter.as_millis() as u64,
+                )),
+                max_gossip_interval: Duration::from_millis(env_or(
+                    "COUNTER_MAX_GOSSIP_INTERVAL_MS",
+                    default.max_gossip_interval.as_millis() as u64,
+                )),
+                dedupe_window: env_or("COUNTER_DEDUPE_WINDOW", default.dedupe_window),
+                compress_threshold: env_or(
+                    "COUNTER_COMPRESS_THRESHOLD",
+                    default.compress_threshold,
+                ),
+                max_round_messages: env_or(
+                    "COUNTER_MAX_ROUND_MESSAGES",
+                    default.max_round_messages,
+                ),
+                ping_interval: Duration::from_millis(env_or(
+                    "COUNTER_PING_INTERVAL_MS",
+                    default.ping_interval.as_millis() as u64,
+                )),
+                peer_timeout: Duration::from_millis(env_or(
+                    "COUNTER_PEER_TIMEOUT_MS",
+                    default.peer_timeout.as_millis() as u64,
+                )),
+                gossip_timeout: Duration::from_millis(env_or(
+                    "COUNTER_GOSSIP_TIMEOUT_MS",
+                    default.gossip_timeout.as_millis() as u64,
+                )),
+                suspend_after: env_or("COUNTER_SUSPEND_AFTER", default.suspend_after),
+                probe_interval: Duration::from_millis(env_or(
+                    "COUNTER_PROBE_INTERVAL_MS",
+                    default.probe_interval.as_millis() as u64,
+                )),
+                mode: env_or("COUNTER_MODE", default.mode),
+                data_dir: std::env::var_os("COUNTER_DATA_DIR").map(PathBuf::from),
+                snapshot_interval: env_or("COUNTER_SNAPSHOT_INTERVAL", default.snapshot_interval),
+                kv_timeout: Duration::from_millis(env_or(
+                    "COUNTER_KV_TIMEOUT_MS",
+                    default.kv_timeout.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Add {
+            msg_id: u64,
+            #[serde(default)]
+            key: String,
+            delta: i64,
+        },
+        /// Several adds to one counter, applied together: all of them or, if their sum would
+        /// overflow, none.
+        AddBatch {
+            msg_id: u64,
+            #[serde(default)]
+            key: String,
+            deltas: Vec<i64>,
+        },
+        AddOk {
+            in_reply_to: u64,
+            msg_id: u64,
+        },
+        /// A client's read of a counter, or our read of a key in seq-kv.
+        Read {
+            msg_id: u64,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            key: Option<String>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            consistency: Option<Consistency>,
+        },
+        ReadOk {
+            in_reply_to: u64,
+            #[serde(default)]
+            msg_id: u64,
+            value: i64,
+            /// Set on a quorum read answered from our own counts, because a majority didn't
+            /// reply in time.
+            #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+            stale: bool,
+        },
+        Write {
+            msg_id: u64,
+            key: String,
+            value: i64,
+        },
+        WriteOk {
+            in_reply_to: u64,
+        },
+        Cas {
+            msg_id: u64,
+            key: String,
+            from: i64,
+            to: i64,
+            create_if_not_exists: bool,
+        },
+        CasOk {
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// The counts the sender thinks we're missing. Past `compress_threshold` entries they
+        /// travel in `packed` instead, and are unpacked before the gossip is handled.
+        Gossip {
+            msg_id: u64,
+            #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+            counts: Counts,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            packed: Option<String>,
+        },
+        /// The counts the receiver of a gossip thinks its sender is missing, once it's merged
+        /// the gossip in, so both sides catch up in one exchange. When `full` is set, this is
+        /// everything the receiver knows instead.
+        GossipOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+            counts: Counts,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            packed: Option<String>,
+            #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+            full: bool,
+        },
+        /// Everything this node knows about a counter, for spotting replicas that disagree.
+        ReadDetailed {
+            msg_id: u64,
+            #[serde(default)]
+            key: String,
+        },
+        /// Each node's count as merged here, and the total they add up to.
+        ReadDetailedOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            counter: PnCounter,
+            epoch: u64,
+            value: i128,
+        },
+        /// An admin request for every counter's merged state, or just `key`'s, optionally
+        /// resetting them all to zero by starting a new epoch once they've been read.
+        Snapshot {
+            msg_id: u64,
+            #[serde(default)]
+            key: Option<String>,
+            #[serde(default)]
+            reset: bool,
+        },
+        /// The counters as they were before any reset.
+        SnapshotOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            counts: Counts,
+        },
+        /// A hash of everything the sender knows, to check a peer is in sync without sending
+        /// the whole map. A peer whose own digest differs replies with a gossip_ok.
+        Digest {
+            msg_id: u64,
+            digest: u64,
+        },
+        /// The receiver's state hashes to `digest` too.
+        DigestOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            digest: u64,
+        },
+        /// Asks a peer for everything it knows, which it sends back as a full gossip_ok. Sent
+        /// by a node starting up, to catch up before it serves reads.
+        Recover {
+            msg_id: u64,
+        },
+        /// A liveness probe, from a peer's gossip loop or an operator.
+        Ping {
+            msg_id: u64,
+        },
+        Pong {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Asks how stale each peer's counts are, to see how well gossip is converging.
+        Stats {
+            msg_id: u64,
+        },
+        StatsOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            peers: HashMap<String, PeerStats>,
+        },
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                adds: Arc::new(Adds::new(&config)),
+                config,
+                counters: HashMap::new(),
+                peers: HashSet::new(),
+                known: HashMap::new(),
+                rounds: 0,
+                idle_rounds: 0,
+                changed: false,
+                last_heard: HashMap::new(),
+                last_pinged: HashMap::new(),
+                last_gossip: HashMap::new(),
+                gossip_sent: HashMap::new(),
+                in_flight: HashMap::new(),
+                gossip_misses: HashMap::new(),
+                suspended: HashMap::new(),
+                ping_turn: None,
+                digest_turn: None,
+                kv: SeqKv::default(),
+                pulls: HashMap::new(),
+                quorum_reads: HashMap::new(),
+                recovery: None,
+            }
+        }
+
+        fn next_msg_id(&self) -> u64 {
+            self.adds.next_msg_id()
+        }
+
+        /// The part of the node that applies adds, for `maelstrom::run` to hand them to.
+        pub fn adds(&self) -> Arc<Adds> {
+            self.adds.clone()
+        }
+
+        /// Merges in the entries adds have made to our counts since we last looked, and
+        /// snapshots every counter if enough adds have been journaled. The store stays locked
+        /// from the fold to the snapshot, so an add journaled after the fold lands in the fresh
+        /// journal rather than being truncated away.
+        fn fold(&mut self) {
+            if !self.adds.snapshot_due.swap(false, Ordering::AcqRel) {
+                self.fold_shards();
+                return;
+            }
+            let adds = self.adds.clone();
+            let mut store = adds.store.lock().unwrap();
+            self.fold_shards();
+            if let Some(store) = store.as_mut() {
+                if let Err(e) = store.snapshot(&self.counters) {
+                    log::error!("Unable to snapshot counters: {}", e);
+                }
+            }
+        }
+
+        fn fold_shards(&mut self) {
+            if !self.adds.pending.swap(false, Ordering::AcqRel) {
+                return;
+            }
+            for shard in &self.adds.shards {
+                let mut shard = shard.lock().unwrap();
+                let Shard { own, added } = &mut *shard;
+                for key in added.drain() {
+                    self.changed |= self
+                        .counters
+                        .entry(key.clone())
+                        .or_default()
+                        .merge(&own[&key]);
+                }
+            }
+        }
+
+        /// Merges `counter` into our counts for `key`. Anything it tells us about our own
+        /// entries, like a reset into a new epoch or more of our count than we remembered after
+        /// a restart, goes back into our shard so later adds build on it.
+        fn merge_counter(&mut self, key: &str, counter: &Epoch<PnCounter>) {
+            if !self
+                .counters
+                .entry(key.to_string())
+                .or_default()
+                .merge(counter)
+            {
+                return;
+            }
+            self.changed = true;
+            self.share(key);
+        }
+
+        /// Brings our entries in `key`'s shard up to date with our merged counts.
+        fn share(&self, key: &str) {
+            let Some(counter) = self.counters.get(key) else {
+                return;
+            };
+            let own = Epoch::new(counter.epoch(), counter.get().only(&self.id));
+            self.adds.shards[shard_of(key)]
+                .lock()
+                .unwrap()
+                .own
+                .entry(key.to_string())
+                .or_default()
+                .merge(&own);
+        }
+
+        pub fn gossip(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            if self.config.mode == Mode::SeqKv {
+                return messages;
+            }
+            self.fold();
+            self.rounds += 1;
+            if self.changed {
+                self.idle_rounds = 0;
+            } else {
+                self.idle_rounds = self.idle_rounds.saturating_add(1);
+            }
+            self.changed = false;
+            self.time_out_gossip();
+            let budget = match self.config.max_round_messages {
+                0 => usize::MAX,
+                max => max,
+            };
+            // Each peer only hears about the entries it hasn't confirmed having, so peers with
+            // nothing to learn (and ourselves) aren't sent anything. A delta is resent every
+            // round until the peer's gossip_ok shows it arrived.
+            // Unresponsive peers are only pinged until they answer; once they do, the deltas
+            // they missed go out again as normal
+            let mut targets: Vec<(String, Counts)> = self
+                .peers
+                .iter()
+                .filter(|node| **node != self.id && self.responsive(node))
+                .filter(|peer| !self.suspended.contains_key(*peer))
+                .map(|peer| (peer.clone(), self.delta_for(peer)))
+                .filter(|(_, delta)| !delta.is_empty())
+                .collect();
+            // A few random peers a round still reaches everyone in O(log n) rounds, and picking
+            // at random rotates who's left out when the round's budget runs short
+            targets.shuffle(&mut rand::thread_rng());
+            if self.config.fanout > 0 {
+                targets.truncate(self.config.fanout);
+            }
+            targets.truncate(budget);
+            let now = Instant::now();
+            for (cnode, counts) in targets {
+                let msg_id = self.next_msg_id();
+                self.gossip_sent.entry(cnode.clone()).or_insert(now);
+                self.in_flight
+                    .insert(msg_id, (cnode.clone(), counts.clone()));
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: cnode,
+                    body: Body::Gossip {
+                        msg_id,
+                        counts,
+                        packed: None,
+                    },
+                });
+            }
+            let left = budget - messages.len();
+            self.ping_quiet_peers(&mut messages, left);
+            let left = budget - messages.len();
+            self.probe_suspended(&mut messages, left);
+            if self.config.digest_rounds > 0
+                && self.rounds.is_multiple_of(self.config.digest_rounds)
+            {
+                let left = budget - messages.len();
+                self.send_digests(&mut messages, left);
+            }
+            self.pack(&mut messages);
+            messages
+        }
+
+        /// Sends our digest to every responsive peer we have nothing to send, or the next
+        /// `limit` of them. One whose state differs answers with all of it, which shows us what
+        /// it really lacks.
+        fn send_digests(&mut self, messages: &mut Vec<Message>, limit: usize) {
+            let digest = digest(&self.counters);
+            let peers: Vec<String> = self
+                .peers
+                .iter()
+                .filter(|peer| **peer != self.id && self.responsive(peer))
+                .filter(|peer| !self.suspended.contains_key(*peer))
+                .filter(|peer| self.delta_for(peer).is_empty())
+                .cloned()
+                .collect();
+            for peer in take_turns(peers, &mut self.digest_turn, limit) {
+                let msg_id = self.next_msg_id();
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Digest { msg_id, digest },
+                });
+            }
+        }
+
+        /// Pings every peer we haven't heard from in `ping_interval`, at most once an interval,
+        /// or the next `limit` of them. The rest stay quiet, so they're pinged in a later round.
+        fn ping_quiet_peers(&mut self, messages: &mut Vec<Message>, limit: usize) {
+            let now = Instant::now();
+            let interval = self.config.ping_interval;
+            let quiet = |heard: Option<&Instant>| heard.is_none_or(|at| now - *at >= interval);
+            let peers: Vec<String> = self
+                .peers
+                .iter()
+                .filter(|peer| **peer != self.id && !self.suspended.contains_key(*peer))
+                .filter(|peer| quiet(self.last_heard.get(*peer)))
+                .filter(|peer| quiet(self.last_pinged.get(*peer)))
+                .cloned()
+                .collect();
+            for peer in take_turns(peers, &mut self.ping_turn, limit) {
+                self.last_pinged.insert(peer.clone(), now);
+                let msg_id = self.next_msg_id();
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Ping { msg_id },
+                });
+            }
+        }
+
+        /// Counts a timeout against every peer whose gossip has gone unanswered for
+        /// `gossip_timeout`, and suspends those that reach `suspend_after` of them in a row.
+        /// Their next gossip starts another timeout.
+        fn time_out_gossip(&mut self) {
+            let timeout = self.config.gossip_timeout;
+            let mut timed_out: Vec<String> = self
+                .gossip_sent
+                .iter()
+                .filter(|(_, sent)| sent.elapsed() >= timeout)
+                .map(|(peer, _)| peer.clone())
+                .collect();
+            timed_out.sort();
+            for peer in timed_out {
+                self.gossip_sent.remove(&peer);
+                self.in_flight.retain(|_, (sent_to, _)| *sent_to != peer);
+                let misses = self.gossip_misses.entry(peer.clone()).or_default();
+                *misses += 1;
+                if self.config.suspend_after > 0 && *misses >= self.config.suspend_after {
+                    log::warn!("Suspending {} after {} gossip timeouts", peer, misses);
+                    self.suspended.insert(peer, None);
+                }
+            }
+        }
+
+        /// Pings the next `limit` suspended peers that are due a probe, once a
+        /// `probe_interval`. Any reply puts them back in the rounds.
+        fn probe_suspended(&mut self, messages: &mut Vec<Message>, limit: usize) {
+            let now = Instant::now();
+            let interval = self.config.probe_interval;
+            let mut due: Vec<String> = self
+                .suspended
+                .iter()
+                .filter(|(_, probed)| probed.is_none_or(|at| now - at >= interval))
+                .map(|(peer, _)| peer.clone())
+                .collect();
+            due.sort();
+            due.truncate(limit);
+            for peer in due {
+                self.suspended.insert(peer.clone(), Some(now));
+                let msg_id = self.next_msg_id();
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Ping { msg_id },
+                });
+            }
+        }
+
+        /// Puts `peer` back in the rounds, now it's answered us.
+        fn restore(&mut self, peer: &str) {
+            if self.suspended.remove(peer).is_some() {
+                log::info!("{} answered, restoring it to gossip rounds", peer);
+            }
+            self.gossip_sent.remove(peer);
+            self.gossip_misses.remove(peer);
+        }
+
+        /// Whether `peer` has sent us anything within `peer_timeout`. Peers count as heard
+        /// from at init, so they get one timeout's grace to start up.
+        fn responsive(&self, peer: &str) -> bool {
+            self.last_heard
+                .get(peer)
+                .is_some_and(|at| at.elapsed() <= self.config.peer_timeout)
+        }
+
+        /// How long to wait before the next gossip round: `gossip_interval`, doubled for each
+        /// idle round up to `max_gossip_interval`, plus up to `gossip_jitter` at random so
+        /// nodes started together don't gossip in lockstep.
+        pub fn gossip_delay(&self) -> Duration {
+            let backoff = 2u32.saturating_pow(self.idle_rounds);
+            let interval = self.config.gossip_interval.saturating_mul(backoff).min(
+                self.config
+                    .max_gossip_interval
+                    .max(self.config.gossip_interval),
+            );
+            let jitter = self.config.gossip_jitter.as_millis() as u64;
+            interval + Duration::from_millis(rand::thread_rng().gen_range(0..=jitter))
+        }
+
+        /// Whether something changed while gossip was backed off, so the next round should
+        /// run now rather than after the rest of the delay.
+        pub fn gossip_due(&self) -> bool {
+            (self.changed || self.adds.pending.load(Ordering::Acquire)) && self.idle_rounds > 0
+        }
+
+        /// The entries `peer` hasn't seen at their current values, for every counter.
+        fn delta_for(&self, peer: &str) -> Counts {
+            let known = self.known.get(peer);
+            self.counters
+                .iter()
+                .filter_map(|(key, counter)| {
+                    let delta = match known.and_then(|known| known.get(key)) {
+                        Some(known) => counter.delta_since(known),
+                        None => counter.delta_since(&Epoch::default()),
+                    };
+                    Some((key.clone(), delta?))
+                })
+                .collect()
+        }
+
+        /// Merges counts `src` sent us. Nodes we haven't heard of are added rather than
+        /// rejected, so members that joined after init still count, and `src` becomes a peer
+        /// we gossip with.
+        fn merge(&mut self, src: &str, counts: &Counts) {
+            for (key, counter) in counts {
+                self.merge_counter(key, counter);
+            }
+            self.peers.insert(src.to_string());
+            self.last_gossip.insert(src.to_string(), Instant::now());
+            self.learned(src, counts);
+        }
+
+        /// How stale each peer's counts look from here: when it last gossiped, and how far the
+        /// values it's shown us trail ours.
+        fn peer_stats(&self) -> HashMap<String, PeerStats> {
+            let empty = Counts::new();
+            self.peers
+                .iter()
+                .filter(|peer| **peer != self.id)
+                .map(|peer| {
+                    let known = self.known.get(peer).unwrap_or(&empty);
+                    let lag = self
+                        .counters
+                        .iter()
+                        .map(|(key, counter)| {
+                            let theirs = known.get(key).map_or(0, |known| known.get().value());
+                            (key.clone(), counter.get().value() - theirs)
+                        })
+                        .filter(|(_, lag)| *lag != 0)
+                        .collect();
+                    let stats = PeerStats {
+                        last_gossip_ms: self
+                            .last_gossip
+                            .get(peer)
+                            .map(|at| at.elapsed().as_millis() as u64),
+                        lag,
+                    };
+                    (peer.clone(), stats)
+                })
+                .collect()
+        }
+
+        /// Records that `peer` has the gossip we sent it as `msg_id`. Earlier gossip to it that's
+        /// still unanswered is forgotten, since anything it carried that `peer` still lacks is
+        /// in our next delta anyway.
+        fn acked(&mut self, peer: &str, msg_id: u64) {
+            if self
+                .in_flight
+                .get(&msg_id)
+                .is_none_or(|(sent_to, _)| sent_to != peer)
+            {
+                return;
+            }
+            let (_, counts) = self.in_flight.remove(&msg_id).unwrap();
+            self.in_flight
+                .retain(|sent_id, (sent_to, _)| sent_to != peer || *sent_id > msg_id);
+            self.learned(peer, &counts);
+        }
+
+        /// Records that `peer` has shown us it knows at least `counts`.
+        fn learned(&mut self, peer: &str, counts: &Counts) {
+            let known = self.known.entry(peer.to_string()).or_default();
+            for (key, counter) in counts {
+                known.entry(key.clone()).or_default().merge(counter);
+            }
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "counters": self.counters.len(),
+                "rounds": self.rounds,
+                "idle_rounds": self.idle_rounds,
+                "in_flight": self.in_flight.len(),
+                "gossip_misses": self.gossip_misses,
+                "suspended": self.suspended.keys().collect::<Vec<_>>(),
+                "quorum_reads": self.quorum_reads.len(),
+                "recovering": self.recovery.is_some(),
+            })
+        }
+
+        pub fn handle_message(&mut self, mut message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            self.fold();
+            let mut messages = Vec::new();
+            if let Err(e) = unpack(&mut message.body) {
+                log::error!(
+                    "Dropping gossip from {} we can't unpack: {}",
+                    message.src,
+                    e
+                );
+                return messages;
+            }
+            let resp_body = self.handle_body(&message.src, &message.body, &mut messages);
+            if self.peers.contains(&message.src) {
+                self.last_heard.insert(message.src.clone(), Instant::now());
+            }
+            if self.suspended.contains_key(&message.src)
+                || matches!(message.body, Body::GossipOk { .. })
+            {
+                self.restore(&message.src);
+            }
+            if let Some(body) = resp_body {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+            }
+            self.pack(&mut messages);
+
+            messages
+        }
+
+        /// Packs the counts in outgoing gossip that has more than `compress_threshold` entries.
+        fn pack(&self, messages: &mut [Message]) {
+            if self.config.compress_threshold == 0 {
+                return;
+            }
+            for message in messages {
+                let (Body::Gossip { counts, packed, .. } | Body::GossipOk { counts, packed, .. }) =
+                    &mut message.body
+                else {
+                    continue;
+                };
+                let entries: usize = counts
+                    .values()
+                    .map(|counter| {
+                        let counter = counter.get();
+                        counter.increments().iter().count() + counter.decrements().iter().count()
+                    })
+                    .sum();
+                if entries <= self.config.compress_threshold {
+                    continue;
+                }
+                match compression::pack(counts) {
+                    Ok(compressed) => {
+                        *packed = Some(compressed);
+                        counts.clear();
+                    }
+                    Err(e) => log::warn!("Sending gossip unpacked, since packing failed: {}", e),
+                }
+            }
+        }
+
+        /// Starts a quorum read: every peer is asked for its counter, and the read is answered
+        /// once a majority of nodes, counting ourselves, have replied and been merged in, or
+        /// from what we have when `quorum_read_timeout` runs out.
+        fn pull_counts(&mut self, client: &str, msg_id: u64, key: &str, outbox: &mut Vec<Message>) {
+            let read_id = self.next_msg_id();
+            self.quorum_reads.insert(
+                read_id,
+                QuorumRead {
+                    client: client.to_string(),
+                    msg_id,
+                    key: key.to_string(),
+                    waiting: self.peers.len() / 2,
+                    deadline: Instant::now() + self.config.quorum_read_timeout,
+                },
+            );
+            let mut peers: Vec<String> = self
+                .peers
+                .iter()
+                .filter(|peer| **peer != self.id)
+                .cloned()
+                .collect();
+            peers.sort();
+            for peer in peers {
+                let pull_id = self.next_msg_id();
+                self.pulls.insert(pull_id, read_id);
+                outbox.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::ReadDetailed {
+                        msg_id: pull_id,
+                        key: key.to_string(),
+                    },
+                });
+            }
+            self.finish_quorum_read(read_id, outbox);
+        }
+
+        /// Merges a peer's counter, and counts it towards the quorum read it was pulled for.
+        fn pulled(
+            &mut self,
+            src: &str,
+            in_reply_to: u64,
+            counter: Epoch<PnCounter>,
+            outbox: &mut Vec<Message>,
+        ) {
+            let Some(read_id) = self.pulls.remove(&in_reply_to) else {
+                return;
+            };
+            let Some(read) = self.quorum_reads.get_mut(&read_id) else {
+                return;
+            };
+            read.waiting = read.waiting.saturating_sub(1);
+            let key = read.key.clone();
+            self.merge_counter(&key, &counter);
+            self.learned(src, &Counts::from([(key, counter)]));
+            self.finish_quorum_read(read_id, outbox);
+        }
+
+        /// Asks a few peers for everything they know, and holds reads until they've answered.
+        fn start_recovery(&mut self, outbox: &mut Vec<Message>) {
+            if self.config.mode != Mode::Gossip {
+                return;
+            }
+            let mut peers: Vec<String> = self
+                .peers
+                .iter()
+                .filter(|peer| **peer != self.id)
+                .cloned()
+                .collect();
+            peers.shuffle(&mut rand::thread_rng());
+            peers.truncate(self.config.recovery_peers);
+            if peers.is_empty() {
+                return;
+            }
+            for peer in &peers {
+                let msg_id = self.next_msg_id();
+                outbox.push(Message {
+                    src: self.id.clone(),
+                    dest: peer.clone(),
+                    body: Body::Recover { msg_id },
+                });
+            }
+            self.recovery = Some(Recovery {
+                waiting: peers.into_iter().collect(),
+                deadline: Instant::now() + self.config.recovery_timeout,
+                reads: Vec::new(),
+            });
+        }
+
+        /// Notes that `peer` has sent its counts, finishing recovery once everyone asked has.
+        fn recovered(&mut self, peer: &str, outbox: &mut Vec<Message>) {
+            let Some(recovery) = &mut self.recovery else {
+                return;
+            };
+            recovery.waiting.remove(peer);
+            if recovery.waiting.is_empty() {
+                self.finish_recovery(outbox);
+            }
+        }
+
+        /// Answers the reads held during recovery from what we have now.
+        fn finish_recovery(&mut self, outbox: &mut Vec<Message>) {
+            let Some(recovery) = self.recovery.take() else {
+                return;
+            };
+            for (client, msg_id, key) in recovery.reads {
+                let reply_id = self.next_msg_id();
+                outbox.push(Message {
+                    src: self.id.clone(),
+                    dest: client,
+                    body: read_result(msg_id, reply_id, self.value(&key)),
+                });
+            }
+        }
+
+        /// When the next quorum read, seq-kv read or recovery times out, if any are waiting.
+        pub fn next_deadline(&self) -> Option<Instant> {
+            let recovery = self.recovery.as_ref().map(|recovery| recovery.deadline);
+            let sums = self.kv.sums.values().map(|sum| sum.deadline);
+            self.quorum_reads
+                .values()
+                .map(|read| read.deadline)
+                .chain(sums)
+                .chain(recovery)
+                .min()
+        }
+
+        /// Answers quorum reads a majority didn't reply to in time from what we've merged so
+        /// far, flagged as stale, fails seq-kv reads whose replies never came, and stops
+        /// holding reads for peers that didn't help us recover.
+        pub fn expire_reads(&mut self) -> Vec<Message> {
+            let now = Instant::now();
+            let mut messages = Vec::new();
+            if self
+                .recovery
+                .as_ref()
+                .is_some_and(|recovery| recovery.deadline <= now)
+            {
+                log::warn!("Peers didn't send their counts in time, serving reads from ours");
+                self.finish_recovery(&mut messages);
+            }
+            let mut expired: Vec<u64> = self
+                .quorum_reads
+                .iter()
+                .filter(|(_, read)| read.deadline <= now)
+                .map(|(read_id, _)| *read_id)
+                .collect();
+            let mut sums: Vec<u64> = self
+                .kv
+                .sums
+                .iter()
+                .filter(|(_, sum)| sum.deadline <= now)
+                .map(|(read_id, _)| *read_id)
+                .collect();
+            sums.sort();
+            for read_id in sums {
+                self.fail_sum(read_id, "seq-kv didn't answer in time", &mut messages);
+            }
+            expired.sort();
+            for read_id in expired {
+                let read = self.quorum_reads.remove(&read_id).unwrap();
+                self.pulls.retain(|_, pulled_for| *pulled_for != read_id);
+                log::warn!(
+                    "Quorum read {} from {} timed out, answering from local counts",
+                    read.msg_id,
+                    read.client
+                );
+                let msg_id = self.next_msg_id();
+                let mut body = read_result(read.msg_id, msg_id, self.value(&read.key));
+                if let Body::ReadOk { stale, .. } = &mut body {
+                    *stale = true;
+                }
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: read.client,
+                    body,
+                });
+            }
+            messages
+        }
+
+        fn finish_quorum_read(&mut self, read_id: u64, outbox: &mut Vec<Message>) {
+            if self
+                .quorum_reads
+                .get(&read_id)
+                .is_none_or(|read| read.waiting > 0)
+            {
+                return;
+            }
+            let read = self.quorum_reads.remove(&read_id).unwrap();
+            self.pulls.retain(|_, pulled_for| *pulled_for != read_id);
+            let msg_id = self.next_msg_id();
+            outbox.push(Message {
+                src: self.id.clone(),
+                dest: read.client,
+                body: read_result(read.msg_id, msg_id, self.value(&read.key)),
+            });
+        }
+
+        /// Folds `counter`'s queued adds into one cas of our total, unless one is already in
+        /// flight. Our total is read first if we don't know it.
+        fn flush_adds(&mut self, counter: &str, outbox: &mut Vec<Message>) {
+            let key = kv_key(counter, &self.id);
+            let total = self.kv.totals.entry(counter.to_string()).or_default();
+            if total.cas.is_some() || total.read.is_some() || total.queued.is_empty() {
+                return;
+            }
+            let msg_id = self.adds.next_msg_id();
+            let Some(from) = total.value else {
+                total.read = Some(msg_id);
+                self.send_kv(
+                    Body::Read {
+                        msg_id,
+                        key: Some(key),
+                        consistency: None,
+                    },
+                    outbox,
+                );
+                return;
+            };
+            let mut to = from;
+            let (mut adds, mut overflowed) = (Vec::new(), Vec::new());
+            for add in std::mem::take(&mut total.queued) {
+                match to.checked_add(add.2) {
+                    Some(sum) => {
+                        to = sum;
+                        adds.push(add);
+                    }
+                    None => overflowed.push(add),
+                }
+            }
+            if !adds.is_empty() {
+                total.cas = Some((msg_id, to, adds));
+                self.send_kv(
+                    Body::Cas {
+                        msg_id,
+                        key,
+                        from,
+                        to,
+                        create_if_not_exists: true,
+                    },
+                    outbox,
+                );
+            }
+            for (client, msg_id, _) in overflowed {
+                self.adds.forget(&client, msg_id);
+                outbox.push(Message {
+                    src: self.id.clone(),
+                    dest: client,
+                    body: overflow(msg_id),
+                });
+            }
+        }
+
+        /// Starts a client read. seq-kv may serve a process stale values until it does
+        /// something that orders it after other writes, so we write a key of our own first
+        /// and only read the totals once that's acknowledged.
+        fn sum_totals(&mut self, client: &str, msg_id: u64, key: &str, outbox: &mut Vec<Message>) {
+            let read_id = self.next_msg_id();
+            self.kv.sums.insert(
+                read_id,
+                Sum {
+                    client: client.to_string(),
+                    msg_id,
+                    key: key.to_string(),
+                    pending: 0,
+                    value: 0,
+                    deadline: Instant::now() + self.config.kv_timeout,
+                },
+            );
+            let sync_id = self.next_msg_id();
+            self.kv.syncs.insert(sync_id, read_id);
+            let key = format!("{}-sync", self.id);
+            self.send_kv(
+                Body::Write {
+                    msg_id: sync_id,
+                    key,
+                    value: sync_id as i64,
+                },
+                outbox,
+            );
+        }
+
+        fn read_totals(&mut self, read_id: u64, outbox: &mut Vec<Message>) {
+            let Some(sum) = self.kv.sums.get_mut(&read_id) else {
+                return;
+            };
+            let mut nodes: Vec<String> = self.peers.iter().cloned().collect();
+            nodes.sort();
+            sum.pending = nodes.len();
+            let counter = sum.key.clone();
+            for node in nodes {
+                let msg_id = self.next_msg_id();
+                self.kv.reads.insert(msg_id, read_id);
+                self.send_kv(
+                    Body::Read {
+                        msg_id,
+                        key: Some(kv_key(&counter, &node)),
+                        consistency: None,
+                    },
+                    outbox,
+                );
+            }
+        }
+
+        /// Handles a node's total read from seq-kv; `None` means the node hasn't written one.
+        fn read_total(&mut self, in_reply_to: u64, value: Option<i64>, outbox: &mut Vec<Message>) {
+            if let Some((counter, total)) = self
+                .kv
+                .totals
+                .iter_mut()
+                .find(|(_, total)| total.read == Some(in_reply_to))
+            {
+                total.read = None;
+                total.value = Some(value.unwrap_or(0));
+                let counter = counter.clone();
+                self.flush_adds(&counter, outbox);
+                return;
+            }
+            let Some(read_id) = self.kv.reads.remove(&in_reply_to) else {
+                return;
+            };
+            let Some(sum) = self.kv.sums.get_mut(&read_id) else {
+                return;
+            };
+            sum.value += value.unwrap_or(0) as i128;
+            sum.pending -= 1;
+            if sum.pending > 0 {
+                return;
+            }
+            let sum = self.kv.sums.remove(&read_id).unwrap();
+            let msg_id = self.next_msg_id();
+            outbox.push(Message {
+                src: self.id.clone(),
+                dest: sum.client,
+                body: read_result(sum.msg_id, msg_id, sum.value),
+            });
+        }
+
+        /// Gives up on a client read, forgetting the seq-kv requests still out for it. Reads
+        /// change nothing, so the client can safely try again.
+        fn fail_sum(&mut self, read_id: u64, reason: &str, outbox: &mut Vec<Message>) {
+            let Some(sum) = self.kv.sums.remove(&read_id) else {
+                return;
+            };
+            self.kv.syncs.retain(|_, sum_id| *sum_id != read_id);
+            self.kv.reads.retain(|_, sum_id| *sum_id != read_id);
+            log::warn!("Read {} from {} failed: {}", sum.msg_id, sum.client, reason);
+            outbox.push(Message {
+                src: self.id.clone(),
+                dest: sum.client,
+                body: Body::Error {
+                    in_reply_to: sum.msg_id,
+                    code: TEMPORARILY_UNAVAILABLE,
+                    text: reason.to_string(),
+                },
+            });
+        }
+
+        /// Takes the in-flight cas with `msg_id`, along with the counter it's for.
+        fn take_cas(&mut self, msg_id: u64) -> Option<(String, Cas)> {
+            self.kv.totals.iter_mut().find_map(|(counter, total)| {
+                let cas = total.cas.take_if(|(cas_id, ..)| *cas_id == msg_id)?;
+                Some((counter.clone(), cas))
+            })
+        }
+
+        fn send_kv(&self, body: Body, outbox: &mut Vec<Message>) {
+            outbox.push(Message {
+                src: self.id.clone(),
+                dest: SEQ_KV.to_string(),
+                body,
+            });
+        }
+
+        /// Opens the store in the data dir, if there is one, and merges back whatever was
+        /// snapshotted and journaled before a restart. Without it, a restarted node would start
+        /// its own counts from zero, and peers would keep the higher counts they'd already
+        /// merged until it caught up, hiding its new adds.
+        fn recover(&mut self) {
+            if self.config.mode != Mode::Gossip {
+                return;
+            }
+            let Some(dir) = &self.config.data_dir else {
+                return;
+            };
+            let recovered = CounterStore::open(dir, &self.id).and_then(|mut store| {
+                let (snapshot, journal) = store.load::<Counts, Journaled>()?;
+                Ok((store, snapshot, journal))
+            });
+            let (store, snapshot, journal) = match recovered {
+                Ok(recovered) => recovered,
+                Err(e) => {
+                    log::error!("Unable to recover counts from {:?}: {}", dir, e);
+                    return;
+                }
+            };
+            let journaled = journal
+                .into_iter()
+                .map(|Journaled { key, counter }| (key, counter));
+            for (key, counter) in snapshot.unwrap_or_default().into_iter().chain(journaled) {
+                self.merge_counter(&key, &counter);
+            }
+            *self.adds.store.lock().unwrap() = Some(store);
+        }
+
+        /// Resets `keys` to zero everywhere by moving them into their next epoch, and
+        /// snapshots right away so a restart doesn't bring back the old counts. The store stays
+        /// locked throughout, so adds can't journal into the old epoch after the snapshot.
+        fn reset<'a>(&mut self, keys: impl Iterator<Item = &'a String>) {
+            let adds = self.adds.clone();
+            let mut store = adds.store.lock().unwrap();
+            self.fold_shards();
+            for key in keys {
+                if let Some(counter) = self.counters.get_mut(key) {
+                    counter.reset();
+                    log::info!("Reset {:?} into epoch {}", key, counter.epoch());
+                    self.share(key);
+                }
+            }
+            self.changed = true;
+            if let Some(store) = store.as_mut() {
+                if let Err(e) = store.snapshot(&self.counters) {
+                    log::error!("Unable to snapshot counters after a reset: {}", e);
+                }
+            }
+        }
+
+        /// Queues `delta` for our total for `key` in seq-kv. The add waits for the cas carrying
+        /// it, which replies to it.
+        fn apply_add(
+            &mut self,
+            src: &str,
+            msg_id: u64,
+            key: &str,
+            delta: i64,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            let total = self.kv.totals.entry(key.to_string()).or_default();
+            total.queued.push((src.to_string(), msg_id, delta));
+            self.flush_adds(key, outbox);
+            None
+        }
+
+        /// Answers a retried add without applying it again. One still waiting on seq-kv gets
+        /// no reply yet; the original's add_ok answers both.
+        fn repeated_add(&self, client: &str, msg_id: u64) -> Option<Body> {
+            log::debug!("Ignoring repeated add {} from {}", msg_id, client);
+            let pending = self.kv.totals.values().any(|total| {
+                let cas = total.cas.iter().flat_map(|(_, _, adds)| adds);
+                total
+                    .queued
+                    .iter()
+                    .chain(cas)
+                    .any(|(c, m, _)| c == client && *m == msg_id)
+            });
+            if pending {
+                return None;
+            }
+            Some(Body::AddOk {
+                in_reply_to: msg_id,
+                msg_id: self.next_msg_id(),
+            })
+        }
+
+        /// Everything added to `key` minus everything subtracted, across all nodes. Summed as
+        /// i128, which every node's u64 counts fit in many times over, so this can't overflow.
+        fn value(&self, key: &str) -> i128 {
+            self.counters
+                .get(key)
+                .map_or(0, |counter| counter.get().value())
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: &Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            if self.adds.takes(body) {
+                let reply = self.adds.apply(src, body);
+                self.fold();
+                return reply;
+            }
+            if let Body::Add { msg_id, .. } | Body::AddBatch { msg_id, .. } = body {
+                if !self.adds.remember(src, *msg_id) {
+                    return self.repeated_add(src, *msg_id);
+                }
+            }
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.id = node_id.clone();
+                    logging::set_node(&self.id);
+                    // Zero entries for every node, so peers hear who's in the cluster
+                    let mut counter = Epoch::<PnCounter>::default();
+                    for node in node_ids {
+                        counter.get_mut().add(node, 0);
+                    }
+                    self.counters = HashMap::from([(String::new(), counter)]);
+                    self.peers = node_ids.iter().cloned().collect();
+                    let now = Instant::now();
+                    self.last_heard = node_ids.iter().map(|node| (node.clone(), now)).collect();
+                    self.recover();
+                    self.start_recovery(outbox);
+                    self.initialized = true;
+                    let _ = self.adds.id.set(self.id.clone());
+                    Body::InitOk {
+                        msg_id: self.next_msg_id(),
+                        in_reply_to: *msg_id,
+                    }
+                }
+                Body::Add { msg_id, key, delta } => {
+                    return self.apply_add(src, *msg_id, key, *delta, outbox);
+                }
+                Body::AddBatch {
+                    msg_id,
+                    key,
+                    deltas,
+                } => {
+                    let Some(delta) = deltas.iter().try_fold(0i64, |sum, d| sum.checked_add(*d))
+                    else {
+                        self.adds.forget(src, *msg_id);
+                        return Some(overflow(*msg_id));
+                    };
+                    return self.apply_add(src, *msg_id, key, delta, outbox);
+                }
+                Body::Read { msg_id, key, .. } if self.config.mode == Mode::SeqKv => {
+                    self.sum_totals(src, *msg_id, key.as_deref().unwrap_or_default(), outbox);
+                    return None;
+                }
+                Body::Read {
+                    msg_id,
+                    key,
+                    consistency: Some(Consistency::Quorum),
+                } => {
+                    self.pull_counts(src, *msg_id, key.as_deref().unwrap_or_default(), outbox);
+                    return None;
+                }
+                Body::Read { msg_id, key, .. } if self.recovery.is_some() => {
+                    let key = key.clone().unwrap_or_default();
+                    if let Some(recovery) = &mut self.recovery {
+                        recovery.reads.push((src.to_string(), *msg_id, key));
+                    }
+                    return None;
+                }
+                Body::Read { msg_id, key, .. } => read_result(
+                    *msg_id,
+                    self.next_msg_id(),
+                    self.value(key.as_deref().unwrap_or_default()),
+                ),
+                Body::Gossip { msg_id, counts, .. } => {
+                    log::debug!("Received gossip from {}, updating local list", src);
+                    self.merge(src, counts);
+                    Body::GossipOk {
+                        msg_id: self.next_msg_id(),
+                        in_reply_to: *msg_id,
+                        counts: self.delta_for(src),
+                        packed: None,
+                        full: false,
+                    }
+                }
+                Body::GossipOk {
+                    in_reply_to,
+                    counts,
+                    full,
+                    ..
+                } => {
+                    self.merge(src, counts);
+                    if *full {
+                        // This is all the peer has, so it replaces what we thought it had
+                        // rather than adding to it; anything we were wrong about goes out again
+                        self.known.insert(src.to_string(), counts.clone());
+                    } else {
+                        self.acked(src, *in_reply_to);
+                    }
+                    self.recovered(src, outbox);
+                    return None;
+                }
+                Body::Digest {
+                    msg_id,
+                    digest: theirs,
+                } => {
+                    if *theirs == digest(&self.counters) {
+                        Body::DigestOk {
+                            msg_id: self.next_msg_id(),
+                            in_reply_to: *msg_id,
+                            digest: *theirs,
+                        }
+                    } else {
+                        Body::GossipOk {
+                            msg_id: self.next_msg_id(),
+                            in_reply_to: *msg_id,
+                            counts: self.counters.clone(),
+                            packed: None,
+                            full: true,
+                        }
+                    }
+                }
+                Body::DigestOk { digest: theirs, .. } => {
+                    // Unless we've changed since, the peer has exactly what we have
+                    if *theirs == digest(&self.counters) {
+                        self.known.insert(src.to_string(), self.counters.clone());
+                    }
+                    return None;
+                }
+                Body::ReadDetailed { msg_id, key } => {
+                    let counter = self.counters.get(key).cloned().unwrap_or_default();
+                    Body::ReadDetailedOk {
+                        msg_id: self.next_msg_id(),
+                        in_reply_to: *msg_id,
+                        epoch: counter.epoch(),
+                        value: counter.get().value(),
+                        counter: counter.get().clone(),
+                    }
+                }
+                Body::ReadDetailedOk {
+                    in_reply_to,
+                    counter,
+                    epoch,
+                    ..
+                } => {
+                    let counter = Epoch::new(*epoch, counter.clone());
+                    self.pulled(src, *in_reply_to, counter, outbox);
+                    return None;
+                }
+                Body::Snapshot { msg_id, .. } if self.config.mode == Mode::SeqKv => Body::Error {
+                    in_reply_to: *msg_id,
+                    code: NOT_SUPPORTED,
+                    text: "counters live in seq-kv in this mode".to_string(),
+                },
+                Body::Snapshot { msg_id, key, reset } => {
+                    let counts: Counts = match key {
+                        Some(key) => self
+                            .counters
+                            .get_key_value(key)
+                            .map(|(key, counter)| (key.clone(), counter.clone()))
+                            .into_iter()
+                            .collect(),
+                        None => self.counters.clone(),
+                    };
+                    if *reset {
+                        self.reset(counts.keys());
+                    }
+                    Body::SnapshotOk {
+                        msg_id: self.next_msg_id(),
+                        in_reply_to: *msg_id,
+                        counts,
+                    }
+                }
+                Body::Recover { msg_id } => Body::GossipOk {
+                    msg_id: self.next_msg_id(),
+                    in_reply_to: *msg_id,
+                    counts: self.counters.clone(),
+                    packed: None,
+                    full: true,
+                },
+                Body::Ping { msg_id } => Body::Pong {
+                    msg_id: self.next_msg_id(),
+                    in_reply_to: *msg_id,
+                },
+                // Hearing back is all a pong is for, and handle_message has noted it
+                Body::Pong { .. } => return None,
+                Body::Stats { msg_id } => Body::StatsOk {
+                    msg_id: self.next_msg_id(),
+                    in_reply_to: *msg_id,
+                    peers: self.peer_stats(),
+                },
+                Body::ReadOk {
+                    in_reply_to, value, ..
+                } => {
+                    self.read_total(*in_reply_to, Some(*value), outbox);
+                    return None;
+                }
+                Body::WriteOk { in_reply_to } => {
+                    if let Some(read_id) = self.kv.syncs.remove(in_reply_to) {
+                        self.read_totals(read_id, outbox);
+                    }
+                    return None;
+                }
+                Body::CasOk { in_reply_to } => {
+                    let (counter, (_, to, adds)) = self.take_cas(*in_reply_to)?;
+                    if let Some(total) = self.kv.totals.get_mut(&counter) {
+                        total.value = Some(to);
+                    }
+                    for (client, msg_id, _) in adds {
+                        let reply_id = self.next_msg_id();
+                        outbox.push(Message {
+                            src: self.id.clone(),
+                            dest: client,
+                            body: Body::AddOk {
+                                in_reply_to: msg_id,
+                                msg_id: reply_id,
+                            },
+                        });
+                    }
+                    self.flush_adds(&counter, outbox);
+                    return None;
+                }
+                Body::Error {
+                    in_reply_to,
+                    code,
+                    text,
+                } => {
+                    if let Some((counter, (_, _, adds))) = self.take_cas(*in_reply_to) {
+                        // Our idea of our own total is stale; read it again and retry
+                        log::debug!("cas of our total failed ({}), rereading it", text);
+                        if let Some(total) = self.kv.totals.get_mut(&counter) {
+                            total.value = None;
+                            total.queued.splice(0..0, adds);
+                        }
+                        self.flush_adds(&counter, outbox);
+                    } else if *code == KEY_DOES_NOT_EXIST {
+                        self.read_total(*in_reply_to, None, outbox);
+                    } else if let Some(read_id) = self
+                        .kv
+                        .syncs
+                        .get(in_reply_to)
+                        .or(self.kv.reads.get(in_reply_to))
+                    {
+                        let reason = format!("seq-kv error {} ({})", code, text);
+                        self.fail_sum(*read_id, &reason, outbox);
+                    } else {
+                        log::warn!("seq-kv error {} ({}) for {}", code, text, in_reply_to);
+                    }
+                    return None;
+                }
+                // Replies to requests we never make, like a stray init_ok; answering them could
+                // start an error loop with whoever sent them
+                _ => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn init(id: &str) -> Node {
+            init_with(id, Config::default())
+        }
+
+        /// A node that serves reads right away rather than recovering from its peers first,
+        /// as if the whole cluster were starting fresh.
+        fn init_with(id: &str, config: Config) -> Node {
+            let mut node = Node::new(Config {
+                recovery_peers: 0,
+                ..config
+            });
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest: id.into(),
+                body: Body::Init {
+                    msg_id: 1,
+                    node_id: id.into(),
+                    node_ids: vec!["n1".into(), "n2".into()],
+                },
+            });
+            node
+        }
+
+        fn add(node: &mut Node, delta: i64) {
+            add_to(node, "", delta);
+        }
+
+        fn add_to(node: &mut Node, key: &str, delta: i64) {
+            let dest = node.id.clone();
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body: Body::Add {
+                    msg_id: node.next_msg_id(),
+                    key: key.into(),
+                    delta,
+                },
+            });
+        }
+
+        #[test]
+        fn test_gossip_only_sends_what_peers_lack() {
+            let mut n1 = init("n1");
+            let mut n2 = init("n2");
+            assert!(n1.gossip().iter().all(|message| message.dest != "n1"));
+
+            add(&mut n2, 1);
+            for message in n2.gossip() {
+                n1.handle_message(message);
+            }
+            // n2 already has everything n1 knows
+            assert!(n1.gossip().is_empty());
+            add(&mut n1, 1);
+            let gossip = n1.gossip();
+            let Body::Gossip { counts, .. } = &gossip[0].body else {
+                panic!("expected gossip, got {:?}", gossip);
+            };
+            assert_eq!(
+                counts[""].get().increments().iter().collect::<Vec<_>>(),
+                vec![("n1", 1)]
+            );
+            // Unacknowledged gossip is sent again, until n2 confirms it
+            let resent = n1.gossip();
+            assert!(
+                matches!(&resent[0].body, Body::Gossip { counts: again, .. } if again == counts)
+            );
+            for ack in n2.handle_message(gossip[0].clone()) {
+                n1.handle_message(ack);
+            }
+            assert!(n1.gossip().is_empty());
+        }
+
+        #[test]
+        fn test_gossip_replies_with_what_the_sender_lacks() {
+            let mut n1 = init("n1");
+            let mut n2 = init("n2");
+            add(&mut n1, 1);
+            add(&mut n2, 2);
+            let gossip = n1.gossip();
+            let replies = n2.handle_message(gossip[0].clone());
+            let Body::GossipOk { counts, .. } = &replies[0].body else {
+                panic!("expected gossip_ok, got {:?}", replies);
+            };
+            // Only n2's add, which n1 hasn't seen, comes back
+            assert_eq!(
+                counts[""].get().increments().iter().collect::<Vec<_>>(),
+                vec![("n2", 2)]
+            );
+            n1.handle_message(replies[0].clone());
+            assert_eq!(n1.value(""), 3);
+            assert_eq!(n2.value(""), 3);
+            // The reply acknowledged the push, and showed n1 everything n2 has
+            assert!(n1.gossip().is_empty());
+        }
+
+        #[test]
+        fn test_digests_repair_what_peers_lack() {
+            let config = Config {
+                digest_rounds: 1,
+                ..Default::default()
+            };
+            let mut n1 = init_with("n1", config.clone());
+            let mut n2 = init_with("n2", config);
+            for message in n1.gossip() {
+                for reply in n2.handle_message(message) {
+                    n1.handle_message(reply);
+                }
+            }
+            // In sync, a digest is all that goes out, and it matches
+            let digests = n1.gossip();
+            assert!(matches!(
+                digests[..],
+                [Message {
+                    body: Body::Digest { .. },
+                    ..
+                }]
+            ));
+            let replies = n2.handle_message(digests[0].clone());
+            assert!(matches!(replies[0].body, Body::DigestOk { .. }));
+            n1.handle_message(replies[0].clone());
+
+            // n1 wrongly thinks n2 has its add
+            add(&mut n1, 1);
+            n1.known.insert("n2".into(), n1.counters.clone());
+            let digests = n1.gossip();
+            let replies = n2.handle_message(digests[0].clone());
+            assert!(matches!(replies[0].body, Body::GossipOk { .. }));
+            n1.handle_message(replies[0].clone());
+            for message in n1.gossip() {
+                n2.handle_message(message);
+            }
+            assert_eq!(n2.value(""), 1);
+        }
+
+        #[test]
+        fn test_gossip_fanout() {
+            let mut node = Node::new(Config {
+                fanout: 2,
+                ..Default::default()
+            });
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Init {
+                    msg_id: 1,
+                    node_id: "n1".into(),
+                    node_ids: (1..=5).map(|i| format!("n{}", i)).collect(),
+                },
+            });
+            assert_eq!(node.gossip().len(), 2);
+            node.config.fanout = 0;
+            assert_eq!(node.gossip().len(), 4);
+        }
+
+        #[test]
+        fn test_gossip_backs_off_while_idle() {
+            let mut n1 = init_with(
+                "n1",
+                Config {
+                    gossip_jitter: Duration::ZERO,
+                    max_gossip_interval: Duration::from_millis(150),
+                    ..Default::default()
+                },
+            );
+            let ms = Duration::from_millis;
+            assert_eq!(n1.gossip_delay(), ms(50));
+            n1.gossip();
+            assert_eq!(n1.gossip_delay(), ms(100));
+            n1.gossip();
+            assert_eq!(n1.gossip_delay(), ms(150));
+            assert!(!n1.gossip_due());
+
+            add(&mut n1, 1);
+            assert!(n1.gossip_due());
+            n1.gossip();
+            assert_eq!(n1.gossip_delay(), ms(50));
+        }
+
+        #[test]
+        fn test_quiet_peers_are_pinged_and_skipped() {
+            let mut n1 = init("n1");
+            let mut n2 = init("n2");
+            add(&mut n1, 1);
+            let long_ago = Instant::now() - Duration::from_secs(3600);
+            n1.last_heard.insert("n2".into(), long_ago);
+            let messages = n1.gossip();
+            assert!(matches!(
+                messages[..],
+                [Message {
+                    body: Body::Ping { .. },
+                    ..
+                }]
+            ));
+            // Once n2 answers it's gossiped to again
+            for pong in n2.handle_message(messages[0].clone()) {
+                n1.handle_message(pong);
+            }
+            assert!(n1
+                .gossip()
+                .iter()
+                .any(|message| matches!(message.body, Body::Gossip { .. })));
+        }
+
+        #[test]
+        fn test_failing_peers_are_suspended_and_probed() {
+            let config = Config {
+                gossip_timeout: Duration::ZERO,
+                suspend_after: 2,
+                probe_interval: Duration::ZERO,
+                ..Config::default()
+            };
+            let mut n1 = init_with("n1", config);
+            let mut n2 = init("n2");
+            add(&mut n1, 1);
+            let kinds = |messages: &[Message]| -> Vec<&'static str> {
+                messages
+                    .iter()
+                    .map(|message| match message.body {
+                        Body::Gossip { .. } => "gossip",
+                        Body::Ping { .. } => "ping",
+                        _ => "other",
+                    })
+                    .collect()
+            };
+            // n2 drops two gossips in a row, so the next round only probes it
+            assert_eq!(kinds(&n1.gossip()), ["gossip"]);
+            assert_eq!(kinds(&n1.gossip()), ["gossip"]);
+            let probes = n1.gossip();
+            assert_eq!(kinds(&probes), ["ping"]);
+            for pong in n2.handle_message(probes[0].clone()) {
+                n1.handle_message(pong);
+            }
+            assert_eq!(kinds(&n1.gossip()), ["gossip"]);
+        }
+
+        #[test]
+        fn test_round_messages_are_capped() {
+            let config = Config {
+                max_round_messages: 2,
+                ping_interval: Duration::ZERO,
+                ..Config::default()
+            };
+            let mut n1 = init_with("n1", config);
+            let long_ago = Instant::now() - Duration::from_secs(3600);
+            for peer in ["n2", "n3", "n4", "n5"] {
+                n1.peers.insert(peer.into());
+                n1.last_heard.insert(peer.into(), long_ago);
+            }
+            let mut pinged = || -> Vec<String> {
+                n1.gossip()
+                    .into_iter()
+                    .map(|message| {
+                        assert!(matches!(message.body, Body::Ping { .. }));
+                        message.dest
+                    })
+                    .collect()
+            };
+            // Every peer is quiet, but they're pinged two a round, taking turns
+            assert_eq!(pinged(), ["n2", "n3"]);
+            assert_eq!(pinged(), ["n4", "n5"]);
+            assert_eq!(pinged(), ["n2", "n3"]);
+        }
+
+        #[test]
+        fn test_gossip_about_unknown_nodes() {
+            let mut n1 = init("n1");
+            let mut counter = Epoch::<PnCounter>::default();
+            counter.get_mut().add("n9", 4);
+            let counts = HashMap::from([(String::new(), counter)]);
+            n1.handle_message(Message {
+                src: "n3".into(),
+                dest: "n1".into(),
+                body: Body::Gossip {
+                    msg_id: 1,
+                    counts,
+                    packed: None,
+                },
+            });
+            assert_eq!(n1.value(""), 4);
+            // n3 joined after init, so it hears back from us too
+            assert!(n1.gossip().iter().any(|message| message.dest == "n3"));
+        }
+
+        #[test]
+        fn test_negative_deltas_survive_gossip() {
+            let mut n1 = init("n1");
+            let mut n2 = init("n2");
+            add(&mut n1, 5);
+            add(&mut n1, -7);
+            add(&mut n2, 3);
+            for message in n1.gossip() {
+                if message.dest == "n2" {
+                    n2.handle_message(message);
+                }
+            }
+            assert_eq!(n2.value(""), 1);
+            // Merging the same gossip again doesn't count it twice
+            for message in n1.gossip() {
+                if message.dest == "n2" {
+                    n2.handle_message(message);
+                }
+            }
+            assert_eq!(n2.value(""), 1);
+        }
+
+        #[test]
+        fn test_named_counters() {
+            let mut n1 = init("n1");
+            let mut n2 = init("n2");
+            add_to(&mut n1, "a", 2);
+            add_to(&mut n1, "b", -1);
+            add(&mut n2, 4);
+            for message in n1.gossip() {
+                for reply in n2.handle_message(message) {
+                    n1.handle_message(reply);
+                }
+            }
+            for node in [&n1, &n2] {
+                assert_eq!(node.value("a"), 2);
+                assert_eq!(node.value("b"), -1);
+                assert_eq!(node.value(""), 4);
+            }
+            let replies = n2.handle_message(Message {
+                src: "c1".into(),
+                dest: "n2".into(),
+                body: Body::Read {
+                    msg_id: 3,
+                    key: Some("b".into()),
+                    consistency: None,
+                },
+            });
+            assert!(matches!(replies[0].body, Body::ReadOk { value: -1, .. }));
+        }
+
+        #[test]
+        fn test_large_gossip_is_packed() {
+            let config = Config {
+                compress_threshold: 1,
+                ..Config::default()
+            };
+            let mut n1 = init_with("n1", config);
+            let mut n2 = init("n2");
+            add_to(&mut n1, "a", 2);
+            add_to(&mut n1, "b", 3);
+            let gossip = n1.gossip();
+            let Body::Gossip { counts, packed, .. } = &gossip[0].body else {
+                panic!("expected gossip, got {:?}", gossip);
+            };
+            assert!(counts.is_empty());
+            assert!(packed.is_some());
+            for ack in n2.handle_message(gossip[0].clone()) {
+                n1.handle_message(ack);
+            }
+            assert_eq!(n2.value("a"), 2);
+            assert_eq!(n2.value("b"), 3);
+            assert!(n1.gossip().is_empty());
+        }
+
+        #[test]
+        fn test_peer_stats() {
+            let mut n1 = init("n1");
+            let mut n2 = init("n2");
+            let stats = |node: &mut Node| {
+                let replies = node.handle_message(Message {
+                    src: "c1".into(),
+                    dest: node.id.clone(),
+                    body: Body::Stats { msg_id: 1 },
+                });
+                let Body::StatsOk { peers, .. } = &replies[0].body else {
+                    panic!("expected stats_ok, got {:?}", replies);
+                };
+                peers["n2"].clone()
+            };
+            assert_eq!(stats(&mut n1).last_gossip_ms, None);
+
+            add(&mut n2, 3);
+            for message in n2.gossip() {
+                n1.handle_message(message);
+            }
+            let n2_stats = stats(&mut n1);
+            assert!(n2_stats.last_gossip_ms.is_some());
+            assert!(n2_stats.lag.is_empty());
+            // n2 hasn't seen our add yet
+            add(&mut n1, 5);
+            assert_eq!(stats(&mut n1).lag, HashMap::from([(String::new(), 5)]));
+        }
+
+        #[test]
+        fn test_batched_add() {
+            let mut n1 = init("n1");
+            let batch = |msg_id, deltas| Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::AddBatch {
+                    msg_id,
+                    key: String::new(),
+                    deltas,
+                },
+            };
+            let replies = n1.handle_message(batch(5, vec![3, -1, 4]));
+            assert!(matches!(
+                replies[0].body,
+                Body::AddOk { in_reply_to: 5, .. }
+            ));
+            assert_eq!(n1.value(""), 6);
+            // A batch that overflows is refused whole
+            let replies = n1.handle_message(batch(6, vec![1, i64::MAX]));
+            assert!(matches!(replies[0].body, Body::Error { code: ABORT, .. }));
+            assert_eq!(n1.value(""), 6);
+        }
+
+        #[test]
+        fn test_read_detailed() {
+            let mut n1 = init("n1");
+            add(&mut n1, 5);
+            add(&mut n1, -2);
+            let replies = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::ReadDetailed {
+                    msg_id: 4,
+                    key: String::new(),
+                },
+            });
+            let Body::ReadDetailedOk { counter, value, .. } = &replies[0].body else {
+                panic!("expected read_detailed_ok, got {:?}", replies);
+            };
+            assert_eq!(counter.increments().get("n1"), 5);
+            assert_eq!(counter.decrements().get("n1"), 2);
+            assert_eq!(counter.increments().get("n2"), 0);
+            assert_eq!(*value, 3);
+        }
+
+        #[test]
+        fn test_snapshot_and_reset() {
+            let mut n1 = init("n1");
+            let mut n2 = init("n2");
+            add(&mut n1, 4);
+            add_to(&mut n1, "a", 1);
+            for message in n1.gossip() {
+                for reply in n2.handle_message(message) {
+                    n1.handle_message(reply);
+                }
+            }
+            let snapshot = |reset| Message {
+                src: "admin".into(),
+                dest: "n1".into(),
+                body: Body::Snapshot {
+                    msg_id: 9,
+                    key: Some(String::new()),
+                    reset,
+                },
+            };
+            let replies = n1.handle_message(snapshot(true));
+            let Body::SnapshotOk { counts, .. } = &replies[0].body else {
+                panic!("expected snapshot_ok, got {:?}", replies);
+            };
+            assert_eq!(counts.keys().collect::<Vec<_>>(), vec![""]);
+            assert_eq!(counts[""].get().value(), 4);
+            assert_eq!(n1.value(""), 0);
+            assert_eq!(n1.value("a"), 1);
+
+            // The reset reaches n2, and its old counts don't come back
+            add(&mut n2, 2);
+            for message in n1.gossip() {
+                for reply in n2.handle_message(message) {
+                    n1.handle_message(reply);
+                }
+            }
+            assert_eq!(n2.value(""), 0);
+            add(&mut n2, 3);
+            for message in n2.gossip() {
+                n1.handle_message(message);
+            }
+            assert_eq!(n1.value(""), 3);
+        }
+
+        #[test]
+        fn test_adds_applied_side_by_side() {
+            let mut n1 = init("n1");
+            let adds = n1.adds();
+            std::thread::scope(|scope| {
+                for client in 0..8 {
+                    let adds = &adds;
+                    scope.spawn(move || {
+                        for msg_id in 0..100 {
+                            let add = Message {
+                                src: format!("c{}", client),
+                                dest: "n1".into(),
+                                body: Body::Add {
+                                    msg_id,
+                                    key: ["a", "b"][msg_id as usize % 2].into(),
+                                    delta: 1,
+                                },
+                            };
+                            assert!(adds.claims(&add));
+                            let reply = adds.handle(add);
+                            assert!(matches!(reply[0].body, Body::AddOk { .. }));
+                        }
+                    });
+                }
+            });
+            // The node picks them up before it next looks at its counts
+            let gossip = n1.gossip();
+            let Body::Gossip { counts, .. } = &gossip[0].body else {
+                panic!("Expected gossip, got {:?}", gossip);
+            };
+            assert_eq!(counts["a"].get().value(), 400);
+            assert_eq!(n1.value("b"), 400);
+        }
+
+        #[test]
+        fn test_retried_add_counts_once() {
+            let mut n1 = init("n1");
+            let add = Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Add {
+                    msg_id: 7,
+                    key: String::new(),
+                    delta: 5,
+                },
+            };
+            for _ in 0..2 {
+                let replies = n1.handle_message(add.clone());
+                assert!(matches!(
+                    replies[0].body,
+                    Body::AddOk { in_reply_to: 7, .. }
+                ));
+            }
+            assert_eq!(n1.value(""), 5);
+            // The same msg_id from another client is a different add
+            n1.handle_message(Message {
+                src: "c2".into(),
+                ..add
+            });
+            assert_eq!(n1.value(""), 10);
+        }
+
+        #[test]
+        fn test_overflow_is_refused() {
+            let mut n1 = init("n1");
+            add(&mut n1, i64::MAX);
+            add(&mut n1, i64::MAX);
+            let read = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Read {
+                    msg_id: 8,
+                    key: None,
+                    consistency: None,
+                },
+            });
+            assert!(matches!(read[0].body, Body::Error { code: ABORT, .. }));
+
+            let replies = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Add {
+                    msg_id: 9,
+                    key: String::new(),
+                    delta: 2,
+                },
+            });
+            assert!(matches!(
+                replies[0].body,
+                Body::Error {
+                    in_reply_to: 9,
+                    code: ABORT,
+                    ..
+                }
+            ));
+            assert_eq!(n1.value(""), 2 * i64::MAX as i128);
+            // Negative deltas count separately, so they still go through
+            add(&mut n1, -i64::MAX);
+            assert_eq!(n1.value(""), i64::MAX as i128);
+        }
+
+        #[test]
+        fn test_counts_survive_restart() {
+            let dir = std::env::temp_dir().join(format!("counter-restart-{}", std::process::id()));
+            let config = Config {
+                data_dir: Some(dir.clone()),
+                snapshot_interval: 2,
+                ..Default::default()
+            };
+            {
+                let mut n1 = init_with("n1", config.clone());
+                let mut n2 = init("n2");
+                add(&mut n2, 10);
+                for message in n2.gossip() {
+                    n1.handle_message(message);
+                }
+                add(&mut n1, 3);
+                // Snapshots everything, including what n2 told us
+                add_to(&mut n1, "a", -1);
+                add(&mut n1, 2);
+            }
+            assert!(dir.join("n1.snapshot.json").exists());
+
+            let n1 = init_with("n1", config);
+            assert_eq!(n1.value(""), 15);
+            assert_eq!(n1.value("a"), -1);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn test_quorum_read_pulls_from_a_majority() {
+            let mut n1 = init("n1");
+            let mut n2 = init("n2");
+            add(&mut n2, 3);
+            let read = |consistency| Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Read {
+                    msg_id: 5,
+                    key: None,
+                    consistency,
+                },
+            };
+            // A local read only sees what n1 has merged
+            let replies = n1.handle_message(read(None));
+            assert!(matches!(replies[0].body, Body::ReadOk { value: 0, .. }));
+
+            let pulls = n1.handle_message(read(Some(Consistency::Quorum)));
+            assert!(pulls.iter().all(|message| message.dest == "n2"));
+            let mut replies = Vec::new();
+            for pull in pulls {
+                for reply in n2.handle_message(pull) {
+                    replies.extend(n1.handle_message(reply));
+                }
+            }
+            assert!(matches!(
+                replies[..],
+                [Message {
+                    body: Body::ReadOk {
+                        in_reply_to: 5,
+                        value: 3,
+                        ..
+                    },
+                    ..
+                }]
+            ));
+        }
+
+        #[test]
+        fn test_quorum_read_times_out_to_local_counts() {
+            let config = Config {
+                quorum_read_timeout: Duration::ZERO,
+                ..Config::default()
+            };
+            let mut n1 = init_with("n1", config);
+            add(&mut n1, 2);
+            let pulls = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Read {
+                    msg_id: 5,
+                    key: None,
+                    consistency: Some(Consistency::Quorum),
+                },
+            });
+            assert!(matches!(pulls[0].body, Body::ReadDetailed { .. }));
+            assert!(n1.next_deadline().is_some());
+            // n2 never answers
+            let replies = n1.expire_reads();
+            assert!(matches!(
+                replies[..],
+                [Message {
+                    body: Body::ReadOk {
+                        in_reply_to: 5,
+                        value: 2,
+                        stale: true,
+                        ..
+                    },
+                    ..
+                }]
+            ));
+            assert!(n1.next_deadline().is_none());
+        }
+
+        /// Answers a seq-kv request the way Maelstrom's service would.
+        fn seq_kv(store: &mut HashMap<String, i64>, request: &Message) -> Body {
+            match &request.body {
+                Body::Read {
+                    msg_id,
+                    key: Some(key),
+                    ..
+                } => match store.get(key) {
+                    Some(value) => Body::ReadOk {
+                        in_reply_to: *msg_id,
+                        msg_id: 0,
+                        value: *value,
+                        stale: false,
+                    },
+                    None => Body::Error {
+                        in_reply_to: *msg_id,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: "not found".into(),
+                    },
+                },
+                Body::Write { msg_id, key, value } => {
+                    store.insert(key.clone(), *value);
+                    Body::WriteOk {
+                        in_reply_to: *msg_id,
+                    }
+                }
+                Body::Cas {
+                    msg_id,
+                    key,
+                    from,
+                    to,
+                    ..
+                } if store.get(key).copied().unwrap_or(*from) == *from => {
+                    store.insert(key.clone(), *to);
+                    Body::CasOk {
+                        in_reply_to: *msg_id,
+                    }
+                }
+                Body::Cas { msg_id, .. } => Body::Error {
+                    in_reply_to: *msg_id,
+                    code: 22,
+                    text: "from doesn't match".into(),
+                },
+                body => panic!("unexpected seq-kv request {:?}", body),
+            }
+        }
+
+        /// Delivers a message and every seq-kv exchange it sets off, returning what's left.
+        fn deliver(
+            node: &mut Node,
+            store: &mut HashMap<String, i64>,
+            message: Message,
+        ) -> Vec<Message> {
+            let mut replies = Vec::new();
+            let mut inbox = vec![message];
+            while let Some(message) = inbox.pop() {
+                for sent in node.handle_message(message) {
+                    if sent.dest == SEQ_KV {
+                        inbox.push(Message {
+                            src: SEQ_KV.into(),
+                            dest: sent.src.clone(),
+                            body: seq_kv(store, &sent),
+                        });
+                    } else {
+                        replies.push(sent);
+                    }
+                }
+            }
+            replies
+        }
+
+        #[test]
+        fn test_reads_wait_for_recovery() {
+            let mut n1 = Node::new(Config::default());
+            let mut n2 = init("n2");
+            add(&mut n2, 3);
+            let recover = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Init {
+                    msg_id: 1,
+                    node_id: "n1".into(),
+                    node_ids: vec!["n1".into(), "n2".into()],
+                },
+            });
+            assert!(matches!(recover[1].body, Body::Recover { .. }));
+            let held = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Read {
+                    msg_id: 5,
+                    key: None,
+                    consistency: None,
+                },
+            });
+            assert!(held.is_empty());
+            let counts = n2.handle_message(recover[1].clone());
+            let replies = n1.handle_message(counts[0].clone());
+            assert!(matches!(
+                replies[..],
+                [Message {
+                    body: Body::ReadOk {
+                        in_reply_to: 5,
+                        value: 3,
+                        ..
+                    },
+                    ..
+                }]
+            ));
+        }
+
+        #[test]
+        fn test_seq_kv_mode() {
+            let mut store = HashMap::from([("n1".to_string(), 5)]);
+            let config = Config {
+                mode: Mode::SeqKv,
+                ..Default::default()
+            };
+            let mut n1 = Node::new(config.clone());
+            let mut n2 = Node::new(config);
+            for (node, id) in [(&mut n1, "n1"), (&mut n2, "n2")] {
+                deliver(
+                    node,
+                    &mut store,
+                    Message {
+                        src: "c1".into(),
+                        dest: id.into(),
+                        body: Body::Init {
+                            msg_id: 1,
+                            node_id: id.into(),
+                            node_ids: vec!["n1".into(), "n2".into()],
+                        },
+                    },
+                );
+            }
+            assert!(n1.gossip().is_empty());
+
+            // n1's total from before a restart is picked up rather than overwritten
+            let add = |msg_id, delta| Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Add {
+                    msg_id,
+                    key: String::new(),
+                    delta,
+                },
+            };
+            let replies = deliver(&mut n1, &mut store, add(2, 2));
+            assert!(matches!(
+                replies[0].body,
+                Body::AddOk { in_reply_to: 2, .. }
+            ));
+            assert_eq!(store["n1"], 7);
+            // A stale cas is retried against the fresh total
+            store.insert("n1".into(), 10);
+            deliver(&mut n1, &mut store, add(3, -3));
+            assert_eq!(store["n1"], 7);
+
+            let replies = deliver(
+                &mut n2,
+                &mut store,
+                Message {
+                    src: "c2".into(),
+                    dest: "n2".into(),
+                    body: Body::Read {
+                        msg_id: 3,
+                        key: None,
+                        consistency: None,
+                    },
+                },
+            );
+            assert!(matches!(
+                replies[0].body,
+                Body::ReadOk {
+                    in_reply_to: 3,
+                    value: 7,
+                    ..
+                }
+            ));
+        }
+
+        #[test]
+        fn test_seq_kv_reads_fail_on_errors_and_timeouts() {
+            let mut n1 = init_with(
+                "n1",
+                Config {
+                    mode: Mode::SeqKv,
+                    kv_timeout: Duration::ZERO,
+                    ..Default::default()
+                },
+            );
+            let read = |msg_id| Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Read {
+                    msg_id,
+                    key: None,
+                    consistency: None,
+                },
+            };
+            let sync = n1.handle_message(read(2));
+            let Body::Write { msg_id, .. } = sync[0].body else {
+                panic!("Expected a sync write, got {:?}", sync);
+            };
+            let replies = n1.handle_message(Message {
+                src: SEQ_KV.into(),
+                dest: "n1".into(),
+                body: Body::Error {
+                    in_reply_to: msg_id,
+                    code: TEMPORARILY_UNAVAILABLE,
+                    text: "busy".into(),
+                },
+            });
+            assert!(matches!(
+                replies[0].body,
+                Body::Error {
+                    in_reply_to: 2,
+                    code: TEMPORARILY_UNAVAILABLE,
+                    ..
+                }
+            ));
+            assert!(n1.kv.sums.is_empty() && n1.kv.syncs.is_empty());
+
+            // A sync whose reply is lost fails once the read's deadline passes
+            n1.handle_message(read(3));
+            assert!(n1.next_deadline().is_some());
+            let expired = n1.expire_reads();
+            assert!(matches!(
+                expired[0].body,
+                Body::Error { in_reply_to: 3, .. }
+            ));
+            assert!(n1.next_deadline().is_none() && n1.kv.syncs.is_empty());
+        }
+
+        #[test]
+        fn test_unexpected_replies_are_ignored() {
+            let mut n1 = init("n1");
+            for body in [
+                Body::InitOk {
+                    msg_id: 1,
+                    in_reply_to: 1,
+                },
+                Body::AddOk {
+                    msg_id: 2,
+                    in_reply_to: 2,
+                },
+            ] {
+                let replies = n1.handle_message(Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body,
+                });
+                assert!(replies.is_empty(), "{:?}", replies);
+            }
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::gossip(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+
+    fn next_tick(&self, _: Duration) -> Duration {
+        node::Node::gossip_delay(self)
+    }
+
+    fn tick_due(&self) -> bool {
+        node::Node::gossip_due(self)
+    }
+
+    fn next_deadline(&self) -> Option<std::time::Instant> {
+        node::Node::next_deadline(self)
+    }
+
+    fn expire(&mut self) -> Vec<node::Message> {
+        node::Node::expire_reads(self)
+    }
+
+    fn shared(&self) -> Option<Arc<dyn maelstrom::Shared<Message = node::Message>>> {
+        Some(node::Node::adds(self))
+    }
+}
+
+impl maelstrom::Shared for node::Adds {
+    type Message = node::Message;
+
+    fn claims(&self, message: &node::Message) -> bool {
+        node::Adds::claims(self, message)
+    }
+
+    fn handle(&self, message: node::Message) -> Vec<node::Message> {
+        node::Adds::handle(self, message)
+    }
+}
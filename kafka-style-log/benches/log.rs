@@ -0,0 +1,75 @@
+//! Latency of the log's two halves on a single node: appending a send to a key, and polling
+//! a batch back out of a key's log.
+
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use serde_json::{json, Value};
+
+#[allow(dead_code, unused_imports)]
+mod kafka {
+    include!("../src/main.rs");
+    pub use node::{Config, Message, Node};
+}
+
+use kafka::{Config, Message, Node};
+
+fn message(body: Value) -> Message {
+    serde_json::from_value(json!({"src": "c1", "dest": "n1", "body": body})).unwrap()
+}
+
+/// A single node cluster, so it leads every key and nothing waits on replicas.
+fn node() -> Node {
+    let node = Node::new(Config::default());
+    node.handle_message(message(
+        json!({"type": "init", "msg_id": 0, "node_id": "n1", "node_ids": ["n1"]}),
+    ));
+    node
+}
+
+fn send(node: &Node, key: &str, msg: u64) -> Vec<Message> {
+    node.handle_message(message(
+        json!({"type": "send", "msg_id": 1, "key": key, "msg": msg}),
+    ))
+}
+
+fn bench_append(c: &mut Criterion) {
+    let mut group = c.benchmark_group("append");
+    group.throughput(Throughput::Elements(1));
+    let node = node();
+    let mut msg = 0;
+    group.bench_function("one_key", |b| {
+        b.iter(|| {
+            msg += 1;
+            send(&node, "k", msg)
+        })
+    });
+    // Spread over enough keys that each send mostly lands on a different log
+    let keys = (0..1000).map(|i| format!("k{}", i)).collect::<Vec<_>>();
+    group.bench_function("many_keys", |b| {
+        b.iter(|| {
+            msg += 1;
+            send(&node, &keys[msg as usize % keys.len()], msg)
+        })
+    });
+    group.finish();
+}
+
+fn bench_poll(c: &mut Criterion) {
+    let mut group = c.benchmark_group("poll");
+    let node = node();
+    for i in 0..10_000 {
+        send(&node, "k", i);
+    }
+    // The most a poll returns per key under the default config is 1000
+    for batch in [1, 100, 1000] {
+        group.throughput(Throughput::Elements(batch));
+        group.bench_with_input(BenchmarkId::from_parameter(batch), &batch, |b, batch| {
+            // Read from near the end so the poll only has `batch` entries to return
+            let poll = json!({"type": "poll", "msg_id": 2, "offsets": {"k": 10_000 - batch}});
+            b.iter(|| node.handle_message(message(poll.clone())))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, bench_append, bench_poll);
+criterion_main!(benches);
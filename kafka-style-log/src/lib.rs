@@ -0,0 +1,5002 @@
+mod store {
+    use serde_json::Value;
+    use std::collections::hash_map::Entry;
+    use std::collections::HashMap;
+    use std::collections::HashSet;
+    use std::fs;
+    use std::io::{self, Write};
+    use std::path::{Path, PathBuf};
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
+
+    /// When writes to the stores are forced to disk. Anything short of `Always` can lose
+    /// acknowledged writes in a crash, in exchange for cheaper appends.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum FsyncPolicy {
+        /// Before every write returns.
+        Always,
+        /// Once every `batch` appends, with whatever's left synced by the next `sync`.
+        Batch,
+        /// Only when `sync` is called.
+        Periodic,
+    }
+
+    impl FromStr for FsyncPolicy {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "always" => Ok(FsyncPolicy::Always),
+                "batch" => Ok(FsyncPolicy::Batch),
+                "periodic" => Ok(FsyncPolicy::Periodic),
+                _ => Err(format!("unknown fsync policy {:?}", s)),
+            }
+        }
+    }
+
+    /// Append-only files holding every key's entries, so a restarted node comes back with its
+    /// data. Each line is `<offset> <crc32> <timestamp> <json>`. The checksum is verified on
+    /// recovery so a torn or corrupted write is reported instead of served to consumers, and
+    /// kept with the entry in memory so reads can verify it too.
+    pub struct EntryStore {
+        dir: PathBuf,
+        files: Mutex<HashMap<String, Arc<Mutex<fs::File>>>>,
+        fsync: FsyncPolicy,
+        batch: usize,
+        dirty: Mutex<HashSet<String>>, // Keys written since their file was last synced
+        unsynced: AtomicUsize,         // Appends since the last sync
+    }
+
+    /// What was read back for one key: every entry up to the first bad one, if any.
+    pub struct RecoveredLog {
+        pub entries: Vec<(u64, u64, Value)>, // (offset, timestamp, msg)
+        pub corruption: Option<String>,
+    }
+
+    impl EntryStore {
+        pub fn open(
+            dir: &Path,
+            node_id: &str,
+            fsync: FsyncPolicy,
+            batch: usize,
+        ) -> io::Result<Self> {
+            let dir = dir.join(format!("{}.entries", node_id));
+            fs::create_dir_all(&dir)?;
+            Ok(EntryStore {
+                dir,
+                files: Mutex::new(HashMap::new()),
+                fsync,
+                batch,
+                dirty: Mutex::new(HashSet::new()),
+                unsynced: AtomicUsize::new(0),
+            })
+        }
+
+        pub fn append(
+            &self,
+            key: &str,
+            offset: u64,
+            checksum: u32,
+            timestamp: u64,
+            msg: &Value,
+        ) -> io::Result<()> {
+            let file = open_for_append(&self.files, &self.dir.join(file_name(key, "log")), key)?;
+            let mut line = format!("{} {:08x} {} ", offset, checksum, timestamp).into_bytes();
+            serde_json::to_writer(&mut line, msg)?;
+            line.push(b'\n');
+            {
+                let mut file = file.lock().unwrap();
+                file.write_all(&line)?;
+                if self.fsync == FsyncPolicy::Always {
+                    return file.sync_data();
+                }
+            }
+            self.dirty.lock().unwrap().insert(key.to_string());
+            let unsynced = self.unsynced.fetch_add(1, Ordering::Relaxed) + 1;
+            if self.fsync == FsyncPolicy::Batch && unsynced >= self.batch {
+                self.sync()?;
+            }
+            Ok(())
+        }
+
+        /// Forces every key written since the last sync to disk.
+        pub fn sync(&self) -> io::Result<()> {
+            self.unsynced.store(0, Ordering::Relaxed);
+            sync_dirty(&self.files, &self.dirty)
+        }
+
+        /// Reads back every key's entries. A key whose file has a bad line keeps the entries
+        /// before it, and the file is truncated there so later appends don't land after junk.
+        pub fn load(&self) -> io::Result<HashMap<String, RecoveredLog>> {
+            let mut logs = HashMap::new();
+            for file in fs::read_dir(&self.dir)? {
+                let path = file?.path();
+                let Some(key) = path.file_stem().and_then(|stem| key_name(stem.to_str()?)) else {
+                    continue;
+                };
+                let bytes = fs::read(&path)?;
+                let mut recovered = RecoveredLog {
+                    entries: Vec::new(),
+                    corruption: None,
+                };
+                let mut valid = 0;
+                for line in bytes.split_inclusive(|b| *b == b'\n') {
+                    match parse_line(line) {
+                        Ok(entry) => recovered.entries.push(entry),
+                        Err(e) => {
+                            recovered.corruption = Some(format!("byte {}: {}", valid, e));
+                            break;
+                        }
+                    }
+                    valid += line.len();
+                }
+                if recovered.corruption.is_some() {
+                    fs::OpenOptions::new()
+                        .write(true)
+                        .open(&path)?
+                        .set_len(valid as u64)?;
+                }
+                logs.insert(key, recovered);
+            }
+            Ok(logs)
+        }
+    }
+
+    fn parse_line(line: &[u8]) -> Result<(u64, u64, Value), String> {
+        let line = line.strip_suffix(b"\n").ok_or("line was cut short")?;
+        let mut fields = line.splitn(4, |b| *b == b' ');
+        let mut field = || {
+            fields
+                .next()
+                .and_then(|field| std::str::from_utf8(field).ok())
+                .ok_or("missing field")
+        };
+        let offset = field()?.parse().map_err(|_| "bad offset")?;
+        let checksum = u32::from_str_radix(field()?, 16).map_err(|_| "bad checksum")?;
+        let timestamp = field()?.parse().map_err(|_| "bad timestamp")?;
+        let json = fields.next().ok_or("missing entry")?;
+        if crc32fast::hash(json) != checksum {
+            return Err(format!("checksum mismatch at offset {}", offset));
+        }
+        let msg = serde_json::from_slice(json).map_err(|e| e.to_string())?;
+        Ok((offset, timestamp, msg))
+    }
+
+    /// The file `key` appends to, opened the first time it's needed and kept open after.
+    fn open_for_append(
+        files: &Mutex<HashMap<String, Arc<Mutex<fs::File>>>>,
+        path: &Path,
+        key: &str,
+    ) -> io::Result<Arc<Mutex<fs::File>>> {
+        Ok(match files.lock().unwrap().entry(key.to_string()) {
+            Entry::Occupied(file) => Arc::clone(file.get()),
+            Entry::Vacant(slot) => {
+                let file = fs::OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(path)?;
+                Arc::clone(slot.insert(Arc::new(Mutex::new(file))))
+            }
+        })
+    }
+
+    /// Forces the files of every key in `dirty` to disk, emptying it.
+    fn sync_dirty(
+        files: &Mutex<HashMap<String, Arc<Mutex<fs::File>>>>,
+        dirty: &Mutex<HashSet<String>>,
+    ) -> io::Result<()> {
+        let dirty = std::mem::take(&mut *dirty.lock().unwrap());
+        for key in dirty {
+            let file = files.lock().unwrap().get(&key).cloned();
+            if let Some(file) = file {
+                file.lock().unwrap().sync_data()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Keys can hold any character, so files are named after their hex encoding.
+    fn file_name(key: &str, extension: &str) -> String {
+        let hex = key
+            .bytes()
+            .map(|b| format!("{:02x}", b))
+            .collect::<String>();
+        format!("{}.{}", hex, extension)
+    }
+
+    fn key_name(hex: &str) -> Option<String> {
+        let bytes = (0..hex.len())
+            .step_by(2)
+            .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+            .collect::<Option<Vec<_>>>()?;
+        String::from_utf8(bytes).ok()
+    }
+
+    /// Durable record of the next offset for each key, so a restarted node never hands out an
+    /// offset it already used. Each key has its own append-only journal of the next offsets
+    /// it has reached, so a save only touches its own key's file. The highest complete line
+    /// is the one that counts, which also means saves landing out of order can't move a key
+    /// backwards.
+    pub struct OffsetStore {
+        dir: PathBuf,
+        files: Mutex<HashMap<String, Arc<Mutex<fs::File>>>>,
+        fsync: FsyncPolicy,
+        dirty: Mutex<HashSet<String>>, // Keys saved since their journal was last synced
+    }
+
+    impl OffsetStore {
+        pub fn open(dir: &Path, node_id: &str, fsync: FsyncPolicy) -> io::Result<Self> {
+            let dir = dir.join(format!("{}.offsets", node_id));
+            fs::create_dir_all(&dir)?;
+            Ok(OffsetStore {
+                dir,
+                files: Mutex::new(HashMap::new()),
+                fsync,
+                dirty: Mutex::new(HashSet::new()),
+            })
+        }
+
+        /// Reads back every key's next offset, ignoring a last line cut short by a crash, and
+        /// compacts each journal down to that one line. The compacted journal is written to a
+        /// temporary file first so a crash mid-write leaves the old one intact.
+        pub fn load(&self) -> io::Result<HashMap<String, u64>> {
+            let mut next_offsets = HashMap::new();
+            for file in fs::read_dir(&self.dir)? {
+                let path = file?.path();
+                if path.extension().is_none_or(|extension| extension != "next") {
+                    continue;
+                }
+                let Some(key) = path.file_stem().and_then(|stem| key_name(stem.to_str()?)) else {
+                    continue;
+                };
+                let bytes = fs::read(&path)?;
+                let next_offset = bytes
+                    .split_inclusive(|b| *b == b'\n')
+                    .filter_map(|line| {
+                        let line = std::str::from_utf8(line.strip_suffix(b"\n")?).ok()?;
+                        line.parse::<u64>().ok()
+                    })
+                    .max();
+                let Some(next_offset) = next_offset else {
+                    continue;
+                };
+                let tmp = path.with_extension("tmp");
+                let mut file = fs::File::create(&tmp)?;
+                file.write_all(format!("{}\n", next_offset).as_bytes())?;
+                file.sync_all()?;
+                fs::rename(tmp, &path)?;
+                next_offsets.insert(key, next_offset);
+            }
+            Ok(next_offsets)
+        }
+
+        /// Appends the next offset for `key` to its journal.
+        pub fn save(&self, key: &str, next_offset: u64) -> io::Result<()> {
+            let file = open_for_append(&self.files, &self.dir.join(file_name(key, "next")), key)?;
+            {
+                let mut file = file.lock().unwrap();
+                file.write_all(format!("{}\n", next_offset).as_bytes())?;
+                if self.fsync == FsyncPolicy::Always {
+                    return file.sync_data();
+                }
+            }
+            self.dirty.lock().unwrap().insert(key.to_string());
+            Ok(())
+        }
+
+        /// Forces every key saved since the last sync to disk.
+        pub fn sync(&self) -> io::Result<()> {
+            sync_dirty(&self.files, &self.dirty)
+        }
+    }
+}
+
+mod quota {
+    use std::time::{Duration, Instant};
+
+    /// Classic token bucket: holds up to `burst` tokens and refills at `rate` per second.
+    pub struct TokenBucket {
+        rate: f64,
+        burst: f64,
+        tokens: f64,
+        refilled: Instant,
+    }
+
+    impl TokenBucket {
+        pub fn new(rate: f64, burst: f64) -> Self {
+            TokenBucket {
+                rate,
+                burst,
+                tokens: burst,
+                refilled: Instant::now(),
+            }
+        }
+
+        /// Takes a token, or says how long until one will be available.
+        pub fn take(&mut self) -> Result<(), Duration> {
+            let now = Instant::now();
+            let elapsed = now.duration_since(self.refilled).as_secs_f64();
+            self.tokens = (self.tokens + elapsed * self.rate).min(self.burst);
+            self.refilled = now;
+            if self.tokens >= 1.0 {
+                self.tokens -= 1.0;
+                Ok(())
+            } else {
+                Err(Duration::from_secs_f64((1.0 - self.tokens) / self.rate))
+            }
+        }
+    }
+}
+
+pub mod node {
+    use super::quota::TokenBucket;
+    use super::store::{EntryStore, FsyncPolicy, OffsetStore};
+    use election::{leader_among, Election};
+    use maelstrom::error::{
+        CRASH, KEY_DOES_NOT_EXIST, NOT_SUPPORTED, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE,
+        TIMEOUT, TXN_CONFLICT,
+    };
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::hash::{Hash, Hasher};
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex, OnceLock, RwLock};
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+    use tokio::runtime::{Handle, RuntimeFlavor};
+    use tokio::task;
+
+    /// Handlers take `&self` so messages run in parallel: every key's log has its own lock,
+    /// and the bookkeeping shared across keys sits behind small locks that are only held for
+    /// a lookup or update. No lock is held while handling another message (see `deliver`),
+    /// and at most one key's log is locked at a time, so a slow append to one key never
+    /// blocks a poll of another.
+    pub struct Node {
+        initialized: AtomicBool,
+        cur_id: AtomicU64,
+        config: Config,
+        cluster: RwLock<Cluster>,
+        store: OnceLock<OffsetStore>, // Only opened when a data directory is configured
+        entry_store: OnceLock<EntryStore>,
+        logs: RwLock<HashMap<String, Arc<Mutex<KeyLog>>>>, // Map of the append only logs
+        forwards: Mutex<HashMap<u64, Forward>>, // Sends forwarded to their leader, by msg_id
+        sessions: Mutex<HashMap<String, HashMap<String, u64>>>, // Highest offset acked per client
+        held_polls: Mutex<Vec<HeldPoll>>, // Polls waiting for us to catch up to a client's writes
+        cas_sends: Mutex<HashMap<u64, CasSend>>, // Pending cas sends, by lin-kv msg_id
+        epoch_requests: Mutex<HashMap<u64, EpochRequest>>, // Pending epochs, by lin-kv msg_id
+        txns: Mutex<HashMap<String, Txn>>, // Transactions we coordinate
+        prepared: Mutex<HashMap<String, PreparedTxn>>, // Transactions we've voted to commit
+        snapshotting: Mutex<HashSet<String>>, // Keys with a snapshot transfer in flight
+        snapshot_chunks: Mutex<HashMap<String, HashSet<usize>>>, // Chunks received, by key
+        quotas: Mutex<HashMap<String, TokenBucket>>, // Sends and polls left to each client
+        next_partition: AtomicUsize,      // Round-robin cursor for partitioned sends
+        fetch_sessions: Mutex<HashMap<u64, FetchSession>>, // Consumer positions, by session id
+        membership: Mutex<Vec<String>>,   // Alive nodes as of the last rebalance
+        migrations: Mutex<HashMap<String, Migration>>, // Keys moving to us
+        pending_acks: Mutex<HashMap<u64, PendingAck>>, // Sends waiting on acks, by ack group
+        replica_acks: Mutex<HashMap<u64, u64>>, // Ack group of each replicate msg_id
+        gathers: Mutex<Vec<PollGather>>,  // Polls waiting on reads from other replicas
+        last_fsync: Mutex<Instant>,       // When the stores were last synced by `tick`
+    }
+
+    /// Who we are and who else is in the cluster, set once by init.
+    struct Cluster {
+        id: String,
+        nodes: HashSet<String>, // List of all nodes
+        election: Election,
+    }
+
+    /// Entries per log segment.
+    const SEGMENT_SIZE: usize = 1024;
+
+    /// A run of consecutive entries starting at `base`.
+    struct Segment {
+        base: u64,
+        entries: Vec<Value>,
+        checksums: Vec<u32>, // CRC32 of each entry's JSON, checked whenever it's read
+        timestamps: Vec<u64>, // When each entry reached us, in ms since the Unix epoch
+    }
+
+    impl Segment {
+        /// Entries from the `skip`th one onwards, with their offsets and checksums.
+        fn iter_from(&self, skip: usize) -> impl Iterator<Item = (u64, &Value, u32)> {
+            (self.base + skip as u64..)
+                .zip(self.entries[skip..].iter())
+                .zip(self.checksums[skip..].iter())
+                .map(|((offset, msg), crc)| (offset, msg, *crc))
+        }
+    }
+
+    fn checksum(msg: &Value) -> u32 {
+        crc32fast::hash(&serde_json::to_vec(msg).unwrap_or_default())
+    }
+
+    fn now_millis() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |elapsed| elapsed.as_millis() as u64)
+    }
+
+    /// The append only log for a single key. Entries are split into fixed size segments so a
+    /// long log never needs one huge contiguous allocation, and the segments double as a
+    /// sparse index: finding an offset is a binary search over their base offsets.
+    #[derive(Default)]
+    struct KeyLog {
+        committed: u64,
+        next: u64, // Offset the next appended entry will get
+        segments: Vec<Segment>,
+        out_of_order: BTreeMap<u64, Value>, // Replicated entries past our tail
+        stalled_since: Option<Instant>,     // When out_of_order last stopped draining
+        locked_by: Option<String>,          // Prepared transaction holding the key
+        fence: u64,                         // Highest consumer epoch that committed offsets
+    }
+
+    impl KeyLog {
+        fn starting_at(next: u64) -> Self {
+            KeyLog {
+                next,
+                ..Default::default()
+            }
+        }
+
+        fn next_offset(&self) -> u64 {
+            self.next
+        }
+
+        /// Number of entries held in memory.
+        fn len(&self) -> usize {
+            self.segments
+                .iter()
+                .map(|segment| segment.entries.len())
+                .sum()
+        }
+
+        /// Frees the entries below `offset`, returning how many were dropped. Polls from below
+        /// it start at the first entry still held, as they would at a hole.
+        fn release_below(&mut self, offset: u64) -> usize {
+            let whole = self
+                .segments
+                .partition_point(|segment| segment.base + segment.entries.len() as u64 <= offset);
+            let mut released: usize = self
+                .segments
+                .drain(..whole)
+                .map(|segment| segment.entries.len())
+                .sum();
+            if let Some(segment) = self.segments.first_mut().filter(|s| s.base < offset) {
+                let skip = (offset - segment.base) as usize;
+                segment.entries.drain(..skip);
+                segment.checksums.drain(..skip);
+                segment.timestamps.drain(..skip);
+                segment.base = offset;
+                released += skip;
+            }
+            released
+        }
+
+        /// Moves the tail forward to `offset`, leaving a hole for entries we'll never receive.
+        fn skip_to(&mut self, offset: u64) {
+            self.next = self.next.max(offset);
+        }
+
+        fn push(&mut self, msg: Value) -> u64 {
+            self.push_at(msg, now_millis())
+        }
+
+        /// Appends an entry ingested at `timestamp`. Timestamps never go backwards within a
+        /// log, even if the clock does, so they can be binary searched.
+        fn push_at(&mut self, msg: Value, timestamp: u64) -> u64 {
+            let next = self.next;
+            let crc = checksum(&msg);
+            let timestamp = self
+                .last_timestamp()
+                .map_or(timestamp, |last| last.max(timestamp));
+            match self.segments.last_mut() {
+                Some(segment)
+                    if segment.entries.len() < SEGMENT_SIZE
+                        && segment.base + segment.entries.len() as u64 == next =>
+                {
+                    segment.entries.push(msg);
+                    segment.checksums.push(crc);
+                    segment.timestamps.push(timestamp);
+                }
+                _ => self.segments.push(Segment {
+                    base: self.next,
+                    entries: vec![msg],
+                    checksums: vec![crc],
+                    timestamps: vec![timestamp],
+                }),
+            }
+            self.next += 1;
+            self.next - 1
+        }
+
+        /// Stores an entry at `offset` wherever it falls: past the tail it's appended, leaving
+        /// a hole before it, and below the tail it fills the hole it lands in. Its timestamp
+        /// is clamped between its neighbours' so they stay sorted. Returns whether it was
+        /// stored, which it isn't if we already hold the offset.
+        fn insert(&mut self, offset: u64, msg: Value, timestamp: u64) -> bool {
+            if offset >= self.next {
+                self.skip_to(offset);
+                self.push_at(msg, timestamp);
+                return true;
+            }
+            if self.timestamp(offset).is_some() {
+                return false;
+            }
+            let after = self
+                .segments
+                .partition_point(|segment| segment.base <= offset);
+            let earliest = after
+                .checked_sub(1)
+                .and_then(|before| self.segments[before].timestamps.last().copied())
+                .unwrap_or(0);
+            let latest = self
+                .segments
+                .get(after)
+                .map_or(u64::MAX, |segment| segment.timestamps[0]);
+            let (crc, timestamp) = (checksum(&msg), timestamp.clamp(earliest, latest));
+            match after
+                .checked_sub(1)
+                .map(|before| &mut self.segments[before])
+            {
+                Some(segment)
+                    if segment.entries.len() < SEGMENT_SIZE
+                        && segment.base + segment.entries.len() as u64 == offset =>
+                {
+                    segment.entries.push(msg);
+                    segment.checksums.push(crc);
+                    segment.timestamps.push(timestamp);
+                }
+                _ => self.segments.insert(
+                    after,
+                    Segment {
+                        base: offset,
+                        entries: vec![msg],
+                        checksums: vec![crc],
+                        timestamps: vec![timestamp],
+                    },
+                ),
+            }
+            true
+        }
+
+        fn last_timestamp(&self) -> Option<u64> {
+            self.segments
+                .last()
+                .and_then(|segment| segment.timestamps.last().copied())
+        }
+
+        /// When the entry at `offset` was ingested, if we hold it.
+        fn timestamp(&self, offset: u64) -> Option<u64> {
+            let segment = self
+                .segments
+                .partition_point(|segment| segment.base <= offset)
+                .checked_sub(1)?;
+            let segment = &self.segments[segment];
+            segment
+                .timestamps
+                .get((offset - segment.base) as usize)
+                .copied()
+        }
+
+        /// The first offset ingested at or after `timestamp`, or the tail if there's none yet.
+        fn offset_for_time(&self, timestamp: u64) -> u64 {
+            let first = self.segments.partition_point(|segment| {
+                segment
+                    .timestamps
+                    .last()
+                    .is_some_and(|last| *last < timestamp)
+            });
+            self.segments.get(first).map_or(self.next, |segment| {
+                segment.base + segment.timestamps.partition_point(|t| *t < timestamp) as u64
+            })
+        }
+
+        /// Entries at or after `offset`, paired with their offsets.
+        fn iter_from(&self, offset: u64) -> impl Iterator<Item = (u64, &Value)> {
+            self.iter_checked_from(offset)
+                .map(|(offset, msg, _)| (offset, msg))
+        }
+
+        /// Like `iter_from`, with the checksum each entry was stored with.
+        fn iter_checked_from(&self, offset: u64) -> impl Iterator<Item = (u64, &Value, u32)> {
+            let first = self
+                .segments
+                .partition_point(|segment| segment.base <= offset)
+                .saturating_sub(1);
+            let mut segments = self.segments[first..].iter();
+            let head = segments.next().map(|segment| {
+                let skip =
+                    (offset.saturating_sub(segment.base) as usize).min(segment.entries.len());
+                segment.iter_from(skip)
+            });
+            head.into_iter()
+                .flatten()
+                .chain(segments.flat_map(|segment| segment.iter_from(0)))
+        }
+    }
+
+    /// A snapshot of one key's log for throughput experiments.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct KeyMetrics {
+        length: u64,
+        committed: u64,
+        lag: u64, // Entries appended but not yet committed
+    }
+
+    /// Tunables read from the environment at startup.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Config {
+        pub poll_max_msgs_per_key: usize,
+        pub poll_max_bytes_per_key: usize,
+        pub poll_max_msgs: usize,
+        pub poll_max_bytes: usize,
+        pub heartbeat_interval: Duration,
+        pub leader_timeout: Duration,
+        pub data_dir: Option<PathBuf>,
+        pub snapshot_chunk_size: usize,
+        pub snapshot_lag_threshold: usize,
+        pub session_wait_timeout: Duration,
+        pub send_mode: SendMode,
+        /// Partitions each key is split into, each with its own leader and offsets. Client
+        /// offsets interleave them (offset `o` of partition `p` is `o * partitions + p`), and
+        /// a poll only returns a key's entries below the next offset every partition could
+        /// still fill, so one that's behind holds the others back rather than being skipped.
+        pub partitions: usize,
+        pub partitioner: Partitioner,
+        pub txn_timeout: Duration,
+        /// Entries a key may hold in memory before sends to it are refused until consumers
+        /// commit past some of them, which frees them; 0 for no limit.
+        pub max_log_entries: usize,
+        /// Blocking threads a multi-key poll reads its keys on; 1 reads them one after another
+        /// on the task handling the poll.
+        pub poll_parallelism: usize,
+        /// Sends and polls per second each client may make; 0 for no limit.
+        pub client_rate: f64,
+        pub client_burst: f64,
+        /// How long a fetch session is remembered without being polled.
+        pub fetch_session_timeout: Duration,
+        /// How long sends to a key moving to us are held before we give up on its transfer.
+        pub migration_timeout: Duration,
+        /// Nodes holding each key's log, leader included; 0 for every node.
+        pub replication_factor: usize,
+        /// Replica acks a send waits for before it's acknowledged, capped at the number of
+        /// other replicas alive when it's sent, which is the default. Waiting on all of them
+        /// means whichever takes over the key holds every acknowledged entry; with fewer, a
+        /// leader dying before its entries reach its successor can have an offset it
+        /// acknowledged handed out again. 0 acknowledges as soon as the leader has the entry.
+        pub acks: usize,
+        /// How long a send waits for its acks before the client is told to retry.
+        pub ack_timeout: Duration,
+        /// When persisted entries and offsets are forced to disk.
+        pub fsync: FsyncPolicy,
+        /// Appends per fsync under the batch policy.
+        pub fsync_batch: usize,
+        /// How often the periodic policy syncs; the batch policy also syncs any partial batch
+        /// this often.
+        pub fsync_interval: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                poll_max_msgs_per_key: 1000,
+                poll_max_bytes_per_key: 64 * 1024,
+                poll_max_msgs: 10_000,
+                poll_max_bytes: 1024 * 1024,
+                heartbeat_interval: Duration::from_millis(100),
+                leader_timeout: Duration::from_millis(500),
+                data_dir: None,
+                snapshot_chunk_size: 512,
+                snapshot_lag_threshold: 256,
+                session_wait_timeout: Duration::from_millis(1000),
+                send_mode: SendMode::Leader,
+                partitions: 1,
+                partitioner: Partitioner::RoundRobin,
+                txn_timeout: Duration::from_millis(2000),
+                max_log_entries: 0,
+                poll_parallelism: 4,
+                client_rate: 0.0,
+                client_burst: 100.0,
+                fetch_session_timeout: Duration::from_secs(60),
+                migration_timeout: Duration::from_millis(2000),
+                replication_factor: 0,
+                acks: usize::MAX,
+                ack_timeout: Duration::from_millis(1000),
+                fsync: FsyncPolicy::Always,
+                fsync_batch: 64,
+                fsync_interval: Duration::from_millis(100),
+            }
+        }
+    }
+
+    impl Config {
+        /// Overrides the send settings that decide how consistent the log is.
+        pub fn with_consistency(self, consistency: Consistency) -> Self {
+            match consistency {
+                Consistency::Sequential => Config {
+                    send_mode: SendMode::Leader,
+                    ..self
+                },
+                Consistency::Linearizable => Config {
+                    send_mode: SendMode::Cas,
+                    ..self
+                },
+            }
+        }
+
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                poll_max_msgs_per_key: env_or(
+                    "KAFKA_POLL_MAX_MSGS_PER_KEY",
+                    default.poll_max_msgs_per_key,
+                ),
+                poll_max_bytes_per_key: env_or(
+                    "KAFKA_POLL_MAX_BYTES_PER_KEY",
+                    default.poll_max_bytes_per_key,
+                ),
+                poll_max_msgs: env_or("KAFKA_POLL_MAX_MSGS", default.poll_max_msgs),
+                poll_max_bytes: env_or("KAFKA_POLL_MAX_BYTES", default.poll_max_bytes),
+                heartbeat_interval: Duration::from_millis(env_or(
+                    "KAFKA_HEARTBEAT_INTERVAL_MS",
+                    default.heartbeat_interval.as_millis() as u64,
+                )),
+                leader_timeout: Duration::from_millis(env_or(
+                    "KAFKA_LEADER_TIMEOUT_MS",
+                    default.leader_timeout.as_millis() as u64,
+                )),
+                data_dir: std::env::var_os("KAFKA_DATA_DIR").map(PathBuf::from),
+                snapshot_chunk_size: env_or(
+                    "KAFKA_SNAPSHOT_CHUNK_SIZE",
+                    default.snapshot_chunk_size,
+                ),
+                snapshot_lag_threshold: env_or(
+                    "KAFKA_SNAPSHOT_LAG_THRESHOLD",
+                    default.snapshot_lag_threshold,
+                ),
+                session_wait_timeout: Duration::from_millis(env_or(
+                    "KAFKA_SESSION_WAIT_TIMEOUT_MS",
+                    default.session_wait_timeout.as_millis() as u64,
+                )),
+                send_mode: env_or("KAFKA_SEND_MODE", default.send_mode),
+                partitions: env_or("KAFKA_PARTITIONS", default.partitions).max(1),
+                partitioner: env_or("KAFKA_PARTITIONER", default.partitioner),
+                txn_timeout: Duration::from_millis(env_or(
+                    "KAFKA_TXN_TIMEOUT_MS",
+                    default.txn_timeout.as_millis() as u64,
+                )),
+                max_log_entries: env_or("KAFKA_MAX_LOG_ENTRIES", default.max_log_entries),
+                poll_parallelism: env_or("KAFKA_POLL_PARALLELISM", default.poll_parallelism),
+                client_rate: env_or("KAFKA_CLIENT_RATE", default.client_rate),
+                client_burst: env_or("KAFKA_CLIENT_BURST", default.client_burst),
+                fetch_session_timeout: Duration::from_millis(env_or(
+                    "KAFKA_FETCH_SESSION_TIMEOUT_MS",
+                    default.fetch_session_timeout.as_millis() as u64,
+                )),
+                migration_timeout: Duration::from_millis(env_or(
+                    "KAFKA_MIGRATION_TIMEOUT_MS",
+                    default.migration_timeout.as_millis() as u64,
+                )),
+                replication_factor: env_or("KAFKA_REPLICATION_FACTOR", default.replication_factor),
+                acks: env_or("KAFKA_ACKS", default.acks),
+                ack_timeout: Duration::from_millis(env_or(
+                    "KAFKA_ACK_TIMEOUT_MS",
+                    default.ack_timeout.as_millis() as u64,
+                )),
+                fsync: env_or("KAFKA_FSYNC", default.fsync),
+                fsync_batch: env_or("KAFKA_FSYNC_BATCH", default.fsync_batch).max(1),
+                fsync_interval: Duration::from_millis(env_or(
+                    "KAFKA_FSYNC_INTERVAL_MS",
+                    default.fsync_interval.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// Maelstrom's linearizable key/value service.
+    const LIN_KV: &str = "lin-kv";
+
+    /// The lin-kv key holding the next offset for `key` in cas mode.
+    fn tail_key(key: &str) -> String {
+        format!("tail-{}", key)
+    }
+
+    /// The lin-kv key holding the last consumer epoch handed out.
+    const EPOCH_KEY: &str = "consumer-epoch";
+
+    /// Lin-kv requests a cas send or epoch request makes before the client is told to retry,
+    /// so a lin-kv that keeps failing, or a key that's always contended, can't keep it going
+    /// forever.
+    const MAX_LIN_KV_REQUESTS: u32 = 16;
+
+    /// How sends pick their offset.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum SendMode {
+        /// The key's elected leader assigns offsets and replicates to its peers.
+        Leader,
+        /// Any node reserves the next offset with a compare-and-swap on the key's tail pointer
+        /// in lin-kv, so appends are linearizable across nodes at the cost of two round trips.
+        Cas,
+    }
+
+    impl FromStr for SendMode {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "leader" => Ok(SendMode::Leader),
+                "cas" => Ok(SendMode::Cas),
+                _ => Err(format!("unknown send mode {:?}", s)),
+            }
+        }
+    }
+
+    /// Which flavour of the challenge to run as, picked with `--consistency` at startup.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Consistency {
+        /// Leaders append locally and replicate before acknowledging: cheaper than going
+        /// through lin-kv, but a consumer on another node can briefly read behind a producer.
+        Sequential,
+        /// Offsets are reserved through lin-kv, so every append is ordered across nodes.
+        Linearizable,
+    }
+
+    impl FromStr for Consistency {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "sequential" => Ok(Consistency::Sequential),
+                "linearizable" => Ok(Consistency::Linearizable),
+                _ => Err(format!("unknown consistency mode {:?}", s)),
+            }
+        }
+    }
+
+    /// How sends to a partitioned key pick their partition.
+    #[derive(Debug, Clone, Copy, PartialEq)]
+    pub enum Partitioner {
+        /// To the partition we've seen fewest entries in, taking turns on ties, so none lags
+        /// far enough to hold back polls.
+        RoundRobin,
+        /// By a hash of the message, so equal messages always share a partition.
+        Hash,
+    }
+
+    impl FromStr for Partitioner {
+        type Err = String;
+
+        fn from_str(s: &str) -> Result<Self, Self::Err> {
+            match s {
+                "round-robin" => Ok(Partitioner::RoundRobin),
+                "hash" => Ok(Partitioner::Hash),
+                _ => Err(format!("unknown partitioner {:?}", s)),
+            }
+        }
+    }
+
+    /// The msg_id of a request, or `None` for replies (and messages without one), which must
+    /// never be answered with an error of their own.
+    fn request_id(body: &Body) -> Option<u64> {
+        let body = serde_json::to_value(body).ok()?;
+        if body.get("in_reply_to").is_some() {
+            return None;
+        }
+        body.get("msg_id")?.as_u64()
+    }
+
+    /// The internal key partition `p` of `key` is stored under. Each partition is a key of
+    /// its own everywhere past the client-facing edge, so it gets its own leader and log.
+    fn partition_key(key: &str, p: usize) -> String {
+        format!("{}#{}", key, p)
+    }
+
+    /// A client send we passed on to the key's leader.
+    struct Forward {
+        client: String,
+        msg_id: u64,
+        key: String,
+        deadline: Instant, // When we stop waiting on the leader and tell the client to retry
+    }
+
+    /// A client send waiting on lin-kv to reserve its offset.
+    struct CasSend {
+        client: String,
+        msg_id: u64,
+        key: String,
+        msg: Value,
+        from: u64, // The tail we're trying to move past
+        requests: u32,
+    }
+
+    /// A consumer waiting on lin-kv to hand it a fresh epoch.
+    struct EpochRequest {
+        client: String,
+        msg_id: u64,
+        from: u64, // The last epoch handed out, which we're trying to move past
+        requests: u32,
+    }
+
+    /// A multi-key send we're coordinating with two-phase commit across the keys' leaders.
+    struct Txn {
+        client: String,
+        msg_id: u64,
+        participants: Vec<String>,
+        waiting: HashSet<String>, // Participants we still need a vote, then offsets, from
+        commit: bool,             // Whether every vote so far was yes
+        deciding: bool,
+        offsets: HashMap<String, u64>,
+        deadline: Instant,
+    }
+
+    /// Entries a participant has voted to append, holding their keys' locks until decided.
+    /// Once committed, the share remembers where its entries went, so a decide the coordinator
+    /// resends because our ack was lost gets the same answer.
+    struct PreparedTxn {
+        coordinator: String,
+        msgs: HashMap<String, Value>,
+        offsets: Option<HashMap<String, u64>>, // Set once committed
+        deadline: Instant, // When to ask the coordinator again, or forget a committed share
+    }
+
+    /// How many `txn_timeout`s a participant remembers a committed share for.
+    const COMMITTED_SHARE_TIMEOUTS: u32 = 10;
+
+    /// A poll from a client whose own acknowledged writes haven't been replicated to us yet,
+    /// or a long poll waiting for new entries.
+    struct HeldPoll {
+        client: String,
+        msg_id: u64,
+        offsets: HashMap<String, u64>,
+        session: Option<u64>,
+        wait_for_data: bool,
+        deadline: Instant,
+    }
+
+    /// A send that's been appended and is waiting on replica acks before its reply goes out.
+    struct PendingAck {
+        client: String,
+        reply: Body,
+        needed: usize,
+        deadline: Instant,
+    }
+
+    /// A poll naming keys we don't hold a replica of. Their entries are read from each key's
+    /// leader and merged with ours before the client is answered.
+    struct PollGather {
+        client: String,
+        msg_id: u64,
+        session: Option<u64>,
+        offsets: HashMap<String, u64>,
+        msgs: Entries,
+        pending: HashSet<u64>, // Outstanding read_entries requests
+        deadline: Instant,
+    }
+
+    /// A consumer's positions remembered between polls, so each poll only has to name the
+    /// keys it's adding or moving.
+    struct FetchSession {
+        positions: HashMap<String, u64>,
+        last_used: Instant,
+    }
+
+    /// A key whose log is being transferred to us after its ownership moved here. Sends to it
+    /// are held until the transfer lands so we don't hand out offsets the old owner used.
+    struct Migration {
+        held: Vec<(String, u64, Value)>, // (src, msg_id, msg)
+        deadline: Instant,
+    }
+
+    /// Remaining room in a poll response, shared across all keys in the request.
+    struct PollBudget {
+        msgs: usize,
+        bytes: usize,
+    }
+
+    /// One key's entries read for a poll, with their serialized sizes.
+    type Slice = Vec<(u64, Value, usize)>;
+
+    /// Entries read for a poll, by key.
+    type Entries = HashMap<String, Vec<(u64, Value)>>;
+
+    /// An entry that no longer matches the checksum it was stored with.
+    struct CorruptEntry {
+        key: String,
+        offset: u64,
+    }
+
+    impl CorruptEntry {
+        /// The reply to a read that ran into it.
+        fn error(&self, in_reply_to: u64) -> Body {
+            log::error!("Entry {} of {} failed its checksum", self.offset, self.key);
+            Body::Error {
+                in_reply_to,
+                code: CRASH,
+                text: format!("entry {} of {} is corrupt", self.offset, self.key),
+                retry_after: None,
+            }
+        }
+    }
+
+    impl PollBudget {
+        /// Takes the longest prefix of a key's slice that still fits. The first entry is always
+        /// taken while there's room for messages so a single oversized entry can't stall a
+        /// consumer.
+        fn take(&mut self, slice: Slice) -> Vec<(u64, Value)> {
+            let mut entries = Vec::new();
+            for (offset, msg, size) in slice {
+                if self.msgs == 0 || (!entries.is_empty() && size > self.bytes) {
+                    break;
+                }
+                self.msgs -= 1;
+                self.bytes = self.bytes.saturating_sub(size);
+                entries.push((offset, msg));
+            }
+            entries
+        }
+    }
+
+    /// Approximate size of a `[offset, msg]` pair once serialized, given the serialized msg.
+    fn entry_size(offset: u64, msg: &[u8]) -> usize {
+        msg.len() + offset.to_string().len() + 3
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Send {
+            msg_id: u64,
+            key: String,
+            msg: Value,
+        },
+        SendOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            offset: u64,
+        },
+        Poll {
+            msg_id: u64,
+            offsets: HashMap<String, Option<u64>>, // No offset resumes from the committed one
+            /// How long to wait for new entries when there are none yet, rather than replying
+            /// with nothing.
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            wait_ms: Option<u64>,
+            /// Fetch session to read remembered positions from, or 0 to start one.
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            session_id: Option<u64>,
+        },
+        PollOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            msgs: HashMap<String, Vec<(u64, Value)>>,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            session_id: Option<u64>,
+        },
+        /// Starts a consumer session, handing out a fencing token newer than any before it.
+        AcquireEpoch {
+            msg_id: u64,
+        },
+        AcquireEpochOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            epoch: u64,
+        },
+        CommitOffsets {
+            msg_id: u64,
+            offsets: HashMap<String, u64>,
+            #[serde(default)]
+            epoch: u64, // 0 for consumers that never acquired one
+        },
+        CommitOffsetsOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        ListCommittedOffsets {
+            msg_id: u64,
+            keys: Vec<String>,
+        },
+        ListCommittedOffsetsOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            offsets: HashMap<String, u64>,
+        },
+        /// Finds the first offset of `key` ingested at or after `timestamp` (ms since the Unix
+        /// epoch), so a consumer can reset to a point in time.
+        OffsetForTime {
+            msg_id: u64,
+            key: String,
+            timestamp: u64,
+        },
+        OffsetForTimeOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            offset: u64,
+        },
+        Metrics {
+            msg_id: u64,
+        },
+        MetricsOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            keys: HashMap<String, KeyMetrics>,
+        },
+        Heartbeat {
+            msg_id: u64,
+        },
+        Replicate {
+            msg_id: u64,
+            key: String,
+            offset: u64,
+            msg: Value,
+            #[serde(default)]
+            ack: bool, // Whether the leader is waiting on a replicate_ok
+        },
+        ReplicateOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Reads entries from a replica for a poll that reached a node without one.
+        ReadEntries {
+            msg_id: u64,
+            offsets: HashMap<String, u64>,
+        },
+        ReadEntriesOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            msgs: HashMap<String, Vec<(u64, Value)>>,
+        },
+        ReplicateCommit {
+            msg_id: u64,
+            offsets: HashMap<String, u64>,
+            #[serde(default)]
+            epoch: u64,
+        },
+        Read {
+            msg_id: u64,
+            key: String,
+        },
+        ReadOk {
+            in_reply_to: u64,
+            value: u64,
+        },
+        Cas {
+            msg_id: u64,
+            key: String,
+            from: u64,
+            to: u64,
+            create_if_not_exists: bool,
+        },
+        CasOk {
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            retry_after: Option<u64>, // Milliseconds a throttled client should back off for
+        },
+        /// Appends to several keys atomically: every message becomes visible or none do.
+        TxnSend {
+            msg_id: u64,
+            msgs: HashMap<String, Value>,
+        },
+        TxnSendOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            offsets: HashMap<String, u64>,
+        },
+        Prepare {
+            msg_id: u64,
+            txn_id: String,
+            msgs: HashMap<String, Value>,
+        },
+        Vote {
+            msg_id: u64,
+            txn_id: String,
+            commit: bool,
+        },
+        Decide {
+            msg_id: u64,
+            txn_id: String,
+            commit: bool,
+        },
+        DecideOk {
+            msg_id: u64,
+            txn_id: String,
+            offsets: HashMap<String, u64>,
+        },
+        /// Asks a transaction's coordinator for its decision, which a participant that voted
+        /// to commit can't make for itself.
+        Inquire {
+            msg_id: u64,
+            txn_id: String,
+        },
+        /// Asks a peer for its logs from the given offsets onwards; no keys means all of them.
+        SnapshotRequest {
+            msg_id: u64,
+            offsets: HashMap<String, u64>,
+        },
+        SnapshotChunk {
+            msg_id: u64,
+            key: String,
+            start: u64, // First offset the sender holds at or after the requested one
+            offset: u64,
+            entries: Vec<Value>,
+            committed: u64,
+            chunk: usize,  // Which of the transfer's chunks this is, from 0
+            chunks: usize, // How many chunks the transfer of the key has
+        },
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: AtomicBool::new(false),
+                cur_id: AtomicU64::new(1),
+                cluster: RwLock::new(Cluster {
+                    id: String::default(),
+                    nodes: HashSet::new(),
+                    election: Election::new(String::default(), Vec::new(), config.leader_timeout),
+                }),
+                config,
+                store: OnceLock::new(),
+                entry_store: OnceLock::new(),
+                logs: RwLock::new(HashMap::new()),
+                forwards: Mutex::new(HashMap::new()),
+                sessions: Mutex::new(HashMap::new()),
+                held_polls: Mutex::new(Vec::new()),
+                cas_sends: Mutex::new(HashMap::new()),
+                epoch_requests: Mutex::new(HashMap::new()),
+                txns: Mutex::new(HashMap::new()),
+                prepared: Mutex::new(HashMap::new()),
+                snapshotting: Mutex::new(HashSet::new()),
+                snapshot_chunks: Mutex::new(HashMap::new()),
+                quotas: Mutex::new(HashMap::new()),
+                next_partition: AtomicUsize::new(0),
+                fetch_sessions: Mutex::new(HashMap::new()),
+                membership: Mutex::new(Vec::new()),
+                migrations: Mutex::new(HashMap::new()),
+                pending_acks: Mutex::new(HashMap::new()),
+                replica_acks: Mutex::new(HashMap::new()),
+                gathers: Mutex::new(Vec::new()),
+                last_fsync: Mutex::new(Instant::now()),
+            }
+        }
+
+        pub fn heartbeat_interval(&self) -> Duration {
+            self.config.heartbeat_interval
+        }
+
+        /// Heartbeats every peer so they can tell we're still alive to lead our keys.
+        pub fn tick(&self) -> Vec<Message> {
+            if !self.initialized.load(Ordering::Acquire) {
+                return Vec::new();
+            }
+            let mut messages = Vec::new();
+            for peer in self.peers() {
+                let msg_id = self.next_msg_id();
+                messages.push(Message {
+                    src: self.id(),
+                    dest: peer,
+                    body: Body::Heartbeat { msg_id },
+                });
+            }
+            self.release_polls(&mut messages);
+            self.expire_txns(&mut messages);
+            self.rebalance(&mut messages);
+            self.expire_migrations(&mut messages);
+            self.expire_acks(&mut messages);
+            self.expire_forwards(&mut messages);
+            self.expire_gathers(&mut messages);
+            self.skip_stalled_gaps();
+            self.sync_stores();
+            let timeout = self.config.fetch_session_timeout;
+            self.fetch_sessions
+                .lock()
+                .unwrap()
+                .retain(|_, session| session.last_used.elapsed() < timeout);
+            messages
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            let cluster = self.cluster.read().unwrap();
+            json!({
+                "id": cluster.id,
+                "nodes": cluster.nodes,
+                "membership": *self.membership.lock().unwrap(),
+                "logs": self.logs.read().unwrap().len(),
+                "forwards": self.forwards.lock().unwrap().len(),
+                "held_polls": self.held_polls.lock().unwrap().len(),
+                "cas_sends": self.cas_sends.lock().unwrap().len(),
+                "txns": self.txns.lock().unwrap().len(),
+                "prepared": self.prepared.lock().unwrap().len(),
+                "snapshotting": *self.snapshotting.lock().unwrap(),
+                "migrations": self.migrations.lock().unwrap().keys().collect::<Vec<_>>(),
+                "pending_acks": self.pending_acks.lock().unwrap().len(),
+                "gathers": self.gathers.lock().unwrap().len(),
+            })
+        }
+
+        pub fn handle_message(&self, message: Message) -> Vec<Message> {
+            if !self.initialized.load(Ordering::Acquire) {
+                match message.body {
+                    Body::Init { .. } => {}
+                    // Peers can finish their init and start heartbeating before we get ours
+                    Body::Heartbeat { .. } => return Vec::new(),
+                    _ => {
+                        log::warn!("Received {:?} before init", message.body);
+                        return self.refuse(
+                            message,
+                            TEMPORARILY_UNAVAILABLE,
+                            "node is not initialized yet",
+                        );
+                    }
+                }
+            }
+            {
+                let cluster = self.cluster.read().unwrap();
+                if cluster.nodes.contains(&message.src) {
+                    cluster.election.heard_from(&message.src);
+                }
+            }
+            let mut messages = Vec::new();
+            let resp_body = self.handle_body(&message.src, &message.body, &mut messages);
+            if let Some(body) = resp_body {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+            }
+            self.release_polls(&mut messages);
+
+            messages
+        }
+
+        fn next_msg_id(&self) -> u64 {
+            self.cur_id.fetch_add(1, Ordering::Relaxed)
+        }
+
+        fn id(&self) -> String {
+            self.cluster.read().unwrap().id.clone()
+        }
+
+        fn is_peer(&self, node: &str) -> bool {
+            self.cluster.read().unwrap().nodes.contains(node)
+        }
+
+        fn peers(&self) -> Vec<String> {
+            self.cluster.read().unwrap().election.peers()
+        }
+
+        fn leader_for(&self, key: &str) -> String {
+            self.cluster
+                .read()
+                .unwrap()
+                .election
+                .leader_for(key)
+                .to_string()
+        }
+
+        fn is_leader(&self, key: &str) -> bool {
+            self.cluster.read().unwrap().election.is_leader(key)
+        }
+
+        /// The log for `key`, created empty if we haven't seen the key before.
+        fn log(&self, key: &str) -> Arc<Mutex<KeyLog>> {
+            if let Some(log) = self.existing_log(key) {
+                return log;
+            }
+            let mut logs = self.logs.write().unwrap();
+            Arc::clone(logs.entry(key.to_string()).or_default())
+        }
+
+        fn existing_log(&self, key: &str) -> Option<Arc<Mutex<KeyLog>>> {
+            self.logs.read().unwrap().get(key).cloned()
+        }
+
+        fn handle_body(&self, src: &str, body: &Body, outbox: &mut Vec<Message>) -> Option<Body> {
+            if let Body::Send { msg_id, .. } | Body::Poll { msg_id, .. } = body {
+                if let Err(wait) = self.throttle(src) {
+                    let retry_after = wait.as_millis() as u64 + 1;
+                    return Some(Body::Error {
+                        in_reply_to: *msg_id,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: format!("{} is over its quota, retry in {}ms", src, retry_after),
+                        retry_after: Some(retry_after),
+                    });
+                }
+            }
+            let partitioned;
+            let body = if self.config.partitions > 1 && !self.is_peer(src) {
+                partitioned = self.to_partitions(body);
+                &partitioned
+            } else {
+                body
+            };
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    if self.initialized.load(Ordering::Acquire) {
+                        log::warn!("Received init {} after already initializing", msg_id);
+                        return Some(Body::Error {
+                            in_reply_to: *msg_id,
+                            code: PRECONDITION_FAILED,
+                            text: "node is already initialized".to_string(),
+                            retry_after: None,
+                        });
+                    }
+                    *self.cluster.write().unwrap() = Cluster {
+                        id: node_id.clone(),
+                        nodes: node_ids.iter().cloned().collect(),
+                        election: Election::new(
+                            node_id.clone(),
+                            node_ids.clone(),
+                            self.config.leader_timeout,
+                        ),
+                    };
+                    *self.membership.lock().unwrap() = node_ids.clone();
+                    logging::set_node(node_id);
+                    // A node coming back with state on disk missed everything while it was
+                    // down, so it catches up from a peer before relying on replication.
+                    if self.recover() {
+                        self.request_snapshot(None, HashMap::new(), outbox);
+                    }
+                    self.initialized.store(true, Ordering::Release);
+                    Body::InitOk {
+                        msg_id: self.next_msg_id(),
+                        in_reply_to: *msg_id,
+                    }
+                }
+                Body::Send { msg_id, key, .. } if self.is_full(key) => Body::Error {
+                    in_reply_to: *msg_id,
+                    code: TEMPORARILY_UNAVAILABLE,
+                    text: self.full_reason(key),
+                    retry_after: None,
+                },
+                Body::Send { msg_id, key, msg } if self.config.send_mode == SendMode::Cas => {
+                    let send = CasSend {
+                        client: src.to_string(),
+                        msg_id: *msg_id,
+                        key: key.clone(),
+                        msg: msg.clone(),
+                        from: 0,
+                        requests: 0,
+                    };
+                    self.read_tail(send, outbox);
+                    return None;
+                }
+                Body::Send { msg_id, key, msg } => {
+                    // A send forwarded by a peer is always applied, even if our views of who
+                    // leads the key disagree, so it can't bounce between nodes forever.
+                    let from_peer = self.is_peer(src);
+                    if !from_peer && !self.is_leader(key) {
+                        let leader = self.leader_for(key);
+                        log::debug!("Forwarding send for {} to leader {}", key, leader);
+                        let forward_id = self.next_msg_id();
+                        self.forwards.lock().unwrap().insert(
+                            forward_id,
+                            Forward {
+                                client: src.to_string(),
+                                msg_id: *msg_id,
+                                key: key.clone(),
+                                deadline: Instant::now() + self.config.leader_timeout,
+                            },
+                        );
+                        outbox.push(Message {
+                            src: self.id(),
+                            dest: leader,
+                            body: Body::Send {
+                                msg_id: forward_id,
+                                key: key.clone(),
+                                msg: msg.clone(),
+                            },
+                        });
+                        return None;
+                    }
+                    if let Some(migration) = self.migrations.lock().unwrap().get_mut(key) {
+                        log::debug!("Holding send for {} until its transfer finishes", key);
+                        migration.held.push((src.to_string(), *msg_id, msg.clone()));
+                        return None;
+                    }
+                    return self.send_locally(src, *msg_id, key, msg, outbox);
+                }
+                Body::SendOk {
+                    in_reply_to,
+                    offset,
+                    ..
+                } => {
+                    let forward = self.forwards.lock().unwrap().remove(in_reply_to);
+                    let Some(forward) = forward else {
+                        log::warn!("Received send_ok for unknown forward {}", in_reply_to);
+                        return None;
+                    };
+                    self.record_session(&forward.client, &forward.key, *offset);
+                    let msg_id = self.next_msg_id();
+                    outbox.push(Message {
+                        src: self.id(),
+                        dest: forward.client,
+                        body: Body::SendOk {
+                            msg_id,
+                            in_reply_to: forward.msg_id,
+                            offset: self.client_offset(&forward.key, *offset).1,
+                        },
+                    });
+                    return None;
+                }
+                Body::ReadOk { in_reply_to, value } => {
+                    let send = self.cas_sends.lock().unwrap().remove(in_reply_to);
+                    if let Some(send) = send {
+                        self.cas_tail(send, *value, outbox);
+                    }
+                    let request = self.epoch_requests.lock().unwrap().remove(in_reply_to);
+                    if let Some(request) = request {
+                        self.cas_epoch(request, *value, outbox);
+                    }
+                    return None;
+                }
+                Body::CasOk { in_reply_to } => {
+                    let send = self.cas_sends.lock().unwrap().remove(in_reply_to);
+                    if let Some(send) = send {
+                        self.finish_cas_send(send, outbox);
+                    }
+                    let request = self.epoch_requests.lock().unwrap().remove(in_reply_to);
+                    if let Some(request) = request {
+                        let msg_id = self.next_msg_id();
+                        outbox.push(Message {
+                            src: self.id(),
+                            dest: request.client,
+                            body: Body::AcquireEpochOk {
+                                msg_id,
+                                in_reply_to: request.msg_id,
+                                epoch: request.from + 1,
+                            },
+                        });
+                    }
+                    return None;
+                }
+                Body::Error {
+                    in_reply_to,
+                    code,
+                    text,
+                    retry_after,
+                } => {
+                    let forward = self.forwards.lock().unwrap().remove(in_reply_to);
+                    if let Some(forward) = forward {
+                        outbox.push(Message {
+                            src: self.id(),
+                            dest: forward.client,
+                            body: Body::Error {
+                                in_reply_to: forward.msg_id,
+                                code: *code,
+                                text: text.clone(),
+                                retry_after: *retry_after,
+                            },
+                        });
+                        return None;
+                    }
+                    if self.gather_failed(*in_reply_to, *code, text, outbox) {
+                        return None;
+                    }
+                    let request = self.epoch_requests.lock().unwrap().remove(in_reply_to);
+                    if let Some(request) = request {
+                        match *code {
+                            KEY_DOES_NOT_EXIST => self.cas_epoch(request, 0, outbox),
+                            // Another consumer got the epoch we wanted; try for the next one
+                            PRECONDITION_FAILED => self.read_epoch(request, outbox),
+                            _ => {
+                                log::warn!("lin-kv error {} ({}), retrying epoch", code, text);
+                                self.read_epoch(request, outbox);
+                            }
+                        }
+                        return None;
+                    }
+                    let send = self.cas_sends.lock().unwrap().remove(in_reply_to);
+                    let Some(send) = send else {
+                        log::warn!("Received error {} ({}) for {}", code, text, in_reply_to);
+                        return None;
+                    };
+                    match *code {
+                        // No send has reserved an offset for this key yet
+                        KEY_DOES_NOT_EXIST => self.cas_tail(send, 0, outbox),
+                        // Another node moved the tail first; read it again and retry
+                        PRECONDITION_FAILED => self.read_tail(send, outbox),
+                        _ => {
+                            log::warn!("lin-kv error {} ({}), retrying send", code, text);
+                            self.read_tail(send, outbox);
+                        }
+                    }
+                    return None;
+                }
+                Body::TxnSend { msg_id, .. } if self.config.send_mode == SendMode::Cas => {
+                    Body::Error {
+                        in_reply_to: *msg_id,
+                        code: NOT_SUPPORTED,
+                        text: "transactions require the leader send mode".to_string(),
+                        retry_after: None,
+                    }
+                }
+                Body::TxnSend { msg_id, msgs } => {
+                    self.begin_txn(src, *msg_id, msgs, outbox);
+                    return None;
+                }
+                Body::Prepare { txn_id, msgs, .. } => {
+                    let commit = self.prepare(src, txn_id, msgs);
+                    let msg_id = self.next_msg_id();
+                    let vote = Body::Vote {
+                        msg_id,
+                        txn_id: txn_id.clone(),
+                        commit,
+                    };
+                    self.deliver(src, vote, outbox);
+                    return None;
+                }
+                Body::Vote { txn_id, commit, .. } => {
+                    let ready = {
+                        let mut txns = self.txns.lock().unwrap();
+                        let txn = txns.get_mut(txn_id)?;
+                        if txn.deciding || !txn.waiting.remove(src) {
+                            return None;
+                        }
+                        txn.commit &= *commit;
+                        txn.waiting.is_empty()
+                    };
+                    if ready {
+                        self.decide(txn_id, outbox);
+                    }
+                    return None;
+                }
+                Body::Decide { txn_id, commit, .. } => {
+                    let offsets = self.apply_decision(txn_id, *commit, outbox);
+                    if *commit {
+                        let msg_id = self.next_msg_id();
+                        let ack = Body::DecideOk {
+                            msg_id,
+                            txn_id: txn_id.clone(),
+                            offsets,
+                        };
+                        self.deliver(src, ack, outbox);
+                    }
+                    return None;
+                }
+                Body::Inquire { txn_id, .. } => {
+                    let commit = match self.txns.lock().unwrap().get(txn_id) {
+                        // Still voting, and it'll tell everyone once it's done
+                        Some(txn) if !txn.deciding => return None,
+                        Some(txn) => txn.commit,
+                        // Aborted, since a commit is only forgotten once every share is in
+                        None => false,
+                    };
+                    self.send_decision(src, txn_id, commit, outbox);
+                    return None;
+                }
+                Body::DecideOk {
+                    txn_id, offsets, ..
+                } => {
+                    let done = {
+                        let mut txns = self.txns.lock().unwrap();
+                        let txn = txns.get_mut(txn_id)?;
+                        if !txn.deciding || !txn.waiting.remove(src) {
+                            return None;
+                        }
+                        txn.offsets.extend(offsets.clone());
+                        if txn.waiting.is_empty() {
+                            txns.remove(txn_id)
+                        } else {
+                            None
+                        }
+                    };
+                    if let Some(txn) = done {
+                        let msg_id = self.next_msg_id();
+                        outbox.push(Message {
+                            src: self.id(),
+                            dest: txn.client,
+                            body: Body::TxnSendOk {
+                                msg_id,
+                                in_reply_to: txn.msg_id,
+                                offsets: txn
+                                    .offsets
+                                    .iter()
+                                    .map(|(key, offset)| self.client_offset(key, *offset))
+                                    .collect(),
+                            },
+                        });
+                    }
+                    return None;
+                }
+                Body::Replicate {
+                    msg_id,
+                    key,
+                    offset,
+                    msg,
+                    ack,
+                } => {
+                    if *ack {
+                        outbox.push(Message {
+                            src: self.id(),
+                            dest: src.to_string(),
+                            body: Body::ReplicateOk {
+                                msg_id: self.next_msg_id(),
+                                in_reply_to: *msg_id,
+                            },
+                        });
+                    }
+                    let log = self.log(key);
+                    let (behind, from) = {
+                        let mut log = log.lock().unwrap();
+                        self.apply_replica(key, &mut log, *offset, msg);
+                        (log.out_of_order.len(), log.next_offset())
+                    };
+                    let snapshotting = self.snapshotting.lock().unwrap().contains(key);
+                    if behind > self.config.snapshot_lag_threshold && !snapshotting {
+                        log::info!(
+                            "{} is {} entries behind, requesting a snapshot",
+                            key,
+                            behind
+                        );
+                        let offsets = HashMap::from([(key.clone(), from)]);
+                        self.request_snapshot(Some(src.to_string()), offsets, outbox);
+                    }
+                    return None;
+                }
+                Body::ReplicateOk { in_reply_to, .. } => {
+                    self.acked(*in_reply_to, outbox);
+                    return None;
+                }
+                Body::ReadEntries { msg_id, offsets } => match self.read_entries(offsets) {
+                    Ok(msgs) => Body::ReadEntriesOk {
+                        msg_id: self.next_msg_id(),
+                        in_reply_to: *msg_id,
+                        msgs,
+                    },
+                    Err(corrupt) => corrupt.error(*msg_id),
+                },
+                Body::ReadEntriesOk {
+                    in_reply_to, msgs, ..
+                } => {
+                    self.gathered(*in_reply_to, msgs, outbox);
+                    return None;
+                }
+                Body::SnapshotRequest { offsets, .. } => {
+                    self.send_snapshot(src, offsets, outbox);
+                    return None;
+                }
+                Body::SnapshotChunk {
+                    key,
+                    start,
+                    offset,
+                    entries,
+                    committed,
+                    chunk,
+                    chunks,
+                    ..
+                } => {
+                    {
+                        let log = self.log(key);
+                        let mut log = log.lock().unwrap();
+                        log.committed = log.committed.max(*committed);
+                        if log.next_offset() < *start && log.segments.is_empty() {
+                            log.skip_to(*start);
+                        }
+                        for (offset, msg) in (*offset..).zip(entries.iter()) {
+                            self.apply_replica(key, &mut log, offset, msg);
+                        }
+                    }
+                    // Chunks can arrive in any order, so the transfer is only done once all have
+                    let done = {
+                        let mut received = self.snapshot_chunks.lock().unwrap();
+                        let seen = received.entry(key.clone()).or_default();
+                        seen.insert(*chunk);
+                        let done = seen.len() >= *chunks;
+                        if done {
+                            received.remove(key);
+                        }
+                        done
+                    };
+                    if done {
+                        log::info!("Finished snapshot transfer of {} from {}", key, src);
+                        self.snapshotting.lock().unwrap().remove(key);
+                        self.finish_migration(key, outbox);
+                    } else if self.is_leader(key) {
+                        // A transfer we didn't ask for means the key just moved to us
+                        self.start_migration(key);
+                    }
+                    return None;
+                }
+                Body::Poll {
+                    msg_id,
+                    offsets,
+                    wait_ms,
+                    session_id,
+                } => {
+                    let mut offsets = self.resume_offsets(offsets);
+                    let session = match session_id {
+                        Some(session_id) => match self.join_session(*session_id, &mut offsets) {
+                            Some(session) => Some(session),
+                            None => {
+                                return Some(Body::Error {
+                                    in_reply_to: *msg_id,
+                                    code: PRECONDITION_FAILED,
+                                    text: format!("unknown fetch session {}", session_id),
+                                    retry_after: None,
+                                })
+                            }
+                        },
+                        None => None,
+                    };
+                    if !self.is_peer(src)
+                        && self.gather_poll(src, *msg_id, &offsets, session, outbox)
+                    {
+                        return None;
+                    }
+                    let offsets = &offsets;
+                    let now = Instant::now();
+                    let mut deadline = None;
+                    if !self.caught_up_with(src, offsets) {
+                        log::debug!("Holding poll {} from {} until we catch up", msg_id, src);
+                        deadline = Some(now + self.config.session_wait_timeout);
+                    }
+                    if let Some(wait) = wait_ms.filter(|_| !self.has_data(offsets)) {
+                        log::debug!("Holding poll {} from {} for new entries", msg_id, src);
+                        deadline = deadline.max(Some(now + Duration::from_millis(wait)));
+                    }
+                    let Some(deadline) = deadline else {
+                        return Some(self.poll(*msg_id, offsets, session));
+                    };
+                    self.held_polls.lock().unwrap().push(HeldPoll {
+                        client: src.to_string(),
+                        msg_id: *msg_id,
+                        offsets: offsets.clone(),
+                        session,
+                        wait_for_data: wait_ms.is_some(),
+                        deadline,
+                    });
+                    return None;
+                }
+                Body::AcquireEpoch { msg_id } => {
+                    let request = EpochRequest {
+                        client: src.to_string(),
+                        msg_id: *msg_id,
+                        from: 0,
+                        requests: 0,
+                    };
+                    self.read_epoch(request, outbox);
+                    return None;
+                }
+                Body::CommitOffsets {
+                    msg_id,
+                    offsets,
+                    epoch,
+                } => {
+                    // A consumer that's been replaced still holds its old epoch, so refusing
+                    // stale epochs keeps it from rolling back its replacement's progress.
+                    for key in offsets.keys() {
+                        let fence = self.log(key).lock().unwrap().fence;
+                        if *epoch < fence {
+                            return Some(Body::Error {
+                                in_reply_to: *msg_id,
+                                code: PRECONDITION_FAILED,
+                                text: format!("epoch {} is fenced off {} by {}", epoch, key, fence),
+                                retry_after: None,
+                            });
+                        }
+                    }
+                    for (key, val) in offsets.iter() {
+                        let log = self.log(key);
+                        let mut log = log.lock().unwrap();
+                        if *epoch >= log.fence {
+                            log.fence = *epoch;
+                            log.committed = *val + 1;
+                        }
+                    }
+                    for peer in self.peers() {
+                        let msg_id = self.next_msg_id();
+                        outbox.push(Message {
+                            src: self.id(),
+                            dest: peer,
+                            body: Body::ReplicateCommit {
+                                msg_id,
+                                offsets: offsets.clone(),
+                                epoch: *epoch,
+                            },
+                        });
+                    }
+                    Body::CommitOffsetsOk {
+                        msg_id: self.next_msg_id(),
+                        in_reply_to: *msg_id,
+                    }
+                }
+                Body::ListCommittedOffsets { msg_id, keys } => {
+                    let mut offsets = HashMap::new();
+                    for key in keys {
+                        if let Some(val) = self.existing_log(key) {
+                            offsets.insert(key.clone(), val.lock().unwrap().committed);
+                        }
+                    }
+                    Body::ListCommittedOffsetsOk {
+                        msg_id: self.next_msg_id(),
+                        in_reply_to: *msg_id,
+                        offsets: self.merge_committed(offsets),
+                    }
+                }
+                Body::OffsetForTime {
+                    msg_id,
+                    key,
+                    timestamp,
+                } => match self.offset_for_time(src, key, *timestamp) {
+                    Some(offset) => Body::OffsetForTimeOk {
+                        msg_id: self.next_msg_id(),
+                        in_reply_to: *msg_id,
+                        offset,
+                    },
+                    None => Body::Error {
+                        in_reply_to: *msg_id,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("no entries have been sent to {}", key),
+                        retry_after: None,
+                    },
+                },
+                Body::ReplicateCommit { offsets, epoch, .. } => {
+                    for (key, val) in offsets.iter() {
+                        let log = self.log(key);
+                        let mut log = log.lock().unwrap();
+                        // A newer epoch's commit wins even if it moves backwards
+                        if *epoch > log.fence {
+                            log.fence = *epoch;
+                            log.committed = *val + 1;
+                        } else if *epoch == log.fence {
+                            log.committed = log.committed.max(*val + 1);
+                        }
+                    }
+                    return None;
+                }
+                Body::Metrics { msg_id } => {
+                    let logs = self.logs.read().unwrap().clone();
+                    Body::MetricsOk {
+                        msg_id: self.next_msg_id(),
+                        in_reply_to: *msg_id,
+                        keys: logs
+                            .into_iter()
+                            .map(|(key, log)| {
+                                let log = log.lock().unwrap();
+                                let length = log.next_offset();
+                                let metrics = KeyMetrics {
+                                    length,
+                                    committed: log.committed,
+                                    lag: length.saturating_sub(log.committed),
+                                };
+                                (key, metrics)
+                            })
+                            .collect(),
+                    }
+                }
+                Body::Heartbeat { .. } => return None,
+                body => {
+                    let Some(msg_id) = request_id(body) else {
+                        log::warn!("Dropping unexpected reply {:?} from {}", body, src);
+                        return None;
+                    };
+                    Body::Error {
+                        in_reply_to: msg_id,
+                        code: NOT_SUPPORTED,
+                        text: "unsupported request".to_string(),
+                        retry_after: None,
+                    }
+                }
+            })
+        }
+
+        /// Answers a request we can't handle with an error, or drops it if it's a reply.
+        fn refuse(&self, message: Message, code: u64, text: &str) -> Vec<Message> {
+            let Some(msg_id) = request_id(&message.body) else {
+                return Vec::new();
+            };
+            vec![Message {
+                src: message.dest,
+                dest: message.src,
+                body: Body::Error {
+                    in_reply_to: msg_id,
+                    code,
+                    text: text.to_string(),
+                    retry_after: None,
+                },
+            }]
+        }
+
+        /// Tells clients whose forwarded sends the leader never answered that the outcome is
+        /// unknown: the leader may have appended the send before dying, and its successor
+        /// could still have it.
+        fn expire_forwards(&self, outbox: &mut Vec<Message>) {
+            let now = Instant::now();
+            let mut expired = Vec::new();
+            self.forwards.lock().unwrap().retain(|_, forward| {
+                if forward.deadline > now {
+                    return true;
+                }
+                expired.push((forward.client.clone(), forward.msg_id, forward.key.clone()));
+                false
+            });
+            for (client, msg_id, key) in expired {
+                outbox.push(Message {
+                    src: self.id(),
+                    dest: client,
+                    body: Body::Error {
+                        in_reply_to: msg_id,
+                        code: TIMEOUT,
+                        text: format!("leader of {} didn't answer", key),
+                        retry_after: None,
+                    },
+                });
+            }
+        }
+
+        /// Charges a client's send or poll to its token bucket, so one aggressive client can't
+        /// starve the rest. Peers forwarding sends on a client's behalf aren't charged again.
+        fn throttle(&self, client: &str) -> Result<(), Duration> {
+            if self.config.client_rate <= 0.0 || self.is_peer(client) {
+                return Ok(());
+            }
+            self.quotas
+                .lock()
+                .unwrap()
+                .entry(client.to_string())
+                .or_insert_with(|| {
+                    TokenBucket::new(self.config.client_rate, self.config.client_burst)
+                })
+                .take()
+        }
+
+        /// Appends to a key we lead and replicates the entry to the key's other replicas so one
+        /// of them can take over the key if we die. Returns the offset along with the msg_ids
+        /// of the replicate messages, which are acked if `acks` is set. Fails with an error code
+        /// and the reason when the key is locked by a prepared transaction, which is worth
+        /// retrying, or full, which isn't; both are checked under the key's lock so nothing can
+        /// change between the check and the append.
+        fn append(
+            &self,
+            key: &str,
+            msg: &Value,
+            outbox: &mut Vec<Message>,
+        ) -> Result<(u64, Vec<u64>), (u64, String)> {
+            let log = self.log(key);
+            let offset = {
+                let mut log = log.lock().unwrap();
+                if let Some(txn_id) = &log.locked_by {
+                    return Err((
+                        TEMPORARILY_UNAVAILABLE,
+                        format!("{} is locked by pending transaction {}", key, txn_id),
+                    ));
+                }
+                if self.over_budget(&mut log) {
+                    return Err((TEMPORARILY_UNAVAILABLE, self.full_reason(key)));
+                }
+                self.push(key, &mut log, msg.clone())
+            };
+            let sent = self.replicate(key, offset, msg, self.config.acks > 0, outbox);
+            Ok((offset, sent))
+        }
+
+        /// Appends to a locked log, persisting the new tail before the lock is released so
+        /// concurrent appends can't persist their tails out of order.
+        fn push(&self, key: &str, log: &mut KeyLog, msg: Value) -> u64 {
+            let offset = log.push(msg);
+            self.persist_entry(key, log, offset);
+            self.persist_offset(key, log.next_offset());
+            offset
+        }
+
+        /// Sends `body` to `dest`, handling it immediately when that's us so a coordinator can
+        /// also be one of its own participants.
+        fn deliver(&self, dest: &str, body: Body, outbox: &mut Vec<Message>) {
+            let id = self.id();
+            if dest == id {
+                self.handle_body(&id, &body, outbox);
+            } else {
+                outbox.push(Message {
+                    src: id,
+                    dest: dest.to_string(),
+                    body,
+                });
+            }
+        }
+
+        /// Splits a transaction's messages by the leader of each key and asks every leader to
+        /// prepare its share.
+        fn begin_txn(
+            &self,
+            client: &str,
+            msg_id: u64,
+            msgs: &HashMap<String, Value>,
+            outbox: &mut Vec<Message>,
+        ) {
+            let txn_id = format!("{}-{}", self.id(), self.next_msg_id());
+            let mut shares: HashMap<String, HashMap<String, Value>> = HashMap::new();
+            for (key, msg) in msgs {
+                shares
+                    .entry(self.leader_for(key))
+                    .or_default()
+                    .insert(key.clone(), msg.clone());
+            }
+            self.txns.lock().unwrap().insert(
+                txn_id.clone(),
+                Txn {
+                    client: client.to_string(),
+                    msg_id,
+                    participants: shares.keys().cloned().collect(),
+                    waiting: shares.keys().cloned().collect(),
+                    commit: true,
+                    deciding: false,
+                    offsets: HashMap::new(),
+                    deadline: Instant::now() + self.config.txn_timeout,
+                },
+            );
+            if shares.is_empty() {
+                self.decide(&txn_id, outbox);
+            }
+            for (participant, msgs) in shares {
+                let msg_id = self.next_msg_id();
+                let prepare = Body::Prepare {
+                    msg_id,
+                    txn_id: txn_id.clone(),
+                    msgs,
+                };
+                self.deliver(&participant, prepare, outbox);
+            }
+        }
+
+        /// Votes on a transaction's share for keys we lead, locking the keys on a yes vote so
+        /// no other append can slip in before the decision. Holding `prepared` throughout
+        /// keeps two transactions from each locking half of the other's keys.
+        fn prepare(&self, coordinator: &str, txn_id: &str, msgs: &HashMap<String, Value>) -> bool {
+            let mut prepared = self.prepared.lock().unwrap();
+            let mut locked = Vec::new();
+            for key in msgs.keys() {
+                let log = self.log(key);
+                let mut log = log.lock().unwrap();
+                let reason = if log.locked_by.is_some() {
+                    "locked"
+                } else if self.over_budget(&mut log) {
+                    "full"
+                } else {
+                    log.locked_by = Some(txn_id.to_string());
+                    locked.push(key);
+                    continue;
+                };
+                drop(log);
+                log::debug!("Voting to abort {}, {} is {}", txn_id, key, reason);
+                for key in locked {
+                    self.log(key).lock().unwrap().locked_by = None;
+                }
+                return false;
+            }
+            prepared.insert(
+                txn_id.to_string(),
+                PreparedTxn {
+                    coordinator: coordinator.to_string(),
+                    msgs: msgs.clone(),
+                    offsets: None,
+                    deadline: Instant::now() + self.config.txn_timeout,
+                },
+            );
+            true
+        }
+
+        /// Tells every participant the outcome once all votes are in (or the vote timed out).
+        fn decide(&self, txn_id: &str, outbox: &mut Vec<Message>) {
+            let (commit, participants) = {
+                let mut txns = self.txns.lock().unwrap();
+                // The last vote and the vote timeout can both get here; only the first decides
+                let Some(txn) = txns.get_mut(txn_id).filter(|txn| !txn.deciding) else {
+                    return;
+                };
+                txn.deciding = true;
+                txn.waiting = txn.participants.iter().cloned().collect();
+                txn.deadline = Instant::now() + self.config.txn_timeout;
+                (txn.commit, txn.participants.clone())
+            };
+            log::debug!(
+                "Deciding {} for {}",
+                if commit { "commit" } else { "abort" },
+                txn_id
+            );
+            if !commit {
+                if let Some(txn) = self.txns.lock().unwrap().remove(txn_id) {
+                    outbox.push(Message {
+                        src: self.id(),
+                        dest: txn.client,
+                        body: Body::Error {
+                            in_reply_to: txn.msg_id,
+                            code: TXN_CONFLICT,
+                            text: format!("transaction {} aborted", txn_id),
+                            retry_after: None,
+                        },
+                    });
+                }
+            }
+            if participants.is_empty() {
+                let txn = self.txns.lock().unwrap().remove(txn_id);
+                if let Some(txn) = txn {
+                    outbox.push(Message {
+                        src: self.id(),
+                        dest: txn.client,
+                        body: Body::TxnSendOk {
+                            msg_id: self.next_msg_id(),
+                            in_reply_to: txn.msg_id,
+                            offsets: HashMap::new(),
+                        },
+                    });
+                }
+                return;
+            }
+            for participant in participants {
+                self.send_decision(&participant, txn_id, commit, outbox);
+            }
+        }
+
+        fn send_decision(
+            &self,
+            participant: &str,
+            txn_id: &str,
+            commit: bool,
+            outbox: &mut Vec<Message>,
+        ) {
+            let msg_id = self.next_msg_id();
+            let decide = Body::Decide {
+                msg_id,
+                txn_id: txn_id.to_string(),
+                commit,
+            };
+            self.deliver(participant, decide, outbox);
+        }
+
+        /// Appends a prepared transaction's messages on commit, releasing each key's lock as
+        /// its entry goes in. A share that's already committed just reports its offsets again.
+        /// `prepared` stays locked throughout so a decide delivered twice is applied once.
+        fn apply_decision(
+            &self,
+            txn_id: &str,
+            commit: bool,
+            outbox: &mut Vec<Message>,
+        ) -> HashMap<String, u64> {
+            let mut offsets = HashMap::new();
+            let mut prepared = self.prepared.lock().unwrap();
+            let share = match prepared.get(txn_id) {
+                None => return offsets,
+                Some(PreparedTxn {
+                    offsets: Some(offsets),
+                    ..
+                }) => return offsets.clone(),
+                Some(_) => prepared.remove(txn_id).unwrap(),
+            };
+            for (key, msg) in share.msgs {
+                let log = self.log(&key);
+                let offset = {
+                    let mut log = log.lock().unwrap();
+                    if log.locked_by.as_deref() == Some(txn_id) {
+                        log.locked_by = None;
+                    }
+                    if !commit {
+                        continue;
+                    }
+                    self.push(&key, &mut log, msg.clone())
+                };
+                self.replicate(&key, offset, &msg, false, outbox);
+                offsets.insert(key, offset);
+            }
+            if commit {
+                prepared.insert(
+                    txn_id.to_string(),
+                    PreparedTxn {
+                        coordinator: share.coordinator,
+                        msgs: HashMap::new(),
+                        offsets: Some(offsets.clone()),
+                        deadline: Instant::now()
+                            + self.config.txn_timeout * COMMITTED_SHARE_TIMEOUTS,
+                    },
+                );
+            }
+            offsets
+        }
+
+        /// Aborts transactions whose votes didn't arrive in time, and resends commit decisions
+        /// participants haven't acked. A participant that voted to commit can't release its
+        /// keys without the decision, so it asks the coordinator for one instead.
+        fn expire_txns(&self, outbox: &mut Vec<Message>) {
+            let now = Instant::now();
+            let stalled = self
+                .txns
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .filter(|(_, txn)| !txn.deciding && txn.deadline <= now)
+                .map(|(txn_id, txn)| {
+                    txn.commit = false;
+                    txn_id.clone()
+                })
+                .collect::<Vec<_>>();
+            for txn_id in stalled {
+                log::warn!("Timed out waiting for votes on {}", txn_id);
+                self.decide(&txn_id, outbox);
+            }
+            let unacked = self
+                .txns
+                .lock()
+                .unwrap()
+                .iter_mut()
+                .filter(|(_, txn)| txn.deciding && txn.deadline <= now)
+                .map(|(txn_id, txn)| {
+                    txn.deadline = now + self.config.txn_timeout;
+                    (txn_id.clone(), txn.commit, txn.waiting.clone())
+                })
+                .collect::<Vec<_>>();
+            for (txn_id, commit, waiting) in unacked {
+                for participant in waiting {
+                    log::warn!("{} hasn't acked {}, resending", participant, txn_id);
+                    self.send_decision(&participant, &txn_id, commit, outbox);
+                }
+            }
+            let mut undecided = Vec::new();
+            self.prepared.lock().unwrap().retain(|txn_id, prepared| {
+                if prepared.deadline > now {
+                    return true;
+                }
+                if prepared.offsets.is_some() {
+                    return false;
+                }
+                prepared.deadline = now + self.config.txn_timeout;
+                undecided.push((txn_id.clone(), prepared.coordinator.clone()));
+                true
+            });
+            for (txn_id, coordinator) in undecided {
+                log::warn!("{} hasn't decided {}, asking it", coordinator, txn_id);
+                let msg_id = self.next_msg_id();
+                let inquire = Body::Inquire { msg_id, txn_id };
+                self.deliver(&coordinator, inquire, outbox);
+            }
+        }
+
+        /// Sends an entry to the key's other replicas, returning the msg_ids it went out with to
+        /// those we think are alive, whose acks a send waits on.
+        fn replicate(
+            &self,
+            key: &str,
+            offset: u64,
+            msg: &Value,
+            ack: bool,
+            outbox: &mut Vec<Message>,
+        ) -> Vec<u64> {
+            let mut sent = Vec::new();
+            for peer in self.replica_peers(key) {
+                let msg_id = self.next_msg_id();
+                if self.cluster.read().unwrap().election.is_alive(&peer) {
+                    sent.push(msg_id);
+                }
+                outbox.push(Message {
+                    src: self.id(),
+                    dest: peer,
+                    body: Body::Replicate {
+                        msg_id,
+                        key: key.to_string(),
+                        offset,
+                        msg: msg.clone(),
+                        ack,
+                    },
+                });
+            }
+            sent
+        }
+
+        /// The other nodes holding `key`'s log: every peer unless a replication factor is set,
+        /// in which case it's the alive nodes ranked just below the leader.
+        fn replica_peers(&self, key: &str) -> Vec<String> {
+            let cluster = self.cluster.read().unwrap();
+            match self.config.replication_factor {
+                0 => cluster.election.peers(),
+                count => {
+                    let mut replicas = cluster.election.replicas_for(key, count);
+                    replicas.retain(|node| *node != cluster.id);
+                    replicas
+                }
+            }
+        }
+
+        /// Whether we're one of the nodes holding `key`'s log.
+        fn holds(&self, key: &str) -> bool {
+            let cluster = self.cluster.read().unwrap();
+            match self.config.replication_factor {
+                0 => true,
+                count => cluster
+                    .election
+                    .replicas_for(key, count)
+                    .contains(&cluster.id),
+            }
+        }
+
+        /// Holds back the reply to a send until `needed` of the replicate messages in `sent`
+        /// are acked, or sends it straight away if there's nothing to wait for.
+        fn await_acks(&self, client: &str, reply: Body, sent: Vec<u64>) -> Option<Body> {
+            let needed = self.config.acks.min(sent.len());
+            if needed == 0 {
+                return Some(reply);
+            }
+            let group = self.next_msg_id();
+            self.replica_acks
+                .lock()
+                .unwrap()
+                .extend(sent.into_iter().map(|msg_id| (msg_id, group)));
+            self.pending_acks.lock().unwrap().insert(
+                group,
+                PendingAck {
+                    client: client.to_string(),
+                    reply,
+                    needed,
+                    deadline: Instant::now() + self.config.ack_timeout,
+                },
+            );
+            None
+        }
+
+        fn acked(&self, replicate_id: u64, outbox: &mut Vec<Message>) {
+            let group = self.replica_acks.lock().unwrap().remove(&replicate_id);
+            let Some(group) = group else {
+                return;
+            };
+            let mut pending_acks = self.pending_acks.lock().unwrap();
+            let Some(pending) = pending_acks.get_mut(&group) else {
+                return;
+            };
+            pending.needed -= 1;
+            if pending.needed > 0 {
+                return;
+            }
+            let Some(pending) = pending_acks.remove(&group) else {
+                return;
+            };
+            drop(pending_acks);
+            self.replica_acks
+                .lock()
+                .unwrap()
+                .retain(|_, waiting| *waiting != group);
+            outbox.push(Message {
+                src: self.id(),
+                dest: pending.client,
+                body: pending.reply,
+            });
+        }
+
+        /// Gives up on sends whose replicas didn't ack in time. The entry is already in our log
+        /// and may still reach them, so the client is told the outcome is unknown rather than
+        /// that the send failed.
+        fn expire_acks(&self, outbox: &mut Vec<Message>) {
+            let now = Instant::now();
+            let mut expired = Vec::new();
+            self.pending_acks.lock().unwrap().retain(|group, pending| {
+                if pending.deadline > now {
+                    return true;
+                }
+                expired.push((*group, pending.client.clone(), pending.reply.clone()));
+                false
+            });
+            for (group, client, reply) in expired {
+                self.replica_acks
+                    .lock()
+                    .unwrap()
+                    .retain(|_, waiting| *waiting != group);
+                let Body::SendOk { in_reply_to, .. } = reply else {
+                    continue;
+                };
+                outbox.push(Message {
+                    src: self.id(),
+                    dest: client,
+                    body: Body::Error {
+                        in_reply_to,
+                        code: TIMEOUT,
+                        text: "not enough replicas acked the send in time".to_string(),
+                        retry_after: None,
+                    },
+                });
+            }
+        }
+
+        /// Starts (or restarts) reserving an offset for a send by reading the key's tail
+        /// pointer from lin-kv; the cas that follows decides which send gets the offset.
+        fn read_tail(&self, send: CasSend, outbox: &mut Vec<Message>) {
+            if self.out_of_requests(send.requests, &send.client, send.msg_id, outbox) {
+                return;
+            }
+            let msg_id = self.next_msg_id();
+            outbox.push(Message {
+                src: self.id(),
+                dest: LIN_KV.to_string(),
+                body: Body::Read {
+                    msg_id,
+                    key: tail_key(&send.key),
+                },
+            });
+            let requests = send.requests + 1;
+            self.cas_sends
+                .lock()
+                .unwrap()
+                .insert(msg_id, CasSend { requests, ..send });
+        }
+
+        fn cas_tail(&self, send: CasSend, from: u64, outbox: &mut Vec<Message>) {
+            if self.out_of_requests(send.requests, &send.client, send.msg_id, outbox) {
+                return;
+            }
+            let msg_id = self.next_msg_id();
+            outbox.push(Message {
+                src: self.id(),
+                dest: LIN_KV.to_string(),
+                body: Body::Cas {
+                    msg_id,
+                    key: tail_key(&send.key),
+                    from,
+                    to: from + 1,
+                    create_if_not_exists: true,
+                },
+            });
+            let requests = send.requests + 1;
+            self.cas_sends.lock().unwrap().insert(
+                msg_id,
+                CasSend {
+                    from,
+                    requests,
+                    ..send
+                },
+            );
+        }
+
+        /// Reads the last epoch handed out; the cas that follows decides which consumer gets
+        /// the next one.
+        fn read_epoch(&self, request: EpochRequest, outbox: &mut Vec<Message>) {
+            if self.out_of_requests(request.requests, &request.client, request.msg_id, outbox) {
+                return;
+            }
+            let msg_id = self.next_msg_id();
+            outbox.push(Message {
+                src: self.id(),
+                dest: LIN_KV.to_string(),
+                body: Body::Read {
+                    msg_id,
+                    key: EPOCH_KEY.to_string(),
+                },
+            });
+            let requests = request.requests + 1;
+            self.epoch_requests.lock().unwrap().insert(
+                msg_id,
+                EpochRequest {
+                    requests,
+                    ..request
+                },
+            );
+        }
+
+        fn cas_epoch(&self, request: EpochRequest, from: u64, outbox: &mut Vec<Message>) {
+            if self.out_of_requests(request.requests, &request.client, request.msg_id, outbox) {
+                return;
+            }
+            let msg_id = self.next_msg_id();
+            outbox.push(Message {
+                src: self.id(),
+                dest: LIN_KV.to_string(),
+                body: Body::Cas {
+                    msg_id,
+                    key: EPOCH_KEY.to_string(),
+                    from,
+                    to: from + 1,
+                    create_if_not_exists: true,
+                },
+            });
+            let requests = request.requests + 1;
+            self.epoch_requests.lock().unwrap().insert(
+                msg_id,
+                EpochRequest {
+                    from,
+                    requests,
+                    ..request
+                },
+            );
+        }
+
+        /// Whether a client's request has made `MAX_LIN_KV_REQUESTS` to lin-kv already, in
+        /// which case it's told to retry. Nothing has been appended for it: an offset its last
+        /// cas may have reserved is left empty, and skipped by `skip_stalled_gaps`.
+        fn out_of_requests(
+            &self,
+            requests: u32,
+            client: &str,
+            msg_id: u64,
+            outbox: &mut Vec<Message>,
+        ) -> bool {
+            if requests < MAX_LIN_KV_REQUESTS {
+                return false;
+            }
+            outbox.push(Message {
+                src: self.id(),
+                dest: client.to_string(),
+                body: Body::Error {
+                    in_reply_to: msg_id,
+                    code: TEMPORARILY_UNAVAILABLE,
+                    text: format!("gave up after {} lin-kv requests", requests),
+                    retry_after: None,
+                },
+            });
+            true
+        }
+
+        /// The cas moving the tail pointer past `send.from` succeeded, so that offset is ours.
+        fn finish_cas_send(&self, send: CasSend, outbox: &mut Vec<Message>) {
+            let offset = send.from;
+            {
+                let log = self.log(&send.key);
+                let mut log = log.lock().unwrap();
+                self.apply_replica(&send.key, &mut log, offset, &send.msg);
+            }
+            self.replicate(&send.key, offset, &send.msg, false, outbox);
+            self.record_session(&send.client, &send.key, offset);
+            let msg_id = self.next_msg_id();
+            outbox.push(Message {
+                src: self.id(),
+                dest: send.client,
+                body: Body::SendOk {
+                    msg_id,
+                    in_reply_to: send.msg_id,
+                    offset: self.client_offset(&send.key, offset).1,
+                },
+            });
+        }
+
+        /// Stores an entry replicated from a key's leader. Entries can arrive out of order, so
+        /// anything past our tail waits until the gap before it is filled. One below our tail
+        /// fills a hole, like those a restart leaves before the recovered next offset.
+        fn apply_replica(&self, key: &str, log: &mut KeyLog, offset: u64, msg: &Value) {
+            if offset < log.next_offset() {
+                if log.insert(offset, msg.clone(), now_millis()) {
+                    self.persist_entry(key, log, offset);
+                }
+                return;
+            }
+            let tail = log.next_offset();
+            log.out_of_order.insert(offset, msg.clone());
+            self.drain_out_of_order(key, log, tail);
+        }
+
+        /// Appends the replicated entries that now follow on from the tail, persisting the
+        /// tail if it's moved on from `tail`.
+        fn drain_out_of_order(&self, key: &str, log: &mut KeyLog, tail: u64) {
+            while let Some(msg) = log.out_of_order.remove(&log.next) {
+                let offset = log.push(msg);
+                self.persist_entry(key, log, offset);
+            }
+            if log.next_offset() != tail {
+                self.persist_offset(key, log.next_offset());
+            }
+            log.stalled_since = if log.out_of_order.is_empty() {
+                None
+            } else if log.next_offset() != tail {
+                Some(Instant::now())
+            } else {
+                log.stalled_since.or_else(|| Some(Instant::now()))
+            };
+        }
+
+        /// In cas mode, an offset whose cas succeeded but whose send then failed or whose node
+        /// died is never filled, and would hold back every entry after it. Once a gap has
+        /// stalled for `leader_timeout` we skip it; should its entry turn up after all, it
+        /// fills the hole.
+        fn skip_stalled_gaps(&self) {
+            if self.config.send_mode != SendMode::Cas {
+                return;
+            }
+            let logs: Vec<(String, Arc<Mutex<KeyLog>>)> = self
+                .logs
+                .read()
+                .unwrap()
+                .iter()
+                .map(|(key, log)| (key.clone(), log.clone()))
+                .collect();
+            for (key, log) in logs {
+                let mut log = log.lock().unwrap();
+                let stalled = log
+                    .stalled_since
+                    .is_some_and(|since| since.elapsed() >= self.config.leader_timeout);
+                let Some(&next) = log.out_of_order.keys().next().filter(|_| stalled) else {
+                    continue;
+                };
+                let tail = log.next_offset();
+                log::warn!("Skipping unfilled offsets {}..{} of {}", tail, next, key);
+                log.skip_to(next);
+                self.drain_out_of_order(&key, &mut log, tail);
+            }
+        }
+
+        /// Reads every polled key's slice, then hands out the response budget in key order so
+        /// which keys get cut short doesn't depend on which read finished first.
+        fn poll(
+            &self,
+            in_reply_to: u64,
+            offsets: &HashMap<String, u64>,
+            session: Option<u64>,
+        ) -> Body {
+            match self.read_entries(offsets) {
+                Ok(msgs) => self.poll_ok(in_reply_to, offsets, msgs, session),
+                Err(corrupt) => corrupt.error(in_reply_to),
+            }
+        }
+
+        /// Reads the polled keys within the poll budget, or fails with the first entry that no
+        /// longer matches its checksum.
+        fn read_entries(&self, offsets: &HashMap<String, u64>) -> Result<Entries, CorruptEntry> {
+            let mut keys = offsets
+                .iter()
+                .filter_map(|(key, offset)| Some((key, *offset, self.existing_log(key)?)))
+                .collect::<Vec<_>>();
+            keys.sort_by_key(|(key, ..)| *key);
+            let slices = self.read_slices(&keys);
+            let mut budget = PollBudget {
+                msgs: self.config.poll_max_msgs,
+                bytes: self.config.poll_max_bytes,
+            };
+            keys.iter()
+                .zip(slices)
+                .map(|((key, ..), slice)| match slice {
+                    Ok(slice) => Ok(((*key).clone(), budget.take(slice))),
+                    Err(offset) => Err(CorruptEntry {
+                        key: (*key).clone(),
+                        offset,
+                    }),
+                })
+                .collect()
+        }
+
+        /// Builds the reply to a poll from the entries read for it from `offsets`, moving its
+        /// fetch session along and merging partitions back into the client's keys.
+        fn poll_ok(
+            &self,
+            in_reply_to: u64,
+            offsets: &HashMap<String, u64>,
+            mut msgs: Entries,
+            session: Option<u64>,
+        ) -> Body {
+            if self.config.partitions > 1 {
+                self.hold_back_unfilled(&mut msgs, offsets);
+            }
+            // A session moves past whatever we hand out, and only reports keys with news
+            if let Some(session) = session {
+                if let Some(session) = self.fetch_sessions.lock().unwrap().get_mut(&session) {
+                    for (key, entries) in msgs.iter() {
+                        if let Some((offset, _)) = entries.last() {
+                            session.positions.insert(key.clone(), offset + 1);
+                        }
+                    }
+                }
+                msgs.retain(|_, entries| !entries.is_empty());
+            }
+            if self.config.partitions > 1 {
+                let mut merged: HashMap<String, Vec<(u64, Value)>> = HashMap::new();
+                for (key, entries) in msgs {
+                    let (client_key, _) = self.client_offset(&key, 0);
+                    let merged = merged.entry(client_key).or_default();
+                    for (offset, msg) in entries {
+                        merged.push((self.client_offset(&key, offset).1, msg));
+                    }
+                }
+                for entries in merged.values_mut() {
+                    entries.sort_by_key(|(offset, _)| *offset);
+                }
+                msgs = merged;
+            }
+            Body::PollOk {
+                msg_id: self.next_msg_id(),
+                in_reply_to,
+                msgs,
+                session_id: session,
+            }
+        }
+
+        /// Starts answering a poll that names keys we don't hold: their entries are read from
+        /// each key's leader, and ours are read now. The poll isn't held for new entries or
+        /// for the client's writes, and each read has its own budget. Returns false, leaving
+        /// the poll to the caller, when we hold every key.
+        fn gather_poll(
+            &self,
+            client: &str,
+            msg_id: u64,
+            offsets: &HashMap<String, u64>,
+            session: Option<u64>,
+            outbox: &mut Vec<Message>,
+        ) -> bool {
+            let (local, remote): (HashMap<_, _>, HashMap<_, _>) = offsets
+                .iter()
+                .map(|(key, offset)| (key.clone(), *offset))
+                .partition(|(key, _)| self.holds(key));
+            if remote.is_empty() {
+                return false;
+            }
+            let mut by_leader: HashMap<String, HashMap<String, u64>> = HashMap::new();
+            for (key, offset) in remote {
+                by_leader
+                    .entry(self.leader_for(&key))
+                    .or_default()
+                    .insert(key, offset);
+            }
+            let msgs = match self.read_entries(&local) {
+                Ok(msgs) => msgs,
+                Err(corrupt) => {
+                    outbox.push(Message {
+                        src: self.id(),
+                        dest: client.to_string(),
+                        body: corrupt.error(msg_id),
+                    });
+                    return true;
+                }
+            };
+            let mut pending = HashSet::new();
+            for (leader, offsets) in by_leader {
+                let read_id = self.next_msg_id();
+                pending.insert(read_id);
+                outbox.push(Message {
+                    src: self.id(),
+                    dest: leader,
+                    body: Body::ReadEntries {
+                        msg_id: read_id,
+                        offsets,
+                    },
+                });
+            }
+            self.gathers.lock().unwrap().push(PollGather {
+                client: client.to_string(),
+                msg_id,
+                session,
+                offsets: offsets.clone(),
+                msgs,
+                pending,
+                deadline: Instant::now() + self.config.session_wait_timeout,
+            });
+            true
+        }
+
+        /// Adds a replica's entries to the poll that asked for them, answering it once the last
+        /// read is in.
+        fn gathered(&self, read_id: u64, msgs: &Entries, outbox: &mut Vec<Message>) {
+            let done = {
+                let mut gathers = self.gathers.lock().unwrap();
+                let Some(i) = gathers
+                    .iter()
+                    .position(|gather| gather.pending.contains(&read_id))
+                else {
+                    return;
+                };
+                let gather = &mut gathers[i];
+                gather.pending.remove(&read_id);
+                gather.msgs.extend(
+                    msgs.iter()
+                        .map(|(key, entries)| (key.clone(), entries.clone())),
+                );
+                if !gather.pending.is_empty() {
+                    return;
+                }
+                gathers.swap_remove(i)
+            };
+            self.finish_gather(done, outbox);
+        }
+
+        /// Fails the poll waiting on `read_id` with the error its replica answered the read with.
+        /// Returns false if no poll was waiting on it.
+        fn gather_failed(
+            &self,
+            read_id: u64,
+            code: u64,
+            text: &str,
+            outbox: &mut Vec<Message>,
+        ) -> bool {
+            let gather = {
+                let mut gathers = self.gathers.lock().unwrap();
+                let Some(i) = gathers
+                    .iter()
+                    .position(|gather| gather.pending.contains(&read_id))
+                else {
+                    return false;
+                };
+                gathers.swap_remove(i)
+            };
+            outbox.push(Message {
+                src: self.id(),
+                dest: gather.client,
+                body: Body::Error {
+                    in_reply_to: gather.msg_id,
+                    code,
+                    text: text.to_string(),
+                    retry_after: None,
+                },
+            });
+            true
+        }
+
+        /// Answers gathered polls with what they have once a replica has kept them waiting
+        /// too long.
+        fn expire_gathers(&self, outbox: &mut Vec<Message>) {
+            let now = Instant::now();
+            let expired = {
+                let mut gathers = self.gathers.lock().unwrap();
+                let (expired, waiting) = std::mem::take(&mut *gathers)
+                    .into_iter()
+                    .partition(|gather| gather.deadline <= now);
+                *gathers = waiting;
+                expired
+            };
+            for gather in expired {
+                self.finish_gather(gather, outbox);
+            }
+        }
+
+        fn finish_gather(&self, gather: PollGather, outbox: &mut Vec<Message>) {
+            let body = self.poll_ok(gather.msg_id, &gather.offsets, gather.msgs, gather.session);
+            outbox.push(Message {
+                src: self.id(),
+                dest: gather.client,
+                body,
+            });
+        }
+
+        /// Rewrites a client's request against partitioned keys into one against the
+        /// partitions themselves: sends pick a partition, and reads and commits of a key fan
+        /// out to all of its partitions.
+        fn to_partitions(&self, body: &Body) -> Body {
+            let n = self.config.partitions;
+            let partitions = |key: &str| {
+                let key = key.to_string();
+                (0..n).map(move |p| (p, partition_key(&key, p)))
+            };
+            match body {
+                Body::Send { msg_id, key, msg } => Body::Send {
+                    msg_id: *msg_id,
+                    key: self.partition_for(key, msg),
+                    msg: msg.clone(),
+                },
+                Body::TxnSend { msg_id, msgs } => Body::TxnSend {
+                    msg_id: *msg_id,
+                    msgs: msgs
+                        .iter()
+                        .map(|(key, msg)| (self.partition_for(key, msg), msg.clone()))
+                        .collect(),
+                },
+                // Start each partition at its first offset at or past the client's offset
+                Body::Poll {
+                    msg_id,
+                    offsets,
+                    wait_ms,
+                    session_id,
+                } => Body::Poll {
+                    msg_id: *msg_id,
+                    offsets: offsets
+                        .iter()
+                        .flat_map(|(key, offset)| {
+                            partitions(key).map(move |(p, key)| {
+                                let start = offset.map(|offset| {
+                                    offset.saturating_sub(p as u64).div_ceil(n as u64)
+                                });
+                                (key, start)
+                            })
+                        })
+                        .collect(),
+                    wait_ms: *wait_ms,
+                    session_id: *session_id,
+                },
+                // Commit every partition entry at or before the client's offset
+                Body::CommitOffsets {
+                    msg_id,
+                    offsets,
+                    epoch,
+                } => Body::CommitOffsets {
+                    msg_id: *msg_id,
+                    offsets: offsets
+                        .iter()
+                        .flat_map(|(key, offset)| {
+                            let offset = *offset;
+                            partitions(key)
+                                .filter(move |(p, _)| offset >= *p as u64)
+                                .map(move |(p, key)| (key, (offset - p as u64) / n as u64))
+                        })
+                        .collect(),
+                    epoch: *epoch,
+                },
+                Body::ListCommittedOffsets { msg_id, keys } => Body::ListCommittedOffsets {
+                    msg_id: *msg_id,
+                    keys: keys
+                        .iter()
+                        .flat_map(|key| partitions(key).map(|(_, key)| key))
+                        .collect(),
+                },
+                body => body.clone(),
+            }
+        }
+
+        /// Drops the entries read for a partitioned key's poll that lie past the lowest client
+        /// offset one of its partitions could still fill: the partition's next offset past
+        /// what was read. A consumer that moved past them would skip that entry.
+        fn hold_back_unfilled(&self, msgs: &mut Entries, offsets: &HashMap<String, u64>) {
+            let mut unfilled: HashMap<String, u64> = HashMap::new();
+            for (key, start) in offsets {
+                let next = msgs
+                    .get(key)
+                    .and_then(|entries| entries.last())
+                    .map_or(*start, |(offset, _)| offset + 1);
+                let (key, next) = self.client_offset(key, next);
+                unfilled
+                    .entry(key)
+                    .and_modify(|unfilled| *unfilled = (*unfilled).min(next))
+                    .or_insert(next);
+            }
+            for (key, entries) in msgs.iter_mut() {
+                let (client_key, _) = self.client_offset(key, 0);
+                if let Some(unfilled) = unfilled.get(&client_key) {
+                    entries.retain(|(offset, _)| self.client_offset(key, *offset).1 < *unfilled);
+                }
+            }
+        }
+
+        fn partition_for(&self, key: &str, msg: &Value) -> String {
+            let n = self.config.partitions;
+            let p = match self.config.partitioner {
+                Partitioner::RoundRobin => {
+                    let turn = self.next_partition.fetch_add(1, Ordering::Relaxed);
+                    (turn..turn + n)
+                        .map(|p| p % n)
+                        .min_by_key(|p| {
+                            self.existing_log(&partition_key(key, *p))
+                                .map_or(0, |log| log.lock().unwrap().next_offset())
+                        })
+                        .unwrap()
+                }
+                Partitioner::Hash => {
+                    let mut hasher = DefaultHasher::new();
+                    msg.to_string().hash(&mut hasher);
+                    hasher.finish() as usize % n
+                }
+            };
+            partition_key(key, p)
+        }
+
+        /// The client's key and offset for an offset into one of our (possibly partition)
+        /// keys.
+        fn client_offset(&self, key: &str, offset: u64) -> (String, u64) {
+            let n = self.config.partitions as u64;
+            let partition = key
+                .rsplit_once('#')
+                .and_then(|(key, p)| Some((key, p.parse::<u64>().ok()?)));
+            match partition {
+                Some((key, p)) if n > 1 => (key.to_string(), offset * n + p),
+                _ => (key.to_string(), offset),
+            }
+        }
+
+        /// Collapses the committed offsets of a key's partitions into the client offset it's
+        /// safe to resume the whole key from: the earliest any partition has to restart at.
+        fn merge_committed(&self, offsets: HashMap<String, u64>) -> HashMap<String, u64> {
+            let mut merged: HashMap<String, u64> = HashMap::new();
+            for (key, committed) in offsets {
+                let (key, offset) = self.client_offset(&key, committed);
+                merged
+                    .entry(key)
+                    .and_modify(|merged| *merged = (*merged).min(offset))
+                    .or_insert(offset);
+            }
+            merged
+        }
+
+        /// The client offset to reset `key` to so it reads everything ingested from `timestamp`
+        /// on. A partitioned key restarts at the earliest offset any partition needs, the same
+        /// way its committed offsets are merged. Returns `None` if we've never seen the key.
+        fn offset_for_time(&self, src: &str, key: &str, timestamp: u64) -> Option<u64> {
+            let n = self.config.partitions;
+            let keys = if n > 1 && !self.is_peer(src) {
+                (0..n).map(|p| partition_key(key, p)).collect()
+            } else {
+                vec![key.to_string()]
+            };
+            if keys.iter().all(|key| self.existing_log(key).is_none()) {
+                return None;
+            }
+            keys.iter()
+                .map(|key| {
+                    let offset = self
+                        .existing_log(key)
+                        .map_or(0, |log| log.lock().unwrap().offset_for_time(timestamp));
+                    self.client_offset(key, offset).1
+                })
+                .min()
+        }
+
+        /// Merges a poll's offsets into its fetch session's positions, starting a new session
+        /// for id 0, and replaces `offsets` with the full set to read. Returns the session id,
+        /// or `None` if the session doesn't exist (it expired, or was started on another node).
+        fn join_session(&self, session_id: u64, offsets: &mut HashMap<String, u64>) -> Option<u64> {
+            let mut sessions = self.fetch_sessions.lock().unwrap();
+            let session_id = match session_id {
+                0 => {
+                    let session_id = self.next_msg_id();
+                    sessions.insert(
+                        session_id,
+                        FetchSession {
+                            positions: HashMap::new(),
+                            last_used: Instant::now(),
+                        },
+                    );
+                    session_id
+                }
+                session_id => session_id,
+            };
+            let session = sessions.get_mut(&session_id)?;
+            session.last_used = Instant::now();
+            session.positions.extend(offsets.drain());
+            *offsets = session.positions.clone();
+            Some(session_id)
+        }
+
+        /// Whether `key` has reached `max_log_entries`, even after freeing the entries every
+        /// consumer has committed past. Producers get a retryable error: once consumers commit
+        /// further, the space is freed and a retry goes through.
+        fn is_full(&self, key: &str) -> bool {
+            self.existing_log(key)
+                .is_some_and(|log| self.over_budget(&mut log.lock().unwrap()))
+        }
+
+        fn over_budget(&self, log: &mut KeyLog) -> bool {
+            let limit = self.config.max_log_entries;
+            if limit == 0 || log.len() < limit {
+                return false;
+            }
+            let committed = log.committed;
+            log.release_below(committed);
+            log.len() >= limit
+        }
+
+        fn full_reason(&self, key: &str) -> String {
+            format!(
+                "{} is full at {} entries until consumers commit past them",
+                key, self.config.max_log_entries
+            )
+        }
+
+        fn record_session(&self, client: &str, key: &str, offset: u64) {
+            let mut sessions = self.sessions.lock().unwrap();
+            let high_water = sessions
+                .entry(client.to_string())
+                .or_default()
+                .entry(key.to_string())
+                .or_default();
+            *high_water = (*high_water).max(offset);
+        }
+
+        /// Whether our copy of every polled key includes all the writes we've acked to `client`.
+        fn caught_up_with(&self, client: &str, offsets: &HashMap<String, u64>) -> bool {
+            let Some(session) = self.sessions.lock().unwrap().get(client).cloned() else {
+                return true;
+            };
+            offsets.keys().all(|key| {
+                session.get(key).is_none_or(|high_water| {
+                    self.existing_log(key)
+                        .is_some_and(|log| log.lock().unwrap().next_offset() > *high_water)
+                })
+            })
+        }
+
+        /// Fills in the offsets a poll left out with each key's committed offset, so a consumer
+        /// can pick up where the last one stopped without asking first.
+        fn resume_offsets(&self, offsets: &HashMap<String, Option<u64>>) -> HashMap<String, u64> {
+            offsets
+                .iter()
+                .map(|(key, offset)| {
+                    let offset = offset.unwrap_or_else(|| {
+                        self.existing_log(key)
+                            .map_or(0, |log| log.lock().unwrap().committed)
+                    });
+                    (key.clone(), offset)
+                })
+                .collect()
+        }
+
+        /// Whether any polled key has an entry at or after the requested offset.
+        fn has_data(&self, offsets: &HashMap<String, u64>) -> bool {
+            offsets.iter().any(|(key, offset)| {
+                self.existing_log(key)
+                    .is_some_and(|log| log.lock().unwrap().iter_from(*offset).next().is_some())
+            })
+        }
+
+        /// Answers held polls that can now see the client's writes (and, for long polls, have
+        /// something new to see), or that have waited long enough that a possibly stale or
+        /// empty answer beats a timeout.
+        fn release_polls(&self, outbox: &mut Vec<Message>) {
+            let now = Instant::now();
+            let held = std::mem::take(&mut *self.held_polls.lock().unwrap());
+            let mut still_held = Vec::new();
+            for poll in held {
+                let ready = self.caught_up_with(&poll.client, &poll.offsets)
+                    && (!poll.wait_for_data || self.has_data(&poll.offsets));
+                if poll.deadline > now && !ready {
+                    still_held.push(poll);
+                    continue;
+                }
+                let body = self.poll(poll.msg_id, &poll.offsets, poll.session);
+                outbox.push(Message {
+                    src: self.id(),
+                    dest: poll.client,
+                    body,
+                });
+            }
+            self.held_polls.lock().unwrap().extend(still_held);
+        }
+
+        /// Appends a send to a key we lead, or one a peer forwarded to us, and builds the reply.
+        fn send_locally(
+            &self,
+            src: &str,
+            msg_id: u64,
+            key: &str,
+            msg: &Value,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            let from_peer = self.is_peer(src);
+            match self.append(key, msg, outbox) {
+                Ok((offset, sent)) => {
+                    if !from_peer {
+                        self.record_session(src, key, offset);
+                    }
+                    let reply = Body::SendOk {
+                        msg_id: self.next_msg_id(),
+                        in_reply_to: msg_id,
+                        offset: if from_peer {
+                            offset
+                        } else {
+                            self.client_offset(key, offset).1
+                        },
+                    };
+                    self.await_acks(src, reply, sent)
+                }
+                Err((code, text)) => Some(Body::Error {
+                    in_reply_to: msg_id,
+                    code,
+                    text,
+                    retry_after: None,
+                }),
+            }
+        }
+
+        /// Recomputes who owns each key when the set of alive nodes changes. Keys we lose are
+        /// pushed to their new owner, which may have missed entries while it was away, and keys
+        /// we take over from a node that's gone are pulled from a survivor in case it saw
+        /// entries that never reached us. Either way the new owner holds sends to the key until
+        /// the transfer lands, and everyone else keeps forwarding to it, so clients never see
+        /// the handover.
+        fn rebalance(&self, outbox: &mut Vec<Message>) {
+            let alive = self.cluster.read().unwrap().election.alive_nodes();
+            let previous = std::mem::replace(&mut *self.membership.lock().unwrap(), alive.clone());
+            if previous == alive {
+                return;
+            }
+            log::info!(
+                "Alive nodes changed from {:?} to {:?}, rebalancing keys",
+                previous,
+                alive
+            );
+            let id = self.id();
+            let keys: Vec<String> = self.logs.read().unwrap().keys().cloned().collect();
+            let mut pulls: HashMap<String, HashMap<String, u64>> = HashMap::new();
+            for key in keys {
+                let (Some(before), Some(after)) =
+                    (leader_among(&previous, &key), leader_among(&alive, &key))
+                else {
+                    continue;
+                };
+                if after == id && before != id {
+                    let Some(source) = alive.iter().find(|node| **node != id) else {
+                        continue;
+                    };
+                    let next = self.log(&key).lock().unwrap().next_offset();
+                    pulls
+                        .entry(source.clone())
+                        .or_default()
+                        .insert(key.clone(), next);
+                    self.start_migration(&key);
+                } else if before == id && after != id {
+                    log::info!("Moving {} to its new owner {}", key, after);
+                    self.send_snapshot(after, &HashMap::from([(key, 0)]), outbox);
+                }
+            }
+            for (peer, offsets) in pulls {
+                log::info!("Taking over {:?} from {}", offsets.keys(), peer);
+                self.request_snapshot(Some(peer), offsets, outbox);
+            }
+        }
+
+        fn start_migration(&self, key: &str) {
+            let deadline = Instant::now() + self.config.migration_timeout;
+            self.migrations
+                .lock()
+                .unwrap()
+                .entry(key.to_string())
+                .or_insert_with(|| Migration {
+                    held: Vec::new(),
+                    deadline,
+                });
+        }
+
+        /// Ends the transfer of `key` to us and applies the sends held while it ran.
+        fn finish_migration(&self, key: &str, outbox: &mut Vec<Message>) {
+            let migration = self.migrations.lock().unwrap().remove(key);
+            let Some(migration) = migration else {
+                return;
+            };
+            for (src, msg_id, msg) in migration.held {
+                if let Some(body) = self.send_locally(&src, msg_id, key, &msg, outbox) {
+                    outbox.push(Message {
+                        src: self.id(),
+                        dest: src,
+                        body,
+                    });
+                }
+            }
+        }
+
+        /// Stops waiting on transfers whose source went quiet, so held sends aren't stuck
+        /// behind a node that died mid-transfer.
+        fn expire_migrations(&self, outbox: &mut Vec<Message>) {
+            let now = Instant::now();
+            let expired: Vec<String> = self
+                .migrations
+                .lock()
+                .unwrap()
+                .iter()
+                .filter(|(_, migration)| migration.deadline <= now)
+                .map(|(key, _)| key.clone())
+                .collect();
+            for key in expired {
+                log::warn!("Transfer of {} timed out, taking it over as is", key);
+                self.snapshot_chunks.lock().unwrap().remove(&key);
+                self.finish_migration(&key, outbox);
+            }
+        }
+
+        /// Asks `peer` (or any peer, if none is given) to stream us its logs for `offsets`, or
+        /// for every key it has when `offsets` is empty.
+        fn request_snapshot(
+            &self,
+            peer: Option<String>,
+            offsets: HashMap<String, u64>,
+            outbox: &mut Vec<Message>,
+        ) {
+            let Some(peer) = peer.or_else(|| {
+                let cluster = self.cluster.read().unwrap();
+                let peers = cluster.election.peers();
+                peers
+                    .iter()
+                    .find(|peer| cluster.election.is_alive(peer))
+                    .or(peers.first())
+                    .cloned()
+            }) else {
+                return;
+            };
+            self.snapshotting
+                .lock()
+                .unwrap()
+                .extend(offsets.keys().cloned());
+            let msg_id = self.next_msg_id();
+            outbox.push(Message {
+                src: self.id(),
+                dest: peer,
+                body: Body::SnapshotRequest { msg_id, offsets },
+            });
+        }
+
+        /// Streams the requested logs to `dest` in chunks of at most `snapshot_chunk_size`
+        /// entries. Every key gets at least one chunk so the receiver learns the transfer is
+        /// done even when we have nothing newer than it does.
+        fn send_snapshot(
+            &self,
+            dest: &str,
+            offsets: &HashMap<String, u64>,
+            outbox: &mut Vec<Message>,
+        ) {
+            let offsets = if offsets.is_empty() {
+                let logs = self.logs.read().unwrap();
+                logs.keys().map(|key| (key.clone(), 0)).collect()
+            } else {
+                offsets.clone()
+            };
+            let chunk_size = self.config.snapshot_chunk_size.max(1);
+            for (key, from) in offsets {
+                let mut chunks = Vec::new();
+                let mut committed = 0;
+                if let Some(log) = self.existing_log(&key) {
+                    let log = log.lock().unwrap();
+                    committed = log.committed;
+                    let mut chunk: (u64, Vec<Value>) = (from, Vec::new());
+                    for (offset, msg) in log.iter_from(from) {
+                        if chunk.1.is_empty() {
+                            chunk.0 = offset;
+                        }
+                        // A hole in our own log starts a new chunk so offsets stay contiguous
+                        if chunk.1.len() == chunk_size || chunk.0 + chunk.1.len() as u64 != offset {
+                            chunks.push(std::mem::replace(&mut chunk, (offset, Vec::new())));
+                        }
+                        chunk.1.push(msg.clone());
+                    }
+                    chunks.push(chunk);
+                } else {
+                    chunks.push((from, Vec::new()));
+                }
+                let start = chunks[0].0;
+                let count = chunks.len();
+                for (i, (offset, entries)) in chunks.into_iter().enumerate() {
+                    let msg_id = self.next_msg_id();
+                    outbox.push(Message {
+                        src: self.id(),
+                        dest: dest.to_string(),
+                        body: Body::SnapshotChunk {
+                            msg_id,
+                            key: key.clone(),
+                            start,
+                            offset,
+                            entries,
+                            committed,
+                            chunk: i,
+                            chunks: count,
+                        },
+                    });
+                }
+            }
+        }
+
+        /// Opens the stores for our node id, reloads every key's entries and resumes it at its
+        /// stored next offset so new appends won't reuse an offset. A key whose entries fail
+        /// their checksums keeps the good ones before and a hole up to its next offset, for the
+        /// snapshot we request after recovering to fill back in; entries are checked again each
+        /// time they're read. Returns whether there was any state to recover.
+        fn recover(&self) -> bool {
+            let Some(dir) = &self.config.data_dir else {
+                return false;
+            };
+            let id = self.id();
+            let fsync = self.config.fsync;
+            let recovered = OffsetStore::open(dir, &id, fsync).and_then(|store| {
+                let next_offsets = store.load()?;
+                let entry_store = EntryStore::open(dir, &id, fsync, self.config.fsync_batch)?;
+                let entries = entry_store.load()?;
+                let _ = self.store.set(store);
+                let _ = self.entry_store.set(entry_store);
+                Ok((next_offsets, entries))
+            });
+            let (mut next_offsets, entries) = match recovered {
+                Ok(recovered) => recovered,
+                Err(e) => {
+                    log::error!("Unable to recover state from {:?}: {}", dir, e);
+                    return false;
+                }
+            };
+            log::info!("Recovered next offsets {:?}", next_offsets);
+            let recovered = !next_offsets.is_empty() || !entries.is_empty();
+            let mut logs = self.logs.write().unwrap();
+            for (key, recovered) in entries {
+                let mut log = KeyLog::default();
+                // Entries that filled holes were written after the ones above them
+                for (offset, timestamp, msg) in recovered.entries {
+                    log.insert(offset, msg, timestamp);
+                }
+                if let Some(e) = recovered.corruption {
+                    log::error!("Entries of {} are corrupt at {}, dropping the rest", key, e);
+                }
+                // Even past dropped entries, the offsets up to it were handed out
+                log.skip_to(next_offsets.remove(&key).unwrap_or_default());
+                logs.insert(key, Arc::new(Mutex::new(log)));
+            }
+            for (key, next_offset) in next_offsets {
+                logs.insert(key, Arc::new(Mutex::new(KeyLog::starting_at(next_offset))));
+            }
+            recovered
+        }
+
+        fn persist_entry(&self, key: &str, log: &KeyLog, offset: u64) {
+            let Some(store) = self.entry_store.get() else {
+                return;
+            };
+            let Some((_, msg, crc)) = log.iter_checked_from(offset).next() else {
+                return;
+            };
+            let timestamp = log.timestamp(offset).unwrap_or_default();
+            if let Err(e) = store.append(key, offset, crc, timestamp, msg) {
+                log::error!("Unable to persist entry {} of {}: {}", offset, key, e);
+            }
+        }
+
+        /// Syncs writes the fsync policy left in the page cache, once `fsync_interval` has
+        /// passed since the last time.
+        fn sync_stores(&self) {
+            if self.config.fsync == FsyncPolicy::Always {
+                return;
+            }
+            {
+                let mut last_fsync = self.last_fsync.lock().unwrap();
+                if last_fsync.elapsed() < self.config.fsync_interval {
+                    return;
+                }
+                *last_fsync = Instant::now();
+            }
+            if let Some(Err(e)) = self.entry_store.get().map(EntryStore::sync) {
+                log::error!("Unable to sync entries: {}", e);
+            }
+            if let Some(Err(e)) = self.store.get().map(OffsetStore::sync) {
+                log::error!("Unable to sync next offsets: {}", e);
+            }
+        }
+
+        fn persist_offset(&self, key: &str, next_offset: u64) {
+            let Some(store) = self.store.get() else {
+                return;
+            };
+            if let Err(e) = store.save(key, next_offset) {
+                log::error!("Unable to persist next offset for {}: {}", key, e);
+            }
+        }
+
+        /// Reads each key's slice, spreading the keys over up to `poll_parallelism` of tokio's
+        /// blocking threads so a consumer subscribed to many keys isn't answered one log at a
+        /// time. Each read only ever locks the log it's reading. Outside a multi-threaded
+        /// runtime, as in tests, the keys are read one after another.
+        fn read_slices(
+            &self,
+            keys: &[(&String, u64, Arc<Mutex<KeyLog>>)],
+        ) -> Vec<Result<Slice, u64>> {
+            let limits = self.read_limits();
+            let workers = self.config.poll_parallelism.clamp(1, keys.len().max(1));
+            let runtime = Handle::try_current()
+                .ok()
+                .filter(|runtime| runtime.runtime_flavor() == RuntimeFlavor::MultiThread);
+            let Some(runtime) = runtime.filter(|_| workers > 1) else {
+                return keys
+                    .iter()
+                    .map(|(_, offset, log)| read_log(&log.lock().unwrap(), *offset, limits))
+                    .collect();
+            };
+            let reads: Vec<_> = keys
+                .chunks(keys.len().div_ceil(workers))
+                .map(|chunk| {
+                    let chunk: Vec<(u64, Arc<Mutex<KeyLog>>)> = chunk
+                        .iter()
+                        .map(|(_, offset, log)| (*offset, Arc::clone(log)))
+                        .collect();
+                    runtime.spawn_blocking(move || {
+                        chunk
+                            .iter()
+                            .map(|(offset, log)| read_log(&log.lock().unwrap(), *offset, limits))
+                            .collect::<Vec<_>>()
+                    })
+                })
+                .collect();
+            // Let the runtime move this worker's other tasks elsewhere while we wait
+            task::block_in_place(|| {
+                runtime.block_on(async {
+                    let mut slices = Vec::with_capacity(keys.len());
+                    for read in reads {
+                        slices.extend(read.await.unwrap());
+                    }
+                    slices
+                })
+            })
+        }
+
+        /// The most entries and bytes a single key's slice may hold.
+        fn read_limits(&self) -> (usize, usize) {
+            let max_msgs = self
+                .config
+                .poll_max_msgs_per_key
+                .min(self.config.poll_max_msgs);
+            let max_bytes = self
+                .config
+                .poll_max_bytes_per_key
+                .min(self.config.poll_max_bytes);
+            (max_msgs, max_bytes)
+        }
+    }
+
+    /// Collects entries starting at `offset` with their serialized sizes, stopping at the
+    /// per-key limits or where the entry would overflow an otherwise empty response. The
+    /// first entry is always returned. Fails with the offset of the first entry that no
+    /// longer matches its checksum.
+    fn read_log(
+        log: &KeyLog,
+        offset: u64,
+        (max_msgs, max_bytes): (usize, usize),
+    ) -> Result<Slice, u64> {
+        let mut entries = Vec::new();
+        let mut key_bytes = 0;
+        for (offset, msg, crc) in log.iter_checked_from(offset) {
+            if entries.len() >= max_msgs {
+                break;
+            }
+            let json = serde_json::to_vec(msg).unwrap_or_default();
+            if crc32fast::hash(&json) != crc {
+                return Err(offset);
+            }
+            let size = entry_size(offset, &json);
+            if !entries.is_empty() && key_bytes + size > max_bytes {
+                break;
+            }
+            key_bytes += size;
+            entries.push((offset, msg.clone(), size));
+        }
+        Ok(entries)
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_log_spans_segments() {
+            let mut log = KeyLog::default();
+            for i in 0..(SEGMENT_SIZE * 2 + 10) {
+                assert_eq!(log.push(Value::from(i)), i as u64);
+            }
+            assert_eq!(log.segments.len(), 3);
+
+            let offset = SEGMENT_SIZE as u64 - 1;
+            let entries = log.iter_from(offset).take(3).collect::<Vec<_>>();
+            assert_eq!(
+                entries,
+                vec![
+                    (offset, &Value::from(offset)),
+                    (offset + 1, &Value::from(offset + 1)),
+                    (offset + 2, &Value::from(offset + 2)),
+                ]
+            );
+            assert_eq!(log.iter_from(log.next_offset()).count(), 0);
+        }
+
+        fn init(id: &str, config: Config) -> Node {
+            let node = Node::new(config);
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest: id.into(),
+                body: Body::Init {
+                    msg_id: 1,
+                    node_id: id.into(),
+                    node_ids: vec!["n1".into(), "n2".into()],
+                },
+            });
+            node
+        }
+
+        #[test]
+        fn test_snapshot_transfer() {
+            let config = Config {
+                snapshot_chunk_size: 2,
+                ..Default::default()
+            };
+            let n1 = init("n1", config.clone());
+            let n2 = init("n2", config);
+            for i in 0..5 {
+                n1.log("k").lock().unwrap().push(Value::from(i));
+            }
+
+            let chunks = n1.handle_message(Message {
+                src: "n2".into(),
+                dest: "n1".into(),
+                body: Body::SnapshotRequest {
+                    msg_id: 1,
+                    offsets: HashMap::new(),
+                },
+            });
+            assert_eq!(chunks.len(), 3);
+            // Deliver out of order to exercise the reorder buffer
+            for chunk in chunks.into_iter().rev() {
+                n2.handle_message(chunk);
+            }
+
+            let log = n2.log("k");
+            let log = log.lock().unwrap();
+            assert_eq!(log.next_offset(), 5);
+            assert_eq!(
+                log.iter_from(0)
+                    .map(|(_, msg)| msg.clone())
+                    .collect::<Vec<_>>(),
+                (0..5).map(Value::from).collect::<Vec<_>>()
+            );
+        }
+
+        #[test]
+        fn test_snapshot_waits_for_every_chunk() {
+            let config = Config {
+                snapshot_chunk_size: 2,
+                ..Default::default()
+            };
+            let n1 = init("n1", config.clone());
+            let n2 = init("n2", config);
+            for i in 0..5 {
+                n1.log("k").lock().unwrap().push(Value::from(i));
+            }
+            let mut requests = Vec::new();
+            let offsets = HashMap::from([("k".to_string(), 0)]);
+            n2.request_snapshot(Some("n1".into()), offsets, &mut requests);
+            let mut chunks = n1.handle_message(requests.remove(0));
+            assert_eq!(chunks.len(), 3);
+
+            // The final chunk arriving first doesn't end the transfer
+            n2.handle_message(chunks.pop().unwrap());
+            assert!(n2.snapshotting.lock().unwrap().contains("k"));
+            for chunk in chunks {
+                n2.handle_message(chunk);
+            }
+            assert!(!n2.snapshotting.lock().unwrap().contains("k"));
+            assert_eq!(n2.log("k").lock().unwrap().iter_from(0).count(), 5);
+        }
+
+        #[test]
+        fn test_poll_waits_for_own_writes() {
+            let n1 = init("n1", Config::default());
+            let key = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| !n1.is_leader(key))
+                .unwrap();
+            let forwarded = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Send {
+                    msg_id: 1,
+                    key: key.clone(),
+                    msg: Value::from("hello"),
+                },
+            });
+            let Body::Send { msg_id, .. } = forwarded[0].body else {
+                panic!("Send wasn't forwarded to the leader!");
+            };
+
+            // The leader's ack overtakes its replication of the entry
+            n1.handle_message(Message {
+                src: "n2".into(),
+                dest: "n1".into(),
+                body: Body::SendOk {
+                    msg_id: 1,
+                    in_reply_to: msg_id,
+                    offset: 0,
+                },
+            });
+            let poll = Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Poll {
+                    msg_id: 2,
+                    offsets: HashMap::from([(key.clone(), Some(0))]),
+                    wait_ms: None,
+                    session_id: None,
+                },
+            };
+            assert_eq!(n1.handle_message(poll), vec![]);
+
+            let released = n1.handle_message(Message {
+                src: "n2".into(),
+                dest: "n1".into(),
+                body: Body::Replicate {
+                    msg_id: 2,
+                    key: key.clone(),
+                    offset: 0,
+                    msg: Value::from("hello"),
+                    ack: false,
+                },
+            });
+            let Body::PollOk { msgs, .. } = &released[0].body else {
+                panic!("Held poll wasn't released after catching up!");
+            };
+            assert_eq!(msgs[&key], vec![(0, Value::from("hello"))]);
+        }
+
+        /// Delivers messages between `nodes` until none are left, returning the ones for clients.
+        fn route(nodes: &[&Node], mut pending: Vec<Message>) -> Vec<Message> {
+            let mut to_clients = Vec::new();
+            while let Some(message) = pending.pop() {
+                match nodes.iter().find(|node| node.id() == message.dest) {
+                    Some(node) => pending.extend(node.handle_message(message)),
+                    None => to_clients.push(message),
+                }
+            }
+            to_clients
+        }
+
+        #[test]
+        fn test_txn_send_across_leaders() {
+            let n1 = init("n1", Config::default());
+            let n2 = init("n2", Config::default());
+            let mine = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| n1.is_leader(key))
+                .unwrap();
+            let theirs = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| !n1.is_leader(key))
+                .unwrap();
+            let txn = Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::TxnSend {
+                    msg_id: 7,
+                    msgs: HashMap::from([
+                        (mine.clone(), Value::from(1)),
+                        (theirs.clone(), Value::from(2)),
+                    ]),
+                },
+            };
+
+            let replies = route(&[&n1, &n2], vec![txn]);
+            assert_eq!(replies.len(), 1);
+            let Body::TxnSendOk {
+                in_reply_to,
+                offsets,
+                ..
+            } = &replies[0].body
+            else {
+                panic!("Transaction didn't commit: {:?}", replies[0]);
+            };
+            assert_eq!(*in_reply_to, 7);
+            assert_eq!(offsets, &HashMap::from([(mine, 0), (theirs, 0)]));
+            for node in [&n1, &n2] {
+                let logs = node.logs.read().unwrap();
+                assert!(logs
+                    .values()
+                    .all(|log| log.lock().unwrap().locked_by.is_none()));
+            }
+        }
+
+        #[test]
+        fn test_prepared_txn_waits_for_lost_decision() {
+            let config = Config {
+                txn_timeout: Duration::from_millis(50),
+                ..Default::default()
+            };
+            let n1 = init("n1", config.clone());
+            let n2 = init("n2", config);
+            let mine = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| n1.is_leader(key))
+                .unwrap();
+            let theirs = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| !n1.is_leader(key))
+                .unwrap();
+            let prepare = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::TxnSend {
+                    msg_id: 7,
+                    msgs: HashMap::from([
+                        (mine.clone(), Value::from(1)),
+                        (theirs.clone(), Value::from(2)),
+                    ]),
+                },
+            });
+            let vote = n2.handle_message(prepare.into_iter().next().unwrap());
+            let decide = n1.handle_message(vote.into_iter().next().unwrap());
+            assert!(decide
+                .iter()
+                .any(|message| matches!(message.body, Body::Decide { commit: true, .. })));
+
+            // The decide to n2 is lost, so n2 keeps its key locked and asks n1
+            std::thread::sleep(Duration::from_millis(60));
+            let mut pending = n2.tick();
+            assert!(pending
+                .iter()
+                .any(|message| matches!(message.body, Body::Inquire { .. })));
+            assert!(n2.log(&theirs).lock().unwrap().locked_by.is_some());
+
+            // Meanwhile n1 resends it, and n2 applies it only once
+            pending.extend(n1.tick());
+            let replies = route(&[&n1, &n2], pending);
+            let [Message {
+                body: Body::TxnSendOk { offsets, .. },
+                ..
+            }] = &replies[..]
+            else {
+                panic!("Transaction didn't commit once: {:?}", replies);
+            };
+            assert_eq!(offsets, &HashMap::from([(mine, 0), (theirs.clone(), 0)]));
+            let log = n2.log(&theirs);
+            let log = log.lock().unwrap();
+            assert!(log.locked_by.is_none());
+            assert_eq!(log.iter_from(0).count(), 1);
+        }
+
+        #[test]
+        fn test_txn_decided_once_when_vote_races_timeout() {
+            let config = Config {
+                txn_timeout: Duration::from_millis(1),
+                ..Default::default()
+            };
+            for _ in 0..20 {
+                let n1 = init("n1", config.clone());
+                let n2 = init("n2", config.clone());
+                let mine = (0..)
+                    .map(|i| format!("k{}", i))
+                    .find(|key| n1.is_leader(key))
+                    .unwrap();
+                let theirs = (0..)
+                    .map(|i| format!("k{}", i))
+                    .find(|key| !n1.is_leader(key))
+                    .unwrap();
+                let prepare = n1.handle_message(Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body: Body::TxnSend {
+                        msg_id: 7,
+                        msgs: HashMap::from([(mine, Value::from(1)), (theirs, Value::from(2))]),
+                    },
+                });
+                let vote = n2.handle_message(prepare.into_iter().next().unwrap());
+                std::thread::sleep(Duration::from_millis(2));
+
+                // The vote timeout and the last vote both try to decide the transaction
+                let (expired, voted) = std::thread::scope(|scope| {
+                    let expired = scope.spawn(|| n1.tick());
+                    let voted = scope.spawn(|| n1.handle_message(vote.into_iter().next().unwrap()));
+                    (expired.join().unwrap(), voted.join().unwrap())
+                });
+                let replies = route(&[&n1, &n2], expired.into_iter().chain(voted).collect());
+                assert_eq!(replies.len(), 1, "{:?}", replies);
+            }
+        }
+
+        #[test]
+        fn test_sends_are_forwarded_to_each_keys_leader() {
+            let n1 = init("n1", Config::default());
+            let n2 = init("n2", Config::default());
+            let mine = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| n1.is_leader(key))
+                .unwrap();
+            let theirs = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| !n1.is_leader(key))
+                .unwrap();
+            assert!(n2.is_leader(&theirs) && !n2.is_leader(&mine));
+
+            let sends = [&mine, &theirs]
+                .into_iter()
+                .enumerate()
+                .map(|(i, key)| Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body: Body::Send {
+                        msg_id: i as u64,
+                        key: key.clone(),
+                        msg: Value::from(i),
+                    },
+                })
+                .collect();
+            let replies = route(&[&n1, &n2], sends);
+            assert_eq!(replies.len(), 2);
+            assert!(replies
+                .iter()
+                .all(|reply| matches!(reply.body, Body::SendOk { offset: 0, .. })));
+
+            // Each key's leader only acked once the other node held the entry too
+            for node in [&n1, &n2] {
+                for (i, key) in [&mine, &theirs].into_iter().enumerate() {
+                    assert_eq!(
+                        node.log(key)
+                            .lock()
+                            .unwrap()
+                            .iter_from(0)
+                            .collect::<Vec<_>>(),
+                        vec![(0, &Value::from(i))]
+                    );
+                }
+            }
+        }
+
+        #[test]
+        fn test_full_log_frees_committed_entries() {
+            let config = Config {
+                max_log_entries: 2,
+                acks: 0,
+                ..Default::default()
+            };
+            let n1 = init("n1", config);
+            let key = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| n1.is_leader(key))
+                .unwrap();
+            let send = |msg_id| Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Send {
+                    msg_id,
+                    key: key.clone(),
+                    msg: Value::from(msg_id),
+                },
+            };
+            let code = |replies: Vec<Message>| match replies[0].body {
+                Body::Error { code, .. } => Some(code),
+                _ => None,
+            };
+            let codes: Vec<Option<u64>> =
+                (0..3).map(|i| code(n1.handle_message(send(i)))).collect();
+            assert_eq!(codes, vec![None, None, Some(TEMPORARILY_UNAVAILABLE)]);
+
+            // Once the first entry is committed past it's freed, making room for one more
+            n1.log(&key).lock().unwrap().committed = 1;
+            let reply = n1.handle_message(send(3));
+            assert!(matches!(reply[0].body, Body::SendOk { offset: 2, .. }));
+            assert_eq!(
+                code(n1.handle_message(send(4))),
+                Some(TEMPORARILY_UNAVAILABLE)
+            );
+
+            let offsets = HashMap::from([(key.clone(), 0)]);
+            let Body::PollOk { msgs, .. } = n1.poll(5, &offsets, None) else {
+                panic!("Poll failed!");
+            };
+            assert_eq!(msgs[&key], vec![(1, Value::from(1)), (2, Value::from(3))]);
+        }
+
+        #[test]
+        fn test_cas_send_gives_up_on_failing_lin_kv() {
+            let config = Config {
+                send_mode: SendMode::Cas,
+                ..Default::default()
+            };
+            let n1 = init("n1", config);
+            let mut pending = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Send {
+                    msg_id: 1,
+                    key: "k".into(),
+                    msg: Value::from(1),
+                },
+            });
+            let mut requests = 0;
+            while let [request] = &pending[..] {
+                if request.dest != LIN_KV {
+                    break;
+                }
+                let (Body::Read { msg_id, .. } | Body::Cas { msg_id, .. }) = request.body else {
+                    panic!("Unexpected lin-kv request {:?}", request);
+                };
+                requests += 1;
+                pending = n1.handle_message(Message {
+                    src: LIN_KV.into(),
+                    dest: "n1".into(),
+                    body: Body::Error {
+                        in_reply_to: msg_id,
+                        code: CRASH,
+                        text: "crashed".into(),
+                        retry_after: None,
+                    },
+                });
+            }
+            assert_eq!(requests, MAX_LIN_KV_REQUESTS);
+            assert!(matches!(
+                pending[..],
+                [Message {
+                    body: Body::Error {
+                        in_reply_to: 1,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        ..
+                    },
+                    ..
+                }]
+            ));
+        }
+
+        #[test]
+        fn test_cas_mode_skips_unfilled_offsets() {
+            let config = Config {
+                send_mode: SendMode::Cas,
+                leader_timeout: Duration::from_millis(50),
+                ..Default::default()
+            };
+            let n1 = init("n1", config);
+            let replicate = |offset, msg: &str| Message {
+                src: "n2".into(),
+                dest: "n1".into(),
+                body: Body::Replicate {
+                    msg_id: offset,
+                    key: "k".into(),
+                    offset,
+                    msg: Value::from(msg),
+                    ack: false,
+                },
+            };
+
+            // Offset 0 was reserved, but whoever reserved it never sent it
+            n1.handle_message(replicate(1, "b"));
+            assert_eq!(n1.log("k").lock().unwrap().next_offset(), 0);
+            std::thread::sleep(Duration::from_millis(60));
+            n1.tick();
+            assert_eq!(
+                n1.log("k").lock().unwrap().iter_from(0).collect::<Vec<_>>(),
+                vec![(1, &Value::from("b"))]
+            );
+
+            // It still fills the hole if it turns up late
+            n1.handle_message(replicate(0, "a"));
+            assert_eq!(
+                n1.log("k").lock().unwrap().iter_from(0).collect::<Vec<_>>(),
+                vec![(0, &Value::from("a")), (1, &Value::from("b"))]
+            );
+        }
+
+        #[test]
+        fn test_entries_hold_any_json() {
+            let n1 = init("n1", Config::default());
+            let n2 = init("n2", Config::default());
+            let payloads = [
+                json!("hello"),
+                json!({"user": "c1", "tags": ["a", null], "n": 1.5}),
+                json!([1, [2, 3]]),
+                json!(null),
+            ];
+            for (msg_id, msg) in payloads.iter().enumerate() {
+                let send = json!({
+                    "src": "c1",
+                    "dest": "n1",
+                    "body": {"type": "send", "msg_id": msg_id, "key": "k", "msg": msg},
+                });
+                let replies = route(&[&n1, &n2], vec![serde_json::from_value(send).unwrap()]);
+                assert!(matches!(replies[0].body, Body::SendOk { .. }));
+            }
+
+            let replies = n2.handle_message(Message {
+                src: "c1".into(),
+                dest: "n2".into(),
+                body: Body::Poll {
+                    msg_id: 9,
+                    offsets: HashMap::from([("k".to_string(), Some(0))]),
+                    wait_ms: None,
+                    session_id: None,
+                },
+            });
+            let reply = serde_json::to_value(&replies[0]).unwrap();
+            let expected: Vec<Value> = payloads
+                .into_iter()
+                .enumerate()
+                .map(|(offset, msg)| json!([offset, msg]))
+                .collect();
+            assert_eq!(reply["body"]["msgs"]["k"], Value::from(expected));
+        }
+
+        #[test]
+        fn test_consistency_picks_how_sends_get_offsets() {
+            let first_dest = |mode: &str| {
+                let consistency = mode.parse().unwrap();
+                let n1 = init("n1", Config::default().with_consistency(consistency));
+                let sent = n1.handle_message(Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body: Body::Send {
+                        msg_id: 1,
+                        key: "k".into(),
+                        msg: Value::from(1),
+                    },
+                });
+                sent[0].dest.clone()
+            };
+            // Linearizable sends reserve their offset through lin-kv, while sequential ones are
+            // either forwarded to n2 or, when n1 leads the key, replicated to it
+            assert_eq!(first_dest("linearizable"), LIN_KV);
+            assert_eq!(first_dest("sequential"), "n2");
+            assert!("eventual".parse::<Consistency>().is_err());
+        }
+
+        #[test]
+        fn test_log_starting_after_restart() {
+            let mut log = KeyLog::starting_at(42);
+            assert_eq!(log.push(Value::from("a")), 42);
+            assert_eq!(
+                log.iter_from(0).collect::<Vec<_>>(),
+                vec![(42, &Value::from("a"))]
+            );
+        }
+
+        #[test]
+        fn test_poll_respects_per_key_limits() {
+            let config = Config {
+                poll_max_msgs_per_key: 2,
+                poll_max_bytes_per_key: 40,
+                ..Default::default()
+            };
+            let n1 = init("n1", config);
+            for i in 0..5 {
+                n1.log("small").lock().unwrap().push(Value::from(i));
+                n1.log("large")
+                    .lock()
+                    .unwrap()
+                    .push(Value::from("x".repeat(30)));
+            }
+            let offsets = ["small", "large"].map(|key| (key.to_string(), 0));
+            let Body::PollOk { msgs, .. } = n1.poll(1, &HashMap::from(offsets), None) else {
+                panic!("Poll failed!");
+            };
+            assert_eq!(
+                msgs["small"],
+                vec![(0, Value::from(0)), (1, Value::from(1))]
+            );
+            // One entry is over half the byte limit, but the first is always returned
+            assert_eq!(msgs["large"], vec![(0, Value::from("x".repeat(30)))]);
+        }
+
+        #[tokio::test(flavor = "multi_thread")]
+        async fn test_poll_budget_across_parallel_reads() {
+            let config = Config {
+                poll_max_msgs: 3,
+                poll_parallelism: 2,
+                ..Default::default()
+            };
+            let n1 = init("n1", config);
+            for key in ["a", "b", "c"] {
+                let log = n1.log(key);
+                let mut log = log.lock().unwrap();
+                log.push(Value::from(0));
+                log.push(Value::from(1));
+            }
+            let offsets = ["a", "b", "c"].map(|key| (key.to_string(), 0));
+            let Body::PollOk { msgs, .. } = n1.poll(1, &HashMap::from(offsets), None) else {
+                panic!("Poll failed!");
+            };
+            assert_eq!(msgs["a"].len(), 2);
+            assert_eq!(msgs["b"], vec![(0, Value::from(0))]);
+            assert_eq!(msgs["c"], vec![]);
+        }
+
+        #[test]
+        fn test_metrics_report_length_and_lag() {
+            let n1 = init("n1", Config::default());
+            for i in 0..3 {
+                n1.log("a").lock().unwrap().push(Value::from(i));
+            }
+            n1.log("a").lock().unwrap().committed = 1;
+            n1.log("b").lock().unwrap().push(Value::from(0));
+            let replies = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Metrics { msg_id: 2 },
+            });
+            let Body::MetricsOk { keys, .. } = &replies[0].body else {
+                panic!("Metrics failed: {:?}", replies[0]);
+            };
+            let metrics = |length, committed, lag| KeyMetrics {
+                length,
+                committed,
+                lag,
+            };
+            assert_eq!(
+                keys,
+                &HashMap::from([
+                    ("a".to_string(), metrics(3, 1, 2)),
+                    ("b".to_string(), metrics(1, 0, 1)),
+                ])
+            );
+        }
+
+        #[test]
+        fn test_commit_from_stale_epoch_is_fenced() {
+            let n1 = init("n1", Config::default());
+            n1.log("k").lock().unwrap().push(Value::from("a"));
+            let commit = |epoch, offset| {
+                n1.handle_message(Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body: Body::CommitOffsets {
+                        msg_id: 1,
+                        offsets: HashMap::from([("k".to_string(), offset)]),
+                        epoch,
+                    },
+                })
+                .remove(0)
+                .body
+            };
+            assert!(matches!(commit(2, 5), Body::CommitOffsetsOk { .. }));
+            assert!(matches!(
+                commit(1, 0),
+                Body::Error {
+                    code: PRECONDITION_FAILED,
+                    ..
+                }
+            ));
+            assert!(matches!(commit(0, 0), Body::Error { .. }));
+            assert_eq!(n1.log("k").lock().unwrap().committed, 6);
+        }
+
+        #[test]
+        fn test_client_over_quota_is_throttled() {
+            let config = Config {
+                client_rate: 1.0,
+                client_burst: 1.0,
+                ..Default::default()
+            };
+            let n1 = init("n1", config);
+            let poll = |client: &str| {
+                n1.handle_message(Message {
+                    src: client.into(),
+                    dest: "n1".into(),
+                    body: Body::Poll {
+                        msg_id: 1,
+                        offsets: HashMap::new(),
+                        wait_ms: None,
+                        session_id: None,
+                    },
+                })
+                .remove(0)
+                .body
+            };
+            assert!(matches!(poll("c1"), Body::PollOk { .. }));
+            assert!(matches!(
+                poll("c1"),
+                Body::Error {
+                    retry_after: Some(_),
+                    ..
+                }
+            ));
+            assert!(matches!(poll("c2"), Body::PollOk { .. }));
+        }
+
+        #[test]
+        fn test_recovery_stops_at_corrupt_entry() {
+            let dir = std::env::temp_dir().join(format!("kafka-corrupt-{}", std::process::id()));
+            let config = Config {
+                data_dir: Some(dir.clone()),
+                ..Default::default()
+            };
+            {
+                let n1 = init("n1", config.clone());
+                let log = n1.log("k");
+                let mut log = log.lock().unwrap();
+                for i in 0..3 {
+                    n1.push("k", &mut log, Value::from(i));
+                }
+            }
+            let path = dir.join("n1.entries").join("6b.log");
+            let entries = std::fs::read_to_string(&path).unwrap();
+            std::fs::write(&path, entries.replacen(" 1\n", " 7\n", 1)).unwrap();
+
+            let n1 = init("n1", config);
+            let log = n1.log("k");
+            let log = log.lock().unwrap();
+            assert_eq!(
+                log.iter_from(0).collect::<Vec<_>>(),
+                vec![(0, &Value::from(0))]
+            );
+            // Offsets past the corruption were handed out, so they're never reused
+            assert_eq!(log.next_offset(), 3);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn test_poll_reports_corrupt_entry() {
+            let n1 = init("n1", Config::default());
+            for i in 0..3 {
+                n1.log("k").lock().unwrap().push(Value::from(i));
+            }
+            n1.log("k").lock().unwrap().segments[0].entries[1] = Value::from(7);
+            let poll = |offset| {
+                n1.handle_message(Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body: Body::Poll {
+                        msg_id: 1,
+                        offsets: HashMap::from([("k".to_string(), Some(offset))]),
+                        wait_ms: None,
+                        session_id: None,
+                    },
+                })
+                .remove(0)
+                .body
+            };
+            let Body::Error { code, text, .. } = poll(0) else {
+                panic!("Corrupt entry was served!");
+            };
+            assert_eq!(code, CRASH);
+            assert_eq!(text, "entry 1 of k is corrupt");
+            // Entries past the corruption are still served
+            assert!(matches!(poll(2), Body::PollOk { .. }));
+        }
+
+        #[test]
+        fn test_restart_refills_old_offsets_from_snapshot() {
+            let dir = std::env::temp_dir().join(format!("kafka-refill-{}", std::process::id()));
+            let config = Config {
+                data_dir: Some(dir.clone()),
+                ..Default::default()
+            };
+            {
+                let n1 = init("n1", config.clone());
+                let log = n1.log("k");
+                let mut log = log.lock().unwrap();
+                for i in 0..3 {
+                    n1.push("k", &mut log, Value::from(i));
+                }
+            }
+            // Only the next offset survives, so the log comes back as a hole up to it
+            std::fs::remove_dir_all(dir.join("n1.entries")).unwrap();
+            let n2 = init("n2", Config::default());
+            for i in 0..3 {
+                n2.log("k").lock().unwrap().push(Value::from(i));
+            }
+            let poll = |node: &Node| {
+                let replies = node.handle_message(Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body: Body::Poll {
+                        msg_id: 1,
+                        offsets: HashMap::from([("k".to_string(), Some(0))]),
+                        wait_ms: None,
+                        session_id: None,
+                    },
+                });
+                let Body::PollOk { msgs, .. } = &replies[0].body else {
+                    panic!("Poll failed: {:?}", replies[0]);
+                };
+                msgs["k"].clone()
+            };
+            let expected = (0..3).map(|i| (i, Value::from(i))).collect::<Vec<_>>();
+
+            {
+                let n1 = init("n1", config.clone());
+                assert_eq!(n1.log("k").lock().unwrap().next_offset(), 3);
+                let chunks = n2.handle_message(Message {
+                    src: "n1".into(),
+                    dest: "n2".into(),
+                    body: Body::SnapshotRequest {
+                        msg_id: 1,
+                        offsets: HashMap::from([("k".to_string(), 0)]),
+                    },
+                });
+                for chunk in chunks {
+                    n1.handle_message(chunk);
+                }
+                assert_eq!(poll(&n1), expected);
+            }
+            // The entries filled in were persisted like any other
+            let n1 = init("n1", config);
+            assert_eq!(poll(&n1), expected);
+            assert_eq!(n1.log("k").lock().unwrap().next_offset(), 3);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn test_restart_never_reuses_offsets() {
+            let dir = std::env::temp_dir().join(format!("kafka-offsets-{}", std::process::id()));
+            let config = Config {
+                data_dir: Some(dir.clone()),
+                ..Default::default()
+            };
+            let push = |n1: &Node, msg: i64| {
+                let log = n1.log("k");
+                let mut log = log.lock().unwrap();
+                n1.push("k", &mut log, Value::from(msg))
+            };
+            {
+                let n1 = init("n1", config.clone());
+                for i in 0..3 {
+                    push(&n1, i);
+                }
+            }
+            // Without any entries, and with a save torn by the crash, the journal still has 3
+            std::fs::remove_dir_all(dir.join("n1.entries")).unwrap();
+            let journal = dir.join("n1.offsets").join("6b.next");
+            let mut file = std::fs::OpenOptions::new()
+                .append(true)
+                .open(&journal)
+                .unwrap();
+            std::io::Write::write_all(&mut file, b"4").unwrap();
+
+            for expected in [3, 4] {
+                let n1 = init("n1", config.clone());
+                assert_eq!(push(&n1, expected), expected as u64);
+            }
+            // Each restart compacts the journal down to the offset it recovered
+            let n1 = init("n1", config);
+            assert_eq!(n1.log("k").lock().unwrap().next_offset(), 5);
+            assert_eq!(std::fs::read_to_string(&journal).unwrap(), "5\n");
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn test_batched_fsync_recovers_every_write() {
+            let dir = std::env::temp_dir().join(format!("kafka-fsync-{}", std::process::id()));
+            let config = Config {
+                data_dir: Some(dir.clone()),
+                fsync: FsyncPolicy::Batch,
+                fsync_batch: 2,
+                fsync_interval: Duration::ZERO,
+                ..Default::default()
+            };
+            {
+                let n1 = init("n1", config.clone());
+                let log = n1.log("k");
+                for i in 0..3 {
+                    n1.push("k", &mut log.lock().unwrap(), Value::from(i));
+                }
+                // The third append waits on its batch until the tick syncs it
+                n1.tick();
+            }
+
+            let n1 = init("n1", config);
+            let log = n1.log("k");
+            let log = log.lock().unwrap();
+            assert_eq!(log.iter_from(0).count(), 3);
+            assert_eq!(log.next_offset(), 3);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+
+        #[test]
+        fn test_long_poll_answered_by_send() {
+            let n1 = init("n1", Config::default());
+            let key = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| n1.is_leader(key))
+                .unwrap();
+            let poll = n1.handle_message(Message {
+                src: "c2".into(),
+                dest: "n1".into(),
+                body: Body::Poll {
+                    msg_id: 1,
+                    offsets: HashMap::from([(key.clone(), Some(0))]),
+                    wait_ms: Some(10_000),
+                    session_id: None,
+                },
+            });
+            assert_eq!(poll, vec![]);
+
+            let replies = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Send {
+                    msg_id: 1,
+                    key: key.clone(),
+                    msg: Value::from("hello"),
+                },
+            });
+            let released = replies
+                .iter()
+                .find(|reply| reply.dest == "c2")
+                .expect("Long poll wasn't released by the send!");
+            let Body::PollOk { msgs, .. } = &released.body else {
+                panic!("Long poll failed: {:?}", released);
+            };
+            assert_eq!(msgs[&key], vec![(0, Value::from("hello"))]);
+        }
+
+        #[test]
+        fn test_poll_resumes_from_committed_offset() {
+            let n1 = init("n1", Config::default());
+            for i in 0..3 {
+                n1.log("k").lock().unwrap().push(Value::from(i));
+            }
+            n1.log("k").lock().unwrap().committed = 2;
+            let replies = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Poll {
+                    msg_id: 1,
+                    offsets: HashMap::from([("k".to_string(), None)]),
+                    wait_ms: None,
+                    session_id: None,
+                },
+            });
+            let Body::PollOk { msgs, .. } = &replies[0].body else {
+                panic!("Poll failed: {:?}", replies[0]);
+            };
+            assert_eq!(msgs["k"], vec![(2, Value::from(2))]);
+        }
+
+        #[test]
+        fn test_fetch_session_remembers_positions() {
+            let n1 = init("n1", Config::default());
+            let poll = |offsets: HashMap<String, Option<u64>>, session_id| {
+                let replies = n1.handle_message(Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body: Body::Poll {
+                        msg_id: 1,
+                        offsets,
+                        wait_ms: None,
+                        session_id: Some(session_id),
+                    },
+                });
+                let Body::PollOk {
+                    msgs, session_id, ..
+                } = &replies[0].body
+                else {
+                    panic!("Poll failed: {:?}", replies[0]);
+                };
+                (msgs.clone(), session_id.unwrap())
+            };
+            n1.log("a").lock().unwrap().push(Value::from("a0"));
+            n1.log("b").lock().unwrap().push(Value::from("b0"));
+
+            let offsets = HashMap::from([("a".to_string(), Some(0)), ("b".to_string(), Some(0))]);
+            let (msgs, session) = poll(offsets, 0);
+            assert_eq!(msgs.len(), 2);
+
+            n1.log("b").lock().unwrap().push(Value::from("b1"));
+            let (msgs, _) = poll(HashMap::new(), session);
+            assert_eq!(
+                msgs,
+                HashMap::from([("b".to_string(), vec![(1, Value::from("b1"))])])
+            );
+        }
+
+        #[test]
+        fn test_partitioned_key_polls_as_one() {
+            let config = Config {
+                partitions: 2,
+                ..Default::default()
+            };
+            let n1 = init("n1", config.clone());
+            let n2 = init("n2", config);
+            let sends = (0..4)
+                .map(|i| Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body: Body::Send {
+                        msg_id: i,
+                        key: "k".into(),
+                        msg: Value::from(i),
+                    },
+                })
+                .collect::<Vec<_>>();
+            let mut offsets = Vec::new();
+            for send in sends {
+                for reply in route(&[&n1, &n2], vec![send]) {
+                    let Body::SendOk { offset, .. } = reply.body else {
+                        panic!("Send failed: {:?}", reply);
+                    };
+                    offsets.push(offset);
+                }
+            }
+            assert_eq!(offsets, vec![0, 1, 2, 3]);
+
+            let replies = route(
+                &[&n1, &n2],
+                vec![Message {
+                    src: "c1".into(),
+                    dest: "n2".into(),
+                    body: Body::Poll {
+                        msg_id: 5,
+                        offsets: HashMap::from([("k".to_string(), Some(1))]),
+                        wait_ms: None,
+                        session_id: None,
+                    },
+                }],
+            );
+            let Body::PollOk { msgs, .. } = &replies[0].body else {
+                panic!("Poll failed: {:?}", replies[0]);
+            };
+            assert_eq!(
+                msgs["k"],
+                (1..4).map(|i| (i, Value::from(i))).collect::<Vec<_>>()
+            );
+        }
+
+        #[test]
+        fn test_partitioned_poll_stops_at_lagging_partition() {
+            let config = Config {
+                partitions: 2,
+                ..Default::default()
+            };
+            let n1 = init("n1", config);
+            for i in [0, 2, 4] {
+                n1.log("k#0").lock().unwrap().push(Value::from(i));
+            }
+            n1.log("k#1").lock().unwrap().push(Value::from(1));
+            let poll = || {
+                let replies = n1.handle_message(Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body: Body::Poll {
+                        msg_id: 1,
+                        offsets: HashMap::from([("k".to_string(), Some(0))]),
+                        wait_ms: None,
+                        session_id: None,
+                    },
+                });
+                let Body::PollOk { msgs, .. } = &replies[0].body else {
+                    panic!("Poll failed: {:?}", replies[0]);
+                };
+                msgs["k"]
+                    .iter()
+                    .map(|(offset, _)| *offset)
+                    .collect::<Vec<_>>()
+            };
+
+            // Partition 1's next entry gets offset 3, so 4 waits for it
+            assert_eq!(poll(), vec![0, 1, 2]);
+            n1.log("k#1").lock().unwrap().push(Value::from(3));
+            assert_eq!(poll(), vec![0, 1, 2, 3, 4]);
+        }
+
+        #[test]
+        fn test_poll_not_blocked_by_other_key() {
+            let n1 = init("n1", Config::default());
+            let busy = n1.log("a");
+            let _busy = busy.lock().unwrap();
+            let replies = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Poll {
+                    msg_id: 2,
+                    offsets: HashMap::from([("b".to_string(), Some(0))]),
+                    wait_ms: None,
+                    session_id: None,
+                },
+            });
+            assert!(matches!(replies[0].body, Body::PollOk { .. }));
+        }
+
+        #[test]
+        fn test_send_waits_for_replica_acks() {
+            let config = Config {
+                acks: 1,
+                ..Default::default()
+            };
+            let n1 = init("n1", config.clone());
+            let n2 = init("n2", config);
+            let key = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| n1.is_leader(key))
+                .unwrap();
+            let replies = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Send {
+                    msg_id: 2,
+                    key: key.clone(),
+                    msg: Value::from(1),
+                },
+            });
+            assert!(replies.iter().all(|reply| reply.dest != "c1"));
+            let to_clients = route(&[&n1, &n2], replies);
+            assert!(matches!(
+                to_clients[..],
+                [Message {
+                    body: Body::SendOk { offset: 0, .. },
+                    ..
+                }]
+            ));
+        }
+
+        #[test]
+        fn test_poll_gathers_keys_held_elsewhere() {
+            let config = Config {
+                replication_factor: 1,
+                ..Default::default()
+            };
+            let n1 = init("n1", config.clone());
+            let n2 = init("n2", config);
+            let key = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| n1.is_leader(key))
+                .unwrap();
+            let replies = n1.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Send {
+                    msg_id: 2,
+                    key: key.clone(),
+                    msg: Value::from(1),
+                },
+            });
+            // With one replica per key, nothing goes to n2
+            assert_eq!(replies.len(), 1);
+            assert!(n2.existing_log(&key).is_none());
+
+            let poll = Message {
+                src: "c1".into(),
+                dest: "n2".into(),
+                body: Body::Poll {
+                    msg_id: 3,
+                    offsets: HashMap::from([(key.clone(), Some(0))]),
+                    wait_ms: None,
+                    session_id: None,
+                },
+            };
+            let to_clients = route(&[&n1, &n2], vec![poll]);
+            let Body::PollOk { msgs, .. } = &to_clients[0].body else {
+                panic!("expected poll_ok, got {:?}", to_clients[0].body);
+            };
+            assert_eq!(msgs[&key], vec![(0, Value::from(1))]);
+        }
+
+        #[test]
+        fn test_failures_are_reported_as_errors() {
+            let error_code = |replies: Vec<Message>| match replies[..] {
+                [Message {
+                    body: Body::Error { code, .. },
+                    ..
+                }] => code,
+                _ => panic!("expected a single error, got {:?}", replies),
+            };
+            let n1 = Node::new(Config::default());
+            let send = Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Send {
+                    msg_id: 1,
+                    key: "k".into(),
+                    msg: Value::from(1),
+                },
+            };
+            assert_eq!(error_code(n1.handle_message(send)), TEMPORARILY_UNAVAILABLE);
+
+            let n1 = init("n1", Config::default());
+            let request = |body| {
+                n1.handle_message(Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body,
+                })
+            };
+            let init = Body::Init {
+                msg_id: 2,
+                node_id: "n1".into(),
+                node_ids: vec!["n1".into()],
+            };
+            assert_eq!(error_code(request(init)), PRECONDITION_FAILED);
+            let read = Body::Read {
+                msg_id: 3,
+                key: "k".into(),
+            };
+            assert_eq!(error_code(request(read)), NOT_SUPPORTED);
+            let lookup = Body::OffsetForTime {
+                msg_id: 4,
+                key: "missing".into(),
+                timestamp: 0,
+            };
+            assert_eq!(error_code(request(lookup)), KEY_DOES_NOT_EXIST);
+            let reply = Body::InitOk {
+                msg_id: 5,
+                in_reply_to: 1,
+            };
+            assert!(request(reply).is_empty());
+        }
+
+        #[test]
+        fn test_offset_for_time() {
+            let n1 = init("n1", Config::default());
+            {
+                let log = n1.log("k");
+                let mut log = log.lock().unwrap();
+                for (i, timestamp) in [10, 20, 20, 30].into_iter().enumerate() {
+                    log.push_at(Value::from(i), timestamp);
+                }
+                // A clock going backwards doesn't reorder the log
+                log.push_at(Value::from(4), 5);
+                assert_eq!(log.timestamp(4), Some(30));
+            }
+            let offset_for = |timestamp| {
+                let replies = n1.handle_message(Message {
+                    src: "c1".into(),
+                    dest: "n1".into(),
+                    body: Body::OffsetForTime {
+                        msg_id: 2,
+                        key: "k".into(),
+                        timestamp,
+                    },
+                });
+                let Body::OffsetForTimeOk { offset, .. } = replies[0].body else {
+                    panic!("expected offset_for_time_ok, got {:?}", replies[0].body);
+                };
+                offset
+            };
+            assert_eq!(offset_for(0), 0);
+            assert_eq!(offset_for(15), 1);
+            assert_eq!(offset_for(20), 1);
+            assert_eq!(offset_for(30), 3);
+            assert_eq!(offset_for(31), 5);
+        }
+
+        #[test]
+        fn test_key_moves_to_returning_owner() {
+            let config = Config {
+                leader_timeout: Duration::from_millis(50),
+                snapshot_chunk_size: 1,
+                ..Default::default()
+            };
+            let n1 = init("n1", config.clone());
+            let n2 = init("n2", config);
+            let key = (0..)
+                .map(|i| format!("k{}", i))
+                .find(|key| !n1.is_leader(key))
+                .unwrap();
+            for i in 0..3 {
+                n1.log(&key).lock().unwrap().push(Value::from(i));
+            }
+
+            // n2 goes quiet, so n1 takes the key over, then comes back and gets it returned
+            std::thread::sleep(Duration::from_millis(60));
+            n1.tick();
+            assert!(n1.is_leader(&key));
+            n1.handle_message(Message {
+                src: "n2".into(),
+                dest: "n1".into(),
+                body: Body::Heartbeat { msg_id: 1 },
+            });
+            let mut chunks: Vec<Message> = n1
+                .tick()
+                .into_iter()
+                .filter(|message| matches!(message.body, Body::SnapshotChunk { .. }))
+                .collect();
+            assert_eq!(chunks.len(), 3);
+
+            // Sends that reach n2 mid-transfer wait for it instead of reusing offsets
+            n2.handle_message(chunks.remove(0));
+            let send = Message {
+                src: "c1".into(),
+                dest: "n2".into(),
+                body: Body::Send {
+                    msg_id: 2,
+                    key: key.clone(),
+                    msg: Value::from(3),
+                },
+            };
+            assert!(n2.handle_message(send).is_empty());
+            let replies = route(&[&n1, &n2], chunks);
+            assert!(matches!(
+                replies
+                    .iter()
+                    .find(|reply| reply.dest == "c1")
+                    .unwrap()
+                    .body,
+                Body::SendOk { offset: 3, .. }
+            ));
+        }
+    }
+}
@@ -0,0 +1,832 @@
+pub mod node {
+    use maelstrom::error::{
+        KEY_DOES_NOT_EXIST, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE, TIMEOUT,
+    };
+    use raft::{Outbox, Raft, RaftMessage, Rejected, StateMachine};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// A linearizable key/value store, like Maelstrom's lin-kv service. Every operation goes
+    /// through a Raft log, so each takes effect at a single point, when it's committed, between
+    /// its request and its reply. Nodes that aren't leading forward requests to the leader and
+    /// relay its replies. Nothing is served while a majority is unreachable.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        node_ids: Vec<String>, // Every node Maelstrom started, member or not
+        raft: Option<Raft<Store>>, // Set at init, once we know the cluster
+        forwards: HashMap<u64, Waiting>, // Requests sent to the leader, by msg_id
+        proposals: HashMap<u64, Proposed>, // Requests we put in the log, by index
+    }
+
+    /// A request in the log, waiting on it to be applied.
+    struct Proposed {
+        term: u64, // The term it went in with, which the applied entry must have too
+        waiting: Waiting,
+        reply: Option<Body>, // The reply to a membership change, which has no output
+    }
+
+    /// A client waiting on a reply to `msg_id`.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on answering
+    }
+
+    /// The values every node applies the log to.
+    #[derive(Default)]
+    struct Store {
+        values: HashMap<String, Value>, // Keyed by each key's JSON, since keys can be any JSON
+    }
+
+    /// An operation as it goes in the log.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "op")]
+    enum Op {
+        Read {
+            key: Value,
+        },
+        Write {
+            key: Value,
+            value: Value,
+        },
+        Cas {
+            key: Value,
+            from: Value,
+            to: Value,
+            create_if_not_exists: bool,
+        },
+    }
+
+    /// Tunables, read from `LIN_KV_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// How long a request waits on being committed, or on the leader to answer it if we
+        /// forwarded it, before we give up.
+        pub request_timeout: Duration,
+        /// Time between ticks, which drive Raft's timers and time out requests.
+        pub tick_interval: Duration,
+        /// How many of the nodes at init, in order, start out as members; 0 for all of them.
+        /// The rest follow the log until an add_node makes them members.
+        pub initial_members: usize,
+        pub raft: raft::Config,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                request_timeout: Duration::from_millis(1000),
+                tick_interval: Duration::from_millis(50),
+                initial_members: 0,
+                raft: raft::Config::default(),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                request_timeout: Duration::from_millis(env_or(
+                    "LIN_KV_REQUEST_TIMEOUT_MS",
+                    default.request_timeout.as_millis() as u64,
+                )),
+                tick_interval: Duration::from_millis(env_or(
+                    "LIN_KV_TICK_INTERVAL_MS",
+                    default.tick_interval.as_millis() as u64,
+                )),
+                initial_members: env_or("LIN_KV_INITIAL_MEMBERS", default.initial_members),
+                raft: raft::Config {
+                    election_timeout: Duration::from_millis(env_or(
+                        "LIN_KV_ELECTION_TIMEOUT_MS",
+                        default.raft.election_timeout.as_millis() as u64,
+                    )),
+                    heartbeat_interval: Duration::from_millis(env_or(
+                        "LIN_KV_HEARTBEAT_INTERVAL_MS",
+                        default.raft.heartbeat_interval.as_millis() as u64,
+                    )),
+                    max_batch: env_or("LIN_KV_MAX_BATCH", default.raft.max_batch),
+                    snapshot_threshold: env_or(
+                        "LIN_KV_SNAPSHOT_THRESHOLD",
+                        default.raft.snapshot_threshold,
+                    ),
+                },
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+            key: Value,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: Value,
+        },
+        Write {
+            msg_id: u64,
+            key: Value,
+            value: Value,
+        },
+        WriteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Sets `key` to `to` if it's `from`. A missing key fails, unless
+        /// `create_if_not_exists` is set, in which case it's created.
+        Cas {
+            msg_id: u64,
+            key: Value,
+            from: Value,
+            to: Value,
+            #[serde(default)]
+            create_if_not_exists: bool,
+        },
+        CasOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Makes `node` a Raft member, replying once that's committed.
+        AddNode {
+            msg_id: u64,
+            node: String,
+        },
+        AddNodeOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Takes `node` out of the Raft cluster, replying once that's committed.
+        RemoveNode {
+            msg_id: u64,
+            node: String,
+        },
+        RemoveNodeOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        #[serde(untagged)]
+        Raft(RaftMessage<Op, HashMap<String, Value>>),
+    }
+
+    impl Body {
+        /// The id of the request this replies to, if it's a reply.
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::InitOk { in_reply_to, .. }
+                | Body::ReadOk { in_reply_to, .. }
+                | Body::WriteOk { in_reply_to, .. }
+                | Body::CasOk { in_reply_to, .. }
+                | Body::AddNodeOk { in_reply_to, .. }
+                | Body::RemoveNodeOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::Init { msg_id, .. }
+                | Body::InitOk { msg_id, .. }
+                | Body::Read { msg_id, .. }
+                | Body::ReadOk { msg_id, .. }
+                | Body::Write { msg_id, .. }
+                | Body::WriteOk { msg_id, .. }
+                | Body::Cas { msg_id, .. }
+                | Body::CasOk { msg_id, .. }
+                | Body::AddNode { msg_id, .. }
+                | Body::AddNodeOk { msg_id, .. }
+                | Body::RemoveNode { msg_id, .. }
+                | Body::RemoveNodeOk { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+
+        /// The request as an operation for the log.
+        fn op(self) -> Option<Op> {
+            Some(match self {
+                Body::Read { key, .. } => Op::Read { key },
+                Body::Write { key, value, .. } => Op::Write { key, value },
+                Body::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                    ..
+                } => Op::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                },
+                _ => return None,
+            })
+        }
+    }
+
+    impl StateMachine for Store {
+        type Command = Op;
+        /// The reply, with its ids still to fill in.
+        type Output = Body;
+        type Snapshot = HashMap<String, Value>;
+
+        fn apply(&mut self, op: &Op) -> Body {
+            match op {
+                Op::Read { key } => match self.values.get(&key.to_string()) {
+                    Some(value) => Body::ReadOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        value: value.clone(),
+                    },
+                    None => Body::Error {
+                        in_reply_to: 0,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("{} doesn't exist", key),
+                    },
+                },
+                Op::Write { key, value } => {
+                    self.values.insert(key.to_string(), value.clone());
+                    Body::WriteOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    }
+                }
+                Op::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                } => match self.values.get_mut(&key.to_string()) {
+                    Some(value) if value == from => {
+                        *value = to.clone();
+                        Body::CasOk {
+                            msg_id: 0,
+                            in_reply_to: 0,
+                        }
+                    }
+                    Some(value) => Body::Error {
+                        in_reply_to: 0,
+                        code: PRECONDITION_FAILED,
+                        text: format!("expected {}, but {} is {}", from, key, value),
+                    },
+                    None if *create_if_not_exists => {
+                        self.values.insert(key.to_string(), to.clone());
+                        Body::CasOk {
+                            msg_id: 0,
+                            in_reply_to: 0,
+                        }
+                    }
+                    None => Body::Error {
+                        in_reply_to: 0,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("{} doesn't exist", key),
+                    },
+                },
+            }
+        }
+
+        fn snapshot(&self) -> HashMap<String, Value> {
+            self.values.clone()
+        }
+
+        fn restore(&mut self, snapshot: HashMap<String, Value>) {
+            self.values = snapshot;
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                node_ids: Vec::new(),
+                raft: None,
+                forwards: HashMap::new(),
+                proposals: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            let raft = self.raft.as_ref().map(|raft| {
+                json!({
+                    "term": raft.term(),
+                    "leader": raft.leader(),
+                    "nodes": raft.nodes(),
+                    "last_index": raft.last_index(),
+                    "commit_index": raft.commit_index(),
+                })
+            });
+            json!({
+                "id": self.id,
+                "raft": raft,
+                "forwards": self.forwards.len(),
+                "proposals": self.proposals.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Drives Raft's timers, and gives up on requests that have waited too long.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            let mut outbox = Vec::new();
+            if let Some(raft) = &mut self.raft {
+                raft.tick(&mut outbox);
+            }
+            self.send_raft(outbox, &mut messages);
+
+            let now = Instant::now();
+            let expired_forwards: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            let expired_proposals: Vec<u64> = self
+                .proposals
+                .iter()
+                .filter(|(_, proposed)| proposed.waiting.deadline <= now)
+                .map(|(index, _)| *index)
+                .collect();
+            let mut expired: Vec<Waiting> = expired_forwards
+                .iter()
+                .filter_map(|msg_id| self.forwards.remove(msg_id))
+                .chain(
+                    expired_proposals
+                        .iter()
+                        .filter_map(|index| self.proposals.remove(index))
+                        .map(|proposed| proposed.waiting),
+                )
+                .collect();
+            expired.sort_by_key(|waiting| waiting.msg_id);
+            for waiting in expired {
+                // It may yet be committed, or have been and only the reply was lost, so this
+                // mustn't claim the request failed
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code: TIMEOUT,
+                    text: "timed out waiting on the log".to_string(),
+                };
+                self.reply(waiting, error, &mut messages);
+            }
+            messages
+        }
+
+        /// Wraps Raft's messages for the wire, and answers the clients whose requests it
+        /// has applied since.
+        fn send_raft(&mut self, outbox: Outbox<Store>, messages: &mut Vec<Message>) {
+            for (dest, message) in outbox {
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest,
+                    body: Body::Raft(message),
+                });
+            }
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            for applied in raft.take_applied() {
+                let Some(proposed) = self.proposals.remove(&applied.index) else {
+                    continue;
+                };
+                let reply = match applied.output.or(proposed.reply) {
+                    Some(reply) if proposed.term == applied.term => reply,
+                    // Another leader's entry took its place, so it never will take effect
+                    _ => Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "lost leadership before committing".to_string(),
+                    },
+                };
+                self.reply(proposed.waiting, reply, messages);
+            }
+        }
+
+        fn reply(&mut self, waiting: Waiting, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = waiting.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: waiting.client,
+                body,
+            });
+        }
+
+        /// Proposes a client's request if we're leading, or sends it on to the leader with an
+        /// id of our own, so we can tell which client its reply is for. Requests that already
+        /// came from another node aren't forwarded again, so they can't go round in circles.
+        fn request(&mut self, src: &str, mut body: Body, messages: &mut Vec<Message>) {
+            let Some(msg_id) = body.msg_id().copied() else {
+                return;
+            };
+            let waiting = Waiting {
+                client: src.to_string(),
+                msg_id,
+                deadline: Instant::now() + self.config.request_timeout,
+            };
+            let from_node = self.node_ids.iter().any(|node| node == src);
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            let mut outbox = Vec::new();
+            let (proposed, reply) = match body.clone() {
+                Body::AddNode { node, .. } => (
+                    raft.add_node(&node, &mut outbox),
+                    Some(Body::AddNodeOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    }),
+                ),
+                Body::RemoveNode { node, .. } => (
+                    raft.remove_node(&node, &mut outbox),
+                    Some(Body::RemoveNodeOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    }),
+                ),
+                request => {
+                    let Some(op) = request.op() else {
+                        return;
+                    };
+                    (raft.propose(op, &mut outbox), None)
+                }
+            };
+            match proposed {
+                Ok(proposal) => {
+                    let proposed = Proposed {
+                        term: proposal.term,
+                        waiting,
+                        reply,
+                    };
+                    self.proposals.insert(proposal.index, proposed);
+                }
+                Err(Rejected::NoChange) => {
+                    if let Some(reply) = reply {
+                        self.reply(waiting, reply, messages);
+                    }
+                }
+                Err(Rejected::NotLeader(Some(leader))) if !from_node => {
+                    let forward_id = self.next_msg_id();
+                    if let Some(msg_id) = body.msg_id() {
+                        *msg_id = forward_id;
+                    }
+                    self.forwards.insert(forward_id, waiting);
+                    messages.push(Message {
+                        src: self.id.clone(),
+                        dest: leader,
+                        body,
+                    });
+                }
+                Err(rejected) => {
+                    let text = match rejected {
+                        Rejected::ChangePending => "another membership change is in progress",
+                        _ => "no leader to serve this",
+                    };
+                    let error = Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: text.to_string(),
+                    };
+                    self.reply(waiting, error, messages);
+                }
+            }
+            self.send_raft(outbox, messages);
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    let members = match self.config.initial_members {
+                        0 => node_ids.clone(),
+                        count => node_ids.iter().take(count).cloned().collect(),
+                    };
+                    self.raft = Some(Raft::new(
+                        node_id.clone(),
+                        members,
+                        Store::default(),
+                        self.config.raft.clone(),
+                    ));
+                    self.node_ids = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Read { .. }
+                | Body::Write { .. }
+                | Body::Cas { .. }
+                | Body::AddNode { .. }
+                | Body::RemoveNode { .. } => {
+                    self.request(src, body, outbox);
+                    None
+                }
+                Body::Raft(message) => {
+                    let mut raft_outbox = Vec::new();
+                    self.raft.as_mut()?.handle(src, message, &mut raft_outbox);
+                    self.send_raft(raft_outbox, outbox);
+                    None
+                }
+                // The leader's answer to a request we forwarded, which goes on to the client
+                mut reply => {
+                    let forward = self.forwards.remove(reply.in_reply_to()?)?;
+                    self.reply(forward, reply, outbox);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, deliver, reply, request};
+        use serde_json::json;
+
+        /// Two nodes, n1 and n2, with n1 elected leader.
+        fn cluster() -> Vec<Node> {
+            cluster_with(2, 0)
+        }
+
+        /// n1 to n`count`, with n1 elected leader.
+        fn cluster_with(count: usize, initial_members: usize) -> Vec<Node> {
+            let mut nodes: Vec<Node> = testing::cluster(count, |id| {
+                let mut config = Config {
+                    initial_members,
+                    ..Config::default()
+                };
+                if id == "n1" {
+                    config.raft.election_timeout = Duration::ZERO;
+                }
+                Node::new(config)
+            });
+            let messages = nodes[0].tick();
+            deliver(&mut nodes, messages);
+            assert!(nodes[0].raft.as_ref().unwrap().is_leader());
+            nodes
+        }
+
+        #[test]
+        fn test_read_write_cas() {
+            let mut nodes = cluster();
+            let mut send = |body| {
+                let messages = vec![request("n1", body)];
+                reply(&deliver(&mut nodes, messages))
+            };
+            assert_eq!(
+                send(json!({"type": "read", "msg_id": 1, "key": 1}))["code"],
+                KEY_DOES_NOT_EXIST
+            );
+            send(json!({"type": "write", "msg_id": 2, "key": 1, "value": 3}));
+            assert_eq!(
+                send(json!({"type": "cas", "msg_id": 3, "key": 1, "from": 4, "to": 5}))["code"],
+                PRECONDITION_FAILED
+            );
+            assert_eq!(
+                send(json!({"type": "cas", "msg_id": 4, "key": 1, "from": 3, "to": 5}))["type"],
+                "cas_ok"
+            );
+            assert_eq!(
+                send(json!({"type": "read", "msg_id": 5, "key": 1}))["value"],
+                5
+            );
+            let created = json!({
+                "type": "cas", "msg_id": 6, "key": 2, "from": 0, "to": 1,
+                "create_if_not_exists": true
+            });
+            assert_eq!(send(created)["type"], "cas_ok");
+        }
+
+        #[test]
+        fn test_requests_are_forwarded_to_the_leader() {
+            let mut nodes = cluster();
+            let write = json!({"type": "write", "msg_id": 7, "key": "k", "value": [1]});
+            let relayed = deliver(&mut nodes, vec![request("n2", write)]);
+            assert_eq!(relayed.len(), 1);
+            assert_eq!(reply(&relayed)["type"], "write_ok");
+            assert_eq!(reply(&relayed)["in_reply_to"], 7);
+            let store = nodes[0].raft.as_ref().unwrap().state();
+            assert_eq!(store.values["\"k\""], json!([1]));
+        }
+
+        #[test]
+        fn test_nodes_are_added_and_removed() {
+            let mut nodes = cluster_with(3, 2);
+            let add = json!({"type": "add_node", "msg_id": 7, "node": "n3"});
+            let added = deliver(&mut nodes, vec![request("n2", add)]);
+            assert_eq!(reply(&added)["type"], "add_node_ok");
+            assert_eq!(reply(&added)["in_reply_to"], 7);
+            for node in &nodes {
+                assert_eq!(node.raft.as_ref().unwrap().nodes(), ["n1", "n2", "n3"]);
+            }
+
+            let remove = json!({"type": "remove_node", "msg_id": 8, "node": "n2"});
+            let removed = deliver(&mut nodes, vec![request("n1", remove)]);
+            assert_eq!(reply(&removed)["type"], "remove_node_ok");
+            assert_eq!(nodes[0].raft.as_ref().unwrap().nodes(), ["n1", "n3"]);
+        }
+
+        #[test]
+        fn test_unanswered_requests_time_out() {
+            let mut nodes = cluster();
+            nodes[1].config.request_timeout = Duration::ZERO;
+            let read = json!({"type": "read", "msg_id": 7, "key": 1});
+            let forwarded = nodes[1].handle_message(request("n2", read));
+            assert_eq!(forwarded[0].dest, "n1");
+            let timed_out: Vec<Message> = nodes[1]
+                .tick()
+                .into_iter()
+                .filter(|message| message.dest == "c1")
+                .collect();
+            assert_eq!(reply(&timed_out)["code"], TIMEOUT);
+            assert_eq!(reply(&timed_out)["in_reply_to"], 7);
+        }
+
+        #[test]
+        fn test_requests_fail_without_a_leader() {
+            let mut node = Node::new(Config::default());
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest: "n2".into(),
+                body: Body::Init {
+                    msg_id: 1,
+                    node_id: "n2".into(),
+                    node_ids: vec!["n1".into(), "n2".into()],
+                },
+            });
+            let read = json!({"type": "read", "msg_id": 7, "key": 1});
+            let failed = node.handle_message(request("n2", read));
+            assert_eq!(reply(&failed)["code"], TEMPORARILY_UNAVAILABLE);
+        }
+
+        #[test]
+        fn test_raft_messages_share_the_body() {
+            let message: Message = request(
+                "n2",
+                json!({"type": "request_vote_ok", "term": 1, "vote_granted": true}),
+            );
+            assert!(matches!(message.body, Body::Raft(_)));
+        }
+
+        #[test]
+        fn test_concurrent_history_is_linearizable() {
+            use rand::rngs::StdRng;
+            use rand::{Rng, SeedableRng};
+            use runner::{linearizability, History, Kind, LinKv, Workload};
+
+            let mut nodes = cluster_with(3, 0);
+            let mut rng = StdRng::seed_from_u64(3);
+            let mut workload = LinKv { keys: 2, values: 3 };
+            let mut history = History::default();
+            // Each client's operation in flight, if any, with its msg_id
+            let mut clients: Vec<Option<(u64, runner::Op)>> = vec![None; 4];
+            let mut in_flight: Vec<Message> = Vec::new();
+            for step in 0..3000u64 {
+                if step % 50 == 0 {
+                    in_flight.extend(nodes[0].tick());
+                }
+                let client = rng.gen_range(0..clients.len());
+                if clients[client].is_none() && step < 2500 {
+                    let op = workload.invoke(&mut rng);
+                    history.record(client as u64, Kind::Invoke, &op.f, op.value.clone());
+                    let mut body = op.body.clone();
+                    body["msg_id"] = json!(step);
+                    let dest = format!("n{}", rng.gen_range(1..=nodes.len()));
+                    in_flight.push(
+                        serde_json::from_value(
+                            json!({"src": format!("c{}", client), "dest": dest, "body": body}),
+                        )
+                        .unwrap(),
+                    );
+                    clients[client] = Some((step, op));
+                }
+                // Messages arrive in any order
+                if in_flight.is_empty() {
+                    continue;
+                }
+                let message = in_flight.swap_remove(rng.gen_range(0..in_flight.len()));
+                if let Some(n) = message.dest.strip_prefix('n') {
+                    let n: usize = n.parse().unwrap();
+                    in_flight.extend(nodes[n - 1].handle_message(message));
+                    continue;
+                }
+                let client: usize = message.dest[1..].parse().unwrap();
+                let reply = serde_json::to_value(&message.body).unwrap();
+                let (msg_id, op) = clients[client].take().unwrap();
+                assert_eq!(reply["in_reply_to"], msg_id);
+                let (kind, value) = workload.complete(&op, &reply);
+                history.record(client as u64, kind, &op.f, value);
+            }
+
+            let completed = history.events().iter().filter(|e| e.kind == Kind::Ok);
+            assert!(completed.count() > 100);
+            assert_eq!(linearizability::check_kv(&history), Ok(()));
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
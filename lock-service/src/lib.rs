@@ -0,0 +1,713 @@
+pub mod node {
+    use maelstrom::error::{PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE, TIMEOUT};
+    use raft::{Outbox, Raft, RaftMessage, Rejected, StateMachine};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    /// A lock service: clients acquire named locks as leases, which lapse unless renewed, so
+    /// a client that crashes holding one only keeps it out of reach until its lease is up.
+    /// Each grant comes with a fencing token, larger than any granted before it, for whatever
+    /// the lock guards to refuse writes from a holder whose lease has since lapsed. Every
+    /// operation goes through a Raft log, with the leader's clock stamped on it, so every
+    /// node agrees on when each lease ran out. Nodes that aren't leading forward requests to
+    /// the leader and relay its replies.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        node_ids: Vec<String>,
+        raft: Option<Raft<Locks>>, // Set at init, once we know the cluster
+        forwards: HashMap<u64, Waiting>, // Requests sent to the leader, by msg_id
+        proposals: HashMap<u64, Proposed>, // Requests we put in the log, by index
+    }
+
+    /// A request in the log, waiting on it to be applied.
+    struct Proposed {
+        term: u64, // The term it went in with, which the applied entry must have too
+        waiting: Waiting,
+    }
+
+    /// A client waiting on a reply to `msg_id`.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on answering
+    }
+
+    /// A lock's current holder.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Lease {
+        token: u64,
+        expires: u64, // By the log's clock
+    }
+
+    /// The leases every node applies the log to.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+    struct Locks {
+        leases: HashMap<String, Lease>, // Expired ones linger until the lock is next acquired
+        last_token: u64,
+        now: u64, // The latest time stamped on an applied op, which never goes backwards
+    }
+
+    /// An operation as it goes in the log, stamped with the proposing leader's clock.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "op")]
+    enum Op {
+        Acquire {
+            lock: String,
+            ttl: u64,
+            now: u64,
+        },
+        Renew {
+            lock: String,
+            token: u64,
+            ttl: u64,
+            now: u64,
+        },
+        Release {
+            lock: String,
+            token: u64,
+            now: u64,
+        },
+    }
+
+    /// Tunables, read from `LOCK_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// How long a lease lasts when the request doesn't say.
+        pub lease_ttl: Duration,
+        /// How long a request waits on being committed, or on the leader to answer it if we
+        /// forwarded it, before we give up.
+        pub request_timeout: Duration,
+        /// Time between ticks, which drive Raft's timers and time out requests.
+        pub tick_interval: Duration,
+        pub raft: raft::Config,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                lease_ttl: Duration::from_millis(5000),
+                request_timeout: Duration::from_millis(1000),
+                tick_interval: Duration::from_millis(50),
+                raft: raft::Config::default(),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                lease_ttl: Duration::from_millis(env_or(
+                    "LOCK_LEASE_TTL_MS",
+                    default.lease_ttl.as_millis() as u64,
+                )),
+                request_timeout: Duration::from_millis(env_or(
+                    "LOCK_REQUEST_TIMEOUT_MS",
+                    default.request_timeout.as_millis() as u64,
+                )),
+                tick_interval: Duration::from_millis(env_or(
+                    "LOCK_TICK_INTERVAL_MS",
+                    default.tick_interval.as_millis() as u64,
+                )),
+                raft: raft::Config {
+                    election_timeout: Duration::from_millis(env_or(
+                        "LOCK_ELECTION_TIMEOUT_MS",
+                        default.raft.election_timeout.as_millis() as u64,
+                    )),
+                    heartbeat_interval: Duration::from_millis(env_or(
+                        "LOCK_HEARTBEAT_INTERVAL_MS",
+                        default.raft.heartbeat_interval.as_millis() as u64,
+                    )),
+                    max_batch: env_or("LOCK_MAX_BATCH", default.raft.max_batch),
+                    snapshot_threshold: env_or(
+                        "LOCK_SNAPSHOT_THRESHOLD",
+                        default.raft.snapshot_threshold,
+                    ),
+                },
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// Milliseconds since the Unix epoch, by this machine's clock.
+    fn wall_clock() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Takes `lock` for `ttl` milliseconds, if no one else's lease on it is current.
+        Acquire {
+            msg_id: u64,
+            lock: String,
+            #[serde(default)]
+            ttl: Option<u64>,
+        },
+        AcquireOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            token: u64,
+        },
+        /// Extends the lease `token` was granted with to `ttl` milliseconds from now, if it
+        /// hasn't already lapsed.
+        Renew {
+            msg_id: u64,
+            lock: String,
+            token: u64,
+            #[serde(default)]
+            ttl: Option<u64>,
+        },
+        RenewOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Gives up the lease `token` was granted with.
+        Release {
+            msg_id: u64,
+            lock: String,
+            token: u64,
+        },
+        ReleaseOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        #[serde(untagged)]
+        Raft(RaftMessage<Op, Locks>),
+    }
+
+    impl Body {
+        /// The id of the request this replies to, if it's a reply.
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::InitOk { in_reply_to, .. }
+                | Body::AcquireOk { in_reply_to, .. }
+                | Body::RenewOk { in_reply_to, .. }
+                | Body::ReleaseOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::Init { msg_id, .. }
+                | Body::InitOk { msg_id, .. }
+                | Body::Acquire { msg_id, .. }
+                | Body::AcquireOk { msg_id, .. }
+                | Body::Renew { msg_id, .. }
+                | Body::RenewOk { msg_id, .. }
+                | Body::Release { msg_id, .. }
+                | Body::ReleaseOk { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+
+        /// The request as an operation for the log, made at `now`, with leases lasting
+        /// `default_ttl` unless it says otherwise.
+        fn op(self, now: u64, default_ttl: u64) -> Option<Op> {
+            Some(match self {
+                Body::Acquire { lock, ttl, .. } => Op::Acquire {
+                    lock,
+                    ttl: ttl.unwrap_or(default_ttl),
+                    now,
+                },
+                Body::Renew {
+                    lock, token, ttl, ..
+                } => Op::Renew {
+                    lock,
+                    token,
+                    ttl: ttl.unwrap_or(default_ttl),
+                    now,
+                },
+                Body::Release { lock, token, .. } => Op::Release { lock, token, now },
+                _ => return None,
+            })
+        }
+    }
+
+    impl Locks {
+        /// `lock`'s lease, if it's held by `token` and hasn't lapsed.
+        fn held(&mut self, lock: &str, token: u64) -> Result<&mut Lease, Body> {
+            match self.leases.get_mut(lock) {
+                Some(lease) if lease.token == token && lease.expires > self.now => Ok(lease),
+                _ => Err(Body::Error {
+                    in_reply_to: 0,
+                    code: PRECONDITION_FAILED,
+                    text: format!("{} isn't held with token {}", lock, token),
+                }),
+            }
+        }
+    }
+
+    impl StateMachine for Locks {
+        type Command = Op;
+        /// The reply, with its ids still to fill in.
+        type Output = Body;
+        type Snapshot = Locks;
+
+        fn apply(&mut self, op: &Op) -> Body {
+            let (Op::Acquire { now, .. } | Op::Renew { now, .. } | Op::Release { now, .. }) = op;
+            // A new leader's clock may be behind the last one's, but leases mustn't come back
+            self.now = self.now.max(*now);
+            match op {
+                Op::Acquire { lock, ttl, .. } => {
+                    if let Some(lease) = self.leases.get(lock) {
+                        if lease.expires > self.now {
+                            return Body::Error {
+                                in_reply_to: 0,
+                                code: PRECONDITION_FAILED,
+                                text: format!("{} is held with token {}", lock, lease.token),
+                            };
+                        }
+                    }
+                    self.last_token += 1;
+                    let lease = Lease {
+                        token: self.last_token,
+                        expires: self.now + ttl,
+                    };
+                    self.leases.insert(lock.clone(), lease);
+                    Body::AcquireOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        token: self.last_token,
+                    }
+                }
+                Op::Renew {
+                    lock, token, ttl, ..
+                } => {
+                    let now = self.now;
+                    match self.held(lock, *token) {
+                        Ok(lease) => {
+                            lease.expires = now + ttl;
+                            Body::RenewOk {
+                                msg_id: 0,
+                                in_reply_to: 0,
+                            }
+                        }
+                        Err(error) => error,
+                    }
+                }
+                Op::Release { lock, token, .. } => match self.held(lock, *token) {
+                    Ok(_) => {
+                        self.leases.remove(lock);
+                        Body::ReleaseOk {
+                            msg_id: 0,
+                            in_reply_to: 0,
+                        }
+                    }
+                    Err(error) => error,
+                },
+            }
+        }
+
+        fn snapshot(&self) -> Locks {
+            self.clone()
+        }
+
+        fn restore(&mut self, snapshot: Locks) {
+            *self = snapshot;
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                node_ids: Vec::new(),
+                raft: None,
+                forwards: HashMap::new(),
+                proposals: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            let raft = self.raft.as_ref().map(|raft| {
+                json!({
+                    "term": raft.term(),
+                    "leader": raft.leader(),
+                    "nodes": raft.nodes(),
+                    "last_index": raft.last_index(),
+                    "commit_index": raft.commit_index(),
+                })
+            });
+            json!({
+                "id": self.id,
+                "raft": raft,
+                "forwards": self.forwards.len(),
+                "proposals": self.proposals.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Drives Raft's timers, and gives up on requests that have waited too long.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            let mut outbox = Vec::new();
+            if let Some(raft) = &mut self.raft {
+                raft.tick(&mut outbox);
+            }
+            self.send_raft(outbox, &mut messages);
+
+            let now = Instant::now();
+            let expired_forwards: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            let expired_proposals: Vec<u64> = self
+                .proposals
+                .iter()
+                .filter(|(_, proposed)| proposed.waiting.deadline <= now)
+                .map(|(index, _)| *index)
+                .collect();
+            let mut expired: Vec<Waiting> = expired_forwards
+                .iter()
+                .filter_map(|msg_id| self.forwards.remove(msg_id))
+                .chain(
+                    expired_proposals
+                        .iter()
+                        .filter_map(|index| self.proposals.remove(index))
+                        .map(|proposed| proposed.waiting),
+                )
+                .collect();
+            expired.sort_by_key(|waiting| waiting.msg_id);
+            for waiting in expired {
+                // It may yet be committed, or have been and only the reply was lost, so this
+                // mustn't claim the request failed
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code: TIMEOUT,
+                    text: "timed out waiting on the log".to_string(),
+                };
+                self.reply(waiting, error, &mut messages);
+            }
+            messages
+        }
+
+        /// Wraps Raft's messages for the wire, and answers the clients whose requests it
+        /// has applied since.
+        fn send_raft(&mut self, outbox: Outbox<Locks>, messages: &mut Vec<Message>) {
+            for (dest, message) in outbox {
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest,
+                    body: Body::Raft(message),
+                });
+            }
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            for applied in raft.take_applied() {
+                let Some(proposed) = self.proposals.remove(&applied.index) else {
+                    continue;
+                };
+                let reply = match applied.output {
+                    Some(reply) if proposed.term == applied.term => reply,
+                    // Another leader's entry took its place, so it never will take effect
+                    _ => Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "lost leadership before committing".to_string(),
+                    },
+                };
+                self.reply(proposed.waiting, reply, messages);
+            }
+        }
+
+        fn reply(&mut self, waiting: Waiting, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = waiting.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: waiting.client,
+                body,
+            });
+        }
+
+        /// Proposes a client's request if we're leading, or sends it on to the leader with an
+        /// id of our own, so we can tell which client its reply is for. Requests that already
+        /// came from another node aren't forwarded again, so they can't go round in circles.
+        fn request(&mut self, src: &str, mut body: Body, messages: &mut Vec<Message>) {
+            let Some(msg_id) = body.msg_id().copied() else {
+                return;
+            };
+            let waiting = Waiting {
+                client: src.to_string(),
+                msg_id,
+                deadline: Instant::now() + self.config.request_timeout,
+            };
+            let from_node = self.node_ids.iter().any(|node| node == src);
+            let default_ttl = self.config.lease_ttl.as_millis() as u64;
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            let Some(op) = body.clone().op(wall_clock(), default_ttl) else {
+                return;
+            };
+            let mut outbox = Vec::new();
+            match raft.propose(op, &mut outbox) {
+                Ok(proposal) => {
+                    let proposed = Proposed {
+                        term: proposal.term,
+                        waiting,
+                    };
+                    self.proposals.insert(proposal.index, proposed);
+                }
+                Err(Rejected::NotLeader(Some(leader))) if !from_node => {
+                    let forward_id = self.next_msg_id();
+                    if let Some(msg_id) = body.msg_id() {
+                        *msg_id = forward_id;
+                    }
+                    self.forwards.insert(forward_id, waiting);
+                    messages.push(Message {
+                        src: self.id.clone(),
+                        dest: leader,
+                        body,
+                    });
+                }
+                Err(_) => {
+                    let error = Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "no leader to serve this".to_string(),
+                    };
+                    self.reply(waiting, error, messages);
+                }
+            }
+            self.send_raft(outbox, messages);
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.raft = Some(Raft::new(
+                        node_id.clone(),
+                        node_ids.clone(),
+                        Locks::default(),
+                        self.config.raft.clone(),
+                    ));
+                    self.node_ids = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Acquire { .. } | Body::Renew { .. } | Body::Release { .. } => {
+                    self.request(src, body, outbox);
+                    None
+                }
+                Body::Raft(message) => {
+                    let mut raft_outbox = Vec::new();
+                    self.raft.as_mut()?.handle(src, message, &mut raft_outbox);
+                    self.send_raft(raft_outbox, outbox);
+                    None
+                }
+                // The leader's answer to a request we forwarded, which goes on to the client
+                mut reply => {
+                    let forward = self.forwards.remove(reply.in_reply_to()?)?;
+                    self.reply(forward, reply, outbox);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, deliver};
+        use serde_json::json;
+
+        /// Two nodes, n1 and n2, with n1 elected leader.
+        fn cluster() -> Vec<Node> {
+            let mut nodes: Vec<Node> = testing::cluster(2, |id| {
+                let mut config = Config::default();
+                if id == "n1" {
+                    config.raft.election_timeout = Duration::ZERO;
+                }
+                Node::new(config)
+            });
+            let messages = nodes[0].tick();
+            deliver(&mut nodes, messages);
+            assert!(nodes[0].raft.as_ref().unwrap().is_leader());
+            nodes
+        }
+
+        /// Sends `body` to `dest` from a client, and returns the reply.
+        fn send(nodes: &mut [Node], dest: &str, body: serde_json::Value) -> serde_json::Value {
+            let message = json!({"src": "c1", "dest": dest, "body": body});
+            let replies = deliver(nodes, vec![serde_json::from_value(message).unwrap()]);
+            serde_json::to_value(&replies[0].body).unwrap()
+        }
+
+        #[test]
+        fn test_locks_are_held_until_released() {
+            let mut nodes = cluster();
+            let acquire = json!({"type": "acquire", "msg_id": 1, "lock": "a"});
+            let granted = send(&mut nodes, "n1", acquire.clone());
+            assert_eq!(granted["type"], "acquire_ok");
+            // Forwarded from n2, it reaches the same log and finds the lock taken
+            assert_eq!(
+                send(&mut nodes, "n2", acquire.clone())["code"],
+                PRECONDITION_FAILED
+            );
+
+            let token = granted["token"].clone();
+            let renew = json!({"type": "renew", "msg_id": 2, "lock": "a", "token": token});
+            assert_eq!(send(&mut nodes, "n2", renew)["type"], "renew_ok");
+            let release = json!({"type": "release", "msg_id": 3, "lock": "a", "token": token});
+            assert_eq!(
+                send(&mut nodes, "n1", release.clone())["type"],
+                "release_ok"
+            );
+            assert_eq!(send(&mut nodes, "n1", release)["code"], PRECONDITION_FAILED);
+
+            // The next holder's token fences off the last one's
+            let regranted = send(&mut nodes, "n1", acquire);
+            assert!(regranted["token"].as_u64() > token.as_u64());
+        }
+
+        #[test]
+        fn test_lapsed_leases_are_taken_over() {
+            let mut nodes = cluster();
+            // A holder that crashed, and so never renews
+            let lapsing = json!({"type": "acquire", "msg_id": 1, "lock": "a", "ttl": 0});
+            let crashed = send(&mut nodes, "n1", lapsing);
+            let acquire = json!({"type": "acquire", "msg_id": 2, "lock": "a"});
+            let granted = send(&mut nodes, "n1", acquire);
+            assert_eq!(granted["type"], "acquire_ok");
+
+            // The crashed holder, come back, can't renew or release what it's lost
+            let token = crashed["token"].clone();
+            let renew = json!({"type": "renew", "msg_id": 3, "lock": "a", "token": token});
+            assert_eq!(send(&mut nodes, "n1", renew)["code"], PRECONDITION_FAILED);
+            let release = json!({"type": "release", "msg_id": 4, "lock": "a", "token": token});
+            assert_eq!(send(&mut nodes, "n1", release)["code"], PRECONDITION_FAILED);
+        }
+
+        #[test]
+        fn test_the_log_clock_never_goes_backwards() {
+            let mut locks = Locks::default();
+            let acquire = |now| Op::Acquire {
+                lock: "a".into(),
+                ttl: 10,
+                now,
+            };
+            assert!(matches!(locks.apply(&acquire(100)), Body::AcquireOk { .. }));
+            // A leader whose clock is behind doesn't bring the lease back from the past
+            assert!(matches!(locks.apply(&acquire(50)), Body::Error { .. }));
+            assert!(matches!(locks.apply(&acquire(110)), Body::AcquireOk { .. }));
+            assert_eq!(locks.now, 110);
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,494 @@
+//! Logging for the nodes. Logs go to stderr unless `NODE_LOG_DIR` is set, in which case each
+//! node writes them as JSON lines to `<dir>/<node id>.log` instead, keeping Maelstrom's
+//! stderr capture free of debug noise. The file is written on a thread of its own, so a
+//! chatty log level doesn't hold up message handling, and rotated to `<node id>.log.1`,
+//! `.2` and so on once it reaches `NODE_LOG_MAX_BYTES`, keeping `NODE_LOG_MAX_FILES` rotated
+//! files besides the one being written.
+//!
+//! The level comes from `RUST_LOG` either way, and defaults to everything. Call `shutdown`
+//! before exiting so nothing buffered is lost; on SIGTERM it's called for you.
+//!
+//! With the `otel` feature, `timed` also exports a span and duration metrics over OTLP when
+//! `OTEL_EXPORTER_OTLP_ENDPOINT` is set. Without it, `timed` just runs what it's given.
+
+use log::{LevelFilter, Log, Metadata, Record, SetLoggerError};
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, BufWriter, Write};
+use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::mpsc::{self, Receiver, Sender, SyncSender};
+use std::sync::OnceLock;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+/// Lines logged before the node knows its id, held until its file can be opened.
+const MAX_PENDING: usize = 10_000;
+/// How much of an unparsable line to log; a runaway payload can be huge.
+const MAX_PAYLOAD: usize = 1024;
+
+static NODE: OnceLock<String> = OnceLock::new();
+static FILE_LOGGER: OnceLock<FileLogger> = OnceLock::new();
+
+/// Installs the logger the environment asks for.
+pub fn init() -> Result<(), SetLoggerError> {
+    match std::env::var_os("NODE_LOG_DIR") {
+        Some(dir) => init_file(PathBuf::from(dir))?,
+        None => simple_logger::SimpleLogger::new().env().init()?,
+    }
+    #[cfg(feature = "otel")]
+    otel::init();
+    // Maelstrom stops nodes with SIGTERM, which would otherwise lose what's still buffered
+    match signal_hook::iterator::Signals::new([signal_hook::consts::SIGTERM]) {
+        Ok(mut signals) => {
+            std::thread::spawn(move || {
+                if signals.forever().next().is_some() {
+                    shutdown();
+                    std::process::exit(128 + signal_hook::consts::SIGTERM);
+                }
+            });
+        }
+        Err(e) => log::warn!("Unable to handle SIGTERM: {}", e),
+    }
+    Ok(())
+}
+
+fn init_file(dir: PathBuf) -> Result<(), SetLoggerError> {
+    let level = env_or("RUST_LOG", LevelFilter::Trace);
+    let writer = Writer {
+        dir,
+        max_bytes: env_or("NODE_LOG_MAX_BYTES", 16 * 1024 * 1024),
+        max_files: env_or("NODE_LOG_MAX_FILES", 4),
+        file: None,
+        pending: Vec::new(),
+    };
+    let (sender, receiver) = mpsc::channel();
+    std::thread::spawn(move || writer.run(receiver));
+    let logger = FILE_LOGGER.get_or_init(|| FileLogger { level, sender });
+    log::set_logger(logger)?;
+    log::set_max_level(level);
+    Ok(())
+}
+
+/// Names the node's log file and tags its spans, once init has told it who it is. Only the
+/// first call counts.
+pub fn set_node(id: &str) {
+    if NODE.set(id.to_string()).is_ok() {
+        if let Some(logger) = FILE_LOGGER.get() {
+            let _ = logger.sender.send(Command::Open(id.to_string()));
+        }
+    }
+}
+
+/// Flushes the logs and whatever spans and metrics haven't been exported yet. Call it before
+/// exiting; the exporters batch, so anything recorded since their last export is lost otherwise.
+/// `init` already has it run on SIGTERM.
+pub fn shutdown() {
+    #[cfg(feature = "otel")]
+    otel::shutdown();
+    log::logger().flush();
+}
+
+#[cfg(feature = "otel")]
+pub use otel::timed;
+
+/// Runs `f`; exporting it as a span named `name` takes the `otel` feature.
+#[cfg(not(feature = "otel"))]
+#[inline]
+pub fn timed<T>(_name: &'static str, f: impl FnOnce() -> T) -> T {
+    f()
+}
+
+/// Logs a line from stdin that didn't parse as a message, and returns a malformed-request
+/// error to send back for it, if it's JSON enough to say who sent it and which message it was.
+/// The line needn't be UTF-8; invalid bytes are logged, and parsed, as replacement characters.
+pub fn malformed(line: &[u8], error: &serde_json::Error) -> Option<String> {
+    let line = String::from_utf8_lossy(line);
+    let mut payload = line.trim_end();
+    if payload.len() > MAX_PAYLOAD {
+        let mut end = MAX_PAYLOAD;
+        while !payload.is_char_boundary(end) {
+            end -= 1;
+        }
+        payload = &payload[..end];
+    }
+    log::error!("Unable to parse {}: {}", payload, error);
+
+    let message: serde_json::Value = serde_json::from_str(&line).ok()?;
+    let (src, msg_id) = (
+        message["src"].as_str()?,
+        message["body"]["msg_id"].as_u64()?,
+    );
+    let dest = message["dest"]
+        .as_str()
+        .or(NODE.get().map(String::as_str))?;
+    let reply = serde_json::json!({
+        "src": dest,
+        "dest": src,
+        "body": {
+            "type": "error",
+            "in_reply_to": msg_id,
+            "code": 12,
+            "text": error.to_string(),
+        },
+    });
+    Some(reply.to_string())
+}
+
+fn env_or<T: FromStr>(name: &str, default: T) -> T {
+    std::env::var(name)
+        .ok()
+        .and_then(|val| val.parse().ok())
+        .unwrap_or(default)
+}
+
+enum Command {
+    Line(String),
+    Open(String),
+    Flush(SyncSender<()>),
+}
+
+struct FileLogger {
+    level: LevelFilter,
+    sender: Sender<Command>,
+}
+
+impl Log for FileLogger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let line = serde_json::json!({
+            "time": time.as_secs_f64(),
+            "level": record.level(),
+            "node": NODE.get(),
+            "target": record.target(),
+            "message": record.args().to_string(),
+        });
+        let _ = self.sender.send(Command::Line(line.to_string()));
+    }
+
+    fn flush(&self) {
+        let (done, wait) = mpsc::sync_channel(0);
+        if self.sender.send(Command::Flush(done)).is_ok() {
+            let _ = wait.recv();
+        }
+    }
+}
+
+/// Owns the log file on the logging thread.
+struct Writer {
+    dir: PathBuf,
+    max_bytes: u64,
+    max_files: usize,
+    file: Option<(String, BufWriter<File>, u64)>, // Node, file, bytes written to it
+    pending: Vec<String>,
+}
+
+impl Writer {
+    fn run(mut self, receiver: Receiver<Command>) {
+        while let Ok(command) = receiver.recv() {
+            // Write everything that's queued up before paying for a flush
+            for command in std::iter::once(command).chain(receiver.try_iter()) {
+                if let Err(e) = self.handle(command) {
+                    eprintln!("Unable to write log: {}", e);
+                }
+            }
+            if let Some((_, file, _)) = &mut self.file {
+                let _ = file.flush();
+            }
+        }
+    }
+
+    fn handle(&mut self, command: Command) -> io::Result<()> {
+        match command {
+            Command::Line(line) => self.write(line),
+            Command::Open(node) => {
+                fs::create_dir_all(&self.dir)?;
+                let file = OpenOptions::new()
+                    .create(true)
+                    .append(true)
+                    .open(self.path(&node, 0))?;
+                let len = file.metadata()?.len();
+                self.file = Some((node, BufWriter::new(file), len));
+                for line in std::mem::take(&mut self.pending) {
+                    self.write(line)?;
+                }
+                Ok(())
+            }
+            Command::Flush(done) => {
+                if let Some((_, file, _)) = &mut self.file {
+                    file.flush()?;
+                }
+                let _ = done.send(());
+                Ok(())
+            }
+        }
+    }
+
+    fn write(&mut self, line: String) -> io::Result<()> {
+        let Some((_, file, len)) = &mut self.file else {
+            if self.pending.len() < MAX_PENDING {
+                self.pending.push(line);
+            }
+            return Ok(());
+        };
+        if *len > 0 && *len + line.len() as u64 + 1 > self.max_bytes {
+            if let Err(e) = self.rotate() {
+                eprintln!("Unable to rotate log: {}", e);
+            }
+            return self.write(line);
+        }
+        file.write_all(line.as_bytes())?;
+        file.write_all(b"\n")?;
+        *len += line.len() as u64 + 1;
+        Ok(())
+    }
+
+    /// Shifts each file up one, dropping the oldest, and starts a fresh one. If that fails
+    /// partway, the file being written is kept on so nothing is lost, and rotation is tried
+    /// again once another `max_bytes` has been written to it.
+    fn rotate(&mut self) -> io::Result<()> {
+        let Some((node, file, len)) = &mut self.file else {
+            return Ok(());
+        };
+        *len = 0;
+        file.flush()?;
+        let node = node.clone();
+        for i in (0..self.max_files).rev() {
+            let from = self.path(&node, i);
+            if from.exists() {
+                fs::rename(from, self.path(&node, i + 1))?;
+            }
+        }
+        if self.max_files == 0 {
+            let _ = fs::remove_file(self.path(&node, 0));
+        }
+        let file = File::create(self.path(&node, 0))?;
+        self.file = Some((node, BufWriter::new(file), 0));
+        Ok(())
+    }
+
+    /// The `n`th most recent file, where 0 is the one being written.
+    fn path(&self, node: &str, n: usize) -> PathBuf {
+        match n {
+            0 => self.dir.join(format!("{}.log", node)),
+            n => self.dir.join(format!("{}.log.{}", node, n)),
+        }
+    }
+}
+
+/// OTLP export over HTTP. Beyond the endpoint, the exporters read the standard `OTEL_*`
+/// variables themselves, `OTEL_SERVICE_NAME` included; the service is named after the binary
+/// otherwise.
+#[cfg(feature = "otel")]
+mod otel {
+    use opentelemetry::metrics::{Counter, Histogram};
+    use opentelemetry::trace::{Span, Tracer};
+    use opentelemetry::{global, KeyValue};
+    use opentelemetry_otlp::{MetricExporter, SpanExporter};
+    use opentelemetry_sdk::metrics::SdkMeterProvider;
+    use opentelemetry_sdk::trace::SdkTracerProvider;
+    use opentelemetry_sdk::Resource;
+    use std::sync::OnceLock;
+    use std::time::Instant;
+
+    const SCOPE: &str = "fly.io-rust";
+
+    struct Instruments {
+        handled: Counter<u64>,
+        duration: Histogram<f64>,
+    }
+
+    // Only set when exporting, so `timed` costs nothing more than a load otherwise
+    static INSTRUMENTS: OnceLock<Instruments> = OnceLock::new();
+    // Kept to shut down; the global handles can't be
+    static TRACER_PROVIDER: OnceLock<SdkTracerProvider> = OnceLock::new();
+    static METER_PROVIDER: OnceLock<SdkMeterProvider> = OnceLock::new();
+
+    pub fn init() {
+        if std::env::var_os("OTEL_EXPORTER_OTLP_ENDPOINT").is_none() {
+            return;
+        }
+        let mut resource = Resource::builder();
+        if std::env::var_os("OTEL_SERVICE_NAME").is_none() {
+            let binary = std::env::current_exe()
+                .ok()
+                .and_then(|path| Some(path.file_stem()?.to_string_lossy().into_owned()));
+            resource = resource.with_service_name(binary.unwrap_or_else(|| SCOPE.into()));
+        }
+        let resource = resource.build();
+
+        match SpanExporter::builder().with_http().build() {
+            Ok(exporter) => {
+                let provider = SdkTracerProvider::builder()
+                    .with_batch_exporter(exporter)
+                    .with_resource(resource.clone())
+                    .build();
+                global::set_tracer_provider(provider.clone());
+                let _ = TRACER_PROVIDER.set(provider);
+            }
+            Err(e) => log::warn!("Unable to export spans: {}", e),
+        }
+        match MetricExporter::builder().with_http().build() {
+            Ok(exporter) => {
+                let provider = SdkMeterProvider::builder()
+                    .with_periodic_exporter(exporter)
+                    .with_resource(resource)
+                    .build();
+                global::set_meter_provider(provider.clone());
+                let _ = METER_PROVIDER.set(provider);
+            }
+            Err(e) => log::warn!("Unable to export metrics: {}", e),
+        }
+        let meter = global::meter(SCOPE);
+        let _ = INSTRUMENTS.set(Instruments {
+            handled: meter
+                .u64_counter("node.handled")
+                .with_description("Calls handled, by name")
+                .build(),
+            duration: meter
+                .f64_histogram("node.handle.duration")
+                .with_description("How long each call took")
+                .with_unit("s")
+                .build(),
+        });
+    }
+
+    /// Exports what the providers still hold and stops them.
+    pub fn shutdown() {
+        if let Some(provider) = TRACER_PROVIDER.get() {
+            if let Err(e) = provider.shutdown() {
+                log::warn!("Unable to shut down span export: {}", e);
+            }
+        }
+        if let Some(provider) = METER_PROVIDER.get() {
+            if let Err(e) = provider.shutdown() {
+                log::warn!("Unable to shut down metric export: {}", e);
+            }
+        }
+    }
+
+    /// Runs `f` in a span named `name`, counting it and recording how long it took.
+    pub fn timed<T>(name: &'static str, f: impl FnOnce() -> T) -> T {
+        let Some(instruments) = INSTRUMENTS.get() else {
+            return f();
+        };
+        let attributes = [
+            KeyValue::new("name", name),
+            KeyValue::new("node", super::NODE.get().cloned().unwrap_or_default()),
+        ];
+        let mut span = global::tracer(SCOPE).start(name);
+        span.set_attributes(attributes.clone());
+        let start = Instant::now();
+        let result = f();
+        span.end();
+        instruments
+            .duration
+            .record(start.elapsed().as_secs_f64(), &attributes);
+        instruments.handled.add(1, &attributes);
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer(name: &str, max_bytes: u64, max_files: usize) -> Writer {
+        let dir = std::env::temp_dir().join(format!("logging-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        Writer {
+            dir,
+            max_bytes,
+            max_files,
+            file: None,
+            pending: Vec::new(),
+        }
+    }
+
+    fn read(writer: &Writer, n: usize) -> Vec<String> {
+        fs::read_to_string(writer.path("n1", n))
+            .unwrap_or_default()
+            .lines()
+            .map(String::from)
+            .collect()
+    }
+
+    #[test]
+    fn test_lines_before_node_are_kept() {
+        let mut writer = writer("pending", 1024, 1);
+        writer.handle(Command::Line("before".into())).unwrap();
+        writer.handle(Command::Open("n1".into())).unwrap();
+        writer.handle(Command::Line("after".into())).unwrap();
+        let (done, wait) = mpsc::sync_channel(1);
+        writer.handle(Command::Flush(done)).unwrap();
+        wait.recv().unwrap();
+
+        assert_eq!(read(&writer, 0), vec!["before", "after"]);
+        fs::remove_dir_all(&writer.dir).unwrap();
+    }
+
+    #[test]
+    fn test_rotates_at_max_bytes() {
+        // Room for two 9 byte lines per file
+        let mut writer = writer("rotate", 20, 2);
+        writer.handle(Command::Open("n1".into())).unwrap();
+        for i in 0..7 {
+            writer.write(format!("line-{:03}", i)).unwrap();
+        }
+        writer.rotate().unwrap();
+
+        assert_eq!(read(&writer, 0), Vec::<String>::new());
+        assert_eq!(read(&writer, 1), vec!["line-006"]);
+        assert_eq!(read(&writer, 2), vec!["line-004", "line-005"]);
+        // The oldest were dropped
+        assert!(!writer.path("n1", 3).exists());
+        fs::remove_dir_all(&writer.dir).unwrap();
+    }
+
+    #[test]
+    fn test_failed_rotation_keeps_logging() {
+        let mut writer = writer("rotate-failed", 20, 1);
+        writer.handle(Command::Open("n1".into())).unwrap();
+        // A non-empty directory where the rotated file should go can't be renamed over
+        fs::create_dir_all(writer.path("n1", 1).join("taken")).unwrap();
+        for i in 0..3 {
+            writer.write(format!("line-{:03}", i)).unwrap();
+        }
+        let (done, wait) = mpsc::sync_channel(1);
+        writer.handle(Command::Flush(done)).unwrap();
+        wait.recv().unwrap();
+
+        assert_eq!(read(&writer, 0), vec!["line-000", "line-001", "line-002"]);
+        fs::remove_dir_all(&writer.dir).unwrap();
+    }
+
+    #[test]
+    fn test_malformed_replies_when_recoverable() {
+        let line = r#"{"src":"c1","dest":"n1","body":{"type":"read","key":[],"msg_id":4}}"#;
+        let error = serde_json::from_str::<u64>("[]").unwrap_err();
+        let reply: serde_json::Value =
+            serde_json::from_str(&malformed(line.as_bytes(), &error).unwrap()).unwrap();
+        assert_eq!(reply["src"], "n1");
+        assert_eq!(reply["dest"], "c1");
+        assert_eq!(reply["body"]["in_reply_to"], 4);
+        assert_eq!(reply["body"]["code"], 12);
+
+        let error = serde_json::from_str::<u64>("{broken").unwrap_err();
+        assert_eq!(malformed(b"{broken", &error), None);
+
+        // Invalid UTF-8 in a string still leaves the sender recoverable
+        let line = b"{\"src\":\"c2\",\"dest\":\"n1\",\"body\":{\"msg_id\":5,\"key\":\"\xff\"}}";
+        let error = serde_json::from_slice::<serde_json::Value>(line).unwrap_err();
+        let reply: serde_json::Value =
+            serde_json::from_str(&malformed(line, &error).unwrap()).unwrap();
+        assert_eq!(reply["dest"], "c2");
+        assert_eq!(reply["body"]["in_reply_to"], 5);
+    }
+}
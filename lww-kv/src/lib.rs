@@ -0,0 +1,369 @@
+pub mod node {
+    use crdt::{Delta, Hlc, LwwMap, Merge};
+    use maelstrom::error::KEY_DOES_NOT_EXIST;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    /// A key-value store clients can read and write at any node, replicated as an `LwwMap`.
+    /// Each write is stamped by the node's hybrid logical clock, and the latest write to a key
+    /// wins everywhere. Each node gossips every peer the writes it hasn't yet acknowledged,
+    /// so reads anywhere converge once writes stop, but a read can miss a write made
+    /// elsewhere moments before.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        peers: Vec<String>,
+        clock: Hlc,
+        values: LwwMap<String, Value>, // Keyed by the key's JSON, since keys can be any value
+        known: HashMap<String, LwwMap<String, Value>>, // What each peer is known to have merged
+        in_flight: HashMap<u64, (String, LwwMap<String, Value>)>, // The latest gossip to each peer, by msg_id
+    }
+
+    /// Tunables, read from `LWW_KV_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between gossip rounds.
+        pub gossip_interval: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                gossip_interval: Duration::from_millis(100),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                gossip_interval: Duration::from_millis(env_or(
+                    "LWW_KV_GOSSIP_INTERVAL_MS",
+                    default.gossip_interval.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// Milliseconds since the Unix epoch, by this machine's clock.
+    fn wall_clock() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+            key: Value,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: Value,
+        },
+        Write {
+            msg_id: u64,
+            key: Value,
+            value: Value,
+        },
+        WriteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// The writes the sender thinks we're missing.
+        Gossip {
+            msg_id: u64,
+            values: LwwMap<String, Value>,
+        },
+        /// The gossip has been merged.
+        GossipOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+    }
+
+    impl Default for Node {
+        fn default() -> Self {
+            Node::new()
+        }
+    }
+
+    impl Node {
+        pub fn new() -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                peers: Vec::new(),
+                clock: Hlc::new(""),
+                values: LwwMap::default(),
+                known: HashMap::new(),
+                in_flight: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "values": self.values.len(),
+                "in_flight": self.in_flight.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let Some(body) = self.handle_body(&message.src, message.body) else {
+                return Vec::new();
+            };
+            self.cur_id += 1;
+            vec![Message {
+                src: message.dest,
+                dest: message.src,
+                body,
+            }]
+        }
+
+        /// Sends each peer what it's missing. Anything a peer doesn't acknowledge is sent
+        /// again next round, so lost gossip only delays convergence.
+        pub fn gossip(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            for peer in self.peers.clone() {
+                let known = self.known.entry(peer.clone()).or_default();
+                let Some(delta) = self.values.delta_since(known) else {
+                    continue;
+                };
+                // Only the latest gossip needs acknowledging, since it has all the earlier ones
+                self.in_flight.retain(|_, (to, _)| *to != peer);
+                let msg_id = self.next_msg_id();
+                self.in_flight.insert(msg_id, (peer.clone(), delta.clone()));
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Gossip {
+                        msg_id,
+                        values: delta,
+                    },
+                });
+            }
+            messages
+        }
+
+        fn handle_body(&mut self, src: &str, body: Body) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+                    self.clock = Hlc::new(&node_id);
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Read { msg_id, key } => match self.values.get(&key.to_string()) {
+                    Some(value) => Body::ReadOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        value: value.clone(),
+                    },
+                    None => Body::Error {
+                        in_reply_to: msg_id,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("no value for {}", key),
+                    },
+                },
+                Body::Write { msg_id, key, value } => {
+                    let timestamp = self.clock.now(wall_clock());
+                    self.values.set(key.to_string(), timestamp, value);
+                    Body::WriteOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Gossip { msg_id, values } => {
+                    // Writes made here from now on come after everything we've seen
+                    if let Some(latest) = values.latest() {
+                        self.clock.observe(latest);
+                    }
+                    self.values.merge(&values);
+                    // Whatever it sent us, it has
+                    self.known
+                        .entry(src.to_string())
+                        .or_default()
+                        .merge(&values);
+                    Body::GossipOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::GossipOk { in_reply_to, .. } => {
+                    let (peer, sent) = self.in_flight.remove(&in_reply_to)?;
+                    self.known.entry(peer).or_default().merge(&sent);
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::ReadOk { .. }
+                | Body::WriteOk { .. }
+                | Body::Error { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, exchange};
+        use serde_json::json;
+
+        fn init(id: &str) -> Node {
+            testing::init(Node::new(), id, 2)
+        }
+
+        fn send(node: &mut Node, body: Body) -> Body {
+            let dest = node.id.clone();
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body,
+            })
+            .remove(0)
+            .body
+        }
+
+        fn write(node: &mut Node, key: Value, value: Value) {
+            let body = send(
+                node,
+                Body::Write {
+                    msg_id: 2,
+                    key,
+                    value,
+                },
+            );
+            assert!(matches!(body, Body::WriteOk { .. }));
+        }
+
+        fn read(node: &mut Node, key: Value) -> Option<Value> {
+            match send(node, Body::Read { msg_id: 9, key }) {
+                Body::ReadOk { value, .. } => Some(value),
+                Body::Error {
+                    code: KEY_DOES_NOT_EXIST,
+                    ..
+                } => None,
+                body => panic!("unexpected reply {:?}", body),
+            }
+        }
+
+        #[test]
+        fn test_writes_converge_through_gossip() {
+            let (mut n1, mut n2) = (init("n1"), init("n2"));
+            write(&mut n1, json!(1), json!("a"));
+            write(&mut n2, json!("1"), json!("b"));
+            assert_eq!(read(&mut n2, json!(1)), None);
+
+            exchange(&mut n1, &mut n2);
+            exchange(&mut n2, &mut n1);
+            for node in [&mut n1, &mut n2] {
+                // 1 and "1" are different keys
+                assert_eq!(read(node, json!(1)), Some(json!("a")));
+                assert_eq!(read(node, json!("1")), Some(json!("b")));
+            }
+            // Both sides know the other is caught up, so there's nothing left to send
+            assert!(n1.gossip().is_empty());
+            assert!(n2.gossip().is_empty());
+        }
+
+        #[test]
+        fn test_writes_after_gossip_win_despite_clock_skew() {
+            let (mut n1, mut n2) = (init("n1"), init("n2"));
+            // n1's clock runs an hour ahead of n2's
+            n1.clock.now(wall_clock() + 3_600_000);
+            write(&mut n1, json!("k"), json!(1));
+            exchange(&mut n1, &mut n2);
+
+            // n2 writes after seeing n1's write, so its write wins
+            write(&mut n2, json!("k"), json!(2));
+            exchange(&mut n2, &mut n1);
+            assert_eq!(read(&mut n1, json!("k")), Some(json!(2)));
+            assert_eq!(read(&mut n2, json!("k")), Some(json!(2)));
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::gossip(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,502 @@
+//! The plumbing every node binary shares. A workload implements `Node` for its node, and its
+//! `main` hands one to `run`, which reads messages from stdin, feeds them to the node along
+//! with its ticks and timeouts, and writes whatever it sends to stdout. Nodes simple enough
+//! to answer each message as it's read use `read_blocking` and `write` instead.
+//!
+//! Sending SIGUSR1 to a running node logs its `dump`, for when a run looks stuck. The error
+//! codes nodes reply with are in `error`, and with the `testing` feature, `testing` has
+//! fixtures for driving nodes in tests.
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+use std::error::Error;
+use std::io::{self, Write};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, BufReader};
+use tokio::signal::unix::{signal, Signal, SignalKind};
+use tokio::sync::{mpsc, Notify};
+use tokio::time::Instant;
+
+#[cfg(feature = "testing")]
+pub mod testing;
+
+/// The error codes Maelstrom defines. Every code but `TIMEOUT` and `CRASH` tells a client its
+/// request definitely didn't take effect; after those two it may or may not have.
+pub mod error {
+    pub const TIMEOUT: u64 = 0;
+    pub const NODE_NOT_FOUND: u64 = 1;
+    pub const NOT_SUPPORTED: u64 = 10;
+    pub const TEMPORARILY_UNAVAILABLE: u64 = 11;
+    pub const MALFORMED_REQUEST: u64 = 12;
+    pub const CRASH: u64 = 13;
+    pub const ABORT: u64 = 14;
+    pub const KEY_DOES_NOT_EXIST: u64 = 20;
+    pub const KEY_ALREADY_EXISTS: u64 = 21;
+    pub const PRECONDITION_FAILED: u64 = 22;
+    pub const TXN_CONFLICT: u64 = 30;
+}
+
+/// The part of a node that can handle some of its messages on any thread, so they don't wait
+/// their turn behind everything else the node handles.
+pub trait Shared: Send + Sync + 'static {
+    type Message;
+
+    /// Whether `message` is for `handle` rather than the node. Asked on the task reading stdin,
+    /// so it should be quick.
+    fn claims(&self, message: &Self::Message) -> bool;
+
+    /// Handles a message `claims` said was its own, returning what to send because of it.
+    fn handle(&self, message: Self::Message) -> Vec<Self::Message>;
+}
+
+/// A workload's node, as `run` drives it.
+pub trait Node: Send + 'static {
+    /// What the node reads from stdin and writes to stdout.
+    type Message: Serialize + DeserializeOwned + Send + 'static;
+
+    /// Handles a message, returning what to send because of it.
+    fn handle_message(&mut self, message: Self::Message) -> Vec<Self::Message>;
+
+    /// Periodic work, like retries and gossip.
+    fn tick(&mut self) -> Vec<Self::Message>;
+
+    /// The node's state, as logged on SIGUSR1.
+    fn dump(&self) -> Value;
+
+    /// How long to wait before the next tick, given the interval `run` was started with.
+    /// Asked before every tick, so a node can pace itself, say by backing off while idle.
+    fn next_tick(&self, tick_interval: Duration) -> Duration {
+        tick_interval
+    }
+
+    /// Whether the next tick should run now rather than after the rest of its delay. Asked
+    /// after every message, including those its shared part handles.
+    fn tick_due(&self) -> bool {
+        false
+    }
+
+    /// When the next request waiting on a timeout gives up, if any are waiting.
+    fn next_deadline(&self) -> Option<std::time::Instant> {
+        None
+    }
+
+    /// Gives up on the requests whose deadlines have passed.
+    fn expire(&mut self) -> Vec<Self::Message> {
+        Vec::new()
+    }
+
+    /// The part of the node that handles some messages off its task, if it has one. Asked once,
+    /// before the first message.
+    fn shared(&self) -> Option<Arc<dyn Shared<Message = Self::Message>>> {
+        None
+    }
+}
+
+/// Runs `node` until stdin closes, ticking it every `tick_interval` unless it paces itself,
+/// then shuts logging down. Messages its shared part claims are handled on the blocking pool,
+/// side by side, since they may block on IO.
+pub async fn run<N: Node>(node: N, tick_interval: Duration) -> Result<(), Box<dyn Error>> {
+    let dump = signal(SignalKind::user_defined1())?;
+    let shared = node.shared();
+    let wake = Arc::new(Notify::new());
+    let (input, node_input) = mpsc::unbounded_channel();
+    let (node_output, mut output) = mpsc::unbounded_channel::<N::Message>();
+    tokio::spawn(drive(
+        node,
+        tick_interval,
+        node_input,
+        node_output.clone(),
+        dump,
+        wake.clone(),
+    ));
+
+    // Blocking writes stay off the runtime, on a thread of their own
+    std::thread::spawn(move || {
+        while let Some(message) = output.blocking_recv() {
+            write(&message).unwrap();
+        }
+    });
+
+    let result = read(|message| match &shared {
+        Some(shared) if shared.claims(&message) => {
+            let (shared, output, wake) = (shared.clone(), node_output.clone(), wake.clone());
+            tokio::task::spawn_blocking(move || {
+                for message in shared.handle(message) {
+                    let _ = output.send(message);
+                }
+                wake.notify_one();
+            });
+            Ok(())
+        }
+        _ => match input.send(message) {
+            Ok(()) => Ok(()),
+            Err(_) => Err("the node stopped".into()),
+        },
+    })
+    .await;
+    logging::shutdown();
+    result
+}
+
+/// Reads messages from stdin until it closes, handing each to `handle`. A line that doesn't
+/// parse, even as UTF-8, is logged, and answered with a malformed-request error if it says who
+/// sent it. A failed read stops it with the error, since it may have left off mid-line.
+pub async fn read<M: DeserializeOwned>(
+    mut handle: impl FnMut(M) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut stdin = BufReader::new(tokio::io::stdin());
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = stdin.read_until(b'\n', &mut line).await;
+        if read.map_err(|e| format!("unable to read stdin: {}", e))? == 0 {
+            return Ok(());
+        }
+        parse(&line, &mut io::stdout(), &mut handle)?;
+    }
+}
+
+/// Like `read`, for nodes that handle each message on the thread that read it.
+pub fn read_blocking<M: DeserializeOwned>(
+    handle: impl FnMut(M) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    read_from(io::stdin().lock(), io::stdout(), handle)
+}
+
+/// Like `read_blocking`, reading from `input` and answering malformed lines on `output`, so
+/// fuzzers and tests can feed a node exactly what it would get on stdin.
+pub fn read_from<M: DeserializeOwned>(
+    mut input: impl io::BufRead,
+    mut output: impl Write,
+    mut handle: impl FnMut(M) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    let mut line = Vec::new();
+    loop {
+        line.clear();
+        let read = input.read_until(b'\n', &mut line);
+        if read.map_err(|e| format!("unable to read stdin: {}", e))? == 0 {
+            return Ok(());
+        }
+        parse(&line, &mut output, &mut handle)?;
+    }
+}
+
+/// Hands the message on `line` to `handle`, or answers it as malformed if it doesn't parse.
+/// Blank lines are skipped.
+fn parse<M: DeserializeOwned>(
+    line: &[u8],
+    output: &mut impl Write,
+    handle: &mut impl FnMut(M) -> Result<(), Box<dyn Error>>,
+) -> Result<(), Box<dyn Error>> {
+    if line.trim_ascii().is_empty() {
+        return Ok(());
+    }
+    match serde_json::from_slice::<M>(line) {
+        Ok(m) => handle(m),
+        Err(e) => {
+            if let Some(reply) = logging::malformed(line, &e) {
+                // In one write, so it can't interleave with other writers on stdout
+                output.write_all(format!("{}\n", reply).as_bytes())?;
+            }
+            Ok(())
+        }
+    }
+}
+
+/// Turns away a message a node can't take yet or any more: anything but an init before it's
+/// `initialized`, and another init after. Returns what to send instead of handling it, or
+/// `None` if the node should handle it.
+pub fn check_init<M: Serialize + DeserializeOwned>(
+    message: &M,
+    is_init: bool,
+    initialized: bool,
+) -> Option<Vec<M>> {
+    let (code, text) = match (is_init, initialized) {
+        (false, false) => (
+            error::TEMPORARILY_UNAVAILABLE,
+            "node is not initialized yet",
+        ),
+        (true, true) => (error::PRECONDITION_FAILED, "node is already initialized"),
+        _ => return None,
+    };
+    Some(refuse(message, code, text).into_iter().collect())
+}
+
+/// The error reply to `message`, which works for any message type whose body has an `error`
+/// variant. Only requests are answered: replies carry a msg_id too, but answering one could
+/// start an error loop with whoever sent it, so they're only logged.
+pub fn refuse<M: Serialize + DeserializeOwned>(message: &M, code: u64, text: &str) -> Option<M> {
+    let message = serde_json::to_value(message).ok()?;
+    let body = &message["body"];
+    log::warn!(
+        "Refusing {} from {}: {}",
+        body["type"],
+        message["src"],
+        text
+    );
+    let msg_id = body["msg_id"]
+        .as_u64()
+        .filter(|_| body.get("in_reply_to").is_none())?;
+    let reply = json!({
+        "src": message["dest"],
+        "dest": message["src"],
+        "body": {"type": "error", "in_reply_to": msg_id, "code": code, "text": text},
+    });
+    serde_json::from_value(reply).ok()
+}
+
+/// Writes `message` to stdout as a line of its own, holding the lock so concurrent writers
+/// can't interleave.
+pub fn write(message: &impl Serialize) -> io::Result<()> {
+    let mut stdout = io::stdout().lock();
+    serde_json::to_writer(&mut stdout, message)?;
+    stdout.write_all(b"\n")
+}
+
+/// Owns the node and feeds it everything in turn: messages from stdin, ticks when they're
+/// due and requests that have waited too long. Nothing else touches the node, so there's no
+/// lock to hold while output is written; what it sends goes to `output` for the writer thread.
+/// `wake` is notified whenever the shared part has handled a message, to ask `tick_due` again.
+async fn drive<N: Node>(
+    mut node: N,
+    tick_interval: Duration,
+    mut input: mpsc::UnboundedReceiver<N::Message>,
+    output: mpsc::UnboundedSender<N::Message>,
+    mut dump: Signal,
+    wake: Arc<Notify>,
+) {
+    let mut next_tick = Instant::now() + node.next_tick(tick_interval);
+    loop {
+        let deadline = node.next_deadline().map(Instant::from_std);
+        let messages = tokio::select! {
+            message = input.recv() => {
+                let Some(message) = message else {
+                    return;
+                };
+                let messages = logging::timed("handle_message", || node.handle_message(message));
+                if node.tick_due() {
+                    next_tick = Instant::now();
+                }
+                messages
+            }
+            _ = tokio::time::sleep_until(next_tick) => {
+                next_tick = Instant::now() + node.next_tick(tick_interval);
+                node.tick()
+            }
+            _ = async {
+                match deadline {
+                    Some(deadline) => tokio::time::sleep_until(deadline).await,
+                    None => std::future::pending().await,
+                }
+            } => {
+                node.expire()
+            }
+            _ = wake.notified() => {
+                if node.tick_due() {
+                    next_tick = Instant::now();
+                }
+                Vec::new()
+            }
+            Some(()) = dump.recv() => {
+                log::info!("State: {}", node.dump());
+                Vec::new()
+            }
+        };
+        for message in messages {
+            if output.send(message).is_err() {
+                return;
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde::Deserialize;
+    use serde_json::json;
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Message {
+        dest: String,
+        value: u64,
+    }
+
+    /// Echoes values back, counts ticks, and answers the request waiting on `deadline` with 0.
+    #[derive(Default)]
+    struct Echo {
+        ticks: u64,
+        deadline: Option<std::time::Instant>,
+    }
+
+    impl Node for Echo {
+        type Message = Message;
+
+        fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            vec![Message {
+                dest: "c1".into(),
+                value: message.value,
+            }]
+        }
+
+        fn tick(&mut self) -> Vec<Message> {
+            self.ticks += 1;
+            vec![Message {
+                dest: "tick".into(),
+                value: self.ticks,
+            }]
+        }
+
+        fn dump(&self) -> Value {
+            json!({ "ticks": self.ticks })
+        }
+
+        fn next_deadline(&self) -> Option<std::time::Instant> {
+            self.deadline
+        }
+
+        fn expire(&mut self) -> Vec<Message> {
+            self.deadline = None;
+            vec![Message {
+                dest: "expired".into(),
+                value: 0,
+            }]
+        }
+    }
+
+    fn start(
+        node: Echo,
+        tick_interval: Duration,
+    ) -> (
+        mpsc::UnboundedSender<Message>,
+        mpsc::UnboundedReceiver<Message>,
+    ) {
+        let (input, node_input) = mpsc::unbounded_channel();
+        let (node_output, output) = mpsc::unbounded_channel();
+        let dump = signal(SignalKind::user_defined1()).unwrap();
+        let wake = Arc::new(Notify::new());
+        tokio::spawn(drive(
+            node,
+            tick_interval,
+            node_input,
+            node_output,
+            dump,
+            wake,
+        ));
+        (input, output)
+    }
+
+    #[tokio::test]
+    async fn test_messages_and_ticks_reach_the_node() {
+        let (input, mut output) = start(Echo::default(), Duration::from_millis(20));
+        input
+            .send(Message {
+                dest: "n1".into(),
+                value: 7,
+            })
+            .unwrap();
+        assert_eq!(
+            output.recv().await,
+            Some(Message {
+                dest: "c1".into(),
+                value: 7
+            })
+        );
+        let tick = output.recv().await.unwrap();
+        assert_eq!((tick.dest.as_str(), tick.value), ("tick", 1));
+    }
+
+    /// Input that yields `lines` and then fails every read.
+    struct Failing {
+        lines: io::Cursor<Vec<u8>>,
+    }
+
+    impl io::Read for Failing {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            match io::Read::read(&mut self.lines, buf)? {
+                0 => Err(io::Error::other("pipe broke")),
+                read => Ok(read),
+            }
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    struct Envelope {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "snake_case", tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            text: String,
+        },
+    }
+
+    #[test]
+    fn test_check_init_answers_requests_only() {
+        let envelope = |body| Envelope {
+            src: "c1".into(),
+            dest: "n1".into(),
+            body,
+        };
+        let init = envelope(Body::Init { msg_id: 4 });
+        assert_eq!(check_init(&init, true, false), None);
+        assert_eq!(
+            check_init(&init, true, true),
+            Some(vec![Envelope {
+                src: "n1".into(),
+                dest: "c1".into(),
+                body: Body::Error {
+                    in_reply_to: 4,
+                    code: error::PRECONDITION_FAILED,
+                    text: "node is already initialized".into(),
+                },
+            }])
+        );
+        let reply = envelope(Body::InitOk {
+            msg_id: 5,
+            in_reply_to: 1,
+        });
+        assert_eq!(check_init(&reply, false, false), Some(Vec::new()));
+    }
+
+    #[test]
+    fn test_failed_read_stops_reading() {
+        let lines = b"{\"dest\":\"n1\",\"value\":1}\n\n{\"dest\":\"n1\",\"val".to_vec();
+        let input = io::BufReader::new(Failing {
+            lines: io::Cursor::new(lines),
+        });
+        let mut read = Vec::new();
+        let result = read_from(input, io::sink(), |message: Message| {
+            read.push(message.value);
+            Ok(())
+        });
+        assert!(result.unwrap_err().to_string().contains("pipe broke"));
+        assert_eq!(read, vec![1]);
+    }
+
+    #[tokio::test]
+    async fn test_deadlines_expire_between_ticks() {
+        let node = Echo {
+            deadline: Some(std::time::Instant::now() + Duration::from_millis(10)),
+            ..Echo::default()
+        };
+        let (_input, mut output) = start(node, Duration::from_secs(60));
+        let expired = output.recv().await.unwrap();
+        assert_eq!(expired.dest, "expired");
+    }
+}
@@ -0,0 +1,72 @@
+//! Fixtures for testing nodes without Maelstrom: initing them, routing what they send to each
+//! other, and standing in for the client. Messages are built and read as JSON, so they work
+//! for any workload's `Message` whose envelope has Maelstrom's shape.
+
+use crate::Node;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use serde_json::{json, Value};
+
+/// `node`, once an init from c1 has made it `id` in a cluster of n1 to n`count`.
+pub fn init<N: Node>(mut node: N, id: &str, count: usize) -> N {
+    let node_ids: Vec<String> = (1..=count).map(|i| format!("n{}", i)).collect();
+    node.handle_message(message(
+        "c1",
+        id,
+        json!({"type": "init", "msg_id": 1, "node_id": id, "node_ids": node_ids}),
+    ));
+    node
+}
+
+/// n1 to n`count`, each made by `new` from its id and inited.
+pub fn cluster<N: Node>(count: usize, mut new: impl FnMut(&str) -> N) -> Vec<N> {
+    (1..=count)
+        .map(|i| {
+            let id = format!("n{}", i);
+            init(new(&id), &id, count)
+        })
+        .collect()
+}
+
+/// Delivers `messages`, and everything sent in response, until only messages to clients are
+/// left, which are returned. `nodes` are n1 onwards, as `cluster` makes them.
+pub fn deliver<N: Node>(nodes: &mut [N], mut messages: Vec<N::Message>) -> Vec<N::Message> {
+    let mut to_clients = Vec::new();
+    while !messages.is_empty() {
+        for message in std::mem::take(&mut messages) {
+            let dest = serde_json::to_value(&message).unwrap()["dest"].clone();
+            match dest.as_str().and_then(|dest| dest.strip_prefix('n')) {
+                Some(n) => {
+                    let node = &mut nodes[n.parse::<usize>().unwrap() - 1];
+                    messages.extend(node.handle_message(message));
+                }
+                None => to_clients.push(message),
+            }
+        }
+    }
+    to_clients
+}
+
+/// Delivers what `from` sends on its next tick, its gossip, to `to`, and the acknowledgements
+/// back.
+pub fn exchange<N: Node>(from: &mut N, to: &mut N) {
+    for gossip in from.tick() {
+        for ack in to.handle_message(gossip) {
+            from.handle_message(ack);
+        }
+    }
+}
+
+/// A request from c1 to `dest`.
+pub fn request<M: DeserializeOwned>(dest: &str, body: Value) -> M {
+    message("c1", dest, body)
+}
+
+/// The body of the first of `messages`.
+pub fn reply<M: Serialize>(messages: &[M]) -> Value {
+    serde_json::to_value(&messages[0]).unwrap()["body"].take()
+}
+
+fn message<M: DeserializeOwned>(src: &str, dest: &str, body: Value) -> M {
+    serde_json::from_value(json!({"src": src, "dest": dest, "body": body})).unwrap()
+}
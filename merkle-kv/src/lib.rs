@@ -0,0 +1,630 @@
+mod merkle {
+    use crdt::Timestamp;
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::BTreeSet;
+    use std::hash::{Hash, Hasher};
+
+    /// A Merkle tree over a keyspace, split by key hash into `2^depth` buckets. Each leaf
+    /// hashes the writes in its bucket and each node above hashes its two children, so two
+    /// replicas holding the same writes have the same root, and where they differ, only the
+    /// subtrees over the buckets they differ in have different hashes.
+    ///
+    /// Nodes are numbered as in a binary heap: the root is 1, the children of `i` are `2i`
+    /// and `2i + 1`, and the leaf for bucket `b` is `2^depth + b`. Keys are hashed with
+    /// `DefaultHasher`, which is only stable within a build, so every replica has to run the
+    /// same binary.
+    pub struct Tree {
+        depth: u32,
+        hashes: Vec<u64>,            // By node number; 0 is unused
+        keys: Vec<BTreeSet<String>>, // The keys in each bucket
+    }
+
+    impl Tree {
+        pub fn new(depth: u32) -> Self {
+            let leaves = 1 << depth;
+            let mut tree = Tree {
+                depth,
+                hashes: vec![0; 2 * leaves],
+                keys: vec![BTreeSet::new(); leaves],
+            };
+            for node in (1..leaves).rev() {
+                tree.rehash(node);
+            }
+            tree
+        }
+
+        pub fn root(&self) -> u64 {
+            self.hashes[1]
+        }
+
+        /// The hash of node `node`, if there's such a node.
+        pub fn hash(&self, node: usize) -> Option<u64> {
+            self.hashes.get(node).copied().filter(|_| node > 0)
+        }
+
+        /// The children of `node`, unless it's a leaf or not a node at all.
+        pub fn children(&self, node: usize) -> Option<[usize; 2]> {
+            (node > 0 && node < self.keys.len()).then_some([2 * node, 2 * node + 1])
+        }
+
+        /// The bucket `node` is the leaf of, if it's a leaf.
+        pub fn bucket_at(&self, node: usize) -> Option<usize> {
+            (self.keys.len()..self.hashes.len())
+                .contains(&node)
+                .then(|| node - self.keys.len())
+        }
+
+        pub fn bucket(&self, key: &str) -> usize {
+            match self.depth {
+                0 => 0,
+                depth => (hash(&key) >> (64 - depth)) as usize,
+            }
+        }
+
+        /// The keys in `bucket`, which must be one of ours.
+        pub fn keys(&self, bucket: usize) -> impl Iterator<Item = &String> {
+            self.keys[bucket].iter()
+        }
+
+        /// Records that `key`, written at `old` if it was written at all, was written again
+        /// at `new`, and rehashes the path from its leaf up to the root.
+        pub fn update(&mut self, key: &str, old: Option<&Timestamp>, new: &Timestamp) {
+            let bucket = self.bucket(key);
+            self.keys[bucket].insert(key.to_string());
+            let mut node = self.keys.len() + bucket;
+            // A leaf's hash is the XOR of its writes' hashes, so one can be swapped for
+            // another without looking at the rest
+            if let Some(old) = old {
+                self.hashes[node] ^= hash(&(key, old));
+            }
+            self.hashes[node] ^= hash(&(key, new));
+            while node > 1 {
+                node /= 2;
+                self.rehash(node);
+            }
+        }
+
+        fn rehash(&mut self, node: usize) {
+            self.hashes[node] = hash(&(self.hashes[2 * node], self.hashes[2 * node + 1]));
+        }
+    }
+
+    fn hash<T: Hash>(value: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+pub mod node {
+    use super::merkle::Tree;
+    use crdt::{Delta, Hlc, LwwMap, Timestamp};
+    use maelstrom::error::KEY_DOES_NOT_EXIST;
+    use rand::seq::SliceRandom;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::BTreeMap;
+    use std::str::FromStr;
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    /// A key-value store clients can read and write at any node, replicated as an `LwwMap`
+    /// by anti-entropy alone. Writes stay where they're made until a sync round carries them
+    /// on, so a read elsewhere can miss them for a while, and of concurrent writes to a key
+    /// the latest by hybrid logical clock wins.
+    ///
+    /// Each round a node sends a random peer the root of its Merkle tree. Where the peer's
+    /// hash differs, it answers with its hashes of the children, and the two walk down only
+    /// the subtrees that differ, a level per message, until one reaches the leaves. It sends
+    /// the writes in those buckets, and the other merges them and answers with whatever it
+    /// has there that's newer. Replicas that agree settle it with the root alone, and ones
+    /// that differ in a few keys exchange those buckets and a handful of hashes, however
+    /// many keys they hold.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        peers: Vec<String>,
+        clock: Hlc,
+        values: LwwMap<String, Value>, // Keyed by the key's JSON, since keys can be any value
+        tree: Tree,
+    }
+
+    /// Tunables, read from `MERKLE_KV_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between sync rounds.
+        pub sync_interval: Duration,
+        /// Levels below the root of the Merkle tree, which has `2^depth` buckets. Deeper
+        /// trees take more messages to walk but ship fewer keys along with each one that
+        /// differs. Every node needs the same depth.
+        pub depth: u32,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                sync_interval: Duration::from_millis(100),
+                depth: 10,
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                sync_interval: Duration::from_millis(env_or(
+                    "MERKLE_KV_SYNC_INTERVAL_MS",
+                    default.sync_interval.as_millis() as u64,
+                )),
+                depth: env_or("MERKLE_KV_DEPTH", default.depth),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// Milliseconds since the Unix epoch, by this machine's clock.
+    fn wall_clock() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+            key: Value,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: Value,
+        },
+        Write {
+            msg_id: u64,
+            key: Value,
+            value: Value,
+        },
+        WriteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// The sender's hashes of some nodes of its tree, all at one level. Answered with
+        /// the level below wherever ours differ, or with a repair once that's the leaves.
+        Compare {
+            msg_id: u64,
+            hashes: BTreeMap<usize, u64>,
+        },
+        /// The sender's writes in buckets where our trees differ.
+        Repair {
+            msg_id: u64,
+            buckets: Vec<usize>,
+            entries: LwwMap<String, Value>,
+        },
+        /// Our writes in the repaired buckets that were newer than the repair's.
+        RepairOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            entries: LwwMap<String, Value>,
+        },
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                peers: Vec::new(),
+                clock: Hlc::new(""),
+                values: LwwMap::default(),
+                tree: Tree::new(config.depth),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "values": self.values.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let Some(body) = self.handle_body(message.body) else {
+                return Vec::new();
+            };
+            self.cur_id += 1;
+            vec![Message {
+                src: message.dest,
+                dest: message.src,
+                body,
+            }]
+        }
+
+        /// Starts a round with a random peer. A round that loses a message just stops, and
+        /// whatever it didn't repair is found again by a later one.
+        pub fn sync(&mut self) -> Vec<Message> {
+            let Some(peer) = self.peers.choose(&mut rand::thread_rng()).cloned() else {
+                return Vec::new();
+            };
+            let msg_id = self.next_msg_id();
+            vec![Message {
+                src: self.id.clone(),
+                dest: peer,
+                body: Body::Compare {
+                    msg_id,
+                    hashes: BTreeMap::from([(1, self.tree.root())]),
+                },
+            }]
+        }
+
+        /// Writes `value` to `key` if `timestamp` is later than what it has, keeping the
+        /// tree in step.
+        fn set(&mut self, key: String, timestamp: Timestamp, value: Value) {
+            let old = self.values.timestamp(&key).cloned();
+            if self.values.set(key.clone(), timestamp.clone(), value) {
+                self.tree.update(&key, old.as_ref(), &timestamp);
+            }
+        }
+
+        fn merge(&mut self, entries: &LwwMap<String, Value>) {
+            // Writes made here from now on come after everything we've seen
+            if let Some(latest) = entries.latest() {
+                self.clock.observe(latest);
+            }
+            for (key, value) in entries.iter() {
+                if let Some(timestamp) = entries.timestamp(key) {
+                    self.set(key.clone(), timestamp.clone(), value.clone());
+                }
+            }
+        }
+
+        fn entries_in(&self, buckets: &[usize]) -> LwwMap<String, Value> {
+            let mut entries = LwwMap::default();
+            for key in buckets.iter().flat_map(|bucket| self.tree.keys(*bucket)) {
+                if let (Some(timestamp), Some(value)) =
+                    (self.values.timestamp(key), self.values.get(key))
+                {
+                    entries.set(key.clone(), timestamp.clone(), value.clone());
+                }
+            }
+            entries
+        }
+
+        /// The answer to a peer's hashes: nothing if they all match ours, our writes in the
+        /// buckets that differ if they're leaves, or else our hashes of the children of the
+        /// nodes that differ.
+        fn compare(&self, hashes: BTreeMap<usize, u64>) -> Option<Body> {
+            let differing: Vec<usize> = hashes
+                .into_iter()
+                .filter(|(node, hash)| self.tree.hash(*node).is_some_and(|ours| ours != *hash))
+                .map(|(node, _)| node)
+                .collect();
+            if differing.is_empty() {
+                return None;
+            }
+            let buckets: Vec<usize> = differing
+                .iter()
+                .filter_map(|node| self.tree.bucket_at(*node))
+                .collect();
+            if !buckets.is_empty() {
+                log::debug!("{} repairing {} buckets", self.id, buckets.len());
+                return Some(Body::Repair {
+                    msg_id: self.cur_id,
+                    entries: self.entries_in(&buckets),
+                    buckets,
+                });
+            }
+            let hashes = differing
+                .iter()
+                .filter_map(|node| self.tree.children(*node))
+                .flatten()
+                .filter_map(|child| Some((child, self.tree.hash(child)?)))
+                .collect();
+            Some(Body::Compare {
+                msg_id: self.cur_id,
+                hashes,
+            })
+        }
+
+        fn handle_body(&mut self, body: Body) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+                    self.clock = Hlc::new(&node_id);
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Read { msg_id, key } => match self.values.get(&key.to_string()) {
+                    Some(value) => Body::ReadOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        value: value.clone(),
+                    },
+                    None => Body::Error {
+                        in_reply_to: msg_id,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("no value for {}", key),
+                    },
+                },
+                Body::Write { msg_id, key, value } => {
+                    let timestamp = self.clock.now(wall_clock());
+                    self.set(key.to_string(), timestamp, value);
+                    Body::WriteOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Compare { hashes, .. } => return self.compare(hashes),
+                Body::Repair {
+                    msg_id,
+                    buckets,
+                    entries,
+                } => {
+                    self.merge(&entries);
+                    // Having merged theirs, ours differ only where we had newer writes
+                    let newer = self.entries_in(&buckets).delta_since(&entries);
+                    Body::RepairOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        entries: newer.unwrap_or_default(),
+                    }
+                }
+                Body::RepairOk { entries, .. } => {
+                    self.merge(&entries);
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::ReadOk { .. }
+                | Body::WriteOk { .. }
+                | Body::Error { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        fn cluster(count: usize, depth: u32) -> Vec<Node> {
+            let node_ids: Vec<String> = (1..=count).map(|i| format!("n{}", i)).collect();
+            node_ids
+                .iter()
+                .map(|id| {
+                    let mut node = Node::new(Config {
+                        depth,
+                        ..Config::default()
+                    });
+                    node.handle_message(Message {
+                        src: "c1".into(),
+                        dest: id.clone(),
+                        body: Body::Init {
+                            msg_id: 1,
+                            node_id: id.clone(),
+                            node_ids: node_ids.clone(),
+                        },
+                    });
+                    node
+                })
+                .collect()
+        }
+
+        /// Delivers `messages`, and everything sent in response, until there's nothing left
+        /// for a node, returning every message delivered between nodes.
+        fn deliver(nodes: &mut [Node], mut messages: Vec<Message>) -> Vec<Message> {
+            let mut delivered = Vec::new();
+            while let Some(message) = messages.pop() {
+                let n: usize = message.dest[1..].parse().unwrap();
+                messages.extend(nodes[n - 1].handle_message(message.clone()));
+                delivered.push(message);
+            }
+            delivered
+        }
+
+        fn send(node: &mut Node, body: serde_json::Value) -> serde_json::Value {
+            let message =
+                serde_json::from_value(json!({"src": "c1", "dest": node.id, "body": body}));
+            let reply = node.handle_message(message.unwrap()).remove(0);
+            serde_json::to_value(reply.body).unwrap()
+        }
+
+        fn write(node: &mut Node, key: serde_json::Value, value: serde_json::Value) {
+            let reply = send(
+                node,
+                json!({"type": "write", "msg_id": 2, "key": key, "value": value}),
+            );
+            assert_eq!(reply["type"], "write_ok");
+        }
+
+        fn read(node: &mut Node, key: serde_json::Value) -> Option<serde_json::Value> {
+            let reply = send(node, json!({"type": "read", "msg_id": 3, "key": key}));
+            (reply["type"] == "read_ok").then(|| reply["value"].clone())
+        }
+
+        /// Runs a sync round from `nodes[from]` to completion.
+        fn sync(nodes: &mut [Node], from: usize) -> Vec<Message> {
+            let messages = nodes[from].sync();
+            deliver(nodes, messages)
+        }
+
+        #[test]
+        fn test_trees_agree_on_the_same_writes() {
+            let stamp = |wall| Timestamp {
+                wall,
+                logical: 0,
+                node: "n1".into(),
+            };
+            let (mut a, mut b) = (Tree::new(4), Tree::new(4));
+            assert_eq!(a.root(), b.root());
+            a.update("x", None, &stamp(1));
+            a.update("y", None, &stamp(2));
+            b.update("y", None, &stamp(2));
+            b.update("x", None, &stamp(1));
+            assert_eq!(a.root(), b.root());
+
+            // Only the path from x's leaf up changes
+            b.update("x", Some(&stamp(1)), &stamp(3));
+            let leaf = 16 + a.bucket("x");
+            for node in 1..32 {
+                let on_path = (0..5).any(|level| leaf >> level == node);
+                assert_eq!(a.hash(node) != b.hash(node), on_path, "node {}", node);
+            }
+        }
+
+        #[test]
+        fn test_writes_converge_through_sync() {
+            let mut nodes = cluster(2, 4);
+            write(&mut nodes[0], json!(1), json!("a"));
+            write(&mut nodes[1], json!("1"), json!("b"));
+            assert_eq!(read(&mut nodes[1], json!(1)), None);
+
+            sync(&mut nodes, 0);
+            for node in nodes.iter_mut() {
+                // 1 and "1" are different keys
+                assert_eq!(read(node, json!(1)), Some(json!("a")));
+                assert_eq!(read(node, json!("1")), Some(json!("b")));
+            }
+            assert_eq!(nodes[0].tree.root(), nodes[1].tree.root());
+        }
+
+        #[test]
+        fn test_replicas_in_sync_only_compare_roots() {
+            let mut nodes = cluster(2, 4);
+            write(&mut nodes[0], json!("k"), json!(1));
+            sync(&mut nodes, 0);
+            assert_eq!(sync(&mut nodes, 1).len(), 1);
+        }
+
+        #[test]
+        fn test_sync_ships_only_divergent_buckets() {
+            let mut nodes = cluster(2, 10);
+            for key in 0..2000 {
+                write(&mut nodes[0], json!(key), json!(key));
+            }
+            sync(&mut nodes, 0);
+            assert_eq!(nodes[1].values.len(), 2000);
+
+            write(&mut nodes[1], json!(7), json!("new"));
+            let delivered = sync(&mut nodes, 0);
+            // A compare per level, then the repair and its answer
+            assert_eq!(delivered.len(), 10 + 1 + 2);
+            let shipped: usize = delivered
+                .iter()
+                .map(|message| match &message.body {
+                    Body::Repair { entries, .. } | Body::RepairOk { entries, .. } => entries.len(),
+                    _ => 0,
+                })
+                .sum();
+            assert!(shipped < 20, "shipped {} entries", shipped);
+            assert_eq!(read(&mut nodes[0], json!(7)), Some(json!("new")));
+        }
+
+        #[test]
+        fn test_later_writes_win() {
+            let mut nodes = cluster(3, 4);
+            write(&mut nodes[0], json!("k"), json!(1));
+            sync(&mut nodes, 0);
+            write(&mut nodes[1], json!("k"), json!(2));
+            // n1 hears of n2's write through n3
+            let messages = vec![Message {
+                src: "n2".into(),
+                dest: "n3".into(),
+                body: Body::Compare {
+                    msg_id: 1,
+                    hashes: BTreeMap::from([(1, nodes[1].tree.root())]),
+                },
+            }];
+            deliver(&mut nodes, messages);
+            let messages = vec![Message {
+                src: "n3".into(),
+                dest: "n1".into(),
+                body: Body::Compare {
+                    msg_id: 1,
+                    hashes: BTreeMap::from([(1, nodes[2].tree.root())]),
+                },
+            }];
+            deliver(&mut nodes, messages);
+            for node in nodes.iter_mut() {
+                assert_eq!(read(node, json!("k")), Some(json!(2)));
+            }
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::sync(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
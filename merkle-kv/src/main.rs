@@ -0,0 +1,10 @@
+use merkle_kv::node;
+use std::error::Error;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    logging::init()?;
+    let config = node::Config::from_env();
+    let sync_interval = config.sync_interval;
+    maelstrom::run(node::Node::new(config), sync_interval).await
+}
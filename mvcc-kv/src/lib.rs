@@ -0,0 +1,849 @@
+pub mod node {
+    use maelstrom::error::{
+        ABORT, KEY_DOES_NOT_EXIST, TEMPORARILY_UNAVAILABLE, TIMEOUT, TXN_CONFLICT,
+    };
+    use raft::{Outbox, Raft, RaftMessage, Rejected, StateMachine};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{BTreeMap, HashMap};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// A key-value store with snapshot isolation: a transaction reads the store as it was
+    /// when it began, along with its own writes, and commits only if nothing it writes was
+    /// committed by another transaction since. Every key keeps each committed version of
+    /// its value, numbered by the commit that wrote it, so a snapshot is just a version.
+    ///
+    /// A transaction lives on the node that began it, which serves its reads from what
+    /// that node has applied of the log, so they never wait on the network. Its id names
+    /// that node, so requests for it that reach another one are sent on there. Committing
+    /// puts its writes in a Raft log, through the leader, and the first to be applied of
+    /// two transactions writing the same key wins; the other is aborted.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        node_ids: Vec<String>,
+        raft: Option<Raft<Store>>, // Set at init, once we know the cluster
+        next_txn: u64,
+        txns: HashMap<String, Txn>,      // Transactions begun here, by id
+        forwards: HashMap<u64, Waiting>, // Requests sent to another node, by msg_id
+        proposals: HashMap<u64, Proposed>, // Commits we put in the log, by index
+    }
+
+    /// A transaction that's yet to commit or abort.
+    struct Txn {
+        snapshot: u64,                   // The version it reads at
+        writes: BTreeMap<String, Value>, // By each key's JSON, since keys can be any JSON
+        touched: Instant,                // When it was last used, so idle ones can be dropped
+    }
+
+    /// A commit in the log, waiting on it to be applied.
+    struct Proposed {
+        term: u64, // The term it went in with, which the applied entry must have too
+        waiting: Waiting,
+    }
+
+    /// A client, or the node that began a transaction, waiting on a reply to `msg_id`.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on answering
+    }
+
+    /// What every node applies the log to.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+    struct Store {
+        version: u64, // That of the last commit
+        /// Every committed version of each key's value, oldest first, by the key's JSON.
+        versions: BTreeMap<String, Vec<(u64, Value)>>,
+    }
+
+    /// A transaction's writes, as they go in the log.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Commit {
+        snapshot: u64,
+        writes: Vec<(String, Value)>,
+    }
+
+    /// Tunables, read from `MVCC_KV_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// How long a commit waits on the log, or a request on the node we sent it to,
+        /// before we give up.
+        pub request_timeout: Duration,
+        /// Time between ticks, which drive Raft's timers and time out requests and idle
+        /// transactions.
+        pub tick_interval: Duration,
+        /// How long a transaction can go without a request before it's aborted.
+        pub txn_timeout: Duration,
+        pub raft: raft::Config,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                request_timeout: Duration::from_millis(1000),
+                tick_interval: Duration::from_millis(50),
+                txn_timeout: Duration::from_millis(10000),
+                raft: raft::Config::default(),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            let millis = |name, default: Duration| {
+                Duration::from_millis(env_or(name, default.as_millis() as u64))
+            };
+            Config {
+                request_timeout: millis("MVCC_KV_REQUEST_TIMEOUT_MS", default.request_timeout),
+                tick_interval: millis("MVCC_KV_TICK_INTERVAL_MS", default.tick_interval),
+                txn_timeout: millis("MVCC_KV_TXN_TIMEOUT_MS", default.txn_timeout),
+                raft: raft::Config {
+                    election_timeout: millis(
+                        "MVCC_KV_ELECTION_TIMEOUT_MS",
+                        default.raft.election_timeout,
+                    ),
+                    heartbeat_interval: millis(
+                        "MVCC_KV_HEARTBEAT_INTERVAL_MS",
+                        default.raft.heartbeat_interval,
+                    ),
+                    max_batch: env_or("MVCC_KV_MAX_BATCH", default.raft.max_batch),
+                    snapshot_threshold: env_or(
+                        "MVCC_KV_SNAPSHOT_THRESHOLD",
+                        default.raft.snapshot_threshold,
+                    ),
+                },
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Begin {
+            msg_id: u64,
+        },
+        BeginOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            txn_id: String,
+        },
+        Read {
+            msg_id: u64,
+            txn_id: String,
+            key: Value,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: Value,
+        },
+        Write {
+            msg_id: u64,
+            txn_id: String,
+            key: Value,
+            value: Value,
+        },
+        WriteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Commit {
+            msg_id: u64,
+            txn_id: String,
+        },
+        /// With the version the transaction's writes were committed at.
+        CommitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            version: u64,
+        },
+        Abort {
+            msg_id: u64,
+            txn_id: String,
+        },
+        AbortOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// A transaction's writes, from the node it began on to the leader, which answers
+        /// with a commit_ok or an error.
+        Apply {
+            msg_id: u64,
+            #[serde(flatten)]
+            commit: Commit,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        #[serde(untagged)]
+        Raft(RaftMessage<Commit, Store>),
+    }
+
+    impl Body {
+        /// The id of the request this replies to, if it's a reply.
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::InitOk { in_reply_to, .. }
+                | Body::BeginOk { in_reply_to, .. }
+                | Body::ReadOk { in_reply_to, .. }
+                | Body::WriteOk { in_reply_to, .. }
+                | Body::CommitOk { in_reply_to, .. }
+                | Body::AbortOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::Init { msg_id, .. }
+                | Body::InitOk { msg_id, .. }
+                | Body::Begin { msg_id, .. }
+                | Body::BeginOk { msg_id, .. }
+                | Body::Read { msg_id, .. }
+                | Body::ReadOk { msg_id, .. }
+                | Body::Write { msg_id, .. }
+                | Body::WriteOk { msg_id, .. }
+                | Body::Commit { msg_id, .. }
+                | Body::CommitOk { msg_id, .. }
+                | Body::Abort { msg_id, .. }
+                | Body::AbortOk { msg_id, .. }
+                | Body::Apply { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+
+        /// The transaction a request is part of.
+        fn txn_id(&self) -> Option<&str> {
+            match self {
+                Body::Read { txn_id, .. }
+                | Body::Write { txn_id, .. }
+                | Body::Commit { txn_id, .. }
+                | Body::Abort { txn_id, .. } => Some(txn_id),
+                _ => None,
+            }
+        }
+    }
+
+    fn error(code: u64, text: &str) -> Body {
+        Body::Error {
+            in_reply_to: 0,
+            code,
+            text: text.to_string(),
+        }
+    }
+
+    impl Store {
+        /// The key's value as of `snapshot`.
+        fn read(&self, key: &str, snapshot: u64) -> Option<&Value> {
+            let versions = self.versions.get(key)?;
+            let (_, value) = versions
+                .iter()
+                .rev()
+                .find(|(version, _)| *version <= snapshot)?;
+            Some(value)
+        }
+    }
+
+    impl StateMachine for Store {
+        type Command = Commit;
+        /// The version committed at, or the key that was written since the snapshot.
+        type Output = Result<u64, String>;
+        type Snapshot = Store;
+
+        fn apply(&mut self, commit: &Commit) -> Result<u64, String> {
+            let conflict = commit.writes.iter().find(|(key, _)| {
+                let latest = self.versions.get(key).and_then(|versions| versions.last());
+                latest.is_some_and(|(version, _)| *version > commit.snapshot)
+            });
+            if let Some((key, _)) = conflict {
+                return Err(key.clone());
+            }
+            self.version += 1;
+            for (key, value) in &commit.writes {
+                let versions = self.versions.entry(key.clone()).or_default();
+                versions.push((self.version, value.clone()));
+            }
+            Ok(self.version)
+        }
+
+        fn snapshot(&self) -> Store {
+            self.clone()
+        }
+
+        fn restore(&mut self, snapshot: Store) {
+            *self = snapshot;
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                node_ids: Vec::new(),
+                raft: None,
+                next_txn: 0,
+                txns: HashMap::new(),
+                forwards: HashMap::new(),
+                proposals: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            let raft = self.raft.as_ref().map(|raft| {
+                json!({
+                    "term": raft.term(),
+                    "leader": raft.leader(),
+                    "nodes": raft.nodes(),
+                    "last_index": raft.last_index(),
+                    "commit_index": raft.commit_index(),
+                })
+            });
+            json!({
+                "id": self.id,
+                "raft": raft,
+                "forwards": self.forwards.len(),
+                "proposals": self.proposals.len(),
+                "txns": self.txns.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Drives Raft's timers, and gives up on requests that have waited too long and
+        /// transactions that have sat idle too long.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            let mut outbox = Vec::new();
+            if let Some(raft) = &mut self.raft {
+                raft.tick(&mut outbox);
+            }
+            self.send_raft(outbox, &mut messages);
+
+            let now = Instant::now();
+            let txn_timeout = self.config.txn_timeout;
+            self.txns.retain(|txn_id, txn| {
+                let idle = txn.touched + txn_timeout <= now;
+                if idle {
+                    log::info!("Aborting idle transaction {}", txn_id);
+                }
+                !idle
+            });
+            let expired_forwards: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            let expired_proposals: Vec<u64> = self
+                .proposals
+                .iter()
+                .filter(|(_, proposed)| proposed.waiting.deadline <= now)
+                .map(|(index, _)| *index)
+                .collect();
+            let mut expired: Vec<Waiting> = expired_forwards
+                .iter()
+                .filter_map(|msg_id| self.forwards.remove(msg_id))
+                .chain(
+                    expired_proposals
+                        .iter()
+                        .filter_map(|index| Some(self.proposals.remove(index)?.waiting)),
+                )
+                .collect();
+            expired.sort_by_key(|waiting| waiting.msg_id);
+            for waiting in expired {
+                // A commit may yet be applied, or have been and only the reply was lost, so
+                // this mustn't claim it was aborted
+                let error = error(TIMEOUT, "timed out waiting on the log");
+                self.reply(waiting, error, &mut messages);
+            }
+            messages
+        }
+
+        /// Wraps Raft's messages for the wire, and answers whoever's waiting on the commits
+        /// it has applied since.
+        fn send_raft(&mut self, outbox: Outbox<Store>, messages: &mut Vec<Message>) {
+            for (dest, message) in outbox {
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest,
+                    body: Body::Raft(message),
+                });
+            }
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            for applied in raft.take_applied() {
+                let Some(proposed) = self.proposals.remove(&applied.index) else {
+                    continue;
+                };
+                let reply = match applied.output {
+                    Some(Ok(version)) if proposed.term == applied.term => Body::CommitOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        version,
+                    },
+                    Some(Err(key)) if proposed.term == applied.term => error(
+                        TXN_CONFLICT,
+                        &format!("key {} was written since the snapshot", key),
+                    ),
+                    // Another leader's entry took its place, so it never will take effect
+                    _ => error(TEMPORARILY_UNAVAILABLE, "lost leadership before committing"),
+                };
+                self.reply(proposed.waiting, reply, messages);
+            }
+        }
+
+        fn reply(&mut self, waiting: Waiting, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = waiting.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: waiting.client,
+                body,
+            });
+        }
+
+        /// Sends a request on to `dest` with an id of our own, so we can tell which client
+        /// its reply is for.
+        fn forward(
+            &mut self,
+            dest: String,
+            mut body: Body,
+            waiting: Waiting,
+            messages: &mut Vec<Message>,
+        ) {
+            let forward_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = forward_id;
+            }
+            self.forwards.insert(forward_id, waiting);
+            messages.push(Message {
+                src: self.id.clone(),
+                dest,
+                body,
+            });
+        }
+
+        /// Puts a transaction's writes in the log if we're leading, or sends them to the
+        /// leader if they came from a client of ours. Writes that already came from another
+        /// node aren't sent on again, so they can't go round in circles.
+        fn propose(&mut self, commit: Commit, waiting: Waiting, messages: &mut Vec<Message>) {
+            let from_node = self.node_ids.contains(&waiting.client);
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            let mut outbox = Vec::new();
+            match raft.propose(commit.clone(), &mut outbox) {
+                Ok(proposal) => {
+                    let proposed = Proposed {
+                        term: proposal.term,
+                        waiting,
+                    };
+                    self.proposals.insert(proposal.index, proposed);
+                }
+                Err(Rejected::NotLeader(Some(leader))) if !from_node => {
+                    let apply = Body::Apply { msg_id: 0, commit };
+                    self.forward(leader, apply, waiting, messages);
+                }
+                Err(_) => {
+                    let error = error(TEMPORARILY_UNAVAILABLE, "no leader to commit this");
+                    self.reply(waiting, error, messages);
+                }
+            }
+            self.send_raft(outbox, messages);
+        }
+
+        /// Serves a request that's part of a transaction begun here, which for a commit with
+        /// writes means putting them in the log.
+        fn txn_request(&mut self, body: Body, waiting: Waiting, messages: &mut Vec<Message>) {
+            let Some(txn) = body.txn_id().and_then(|txn_id| self.txns.get_mut(txn_id)) else {
+                let error = error(ABORT, "no such transaction, or it's been aborted");
+                self.reply(waiting, error, messages);
+                return;
+            };
+            txn.touched = Instant::now();
+            let reply = match body {
+                Body::Read { key, .. } => {
+                    let key = key.to_string();
+                    let store = self.raft.as_ref().map(|raft| raft.state());
+                    let value = (txn.writes.get(&key))
+                        .or_else(|| store?.read(&key, txn.snapshot))
+                        .cloned();
+                    match value {
+                        Some(value) => Body::ReadOk {
+                            msg_id: 0,
+                            in_reply_to: 0,
+                            value,
+                        },
+                        None => error(KEY_DOES_NOT_EXIST, "key does not exist"),
+                    }
+                }
+                Body::Write { key, value, .. } => {
+                    txn.writes.insert(key.to_string(), value);
+                    Body::WriteOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    }
+                }
+                Body::Commit { txn_id, .. } => {
+                    let Some(txn) = self.txns.remove(&txn_id) else {
+                        return;
+                    };
+                    // Reading only, it saw a snapshot that was committed, so there's nothing
+                    // to check
+                    if txn.writes.is_empty() {
+                        Body::CommitOk {
+                            msg_id: 0,
+                            in_reply_to: 0,
+                            version: txn.snapshot,
+                        }
+                    } else {
+                        let commit = Commit {
+                            snapshot: txn.snapshot,
+                            writes: txn.writes.into_iter().collect(),
+                        };
+                        self.propose(commit, waiting, messages);
+                        return;
+                    }
+                }
+                Body::Abort { txn_id, .. } => {
+                    self.txns.remove(&txn_id);
+                    Body::AbortOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    }
+                }
+                _ => return,
+            };
+            self.reply(waiting, reply, messages);
+        }
+
+        /// Serves a request from a client, or from another node on its behalf: beginning a
+        /// transaction here, or taking part in one, which is sent on to the node that began
+        /// it if that isn't us.
+        fn request(&mut self, src: &str, body: Body, messages: &mut Vec<Message>) {
+            let Some(msg_id) = body.clone().msg_id().copied() else {
+                return;
+            };
+            let waiting = Waiting {
+                client: src.to_string(),
+                msg_id,
+                deadline: Instant::now() + self.config.request_timeout,
+            };
+            match &body {
+                Body::Begin { .. } => {
+                    let txn_id = format!("{}:{}", self.id, self.next_txn);
+                    self.next_txn += 1;
+                    let txn = Txn {
+                        snapshot: self.raft.as_ref().map_or(0, |raft| raft.state().version),
+                        writes: BTreeMap::new(),
+                        touched: Instant::now(),
+                    };
+                    self.txns.insert(txn_id.clone(), txn);
+                    let begin_ok = Body::BeginOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        txn_id,
+                    };
+                    self.reply(waiting, begin_ok, messages);
+                }
+                Body::Apply { commit, .. } => self.propose(commit.clone(), waiting, messages),
+                _ => {
+                    let owner = body.txn_id().and_then(|txn_id| txn_id.split_once(':'));
+                    let owner = owner.map(|(node, _)| node.to_string()).unwrap_or_default();
+                    let from_node = self.node_ids.iter().any(|node| node == src);
+                    if self.node_ids.contains(&owner) && owner != self.id && !from_node {
+                        self.forward(owner, body, waiting, messages);
+                    } else {
+                        self.txn_request(body, waiting, messages);
+                    }
+                }
+            }
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.raft = Some(Raft::new(
+                        node_id.clone(),
+                        node_ids.clone(),
+                        Store::default(),
+                        self.config.raft.clone(),
+                    ));
+                    self.node_ids = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Begin { .. }
+                | Body::Read { .. }
+                | Body::Write { .. }
+                | Body::Commit { .. }
+                | Body::Abort { .. }
+                | Body::Apply { .. } => {
+                    self.request(src, body, outbox);
+                    None
+                }
+                Body::Raft(message) => {
+                    let mut raft_outbox = Vec::new();
+                    self.raft.as_mut()?.handle(src, message, &mut raft_outbox);
+                    self.send_raft(raft_outbox, outbox);
+                    None
+                }
+                // An answer to a request we sent on, which goes back to whoever sent it us
+                mut reply => {
+                    let forward = self.forwards.remove(reply.in_reply_to()?)?;
+                    self.reply(forward, reply, outbox);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, deliver};
+        use serde_json::json;
+
+        /// n1 to n`count`, with n1 elected leader.
+        fn cluster(count: usize) -> Vec<Node> {
+            let mut nodes: Vec<Node> = testing::cluster(count, |id| {
+                let mut config = Config::default();
+                config.raft.heartbeat_interval = Duration::ZERO;
+                if id == "n1" {
+                    config.raft.election_timeout = Duration::ZERO;
+                }
+                Node::new(config)
+            });
+            let messages = nodes[0].tick();
+            deliver(&mut nodes, messages);
+            assert!(nodes[0].raft.as_ref().unwrap().is_leader());
+            nodes
+        }
+
+        /// Sends `body` to `dest` from a client, returning the reply as JSON.
+        fn request(nodes: &mut [Node], dest: &str, body: serde_json::Value) -> serde_json::Value {
+            let message =
+                serde_json::from_value(json!({"src": "c1", "dest": dest, "body": body})).unwrap();
+            let n: usize = dest[1..].parse().unwrap();
+            let messages = nodes[n - 1].handle_message(message);
+            let replies = deliver(nodes, messages);
+            assert_eq!(replies.len(), 1);
+            serde_json::to_value(&replies[0].body).unwrap()
+        }
+
+        fn begin(nodes: &mut [Node], dest: &str) -> String {
+            let reply = request(nodes, dest, json!({"type": "begin", "msg_id": 1}));
+            reply["txn_id"].as_str().unwrap().to_string()
+        }
+
+        fn read(nodes: &mut [Node], dest: &str, txn_id: &str, key: u64) -> serde_json::Value {
+            request(
+                nodes,
+                dest,
+                json!({"type": "read", "msg_id": 2, "txn_id": txn_id, "key": key}),
+            )
+        }
+
+        fn write(nodes: &mut [Node], dest: &str, txn_id: &str, key: u64, value: u64) {
+            let body =
+                json!({"type": "write", "msg_id": 3, "txn_id": txn_id, "key": key, "value": value});
+            assert_eq!(request(nodes, dest, body)["type"], "write_ok");
+        }
+
+        fn commit(nodes: &mut [Node], dest: &str, txn_id: &str) -> serde_json::Value {
+            request(
+                nodes,
+                dest,
+                json!({"type": "commit", "msg_id": 4, "txn_id": txn_id}),
+            )
+        }
+
+        #[test]
+        fn test_reads_see_snapshot_and_own_writes() {
+            let mut nodes = cluster(3);
+            let before = begin(&mut nodes, "n1");
+            let txn = begin(&mut nodes, "n1");
+            write(&mut nodes, "n1", &txn, 1, 10);
+            assert_eq!(read(&mut nodes, "n1", &txn, 1)["value"], 10);
+            assert_eq!(commit(&mut nodes, "n1", &txn)["version"], 1);
+
+            assert_eq!(
+                read(&mut nodes, "n1", &before, 1)["code"],
+                KEY_DOES_NOT_EXIST
+            );
+            let after = begin(&mut nodes, "n1");
+            assert_eq!(read(&mut nodes, "n1", &after, 1)["value"], 10);
+            // Reading only, it commits at its snapshot
+            assert_eq!(commit(&mut nodes, "n1", &before)["version"], 0);
+        }
+
+        #[test]
+        fn test_write_write_conflict_aborts() {
+            let mut nodes = cluster(3);
+            let first = begin(&mut nodes, "n1");
+            let second = begin(&mut nodes, "n2");
+            write(&mut nodes, "n1", &first, 1, 10);
+            write(&mut nodes, "n2", &second, 1, 20);
+            write(&mut nodes, "n2", &second, 2, 20);
+
+            assert_eq!(commit(&mut nodes, "n1", &first)["type"], "commit_ok");
+            let conflict = commit(&mut nodes, "n2", &second);
+            assert_eq!(
+                (conflict["type"].as_str(), conflict["code"].as_u64()),
+                (Some("error"), Some(TXN_CONFLICT))
+            );
+
+            let store = nodes[0].raft.as_ref().unwrap().state();
+            assert_eq!(store.versions["1"], vec![(1, json!(10))]);
+            assert!(!store.versions.contains_key("2"));
+        }
+
+        #[test]
+        fn test_commits_on_followers_reach_every_node() {
+            let mut nodes = cluster(3);
+            let txn = begin(&mut nodes, "n2");
+            write(&mut nodes, "n2", &txn, 1, 10);
+            assert_eq!(commit(&mut nodes, "n2", &txn)["version"], 1);
+
+            // Followers hear the commit with the next heartbeat
+            let messages = nodes[0].tick();
+            deliver(&mut nodes, messages);
+            for node in &nodes {
+                let store = node.raft.as_ref().unwrap().state();
+                assert_eq!(store.read("1", 1), Some(&json!(10)));
+            }
+        }
+
+        #[test]
+        fn test_requests_go_to_the_node_that_began_the_txn() {
+            let mut nodes = cluster(3);
+            let txn = begin(&mut nodes, "n1");
+            assert!(txn.starts_with("n1:"));
+            write(&mut nodes, "n3", &txn, 1, 10);
+            assert_eq!(read(&mut nodes, "n2", &txn, 1)["value"], 10);
+            assert_eq!(commit(&mut nodes, "n3", &txn)["version"], 1);
+            assert!(nodes.iter().all(|node| node.txns.is_empty()));
+        }
+
+        #[test]
+        fn test_aborted_and_idle_txns_are_gone() {
+            let mut nodes = cluster(3);
+            let txn = begin(&mut nodes, "n1");
+            write(&mut nodes, "n1", &txn, 1, 10);
+            let abort = json!({"type": "abort", "msg_id": 5, "txn_id": txn});
+            assert_eq!(request(&mut nodes, "n1", abort)["type"], "abort_ok");
+            assert_eq!(commit(&mut nodes, "n1", &txn)["code"], ABORT);
+
+            let idle = begin(&mut nodes, "n2");
+            nodes[1].config.txn_timeout = Duration::ZERO;
+            nodes[1].tick();
+            assert_eq!(read(&mut nodes, "n2", &idle, 1)["code"], ABORT);
+            assert_eq!(nodes[0].raft.as_ref().unwrap().state().version, 0);
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
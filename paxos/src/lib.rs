@@ -0,0 +1,739 @@
+//! Multi-Paxos over the Maelstrom message bus: a distinguished proposer, elected by running
+//! the prepare round once for every slot it hasn't seen chosen, then choosing a command per
+//! slot with a single accept round each. Every node applies the chosen commands, in slot
+//! order, to the same `StateMachine` Raft drives, so a host can run either underneath it.
+//!
+//! Hosts use it the way they use Raft: embed `PaxosMessage`s in their own message bodies,
+//! hand the ones they receive to `Paxos::handle`, call `Paxos::tick` regularly, and send on
+//! whatever lands in the outbox. Nothing is persisted, and unlike Raft the log is never
+//! compacted, so a lagging node is caught up from every slot it's missing.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+pub use raft::StateMachine;
+
+/// Tunables for a Paxos node.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How long a node waits to hear from a leader before preparing a ballot of its own,
+    /// plus up to as long again at random so candidates rarely knock each other out.
+    pub election_timeout: Duration,
+    /// Time between a leader's accepts to each node, which keep it in office and resend
+    /// whatever isn't chosen yet.
+    pub heartbeat_interval: Duration,
+    /// Most slots sent in one accept or learn.
+    pub max_batch: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            election_timeout: Duration::from_millis(500),
+            heartbeat_interval: Duration::from_millis(100),
+            max_batch: 64,
+        }
+    }
+}
+
+/// Orders proposers, by round and then by node, so no two share one.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Ballot {
+    pub round: u64,
+    pub node: String,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry<C> {
+    /// The ballot that first proposed it, which it keeps when a later leader proposes it
+    /// again.
+    pub ballot: Ballot,
+    /// `None` for the no-op a new leader fills the slots nobody proposed anything for with.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<C>,
+}
+
+/// What an acceptor holds for a slot.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Slot<C> {
+    /// The ballot it was accepted under.
+    pub ballot: Ballot,
+    /// Whether we know it's chosen, and so can never change.
+    pub chosen: bool,
+    pub entry: Entry<C>,
+}
+
+/// The messages Paxos nodes exchange, tagged by "type" like any Maelstrom body, carrying
+/// commands of type `C`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum PaxosMessage<C> {
+    /// Asks for a promise to ignore lower ballots, for `first_index` and every slot after.
+    Prepare { ballot: Ballot, first_index: u64 },
+    /// The promise, with what we hold from the prepare's `first_index` on.
+    Promise {
+        ballot: Ballot,
+        slots: BTreeMap<u64, Slot<C>>,
+    },
+    /// Asks for `entries` to be accepted into the slots from `first_index` on. Also the
+    /// leader's heartbeat, and how it tells acceptors what's chosen.
+    Accept {
+        ballot: Ballot,
+        first_index: u64,
+        entries: Vec<Entry<C>>,
+        commit_index: u64,
+    },
+    /// Accepts the slots from `first_index` to `last_index`, reporting how far we know
+    /// what's chosen.
+    AcceptOk {
+        ballot: Ballot,
+        first_index: u64,
+        last_index: u64,
+        commit_index: u64,
+    },
+    /// Chosen entries, for the slots from `first_index` on, for a node that's missing them.
+    Learn {
+        ballot: Ballot,
+        first_index: u64,
+        entries: Vec<Entry<C>>,
+    },
+    /// Refuses a prepare or accept for `ballot`, having promised the higher `promised`.
+    Reject { ballot: Ballot, promised: Ballot },
+}
+
+/// Where a proposed command went. It took effect if the entry applied at `index` has the
+/// same `ballot`; if an entry of another ballot is applied there, it never will.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Proposal {
+    pub index: u64,
+    pub ballot: Ballot,
+}
+
+/// A command's result, once it's been chosen and applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Applied<O> {
+    pub index: u64,
+    pub ballot: Ballot,
+    pub output: O,
+}
+
+/// A proposal made to a node that isn't leading, with who is, if it knows.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotLeader(pub Option<String>);
+
+enum Role<C> {
+    Follower,
+    Candidate {
+        promises: HashMap<String, BTreeMap<u64, Slot<C>>>, // What each promising node holds
+    },
+    Leader {
+        accepted: HashMap<u64, HashSet<String>>, // Who has accepted each unchosen slot
+    },
+}
+
+pub struct Paxos<S: StateMachine> {
+    id: String,
+    nodes: Vec<String>,
+    config: Config,
+    state: S,
+    promised: Ballot, // The highest ballot we've seen, which is ours while we stand or lead
+    role: Role<S::Command>,
+    leader: Option<String>, // Whose ballot we last accepted under
+    log: BTreeMap<u64, Slot<S::Command>>,
+    commit_index: u64,                // Every slot up to here is chosen and applied
+    applied: Vec<Applied<S::Output>>, // Results not yet taken by the host
+    election_deadline: Instant,
+    heartbeat_due: Instant,
+}
+
+/// Messages for other nodes, with who each is for.
+pub type Outbox<S> = Vec<(String, PaxosMessage<<S as StateMachine>::Command>)>;
+
+impl<S: StateMachine> Paxos<S> {
+    pub fn new(id: String, nodes: Vec<String>, state: S, config: Config) -> Self {
+        let now = Instant::now();
+        let mut paxos = Paxos {
+            id,
+            nodes,
+            config,
+            state,
+            promised: Ballot::default(),
+            role: Role::Follower,
+            leader: None,
+            log: BTreeMap::new(),
+            commit_index: 0,
+            applied: Vec::new(),
+            election_deadline: now,
+            heartbeat_due: now,
+        };
+        paxos.reset_election_deadline();
+        paxos
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    /// The leader we're following, if we know it.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    pub fn ballot(&self) -> &Ballot {
+        &self.promised
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Results of the commands applied since the last call, in slot order.
+    pub fn take_applied(&mut self) -> Vec<Applied<S::Output>> {
+        std::mem::take(&mut self.applied)
+    }
+
+    /// Proposes `command` for the next free slot if we're the leader.
+    pub fn propose(
+        &mut self,
+        command: S::Command,
+        outbox: &mut Outbox<S>,
+    ) -> Result<Proposal, NotLeader> {
+        if !self.is_leader() {
+            return Err(NotLeader(self.leader.clone()));
+        }
+        let index = self.last_index() + 1;
+        let entry = Entry {
+            ballot: self.promised.clone(),
+            command: Some(command),
+        };
+        self.log.insert(
+            index,
+            Slot {
+                ballot: self.promised.clone(),
+                chosen: false,
+                entry: entry.clone(),
+            },
+        );
+        let accept = PaxosMessage::Accept {
+            ballot: self.promised.clone(),
+            first_index: index,
+            entries: vec![entry],
+            commit_index: self.commit_index,
+        };
+        for peer in self.peers() {
+            outbox.push((peer.clone(), accept.clone()));
+        }
+        self.count_accepted(&self.id.clone(), index, index);
+        Ok(Proposal {
+            index,
+            ballot: self.promised.clone(),
+        })
+    }
+
+    /// Sends heartbeats if we're leading, or prepares a ballot if the leader's gone quiet.
+    pub fn tick(&mut self, outbox: &mut Outbox<S>) {
+        let now = Instant::now();
+        if self.is_leader() {
+            if now >= self.heartbeat_due {
+                self.broadcast_accept(outbox);
+            }
+        } else if now >= self.election_deadline {
+            self.start_election(outbox);
+        }
+    }
+
+    pub fn handle(&mut self, src: &str, message: PaxosMessage<S::Command>, outbox: &mut Outbox<S>) {
+        match message {
+            PaxosMessage::Prepare {
+                ballot,
+                first_index,
+            } => {
+                if ballot < self.promised {
+                    outbox.push((src.to_string(), self.reject(ballot)));
+                    return;
+                }
+                self.adopt(ballot.clone());
+                self.reset_election_deadline();
+                let slots = self
+                    .log
+                    .range(first_index..)
+                    .map(|(index, slot)| (*index, slot.clone()))
+                    .collect();
+                outbox.push((src.to_string(), PaxosMessage::Promise { ballot, slots }));
+            }
+            PaxosMessage::Promise { ballot, slots } => {
+                if ballot != self.promised {
+                    return;
+                }
+                if let Role::Candidate { promises } = &mut self.role {
+                    promises.insert(src.to_string(), slots);
+                    self.check_promises(outbox);
+                }
+            }
+            PaxosMessage::Accept {
+                ballot,
+                first_index,
+                entries,
+                commit_index,
+            } => {
+                if ballot < self.promised {
+                    outbox.push((src.to_string(), self.reject(ballot)));
+                    return;
+                }
+                self.adopt(ballot.clone());
+                self.leader = Some(ballot.node.clone());
+                self.reset_election_deadline();
+                let last_index = first_index + entries.len() as u64 - 1;
+                for (index, entry) in (first_index..).zip(entries) {
+                    if self.log.get(&index).is_some_and(|slot| slot.chosen) {
+                        continue;
+                    }
+                    let slot = Slot {
+                        ballot: ballot.clone(),
+                        chosen: false,
+                        entry,
+                    };
+                    self.log.insert(index, slot);
+                }
+                // The leader proposes one entry per slot, so one we accepted under its
+                // ballot is the one it's seen chosen
+                let known = self.log.range_mut(self.commit_index + 1..);
+                for (_, slot) in known.take_while(|(index, _)| **index <= commit_index) {
+                    if slot.ballot == ballot {
+                        slot.chosen = true;
+                    }
+                }
+                self.advance();
+                outbox.push((
+                    src.to_string(),
+                    PaxosMessage::AcceptOk {
+                        ballot,
+                        first_index,
+                        last_index,
+                        commit_index: self.commit_index,
+                    },
+                ));
+            }
+            PaxosMessage::AcceptOk {
+                ballot,
+                first_index,
+                last_index,
+                commit_index,
+            } => {
+                if ballot != self.promised || !self.is_leader() {
+                    return;
+                }
+                self.count_accepted(src, first_index, last_index);
+                if commit_index < self.commit_index {
+                    let entries = self
+                        .log
+                        .range(commit_index + 1..=self.commit_index)
+                        .take(self.config.max_batch)
+                        .map(|(_, slot)| slot.entry.clone())
+                        .collect();
+                    outbox.push((
+                        src.to_string(),
+                        PaxosMessage::Learn {
+                            ballot,
+                            first_index: commit_index + 1,
+                            entries,
+                        },
+                    ));
+                }
+            }
+            PaxosMessage::Learn {
+                ballot,
+                first_index,
+                entries,
+            } => {
+                // What's chosen stays chosen, whoever's ballot told us
+                for (index, entry) in (first_index..).zip(entries) {
+                    let slot = Slot {
+                        ballot: ballot.clone(),
+                        chosen: true,
+                        entry,
+                    };
+                    self.log.insert(index, slot);
+                }
+                self.advance();
+            }
+            PaxosMessage::Reject { promised, .. } => {
+                if promised > self.promised {
+                    self.adopt(promised);
+                }
+            }
+        }
+    }
+
+    /// The highest slot we hold an entry for.
+    pub fn last_index(&self) -> u64 {
+        self.log.keys().next_back().copied().unwrap_or(0)
+    }
+
+    fn majority(&self) -> usize {
+        self.nodes.len() / 2 + 1
+    }
+
+    fn peers(&self) -> impl Iterator<Item = &String> {
+        self.nodes.iter().filter(move |node| **node != self.id)
+    }
+
+    fn reject(&self, ballot: Ballot) -> PaxosMessage<S::Command> {
+        PaxosMessage::Reject {
+            ballot,
+            promised: self.promised.clone(),
+        }
+    }
+
+    /// Promises `ballot`, which is at least ours, stepping down if it's someone else's.
+    fn adopt(&mut self, ballot: Ballot) {
+        if ballot == self.promised {
+            return;
+        }
+        self.promised = ballot;
+        self.leader = None;
+        if !matches!(self.role, Role::Follower) {
+            log::info!("{} stepping down for {:?}", self.id, self.promised);
+            self.role = Role::Follower;
+        }
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let jitter =
+            rand::thread_rng().gen_range(0..=self.config.election_timeout.as_millis() as u64);
+        self.election_deadline =
+            Instant::now() + self.config.election_timeout + Duration::from_millis(jitter);
+    }
+
+    /// Prepares a ballot higher than any we've seen, for every slot we don't know is chosen.
+    fn start_election(&mut self, outbox: &mut Outbox<S>) {
+        self.promised = Ballot {
+            round: self.promised.round + 1,
+            node: self.id.clone(),
+        };
+        self.leader = None;
+        let first_index = self.commit_index + 1;
+        let ours = self
+            .log
+            .range(first_index..)
+            .map(|(index, slot)| (*index, slot.clone()))
+            .collect();
+        self.role = Role::Candidate {
+            promises: HashMap::from([(self.id.clone(), ours)]),
+        };
+        self.reset_election_deadline();
+        log::info!("{} preparing {:?}", self.id, self.promised);
+        let prepare = PaxosMessage::Prepare {
+            ballot: self.promised.clone(),
+            first_index,
+        };
+        for peer in self.peers() {
+            outbox.push((peer.clone(), prepare.clone()));
+        }
+        self.check_promises(outbox);
+    }
+
+    /// Takes office once a majority has promised: every slot any of them holds is proposed
+    /// again under our ballot, with what was chosen there if anyone knows, and otherwise
+    /// what was accepted under the highest ballot, since that's the only entry that could
+    /// have been chosen. Slots none of them hold get no-ops.
+    fn check_promises(&mut self, outbox: &mut Outbox<S>) {
+        let Role::Candidate { promises } = &mut self.role else {
+            return;
+        };
+        if promises.len() < self.nodes.len() / 2 + 1 {
+            return;
+        }
+        let mut merged: BTreeMap<u64, Slot<S::Command>> = BTreeMap::new();
+        for (index, slot) in std::mem::take(promises).into_values().flatten() {
+            match merged.get(&index) {
+                Some(held) if held.chosen || (!slot.chosen && held.ballot >= slot.ballot) => {}
+                _ => {
+                    merged.insert(index, slot);
+                }
+            }
+        }
+        let last_index = merged.keys().next_back().copied().unwrap_or(0);
+        for index in self.commit_index + 1..=last_index {
+            let slot = match merged.remove(&index) {
+                Some(slot) if slot.chosen => slot,
+                Some(slot) => Slot {
+                    ballot: self.promised.clone(),
+                    chosen: false,
+                    entry: slot.entry,
+                },
+                None => Slot {
+                    ballot: self.promised.clone(),
+                    chosen: false,
+                    entry: Entry {
+                        ballot: self.promised.clone(),
+                        command: None,
+                    },
+                },
+            };
+            self.log.insert(index, slot);
+        }
+        log::info!("{} leading with {:?}", self.id, self.promised);
+        self.role = Role::Leader {
+            accepted: HashMap::new(),
+        };
+        self.leader = Some(self.id.clone());
+        self.count_accepted(&self.id.clone(), self.commit_index + 1, last_index);
+        self.broadcast_accept(outbox);
+    }
+
+    /// Sends every node the slots we haven't seen chosen yet, up to a batch of them, or
+    /// just a heartbeat if there are none.
+    fn broadcast_accept(&mut self, outbox: &mut Outbox<S>) {
+        let entries: Vec<Entry<S::Command>> = self
+            .log
+            .range(self.commit_index + 1..)
+            .take(self.config.max_batch)
+            .map(|(_, slot)| slot.entry.clone())
+            .collect();
+        let accept = PaxosMessage::Accept {
+            ballot: self.promised.clone(),
+            first_index: self.commit_index + 1,
+            entries,
+            commit_index: self.commit_index,
+        };
+        for peer in self.peers() {
+            outbox.push((peer.clone(), accept.clone()));
+        }
+        self.heartbeat_due = Instant::now() + self.config.heartbeat_interval;
+    }
+
+    /// Counts `acceptor` as having accepted the slots from `first_index` to `last_index`,
+    /// marking those a majority has accepted as chosen.
+    fn count_accepted(&mut self, acceptor: &str, first_index: u64, last_index: u64) {
+        let majority = self.majority();
+        let Role::Leader { accepted } = &mut self.role else {
+            return;
+        };
+        let slots = self.log.range_mut(first_index..);
+        for (index, slot) in slots.take_while(|(index, _)| **index <= last_index) {
+            if slot.chosen {
+                continue;
+            }
+            let acceptors = accepted.entry(*index).or_default();
+            acceptors.insert(acceptor.to_string());
+            if acceptors.len() >= majority {
+                slot.chosen = true;
+                accepted.remove(index);
+            }
+        }
+        self.advance();
+    }
+
+    /// Applies every chosen slot after the last one applied, stopping at the first gap.
+    fn advance(&mut self) {
+        while let Some(slot) = self.log.get(&(self.commit_index + 1)) {
+            if !slot.chosen {
+                break;
+            }
+            self.commit_index += 1;
+            if let Some(command) = &slot.entry.command {
+                let output = self.state.apply(command);
+                self.applied.push(Applied {
+                    index: self.commit_index,
+                    ballot: slot.entry.ballot.clone(),
+                    output,
+                });
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds up every command, returning the running total.
+    #[derive(Default)]
+    struct Sum(u64);
+
+    impl StateMachine for Sum {
+        type Command = u64;
+        type Output = u64;
+        type Snapshot = u64;
+
+        fn apply(&mut self, command: &u64) -> u64 {
+            self.0 += command;
+            self.0
+        }
+
+        fn snapshot(&self) -> u64 {
+            self.0
+        }
+
+        fn restore(&mut self, snapshot: u64) {
+            self.0 = snapshot;
+        }
+    }
+
+    fn cluster() -> Vec<Paxos<Sum>> {
+        let nodes: Vec<String> = vec!["n0".into(), "n1".into(), "n2".into()];
+        nodes
+            .iter()
+            .map(|id| Paxos::new(id.clone(), nodes.clone(), Sum::default(), Config::default()))
+            .collect()
+    }
+
+    /// Delivers `outbox` from `src`, and everything sent in response, until the cluster's
+    /// quiet. Messages to or from nodes in `down` are dropped.
+    fn deliver(cluster: &mut [Paxos<Sum>], src: usize, outbox: Outbox<Sum>, down: &[usize]) {
+        let mut in_flight: Vec<(usize, Outbox<Sum>)> = vec![(src, outbox)];
+        while let Some((src, outbox)) = in_flight.pop() {
+            for (dest, message) in outbox {
+                let dest: usize = dest[1..].parse().unwrap();
+                if down.contains(&src) || down.contains(&dest) {
+                    continue;
+                }
+                let mut replies = Vec::new();
+                cluster[dest].handle(&format!("n{}", src), message, &mut replies);
+                in_flight.push((dest, replies));
+            }
+        }
+    }
+
+    fn elect(cluster: &mut [Paxos<Sum>], node: usize, down: &[usize]) {
+        let mut outbox = Vec::new();
+        cluster[node].start_election(&mut outbox);
+        deliver(cluster, node, outbox, down);
+        assert!(cluster[node].is_leader());
+    }
+
+    fn propose(cluster: &mut [Paxos<Sum>], node: usize, command: u64, down: &[usize]) -> Proposal {
+        let mut outbox = Vec::new();
+        let proposal = cluster[node].propose(command, &mut outbox).unwrap();
+        deliver(cluster, node, outbox, down);
+        proposal
+    }
+
+    fn heartbeat(cluster: &mut [Paxos<Sum>], node: usize, down: &[usize]) {
+        let mut outbox = Vec::new();
+        cluster[node].broadcast_accept(&mut outbox);
+        deliver(cluster, node, outbox, down);
+    }
+
+    #[test]
+    fn test_commands_apply_everywhere_in_order() {
+        let mut cluster = cluster();
+        elect(&mut cluster, 0, &[]);
+        propose(&mut cluster, 0, 2, &[]);
+        let proposal = propose(&mut cluster, 0, 3, &[]);
+        // The others hear what's chosen with the next heartbeat
+        heartbeat(&mut cluster, 0, &[]);
+        for paxos in &mut cluster {
+            let applied = paxos.take_applied();
+            let outputs: Vec<u64> = applied.iter().map(|a| a.output).collect();
+            assert_eq!(outputs, [2, 5]);
+            assert_eq!(applied[1].index, proposal.index);
+            assert_eq!(applied[1].ballot, proposal.ballot);
+        }
+        assert_eq!(
+            cluster[1].propose(1, &mut Vec::new()),
+            Err(NotLeader(Some("n0".into())))
+        );
+    }
+
+    #[test]
+    fn test_a_new_leader_keeps_what_a_majority_accepted() {
+        let mut cluster = cluster();
+        elect(&mut cluster, 0, &[]);
+        // Accepted by n0 and n1, but n0 goes down before telling anyone it's chosen
+        let kept = propose(&mut cluster, 0, 4, &[2]);
+        // Only n0 ever has this one
+        let lost = propose(&mut cluster, 0, 7, &[1, 2]);
+
+        elect(&mut cluster, 2, &[0]);
+        propose(&mut cluster, 2, 1, &[0]);
+        heartbeat(&mut cluster, 2, &[]);
+        assert!(!cluster[0].is_leader());
+        let applied = cluster[0].take_applied();
+        assert_eq!(applied[0].ballot, kept.ballot);
+        let outputs: Vec<u64> = applied.iter().map(|a| a.output).collect();
+        assert_eq!(outputs, [4, 5]);
+        assert!(applied
+            .iter()
+            .all(|a| a.index != lost.index || a.ballot != lost.ballot));
+        assert_eq!(cluster[1].state().0, 5);
+    }
+
+    #[test]
+    fn test_lagging_nodes_learn_what_was_chosen() {
+        let mut cluster = cluster();
+        elect(&mut cluster, 0, &[2]);
+        for command in 1..=3 {
+            propose(&mut cluster, 0, command, &[2]);
+        }
+        assert_eq!(cluster[2].commit_index(), 0);
+        heartbeat(&mut cluster, 0, &[]);
+        assert_eq!(cluster[2].state().0, 6);
+        assert_eq!(cluster[2].commit_index(), cluster[0].commit_index());
+    }
+
+    #[test]
+    fn test_lower_ballots_are_rejected() {
+        let mut cluster = cluster();
+        elect(&mut cluster, 0, &[]);
+        elect(&mut cluster, 1, &[0]);
+        // n0 still thinks it's leading, until its accepts are turned down
+        assert!(cluster[0].is_leader());
+        let mut outbox = Vec::new();
+        assert!(cluster[0].propose(1, &mut outbox).is_ok());
+        deliver(&mut cluster, 0, outbox, &[]);
+        assert!(!cluster[0].is_leader());
+        assert_eq!(cluster[0].ballot(), cluster[1].ballot());
+        heartbeat(&mut cluster, 1, &[]);
+        assert_eq!(cluster[0].leader(), Some("n1"));
+        assert_eq!(cluster[0].state().0, 0);
+    }
+
+    #[test]
+    fn test_message_format() {
+        let ballot = Ballot {
+            round: 2,
+            node: "n1".into(),
+        };
+        let message: PaxosMessage<u64> = PaxosMessage::Accept {
+            ballot: ballot.clone(),
+            first_index: 3,
+            entries: vec![
+                Entry {
+                    ballot: ballot.clone(),
+                    command: None,
+                },
+                Entry {
+                    ballot,
+                    command: Some(5),
+                },
+            ],
+            commit_index: 2,
+        };
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["type"], "accept");
+        assert_eq!(
+            json["entries"],
+            serde_json::json!([
+                {"ballot": {"round": 2, "node": "n1"}},
+                {"ballot": {"round": 2, "node": "n1"}, "command": 5}
+            ])
+        );
+        assert_eq!(
+            serde_json::from_value::<PaxosMessage<u64>>(json).unwrap(),
+            message
+        );
+    }
+}
@@ -0,0 +1,357 @@
+pub mod node {
+    use crdt::{Delta, Merge, PnCounter};
+    use maelstrom::error::ABORT;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    /// A counter clients can add to and subtract from at any node, replicated as a
+    /// `PnCounter`. Each node gossips every peer the part of the counter it hasn't yet
+    /// acknowledged, so reads anywhere converge on the same total once adds stop.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        peers: Vec<String>,
+        counter: PnCounter,
+        known: HashMap<String, PnCounter>, // What each peer is known to have merged
+        in_flight: HashMap<u64, (String, PnCounter)>, // The latest gossip to each peer, by msg_id
+    }
+
+    /// Tunables, read from `PN_COUNTER_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between gossip rounds.
+        pub gossip_interval: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                gossip_interval: Duration::from_millis(100),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                gossip_interval: Duration::from_millis(env_or(
+                    "PN_COUNTER_GOSSIP_INTERVAL_MS",
+                    default.gossip_interval.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Add {
+            msg_id: u64,
+            delta: i64,
+        },
+        AddOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: i128,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// The part of the counter the sender thinks we're missing.
+        Gossip {
+            msg_id: u64,
+            counter: PnCounter,
+        },
+        /// The gossip has been merged.
+        GossipOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+    }
+
+    impl Default for Node {
+        fn default() -> Self {
+            Node::new()
+        }
+    }
+
+    impl Node {
+        pub fn new() -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                peers: Vec::new(),
+                counter: PnCounter::default(),
+                known: HashMap::new(),
+                in_flight: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "value": self.counter.value().to_string(),
+                "in_flight": self.in_flight.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let Some(body) = self.handle_body(&message.src, message.body) else {
+                return Vec::new();
+            };
+            self.cur_id += 1;
+            vec![Message {
+                src: message.dest,
+                dest: message.src,
+                body,
+            }]
+        }
+
+        /// Sends each peer what it's missing. Anything a peer doesn't acknowledge is sent
+        /// again next round, so lost gossip only delays convergence.
+        pub fn gossip(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            for peer in self.peers.clone() {
+                let known = self.known.entry(peer.clone()).or_default();
+                let Some(delta) = self.counter.delta_since(known) else {
+                    continue;
+                };
+                // Only the latest gossip needs acknowledging, since it has all the earlier ones
+                self.in_flight.retain(|_, (to, _)| *to != peer);
+                let msg_id = self.next_msg_id();
+                self.in_flight.insert(msg_id, (peer.clone(), delta.clone()));
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Gossip {
+                        msg_id,
+                        counter: delta,
+                    },
+                });
+            }
+            messages
+        }
+
+        fn handle_body(&mut self, src: &str, body: Body) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Add { msg_id, delta } => {
+                    if !self.counter.add(&self.id, delta) {
+                        return Some(Body::Error {
+                            in_reply_to: msg_id,
+                            code: ABORT,
+                            text: "add would overflow the counter".to_string(),
+                        });
+                    }
+                    Body::AddOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Read { msg_id } => Body::ReadOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: msg_id,
+                    value: self.counter.value(),
+                },
+                Body::Gossip { msg_id, counter } => {
+                    self.counter.merge(&counter);
+                    // Whatever it sent us, it has
+                    self.known
+                        .entry(src.to_string())
+                        .or_default()
+                        .merge(&counter);
+                    Body::GossipOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::GossipOk { in_reply_to, .. } => {
+                    let (peer, sent) = self.in_flight.remove(&in_reply_to)?;
+                    self.known.entry(peer).or_default().merge(&sent);
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::AddOk { .. }
+                | Body::ReadOk { .. }
+                | Body::Error { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, exchange};
+
+        fn init(id: &str) -> Node {
+            testing::init(Node::new(), id, 2)
+        }
+
+        fn send(node: &mut Node, body: Body) -> Body {
+            let dest = node.id.clone();
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body,
+            })
+            .remove(0)
+            .body
+        }
+
+        fn read(node: &mut Node) -> i128 {
+            let Body::ReadOk { value, .. } = send(node, Body::Read { msg_id: 9 }) else {
+                panic!("expected read_ok");
+            };
+            value
+        }
+
+        #[test]
+        fn test_adds_converge_through_gossip() {
+            let (mut n1, mut n2) = (init("n1"), init("n2"));
+            send(
+                &mut n1,
+                Body::Add {
+                    msg_id: 2,
+                    delta: 5,
+                },
+            );
+            send(
+                &mut n2,
+                Body::Add {
+                    msg_id: 2,
+                    delta: -2,
+                },
+            );
+            assert_eq!(read(&mut n1), 5);
+
+            exchange(&mut n1, &mut n2);
+            exchange(&mut n2, &mut n1);
+            assert_eq!(read(&mut n1), 3);
+            assert_eq!(read(&mut n2), 3);
+            // Both sides know the other is caught up, so there's nothing left to send
+            assert!(n1.gossip().is_empty());
+            assert!(n2.gossip().is_empty());
+        }
+
+        #[test]
+        fn test_unacknowledged_gossip_is_sent_again() {
+            let (mut n1, mut n2) = (init("n1"), init("n2"));
+            send(
+                &mut n1,
+                Body::Add {
+                    msg_id: 2,
+                    delta: 4,
+                },
+            );
+            assert_eq!(n1.gossip().len(), 1);
+            exchange(&mut n1, &mut n2);
+            assert_eq!(read(&mut n2), 4);
+            assert_eq!(n1.in_flight.len(), 0);
+        }
+
+        #[test]
+        fn test_overflowing_adds_are_refused() {
+            let mut n1 = init("n1");
+            // Two of these fit in a node's u64 of increments, but not three
+            let replies: Vec<Body> = (2..5)
+                .map(|msg_id| {
+                    let delta = i64::MAX;
+                    send(&mut n1, Body::Add { msg_id, delta })
+                })
+                .collect();
+            assert!(matches!(replies[1], Body::AddOk { .. }));
+            assert!(matches!(replies[2], Body::Error { code: ABORT, .. }));
+            assert_eq!(read(&mut n1), 2 * i64::MAX as i128);
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::gossip(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,896 @@
+mod detector {
+    use std::collections::HashMap;
+    use std::time::{Duration, Instant};
+
+    /// Suspects a node once it's gone `timeout` without being heard from. It can't tell a
+    /// dead node from a slow or cut-off one, so a suspicion can be wrong.
+    pub struct Detector {
+        id: String,
+        last_seen: HashMap<String, Instant>,
+        timeout: Duration,
+    }
+
+    impl Detector {
+        /// Starts out having just heard from every one of `nodes`.
+        pub fn new(id: &str, nodes: &[String], timeout: Duration) -> Self {
+            let now = Instant::now();
+            Detector {
+                id: id.to_string(),
+                last_seen: nodes.iter().map(|node| (node.clone(), now)).collect(),
+                timeout,
+            }
+        }
+
+        pub fn heard_from(&mut self, node: &str) {
+            if let Some(seen) = self.last_seen.get_mut(node) {
+                *seen = Instant::now();
+            }
+        }
+
+        pub fn is_alive(&self, node: &str) -> bool {
+            node == self.id
+                || self
+                    .last_seen
+                    .get(node)
+                    .is_some_and(|seen| seen.elapsed() < self.timeout)
+        }
+    }
+}
+
+pub mod node {
+    use super::detector::Detector;
+    use maelstrom::error::{
+        KEY_DOES_NOT_EXIST, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE, TIMEOUT,
+    };
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{HashMap, VecDeque};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// A key/value store where one node, the primary, serves every request, and applies each
+    /// write on every backup before acknowledging it. Backups forward requests to the primary
+    /// and relay its replies.
+    ///
+    /// Every node heartbeats every other, and when a backup's failure detector suspects the
+    /// primary, the lowest-id node it still thinks is alive takes over in a new view, sending
+    /// everyone its state. Views are ordered by number, then by primary, so if two backups
+    /// take over at once everyone settles on the same one. The primary only waits on the
+    /// backups it thinks are alive, so this trusts the detector: a primary cut off from its
+    /// backups carries on alone, and writes it acknowledges then can be lost when it rejoins.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        nodes: Vec<String>, // Every node, sorted, which is the order they take over in
+        detector: Detector,
+        view: u64,
+        primary: String,
+        values: HashMap<String, Value>, // Keyed by each key's JSON, since keys can be any JSON
+        seq: u64,                       // How many writes have been applied here
+        forwards: HashMap<u64, Waiting>, // Requests sent to the primary, by msg_id
+        // As primary
+        log: VecDeque<(String, Value)>, // Writes from `log_start` on that some backup may lack
+        log_start: u64,
+        acked: HashMap<String, u64>, // How many writes each backup has applied
+        pending: Vec<Pending>,       // Replies waiting on backups
+    }
+
+    /// A client waiting on a reply to `msg_id`.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on answering
+    }
+
+    /// A reply the primary sends once every live backup has applied `seq` writes.
+    struct Pending {
+        seq: u64,
+        waiting: Waiting,
+        reply: Body,
+    }
+
+    /// Tunables, read from `PRIMARY_BACKUP_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between ticks, which heartbeat, resend writes, and time out requests.
+        pub tick_interval: Duration,
+        /// How long a node goes unheard from before it's suspected.
+        pub failure_timeout: Duration,
+        /// How long a request waits on backups, or on the primary if we forwarded it.
+        pub request_timeout: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                tick_interval: Duration::from_millis(100),
+                failure_timeout: Duration::from_millis(500),
+                request_timeout: Duration::from_millis(1000),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                tick_interval: Duration::from_millis(env_or(
+                    "PRIMARY_BACKUP_TICK_INTERVAL_MS",
+                    default.tick_interval.as_millis() as u64,
+                )),
+                failure_timeout: Duration::from_millis(env_or(
+                    "PRIMARY_BACKUP_FAILURE_TIMEOUT_MS",
+                    default.failure_timeout.as_millis() as u64,
+                )),
+                request_timeout: Duration::from_millis(env_or(
+                    "PRIMARY_BACKUP_REQUEST_TIMEOUT_MS",
+                    default.request_timeout.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+            key: Value,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: Value,
+        },
+        Write {
+            msg_id: u64,
+            key: Value,
+            value: Value,
+        },
+        WriteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Sets `key` to `to` if it's `from`. A missing key fails, unless
+        /// `create_if_not_exists` is set, in which case it's created.
+        Cas {
+            msg_id: u64,
+            key: Value,
+            from: Value,
+            to: Value,
+            #[serde(default)]
+            create_if_not_exists: bool,
+        },
+        CasOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// The view the sender is in, so a primary can bring a node in an older one up to date.
+        Heartbeat {
+            msg_id: u64,
+            view: u64,
+            primary: String,
+        },
+        /// The primary's writes from number `from` on.
+        Replicate {
+            msg_id: u64,
+            view: u64,
+            from: u64,
+            entries: Vec<(String, Value)>,
+        },
+        /// The primary's whole state, sent when it takes over, or to a backup too far behind
+        /// for `Replicate`.
+        Sync {
+            msg_id: u64,
+            view: u64,
+            seq: u64,
+            values: HashMap<String, Value>,
+        },
+        /// How many writes the backup has applied.
+        ReplicateOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            view: u64,
+            seq: u64,
+        },
+    }
+
+    impl Body {
+        /// The id of the request this replies to, if it's a reply to a client request.
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::ReadOk { in_reply_to, .. }
+                | Body::WriteOk { in_reply_to, .. }
+                | Body::CasOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::Read { msg_id, .. }
+                | Body::ReadOk { msg_id, .. }
+                | Body::Write { msg_id, .. }
+                | Body::WriteOk { msg_id, .. }
+                | Body::Cas { msg_id, .. }
+                | Body::CasOk { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            let detector = Detector::new("", &[], config.failure_timeout);
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                nodes: Vec::new(),
+                detector,
+                view: 0,
+                primary: String::default(),
+                values: HashMap::new(),
+                seq: 0,
+                forwards: HashMap::new(),
+                log: VecDeque::new(),
+                log_start: 1,
+                acked: HashMap::new(),
+                pending: Vec::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "nodes": self.nodes,
+                "view": self.view,
+                "primary": self.primary,
+                "values": self.values.len(),
+                "seq": self.seq,
+                "forwards": self.forwards.len(),
+                "log": self.log.len(),
+                "log_start": self.log_start,
+                "acked": self.acked,
+                "pending": self.pending.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            // Peers can finish their init and start heartbeating before we get ours
+            if !self.initialized && matches!(message.body, Body::Heartbeat { .. }) {
+                return Vec::new();
+            }
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            self.detector.heard_from(&message.src);
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Heartbeats every peer, and then as primary, resends backups what they're missing
+        /// and answers whatever no longer waits on a backup we suspect; as a backup, takes over
+        /// if the primary is suspected and we're next in line. Requests that have waited too
+        /// long are timed out.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            for peer in self.peers() {
+                let msg_id = self.next_msg_id();
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Heartbeat {
+                        msg_id,
+                        view: self.view,
+                        primary: self.primary.clone(),
+                    },
+                });
+            }
+
+            if self.primary == self.id {
+                for backup in self.peers() {
+                    if self.acked.get(&backup).copied().unwrap_or(0) < self.seq {
+                        self.send_replicate(backup, &mut messages);
+                    }
+                }
+                self.release(&mut messages);
+            } else if !self.detector.is_alive(&self.primary) {
+                let next = self.nodes.iter().find(|node| self.detector.is_alive(node));
+                if next == Some(&self.id) {
+                    self.take_over(&mut messages);
+                }
+            }
+
+            let now = Instant::now();
+            let expired_forwards: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            let mut expired: Vec<Waiting> = expired_forwards
+                .iter()
+                .filter_map(|msg_id| self.forwards.remove(msg_id))
+                .collect();
+            let (late, pending) = std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|pending| pending.waiting.deadline <= now);
+            self.pending = pending;
+            expired.extend(late.into_iter().map(|pending: Pending| pending.waiting));
+            expired.sort_by_key(|waiting| waiting.msg_id);
+            for waiting in expired {
+                self.time_out(waiting, &mut messages);
+            }
+            messages
+        }
+
+        fn peers(&self) -> Vec<String> {
+            self.nodes
+                .iter()
+                .filter(|node| **node != self.id)
+                .cloned()
+                .collect()
+        }
+
+        /// Starts a new view with us as primary, and sends everyone our state.
+        fn take_over(&mut self, messages: &mut Vec<Message>) {
+            self.view += 1;
+            self.primary = self.id.clone();
+            log::info!("Taking over as primary in view {}", self.view);
+            self.log.clear();
+            self.log_start = self.seq + 1;
+            self.acked.clear();
+            for peer in self.peers() {
+                self.send_sync(peer, messages);
+            }
+        }
+
+        fn send_sync(&mut self, peer: String, messages: &mut Vec<Message>) {
+            let msg_id = self.next_msg_id();
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: peer,
+                body: Body::Sync {
+                    msg_id,
+                    view: self.view,
+                    seq: self.seq,
+                    values: self.values.clone(),
+                },
+            });
+        }
+
+        /// Sends a backup the writes it hasn't acknowledged, or our whole state if they're no
+        /// longer in the log.
+        fn send_replicate(&mut self, backup: String, messages: &mut Vec<Message>) {
+            let from = self.acked.get(&backup).copied().unwrap_or(0) + 1;
+            if from < self.log_start {
+                return self.send_sync(backup, messages);
+            }
+            let entries: Vec<(String, Value)> = self
+                .log
+                .iter()
+                .skip((from - self.log_start) as usize)
+                .cloned()
+                .collect();
+            if entries.is_empty() {
+                return;
+            }
+            let msg_id = self.next_msg_id();
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: backup,
+                body: Body::Replicate {
+                    msg_id,
+                    view: self.view,
+                    from,
+                    entries,
+                },
+            });
+        }
+
+        /// Applies a write as primary, and sends it to every backup.
+        fn write(&mut self, key: String, value: Value, messages: &mut Vec<Message>) {
+            self.seq += 1;
+            self.values.insert(key.clone(), value.clone());
+            self.log.push_back((key, value));
+            for backup in self.peers() {
+                self.send_replicate(backup, messages);
+            }
+        }
+
+        /// Sends every reply the backups we think are alive have caught up to, and drops the
+        /// writes they all have from the log.
+        fn release(&mut self, messages: &mut Vec<Message>) {
+            let caught_up = self
+                .peers()
+                .iter()
+                .filter(|backup| self.detector.is_alive(backup))
+                .map(|backup| self.acked.get(backup).copied().unwrap_or(0))
+                .fold(self.seq, u64::min);
+            while self.log_start <= caught_up && self.log.pop_front().is_some() {
+                self.log_start += 1;
+            }
+            let (ready, pending) = std::mem::take(&mut self.pending)
+                .into_iter()
+                .partition(|pending| pending.seq <= caught_up);
+            self.pending = pending;
+            for pending in ready {
+                let Pending { waiting, reply, .. } = pending;
+                self.reply(waiting, reply, messages);
+            }
+        }
+
+        /// Answers a client request as primary, once the backups have everything it saw.
+        fn serve(&mut self, waiting: Waiting, request: Body, messages: &mut Vec<Message>) {
+            let reply = match request {
+                Body::Read { key, .. } => match self.values.get(&key.to_string()) {
+                    Some(value) => Body::ReadOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        value: value.clone(),
+                    },
+                    None => Body::Error {
+                        in_reply_to: 0,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("{} doesn't exist", key),
+                    },
+                },
+                Body::Write { key, value, .. } => {
+                    self.write(key.to_string(), value, messages);
+                    Body::WriteOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    }
+                }
+                Body::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                    ..
+                } => match self.values.get(&key.to_string()) {
+                    Some(value) if *value != from => Body::Error {
+                        in_reply_to: 0,
+                        code: PRECONDITION_FAILED,
+                        text: format!("expected {}, but {} is {}", from, key, value),
+                    },
+                    None if !create_if_not_exists => Body::Error {
+                        in_reply_to: 0,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("{} doesn't exist", key),
+                    },
+                    _ => {
+                        self.write(key.to_string(), to, messages);
+                        Body::CasOk {
+                            msg_id: 0,
+                            in_reply_to: 0,
+                        }
+                    }
+                },
+                _ => return,
+            };
+            self.pending.push(Pending {
+                seq: self.seq,
+                waiting,
+                reply,
+            });
+            self.release(messages);
+        }
+
+        fn reply(&mut self, waiting: Waiting, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = waiting.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: waiting.client,
+                body,
+            });
+        }
+
+        fn time_out(&mut self, waiting: Waiting, messages: &mut Vec<Message>) {
+            // It may yet be applied, or have been and only the reply was lost, so this
+            // mustn't claim the request failed
+            let error = Body::Error {
+                in_reply_to: 0,
+                code: TIMEOUT,
+                text: "timed out waiting on the primary".to_string(),
+            };
+            self.reply(waiting, error, messages);
+        }
+
+        /// Serves a client's request if we're primary, or sends it on to the primary with an
+        /// id of our own, so we can tell which client its reply is for. Requests that already
+        /// came from another node aren't forwarded again, so they can't go round in circles.
+        fn request(&mut self, src: &str, mut body: Body, messages: &mut Vec<Message>) {
+            let Some(msg_id) = body.msg_id().copied() else {
+                return;
+            };
+            let waiting = Waiting {
+                client: src.to_string(),
+                msg_id,
+                deadline: Instant::now() + self.config.request_timeout,
+            };
+            if self.primary == self.id {
+                return self.serve(waiting, body, messages);
+            }
+            if self.nodes.iter().any(|node| node == src) {
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code: TEMPORARILY_UNAVAILABLE,
+                    text: "not the primary".to_string(),
+                };
+                return self.reply(waiting, error, messages);
+            }
+            let forward_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = forward_id;
+            }
+            self.forwards.insert(forward_id, waiting);
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: self.primary.clone(),
+                body,
+            });
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            messages: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    mut node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    node_ids.sort();
+                    self.detector = Detector::new(&node_id, &node_ids, self.config.failure_timeout);
+                    self.primary = node_ids.first().unwrap_or(&node_id).clone();
+                    self.nodes = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Read { .. } | Body::Write { .. } | Body::Cas { .. } => {
+                    self.request(src, body, messages);
+                    None
+                }
+                Body::Heartbeat { view, primary, .. } => {
+                    if self.primary == self.id && (view, primary) < (self.view, self.id.clone()) {
+                        self.send_sync(src.to_string(), messages);
+                    }
+                    None
+                }
+                Body::Replicate {
+                    msg_id,
+                    view,
+                    from,
+                    entries,
+                } => {
+                    // Writes from a view we're not in can't be trusted to line up with ours
+                    if (view, src) != (self.view, self.primary.as_str()) {
+                        return None;
+                    }
+                    for (seq, (key, value)) in (from..).zip(entries) {
+                        if seq == self.seq + 1 {
+                            self.values.insert(key, value);
+                            self.seq = seq;
+                        }
+                    }
+                    Some(Body::ReplicateOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        view,
+                        seq: self.seq,
+                    })
+                }
+                Body::Sync {
+                    msg_id,
+                    view,
+                    seq,
+                    values,
+                } => {
+                    if (view, src) < (self.view, self.primary.as_str()) {
+                        return None;
+                    }
+                    if self.primary == self.id && src != self.id {
+                        log::info!("Stepping down for {} in view {}", src, view);
+                        self.log.clear();
+                        self.acked.clear();
+                        for pending in std::mem::take(&mut self.pending) {
+                            self.time_out(pending.waiting, messages);
+                        }
+                    }
+                    self.view = view;
+                    self.primary = src.to_string();
+                    self.values = values;
+                    self.seq = seq;
+                    Some(Body::ReplicateOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        view,
+                        seq,
+                    })
+                }
+                Body::ReplicateOk { view, seq, .. } => {
+                    if self.primary == self.id && view == self.view {
+                        let acked = self.acked.entry(src.to_string()).or_default();
+                        *acked = (*acked).max(seq);
+                        self.release(messages);
+                    }
+                    None
+                }
+                // The primary's answer to a request we forwarded, which goes on to the client
+                mut reply => {
+                    let forward = self.forwards.remove(reply.in_reply_to()?)?;
+                    self.reply(forward, reply, messages);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        /// n1 to n3, with n1 primary.
+        fn cluster() -> Vec<Node> {
+            let ids: Vec<String> = (1..=3).map(|i| format!("n{}", i)).collect();
+            ids.iter()
+                .map(|id| {
+                    let mut node = Node::new(Config::default());
+                    node.handle_message(Message {
+                        src: "c1".into(),
+                        dest: id.clone(),
+                        body: Body::Init {
+                            msg_id: 1,
+                            node_id: id.clone(),
+                            node_ids: ids.clone(),
+                        },
+                    });
+                    node
+                })
+                .collect()
+        }
+
+        /// Makes `node` suspect every node in `down`, and only them.
+        fn suspect(node: &mut Node, down: &[&str]) {
+            let alive: Vec<String> = node
+                .nodes
+                .iter()
+                .filter(|n| !down.contains(&n.as_str()))
+                .cloned()
+                .collect();
+            node.detector = Detector::new(&node.id, &alive, node.config.failure_timeout);
+        }
+
+        /// Delivers messages until none are left, dropping any to nodes in `down`, and
+        /// returns what's sent to clients.
+        fn deliver(nodes: &mut [Node], mut messages: Vec<Message>, down: &[&str]) -> Vec<Body> {
+            let mut replies = Vec::new();
+            while !messages.is_empty() {
+                let message = messages.remove(0);
+                if down.contains(&message.dest.as_str()) {
+                    continue;
+                }
+                match nodes.iter_mut().find(|n| n.id == message.dest) {
+                    Some(node) => messages.extend(node.handle_message(message)),
+                    None => replies.push(message.body),
+                }
+            }
+            replies
+        }
+
+        /// Sends `node` a request from a client, returning what it sends.
+        fn request(node: &mut Node, body: Body) -> Vec<Message> {
+            let dest = node.id.clone();
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body,
+            })
+        }
+
+        fn write(key: Value, value: Value) -> Body {
+            Body::Write {
+                msg_id: 2,
+                key,
+                value,
+            }
+        }
+
+        fn read(key: Value) -> Body {
+            Body::Read { msg_id: 3, key }
+        }
+
+        #[test]
+        fn test_writes_reach_every_backup_before_the_reply() {
+            let mut nodes = cluster();
+            let sent = request(&mut nodes[0], write(json!(1), json!(10)));
+            // Just the writes to the backups, with the reply held back
+            assert_eq!(sent.len(), 2);
+            assert!(sent
+                .iter()
+                .all(|m| matches!(m.body, Body::Replicate { .. })));
+
+            let replies = deliver(&mut nodes, sent, &[]);
+            assert!(matches!(replies[..], [Body::WriteOk { .. }]));
+            assert!(nodes
+                .iter()
+                .all(|node| node.values[&"1".to_string()] == json!(10)));
+
+            // Backups forward to the primary, and relay its answer
+            let cas = Body::Cas {
+                msg_id: 4,
+                key: json!(1),
+                from: json!(5),
+                to: json!(6),
+                create_if_not_exists: false,
+            };
+            let sent = request(&mut nodes[2], cas);
+            let replies = deliver(&mut nodes, sent, &[]);
+            assert!(matches!(
+                replies[..],
+                [Body::Error {
+                    in_reply_to: 4,
+                    code: PRECONDITION_FAILED,
+                    ..
+                }]
+            ));
+            assert!(nodes[0].log.is_empty());
+        }
+
+        #[test]
+        fn test_backups_take_over_from_a_failed_primary() {
+            let mut nodes = cluster();
+            let sent = request(&mut nodes[0], write(json!(1), json!(10)));
+            deliver(&mut nodes, sent, &[]);
+
+            // n1 dies, and n2, next in line, takes over
+            suspect(&mut nodes[1], &["n1"]);
+            suspect(&mut nodes[2], &["n1"]);
+            let sent = nodes[2].tick();
+            deliver(&mut nodes, sent, &["n1"]);
+            assert_eq!(nodes[2].primary, "n1");
+            let sent = nodes[1].tick();
+            deliver(&mut nodes, sent, &["n1"]);
+            assert_eq!((nodes[2].view, nodes[2].primary.as_str()), (1, "n2"));
+
+            let sent = request(&mut nodes[2], write(json!(2), json!(20)));
+            let replies = deliver(&mut nodes, sent, &["n1"]);
+            assert!(matches!(replies[..], [Body::WriteOk { .. }]));
+            let sent = request(&mut nodes[2], read(json!(1)));
+            let replies = deliver(&mut nodes, sent, &["n1"]);
+            assert!(matches!(&replies[..], [Body::ReadOk { value, .. }] if *value == json!(10)));
+
+            // n1 comes back, and n2 brings it up to date as a backup
+            let sent = nodes[0].tick();
+            deliver(&mut nodes, sent, &[]);
+            assert_eq!((nodes[0].view, nodes[0].primary.as_str()), (1, "n2"));
+            assert_eq!(nodes[0].values[&"2".to_string()], json!(20));
+        }
+
+        #[test]
+        fn test_primary_stops_waiting_on_suspected_backups() {
+            let mut nodes = cluster();
+            let sent = request(&mut nodes[0], write(json!(1), json!(10)));
+            assert!(deliver(&mut nodes, sent, &["n3"]).is_empty());
+
+            suspect(&mut nodes[0], &["n3"]);
+            let replies: Vec<Body> = nodes[0]
+                .tick()
+                .into_iter()
+                .filter(|m| m.dest == "c1")
+                .map(|m| m.body)
+                .collect();
+            assert!(matches!(replies[..], [Body::WriteOk { .. }]));
+        }
+
+        #[test]
+        fn test_racing_takeovers_settle_on_one_primary() {
+            let mut nodes = cluster();
+            // n2 and n3 each think they're next
+            suspect(&mut nodes[1], &["n1"]);
+            suspect(&mut nodes[2], &["n1", "n2"]);
+            let mut sent = nodes[1].tick();
+            sent.extend(nodes[2].tick());
+            deliver(&mut nodes, sent, &[]);
+            let sent: Vec<Message> = nodes.iter_mut().flat_map(|node| node.tick()).collect();
+            deliver(&mut nodes, sent, &[]);
+            for node in &nodes {
+                assert_eq!((node.view, node.primary.as_str()), (1, "n3"));
+            }
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,997 @@
+pub mod node {
+    use maelstrom::error::{TEMPORARILY_UNAVAILABLE, TIMEOUT};
+    use raft::{Outbox, Raft, RaftMessage, Rejected, StateMachine};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{BTreeMap, HashMap};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// Topics clients publish to and subscribe to. Every topic is a log of messages, kept
+    /// along with each subscriber's place in it, in a Raft log, so they survive any node
+    /// that isn't a majority going down.
+    ///
+    /// The leader pushes each subscriber whatever it hasn't yet acknowledged, in a deliver
+    /// it resends until the subscriber answers with a deliver_ok, which moves its place on
+    /// through the log. Delivery is at least once: a subscriber that's been away, or whose
+    /// acknowledgement didn't make it, is sent everything from its place on again, which
+    /// it can tell apart by offset. Subscribing again keeps a subscriber's place, so that's
+    /// how it catches up on reconnecting, unless it asks to start from an offset of its
+    /// own.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        node_ids: Vec<String>,
+        raft: Option<Raft<Topics>>, // Set at init, once we know the cluster
+        forwards: HashMap<u64, Waiting>, // Requests sent to the leader, by msg_id
+        proposals: HashMap<u64, Proposed>, // Requests we put in the log, by index
+        deliveries: HashMap<(String, String), Delivery>, // Sent while leading, by subscriber and topic
+    }
+
+    /// A request in the log, waiting on it to be applied.
+    struct Proposed {
+        term: u64, // The term it went in with, which the applied entry must have too
+        waiting: Waiting,
+    }
+
+    /// A client waiting on a reply to `msg_id`.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on answering
+    }
+
+    /// The last deliver we sent a subscriber for a topic.
+    struct Delivery {
+        msg_id: u64,
+        end: u64,      // The offset after the last message in it
+        acked: bool,   // Whether it's been acknowledged, though maybe not yet committed
+        sent: Instant, // When, so we know when to resend it
+    }
+
+    /// What every node applies the log to.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+    struct Topics {
+        logs: BTreeMap<String, Vec<Value>>,
+        /// Each client's subscriptions, with the offset of the first message it hasn't
+        /// acknowledged.
+        subscriptions: BTreeMap<String, BTreeMap<String, u64>>,
+    }
+
+    /// An operation as it goes in the log.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "op")]
+    enum Op {
+        /// Starts from `offset`, if given, or else where the client left off, if it's already
+        /// subscribed, or else the end of the topic.
+        Subscribe {
+            client: String,
+            topic: String,
+            offset: Option<u64>,
+        },
+        Unsubscribe {
+            client: String,
+            topic: String,
+        },
+        Publish {
+            topic: String,
+            msg: Value,
+        },
+        /// Moves the client's place on to `offset`, never back.
+        Ack {
+            client: String,
+            topic: String,
+            offset: u64,
+        },
+    }
+
+    /// Tunables, read from `PUB_SUB_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// How long a request waits on being committed, or on the leader to answer it if we
+        /// forwarded it, before we give up.
+        pub request_timeout: Duration,
+        /// Time between ticks, which drive Raft's timers, push messages to subscribers and
+        /// time out requests.
+        pub tick_interval: Duration,
+        /// How long a deliver goes unacknowledged before we send it again.
+        pub resend_interval: Duration,
+        /// The most messages sent in one deliver.
+        pub max_delivery: usize,
+        pub raft: raft::Config,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                request_timeout: Duration::from_millis(1000),
+                tick_interval: Duration::from_millis(50),
+                resend_interval: Duration::from_millis(500),
+                max_delivery: 100,
+                raft: raft::Config::default(),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            let millis = |name, default: Duration| {
+                Duration::from_millis(env_or(name, default.as_millis() as u64))
+            };
+            Config {
+                request_timeout: millis("PUB_SUB_REQUEST_TIMEOUT_MS", default.request_timeout),
+                tick_interval: millis("PUB_SUB_TICK_INTERVAL_MS", default.tick_interval),
+                resend_interval: millis("PUB_SUB_RESEND_INTERVAL_MS", default.resend_interval),
+                max_delivery: env_or("PUB_SUB_MAX_DELIVERY", default.max_delivery),
+                raft: raft::Config {
+                    election_timeout: millis(
+                        "PUB_SUB_ELECTION_TIMEOUT_MS",
+                        default.raft.election_timeout,
+                    ),
+                    heartbeat_interval: millis(
+                        "PUB_SUB_HEARTBEAT_INTERVAL_MS",
+                        default.raft.heartbeat_interval,
+                    ),
+                    max_batch: env_or("PUB_SUB_MAX_BATCH", default.raft.max_batch),
+                    snapshot_threshold: env_or(
+                        "PUB_SUB_SNAPSHOT_THRESHOLD",
+                        default.raft.snapshot_threshold,
+                    ),
+                },
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Subscribe {
+            msg_id: u64,
+            topic: String,
+            #[serde(default)]
+            offset: Option<u64>,
+            /// Who's subscribing, when a node forwards this on a client's behalf.
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            client: Option<String>,
+        },
+        /// With the offset deliveries start from.
+        SubscribeOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            offset: u64,
+        },
+        Unsubscribe {
+            msg_id: u64,
+            topic: String,
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            client: Option<String>,
+        },
+        UnsubscribeOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Publish {
+            msg_id: u64,
+            topic: String,
+            msg: Value,
+        },
+        PublishOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            offset: u64,
+        },
+        /// Messages for a subscriber, the first of them at `offset`.
+        Deliver {
+            msg_id: u64,
+            topic: String,
+            offset: u64,
+            msgs: Vec<Value>,
+        },
+        DeliverOk {
+            #[serde(default)]
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        #[serde(untagged)]
+        Raft(RaftMessage<Op, Topics>),
+    }
+
+    impl Body {
+        /// The id of the request this replies to, if it's a reply.
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::InitOk { in_reply_to, .. }
+                | Body::SubscribeOk { in_reply_to, .. }
+                | Body::UnsubscribeOk { in_reply_to, .. }
+                | Body::PublishOk { in_reply_to, .. }
+                | Body::DeliverOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::Init { msg_id, .. }
+                | Body::InitOk { msg_id, .. }
+                | Body::Subscribe { msg_id, .. }
+                | Body::SubscribeOk { msg_id, .. }
+                | Body::Unsubscribe { msg_id, .. }
+                | Body::UnsubscribeOk { msg_id, .. }
+                | Body::Publish { msg_id, .. }
+                | Body::PublishOk { msg_id, .. }
+                | Body::Deliver { msg_id, .. }
+                | Body::DeliverOk { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+
+        /// Who a request is on behalf of, for the ones that say so when forwarded.
+        fn client(&mut self) -> Option<&mut Option<String>> {
+            match self {
+                Body::Subscribe { client, .. } | Body::Unsubscribe { client, .. } => Some(client),
+                _ => None,
+            }
+        }
+
+        /// The request as an operation for the log, sent to us by `src`.
+        fn op(self, src: &str) -> Option<Op> {
+            let client = |client: Option<String>| client.unwrap_or_else(|| src.to_string());
+            Some(match self {
+                Body::Subscribe {
+                    topic,
+                    offset,
+                    client: on_behalf_of,
+                    ..
+                } => Op::Subscribe {
+                    client: client(on_behalf_of),
+                    topic,
+                    offset,
+                },
+                Body::Unsubscribe {
+                    topic,
+                    client: on_behalf_of,
+                    ..
+                } => Op::Unsubscribe {
+                    client: client(on_behalf_of),
+                    topic,
+                },
+                Body::Publish { topic, msg, .. } => Op::Publish { topic, msg },
+                _ => return None,
+            })
+        }
+    }
+
+    impl StateMachine for Topics {
+        type Command = Op;
+        /// The reply to a client's request, with its ids still to fill in, or nothing for
+        /// an acknowledgement.
+        type Output = Option<Body>;
+        type Snapshot = Topics;
+
+        fn apply(&mut self, op: &Op) -> Option<Body> {
+            match op {
+                Op::Subscribe {
+                    client,
+                    topic,
+                    offset,
+                } => {
+                    let end = self.logs.get(topic).map_or(0, |log| log.len() as u64);
+                    let subscriptions = self.subscriptions.entry(client.clone()).or_default();
+                    let next = subscriptions.entry(topic.clone()).or_insert(end);
+                    if let Some(offset) = offset {
+                        *next = *offset;
+                    }
+                    Some(Body::SubscribeOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        offset: *next,
+                    })
+                }
+                Op::Unsubscribe { client, topic } => {
+                    if let Some(subscriptions) = self.subscriptions.get_mut(client) {
+                        subscriptions.remove(topic);
+                        if subscriptions.is_empty() {
+                            self.subscriptions.remove(client);
+                        }
+                    }
+                    Some(Body::UnsubscribeOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    })
+                }
+                Op::Publish { topic, msg } => {
+                    let log = self.logs.entry(topic.clone()).or_default();
+                    log.push(msg.clone());
+                    Some(Body::PublishOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        offset: log.len() as u64 - 1,
+                    })
+                }
+                Op::Ack {
+                    client,
+                    topic,
+                    offset,
+                } => {
+                    // Unsubscribing since, or a stale acknowledgement, leaves it be
+                    let subscriptions = self.subscriptions.get_mut(client);
+                    if let Some(next) = subscriptions.and_then(|subs| subs.get_mut(topic)) {
+                        *next = (*next).max(*offset);
+                    }
+                    None
+                }
+            }
+        }
+
+        fn snapshot(&self) -> Topics {
+            self.clone()
+        }
+
+        fn restore(&mut self, snapshot: Topics) {
+            *self = snapshot;
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                node_ids: Vec::new(),
+                raft: None,
+                forwards: HashMap::new(),
+                proposals: HashMap::new(),
+                deliveries: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            let raft = self.raft.as_ref().map(|raft| {
+                json!({
+                    "term": raft.term(),
+                    "leader": raft.leader(),
+                    "nodes": raft.nodes(),
+                    "last_index": raft.last_index(),
+                    "commit_index": raft.commit_index(),
+                })
+            });
+            json!({
+                "id": self.id,
+                "raft": raft,
+                "forwards": self.forwards.len(),
+                "proposals": self.proposals.len(),
+                "deliveries": self.deliveries.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Drives Raft's timers, pushes subscribers what they're missing if we're leading,
+        /// and gives up on requests that have waited too long.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            let mut outbox = Vec::new();
+            if let Some(raft) = &mut self.raft {
+                raft.tick(&mut outbox);
+            }
+            self.send_raft(outbox, &mut messages);
+            self.deliver(&mut messages);
+
+            let now = Instant::now();
+            let expired_forwards: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            let expired_proposals: Vec<u64> = self
+                .proposals
+                .iter()
+                .filter(|(_, proposed)| proposed.waiting.deadline <= now)
+                .map(|(index, _)| *index)
+                .collect();
+            let mut expired: Vec<Waiting> = expired_forwards
+                .iter()
+                .filter_map(|msg_id| self.forwards.remove(msg_id))
+                .chain(
+                    expired_proposals
+                        .iter()
+                        .filter_map(|index| Some(self.proposals.remove(index)?.waiting)),
+                )
+                .collect();
+            expired.sort_by_key(|waiting| waiting.msg_id);
+            for waiting in expired {
+                // It may yet be committed, or have been and only the reply was lost, so this
+                // mustn't claim it didn't happen
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code: TIMEOUT,
+                    text: "timed out waiting on the log".to_string(),
+                };
+                self.reply(waiting, error, &mut messages);
+            }
+            messages
+        }
+
+        /// Sends every subscriber the messages past its place in each of its topics, unless
+        /// they're already on their way. Only the leader delivers, since only it's sure to
+        /// have everything that's been committed.
+        fn deliver(&mut self, messages: &mut Vec<Message>) {
+            let Some(raft) = self.raft.as_ref().filter(|raft| raft.is_leader()) else {
+                self.deliveries.clear();
+                return;
+            };
+            let topics = raft.state();
+            self.deliveries.retain(|(client, topic), _| {
+                (topics.subscriptions.get(client)).is_some_and(|subs| subs.contains_key(topic))
+            });
+            let now = Instant::now();
+            let mut delivers = Vec::new();
+            for (client, subscriptions) in &topics.subscriptions {
+                for (topic, next) in subscriptions {
+                    let key = (client.clone(), topic.clone());
+                    // What we've had acknowledged counts, though it's yet to be committed
+                    let (next, resend) = match self.deliveries.get(&key) {
+                        Some(delivery) if delivery.acked => ((*next).max(delivery.end), true),
+                        Some(delivery) => {
+                            (*next, delivery.sent + self.config.resend_interval <= now)
+                        }
+                        None => (*next, true),
+                    };
+                    let log = topics.logs.get(topic).map_or(&[][..], |log| &log[..]);
+                    let msgs: Vec<Value> = log
+                        .iter()
+                        .skip(next as usize)
+                        .take(self.config.max_delivery)
+                        .cloned()
+                        .collect();
+                    if resend && !msgs.is_empty() {
+                        delivers.push((key, next, msgs));
+                    }
+                }
+            }
+            for ((client, topic), offset, msgs) in delivers {
+                let msg_id = self.next_msg_id();
+                let delivery = Delivery {
+                    msg_id,
+                    end: offset + msgs.len() as u64,
+                    acked: false,
+                    sent: now,
+                };
+                self.deliveries
+                    .insert((client.clone(), topic.clone()), delivery);
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: client,
+                    body: Body::Deliver {
+                        msg_id,
+                        topic,
+                        offset,
+                        msgs,
+                    },
+                });
+            }
+        }
+
+        /// Moves a subscriber's place on past the deliver it's acknowledged, if it's the
+        /// latest we sent it.
+        fn acknowledge(&mut self, src: &str, in_reply_to: u64, messages: &mut Vec<Message>) {
+            let Some(((_, topic), delivery)) = self
+                .deliveries
+                .iter_mut()
+                .find(|((client, _), delivery)| client == src && delivery.msg_id == in_reply_to)
+            else {
+                return;
+            };
+            delivery.acked = true;
+            let ack = Op::Ack {
+                client: src.to_string(),
+                topic: topic.clone(),
+                offset: delivery.end,
+            };
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            let mut outbox = Vec::new();
+            if raft.propose(ack, &mut outbox).is_ok() {
+                self.send_raft(outbox, messages);
+            }
+        }
+
+        /// Wraps Raft's messages for the wire, and answers the clients whose requests it has
+        /// applied since.
+        fn send_raft(&mut self, outbox: Outbox<Topics>, messages: &mut Vec<Message>) {
+            for (dest, message) in outbox {
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest,
+                    body: Body::Raft(message),
+                });
+            }
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            for applied in raft.take_applied() {
+                let Some(proposed) = self.proposals.remove(&applied.index) else {
+                    continue;
+                };
+                let reply = match applied.output.flatten() {
+                    Some(reply) if proposed.term == applied.term => reply,
+                    // Another leader's entry took its place, so it never will take effect
+                    _ => Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "lost leadership before committing".to_string(),
+                    },
+                };
+                self.reply(proposed.waiting, reply, messages);
+            }
+        }
+
+        fn reply(&mut self, waiting: Waiting, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = waiting.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: waiting.client,
+                body,
+            });
+        }
+
+        /// Proposes a client's request if we're leading, or sends it on to the leader with an
+        /// id of our own, so we can tell which client its reply is for. Requests that already
+        /// came from another node aren't forwarded again, so they can't go round in circles.
+        /// A forwarded subscription names the client, so it's the one the leader delivers to.
+        fn request(&mut self, src: &str, mut body: Body, messages: &mut Vec<Message>) {
+            let Some(msg_id) = body.msg_id().copied() else {
+                return;
+            };
+            let waiting = Waiting {
+                client: src.to_string(),
+                msg_id,
+                deadline: Instant::now() + self.config.request_timeout,
+            };
+            let from_node = self.node_ids.iter().any(|node| node == src);
+            let (Some(raft), Some(op)) = (&mut self.raft, body.clone().op(src)) else {
+                return;
+            };
+            let subscriber = match &op {
+                Op::Subscribe { client, topic, .. } | Op::Unsubscribe { client, topic } => {
+                    Some((client.clone(), topic.clone()))
+                }
+                _ => None,
+            };
+            let mut outbox = Vec::new();
+            match raft.propose(op, &mut outbox) {
+                Ok(proposal) => {
+                    let proposed = Proposed {
+                        term: proposal.term,
+                        waiting,
+                    };
+                    self.proposals.insert(proposal.index, proposed);
+                    // What we last sent no longer says where the subscriber is up to
+                    if let Some(subscriber) = subscriber {
+                        self.deliveries.remove(&subscriber);
+                    }
+                }
+                Err(Rejected::NotLeader(Some(leader))) if !from_node => {
+                    let forward_id = self.next_msg_id();
+                    if let Some(msg_id) = body.msg_id() {
+                        *msg_id = forward_id;
+                    }
+                    if let Some(client) = body.client() {
+                        client.get_or_insert_with(|| src.to_string());
+                    }
+                    self.forwards.insert(forward_id, waiting);
+                    messages.push(Message {
+                        src: self.id.clone(),
+                        dest: leader,
+                        body,
+                    });
+                }
+                Err(_) => {
+                    let error = Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "no leader to serve this".to_string(),
+                    };
+                    self.reply(waiting, error, messages);
+                }
+            }
+            self.send_raft(outbox, messages);
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.raft = Some(Raft::new(
+                        node_id.clone(),
+                        node_ids.clone(),
+                        Topics::default(),
+                        self.config.raft.clone(),
+                    ));
+                    self.node_ids = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Subscribe { .. } | Body::Unsubscribe { .. } | Body::Publish { .. } => {
+                    self.request(src, body, outbox);
+                    None
+                }
+                Body::DeliverOk { in_reply_to, .. } => {
+                    self.acknowledge(src, in_reply_to, outbox);
+                    None
+                }
+                Body::Raft(message) => {
+                    let mut raft_outbox = Vec::new();
+                    self.raft.as_mut()?.handle(src, message, &mut raft_outbox);
+                    self.send_raft(raft_outbox, outbox);
+                    None
+                }
+                // The leader's answer to a request we forwarded, which goes on to the client
+                mut reply => {
+                    let forward = self.forwards.remove(reply.in_reply_to()?)?;
+                    self.reply(forward, reply, outbox);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        /// n1 to n`count`, with n1 elected leader. n2 calls an election on its first tick
+        /// too, so only tick it to have it take over.
+        fn cluster(count: usize) -> Vec<Node> {
+            let node_ids: Vec<String> = (1..=count).map(|i| format!("n{}", i)).collect();
+            let mut nodes: Vec<Node> = node_ids
+                .iter()
+                .map(|id| {
+                    let mut config = Config::default();
+                    config.raft.heartbeat_interval = Duration::ZERO;
+                    config.resend_interval = Duration::from_secs(3600);
+                    if id == "n1" || id == "n2" {
+                        config.raft.election_timeout = Duration::ZERO;
+                    }
+                    let mut node = Node::new(config);
+                    node.handle_message(Message {
+                        src: "c1".into(),
+                        dest: id.clone(),
+                        body: Body::Init {
+                            msg_id: 1,
+                            node_id: id.clone(),
+                            node_ids: node_ids.clone(),
+                        },
+                    });
+                    node
+                })
+                .collect();
+            let messages = nodes[0].tick();
+            deliver(&mut nodes, messages, &[]);
+            assert!(nodes[0].raft.as_ref().unwrap().is_leader());
+            nodes
+        }
+
+        /// Delivers `messages`, and everything sent in response, until only messages to
+        /// clients are left, which are returned. Messages to the nodes that are `down` are
+        /// lost.
+        fn deliver(nodes: &mut [Node], mut messages: Vec<Message>, down: &[&str]) -> Vec<Message> {
+            let mut to_clients = Vec::new();
+            while !messages.is_empty() {
+                for message in std::mem::take(&mut messages) {
+                    match message.dest.strip_prefix('n') {
+                        Some(_) if down.contains(&message.dest.as_str()) => {}
+                        Some(n) => {
+                            let node = &mut nodes[n.parse::<usize>().unwrap() - 1];
+                            messages.extend(node.handle_message(message));
+                        }
+                        None => to_clients.push(message),
+                    }
+                }
+            }
+            to_clients
+        }
+
+        /// Has `client` send `body` to `dest`, returning whatever reaches clients.
+        fn request(
+            nodes: &mut [Node],
+            client: &str,
+            dest: &str,
+            body: serde_json::Value,
+        ) -> Vec<Message> {
+            let message =
+                serde_json::from_value(json!({"src": client, "dest": dest, "body": body})).unwrap();
+            let n: usize = dest[1..].parse().unwrap();
+            let messages = nodes[n - 1].handle_message(message);
+            deliver(nodes, messages, &[])
+        }
+
+        /// Ticks `node`, returning whatever reaches clients.
+        fn tick(nodes: &mut [Node], node: usize, down: &[&str]) -> Vec<Message> {
+            let messages = nodes[node].tick();
+            deliver(nodes, messages, down)
+        }
+
+        fn publish(nodes: &mut [Node], msgs: &[i64]) {
+            for (i, msg) in msgs.iter().enumerate() {
+                let body = json!({"type": "publish", "msg_id": 100 + i, "topic": "t", "msg": msg});
+                let replies = request(nodes, "c1", "n3", body);
+                assert!(matches!(replies[0].body, Body::PublishOk { .. }));
+            }
+        }
+
+        /// The offsets and messages delivered to `client`, with the deliver's msg_id.
+        fn delivered(messages: &[Message], client: &str) -> Vec<(u64, u64, Vec<Value>)> {
+            messages
+                .iter()
+                .filter(|message| message.dest == client)
+                .filter_map(|message| match &message.body {
+                    Body::Deliver {
+                        msg_id,
+                        offset,
+                        msgs,
+                        ..
+                    } => Some((*msg_id, *offset, msgs.clone())),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        #[test]
+        fn test_published_messages_reach_subscribers() {
+            let mut nodes = cluster(3);
+            let subscribe = json!({"type": "subscribe", "msg_id": 1, "topic": "t"});
+            let replies = request(&mut nodes, "c2", "n2", subscribe);
+            assert!(matches!(
+                replies[0].body,
+                Body::SubscribeOk {
+                    in_reply_to: 1,
+                    offset: 0,
+                    ..
+                }
+            ));
+            assert_eq!(replies[0].dest, "c2");
+            publish(&mut nodes, &[1, 2]);
+
+            let to_clients = tick(&mut nodes, 0, &[]);
+            let deliveries = delivered(&to_clients, "c2");
+            assert_eq!(deliveries.len(), 1);
+            assert_eq!(
+                (deliveries[0].1, &deliveries[0].2[..]),
+                (0, &[json!(1), json!(2)][..])
+            );
+            assert!(delivered(&to_clients, "c1").is_empty());
+        }
+
+        #[test]
+        fn test_delivery_resent_until_acknowledged() {
+            let mut nodes = cluster(3);
+            nodes[0].config.resend_interval = Duration::ZERO;
+            request(
+                &mut nodes,
+                "c2",
+                "n1",
+                json!({"type": "subscribe", "msg_id": 1, "topic": "t"}),
+            );
+            publish(&mut nodes, &[1]);
+
+            let first = delivered(&tick(&mut nodes, 0, &[]), "c2");
+            let again = delivered(&tick(&mut nodes, 0, &[]), "c2");
+            assert_eq!((first[0].1, again[0].1), (0, 0));
+
+            let ack = json!({"type": "deliver_ok", "in_reply_to": again[0].0});
+            request(&mut nodes, "c2", "n1", ack);
+            assert!(delivered(&tick(&mut nodes, 0, &[]), "c2").is_empty());
+            publish(&mut nodes, &[2]);
+            let next = delivered(&tick(&mut nodes, 0, &[]), "c2");
+            assert_eq!((next[0].1, &next[0].2[..]), (1, &[json!(2)][..]));
+        }
+
+        #[test]
+        fn test_new_leader_catches_subscriber_up_from_its_place() {
+            let mut nodes = cluster(3);
+            request(
+                &mut nodes,
+                "c2",
+                "n1",
+                json!({"type": "subscribe", "msg_id": 1, "topic": "t"}),
+            );
+            publish(&mut nodes, &[1]);
+            let first = delivered(&tick(&mut nodes, 0, &[]), "c2");
+            request(
+                &mut nodes,
+                "c2",
+                "n1",
+                json!({"type": "deliver_ok", "in_reply_to": first[0].0}),
+            );
+            // Commits the acknowledgement everywhere
+            tick(&mut nodes, 0, &[]);
+            publish(&mut nodes, &[2, 3]);
+
+            // n1 goes down, and n2 has only the log to go on
+            let mut to_clients = Vec::new();
+            for _ in 0..3 {
+                to_clients.extend(tick(&mut nodes, 1, &["n1"]));
+            }
+            assert!(nodes[1].raft.as_ref().unwrap().is_leader());
+            let deliveries = delivered(&to_clients, "c2");
+            assert_eq!(deliveries.len(), 1);
+            assert_eq!(
+                (deliveries[0].1, &deliveries[0].2[..]),
+                (1, &[json!(2), json!(3)][..])
+            );
+        }
+
+        #[test]
+        fn test_resubscribing_keeps_place_unless_given_offset() {
+            let mut nodes = cluster(3);
+            request(
+                &mut nodes,
+                "c2",
+                "n1",
+                json!({"type": "subscribe", "msg_id": 1, "topic": "t"}),
+            );
+            publish(&mut nodes, &[1, 2]);
+            let first = delivered(&tick(&mut nodes, 0, &[]), "c2");
+            request(
+                &mut nodes,
+                "c2",
+                "n1",
+                json!({"type": "deliver_ok", "in_reply_to": first[0].0}),
+            );
+
+            let again = json!({"type": "subscribe", "msg_id": 2, "topic": "t"});
+            let replies = request(&mut nodes, "c2", "n1", again);
+            assert!(matches!(
+                replies[0].body,
+                Body::SubscribeOk { offset: 2, .. }
+            ));
+
+            let rewind = json!({"type": "subscribe", "msg_id": 3, "topic": "t", "offset": 1});
+            let replies = request(&mut nodes, "c2", "n1", rewind);
+            assert!(matches!(
+                replies[0].body,
+                Body::SubscribeOk { offset: 1, .. }
+            ));
+            let deliveries = delivered(&tick(&mut nodes, 0, &[]), "c2");
+            assert_eq!(
+                (deliveries[0].1, &deliveries[0].2[..]),
+                (1, &[json!(2)][..])
+            );
+        }
+
+        #[test]
+        fn test_unsubscribed_clients_get_nothing() {
+            let mut nodes = cluster(3);
+            request(
+                &mut nodes,
+                "c2",
+                "n1",
+                json!({"type": "subscribe", "msg_id": 1, "topic": "t"}),
+            );
+            let unsubscribe = json!({"type": "unsubscribe", "msg_id": 2, "topic": "t"});
+            let replies = request(&mut nodes, "c2", "n3", unsubscribe);
+            assert!(matches!(
+                replies[0].body,
+                Body::UnsubscribeOk { in_reply_to: 2, .. }
+            ));
+            publish(&mut nodes, &[1]);
+            assert!(delivered(&tick(&mut nodes, 0, &[]), "c2").is_empty());
+            assert!(nodes[0]
+                .raft
+                .as_ref()
+                .unwrap()
+                .state()
+                .subscriptions
+                .is_empty());
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,732 @@
+pub mod node {
+    use maelstrom::error::{TEMPORARILY_UNAVAILABLE, TIMEOUT};
+    use raft::{Outbox, Raft, RaftMessage, Rejected, StateMachine};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{BTreeMap, BTreeSet, HashMap};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    /// FIFO queues with at-least-once delivery. A dequeued message isn't gone, only hidden
+    /// for a redelivery timeout: the consumer acks it once it's done with it, and if it
+    /// doesn't, say because it crashed, the message goes back to the front of the queue to be
+    /// handed out again. Every operation goes through a Raft log, with the leader's clock
+    /// stamped on it, so every node agrees on which messages are due for redelivery. Nodes
+    /// that aren't leading forward requests to the leader and relay its replies.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        node_ids: Vec<String>,
+        raft: Option<Raft<Queues>>, // Set at init, once we know the cluster
+        forwards: HashMap<u64, Waiting>, // Requests sent to the leader, by msg_id
+        proposals: HashMap<u64, Proposed>, // Requests we put in the log, by index
+    }
+
+    /// A request in the log, waiting on it to be applied.
+    struct Proposed {
+        term: u64, // The term it went in with, which the applied entry must have too
+        waiting: Waiting,
+    }
+
+    /// A client waiting on a reply to `msg_id`.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on answering
+    }
+
+    /// A message in a queue, until it's acked.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Queued {
+        value: Value,
+        deliveries: u64, // Times it's been handed out
+    }
+
+    /// One queue's messages, by id, which they're delivered in the order of.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+    struct Queue {
+        next_id: u64,
+        messages: HashMap<u64, Queued>,
+        ready: BTreeSet<u64>,
+        hidden: BTreeSet<(u64, u64)>, // Handed out and not yet acked, by when they're due back
+    }
+
+    /// The queues every node applies the log to.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+    struct Queues {
+        queues: BTreeMap<String, Queue>,
+        now: u64, // The latest time stamped on an applied op, which never goes backwards
+    }
+
+    /// An operation as it goes in the log, stamped with the proposing leader's clock.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "op")]
+    enum Op {
+        Enqueue {
+            queue: String,
+            value: Value,
+            now: u64,
+        },
+        Dequeue {
+            queue: String,
+            timeout: u64,
+            now: u64,
+        },
+        Ack {
+            queue: String,
+            id: u64,
+            now: u64,
+        },
+    }
+
+    /// A dequeued message, and how many times it's been handed out, this time included.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Delivery {
+        id: u64,
+        value: Value,
+        attempt: u64,
+    }
+
+    /// Tunables, read from `QUEUE_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// How long a dequeued message stays hidden, waiting on its ack, before it's handed
+        /// out again, when the request doesn't say.
+        pub redelivery_timeout: Duration,
+        /// How long a request waits on being committed, or on the leader to answer it if we
+        /// forwarded it, before we give up.
+        pub request_timeout: Duration,
+        /// Time between ticks, which drive Raft's timers and time out requests.
+        pub tick_interval: Duration,
+        pub raft: raft::Config,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                redelivery_timeout: Duration::from_millis(5000),
+                request_timeout: Duration::from_millis(1000),
+                tick_interval: Duration::from_millis(50),
+                raft: raft::Config::default(),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                redelivery_timeout: Duration::from_millis(env_or(
+                    "QUEUE_REDELIVERY_TIMEOUT_MS",
+                    default.redelivery_timeout.as_millis() as u64,
+                )),
+                request_timeout: Duration::from_millis(env_or(
+                    "QUEUE_REQUEST_TIMEOUT_MS",
+                    default.request_timeout.as_millis() as u64,
+                )),
+                tick_interval: Duration::from_millis(env_or(
+                    "QUEUE_TICK_INTERVAL_MS",
+                    default.tick_interval.as_millis() as u64,
+                )),
+                raft: raft::Config {
+                    election_timeout: Duration::from_millis(env_or(
+                        "QUEUE_ELECTION_TIMEOUT_MS",
+                        default.raft.election_timeout.as_millis() as u64,
+                    )),
+                    heartbeat_interval: Duration::from_millis(env_or(
+                        "QUEUE_HEARTBEAT_INTERVAL_MS",
+                        default.raft.heartbeat_interval.as_millis() as u64,
+                    )),
+                    max_batch: env_or("QUEUE_MAX_BATCH", default.raft.max_batch),
+                    snapshot_threshold: env_or(
+                        "QUEUE_SNAPSHOT_THRESHOLD",
+                        default.raft.snapshot_threshold,
+                    ),
+                },
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// Milliseconds since the Unix epoch, by this machine's clock.
+    fn wall_clock() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Enqueue {
+            msg_id: u64,
+            queue: String,
+            value: Value,
+        },
+        EnqueueOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            id: u64,
+        },
+        /// Hands out the message at the front of `queue`, if there is one, hiding it for
+        /// `timeout` milliseconds.
+        Dequeue {
+            msg_id: u64,
+            queue: String,
+            #[serde(default)]
+            timeout: Option<u64>,
+        },
+        DequeueOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            msg: Option<Delivery>,
+        },
+        /// Takes message `id` out of `queue` for good. Acking one that's already gone is a
+        /// no-op, since its redelivery may have been acked first.
+        Ack {
+            msg_id: u64,
+            queue: String,
+            id: u64,
+        },
+        AckOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        #[serde(untagged)]
+        Raft(RaftMessage<Op, Queues>),
+    }
+
+    impl Body {
+        /// The id of the request this replies to, if it's a reply.
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::InitOk { in_reply_to, .. }
+                | Body::EnqueueOk { in_reply_to, .. }
+                | Body::DequeueOk { in_reply_to, .. }
+                | Body::AckOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::Init { msg_id, .. }
+                | Body::InitOk { msg_id, .. }
+                | Body::Enqueue { msg_id, .. }
+                | Body::EnqueueOk { msg_id, .. }
+                | Body::Dequeue { msg_id, .. }
+                | Body::DequeueOk { msg_id, .. }
+                | Body::Ack { msg_id, .. }
+                | Body::AckOk { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+
+        /// The request as an operation for the log, made at `now`, with messages hidden for
+        /// `default_timeout` unless it says otherwise.
+        fn op(self, now: u64, default_timeout: u64) -> Option<Op> {
+            Some(match self {
+                Body::Enqueue { queue, value, .. } => Op::Enqueue { queue, value, now },
+                Body::Dequeue { queue, timeout, .. } => Op::Dequeue {
+                    queue,
+                    timeout: timeout.unwrap_or(default_timeout),
+                    now,
+                },
+                Body::Ack { queue, id, .. } => Op::Ack { queue, id, now },
+                _ => return None,
+            })
+        }
+    }
+
+    impl Queue {
+        /// Puts every hidden message whose timeout is up by `now` back in line.
+        fn redeliver(&mut self, now: u64) {
+            while let Some(&(due, id)) = self.hidden.first() {
+                if due > now {
+                    break;
+                }
+                self.hidden.pop_first();
+                self.ready.insert(id);
+            }
+        }
+    }
+
+    impl StateMachine for Queues {
+        type Command = Op;
+        /// The reply, with its ids still to fill in.
+        type Output = Body;
+        type Snapshot = Queues;
+
+        fn apply(&mut self, op: &Op) -> Body {
+            let (Op::Enqueue { now, .. } | Op::Dequeue { now, .. } | Op::Ack { now, .. }) = op;
+            // A new leader's clock may be behind the last one's, but a message due back
+            // mustn't be hidden again
+            self.now = self.now.max(*now);
+            match op {
+                Op::Enqueue { queue, value, .. } => {
+                    let queue = self.queues.entry(queue.clone()).or_default();
+                    let id = queue.next_id;
+                    queue.next_id += 1;
+                    let value = value.clone();
+                    queue.messages.insert(
+                        id,
+                        Queued {
+                            value,
+                            deliveries: 0,
+                        },
+                    );
+                    queue.ready.insert(id);
+                    Body::EnqueueOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        id,
+                    }
+                }
+                Op::Dequeue { queue, timeout, .. } => {
+                    let now = self.now;
+                    let msg = self.queues.get_mut(queue).and_then(|queue| {
+                        queue.redeliver(now);
+                        let id = queue.ready.pop_first()?;
+                        queue.hidden.insert((now + timeout, id));
+                        let queued = queue.messages.get_mut(&id)?;
+                        queued.deliveries += 1;
+                        Some(Delivery {
+                            id,
+                            value: queued.value.clone(),
+                            attempt: queued.deliveries,
+                        })
+                    });
+                    Body::DequeueOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        msg,
+                    }
+                }
+                Op::Ack { queue, id, .. } => {
+                    if let Some(queue) = self.queues.get_mut(queue) {
+                        if queue.messages.remove(id).is_some() {
+                            queue.ready.remove(id);
+                            queue.hidden.retain(|(_, hidden)| hidden != id);
+                        }
+                    }
+                    Body::AckOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    }
+                }
+            }
+        }
+
+        fn snapshot(&self) -> Queues {
+            self.clone()
+        }
+
+        fn restore(&mut self, snapshot: Queues) {
+            *self = snapshot;
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                node_ids: Vec::new(),
+                raft: None,
+                forwards: HashMap::new(),
+                proposals: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            let raft = self.raft.as_ref().map(|raft| {
+                json!({
+                    "term": raft.term(),
+                    "leader": raft.leader(),
+                    "nodes": raft.nodes(),
+                    "last_index": raft.last_index(),
+                    "commit_index": raft.commit_index(),
+                })
+            });
+            json!({
+                "id": self.id,
+                "raft": raft,
+                "forwards": self.forwards.len(),
+                "proposals": self.proposals.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Drives Raft's timers, and gives up on requests that have waited too long.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            let mut outbox = Vec::new();
+            if let Some(raft) = &mut self.raft {
+                raft.tick(&mut outbox);
+            }
+            self.send_raft(outbox, &mut messages);
+
+            let now = Instant::now();
+            let expired_forwards: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            let expired_proposals: Vec<u64> = self
+                .proposals
+                .iter()
+                .filter(|(_, proposed)| proposed.waiting.deadline <= now)
+                .map(|(index, _)| *index)
+                .collect();
+            let mut expired: Vec<Waiting> = expired_forwards
+                .iter()
+                .filter_map(|msg_id| self.forwards.remove(msg_id))
+                .chain(
+                    expired_proposals
+                        .iter()
+                        .filter_map(|index| self.proposals.remove(index))
+                        .map(|proposed| proposed.waiting),
+                )
+                .collect();
+            expired.sort_by_key(|waiting| waiting.msg_id);
+            for waiting in expired {
+                // It may yet be committed, or have been and only the reply was lost, so this
+                // mustn't claim the request failed
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code: TIMEOUT,
+                    text: "timed out waiting on the log".to_string(),
+                };
+                self.reply(waiting, error, &mut messages);
+            }
+            messages
+        }
+
+        /// Wraps Raft's messages for the wire, and answers the clients whose requests it
+        /// has applied since.
+        fn send_raft(&mut self, outbox: Outbox<Queues>, messages: &mut Vec<Message>) {
+            for (dest, message) in outbox {
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest,
+                    body: Body::Raft(message),
+                });
+            }
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            for applied in raft.take_applied() {
+                let Some(proposed) = self.proposals.remove(&applied.index) else {
+                    continue;
+                };
+                let reply = match applied.output {
+                    Some(reply) if proposed.term == applied.term => reply,
+                    // Another leader's entry took its place, so it never will take effect
+                    _ => Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "lost leadership before committing".to_string(),
+                    },
+                };
+                self.reply(proposed.waiting, reply, messages);
+            }
+        }
+
+        fn reply(&mut self, waiting: Waiting, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = waiting.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: waiting.client,
+                body,
+            });
+        }
+
+        /// Proposes a client's request if we're leading, or sends it on to the leader with an
+        /// id of our own, so we can tell which client its reply is for. Requests that already
+        /// came from another node aren't forwarded again, so they can't go round in circles.
+        fn request(&mut self, src: &str, mut body: Body, messages: &mut Vec<Message>) {
+            let Some(msg_id) = body.msg_id().copied() else {
+                return;
+            };
+            let waiting = Waiting {
+                client: src.to_string(),
+                msg_id,
+                deadline: Instant::now() + self.config.request_timeout,
+            };
+            let from_node = self.node_ids.iter().any(|node| node == src);
+            let default_timeout = self.config.redelivery_timeout.as_millis() as u64;
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            let Some(op) = body.clone().op(wall_clock(), default_timeout) else {
+                return;
+            };
+            let mut outbox = Vec::new();
+            match raft.propose(op, &mut outbox) {
+                Ok(proposal) => {
+                    let proposed = Proposed {
+                        term: proposal.term,
+                        waiting,
+                    };
+                    self.proposals.insert(proposal.index, proposed);
+                }
+                Err(Rejected::NotLeader(Some(leader))) if !from_node => {
+                    let forward_id = self.next_msg_id();
+                    if let Some(msg_id) = body.msg_id() {
+                        *msg_id = forward_id;
+                    }
+                    self.forwards.insert(forward_id, waiting);
+                    messages.push(Message {
+                        src: self.id.clone(),
+                        dest: leader,
+                        body,
+                    });
+                }
+                Err(_) => {
+                    let error = Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "no leader to serve this".to_string(),
+                    };
+                    self.reply(waiting, error, messages);
+                }
+            }
+            self.send_raft(outbox, messages);
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.raft = Some(Raft::new(
+                        node_id.clone(),
+                        node_ids.clone(),
+                        Queues::default(),
+                        self.config.raft.clone(),
+                    ));
+                    self.node_ids = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Enqueue { .. } | Body::Dequeue { .. } | Body::Ack { .. } => {
+                    self.request(src, body, outbox);
+                    None
+                }
+                Body::Raft(message) => {
+                    let mut raft_outbox = Vec::new();
+                    self.raft.as_mut()?.handle(src, message, &mut raft_outbox);
+                    self.send_raft(raft_outbox, outbox);
+                    None
+                }
+                // The leader's answer to a request we forwarded, which goes on to the client
+                mut reply => {
+                    let forward = self.forwards.remove(reply.in_reply_to()?)?;
+                    self.reply(forward, reply, outbox);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, deliver};
+        use serde_json::json;
+
+        /// Two nodes, n1 and n2, with n1 elected leader.
+        fn cluster() -> Vec<Node> {
+            let mut nodes: Vec<Node> = testing::cluster(2, |id| {
+                let mut config = Config::default();
+                if id == "n1" {
+                    config.raft.election_timeout = Duration::ZERO;
+                }
+                Node::new(config)
+            });
+            let messages = nodes[0].tick();
+            deliver(&mut nodes, messages);
+            assert!(nodes[0].raft.as_ref().unwrap().is_leader());
+            nodes
+        }
+
+        /// Sends `body` to `dest` from a client, and returns the reply.
+        fn send(nodes: &mut [Node], dest: &str, body: serde_json::Value) -> serde_json::Value {
+            let message = json!({"src": "c1", "dest": dest, "body": body});
+            let replies = deliver(nodes, vec![serde_json::from_value(message).unwrap()]);
+            serde_json::to_value(&replies[0].body).unwrap()
+        }
+
+        #[test]
+        fn test_messages_come_out_in_order() {
+            let mut nodes = cluster();
+            for (msg_id, value) in [(1, "a"), (2, "b")] {
+                let enqueue =
+                    json!({"type": "enqueue", "msg_id": msg_id, "queue": "q", "value": value});
+                assert_eq!(send(&mut nodes, "n2", enqueue)["type"], "enqueue_ok");
+            }
+            let dequeue = json!({"type": "dequeue", "msg_id": 3, "queue": "q"});
+            let first = send(&mut nodes, "n1", dequeue.clone());
+            assert_eq!(first["msg"], json!({"id": 0, "value": "a", "attempt": 1}));
+            // The first is hidden waiting on its ack, so the next consumer gets the second
+            let second = send(&mut nodes, "n2", dequeue.clone());
+            assert_eq!(second["msg"]["value"], "b");
+            assert_eq!(send(&mut nodes, "n1", dequeue)["msg"], json!(null));
+        }
+
+        #[test]
+        fn test_unacked_messages_are_redelivered() {
+            let mut nodes = cluster();
+            let enqueue = json!({"type": "enqueue", "msg_id": 1, "queue": "q", "value": 7});
+            send(&mut nodes, "n1", enqueue);
+            // A consumer that crashes before acking
+            let lapsing = json!({"type": "dequeue", "msg_id": 2, "queue": "q", "timeout": 0});
+            assert_eq!(send(&mut nodes, "n1", lapsing)["msg"]["attempt"], 1);
+
+            let dequeue = json!({"type": "dequeue", "msg_id": 3, "queue": "q"});
+            let redelivered = send(&mut nodes, "n1", dequeue.clone());
+            assert_eq!(
+                redelivered["msg"],
+                json!({"id": 0, "value": 7, "attempt": 2})
+            );
+            let ack = json!({"type": "ack", "msg_id": 4, "queue": "q", "id": 0});
+            assert_eq!(send(&mut nodes, "n1", ack.clone())["type"], "ack_ok");
+            // However many times it's acked, it's gone for good
+            assert_eq!(send(&mut nodes, "n1", ack)["type"], "ack_ok");
+            let queues = nodes[1].raft.as_ref().unwrap().state();
+            assert!(queues.queues["q"].messages.is_empty());
+        }
+
+        #[test]
+        fn test_the_log_clock_never_goes_backwards() {
+            let mut queues = Queues::default();
+            let dequeue = |now| Op::Dequeue {
+                queue: "q".into(),
+                timeout: 10,
+                now,
+            };
+            queues.apply(&Op::Enqueue {
+                queue: "q".into(),
+                value: Value::Null,
+                now: 100,
+            });
+            assert!(matches!(
+                queues.apply(&dequeue(100)),
+                Body::DequeueOk { msg: Some(_), .. }
+            ));
+            // A leader whose clock is behind doesn't hide the message for longer
+            assert!(matches!(
+                queues.apply(&dequeue(50)),
+                Body::DequeueOk { msg: None, .. }
+            ));
+            assert!(matches!(
+                queues.apply(&dequeue(110)),
+                Body::DequeueOk { msg: Some(_), .. }
+            ));
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
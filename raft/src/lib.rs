@@ -0,0 +1,1055 @@
+//! Raft consensus over the Maelstrom message bus: leader election and log replication,
+//! driving a state machine every node applies the same commands to in the same order.
+//!
+//! Hosts embed `RaftMessage`s in their own message bodies, hand the ones they receive to
+//! `Raft::handle`, call `Raft::tick` regularly, and send on whatever lands in the outbox.
+//! Nothing is persisted: a Maelstrom node that restarts comes back empty anyway.
+
+use rand::Rng;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::fmt::Debug;
+use std::time::{Duration, Instant};
+
+/// What Raft replicates: commands go into the log, and once committed each node applies
+/// them, in log order, to its own copy.
+pub trait StateMachine {
+    type Command: Serialize + DeserializeOwned + Clone + Debug;
+    type Output;
+    /// A copy of the whole state, which stands in for the log up to where it was taken.
+    type Snapshot: Serialize + DeserializeOwned + Clone + Debug;
+
+    fn apply(&mut self, command: &Self::Command) -> Self::Output;
+
+    fn snapshot(&self) -> Self::Snapshot;
+
+    /// Replaces the state with `snapshot`.
+    fn restore(&mut self, snapshot: Self::Snapshot);
+}
+
+/// Tunables for a Raft node.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How long a follower waits to hear from a leader before standing for election, plus
+    /// up to as long again at random so candidates rarely split the vote.
+    pub election_timeout: Duration,
+    /// Time between a leader's append_entries to each follower, which keep it in office.
+    pub heartbeat_interval: Duration,
+    /// Most entries sent in one append_entries.
+    pub max_batch: usize,
+    /// Applied entries the log holds before they're folded into a snapshot; 0 to keep the
+    /// whole log.
+    pub snapshot_threshold: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            election_timeout: Duration::from_millis(500),
+            heartbeat_interval: Duration::from_millis(100),
+            max_batch: 64,
+            snapshot_threshold: 1000,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry<C> {
+    pub term: u64,
+    /// `None` for the no-op a new leader appends, which lets it commit what earlier terms
+    /// left behind, and for membership changes.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub command: Option<C>,
+    /// The cluster's new members, for a membership change. Each node goes by the latest
+    /// membership in its log, whether or not it's committed.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub nodes: Option<Vec<String>>,
+}
+
+/// The messages Raft nodes exchange, tagged by "type" like any Maelstrom body, carrying
+/// commands of type `C` and snapshots of type `D`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum RaftMessage<C, D> {
+    RequestVote {
+        term: u64,
+        last_log_index: u64,
+        last_log_term: u64,
+    },
+    RequestVoteOk {
+        term: u64,
+        vote_granted: bool,
+    },
+    AppendEntries {
+        term: u64,
+        prev_log_index: u64,
+        prev_log_term: u64,
+        entries: Vec<Entry<C>>,
+        leader_commit: u64,
+    },
+    /// The state up to `last_included_index`, for a follower that needs entries the leader
+    /// has already compacted away. It's answered with an append_entries_ok.
+    InstallSnapshot {
+        term: u64,
+        last_included_index: u64,
+        last_included_term: u64,
+        nodes: Vec<String>, // The members as of last_included_index
+        data: D,
+    },
+    /// On success, `match_index` is the last index known to match the leader's log. On
+    /// failure, it's where the leader should try again from.
+    AppendEntriesOk {
+        term: u64,
+        success: bool,
+        match_index: u64,
+    },
+}
+
+/// Where a proposed command went in the log. It took effect if the entry applied at
+/// `index` has the same `term`; if another term's entry is applied there, it never will.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Proposal {
+    pub index: u64,
+    pub term: u64,
+}
+
+/// A command's result, once it's been committed and applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Applied<O> {
+    pub index: u64,
+    pub term: u64,
+    /// `None` for a membership change, which has no output.
+    pub output: Option<O>,
+}
+
+/// Why a proposal wasn't put in the log.
+#[derive(Debug, Clone, PartialEq)]
+pub enum Rejected {
+    /// We aren't leading; this is who is, if we know.
+    NotLeader(Option<String>),
+    /// The last membership change, or our election, isn't committed yet. Changing one node
+    /// at a time only keeps every majority overlapping if each change waits on the last.
+    ChangePending,
+    /// The membership already is what was asked for.
+    NoChange,
+}
+
+enum Role {
+    Follower,
+    Candidate {
+        votes: HashSet<String>,
+    },
+    Leader {
+        next_index: HashMap<String, u64>, // Next entry to send each follower
+        match_index: HashMap<String, u64>, // Last entry each follower is known to have
+    },
+}
+
+pub struct Raft<S: StateMachine> {
+    id: String,
+    nodes: Vec<String>, // Every member of the cluster, by the latest membership in our log
+    config: Config,
+    state: S,
+    term: u64,
+    voted_for: Option<String>, // Who we voted for in `term`
+    role: Role,
+    leader: Option<String>, // The leader of `term`, once we've heard from it
+    log: Vec<Entry<S::Command>>, // The entry at index i is log[i - snapshot_index - 1]
+    snapshot_index: u64,    // Last index the snapshot covers
+    snapshot_term: u64,     // Term of the entry at snapshot_index
+    snapshot: Option<S::Snapshot>, // The state as of snapshot_index, if we've taken one
+    snapshot_nodes: Vec<String>, // The members as of snapshot_index
+    commit_index: u64,
+    last_applied: u64,
+    applied: Vec<Applied<S::Output>>, // Results not yet taken by the host
+    election_deadline: Instant,
+    heartbeat_due: Instant,
+}
+
+/// Messages for other nodes, with who each is for.
+pub type Outbox<S> = Vec<(
+    String,
+    RaftMessage<<S as StateMachine>::Command, <S as StateMachine>::Snapshot>,
+)>;
+
+impl<S: StateMachine> Raft<S> {
+    pub fn new(id: String, nodes: Vec<String>, state: S, config: Config) -> Self {
+        let now = Instant::now();
+        let mut raft = Raft {
+            id,
+            snapshot_nodes: nodes.clone(),
+            nodes,
+            config,
+            state,
+            term: 0,
+            voted_for: None,
+            role: Role::Follower,
+            leader: None,
+            log: Vec::new(),
+            snapshot_index: 0,
+            snapshot_term: 0,
+            snapshot: None,
+            commit_index: 0,
+            last_applied: 0,
+            applied: Vec::new(),
+            election_deadline: now,
+            heartbeat_due: now,
+        };
+        raft.reset_election_deadline();
+        raft
+    }
+
+    pub fn is_leader(&self) -> bool {
+        matches!(self.role, Role::Leader { .. })
+    }
+
+    /// The leader of the current term, if we know it.
+    pub fn leader(&self) -> Option<&str> {
+        self.leader.as_deref()
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    pub fn term(&self) -> u64 {
+        self.term
+    }
+
+    pub fn commit_index(&self) -> u64 {
+        self.commit_index
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Results of the commands applied since the last call, in log order.
+    pub fn take_applied(&mut self) -> Vec<Applied<S::Output>> {
+        std::mem::take(&mut self.applied)
+    }
+
+    /// Appends `command` to the log if we're the leader.
+    pub fn propose(
+        &mut self,
+        command: S::Command,
+        outbox: &mut Outbox<S>,
+    ) -> Result<Proposal, Rejected> {
+        if !self.is_leader() {
+            return Err(Rejected::NotLeader(self.leader.clone()));
+        }
+        Ok(self.append(Some(command), None, outbox))
+    }
+
+    /// Makes `node` a member, once it's committed. Until then, it only follows the log.
+    pub fn add_node(&mut self, node: &str, outbox: &mut Outbox<S>) -> Result<Proposal, Rejected> {
+        let mut nodes = self.nodes.clone();
+        if !nodes.iter().any(|member| member == node) {
+            nodes.push(node.to_string());
+        }
+        self.change_nodes(nodes, outbox)
+    }
+
+    /// Takes `node` out of the cluster. If that's us, we lead until it's committed, then
+    /// step down.
+    pub fn remove_node(
+        &mut self,
+        node: &str,
+        outbox: &mut Outbox<S>,
+    ) -> Result<Proposal, Rejected> {
+        let mut nodes = self.nodes.clone();
+        nodes.retain(|member| member != node);
+        self.change_nodes(nodes, outbox)
+    }
+
+    /// Sends heartbeats if we're leading, or stands for election if the leader's gone quiet.
+    pub fn tick(&mut self, outbox: &mut Outbox<S>) {
+        let now = Instant::now();
+        if self.is_leader() {
+            if now >= self.heartbeat_due {
+                self.broadcast_append(outbox);
+            }
+        } else if now >= self.election_deadline && self.nodes.contains(&self.id) {
+            self.start_election(outbox);
+        }
+    }
+
+    pub fn handle(
+        &mut self,
+        src: &str,
+        message: RaftMessage<S::Command, S::Snapshot>,
+        outbox: &mut Outbox<S>,
+    ) {
+        match message {
+            RaftMessage::RequestVote {
+                term,
+                last_log_index,
+                last_log_term,
+            } => {
+                if term > self.term {
+                    self.step_down(term);
+                }
+                // Only a candidate with everything we have can be sure to have every
+                // committed entry
+                let up_to_date =
+                    (last_log_term, last_log_index) >= (self.last_term(), self.last_index());
+                let vote_granted = term == self.term
+                    && up_to_date
+                    && self.voted_for.as_deref().is_none_or(|voted| voted == src);
+                if vote_granted {
+                    self.voted_for = Some(src.to_string());
+                    self.reset_election_deadline();
+                }
+                outbox.push((
+                    src.to_string(),
+                    RaftMessage::RequestVoteOk {
+                        term: self.term,
+                        vote_granted,
+                    },
+                ));
+            }
+            RaftMessage::RequestVoteOk { term, vote_granted } => {
+                if term > self.term {
+                    self.step_down(term);
+                    return;
+                }
+                if term < self.term || !vote_granted {
+                    return;
+                }
+                if let Role::Candidate { votes } = &mut self.role {
+                    votes.insert(src.to_string());
+                    self.check_votes(outbox);
+                }
+            }
+            RaftMessage::AppendEntries {
+                term,
+                mut prev_log_index,
+                mut prev_log_term,
+                mut entries,
+                leader_commit,
+            } => {
+                if term < self.term {
+                    outbox.push((src.to_string(), self.append_reply(false, 0)));
+                    return;
+                }
+                self.follow(src, term);
+                if prev_log_index < self.snapshot_index {
+                    // What our snapshot covers is committed, so it matches the leader's log
+                    let covered = self.snapshot_index - prev_log_index;
+                    if entries.len() as u64 <= covered {
+                        let reply = self.append_reply(true, self.snapshot_index);
+                        outbox.push((src.to_string(), reply));
+                        return;
+                    }
+                    entries.drain(..covered as usize);
+                    prev_log_index = self.snapshot_index;
+                    prev_log_term = self.snapshot_term;
+                }
+                if prev_log_index > self.last_index()
+                    || self.term_at(prev_log_index) != prev_log_term
+                {
+                    // Back up to before the mismatch, or to the end of our log if it's short
+                    let retry = self.last_index().min(prev_log_index.saturating_sub(1));
+                    outbox.push((src.to_string(), self.append_reply(false, retry)));
+                    return;
+                }
+                let matched = prev_log_index + entries.len() as u64;
+                for (index, entry) in (prev_log_index + 1..).zip(entries) {
+                    if index <= self.last_index() {
+                        if self.term_at(index) == entry.term {
+                            continue;
+                        }
+                        // Never committed, since the leader doesn't have it
+                        self.log
+                            .truncate((index - self.snapshot_index - 1) as usize);
+                    }
+                    self.log.push(entry);
+                }
+                self.refresh_nodes();
+                if leader_commit > self.commit_index {
+                    self.commit_index = leader_commit.min(matched).max(self.commit_index);
+                    self.apply();
+                }
+                outbox.push((src.to_string(), self.append_reply(true, matched)));
+            }
+            RaftMessage::InstallSnapshot {
+                term,
+                last_included_index,
+                last_included_term,
+                nodes,
+                data,
+            } => {
+                if term < self.term {
+                    outbox.push((src.to_string(), self.append_reply(false, 0)));
+                    return;
+                }
+                self.follow(src, term);
+                // Anything we've committed already is in there, so only take a newer one
+                if last_included_index > self.commit_index {
+                    if last_included_index <= self.last_index()
+                        && self.term_at(last_included_index) == last_included_term
+                    {
+                        // What follows it may still be good
+                        self.log
+                            .drain(..(last_included_index - self.snapshot_index) as usize);
+                    } else {
+                        self.log.clear();
+                    }
+                    log::info!(
+                        "{} restoring a snapshot up to {}",
+                        self.id,
+                        last_included_index
+                    );
+                    self.snapshot_index = last_included_index;
+                    self.snapshot_term = last_included_term;
+                    self.state.restore(data.clone());
+                    self.snapshot = Some(data);
+                    self.snapshot_nodes = nodes;
+                    self.refresh_nodes();
+                    self.commit_index = last_included_index;
+                    self.last_applied = last_included_index;
+                }
+                outbox.push((
+                    src.to_string(),
+                    self.append_reply(true, last_included_index),
+                ));
+            }
+            RaftMessage::AppendEntriesOk {
+                term,
+                success,
+                match_index,
+            } => {
+                if term > self.term {
+                    self.step_down(term);
+                    return;
+                }
+                let last_index = self.last_index();
+                let Role::Leader {
+                    next_index,
+                    match_index: matched,
+                } = &mut self.role
+                else {
+                    return;
+                };
+                if term < self.term {
+                    return;
+                }
+                let next = next_index.entry(src.to_string()).or_insert(last_index + 1);
+                if success {
+                    let known = matched.entry(src.to_string()).or_default();
+                    *known = (*known).max(match_index);
+                    *next = (*next).max(match_index + 1);
+                } else {
+                    *next = (*next).min(match_index + 1).max(1);
+                }
+                let behind = *next <= last_index;
+                if success {
+                    self.advance_commit();
+                }
+                // Keep a follower that's catching up busy, rather than waiting on a heartbeat
+                if behind {
+                    self.send_append(src, outbox);
+                }
+            }
+        }
+    }
+
+    /// The index of the last entry in the log, including any compacted into the snapshot.
+    pub fn last_index(&self) -> u64 {
+        self.snapshot_index + self.log.len() as u64
+    }
+
+    fn last_term(&self) -> u64 {
+        self.term_at(self.last_index())
+    }
+
+    /// The entry at `index`, which must be past the snapshot.
+    fn entry(&self, index: u64) -> &Entry<S::Command> {
+        &self.log[(index - self.snapshot_index - 1) as usize]
+    }
+
+    /// The term of the entry at `index`, which mustn't be before the snapshot.
+    fn term_at(&self, index: u64) -> u64 {
+        if index == self.snapshot_index {
+            self.snapshot_term
+        } else {
+            self.entry(index).term
+        }
+    }
+
+    fn majority(&self) -> usize {
+        self.nodes.len() / 2 + 1
+    }
+
+    fn peers(&self) -> impl Iterator<Item = &String> {
+        self.nodes.iter().filter(move |node| **node != self.id)
+    }
+
+    /// The latest membership at or before `index`, which mustn't be before the snapshot,
+    /// with the index of the entry that set it.
+    fn membership_at(&self, index: u64) -> (u64, &[String]) {
+        (self.snapshot_index + 1..=index)
+            .rev()
+            .find_map(|i| self.entry(i).nodes.as_deref().map(|nodes| (i, nodes)))
+            .unwrap_or((self.snapshot_index, &self.snapshot_nodes))
+    }
+
+    /// Takes up the latest membership in our log.
+    fn refresh_nodes(&mut self) {
+        let (_, nodes) = self.membership_at(self.last_index());
+        if nodes != self.nodes {
+            log::info!("{} now has members {:?}", self.id, nodes);
+            self.nodes = nodes.to_vec();
+        }
+    }
+
+    /// Who a leader replicates to: the other members, and while a change is uncommitted,
+    /// anyone it removed, so they hear they're out rather than standing for election.
+    fn replicas(&self) -> Vec<String> {
+        let mut replicas: Vec<String> = self.peers().cloned().collect();
+        let (changed_at, _) = self.membership_at(self.last_index());
+        if changed_at > self.commit_index {
+            let (_, before) = self.membership_at(changed_at - 1);
+            for node in before {
+                if *node != self.id && !replicas.contains(node) {
+                    replicas.push(node.clone());
+                }
+            }
+        }
+        replicas
+    }
+
+    fn append(
+        &mut self,
+        command: Option<S::Command>,
+        nodes: Option<Vec<String>>,
+        outbox: &mut Outbox<S>,
+    ) -> Proposal {
+        self.log.push(Entry {
+            term: self.term,
+            command,
+            nodes,
+        });
+        self.refresh_nodes();
+        self.broadcast_append(outbox);
+        self.advance_commit();
+        Proposal {
+            index: self.last_index(),
+            term: self.term,
+        }
+    }
+
+    fn change_nodes(
+        &mut self,
+        nodes: Vec<String>,
+        outbox: &mut Outbox<S>,
+    ) -> Result<Proposal, Rejected> {
+        if !self.is_leader() {
+            return Err(Rejected::NotLeader(self.leader.clone()));
+        }
+        let (changed_at, _) = self.membership_at(self.last_index());
+        // Until something from our term is committed, an uncommitted change from an earlier
+        // leader could still be in play
+        if changed_at > self.commit_index || self.term_at(self.commit_index) != self.term {
+            return Err(Rejected::ChangePending);
+        }
+        if nodes == self.nodes {
+            return Err(Rejected::NoChange);
+        }
+        Ok(self.append(None, Some(nodes), outbox))
+    }
+
+    fn reset_election_deadline(&mut self) {
+        let jitter =
+            rand::thread_rng().gen_range(0..=self.config.election_timeout.as_millis() as u64);
+        self.election_deadline =
+            Instant::now() + self.config.election_timeout + Duration::from_millis(jitter);
+    }
+
+    /// Recognizes `leader` as leading `term`, which is at least ours.
+    fn follow(&mut self, leader: &str, term: u64) {
+        if term > self.term || !matches!(self.role, Role::Follower) {
+            self.step_down(term);
+        }
+        self.leader = Some(leader.to_string());
+        self.reset_election_deadline();
+    }
+
+    /// Follows whoever leads `term`, once we hear from them.
+    fn step_down(&mut self, term: u64) {
+        if term > self.term {
+            self.term = term;
+            self.voted_for = None;
+            self.leader = None;
+        }
+        if !matches!(self.role, Role::Follower) {
+            log::info!("{} stepping down in term {}", self.id, self.term);
+            self.role = Role::Follower;
+        }
+    }
+
+    fn start_election(&mut self, outbox: &mut Outbox<S>) {
+        self.term += 1;
+        self.voted_for = Some(self.id.clone());
+        self.leader = None;
+        self.role = Role::Candidate {
+            votes: HashSet::from([self.id.clone()]),
+        };
+        self.reset_election_deadline();
+        log::info!("{} standing for election in term {}", self.id, self.term);
+        let request = RaftMessage::RequestVote {
+            term: self.term,
+            last_log_index: self.last_index(),
+            last_log_term: self.last_term(),
+        };
+        for peer in self.peers() {
+            outbox.push((peer.clone(), request.clone()));
+        }
+        self.check_votes(outbox);
+    }
+
+    fn check_votes(&mut self, outbox: &mut Outbox<S>) {
+        let Role::Candidate { votes } = &self.role else {
+            return;
+        };
+        if votes
+            .iter()
+            .filter(|voter| self.nodes.contains(voter))
+            .count()
+            < self.majority()
+        {
+            return;
+        }
+        log::info!("{} elected leader of term {}", self.id, self.term);
+        let next = self.last_index() + 1;
+        self.role = Role::Leader {
+            next_index: self.peers().map(|peer| (peer.clone(), next)).collect(),
+            match_index: self.peers().map(|peer| (peer.clone(), 0)).collect(),
+        };
+        self.leader = Some(self.id.clone());
+        self.append(None, None, outbox);
+    }
+
+    fn append_reply(
+        &self,
+        success: bool,
+        match_index: u64,
+    ) -> RaftMessage<S::Command, S::Snapshot> {
+        RaftMessage::AppendEntriesOk {
+            term: self.term,
+            success,
+            match_index,
+        }
+    }
+
+    fn broadcast_append(&mut self, outbox: &mut Outbox<S>) {
+        for peer in self.replicas() {
+            self.send_append(&peer, outbox);
+        }
+        self.heartbeat_due = Instant::now() + self.config.heartbeat_interval;
+    }
+
+    /// Sends `peer` the entries it's missing, up to a batch of them, or just a heartbeat if
+    /// it has them all. If it's missing entries we've compacted, it gets our snapshot instead.
+    fn send_append(&self, peer: &str, outbox: &mut Outbox<S>) {
+        let Role::Leader { next_index, .. } = &self.role else {
+            return;
+        };
+        let next = next_index
+            .get(peer)
+            .copied()
+            .unwrap_or(self.last_index() + 1)
+            .min(self.last_index() + 1);
+        if next <= self.snapshot_index {
+            if let Some(snapshot) = &self.snapshot {
+                outbox.push((
+                    peer.to_string(),
+                    RaftMessage::InstallSnapshot {
+                        term: self.term,
+                        last_included_index: self.snapshot_index,
+                        last_included_term: self.snapshot_term,
+                        nodes: self.snapshot_nodes.clone(),
+                        data: snapshot.clone(),
+                    },
+                ));
+            }
+            return;
+        }
+        let entries = self.log[(next - self.snapshot_index - 1) as usize..]
+            .iter()
+            .take(self.config.max_batch)
+            .cloned()
+            .collect();
+        outbox.push((
+            peer.to_string(),
+            RaftMessage::AppendEntries {
+                term: self.term,
+                prev_log_index: next - 1,
+                prev_log_term: self.term_at(next - 1),
+                entries,
+                leader_commit: self.commit_index,
+            },
+        ));
+    }
+
+    /// Commits the latest entry of our term that a majority of members has. Earlier terms'
+    /// entries commit along with it, but never by counting replicas on their own.
+    fn advance_commit(&mut self) {
+        let Role::Leader { match_index, .. } = &self.role else {
+            return;
+        };
+        let has = |node: &String, index: u64| {
+            *node == self.id
+                || match_index
+                    .get(node)
+                    .is_some_and(|matched| *matched >= index)
+        };
+        let committed = (self.commit_index + 1..=self.last_index())
+            .rev()
+            .find(|index| {
+                self.term_at(*index) == self.term
+                    && self.nodes.iter().filter(|node| has(node, *index)).count() >= self.majority()
+            });
+        if let Some(index) = committed {
+            self.commit_index = index;
+            self.apply();
+        }
+    }
+
+    fn apply(&mut self) {
+        while self.last_applied < self.commit_index {
+            self.last_applied += 1;
+            let entry = &self.log[(self.last_applied - self.snapshot_index - 1) as usize];
+            if let Some(command) = &entry.command {
+                let output = self.state.apply(command);
+                self.applied.push(Applied {
+                    index: self.last_applied,
+                    term: entry.term,
+                    output: Some(output),
+                });
+            } else if entry.nodes.is_some() {
+                self.applied.push(Applied {
+                    index: self.last_applied,
+                    term: entry.term,
+                    output: None,
+                });
+            }
+        }
+        // A leader that's been removed leads until that's committed, and no longer
+        let (_, nodes) = self.membership_at(self.commit_index);
+        if self.is_leader() && !nodes.contains(&self.id) {
+            self.step_down(self.term);
+            self.leader = None;
+        }
+        self.compact();
+    }
+
+    /// Folds the applied entries into a snapshot once there are enough of them, so the log
+    /// doesn't grow without bound.
+    fn compact(&mut self) {
+        let threshold = self.config.snapshot_threshold as u64;
+        if threshold == 0 || self.last_applied - self.snapshot_index < threshold {
+            return;
+        }
+        self.snapshot_term = self.term_at(self.last_applied);
+        self.snapshot_nodes = self.membership_at(self.last_applied).1.to_vec();
+        self.log
+            .drain(..(self.last_applied - self.snapshot_index) as usize);
+        self.snapshot_index = self.last_applied;
+        self.snapshot = Some(self.state.snapshot());
+        log::debug!(
+            "{} compacted its log up to {}",
+            self.id,
+            self.snapshot_index
+        );
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds up every command, returning the running total.
+    #[derive(Default)]
+    struct Sum(u64);
+
+    impl StateMachine for Sum {
+        type Command = u64;
+        type Output = u64;
+        type Snapshot = u64;
+
+        fn apply(&mut self, command: &u64) -> u64 {
+            self.0 += command;
+            self.0
+        }
+
+        fn snapshot(&self) -> u64 {
+            self.0
+        }
+
+        fn restore(&mut self, snapshot: u64) {
+            self.0 = snapshot;
+        }
+    }
+
+    fn cluster() -> Vec<Raft<Sum>> {
+        cluster_with(Config::default())
+    }
+
+    fn cluster_with(config: Config) -> Vec<Raft<Sum>> {
+        let nodes: Vec<String> = vec!["n0".into(), "n1".into(), "n2".into()];
+        nodes
+            .iter()
+            .map(|id| Raft::new(id.clone(), nodes.clone(), Sum::default(), config.clone()))
+            .collect()
+    }
+
+    /// Delivers `outbox` from `src`, and everything sent in response, until the cluster's
+    /// quiet. Messages to or from nodes in `down` are dropped.
+    fn deliver(cluster: &mut [Raft<Sum>], src: usize, outbox: Outbox<Sum>, down: &[usize]) {
+        let mut in_flight: Vec<(usize, Outbox<Sum>)> = vec![(src, outbox)];
+        while let Some((src, outbox)) = in_flight.pop() {
+            for (dest, message) in outbox {
+                let dest: usize = dest[1..].parse().unwrap();
+                if down.contains(&src) || down.contains(&dest) {
+                    continue;
+                }
+                let mut replies = Vec::new();
+                cluster[dest].handle(&format!("n{}", src), message, &mut replies);
+                in_flight.push((dest, replies));
+            }
+        }
+    }
+
+    fn elect(cluster: &mut [Raft<Sum>], node: usize, down: &[usize]) {
+        let mut outbox = Vec::new();
+        cluster[node].start_election(&mut outbox);
+        deliver(cluster, node, outbox, down);
+        assert!(cluster[node].is_leader());
+    }
+
+    fn propose(cluster: &mut [Raft<Sum>], node: usize, command: u64, down: &[usize]) -> Proposal {
+        let mut outbox = Vec::new();
+        let proposal = cluster[node].propose(command, &mut outbox).unwrap();
+        deliver(cluster, node, outbox, down);
+        proposal
+    }
+
+    #[test]
+    fn test_commands_apply_everywhere_in_order() {
+        let mut cluster = cluster();
+        elect(&mut cluster, 0, &[]);
+        propose(&mut cluster, 0, 2, &[]);
+        let proposal = propose(&mut cluster, 0, 3, &[]);
+        // Followers hear of the last commit with the next heartbeat
+        let mut outbox = Vec::new();
+        cluster[0].broadcast_append(&mut outbox);
+        deliver(&mut cluster, 0, outbox, &[]);
+        for raft in &mut cluster {
+            let applied = raft.take_applied();
+            let outputs: Vec<u64> = applied.iter().filter_map(|a| a.output).collect();
+            assert_eq!(outputs, [2, 5]);
+            assert_eq!(
+                (applied[1].index, applied[1].term),
+                (proposal.index, proposal.term)
+            );
+        }
+        assert_eq!(
+            cluster[1].propose(1, &mut Vec::new()),
+            Err(Rejected::NotLeader(Some("n0".into())))
+        );
+    }
+
+    #[test]
+    fn test_a_deposed_leaders_uncommitted_entries_are_replaced() {
+        let mut cluster = cluster();
+        elect(&mut cluster, 0, &[]);
+        // n0 is cut off, so this never reaches a majority
+        let lost = propose(&mut cluster, 0, 7, &[1, 2]);
+        assert_eq!(cluster[0].commit_index(), lost.index - 1);
+
+        elect(&mut cluster, 1, &[0]);
+        let kept = propose(&mut cluster, 1, 1, &[0]);
+        assert_eq!(kept.index, lost.index + 1);
+
+        // n0 comes back, hears from the new leader and takes its log
+        let mut outbox = Vec::new();
+        cluster[1].broadcast_append(&mut outbox);
+        deliver(&mut cluster, 1, outbox, &[]);
+        assert!(!cluster[0].is_leader());
+        assert_eq!(cluster[0].log, cluster[1].log);
+        let applied = cluster[0].take_applied();
+        let outputs: Vec<u64> = applied.iter().filter_map(|a| a.output).collect();
+        assert_eq!(outputs, [1]);
+        assert_eq!(cluster[0].state().0, 1);
+    }
+
+    #[test]
+    fn test_lagging_followers_catch_up_from_a_snapshot() {
+        let mut cluster = cluster_with(Config {
+            snapshot_threshold: 2,
+            ..Config::default()
+        });
+        elect(&mut cluster, 0, &[2]);
+        for command in 1..=3 {
+            propose(&mut cluster, 0, command, &[2]);
+        }
+        let mut outbox = Vec::new();
+        cluster[0].broadcast_append(&mut outbox);
+        deliver(&mut cluster, 0, outbox, &[2]);
+        assert!(cluster[0].snapshot_index > 1);
+        assert!(cluster[0].log.len() < cluster[0].last_index() as usize);
+
+        // n2 comes back needing entries n0 no longer has
+        let mut outbox = Vec::new();
+        cluster[0].broadcast_append(&mut outbox);
+        deliver(&mut cluster, 0, outbox, &[]);
+        assert_eq!(cluster[2].state().0, 6);
+        assert_eq!(cluster[2].last_index(), cluster[0].last_index());
+
+        propose(&mut cluster, 0, 4, &[]);
+        let mut outbox = Vec::new();
+        cluster[0].broadcast_append(&mut outbox);
+        deliver(&mut cluster, 0, outbox, &[]);
+        assert_eq!(cluster[2].state().0, 10);
+        assert_eq!(
+            cluster[2].take_applied().last().and_then(|a| a.output),
+            Some(10)
+        );
+    }
+
+    /// n0 to n3, with only n0 to n2 members to begin with.
+    fn cluster_with_spare() -> Vec<Raft<Sum>> {
+        let members: Vec<String> = vec!["n0".into(), "n1".into(), "n2".into()];
+        (0..4)
+            .map(|i| {
+                let id = format!("n{}", i);
+                Raft::new(id, members.clone(), Sum::default(), Config::default())
+            })
+            .collect()
+    }
+
+    fn heartbeat(cluster: &mut [Raft<Sum>], node: usize, down: &[usize]) {
+        let mut outbox = Vec::new();
+        cluster[node].broadcast_append(&mut outbox);
+        deliver(cluster, node, outbox, down);
+    }
+
+    #[test]
+    fn test_nodes_join_and_leave() {
+        let mut cluster = cluster_with_spare();
+        elect(&mut cluster, 0, &[]);
+        propose(&mut cluster, 0, 2, &[]);
+        assert!(cluster[3].log.is_empty());
+
+        let mut outbox = Vec::new();
+        let joined = cluster[0].add_node("n3", &mut outbox).unwrap();
+        deliver(&mut cluster, 0, outbox, &[]);
+        heartbeat(&mut cluster, 0, &[]);
+        assert!(cluster[0].commit_index() >= joined.index);
+        assert_eq!(cluster[3].nodes(), ["n0", "n1", "n2", "n3"]);
+        assert_eq!(cluster[3].state().0, 2);
+
+        // Once n0's removal of itself commits, it stops leading, and the rest carry on
+        let mut outbox = Vec::new();
+        cluster[0].remove_node("n0", &mut outbox).unwrap();
+        deliver(&mut cluster, 0, outbox, &[]);
+        heartbeat(&mut cluster, 0, &[]);
+        assert!(!cluster[0].is_leader());
+        assert_eq!(cluster[0].nodes(), ["n1", "n2", "n3"]);
+        let mut outbox = Vec::new();
+        cluster[0].election_deadline = Instant::now();
+        cluster[0].tick(&mut outbox);
+        assert!(outbox.is_empty(), "n0 stood for election: {:?}", outbox);
+
+        elect(&mut cluster, 3, &[0, 1]);
+        propose(&mut cluster, 3, 3, &[0, 1]);
+        heartbeat(&mut cluster, 3, &[0, 1]);
+        assert_eq!(cluster[2].state().0, 5);
+    }
+
+    #[test]
+    fn test_one_membership_change_at_a_time() {
+        let mut cluster = cluster_with_spare();
+        elect(&mut cluster, 0, &[]);
+        let mut outbox = Vec::new();
+        assert_eq!(
+            cluster[0].add_node("n1", &mut outbox),
+            Err(Rejected::NoChange)
+        );
+        // With n1 and n2 cut off, the first change can't commit
+        cluster[0].add_node("n3", &mut outbox).unwrap();
+        deliver(&mut cluster, 0, outbox, &[1, 2]);
+        assert_eq!(
+            cluster[0].remove_node("n2", &mut Vec::new()),
+            Err(Rejected::ChangePending)
+        );
+        assert_eq!(
+            cluster[1].add_node("n3", &mut Vec::new()),
+            Err(Rejected::NotLeader(Some("n0".into())))
+        );
+    }
+
+    #[test]
+    fn test_one_vote_per_term() {
+        let mut cluster = cluster();
+        let mut outbox = Vec::new();
+        cluster[0].start_election(&mut outbox);
+        let request = outbox[0].1.clone();
+        let mut replies = Vec::new();
+        cluster[2].handle("n0", request.clone(), &mut replies);
+        cluster[2].handle("n1", request, &mut replies);
+        let granted: Vec<bool> = replies
+            .iter()
+            .map(|(_, reply)| {
+                matches!(
+                    reply,
+                    RaftMessage::RequestVoteOk {
+                        vote_granted: true,
+                        ..
+                    }
+                )
+            })
+            .collect();
+        assert_eq!(granted, [true, false]);
+    }
+
+    #[test]
+    fn test_message_format() {
+        let message: RaftMessage<u64, u64> = RaftMessage::AppendEntries {
+            term: 2,
+            prev_log_index: 1,
+            prev_log_term: 1,
+            entries: vec![
+                Entry {
+                    term: 2,
+                    command: None,
+                    nodes: None,
+                },
+                Entry {
+                    term: 2,
+                    command: Some(5),
+                    nodes: None,
+                },
+                Entry {
+                    term: 2,
+                    command: None,
+                    nodes: Some(vec!["n1".into()]),
+                },
+            ],
+            leader_commit: 1,
+        };
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["type"], "append_entries");
+        assert_eq!(
+            json["entries"],
+            serde_json::json!([{"term": 2}, {"term": 2, "command": 5}, {"term": 2, "nodes": ["n1"]}])
+        );
+        assert_eq!(
+            serde_json::from_value::<RaftMessage<u64, u64>>(json).unwrap(),
+            message
+        );
+    }
+}
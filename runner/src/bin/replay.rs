@@ -0,0 +1,164 @@
+use runner::replay::{self, Matcher, Step};
+use serde_json::Value;
+use std::error::Error;
+use std::fs::File;
+use std::io;
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::Duration;
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::Command;
+use tokio::sync::mpsc;
+use tokio::time::{timeout_at, Instant};
+
+const USAGE: &str = "\
+Usage: replay --bin <path> --store <path> [options]
+
+Feeds one node's inputs from a recorded Maelstrom run to a node binary, in the order it got
+them, and diffs what it sends against what was recorded: - for a message it didn't send, +
+for one it sent that wasn't. Exits 1 if they differ.
+
+Options:
+  --bin <path>          The node binary to run
+  --store <path>        A run directory under store/, whose jepsen.log is read, or a log
+                        file. Maelstrom only logs messages if run with --log-net-send
+  --node <id>           The node to replay [default: n1]
+  --timeout-ms <ms>     How long to wait on each message the node should send [default: 1000]";
+
+/// Options, from the command line.
+struct Config {
+    bin: PathBuf,
+    store: PathBuf,
+    node: String,
+    timeout: Duration,
+}
+
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config {
+            bin: PathBuf::new(),
+            store: PathBuf::new(),
+            node: "n1".to_string(),
+            timeout: Duration::from_millis(1000),
+        };
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or(format!("{} needs a value", flag))?;
+            let invalid = || format!("invalid value {:?} for {}", value, flag);
+            match flag.as_str() {
+                "--bin" => config.bin = PathBuf::from(&value),
+                "--store" => config.store = PathBuf::from(&value),
+                "--node" => config.node = value,
+                "--timeout-ms" => {
+                    config.timeout = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+                }
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        if config.bin.as_os_str().is_empty() || config.store.as_os_str().is_empty() {
+            return Err("--bin and --store are required".to_string());
+        }
+        Ok(config)
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    simple_logger::SimpleLogger::new().env().init()?;
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+
+    let log = match config.store.is_dir() {
+        true => config.store.join("jepsen.log"),
+        false => config.store.clone(),
+    };
+    let messages = replay::read_messages(io::BufReader::new(File::open(&log)?))?;
+    let steps = replay::steps(&messages, &config.node);
+    if steps.is_empty() {
+        return Err(format!(
+            "no messages to or from {} in {}; was the run logged with --log-net-send?",
+            config.node,
+            log.display()
+        )
+        .into());
+    }
+
+    let mut child = Command::new(&config.bin)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .kill_on_drop(true)
+        .spawn()?;
+    let (Some(mut stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+        unreachable!("stdin and stdout are piped");
+    };
+    let (output, mut received) = mpsc::unbounded_channel::<Value>();
+    tokio::spawn(async move {
+        let mut lines = BufReader::new(stdout).lines();
+        while let Ok(Some(line)) = lines.next_line().await {
+            match serde_json::from_str(&line) {
+                Ok(message) => {
+                    if output.send(message).is_err() {
+                        return;
+                    }
+                }
+                Err(e) => log::warn!("Unable to parse from the node: {}", e),
+            }
+        }
+    });
+
+    let mut matcher = Matcher::default();
+    let (mut inputs, mut outputs, mut missing) = (0, 0, Vec::new());
+    for step in steps {
+        match step {
+            Step::Input(message) => {
+                inputs += 1;
+                let line = format!("{}\n", matcher.input(message));
+                if let Err(e) = stdin.write_all(line.as_bytes()).await {
+                    log::warn!("Unable to write to the node: {}", e);
+                }
+            }
+            Step::Output(expected) => {
+                outputs += 1;
+                let deadline = Instant::now() + config.timeout;
+                while !matcher.expect(&expected) {
+                    match timeout_at(deadline, received.recv()).await {
+                        Ok(Some(message)) => matcher.sent(message),
+                        _ => {
+                            missing.push(expected);
+                            break;
+                        }
+                    }
+                }
+            }
+        }
+    }
+    // Anything else it was going to send
+    while let Ok(Some(message)) = tokio::time::timeout(config.timeout, received.recv()).await {
+        matcher.sent(message);
+    }
+    let unexpected = matcher.unexpected();
+    drop(child); // Killed here, as exiting won't drop it
+
+    log::info!(
+        "Replayed {} messages to {}; {} of the {} it sent matched",
+        inputs,
+        config.node,
+        outputs - missing.len(),
+        outputs
+    );
+    for message in &missing {
+        println!("- {}", message);
+    }
+    for message in &unexpected {
+        println!("+ {}", message);
+    }
+    if !missing.is_empty() || !unexpected.is_empty() {
+        std::process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,896 @@
+//! What the cluster runner records and drives: the history of operations clients ran
+//! against a cluster, and the workloads that generate those operations.
+//!
+//! A history is a list of events, Jepsen style. Each operation shows up twice: when a
+//! client invokes it, and when it completes, as `Ok` if it took effect, `Fail` if it
+//! definitely didn't, or `Info` if there's no telling, say because it timed out. A process
+//! is one client doing one operation at a time; after an `Info` its operation may still be
+//! in flight, so the client carries on as a new process.
+
+use rand::rngs::StdRng;
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::io::{self, BufRead, Write};
+use std::time::Instant;
+
+/// How an event in a history came about.
+#[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum Kind {
+    Invoke,
+    Ok,
+    Fail,
+    Info,
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Event {
+    pub index: u64,
+    /// Nanoseconds since the history began.
+    pub time: u64,
+    pub process: u64,
+    #[serde(rename = "type")]
+    pub kind: Kind,
+    /// The operation's function, like `read` or `write`.
+    pub f: String,
+    /// Its argument when invoked, and its result once complete.
+    pub value: Value,
+}
+
+/// Events, in the order they happened.
+#[derive(Debug, Clone)]
+pub struct History {
+    events: Vec<Event>,
+    start: Instant,
+}
+
+impl Default for History {
+    fn default() -> Self {
+        History {
+            events: Vec::new(),
+            start: Instant::now(),
+        }
+    }
+}
+
+impl History {
+    pub fn record(&mut self, process: u64, kind: Kind, f: &str, value: Value) {
+        self.events.push(Event {
+            index: self.events.len() as u64,
+            time: self.start.elapsed().as_nanos() as u64,
+            process,
+            kind,
+            f: f.to_string(),
+            value,
+        });
+    }
+
+    pub fn events(&self) -> &[Event] {
+        &self.events
+    }
+
+    /// Writes one event per line, as JSON. That's a history as elle-cli reads JSON ones,
+    /// with the event's kind as its `type`.
+    pub fn write_to(&self, mut writer: impl Write) -> io::Result<()> {
+        for event in &self.events {
+            serde_json::to_writer(&mut writer, event)?;
+            writer.write_all(b"\n")?;
+        }
+        writer.flush()
+    }
+
+    /// Writes one event per line as an EDN map, the way Jepsen writes `history.edn`, for
+    /// Knossos and Elle to read.
+    pub fn write_edn(&self, mut writer: impl Write) -> io::Result<()> {
+        for event in &self.events {
+            let mut line = String::new();
+            line.push_str(&format!(
+                "{{:index {}, :time {}, :process {}, :type :{}, :f :{}, :value ",
+                event.index,
+                event.time,
+                event.process,
+                event.kind.name(),
+                event.f
+            ));
+            edn(&event.value, &mut line);
+            line.push_str("}\n");
+            writer.write_all(line.as_bytes())?;
+        }
+        writer.flush()
+    }
+
+    /// Reads back what `write_to` wrote.
+    pub fn read_from(reader: impl BufRead) -> io::Result<History> {
+        let mut events = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            if !line.trim().is_empty() {
+                events.push(serde_json::from_str(&line)?);
+            }
+        }
+        Ok(History {
+            events,
+            start: Instant::now(),
+        })
+    }
+}
+
+impl From<Vec<Event>> for History {
+    fn from(events: Vec<Event>) -> Self {
+        History {
+            events,
+            start: Instant::now(),
+        }
+    }
+}
+
+impl Kind {
+    fn name(&self) -> &'static str {
+        match self {
+            Kind::Invoke => "invoke",
+            Kind::Ok => "ok",
+            Kind::Fail => "fail",
+            Kind::Info => "info",
+        }
+    }
+}
+
+/// Writes `value` as EDN. Objects' keys become keywords, and so do the functions of a
+/// transaction's micro-ops, like the `"r"` in `["r", 1, null]`, since that's how Elle has
+/// them.
+fn edn(value: &Value, out: &mut String) {
+    match value {
+        Value::Null => out.push_str("nil"),
+        Value::Bool(_) | Value::Number(_) | Value::String(_) => out.push_str(&value.to_string()),
+        Value::Array(values) => {
+            out.push('[');
+            for (i, value) in values.iter().enumerate() {
+                if i > 0 {
+                    out.push(' ');
+                }
+                match value.as_str() {
+                    Some(f @ ("r" | "w" | "append")) if i == 0 && values.len() == 3 => {
+                        out.push(':');
+                        out.push_str(f);
+                    }
+                    _ => edn(value, out),
+                }
+            }
+            out.push(']');
+        }
+        Value::Object(entries) => {
+            out.push('{');
+            for (i, (key, value)) in entries.iter().enumerate() {
+                if i > 0 {
+                    out.push_str(", ");
+                }
+                out.push(':');
+                out.push_str(key);
+                out.push(' ');
+                edn(value, out);
+            }
+            out.push('}');
+        }
+    }
+}
+
+/// An operation a client is about to invoke.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Op {
+    pub f: String,
+    pub value: Value,
+    /// The request to send for it, without a `msg_id`.
+    pub body: Value,
+}
+
+/// Generates the operations for one kind of node, and reads their replies.
+pub trait Workload {
+    fn invoke(&mut self, rng: &mut StdRng) -> Op;
+
+    /// The value an operation completed with, going by its reply, which wasn't an error.
+    fn ok(&self, op: &Op, reply: &Value) -> Value;
+
+    /// How an operation completed, and with what value, going by its reply.
+    fn complete(&self, op: &Op, reply: &Value) -> (Kind, Value) {
+        if reply["type"] != "error" {
+            return (Kind::Ok, self.ok(op, reply));
+        }
+        // Every code but a timeout means the request definitely didn't take effect
+        match reply["code"].as_u64() {
+            Some(0) | None => (Kind::Info, op.value.clone()),
+            Some(_) => (Kind::Fail, op.value.clone()),
+        }
+    }
+}
+
+/// The workload called `name`, if there is one.
+pub fn workload(name: &str) -> Option<Box<dyn Workload>> {
+    Some(match name {
+        "echo" => Box::new(Echo),
+        "g-counter" => Box::new(GCounter),
+        "lin-kv" => Box::new(LinKv { keys: 5, values: 5 }),
+        _ => return None,
+    })
+}
+
+/// Echoes random numbers, completing with whatever comes back.
+pub struct Echo;
+
+impl Workload for Echo {
+    fn invoke(&mut self, rng: &mut StdRng) -> Op {
+        let echo = rng.gen_range(0..1000);
+        Op {
+            f: "echo".to_string(),
+            value: json!(echo),
+            body: json!({"type": "echo", "echo": echo}),
+        }
+    }
+
+    fn ok(&self, _: &Op, reply: &Value) -> Value {
+        reply["echo"].clone()
+    }
+}
+
+/// Adds to a counter, and reads it.
+pub struct GCounter;
+
+impl Workload for GCounter {
+    fn invoke(&mut self, rng: &mut StdRng) -> Op {
+        if rng.gen_bool(0.5) {
+            return Op {
+                f: "read".to_string(),
+                value: Value::Null,
+                body: json!({"type": "read"}),
+            };
+        }
+        let delta = rng.gen_range(1..=5);
+        Op {
+            f: "add".to_string(),
+            value: json!(delta),
+            body: json!({"type": "add", "delta": delta}),
+        }
+    }
+
+    fn ok(&self, op: &Op, reply: &Value) -> Value {
+        match op.f.as_str() {
+            "read" => reply["value"].clone(),
+            _ => op.value.clone(),
+        }
+    }
+}
+
+/// Reads, writes and compare-and-sets a few keys, with values as Knossos has them: `[key,
+/// value]` for reads and writes, and `[key, [from, to]]` for a compare-and-set.
+pub struct LinKv {
+    pub keys: u64,
+    pub values: u64,
+}
+
+impl Workload for LinKv {
+    fn invoke(&mut self, rng: &mut StdRng) -> Op {
+        let key = rng.gen_range(0..self.keys);
+        let (f, value, body) = match rng.gen_range(0..3) {
+            0 => (
+                "read",
+                json!([key, null]),
+                json!({"type": "read", "key": key}),
+            ),
+            1 => {
+                let value = rng.gen_range(0..self.values);
+                (
+                    "write",
+                    json!([key, value]),
+                    json!({"type": "write", "key": key, "value": value}),
+                )
+            }
+            _ => {
+                let (from, to) = (rng.gen_range(0..self.values), rng.gen_range(0..self.values));
+                (
+                    "cas",
+                    json!([key, [from, to]]),
+                    json!({"type": "cas", "key": key, "from": from, "to": to}),
+                )
+            }
+        };
+        Op {
+            f: f.to_string(),
+            value,
+            body,
+        }
+    }
+
+    fn ok(&self, op: &Op, reply: &Value) -> Value {
+        match op.f.as_str() {
+            "read" => json!([op.value[0], reply["value"]]),
+            _ => op.value.clone(),
+        }
+    }
+}
+
+/// Checks histories for linearizability: whether every operation can be taken to have
+/// happened at some instant between its invocation and its completion, so that in that
+/// order they're all what a single copy of the data would have done.
+///
+/// The search is Wing and Gong's, with Lowe's cache: walk the history in order, linearize
+/// the first operation the model agrees with, start over from the top, and back out the
+/// latest one linearized whenever a completion comes up whose operation isn't yet. A set
+/// of linearized operations that's already been tried with the same state is never tried
+/// again. Operations whose outcome is unknown may be linearized anywhere after they were
+/// invoked, or not at all; failed ones are left out entirely.
+pub mod linearizability {
+    use super::{Event, History, Kind};
+    use serde_json::Value;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::hash::Hash;
+
+    /// An operation, from its invocation to its completion.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Operation {
+        pub process: u64,
+        pub f: String,
+        /// What it completed with, or what it was invoked with if it's not known to have.
+        pub value: Value,
+        /// Whether it's known to have completed; if not, it may or may not have happened.
+        pub completed: bool,
+        call: usize, // Indexes into the history of its invocation
+        ret: usize,  // and completion, the end of the history if it didn't
+    }
+
+    /// A model of what's being checked, as a state operations step from one to the next.
+    pub trait Model {
+        type State: Clone + Eq + Hash;
+
+        fn init(&self) -> Self::State;
+
+        /// The state after `op`, if it could have given the value it did from `state`.
+        fn step(&self, state: &Self::State, op: &Operation) -> Option<Self::State>;
+    }
+
+    /// A register read and written as Knossos has it, with `[key, value]` for reads and
+    /// writes and `[key, [from, to]]` for compare-and-sets. Keys are all the same to it,
+    /// so histories of more than one key want splitting up first. Its state is the value's
+    /// JSON, since JSON values can't be hashed.
+    pub struct Register;
+
+    impl Model for Register {
+        type State = String;
+
+        fn init(&self) -> String {
+            Value::Null.to_string()
+        }
+
+        fn step(&self, state: &String, op: &Operation) -> Option<String> {
+            match op.f.as_str() {
+                "read" if !op.completed => Some(state.clone()),
+                "read" => (json(&op.value[1]) == *state).then(|| state.clone()),
+                "write" => Some(op.value[1].to_string()),
+                "cas" => (json(&op.value[1][0]) == *state).then(|| json(&op.value[1][1])),
+                _ => None,
+            }
+        }
+    }
+
+    /// A value as `Register` keeps it.
+    fn json(value: &Value) -> String {
+        value.to_string()
+    }
+
+    /// Pairs each invocation with its completion, leaving out operations that failed.
+    pub fn operations(events: &[Event]) -> Vec<Operation> {
+        let mut invoked: HashMap<u64, Operation> = HashMap::new();
+        let mut operations = Vec::new();
+        for (index, event) in events.iter().enumerate() {
+            if event.kind == Kind::Invoke {
+                let op = Operation {
+                    process: event.process,
+                    f: event.f.clone(),
+                    value: event.value.clone(),
+                    completed: false,
+                    call: index,
+                    ret: events.len(),
+                };
+                invoked.insert(event.process, op);
+                continue;
+            }
+            let Some(mut op) = invoked.remove(&event.process) else {
+                continue;
+            };
+            match event.kind {
+                Kind::Ok => {
+                    op.value = event.value.clone();
+                    op.completed = true;
+                    op.ret = index;
+                    operations.push(op);
+                }
+                Kind::Info => operations.push(op),
+                _ => {}
+            }
+        }
+        // Still going when the history ended, so there's no telling
+        operations.extend(invoked.into_values());
+        operations.sort_by_key(|op| op.call);
+        operations
+    }
+
+    /// Whether `ops` are linearizable under `model`.
+    pub fn is_linearizable<M: Model>(model: &M, ops: &[Operation]) -> bool {
+        // The invocations and completions, in order, as a linked list with a head at the
+        // end, so operations can be lifted out of it and put back
+        let mut entries: Vec<(usize, bool)> = Vec::new();
+        for (i, op) in ops.iter().enumerate() {
+            entries.push((i, true));
+            if op.completed {
+                entries.push((i, false));
+            }
+        }
+        entries.sort_by_key(|(i, call)| if *call { ops[*i].call } else { ops[*i].ret });
+        if entries.is_empty() {
+            return true;
+        }
+        let (head, none) = (entries.len(), usize::MAX);
+        let mut next: Vec<usize> = (1..entries.len()).chain([none, 0]).collect();
+        let mut prev: Vec<usize> = [head].into_iter().chain(0..entries.len() - 1).collect();
+        let mut positions: Vec<Vec<usize>> = vec![Vec::new(); ops.len()];
+        for (position, (i, _)) in entries.iter().enumerate() {
+            positions[*i].push(position);
+        }
+
+        let unlink = |next: &mut Vec<usize>, prev: &mut Vec<usize>, x: usize| {
+            next[prev[x]] = next[x];
+            if next[x] != none {
+                prev[next[x]] = prev[x];
+            }
+        };
+        let relink = |next: &mut Vec<usize>, prev: &mut Vec<usize>, x: usize| {
+            next[prev[x]] = x;
+            if next[x] != none {
+                prev[next[x]] = x;
+            }
+        };
+
+        let mut state = model.init();
+        let mut linearized = vec![0u64; ops.len().div_ceil(64)];
+        let mut tried: HashSet<(Vec<u64>, M::State)> = HashSet::new();
+        let mut stack: Vec<(usize, M::State)> = Vec::new();
+        let mut entry = next[head];
+        // Running off the end means every completion left has been linearized
+        while entry != none {
+            let (i, call) = entries[entry];
+            if !call {
+                // An operation that's completed, but that we couldn't linearize before
+                let Some((i, before)) = stack.pop() else {
+                    return false;
+                };
+                linearized[i / 64] &= !(1 << (i % 64));
+                state = before;
+                for position in positions[i].iter().rev() {
+                    relink(&mut next, &mut prev, *position);
+                }
+                entry = next[positions[i][0]];
+                continue;
+            }
+            if let Some(after) = model.step(&state, &ops[i]) {
+                linearized[i / 64] |= 1 << (i % 64);
+                if tried.insert((linearized.clone(), after.clone())) {
+                    stack.push((i, std::mem::replace(&mut state, after)));
+                    for position in &positions[i] {
+                        unlink(&mut next, &mut prev, *position);
+                    }
+                    entry = next[head];
+                    continue;
+                }
+                linearized[i / 64] &= !(1 << (i % 64));
+            }
+            entry = next[entry];
+        }
+        true
+    }
+
+    /// Checks a history of reads, writes and compare-and-sets of many keys, each its own
+    /// `Register`, returning the first key whose operations aren't linearizable.
+    pub fn check_kv(history: &History) -> Result<(), Value> {
+        let mut keys: BTreeMap<String, (Value, Vec<Operation>)> = BTreeMap::new();
+        for op in operations(history.events()) {
+            let key = op.value[0].clone();
+            keys.entry(key.to_string())
+                .or_insert((key, Vec::new()))
+                .1
+                .push(op);
+        }
+        for (key, ops) in keys.into_values() {
+            if !is_linearizable(&Register, &ops) {
+                return Err(key);
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Replaying one node's side of a recorded Maelstrom run: what it was sent, to feed to a
+/// rebuilt node, and what it sent back, to check the rebuilt node against.
+pub mod replay {
+    use serde_json::{Map, Value};
+    use std::collections::HashMap;
+    use std::io::{self, BufRead};
+
+    /// A message to or from the node being replayed.
+    #[derive(Debug, Clone, PartialEq)]
+    pub enum Step {
+        Input(Value),
+        Output(Value),
+    }
+
+    /// Reads the messages sent during a run, in the order they were sent. A line is either a
+    /// message as JSON, or a log line with `:send` followed by a message as EDN, which is how
+    /// Maelstrom logs them to `jepsen.log` with `--log-net-send`. Anything else is skipped.
+    pub fn read_messages(reader: impl BufRead) -> io::Result<Vec<Value>> {
+        let mut messages = Vec::new();
+        for line in reader.lines() {
+            let line = line?;
+            let message = if line.trim_start().starts_with('{') {
+                serde_json::from_str(&line).ok()
+            } else {
+                line.split_once(":send ")
+                    .and_then(|(_, edn)| parse_edn(edn).ok())
+                    .map(|(message, _)| message)
+            };
+            let Some(message) = message else {
+                continue;
+            };
+            if message["src"].is_string() && message["dest"].is_string() {
+                // Maelstrom adds its own fields, like an id; the node only sees these
+                messages.push(serde_json::json!({
+                    "src": message["src"],
+                    "dest": message["dest"],
+                    "body": message["body"],
+                }));
+            }
+        }
+        Ok(messages)
+    }
+
+    /// `node`'s side of `messages`.
+    pub fn steps(messages: &[Value], node: &str) -> Vec<Step> {
+        messages
+            .iter()
+            .filter_map(|message| {
+                if message["dest"] == node {
+                    Some(Step::Input(message.clone()))
+                } else if message["src"] == node {
+                    Some(Step::Output(message.clone()))
+                } else {
+                    None
+                }
+            })
+            .collect()
+    }
+
+    /// Matches what a replayed node sends against what it sent in the recording. Its own
+    /// message ids needn't match the recording's, so they're left out of the comparison, and
+    /// replies to it are rewritten to the ids it actually used.
+    #[derive(Debug, Default)]
+    pub struct Matcher {
+        ids: HashMap<u64, u64>, // Recorded msg_id to the replayed one
+        unmatched: Vec<Value>,  // Sent by the replayed node, and not yet expected
+    }
+
+    impl Matcher {
+        /// An input as it should be sent to the replayed node.
+        pub fn input(&self, mut message: Value) -> Value {
+            let body = &mut message["body"];
+            if let Some(id) = body["in_reply_to"]
+                .as_u64()
+                .and_then(|id| self.ids.get(&id))
+            {
+                body["in_reply_to"] = (*id).into();
+            }
+            message
+        }
+
+        /// Notes a message the replayed node sent.
+        pub fn sent(&mut self, message: Value) {
+            self.unmatched.push(message);
+        }
+
+        /// Looks for `expected` among what the replayed node has sent, taking it if it's there.
+        pub fn expect(&mut self, expected: &Value) -> bool {
+            let Some(i) = self
+                .unmatched
+                .iter()
+                .position(|sent| without_id(sent) == without_id(expected))
+            else {
+                return false;
+            };
+            let sent = self.unmatched.remove(i);
+            if let (Some(recorded), Some(replayed)) = (
+                expected["body"]["msg_id"].as_u64(),
+                sent["body"]["msg_id"].as_u64(),
+            ) {
+                self.ids.insert(recorded, replayed);
+            }
+            true
+        }
+
+        /// What the replayed node sent that the recording didn't have.
+        pub fn unexpected(self) -> Vec<Value> {
+            self.unmatched
+        }
+    }
+
+    fn without_id(message: &Value) -> Value {
+        let mut message = message.clone();
+        if let Some(body) = message["body"].as_object_mut() {
+            body.remove("msg_id");
+        }
+        message
+    }
+
+    /// Parses the EDN value at the start of `input` into JSON, returning it and whatever
+    /// follows it. Keywords become strings, as do map keys that aren't already, and lists and
+    /// sets become arrays. Tags, like the record name Maelstrom prints messages with, are
+    /// dropped.
+    pub fn parse_edn(input: &str) -> Result<(Value, &str), String> {
+        let input = skip_space(input);
+        let mut chars = input.chars();
+        match chars.next() {
+            None => Err("unexpected end of input".to_string()),
+            Some('{') => {
+                let mut map = Map::new();
+                let mut rest = &input[1..];
+                loop {
+                    rest = skip_space(rest);
+                    if let Some(rest) = rest.strip_prefix('}') {
+                        return Ok((Value::Object(map), rest));
+                    }
+                    let (key, after_key) = parse_edn(rest)?;
+                    let (value, after_value) = parse_edn(after_key)?;
+                    let key = match key {
+                        Value::String(key) => key,
+                        key => key.to_string(),
+                    };
+                    map.insert(key, value);
+                    rest = after_value;
+                }
+            }
+            Some('[') => parse_seq(&input[1..], ']'),
+            Some('(') => parse_seq(&input[1..], ')'),
+            Some('#') if chars.next() == Some('{') => parse_seq(&input[2..], '}'),
+            Some('#') => {
+                let tag_end = input
+                    .find(|c: char| c.is_whitespace() || "{[(\"".contains(c))
+                    .ok_or("tag without a value")?;
+                parse_edn(&input[tag_end..])
+            }
+            Some('"') => parse_string(&input[1..]),
+            Some(_) => {
+                let end = input
+                    .find(|c: char| c.is_whitespace() || ",{}[]()\"".contains(c))
+                    .unwrap_or(input.len());
+                let (atom, rest) = input.split_at(end);
+                let value = match atom {
+                    "nil" => Value::Null,
+                    "true" => Value::Bool(true),
+                    "false" => Value::Bool(false),
+                    _ => match atom.strip_prefix(':') {
+                        Some(keyword) => Value::String(keyword.to_string()),
+                        None => number(atom.trim_end_matches(['N', 'M']))
+                            .unwrap_or_else(|| Value::String(atom.to_string())),
+                    },
+                };
+                Ok((value, rest))
+            }
+        }
+    }
+
+    fn skip_space(input: &str) -> &str {
+        input.trim_start_matches(|c: char| c.is_whitespace() || c == ',')
+    }
+
+    fn parse_seq(mut rest: &str, close: char) -> Result<(Value, &str), String> {
+        let mut values = Vec::new();
+        loop {
+            rest = skip_space(rest);
+            if let Some(rest) = rest.strip_prefix(close) {
+                return Ok((Value::Array(values), rest));
+            }
+            let (value, after) = parse_edn(rest)?;
+            values.push(value);
+            rest = after;
+        }
+    }
+
+    /// Parses a string whose opening quote has been consumed.
+    fn parse_string(input: &str) -> Result<(Value, &str), String> {
+        let mut string = String::new();
+        let mut chars = input.char_indices();
+        while let Some((i, c)) = chars.next() {
+            match c {
+                '"' => return Ok((Value::String(string), &input[i + 1..])),
+                '\\' => match chars.next() {
+                    Some((_, 'n')) => string.push('\n'),
+                    Some((_, 't')) => string.push('\t'),
+                    Some((_, 'r')) => string.push('\r'),
+                    Some((_, c)) => string.push(c),
+                    None => break,
+                },
+                c => string.push(c),
+            }
+        }
+        Err("unterminated string".to_string())
+    }
+
+    fn number(atom: &str) -> Option<Value> {
+        if let Ok(n) = atom.parse::<i64>() {
+            return Some(n.into());
+        }
+        if let Ok(n) = atom.parse::<u64>() {
+            return Some(n.into());
+        }
+        atom.parse::<f64>().ok().map(Value::from)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use rand::SeedableRng;
+
+    #[test]
+    fn test_history_round_trips() {
+        let mut history = History::default();
+        history.record(0, Kind::Invoke, "write", json!([1, 2]));
+        history.record(0, Kind::Ok, "write", json!([1, 2]));
+        let mut written = Vec::new();
+        history.write_to(&mut written).unwrap();
+        let line = String::from_utf8(written.clone()).unwrap();
+        assert!(line.starts_with(r#"{"index":0,"time":"#));
+        assert!(line.contains(r#""type":"invoke","f":"write","value":[1,2]}"#));
+
+        let read = History::read_from(&written[..]).unwrap();
+        assert_eq!(read.events(), history.events());
+    }
+
+    #[test]
+    fn test_lin_kv_completions() {
+        let mut workload = LinKv { keys: 1, values: 3 };
+        let mut rng = StdRng::seed_from_u64(7);
+        let ops: Vec<Op> = (0..20).map(|_| workload.invoke(&mut rng)).collect();
+        let read = ops.iter().find(|op| op.f == "read").unwrap();
+        let cas = ops.iter().find(|op| op.f == "cas").unwrap();
+
+        let read_ok = json!({"type": "read_ok", "value": 2});
+        assert_eq!(workload.complete(read, &read_ok), (Kind::Ok, json!([0, 2])));
+        let precondition_failed = json!({"type": "error", "code": 22});
+        assert_eq!(
+            workload.complete(cas, &precondition_failed),
+            (Kind::Fail, cas.value.clone())
+        );
+        let timeout = json!({"type": "error", "code": 0});
+        assert_eq!(
+            workload.complete(cas, &timeout),
+            (Kind::Info, cas.value.clone())
+        );
+    }
+
+    fn history(events: &[(u64, Kind, &str, Value)]) -> History {
+        let mut history = History::default();
+        for (process, kind, f, value) in events {
+            history.record(*process, *kind, f, value.clone());
+        }
+        history
+    }
+
+    #[test]
+    fn test_concurrent_operations_linearize() {
+        // The read overlaps both writes, so it may see either
+        let history = history(&[
+            (0, Kind::Invoke, "write", json!([1, 1])),
+            (1, Kind::Invoke, "read", json!([1, null])),
+            (0, Kind::Ok, "write", json!([1, 1])),
+            (0, Kind::Invoke, "write", json!([1, 2])),
+            (0, Kind::Ok, "write", json!([1, 2])),
+            (1, Kind::Ok, "read", json!([1, 1])),
+            // Its outcome's unknown, but the cas must have happened for the read after it
+            (2, Kind::Invoke, "cas", json!([1, [2, 3]])),
+            (2, Kind::Info, "cas", json!([1, [2, 3]])),
+            (0, Kind::Invoke, "read", json!([1, null])),
+            (0, Kind::Ok, "read", json!([1, 3])),
+            // Failed, so it never happened
+            (1, Kind::Invoke, "write", json!([1, 4])),
+            (1, Kind::Fail, "write", json!([1, 4])),
+        ]);
+        assert_eq!(linearizability::check_kv(&history), Ok(()));
+    }
+
+    #[test]
+    fn test_stale_read_is_caught() {
+        let history = history(&[
+            (0, Kind::Invoke, "write", json!([1, 1])),
+            (0, Kind::Ok, "write", json!([1, 1])),
+            (1, Kind::Invoke, "write", json!([2, 5])),
+            (1, Kind::Ok, "write", json!([2, 5])),
+            (0, Kind::Invoke, "write", json!([1, 2])),
+            (0, Kind::Ok, "write", json!([1, 2])),
+            (1, Kind::Invoke, "read", json!([1, null])),
+            (1, Kind::Ok, "read", json!([1, 1])),
+        ]);
+        assert_eq!(linearizability::check_kv(&history), Err(json!(1)));
+    }
+
+    #[test]
+    fn test_history_as_edn() {
+        let mut history = History::default();
+        history.record(3, Kind::Invoke, "read", json!([1, null]));
+        history.record(3, Kind::Ok, "read", json!([1, "a \"b\""]));
+        history.record(
+            4,
+            Kind::Info,
+            "txn",
+            json!([["append", 1, 2], ["r", 1, [2]]]),
+        );
+        let mut written = Vec::new();
+        history.write_edn(&mut written).unwrap();
+        let lines: Vec<String> = String::from_utf8(written)
+            .unwrap()
+            .lines()
+            .map(|line| line.split_once(", :process").unwrap().1.to_string())
+            .collect();
+        assert_eq!(
+            lines,
+            vec![
+                r#" 3, :type :invoke, :f :read, :value [1 nil]}"#,
+                r#" 3, :type :ok, :f :read, :value [1 "a \"b\""]}"#,
+                r#" 4, :type :info, :f :txn, :value [[:append 1 2] [:r 1 [2]]]}"#,
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replay_reads_maelstrom_log() {
+        let log = "\
+INFO [2024-09-01 12:00:00,000] jepsen worker 0 - jepsen.maelstrom.net :send #jepsen.maelstrom.net.message.Message{:id 4, :src \"c1\", :dest \"n1\", :body {:type \"echo\", :echo \"a \\\"b\\\"\", :msg_id 1}}
+INFO [2024-09-01 12:00:00,001] jepsen worker 0 - jepsen.maelstrom.net :recv {:id 4}
+{\"src\":\"n1\",\"dest\":\"c1\",\"body\":{\"type\":\"echo_ok\",\"echo\":\"a \\\"b\\\"\",\"in_reply_to\":1,\"msg_id\":7}}
+INFO [2024-09-01 12:00:00,002] jepsen worker 0 - jepsen.maelstrom.net :send {:id 6, :src \"c2\", :dest \"n2\", :body {:type \"read\", :keys #{1}, :msg_id 1}}";
+        let messages = replay::read_messages(log.as_bytes()).unwrap();
+        assert_eq!(
+            messages,
+            vec![
+                json!({"src": "c1", "dest": "n1", "body": {"type": "echo", "echo": "a \"b\"", "msg_id": 1}}),
+                json!({"src": "n1", "dest": "c1", "body": {"type": "echo_ok", "echo": "a \"b\"", "in_reply_to": 1, "msg_id": 7}}),
+                json!({"src": "c2", "dest": "n2", "body": {"type": "read", "keys": [1], "msg_id": 1}}),
+            ]
+        );
+        assert_eq!(
+            replay::steps(&messages, "n1"),
+            vec![
+                replay::Step::Input(messages[0].clone()),
+                replay::Step::Output(messages[1].clone()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_replay_matches_on_replayed_ids() {
+        let mut matcher = replay::Matcher::default();
+        let recorded =
+            json!({"src": "n1", "dest": "lin-kv", "body": {"type": "read", "key": 1, "msg_id": 3}});
+        assert!(!matcher.expect(&recorded));
+        matcher.sent(
+            json!({"src": "n1", "dest": "lin-kv", "body": {"type": "read", "key": 1, "msg_id": 9}}),
+        );
+        matcher.sent(
+            json!({"src": "n1", "dest": "c1", "body": {"type": "read_ok", "in_reply_to": 1}}),
+        );
+        assert!(matcher.expect(&recorded));
+
+        let reply =
+            json!({"src": "lin-kv", "dest": "n1", "body": {"type": "read_ok", "in_reply_to": 3}});
+        assert_eq!(matcher.input(reply)["body"]["in_reply_to"], 9);
+        assert_eq!(
+            matcher.unexpected(),
+            vec![json!({"src": "n1", "dest": "c1", "body": {"type": "read_ok", "in_reply_to": 1}})]
+        );
+    }
+}
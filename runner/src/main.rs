@@ -0,0 +1,385 @@
+use rand::rngs::StdRng;
+use rand::{Rng, SeedableRng};
+use runner::{History, Kind, Op, Workload};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufWriter};
+use std::path::PathBuf;
+use std::process::Stdio;
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncBufReadExt, AsyncWriteExt, BufReader};
+use tokio::process::{Child, ChildStdin, Command};
+use tokio::sync::mpsc;
+
+const USAGE: &str = "\
+Usage: runner --bin <path> [options]
+
+Runs a cluster of node processes, routing their messages between them, has clients run a
+workload against it, and records what the clients saw.
+
+Options:
+  --bin <path>          The node binary to run
+  --nodes <n>           How many nodes to run [default: 3]
+  --workload <name>     echo, g-counter or lin-kv [default: lin-kv]
+  --time-limit <secs>   How long to run the workload for [default: 10]
+  --rate <ops>          Operations invoked per second, across all clients [default: 10]
+  --concurrency <n>     How many clients to run [default: 2]
+  --timeout-ms <ms>     How long an operation waits on a reply [default: 1000]
+  --seed <n>            Seeds the workload and which node each client talks to
+  --history <path>      Where to write the history [default: history.jsonl]
+  --format <format>     json, one event per line as elle-cli reads, or edn, as Jepsen
+                        writes history.edn for Knossos and Elle [default: json]";
+
+/// Options, from the command line.
+struct Config {
+    bin: PathBuf,
+    nodes: usize,
+    workload: String,
+    time_limit: Duration,
+    rate: f64,
+    concurrency: usize,
+    timeout: Duration,
+    seed: u64,
+    history: PathBuf,
+    edn: bool,
+}
+
+impl Config {
+    fn from_args(mut args: impl Iterator<Item = String>) -> Result<Config, String> {
+        let mut config = Config {
+            bin: PathBuf::new(),
+            nodes: 3,
+            workload: "lin-kv".to_string(),
+            time_limit: Duration::from_secs(10),
+            rate: 10.0,
+            concurrency: 2,
+            timeout: Duration::from_millis(1000),
+            seed: rand::random(),
+            history: PathBuf::from("history.jsonl"),
+            edn: false,
+        };
+        while let Some(flag) = args.next() {
+            let value = args.next().ok_or(format!("{} needs a value", flag))?;
+            let invalid = || format!("invalid value {:?} for {}", value, flag);
+            match flag.as_str() {
+                "--bin" => config.bin = PathBuf::from(&value),
+                "--nodes" => config.nodes = value.parse().map_err(|_| invalid())?,
+                "--workload" => config.workload = value,
+                "--time-limit" => {
+                    config.time_limit = Duration::from_secs(value.parse().map_err(|_| invalid())?)
+                }
+                "--rate" => config.rate = value.parse().map_err(|_| invalid())?,
+                "--concurrency" => config.concurrency = value.parse().map_err(|_| invalid())?,
+                "--timeout-ms" => {
+                    config.timeout = Duration::from_millis(value.parse().map_err(|_| invalid())?)
+                }
+                "--seed" => config.seed = value.parse().map_err(|_| invalid())?,
+                "--history" => config.history = PathBuf::from(&value),
+                "--format" => {
+                    config.edn = match value.as_str() {
+                        "json" => false,
+                        "edn" => true,
+                        _ => return Err(invalid()),
+                    }
+                }
+                _ => return Err(format!("unknown option {}", flag)),
+            }
+        }
+        if config.bin.as_os_str().is_empty() {
+            return Err("--bin is required".to_string());
+        }
+        if config.nodes == 0 || config.concurrency == 0 || config.rate <= 0.0 {
+            return Err("--nodes, --concurrency and --rate must be positive".to_string());
+        }
+        Ok(config)
+    }
+}
+
+/// The node processes, and a way to write to each.
+struct Cluster {
+    node_ids: Vec<String>,
+    stdins: HashMap<String, ChildStdin>, // Dropped once writing to it fails
+    _children: Vec<Child>,               // Killed when the cluster's dropped
+}
+
+impl Cluster {
+    /// Starts the nodes, each writing every message it sends, parsed, to the receiver.
+    fn spawn(config: &Config) -> io::Result<(Cluster, mpsc::UnboundedReceiver<Value>)> {
+        let (output, received) = mpsc::unbounded_channel();
+        let node_ids: Vec<String> = (1..=config.nodes).map(|i| format!("n{}", i)).collect();
+        let mut stdins = HashMap::new();
+        let mut children = Vec::new();
+        for id in &node_ids {
+            let mut child = Command::new(&config.bin)
+                .stdin(Stdio::piped())
+                .stdout(Stdio::piped())
+                .stderr(Stdio::inherit())
+                .kill_on_drop(true)
+                .spawn()?;
+            let (Some(stdin), Some(stdout)) = (child.stdin.take(), child.stdout.take()) else {
+                unreachable!("stdin and stdout are piped");
+            };
+            stdins.insert(id.clone(), stdin);
+            children.push(child);
+
+            let (id, output) = (id.clone(), output.clone());
+            tokio::spawn(async move {
+                let mut lines = BufReader::new(stdout).lines();
+                while let Ok(Some(line)) = lines.next_line().await {
+                    match serde_json::from_str(&line) {
+                        Ok(message) => {
+                            if output.send(message).is_err() {
+                                return;
+                            }
+                        }
+                        Err(e) => log::warn!("Unable to parse from {}: {}", id, e),
+                    }
+                }
+                log::warn!("{} closed its stdout", id);
+            });
+        }
+        let cluster = Cluster {
+            node_ids,
+            stdins,
+            _children: children,
+        };
+        Ok((cluster, received))
+    }
+
+    /// Writes `message` to the node it's for.
+    async fn send(&mut self, message: &Value) {
+        let Some(dest) = message["dest"].as_str() else {
+            return;
+        };
+        let Some(stdin) = self.stdins.get_mut(dest) else {
+            log::debug!("Dropping message to {}: {}", dest, message);
+            return;
+        };
+        let mut line = message.to_string();
+        line.push('\n');
+        if let Err(e) = stdin.write_all(line.as_bytes()).await {
+            log::warn!(
+                "Unable to write to {}, so it won't get anything more: {}",
+                dest,
+                e
+            );
+            self.stdins.remove(dest);
+        }
+    }
+
+    /// Sends every node its init, and waits for them all to answer, passing on any
+    /// messages they send each other meanwhile.
+    async fn init(
+        &mut self,
+        received: &mut mpsc::UnboundedReceiver<Value>,
+        timeout: Duration,
+    ) -> Result<(), String> {
+        for (msg_id, id) in self.node_ids.clone().iter().enumerate() {
+            let init = json!({
+                "src": "c0",
+                "dest": id,
+                "body": {"type": "init", "msg_id": msg_id, "node_id": id, "node_ids": self.node_ids},
+            });
+            self.send(&init).await;
+        }
+        let deadline = tokio::time::Instant::now() + timeout;
+        let mut waiting = self.node_ids.len();
+        while waiting > 0 {
+            let message = tokio::time::timeout_at(deadline, received.recv())
+                .await
+                .map_err(|_| format!("{} nodes never answered their init", waiting))?
+                .ok_or("every node exited before answering its init")?;
+            if message["body"]["type"] == "init_ok" {
+                waiting -= 1;
+            } else if message["dest"] != "c0" {
+                self.send(&message).await;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A client, talking to one node, one operation at a time.
+struct Client {
+    id: String,
+    node: String,
+    process: u64, // Changes once an operation's outcome is unknown, since it may still happen
+    pending: Option<Pending>,
+}
+
+struct Pending {
+    msg_id: u64,
+    op: Op,
+    deadline: Instant,
+}
+
+/// The clients, running a workload against the cluster and recording its history.
+struct Clients {
+    clients: Vec<Client>,
+    workload: Box<dyn Workload>,
+    history: History,
+    rng: StdRng,
+    next_msg_id: u64,
+    timeout: Duration,
+}
+
+impl Clients {
+    /// Invokes an operation from an idle client, if there is one, returning the request.
+    fn invoke(&mut self) -> Option<Value> {
+        let idle: Vec<usize> = (0..self.clients.len())
+            .filter(|i| self.clients[*i].pending.is_none())
+            .collect();
+        if idle.is_empty() {
+            return None;
+        }
+        let client = &mut self.clients[idle[self.rng.gen_range(0..idle.len())]];
+        let op = self.workload.invoke(&mut self.rng);
+        let msg_id = self.next_msg_id;
+        self.next_msg_id += 1;
+        let mut body = op.body.clone();
+        body["msg_id"] = json!(msg_id);
+        let request = json!({"src": client.id, "dest": client.node, "body": body});
+        self.history
+            .record(client.process, Kind::Invoke, &op.f, op.value.clone());
+        client.pending = Some(Pending {
+            msg_id,
+            op,
+            deadline: Instant::now() + self.timeout,
+        });
+        Some(request)
+    }
+
+    /// Completes the operation `message` replies to.
+    fn receive(&mut self, message: &Value) {
+        let body = &message["body"];
+        let processes = self.clients.len() as u64;
+        let client = self.clients.iter_mut().find(|client| {
+            message["dest"] == client.id.as_str()
+                && (client.pending.as_ref()).is_some_and(|p| body["in_reply_to"] == p.msg_id)
+        });
+        let Some(client) = client else {
+            log::debug!("Ignoring unexpected message to a client: {}", message);
+            return;
+        };
+        let Some(pending) = client.pending.take() else {
+            return;
+        };
+        let (kind, value) = self.workload.complete(&pending.op, body);
+        self.history
+            .record(client.process, kind, &pending.op.f, value);
+        if kind == Kind::Info {
+            client.process += processes;
+        }
+    }
+
+    /// Gives up on the operations that have gone unanswered until `now`, with no telling
+    /// whether they happened.
+    fn time_out(&mut self, now: Instant) {
+        let processes = self.clients.len() as u64;
+        for client in &mut self.clients {
+            let Some(pending) = client.pending.take_if(|pending| pending.deadline <= now) else {
+                continue;
+            };
+            self.history
+                .record(client.process, Kind::Info, &pending.op.f, pending.op.value);
+            client.process += processes;
+        }
+    }
+}
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    simple_logger::SimpleLogger::new().env().init()?;
+    let config = match Config::from_args(std::env::args().skip(1)) {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}\n\n{}", e, USAGE);
+            std::process::exit(2);
+        }
+    };
+    let Some(workload) = runner::workload(&config.workload) else {
+        eprintln!("unknown workload {}\n\n{}", config.workload, USAGE);
+        std::process::exit(2);
+    };
+
+    let (mut cluster, mut received) = Cluster::spawn(&config)?;
+    cluster.init(&mut received, Duration::from_secs(10)).await?;
+    log::info!("Initialized {} nodes", config.nodes);
+
+    let mut rng = StdRng::seed_from_u64(config.seed);
+    let clients = (1..=config.concurrency)
+        .map(|i| Client {
+            id: format!("c{}", i),
+            node: cluster.node_ids[rng.gen_range(0..config.nodes)].clone(),
+            process: i as u64 - 1,
+            pending: None,
+        })
+        .collect();
+    let mut clients = Clients {
+        clients,
+        workload,
+        history: History::default(),
+        rng,
+        next_msg_id: 1,
+        timeout: config.timeout,
+    };
+
+    let end = Instant::now() + config.time_limit;
+    let between_ops = Duration::from_secs_f64(1.0 / config.rate);
+    let mut next_op = Instant::now();
+    let mut tick = tokio::time::interval(Duration::from_millis(5));
+    loop {
+        tokio::select! {
+            message = received.recv() => {
+                let Some(message) = message else {
+                    log::warn!("Every node has exited");
+                    break;
+                };
+                match message["dest"].as_str() {
+                    Some(dest) if dest.starts_with('n') => cluster.send(&message).await,
+                    _ => clients.receive(&message),
+                }
+            }
+            _ = tick.tick() => {
+                let now = Instant::now();
+                if now >= end {
+                    break;
+                }
+                clients.time_out(now);
+                while next_op <= now {
+                    let Some(request) = clients.invoke() else {
+                        // Every client's busy, so the operations due meanwhile are skipped
+                        next_op = now + between_ops;
+                        break;
+                    };
+                    cluster.send(&request).await;
+                    next_op += between_ops;
+                }
+            }
+        }
+    }
+    // Whatever's still pending may or may not have happened
+    clients.time_out(Instant::now() + config.timeout);
+
+    let mut counts: HashMap<Kind, usize> = HashMap::new();
+    for event in clients.history.events() {
+        *counts.entry(event.kind).or_default() += 1;
+    }
+    log::info!(
+        "Invoked {} operations: {} ok, {} failed, {} unknown",
+        counts.get(&Kind::Invoke).unwrap_or(&0),
+        counts.get(&Kind::Ok).unwrap_or(&0),
+        counts.get(&Kind::Fail).unwrap_or(&0),
+        counts.get(&Kind::Info).unwrap_or(&0),
+    );
+    let file = BufWriter::new(File::create(&config.history)?);
+    if config.edn {
+        clients.history.write_edn(file)?;
+    } else {
+        clients.history.write_to(file)?;
+    }
+    log::info!("Wrote the history to {}", config.history.display());
+    Ok(())
+}
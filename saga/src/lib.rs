@@ -0,0 +1,853 @@
+mod store {
+    use serde::de::DeserializeOwned;
+    use serde::Serialize;
+    use std::fs;
+    use std::io;
+    use std::io::Write;
+    use std::path::{Path, PathBuf};
+
+    /// A node's durable state, kept as one JSON file that's rewritten whole on every save.
+    pub struct Store {
+        path: PathBuf,
+    }
+
+    impl Store {
+        pub fn open(dir: &Path, node_id: &str) -> io::Result<Self> {
+            fs::create_dir_all(dir)?;
+            Ok(Store {
+                path: dir.join(format!("{}.saga.json", node_id)),
+            })
+        }
+
+        /// The last state saved, if anything ever was.
+        pub fn load<T: DeserializeOwned>(&self) -> io::Result<Option<T>> {
+            match fs::read(&self.path) {
+                Ok(bytes) => Ok(Some(serde_json::from_slice(&bytes)?)),
+                Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(None),
+                Err(e) => Err(e),
+            }
+        }
+
+        /// Writes to a temporary file and renames it over the last save, so a crash
+        /// mid-write leaves that intact.
+        pub fn save<T: Serialize>(&self, state: &T) -> io::Result<()> {
+            let tmp = self.path.with_extension("json.tmp");
+            let mut file = fs::File::create(&tmp)?;
+            serde_json::to_writer(&mut file, state)?;
+            file.flush()?;
+            file.sync_all()?;
+            fs::rename(tmp, &self.path)
+        }
+    }
+}
+
+pub mod node {
+    use super::store::Store;
+    use maelstrom::error::{ABORT, MALFORMED_REQUEST};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{BTreeMap, HashMap};
+    use std::path::PathBuf;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// Runs sagas: sequences of steps, each adjusting a counter at some node, that either
+    /// all take effect or are all undone. Any node coordinates the sagas clients send it,
+    /// and every node, coordinators included, is a participant holding counters.
+    ///
+    /// A coordinator runs a saga's steps one at a time, each at the node it names. A step
+    /// fails if it would take its counter below zero, and a step that goes unanswered for
+    /// `step_timeout` is taken to have failed, since it may never have arrived. Either way
+    /// the coordinator compensates, undoing the steps that may have taken effect, the
+    /// failed one included, newest first, and resends each compensation until it's
+    /// acknowledged. Other sagas can see a saga's steps before it finishes or is undone.
+    ///
+    /// Participants record the outcome of every step and compensation they're sent, so
+    /// resends are answered the same way and a step that arrives after its compensation is
+    /// refused rather than run. Those records are kept forever.
+    ///
+    /// With a `data_dir`, everything that has to survive a restart, sagas and counters
+    /// alike, is saved before any message that depends on it is sent, and a restarted node
+    /// picks up where it left off, resending whatever its in-flight sagas were waiting on.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        node_ids: Vec<String>,
+        durable: Durable,
+        store: Option<Store>,
+        timers: HashMap<String, Timers>, // For each saga we're coordinating
+    }
+
+    /// Everything a node saves.
+    #[derive(Serialize, Deserialize, Debug, Default)]
+    struct Durable {
+        next_saga: u64,
+        sagas: BTreeMap<String, Saga>, // The sagas we're coordinating, by id
+        counters: BTreeMap<String, i64>,
+        records: BTreeMap<String, Record>, // Every step we've been sent, by `step_id`
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Saga {
+        client: String,
+        client_msg_id: u64,
+        steps: Vec<Step>,
+        phase: Phase,
+    }
+
+    /// Adds `delta` to the counter `key` at `node`.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Step {
+        node: String,
+        key: String,
+        delta: i64,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    enum Phase {
+        /// Waiting on the outcome of the step at this index.
+        Executing(usize),
+        /// Undoing the steps before `remaining`, newest first, because of `reason`.
+        Compensating { remaining: usize, reason: String },
+    }
+
+    /// What a participant did with a step.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    enum Record {
+        Applied,
+        Refused,
+        Compensated,
+    }
+
+    /// When a saga's step gives up, and when we next resend what it's waiting on. Not
+    /// saved, so a restarted node gives each step a fresh timeout.
+    struct Timers {
+        deadline: Instant,
+        resend_at: Instant,
+    }
+
+    /// Tunables, read from `SAGA_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between ticks, which resend unanswered messages and time out steps.
+        pub tick_interval: Duration,
+        /// How long a step can go unanswered before the saga is compensated.
+        pub step_timeout: Duration,
+        /// How long to wait on an answer before resending a step or compensation.
+        pub resend_interval: Duration,
+        /// Where to save state across restarts; nowhere if unset.
+        pub data_dir: Option<PathBuf>,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                tick_interval: Duration::from_millis(100),
+                step_timeout: Duration::from_millis(1000),
+                resend_interval: Duration::from_millis(200),
+                data_dir: None,
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            let millis = |name, default: Duration| {
+                Duration::from_millis(env_or(name, default.as_millis() as u64))
+            };
+            Config {
+                tick_interval: millis("SAGA_TICK_INTERVAL_MS", default.tick_interval),
+                step_timeout: millis("SAGA_STEP_TIMEOUT_MS", default.step_timeout),
+                resend_interval: millis("SAGA_RESEND_INTERVAL_MS", default.resend_interval),
+                data_dir: std::env::var_os("SAGA_DATA_DIR").map(PathBuf::from),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// Names a saga's step uniquely across every coordinator.
+    fn step_id(saga: &str, step: usize) -> String {
+        format!("{}#{}", saga, step)
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Saga {
+            msg_id: u64,
+            steps: Vec<Step>,
+        },
+        /// Every step took effect.
+        SagaOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Reads a counter held here; one never stepped is 0.
+        Read {
+            msg_id: u64,
+            key: String,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: i64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        Execute {
+            msg_id: u64,
+            saga: String,
+            step: usize,
+            key: String,
+            delta: i64,
+        },
+        /// Whether the step took effect.
+        ExecuteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            saga: String,
+            step: usize,
+            applied: bool,
+        },
+        /// Undoes the step if it took effect, and makes sure it never does if it hasn't.
+        Compensate {
+            msg_id: u64,
+            saga: String,
+            step: usize,
+            key: String,
+            delta: i64,
+        },
+        CompensateOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            saga: String,
+            step: usize,
+        },
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                node_ids: Vec::new(),
+                durable: Durable::default(),
+                store: None,
+                timers: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "sagas": self.durable.sagas.len(),
+                "records": self.durable.records.len(),
+                "timers": self.timers.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut outbox = Vec::new();
+            self.respond(message, &mut outbox);
+            self.route(outbox)
+        }
+
+        /// Handles a message, queuing whatever it sends, its reply first.
+        fn respond(&mut self, message: Message, outbox: &mut Vec<Message>) {
+            let start = outbox.len();
+            if let Some(body) = self.handle_body(&message.src, message.body, outbox) {
+                outbox.insert(
+                    start,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+        }
+
+        /// Handles every message we've sent ourselves, since coordinators are participants
+        /// too, and returns the rest.
+        fn route(&mut self, mut outbox: Vec<Message>) -> Vec<Message> {
+            let mut sent = Vec::new();
+            while !outbox.is_empty() {
+                let mut more = Vec::new();
+                for message in outbox {
+                    if message.dest == self.id {
+                        self.respond(message, &mut more);
+                    } else {
+                        sent.push(message);
+                    }
+                }
+                outbox = more;
+            }
+            sent
+        }
+
+        /// Compensates sagas whose step has timed out, and resends whatever the rest have
+        /// waited on too long.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let now = Instant::now();
+            let mut outbox = Vec::new();
+            let sagas: Vec<(String, Phase)> = self
+                .durable
+                .sagas
+                .iter()
+                .map(|(id, saga)| (id.clone(), saga.phase.clone()))
+                .collect();
+            for (id, phase) in sagas {
+                let timers = self.timers.entry(id.clone()).or_insert_with(|| Timers {
+                    deadline: now + self.config.step_timeout,
+                    resend_at: now,
+                });
+                match phase {
+                    Phase::Executing(step) if timers.deadline <= now => {
+                        log::info!("Step {} of saga {} timed out, compensating", step, id);
+                        let reason = format!("step {} timed out", step);
+                        self.compensate(&id, step + 1, reason, &mut outbox);
+                    }
+                    _ if timers.resend_at <= now => self.send_current(&id, &mut outbox),
+                    _ => {}
+                }
+            }
+            self.route(outbox)
+        }
+
+        /// Saves our durable state, if we have somewhere to.
+        fn save(&self) {
+            let Some(store) = &self.store else {
+                return;
+            };
+            if let Err(e) = store.save(&self.durable) {
+                log::error!("Unable to save state: {}", e);
+            }
+        }
+
+        /// Loads whatever was saved before a restart.
+        fn recover(&mut self) {
+            let Some(dir) = &self.config.data_dir else {
+                return;
+            };
+            let store = match Store::open(dir, &self.id) {
+                Ok(store) => store,
+                Err(e) => {
+                    log::error!("Unable to open state in {:?}: {}", dir, e);
+                    return;
+                }
+            };
+            match store.load() {
+                Ok(Some(durable)) => {
+                    self.durable = durable;
+                    log::info!("Resuming {} sagas", self.durable.sagas.len());
+                }
+                Ok(None) => {}
+                Err(e) => log::error!("Unable to load state from {:?}: {}", dir, e),
+            }
+            self.store = Some(store);
+        }
+
+        /// Starts coordinating a client's saga, unless there's nothing to coordinate.
+        fn begin(
+            &mut self,
+            client: &str,
+            client_msg_id: u64,
+            steps: Vec<Step>,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            if let Some(step) = steps
+                .iter()
+                .find(|step| !self.node_ids.contains(&step.node))
+            {
+                return Some(Body::Error {
+                    in_reply_to: client_msg_id,
+                    code: MALFORMED_REQUEST,
+                    text: format!("no node {} to run a step at", step.node),
+                });
+            }
+            if steps.is_empty() {
+                return Some(Body::SagaOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: client_msg_id,
+                });
+            }
+            let id = format!("{}-{}", self.id, self.durable.next_saga);
+            self.durable.next_saga += 1;
+            let saga = Saga {
+                client: client.to_string(),
+                client_msg_id,
+                steps,
+                phase: Phase::Executing(0),
+            };
+            self.durable.sagas.insert(id.clone(), saga);
+            self.save();
+            self.arm(&id);
+            self.send_current(&id, outbox);
+            None
+        }
+
+        /// Restarts a saga's timers, due to send what it's waiting on straight away.
+        fn arm(&mut self, id: &str) {
+            let now = Instant::now();
+            let timers = Timers {
+                deadline: now + self.config.step_timeout,
+                resend_at: now,
+            };
+            self.timers.insert(id.to_string(), timers);
+        }
+
+        /// Sends the step or compensation a saga is waiting on.
+        fn send_current(&mut self, id: &str, outbox: &mut Vec<Message>) {
+            let Some(saga) = self.durable.sagas.get(id) else {
+                return;
+            };
+            let (step, execute) = match saga.phase {
+                Phase::Executing(step) => (step, true),
+                Phase::Compensating { remaining, .. } => (remaining - 1, false),
+            };
+            let Step { node, key, delta } = saga.steps[step].clone();
+            let msg_id = self.next_msg_id();
+            let saga = id.to_string();
+            let body = match execute {
+                true => Body::Execute {
+                    msg_id,
+                    saga,
+                    step,
+                    key,
+                    delta,
+                },
+                false => Body::Compensate {
+                    msg_id,
+                    saga,
+                    step,
+                    key,
+                    delta,
+                },
+            };
+            if let Some(timers) = self.timers.get_mut(id) {
+                timers.resend_at = Instant::now() + self.config.resend_interval;
+            }
+            outbox.push(Message {
+                src: self.id.clone(),
+                dest: node,
+                body,
+            });
+        }
+
+        /// Moves a saga on to the given phase, or finishes it once there's nothing left.
+        fn advance(&mut self, id: &str, phase: Phase, outbox: &mut Vec<Message>) {
+            let Some(saga) = self.durable.sagas.get_mut(id) else {
+                return;
+            };
+            let finished = match &phase {
+                Phase::Executing(step) => *step == saga.steps.len(),
+                Phase::Compensating { remaining, .. } => *remaining == 0,
+            };
+            if !finished {
+                saga.phase = phase;
+                self.save();
+                self.arm(id);
+                return self.send_current(id, outbox);
+            }
+            let saga = self.durable.sagas.remove(id).unwrap();
+            self.timers.remove(id);
+            self.save();
+            let body = match phase {
+                Phase::Executing(_) => Body::SagaOk {
+                    msg_id: self.next_msg_id(),
+                    in_reply_to: saga.client_msg_id,
+                },
+                Phase::Compensating { reason, .. } => Body::Error {
+                    in_reply_to: saga.client_msg_id,
+                    code: ABORT,
+                    text: format!("saga {} undone: {}", id, reason),
+                },
+            };
+            outbox.push(Message {
+                src: self.id.clone(),
+                dest: saga.client,
+                body,
+            });
+        }
+
+        /// Starts undoing the steps before `remaining`.
+        fn compensate(
+            &mut self,
+            id: &str,
+            remaining: usize,
+            reason: String,
+            outbox: &mut Vec<Message>,
+        ) {
+            let phase = Phase::Compensating { remaining, reason };
+            self.advance(id, phase, outbox);
+        }
+
+        /// Runs a step here, unless it's already been run or compensated.
+        fn execute(&mut self, saga: &str, step: usize, key: String, delta: i64) -> bool {
+            let step_id = step_id(saga, step);
+            if let Some(record) = self.durable.records.get(&step_id) {
+                return *record == Record::Applied;
+            }
+            let counter = self.durable.counters.entry(key).or_default();
+            let record = match counter.checked_add(delta) {
+                Some(value) if value >= 0 => {
+                    *counter = value;
+                    Record::Applied
+                }
+                _ => Record::Refused,
+            };
+            self.durable.records.insert(step_id, record);
+            self.save();
+            record == Record::Applied
+        }
+
+        fn undo(&mut self, saga: &str, step: usize, key: String, delta: i64) {
+            let step_id = step_id(saga, step);
+            let record = self.durable.records.get(&step_id).copied();
+            if record == Some(Record::Compensated) {
+                return;
+            }
+            if record == Some(Record::Applied) {
+                let counter = self.durable.counters.entry(key).or_default();
+                *counter = counter.saturating_sub(delta);
+            }
+            self.durable.records.insert(step_id, Record::Compensated);
+            self.save();
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.node_ids = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    self.recover();
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Saga { msg_id, steps } => self.begin(src, msg_id, steps, outbox),
+                Body::Read { msg_id, key } => Some(Body::ReadOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: msg_id,
+                    value: self.durable.counters.get(&key).copied().unwrap_or_default(),
+                }),
+                Body::Execute {
+                    msg_id,
+                    saga,
+                    step,
+                    key,
+                    delta,
+                } => {
+                    let applied = self.execute(&saga, step, key, delta);
+                    Some(Body::ExecuteOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        saga,
+                        step,
+                        applied,
+                    })
+                }
+                Body::ExecuteOk {
+                    saga: id,
+                    step,
+                    applied,
+                    ..
+                } => {
+                    let saga = self.durable.sagas.get(&id)?;
+                    if saga.phase != Phase::Executing(step) {
+                        return None;
+                    }
+                    match applied {
+                        true => self.advance(&id, Phase::Executing(step + 1), outbox),
+                        false => {
+                            let node = &saga.steps[step].node;
+                            let reason = format!("step {} refused by {}", step, node);
+                            // It didn't take effect, but it's cheap to make sure
+                            self.compensate(&id, step + 1, reason, outbox);
+                        }
+                    }
+                    None
+                }
+                Body::Compensate {
+                    msg_id,
+                    saga,
+                    step,
+                    key,
+                    delta,
+                } => {
+                    self.undo(&saga, step, key, delta);
+                    Some(Body::CompensateOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        saga,
+                        step,
+                    })
+                }
+                Body::CompensateOk { saga: id, step, .. } => {
+                    let saga = self.durable.sagas.get(&id)?;
+                    let Phase::Compensating { remaining, reason } = &saga.phase else {
+                        return None;
+                    };
+                    if *remaining != step + 1 {
+                        return None;
+                    }
+                    let reason = reason.clone();
+                    self.compensate(&id, step, reason, outbox);
+                    None
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::SagaOk { .. }
+                | Body::ReadOk { .. }
+                | Body::Error { .. } => None,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        fn init(id: &str, count: usize, config: Config) -> Node {
+            let ids: Vec<String> = (1..=count).map(|i| format!("n{}", i)).collect();
+            let mut node = Node::new(config);
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest: id.into(),
+                body: Body::Init {
+                    msg_id: 1,
+                    node_id: id.into(),
+                    node_ids: ids,
+                },
+            });
+            node
+        }
+
+        fn cluster(count: usize, config: Config) -> Vec<Node> {
+            (1..=count)
+                .map(|i| init(&format!("n{}", i), count, config.clone()))
+                .collect()
+        }
+
+        /// Delivers messages between nodes until none are left, returning what's sent to
+        /// clients.
+        fn deliver(nodes: &mut [Node], mut messages: Vec<Message>) -> Vec<serde_json::Value> {
+            let mut replies = Vec::new();
+            while !messages.is_empty() {
+                let message = messages.remove(0);
+                match nodes.iter_mut().find(|n| n.id == message.dest) {
+                    Some(node) => messages.extend(node.handle_message(message)),
+                    None => replies.push(serde_json::to_value(message.body).unwrap()),
+                }
+            }
+            replies
+        }
+
+        fn saga(to: &str, steps: serde_json::Value) -> Message {
+            let message = json!({
+                "src": "c1",
+                "dest": to,
+                "body": {"type": "saga", "msg_id": 7, "steps": steps},
+            });
+            serde_json::from_value(message).unwrap()
+        }
+
+        fn counter(node: &Node, key: &str) -> i64 {
+            node.durable.counters.get(key).copied().unwrap_or_default()
+        }
+
+        #[test]
+        fn test_saga_runs_every_step() {
+            let mut nodes = cluster(3, Config::default());
+            let steps = json!([
+                {"node": "n1", "key": "flight", "delta": 2},
+                {"node": "n2", "key": "hotel", "delta": 1},
+                {"node": "n3", "key": "car", "delta": 3},
+            ]);
+            let replies = deliver(&mut nodes, vec![saga("n1", steps)]);
+            assert_eq!(replies.len(), 1);
+            assert_eq!(replies[0]["type"], "saga_ok");
+            assert_eq!(replies[0]["in_reply_to"], 7);
+            assert_eq!(counter(&nodes[0], "flight"), 2);
+            assert_eq!(counter(&nodes[1], "hotel"), 1);
+            assert_eq!(counter(&nodes[2], "car"), 3);
+            assert!(nodes[0].durable.sagas.is_empty());
+        }
+
+        #[test]
+        fn test_failed_step_compensates_earlier_ones() {
+            let mut nodes = cluster(3, Config::default());
+            let stock = json!([{"node": "n2", "key": "hotel", "delta": 5}]);
+            deliver(&mut nodes, vec![saga("n2", stock)]);
+
+            let steps = json!([
+                {"node": "n1", "key": "flight", "delta": 4},
+                {"node": "n2", "key": "hotel", "delta": -2},
+                {"node": "n3", "key": "car", "delta": -1},
+            ]);
+            let replies = deliver(&mut nodes, vec![saga("n1", steps)]);
+            assert_eq!(replies[0]["code"], ABORT);
+            assert_eq!(counter(&nodes[0], "flight"), 0);
+            assert_eq!(counter(&nodes[1], "hotel"), 5);
+            assert_eq!(counter(&nodes[2], "car"), 0);
+        }
+
+        #[test]
+        fn test_timed_out_step_is_compensated_and_never_runs() {
+            let config = Config {
+                step_timeout: Duration::ZERO,
+                ..Config::default()
+            };
+            let mut nodes = cluster(2, config);
+            let steps = json!([
+                {"node": "n1", "key": "a", "delta": 1},
+                {"node": "n2", "key": "b", "delta": 1},
+            ]);
+            // The step sent to n2 is lost
+            let lost = nodes[0].handle_message(saga("n1", steps));
+            assert!(matches!(lost[0].body, Body::Execute { step: 1, .. }));
+
+            let messages = nodes[0].tick();
+            let replies = deliver(&mut nodes, messages);
+            assert_eq!(replies[0]["code"], ABORT);
+            assert_eq!(counter(&nodes[0], "a"), 0);
+
+            // The step turns up late, and is refused
+            let replies = deliver(&mut nodes, lost);
+            assert!(replies.is_empty());
+            assert_eq!(counter(&nodes[1], "b"), 0);
+        }
+
+        #[test]
+        fn test_resent_steps_run_once() {
+            let mut nodes = cluster(2, Config::default());
+            let execute = Message {
+                src: "n1".into(),
+                dest: "n2".into(),
+                body: Body::Execute {
+                    msg_id: 3,
+                    saga: "n1-0".into(),
+                    step: 0,
+                    key: "k".into(),
+                    delta: 3,
+                },
+            };
+            for _ in 0..3 {
+                let reply = nodes[1].handle_message(execute.clone());
+                assert!(matches!(
+                    reply[0].body,
+                    Body::ExecuteOk { applied: true, .. }
+                ));
+            }
+            assert_eq!(counter(&nodes[1], "k"), 3);
+        }
+
+        #[test]
+        fn test_restarted_coordinator_resumes_sagas() {
+            let dir = std::env::temp_dir().join(format!("saga-resume-{}", std::process::id()));
+            let config = Config {
+                data_dir: Some(dir.clone()),
+                ..Config::default()
+            };
+            let mut nodes = cluster(2, config.clone());
+            let steps = json!([
+                {"node": "n2", "key": "a", "delta": 1},
+                {"node": "n1", "key": "b", "delta": 1},
+            ]);
+            // n1 crashes having sent the first step
+            nodes[0].handle_message(saga("n1", steps));
+            nodes[0] = init("n1", 2, config);
+            assert_eq!(nodes[0].durable.sagas.len(), 1);
+
+            let messages = nodes[0].tick();
+            let replies = deliver(&mut nodes, messages);
+            assert_eq!(replies[0]["type"], "saga_ok");
+            assert_eq!(counter(&nodes[0], "b"), 1);
+            assert_eq!(counter(&nodes[1], "a"), 1);
+            std::fs::remove_dir_all(dir).unwrap();
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,845 @@
+pub mod node {
+    use maelstrom::error::{TEMPORARILY_UNAVAILABLE, TIMEOUT};
+    use raft::{Outbox, Raft, RaftMessage, Rejected, StateMachine};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{BTreeMap, HashMap};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+    /// A scheduler for delayed jobs: clients schedule a job to fire at a wall-clock time, and
+    /// once it's due, the client that scheduled it is sent a job_fired, once. Jobs go
+    /// through a Raft log, so every node has every job, and one that's been accepted is
+    /// fired even if the node that accepted it is gone.
+    ///
+    /// Which node fires a job is settled in the log too. The leader proposes a fire for
+    /// each due job, and the first fire committed for it wins; any later ones, say from a
+    /// leader that took over before the first was committed, change nothing. Only the node
+    /// that proposed the winning fire sends job_fired, and only once it's applied, so no
+    /// job is ever fired twice. A node that crashes between its fire being committed and
+    /// sending job_fired takes the message with it, since nothing is persisted, but the job
+    /// still reads as fired.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        node_ids: Vec<String>,
+        raft: Option<Raft<Schedule>>, // Set at init, once we know the cluster
+        forwards: HashMap<u64, Waiting>, // Requests sent to the leader, by msg_id
+        proposals: HashMap<u64, Proposed>, // Entries we put in the log, by index
+        firing: HashMap<u64, u64>,    // The term we proposed firing each job in, until it's fired
+    }
+
+    /// An entry in the log, waiting on it to be applied.
+    struct Proposed {
+        term: u64,                // The term it went in with, which the applied entry must have too
+        waiting: Option<Waiting>, // Nobody, for a fire
+    }
+
+    /// A client waiting on a reply to `msg_id`.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on answering
+    }
+
+    /// The jobs every node applies the log to. Fired jobs are kept, so reads can tell
+    /// which have fired.
+    #[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq)]
+    struct Schedule {
+        next_job: u64,
+        jobs: BTreeMap<u64, Job>,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Job {
+        client: String, // Who scheduled it, and gets the job_fired
+        fire_at: u64,
+        payload: Value,
+        fired_by: Option<String>,
+    }
+
+    /// An operation as it goes in the log.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "op")]
+    enum Op {
+        Schedule {
+            client: String,
+            fire_at: u64,
+            payload: Value,
+        },
+        /// Fires the job, unless it already has.
+        Fire {
+            job_id: u64,
+            by: String,
+        },
+        Read,
+    }
+
+    /// What applying an operation came to.
+    enum Outcome {
+        /// The reply to a client's request, with its ids still to fill in.
+        Reply(Body),
+        /// This fire won, so whoever proposed it sends the job_fired.
+        Fired {
+            job_id: u64,
+            client: String,
+            payload: Value,
+        },
+        /// A fire for a job that's already fired.
+        Unchanged,
+    }
+
+    /// Tunables, read from `SCHEDULER_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// How long a request waits on being committed, or on the leader to answer it if we
+        /// forwarded it, before we give up.
+        pub request_timeout: Duration,
+        /// Time between ticks, which drive Raft's timers, fire due jobs and time out
+        /// requests. Jobs fire up to this late.
+        pub tick_interval: Duration,
+        pub raft: raft::Config,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                request_timeout: Duration::from_millis(1000),
+                tick_interval: Duration::from_millis(50),
+                raft: raft::Config::default(),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            let millis = |name, default: Duration| {
+                Duration::from_millis(env_or(name, default.as_millis() as u64))
+            };
+            Config {
+                request_timeout: millis("SCHEDULER_REQUEST_TIMEOUT_MS", default.request_timeout),
+                tick_interval: millis("SCHEDULER_TICK_INTERVAL_MS", default.tick_interval),
+                raft: raft::Config {
+                    election_timeout: millis(
+                        "SCHEDULER_ELECTION_TIMEOUT_MS",
+                        default.raft.election_timeout,
+                    ),
+                    heartbeat_interval: millis(
+                        "SCHEDULER_HEARTBEAT_INTERVAL_MS",
+                        default.raft.heartbeat_interval,
+                    ),
+                    max_batch: env_or("SCHEDULER_MAX_BATCH", default.raft.max_batch),
+                    snapshot_threshold: env_or(
+                        "SCHEDULER_SNAPSHOT_THRESHOLD",
+                        default.raft.snapshot_threshold,
+                    ),
+                },
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// Milliseconds since the Unix epoch, by this machine's clock.
+    fn wall_clock() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_millis() as u64)
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Fires a job at `fire_at`, in milliseconds since the Unix epoch.
+        Schedule {
+            msg_id: u64,
+            fire_at: u64,
+            #[serde(default)]
+            payload: Value,
+            /// Who to send the job_fired to, when a node forwards this on a client's behalf.
+            #[serde(default, skip_serializing_if = "Option::is_none")]
+            client: Option<String>,
+        },
+        ScheduleOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            job_id: u64,
+        },
+        /// Sent to whoever scheduled the job, once it's fired.
+        JobFired {
+            msg_id: u64,
+            job_id: u64,
+            payload: Value,
+        },
+        Read {
+            msg_id: u64,
+        },
+        /// The jobs yet to fire, and the ones that have, with the node that fired each.
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            pending: Vec<u64>,
+            fired: BTreeMap<u64, String>,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        #[serde(untagged)]
+        Raft(RaftMessage<Op, Schedule>),
+    }
+
+    impl Body {
+        /// The id of the request this replies to, if it's a reply.
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::InitOk { in_reply_to, .. }
+                | Body::ScheduleOk { in_reply_to, .. }
+                | Body::ReadOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::Init { msg_id, .. }
+                | Body::InitOk { msg_id, .. }
+                | Body::Schedule { msg_id, .. }
+                | Body::ScheduleOk { msg_id, .. }
+                | Body::JobFired { msg_id, .. }
+                | Body::Read { msg_id, .. }
+                | Body::ReadOk { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+
+        /// The request as an operation for the log, sent to us by `src`.
+        fn op(self, src: &str) -> Option<Op> {
+            Some(match self {
+                Body::Schedule {
+                    fire_at,
+                    payload,
+                    client,
+                    ..
+                } => Op::Schedule {
+                    client: client.unwrap_or_else(|| src.to_string()),
+                    fire_at,
+                    payload,
+                },
+                Body::Read { .. } => Op::Read,
+                _ => return None,
+            })
+        }
+    }
+
+    impl StateMachine for Schedule {
+        type Command = Op;
+        type Output = Outcome;
+        type Snapshot = Schedule;
+
+        fn apply(&mut self, op: &Op) -> Outcome {
+            match op {
+                Op::Schedule {
+                    client,
+                    fire_at,
+                    payload,
+                } => {
+                    let job_id = self.next_job;
+                    self.next_job += 1;
+                    let job = Job {
+                        client: client.clone(),
+                        fire_at: *fire_at,
+                        payload: payload.clone(),
+                        fired_by: None,
+                    };
+                    self.jobs.insert(job_id, job);
+                    Outcome::Reply(Body::ScheduleOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        job_id,
+                    })
+                }
+                Op::Fire { job_id, by } => match self.jobs.get_mut(job_id) {
+                    Some(job) if job.fired_by.is_none() => {
+                        job.fired_by = Some(by.clone());
+                        Outcome::Fired {
+                            job_id: *job_id,
+                            client: job.client.clone(),
+                            payload: job.payload.clone(),
+                        }
+                    }
+                    _ => Outcome::Unchanged,
+                },
+                Op::Read => {
+                    let (fired, pending): (Vec<_>, Vec<_>) = self
+                        .jobs
+                        .iter()
+                        .partition(|(_, job)| job.fired_by.is_some());
+                    Outcome::Reply(Body::ReadOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        pending: pending.into_iter().map(|(job_id, _)| *job_id).collect(),
+                        fired: fired
+                            .into_iter()
+                            .filter_map(|(job_id, job)| Some((*job_id, job.fired_by.clone()?)))
+                            .collect(),
+                    })
+                }
+            }
+        }
+
+        fn snapshot(&self) -> Schedule {
+            self.clone()
+        }
+
+        fn restore(&mut self, snapshot: Schedule) {
+            *self = snapshot;
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                node_ids: Vec::new(),
+                raft: None,
+                forwards: HashMap::new(),
+                proposals: HashMap::new(),
+                firing: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            let raft = self.raft.as_ref().map(|raft| {
+                json!({
+                    "term": raft.term(),
+                    "leader": raft.leader(),
+                    "nodes": raft.nodes(),
+                    "last_index": raft.last_index(),
+                    "commit_index": raft.commit_index(),
+                })
+            });
+            json!({
+                "id": self.id,
+                "raft": raft,
+                "forwards": self.forwards.len(),
+                "proposals": self.proposals.len(),
+                "firing": self.firing,
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Drives Raft's timers, proposes firing whatever's due if we're leading, and gives
+        /// up on requests that have waited too long.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            let mut outbox = Vec::new();
+            if let Some(raft) = &mut self.raft {
+                raft.tick(&mut outbox);
+            }
+            self.fire_due(&mut outbox);
+            self.send_raft(outbox, &mut messages);
+
+            let now = Instant::now();
+            let expired_forwards: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            let expired_proposals: Vec<u64> = self
+                .proposals
+                .iter()
+                .filter(|(_, proposed)| {
+                    (proposed.waiting.as_ref()).is_some_and(|waiting| waiting.deadline <= now)
+                })
+                .map(|(index, _)| *index)
+                .collect();
+            let mut expired: Vec<Waiting> = expired_forwards
+                .iter()
+                .filter_map(|msg_id| self.forwards.remove(msg_id))
+                .chain(
+                    expired_proposals
+                        .iter()
+                        .filter_map(|index| self.proposals.remove(index)?.waiting),
+                )
+                .collect();
+            expired.sort_by_key(|waiting| waiting.msg_id);
+            for waiting in expired {
+                // It may yet be committed, or have been and only the reply was lost, so this
+                // mustn't claim the job wasn't scheduled
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code: TIMEOUT,
+                    text: "timed out waiting on the log".to_string(),
+                };
+                self.reply(waiting, error, &mut messages);
+            }
+            messages
+        }
+
+        /// Proposes firing every due job we haven't already proposed firing this term.
+        fn fire_due(&mut self, outbox: &mut Outbox<Schedule>) {
+            let Some(raft) = self.raft.as_mut().filter(|raft| raft.is_leader()) else {
+                return;
+            };
+            let (now, term) = (wall_clock(), raft.term());
+            let due: Vec<u64> = raft
+                .state()
+                .jobs
+                .iter()
+                .filter(|(_, job)| job.fired_by.is_none() && job.fire_at <= now)
+                .map(|(job_id, _)| *job_id)
+                .filter(|job_id| self.firing.get(job_id) != Some(&term))
+                .collect();
+            for job_id in due {
+                let fire = Op::Fire {
+                    job_id,
+                    by: self.id.clone(),
+                };
+                let Ok(proposal) = raft.propose(fire, outbox) else {
+                    return;
+                };
+                let proposed = Proposed {
+                    term: proposal.term,
+                    waiting: None,
+                };
+                self.proposals.insert(proposal.index, proposed);
+                self.firing.insert(job_id, term);
+            }
+        }
+
+        /// Wraps Raft's messages for the wire, answers the clients whose requests it has
+        /// applied since, and sends job_fired for the fires we proposed that won.
+        fn send_raft(&mut self, outbox: Outbox<Schedule>, messages: &mut Vec<Message>) {
+            for (dest, message) in outbox {
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest,
+                    body: Body::Raft(message),
+                });
+            }
+            let Some(raft) = &mut self.raft else {
+                return;
+            };
+            let applied = raft.take_applied();
+            let jobs = &raft.state().jobs;
+            self.firing
+                .retain(|job_id, _| jobs.get(job_id).is_some_and(|job| job.fired_by.is_none()));
+            for applied in applied {
+                let Some(proposed) = self.proposals.remove(&applied.index) else {
+                    continue;
+                };
+                let ours = proposed.term == applied.term;
+                match (applied.output, proposed.waiting) {
+                    (
+                        Some(Outcome::Fired {
+                            job_id,
+                            client,
+                            payload,
+                        }),
+                        None,
+                    ) if ours => {
+                        log::info!("Firing job {}", job_id);
+                        let msg_id = self.next_msg_id();
+                        messages.push(Message {
+                            src: self.id.clone(),
+                            dest: client,
+                            body: Body::JobFired {
+                                msg_id,
+                                job_id,
+                                payload,
+                            },
+                        });
+                    }
+                    (Some(Outcome::Reply(reply)), Some(waiting)) if ours => {
+                        self.reply(waiting, reply, messages);
+                    }
+                    // Another leader's entry took its place, so it never will take effect
+                    (_, Some(waiting)) => {
+                        let error = Body::Error {
+                            in_reply_to: 0,
+                            code: TEMPORARILY_UNAVAILABLE,
+                            text: "lost leadership before committing".to_string(),
+                        };
+                        self.reply(waiting, error, messages);
+                    }
+                    (_, None) => {}
+                }
+            }
+        }
+
+        fn reply(&mut self, waiting: Waiting, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = waiting.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: waiting.client,
+                body,
+            });
+        }
+
+        /// Proposes a client's request if we're leading, or sends it on to the leader with an
+        /// id of our own, so we can tell which client its reply is for. Requests that already
+        /// came from another node aren't forwarded again, so they can't go round in circles.
+        /// A forwarded schedule names the client, so the job_fired goes straight to it.
+        fn request(&mut self, src: &str, mut body: Body, messages: &mut Vec<Message>) {
+            let Some(msg_id) = body.msg_id().copied() else {
+                return;
+            };
+            let waiting = Waiting {
+                client: src.to_string(),
+                msg_id,
+                deadline: Instant::now() + self.config.request_timeout,
+            };
+            let from_node = self.node_ids.iter().any(|node| node == src);
+            let (Some(raft), Some(op)) = (&mut self.raft, body.clone().op(src)) else {
+                return;
+            };
+            let mut outbox = Vec::new();
+            match raft.propose(op, &mut outbox) {
+                Ok(proposal) => {
+                    let proposed = Proposed {
+                        term: proposal.term,
+                        waiting: Some(waiting),
+                    };
+                    self.proposals.insert(proposal.index, proposed);
+                }
+                Err(Rejected::NotLeader(Some(leader))) if !from_node => {
+                    let forward_id = self.next_msg_id();
+                    if let Some(msg_id) = body.msg_id() {
+                        *msg_id = forward_id;
+                    }
+                    if let Body::Schedule { client, .. } = &mut body {
+                        client.get_or_insert_with(|| src.to_string());
+                    }
+                    self.forwards.insert(forward_id, waiting);
+                    messages.push(Message {
+                        src: self.id.clone(),
+                        dest: leader,
+                        body,
+                    });
+                }
+                Err(_) => {
+                    let error = Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "no leader to serve this".to_string(),
+                    };
+                    self.reply(waiting, error, messages);
+                }
+            }
+            self.send_raft(outbox, messages);
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.raft = Some(Raft::new(
+                        node_id.clone(),
+                        node_ids.clone(),
+                        Schedule::default(),
+                        self.config.raft.clone(),
+                    ));
+                    self.node_ids = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Schedule { .. } | Body::Read { .. } => {
+                    self.request(src, body, outbox);
+                    None
+                }
+                Body::Raft(message) => {
+                    let mut raft_outbox = Vec::new();
+                    self.raft.as_mut()?.handle(src, message, &mut raft_outbox);
+                    self.send_raft(raft_outbox, outbox);
+                    None
+                }
+                // The leader's answer to a request we forwarded, which goes on to the client
+                mut reply => {
+                    let forward = self.forwards.remove(reply.in_reply_to()?)?;
+                    self.reply(forward, reply, outbox);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use serde_json::json;
+
+        /// n1 to n`count`, with n1 elected leader. n2 calls an election on its first tick
+        /// too, so only tick it to have it take over.
+        fn cluster(count: usize) -> Vec<Node> {
+            let node_ids: Vec<String> = (1..=count).map(|i| format!("n{}", i)).collect();
+            let mut nodes: Vec<Node> = node_ids
+                .iter()
+                .map(|id| {
+                    let mut config = Config::default();
+                    config.raft.heartbeat_interval = Duration::ZERO;
+                    if id == "n1" || id == "n2" {
+                        config.raft.election_timeout = Duration::ZERO;
+                    }
+                    let mut node = Node::new(config);
+                    node.handle_message(Message {
+                        src: "c1".into(),
+                        dest: id.clone(),
+                        body: Body::Init {
+                            msg_id: 1,
+                            node_id: id.clone(),
+                            node_ids: node_ids.clone(),
+                        },
+                    });
+                    node
+                })
+                .collect();
+            let messages = nodes[0].tick();
+            deliver(&mut nodes, messages, &[]);
+            assert!(nodes[0].raft.as_ref().unwrap().is_leader());
+            nodes
+        }
+
+        /// Delivers `messages`, and everything sent in response, until only messages to
+        /// clients are left, which are returned. Messages to the nodes that are `down` are
+        /// lost.
+        fn deliver(nodes: &mut [Node], mut messages: Vec<Message>, down: &[&str]) -> Vec<Message> {
+            let mut to_clients = Vec::new();
+            while !messages.is_empty() {
+                for message in std::mem::take(&mut messages) {
+                    match message.dest.strip_prefix('n') {
+                        Some(_) if down.contains(&message.dest.as_str()) => {}
+                        Some(n) => {
+                            let node = &mut nodes[n.parse::<usize>().unwrap() - 1];
+                            messages.extend(node.handle_message(message));
+                        }
+                        None => to_clients.push(message),
+                    }
+                }
+            }
+            to_clients
+        }
+
+        /// Ticks `node` a few times, returning whatever reaches clients.
+        fn tick(nodes: &mut [Node], node: usize, down: &[&str]) -> Vec<Message> {
+            let mut to_clients = Vec::new();
+            for _ in 0..5 {
+                let messages = nodes[node].tick();
+                to_clients.extend(deliver(nodes, messages, down));
+            }
+            to_clients
+        }
+
+        fn request(dest: &str, body: serde_json::Value) -> Message {
+            serde_json::from_value(json!({"src": "c1", "dest": dest, "body": body})).unwrap()
+        }
+
+        fn schedule(msg_id: u64, fire_at: u64) -> serde_json::Value {
+            json!({"type": "schedule", "msg_id": msg_id, "fire_at": fire_at, "payload": "job"})
+        }
+
+        fn fired(messages: &[Message]) -> Vec<u64> {
+            messages
+                .iter()
+                .filter_map(|message| match message.body {
+                    Body::JobFired { job_id, .. } => Some(job_id),
+                    _ => None,
+                })
+                .collect()
+        }
+
+        #[test]
+        fn test_schedule_through_follower_reaches_every_node() {
+            let mut nodes = cluster(3);
+            let fire_at = wall_clock() + 3_600_000;
+            let messages = nodes[1].handle_message(request("n2", schedule(5, fire_at)));
+            let replies = deliver(&mut nodes, messages, &[]);
+            assert!(matches!(
+                replies[0].body,
+                Body::ScheduleOk {
+                    in_reply_to: 5,
+                    job_id: 0,
+                    ..
+                }
+            ));
+            assert_eq!(replies[0].dest, "c1");
+            // Followers hear the entry's committed with the next heartbeat
+            tick(&mut nodes, 0, &[]);
+            for node in &nodes {
+                let job = &node.raft.as_ref().unwrap().state().jobs[&0];
+                assert_eq!((job.client.as_str(), job.fire_at), ("c1", fire_at));
+            }
+        }
+
+        #[test]
+        fn test_due_job_fires_once() {
+            let mut nodes = cluster(3);
+            let messages = nodes[0].handle_message(request("n1", schedule(1, 0)));
+            deliver(&mut nodes, messages, &[]);
+
+            let to_clients = tick(&mut nodes, 0, &[]);
+            assert_eq!(fired(&to_clients), vec![0]);
+            let job_fired = to_clients.iter().find(|m| m.dest == "c1").unwrap();
+            assert!(matches!(&job_fired.body, Body::JobFired { payload, .. } if payload == "job"));
+            assert!(fired(&tick(&mut nodes, 0, &[])).is_empty());
+        }
+
+        #[test]
+        fn test_future_job_waits() {
+            let mut nodes = cluster(3);
+            let fire_at = wall_clock() + 3_600_000;
+            let messages = nodes[0].handle_message(request("n1", schedule(1, fire_at)));
+            deliver(&mut nodes, messages, &[]);
+            assert!(fired(&tick(&mut nodes, 0, &[])).is_empty());
+
+            let messages =
+                nodes[0].handle_message(request("n1", json!({"type": "read", "msg_id": 2})));
+            let replies = deliver(&mut nodes, messages, &[]);
+            assert!(matches!(
+                &replies[0].body,
+                Body::ReadOk { pending, fired, .. } if pending == &[0] && fired.is_empty()
+            ));
+        }
+
+        #[test]
+        fn test_job_fires_after_accepting_node_dies() {
+            let mut nodes = cluster(3);
+            let messages = nodes[0].handle_message(request("n1", schedule(1, 0)));
+            deliver(&mut nodes, messages, &[]);
+
+            // n1 goes before it ever ticks again, so before it can fire the job
+            let to_clients = tick(&mut nodes, 1, &["n1"]);
+            assert!(nodes[1].raft.as_ref().unwrap().is_leader());
+            assert_eq!(fired(&to_clients), vec![0]);
+            assert_eq!(
+                nodes[1].raft.as_ref().unwrap().state().jobs[&0]
+                    .fired_by
+                    .as_deref(),
+                Some("n2")
+            );
+        }
+
+        #[test]
+        fn test_fired_job_is_not_fired_again_by_new_leader() {
+            let mut nodes = cluster(3);
+            let messages = nodes[0].handle_message(request("n1", schedule(1, 0)));
+            deliver(&mut nodes, messages, &[]);
+            assert_eq!(fired(&tick(&mut nodes, 0, &[])), vec![0]);
+
+            let to_clients = tick(&mut nodes, 1, &["n1"]);
+            assert!(nodes[1].raft.as_ref().unwrap().is_leader());
+            assert!(fired(&to_clients).is_empty());
+
+            // Nor does a fire that lost the race to be committed first
+            let mut schedule = nodes[1].raft.as_ref().unwrap().state().clone();
+            let fire = Op::Fire {
+                job_id: 0,
+                by: "n3".to_string(),
+            };
+            assert!(matches!(schedule.apply(&fire), Outcome::Unchanged));
+            assert_eq!(schedule.jobs[&0].fired_by.as_deref(), Some("n1"));
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,815 @@
+mod ring {
+    use std::collections::hash_map::DefaultHasher;
+    use std::collections::BTreeMap;
+    use std::hash::{Hash, Hasher};
+
+    /// A consistent hash ring: each node sits at `vnodes` points on it, and owns the keys
+    /// that hash to just before each of its points. A node joining or leaving only moves the
+    /// keys next to its own points, spread across every other node, rather than reshuffling
+    /// the rest.
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct Ring {
+        points: BTreeMap<u64, String>,
+    }
+
+    impl Ring {
+        pub fn new(nodes: &[String], vnodes: usize) -> Self {
+            let points = nodes
+                .iter()
+                .flat_map(|node| (0..vnodes).map(move |i| (hash(&(node, i)), node.clone())))
+                .collect();
+            Ring { points }
+        }
+
+        /// The node that owns `key`, unless the ring's empty.
+        pub fn owner(&self, key: &str) -> Option<&str> {
+            let at = hash(&key);
+            self.points
+                .range(at..)
+                .chain(&self.points)
+                .next()
+                .map(|(_, node)| node.as_str())
+        }
+    }
+
+    fn hash<T: Hash>(value: &T) -> u64 {
+        let mut hasher = DefaultHasher::new();
+        value.hash(&mut hasher);
+        hasher.finish()
+    }
+}
+
+pub mod node {
+    use super::ring::Ring;
+    use maelstrom::error::{
+        KEY_DOES_NOT_EXIST, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE, TIMEOUT,
+    };
+    use rand::seq::SliceRandom;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// A key/value store serving lin-kv's read, write and cas, with keys sharded across the
+    /// members on a consistent hash ring. Each key lives only at its owner, which serves it
+    /// directly; any other node forwards requests for it to the owner and relays the reply.
+    ///
+    /// add_node and remove_node change the members at any node, which bumps the membership's
+    /// epoch and spreads it to the others, and every node gossips its membership to a random
+    /// other each tick so a lost update still gets everywhere. Nodes take up whichever
+    /// membership has the highest epoch, breaking ties by who made the change.
+    ///
+    /// When the ring changes, each node hands the keys it no longer owns to their new owner,
+    /// resending until they're acknowledged. The owner keeps any value it already has for a
+    /// handed-off key, which can only have been written since it took the key over. Until a
+    /// handoff lands, the new owner doesn't know the keys in it.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        node_ids: Vec<String>, // Every node Maelstrom started, member or not
+        membership: Membership,
+        ring: Ring,
+        values: HashMap<String, Value>, // The keys we own, by each key's JSON
+        forwards: HashMap<u64, Waiting>, // Requests sent to owners, by msg_id
+        handoffs: HashMap<u64, Handoff>, // Keys sent to new owners, by msg_id, until acked
+    }
+
+    /// The members, as of a change made at `origin`.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Membership {
+        epoch: u64,
+        origin: String,
+        members: Vec<String>,
+    }
+
+    /// A client waiting on a reply to `msg_id`.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on answering
+    }
+
+    /// Keys on their way to a new owner.
+    struct Handoff {
+        dest: String,
+        values: HashMap<String, Value>,
+    }
+
+    /// Tunables, read from `SHARDED_KV_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// How long a forwarded request waits on the owner to answer before we give up.
+        pub request_timeout: Duration,
+        /// Time between ticks, which gossip membership, resend handoffs and time out
+        /// requests.
+        pub tick_interval: Duration,
+        /// Points each member has on the ring. More spread keys more evenly, at the cost of
+        /// a bigger ring.
+        pub vnodes: usize,
+        /// How many of the nodes at init, in order, start out as members; 0 for all of them.
+        /// The rest own nothing until an add_node makes them members.
+        pub initial_members: usize,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                request_timeout: Duration::from_millis(1000),
+                tick_interval: Duration::from_millis(100),
+                vnodes: 64,
+                initial_members: 0,
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                request_timeout: Duration::from_millis(env_or(
+                    "SHARDED_KV_REQUEST_TIMEOUT_MS",
+                    default.request_timeout.as_millis() as u64,
+                )),
+                tick_interval: Duration::from_millis(env_or(
+                    "SHARDED_KV_TICK_INTERVAL_MS",
+                    default.tick_interval.as_millis() as u64,
+                )),
+                vnodes: env_or("SHARDED_KV_VNODES", default.vnodes),
+                initial_members: env_or("SHARDED_KV_INITIAL_MEMBERS", default.initial_members),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+            key: Value,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: Value,
+        },
+        Write {
+            msg_id: u64,
+            key: Value,
+            value: Value,
+        },
+        WriteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Sets `key` to `to` if it's `from`. A missing key fails, unless
+        /// `create_if_not_exists` is set, in which case it's created.
+        Cas {
+            msg_id: u64,
+            key: Value,
+            from: Value,
+            to: Value,
+            #[serde(default)]
+            create_if_not_exists: bool,
+        },
+        CasOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Makes `node` a member, replying once we've taken up the change.
+        AddNode {
+            msg_id: u64,
+            node: String,
+        },
+        AddNodeOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Takes `node` out of the members, replying once we've taken up the change.
+        RemoveNode {
+            msg_id: u64,
+            node: String,
+        },
+        RemoveNodeOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// The sender's membership. Not acknowledged, since it's gossiped again and again.
+        Members {
+            msg_id: u64,
+            membership: Membership,
+        },
+        /// Keys the receiver now owns, by each key's JSON.
+        Handoff {
+            msg_id: u64,
+            values: HashMap<String, Value>,
+        },
+        HandoffOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+    }
+
+    impl Body {
+        /// The id of the request this replies to, if it's a reply.
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::InitOk { in_reply_to, .. }
+                | Body::ReadOk { in_reply_to, .. }
+                | Body::WriteOk { in_reply_to, .. }
+                | Body::CasOk { in_reply_to, .. }
+                | Body::AddNodeOk { in_reply_to, .. }
+                | Body::RemoveNodeOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. }
+                | Body::HandoffOk { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::Read { msg_id, .. }
+                | Body::ReadOk { msg_id, .. }
+                | Body::Write { msg_id, .. }
+                | Body::WriteOk { msg_id, .. }
+                | Body::Cas { msg_id, .. }
+                | Body::CasOk { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+
+        fn key(&self) -> Option<&Value> {
+            match self {
+                Body::Read { key, .. } | Body::Write { key, .. } | Body::Cas { key, .. } => {
+                    Some(key)
+                }
+                _ => None,
+            }
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                membership: Membership {
+                    epoch: 0,
+                    origin: String::default(),
+                    members: Vec::new(),
+                },
+                ring: Ring::new(&[], config.vnodes),
+                config,
+                node_ids: Vec::new(),
+                values: HashMap::new(),
+                forwards: HashMap::new(),
+                handoffs: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "membership": self.membership,
+                "values": self.values.len(),
+                "forwards": self.forwards.len(),
+                "handoffs": self.handoffs.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Gossips our membership to a random node, resends handoffs that haven't been
+        /// acknowledged, and gives up on forwarded requests that have waited too long.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            let peers: Vec<&String> = self.node_ids.iter().filter(|id| **id != self.id).collect();
+            if let Some(peer) = peers
+                .choose(&mut rand::thread_rng())
+                .map(|peer| peer.to_string())
+            {
+                let msg_id = self.next_msg_id();
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Members {
+                        msg_id,
+                        membership: self.membership.clone(),
+                    },
+                });
+            }
+
+            let mut handoffs: Vec<(&u64, &Handoff)> = self.handoffs.iter().collect();
+            handoffs.sort_by_key(|(msg_id, _)| **msg_id);
+            for (msg_id, handoff) in handoffs {
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: handoff.dest.clone(),
+                    body: Body::Handoff {
+                        msg_id: *msg_id,
+                        values: handoff.values.clone(),
+                    },
+                });
+            }
+
+            let now = Instant::now();
+            let mut expired: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            expired.sort();
+            for msg_id in expired {
+                let Some(waiting) = self.forwards.remove(&msg_id) else {
+                    continue;
+                };
+                // The owner may have applied it, and only the reply was lost
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code: TIMEOUT,
+                    text: "timed out waiting on the key's owner".to_string(),
+                };
+                self.reply(waiting, error, &mut messages);
+            }
+            messages
+        }
+
+        fn reply(&mut self, waiting: Waiting, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = waiting.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: waiting.client,
+                body,
+            });
+        }
+
+        /// Serves a request if we own its key, or sends it on to the owner with an id of our
+        /// own, so we can tell which client its reply is for. Requests that already came from
+        /// another node aren't forwarded again, so nodes whose rings disagree can't send one
+        /// round in circles.
+        fn request(
+            &mut self,
+            src: &str,
+            mut body: Body,
+            messages: &mut Vec<Message>,
+        ) -> Option<Body> {
+            let key = body.key()?.to_string();
+            let owner = self.ring.owner(&key).map(str::to_string);
+            let msg_id = *body.msg_id()?;
+            match owner {
+                Some(owner) if owner == self.id => Some(self.apply(&key, body)),
+                Some(owner) if !self.node_ids.iter().any(|node| node == src) => {
+                    let forward_id = self.next_msg_id();
+                    if let Some(msg_id) = body.msg_id() {
+                        *msg_id = forward_id;
+                    }
+                    let waiting = Waiting {
+                        client: src.to_string(),
+                        msg_id,
+                        deadline: Instant::now() + self.config.request_timeout,
+                    };
+                    self.forwards.insert(forward_id, waiting);
+                    messages.push(Message {
+                        src: self.id.clone(),
+                        dest: owner,
+                        body,
+                    });
+                    None
+                }
+                _ => Some(Body::Error {
+                    in_reply_to: msg_id,
+                    code: TEMPORARILY_UNAVAILABLE,
+                    text: "not the key's owner".to_string(),
+                }),
+            }
+        }
+
+        /// Runs a request against a key we own.
+        fn apply(&mut self, key: &str, body: Body) -> Body {
+            match body {
+                Body::Read { msg_id, .. } => match self.values.get(key) {
+                    Some(value) => Body::ReadOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        value: value.clone(),
+                    },
+                    None => Body::Error {
+                        in_reply_to: msg_id,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("{} doesn't exist", key),
+                    },
+                },
+                Body::Write { msg_id, value, .. } => {
+                    self.values.insert(key.to_string(), value);
+                    Body::WriteOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Cas {
+                    msg_id,
+                    from,
+                    to,
+                    create_if_not_exists,
+                    ..
+                } => match self.values.get_mut(key) {
+                    Some(value) if *value == from => {
+                        *value = to;
+                        Body::CasOk {
+                            msg_id: self.cur_id,
+                            in_reply_to: msg_id,
+                        }
+                    }
+                    Some(value) => Body::Error {
+                        in_reply_to: msg_id,
+                        code: PRECONDITION_FAILED,
+                        text: format!("expected {}, but {} is {}", from, key, value),
+                    },
+                    None if create_if_not_exists => {
+                        self.values.insert(key.to_string(), to);
+                        Body::CasOk {
+                            msg_id: self.cur_id,
+                            in_reply_to: msg_id,
+                        }
+                    }
+                    None => Body::Error {
+                        in_reply_to: msg_id,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("{} doesn't exist", key),
+                    },
+                },
+                _ => unreachable!("only reads, writes and cas are applied"),
+            }
+        }
+
+        /// Changes the members, under an epoch past any we've seen, and tells everyone.
+        fn change_members(&mut self, members: Vec<String>, messages: &mut Vec<Message>) {
+            if members == self.membership.members {
+                return;
+            }
+            let membership = Membership {
+                epoch: self.membership.epoch + 1,
+                origin: self.id.clone(),
+                members,
+            };
+            log::info!("{} changing members to {:?}", self.id, membership.members);
+            for node in self.node_ids.clone() {
+                if node == self.id {
+                    continue;
+                }
+                let msg_id = self.next_msg_id();
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: node,
+                    body: Body::Members {
+                        msg_id,
+                        membership: membership.clone(),
+                    },
+                });
+            }
+            self.adopt(membership, messages);
+        }
+
+        /// Takes up `membership` if it's newer than ours, and hands off whatever keys the
+        /// new ring gives to someone else.
+        fn adopt(&mut self, membership: Membership, messages: &mut Vec<Message>) {
+            let newer = (membership.epoch, &membership.origin)
+                > (self.membership.epoch, &self.membership.origin);
+            if !newer {
+                return;
+            }
+            self.ring = Ring::new(&membership.members, self.config.vnodes);
+            self.membership = membership;
+            self.rebalance(messages);
+        }
+
+        /// Sends each key we hold but don't own to its owner.
+        fn rebalance(&mut self, messages: &mut Vec<Message>) {
+            let mut moving: HashMap<String, HashMap<String, Value>> = HashMap::new();
+            let keys: Vec<String> = self.values.keys().cloned().collect();
+            for key in keys {
+                let Some(owner) = self.ring.owner(&key) else {
+                    // No members at all, so nowhere better for it
+                    continue;
+                };
+                if owner == self.id {
+                    continue;
+                }
+                let owner = owner.to_string();
+                if let Some(value) = self.values.remove(&key) {
+                    moving.entry(owner).or_default().insert(key, value);
+                }
+            }
+            let mut moving: Vec<(String, HashMap<String, Value>)> = moving.into_iter().collect();
+            moving.sort_by(|a, b| a.0.cmp(&b.0));
+            for (dest, values) in moving {
+                log::debug!("{} handing {} keys to {}", self.id, values.len(), dest);
+                let msg_id = self.next_msg_id();
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: dest.clone(),
+                    body: Body::Handoff {
+                        msg_id,
+                        values: values.clone(),
+                    },
+                });
+                self.handoffs.insert(msg_id, Handoff { dest, values });
+            }
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            messages: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    let members: Vec<String> = match self.config.initial_members {
+                        0 => node_ids.clone(),
+                        count => node_ids.iter().take(count).cloned().collect(),
+                    };
+                    self.ring = Ring::new(&members, self.config.vnodes);
+                    self.membership.members = members;
+                    self.node_ids = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Read { .. } | Body::Write { .. } | Body::Cas { .. } => {
+                    self.request(src, body, messages)
+                }
+                Body::AddNode { msg_id, node } => {
+                    let mut members = self.membership.members.clone();
+                    if !members.contains(&node) {
+                        members.push(node);
+                    }
+                    self.change_members(members, messages);
+                    Some(Body::AddNodeOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::RemoveNode { msg_id, node } => {
+                    let mut members = self.membership.members.clone();
+                    members.retain(|member| *member != node);
+                    self.change_members(members, messages);
+                    Some(Body::RemoveNodeOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Members { membership, .. } => {
+                    self.adopt(membership, messages);
+                    None
+                }
+                Body::Handoff { msg_id, values } => {
+                    for (key, value) in values {
+                        self.values.entry(key).or_insert(value);
+                    }
+                    // Our ring may have moved on since the sender's, so some may go further
+                    self.rebalance(messages);
+                    Some(Body::HandoffOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::HandoffOk { in_reply_to, .. } => {
+                    self.handoffs.remove(&in_reply_to);
+                    None
+                }
+                // An owner's answer to a request we forwarded, which goes on to the client
+                mut reply => {
+                    let forward = self.forwards.remove(reply.in_reply_to()?)?;
+                    self.reply(forward, reply, messages);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, deliver, reply, request};
+        use serde_json::json;
+
+        /// n1 to n`count`, the first `members` of them members.
+        fn cluster(count: usize, members: usize) -> Vec<Node> {
+            testing::cluster(count, |_| {
+                Node::new(Config {
+                    initial_members: members,
+                    ..Config::default()
+                })
+            })
+        }
+
+        fn send(nodes: &mut [Node], dest: &str, body: serde_json::Value) -> serde_json::Value {
+            reply(&deliver(nodes, vec![request(dest, body)]))
+        }
+
+        #[test]
+        fn test_virtual_nodes_spread_keys_evenly() {
+            let nodes: Vec<String> = (1..=4).map(|i| format!("n{}", i)).collect();
+            let ring = Ring::new(&nodes, 64);
+            let mut owned: HashMap<&str, usize> = HashMap::new();
+            for key in 0..10000 {
+                *owned
+                    .entry(ring.owner(&key.to_string()).unwrap())
+                    .or_default() += 1;
+            }
+            assert_eq!(owned.len(), 4);
+            for (node, count) in owned {
+                assert!((1500..3500).contains(&count), "{} owns {}", node, count);
+            }
+        }
+
+        #[test]
+        fn test_requests_are_served_by_the_owner() {
+            let mut nodes = cluster(3, 0);
+            for key in 0..20 {
+                let dest = format!("n{}", key % 3 + 1);
+                let write = json!({"type": "write", "msg_id": key, "key": key, "value": key});
+                assert_eq!(send(&mut nodes, &dest, write)["type"], "write_ok");
+            }
+            let total: usize = nodes.iter().map(|node| node.values.len()).sum();
+            assert_eq!(total, 20);
+            for node in &nodes {
+                for key in node.values.keys() {
+                    assert_eq!(node.ring.owner(key), Some(node.id.as_str()));
+                }
+            }
+            let cas = json!({"type": "cas", "msg_id": 30, "key": 7, "from": 7, "to": 8});
+            assert_eq!(send(&mut nodes, "n1", cas)["type"], "cas_ok");
+            let read = send(
+                &mut nodes,
+                "n2",
+                json!({"type": "read", "msg_id": 31, "key": 7}),
+            );
+            assert_eq!(read["value"], 8);
+            assert_eq!(read["in_reply_to"], 31);
+        }
+
+        #[test]
+        fn test_keys_move_when_members_change() {
+            let mut nodes = cluster(3, 2);
+            for key in 0..30 {
+                let write = json!({"type": "write", "msg_id": key, "key": key, "value": key});
+                send(&mut nodes, "n1", write);
+            }
+            assert!(nodes[2].values.is_empty());
+
+            let add = json!({"type": "add_node", "msg_id": 40, "node": "n3"});
+            assert_eq!(send(&mut nodes, "n2", add)["type"], "add_node_ok");
+            assert!(!nodes[2].values.is_empty());
+            assert!(nodes.iter().all(|node| node.handoffs.is_empty()));
+
+            let remove = json!({"type": "remove_node", "msg_id": 41, "node": "n1"});
+            send(&mut nodes, "n3", remove);
+            assert!(nodes[0].values.is_empty());
+            for key in 0..30 {
+                let read = json!({"type": "read", "msg_id": 50 + key, "key": key});
+                assert_eq!(send(&mut nodes, "n1", read)["value"], key);
+            }
+        }
+
+        #[test]
+        fn test_membership_is_gossiped_and_handoffs_resent() {
+            let mut nodes = cluster(2, 1);
+            let both = Ring::new(&["n1".into(), "n2".into()], Config::default().vnodes);
+            let key = (0..)
+                .find(|key: &u64| both.owner(&key.to_string()) == Some("n2"))
+                .unwrap();
+            let write = json!({"type": "write", "msg_id": 1, "key": key, "value": 1});
+            send(&mut nodes, "n1", write);
+            // Everything n1 sends about the change is lost
+            let add = json!({"type": "add_node", "msg_id": 2, "node": "n2"});
+            nodes[0].handle_message(request("n1", add));
+            assert!(nodes[0].values.is_empty());
+            assert_eq!(nodes[1].membership.epoch, 0);
+
+            let messages = nodes[0].tick();
+            deliver(&mut nodes, messages);
+            assert_eq!(nodes[1].membership, nodes[0].membership);
+            assert_eq!(nodes[1].values[&key.to_string()], json!(1));
+            assert!(nodes[0].handoffs.is_empty());
+        }
+
+        #[test]
+        fn test_handoffs_keep_newer_writes() {
+            let mut nodes = cluster(2, 0);
+            let key = (0..)
+                .find(|key: &u64| nodes[0].ring.owner(&key.to_string()) == Some("n2"))
+                .unwrap();
+            let write = json!({"type": "write", "msg_id": 1, "key": key, "value": "new"});
+            send(&mut nodes, "n2", write);
+            let handoff = Message {
+                src: "n1".into(),
+                dest: "n2".into(),
+                body: Body::Handoff {
+                    msg_id: 2,
+                    values: HashMap::from([(key.to_string(), json!("old"))]),
+                },
+            };
+            deliver(&mut nodes, vec![handoff]);
+            assert_eq!(nodes[1].values[&key.to_string()], json!("new"));
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,406 @@
+pub mod node {
+    use crdt::{Delta, Merge, OrMap};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    /// Shopping carts clients can add items to and remove them from at any node,
+    /// replicated as an `OrMap` from each cart to its items. Removing an item, or clearing
+    /// a cart, only undoes the adds its node has seen, so an item put in the cart
+    /// concurrently elsewhere stays in it. Each node gossips every peer the part of the
+    /// carts it hasn't yet acknowledged, so reads anywhere converge once writes stop.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        peers: Vec<String>,
+        carts: OrMap<String, String>,
+        known: HashMap<String, OrMap<String, String>>, // What each peer is known to have merged
+        in_flight: HashMap<u64, (String, OrMap<String, String>)>, // The latest gossip to each peer, by msg_id
+    }
+
+    /// Tunables, read from `SHOPPING_CART_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between gossip rounds.
+        pub gossip_interval: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                gossip_interval: Duration::from_millis(100),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                gossip_interval: Duration::from_millis(env_or(
+                    "SHOPPING_CART_GOSSIP_INTERVAL_MS",
+                    default.gossip_interval.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Add {
+            msg_id: u64,
+            cart: String,
+            item: String,
+        },
+        AddOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Remove {
+            msg_id: u64,
+            cart: String,
+            item: String,
+        },
+        RemoveOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Removes every item in the cart.
+        Clear {
+            msg_id: u64,
+            cart: String,
+        },
+        ClearOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+            cart: String,
+        },
+        /// The cart's items, in ascending order.
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: Vec<String>,
+        },
+        /// The part of the carts the sender thinks we're missing.
+        Gossip {
+            msg_id: u64,
+            carts: OrMap<String, String>,
+        },
+        /// The gossip has been merged.
+        GossipOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+    }
+
+    impl Default for Node {
+        fn default() -> Self {
+            Node::new()
+        }
+    }
+
+    impl Node {
+        pub fn new() -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                peers: Vec::new(),
+                carts: OrMap::default(),
+                known: HashMap::new(),
+                in_flight: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "carts": self.carts.len(),
+                "in_flight": self.in_flight.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let Some(body) = self.handle_body(&message.src, message.body) else {
+                return Vec::new();
+            };
+            self.cur_id += 1;
+            vec![Message {
+                src: message.dest,
+                dest: message.src,
+                body,
+            }]
+        }
+
+        /// Sends each peer what it's missing. Anything a peer doesn't acknowledge is sent
+        /// again next round, so lost gossip only delays convergence.
+        pub fn gossip(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            for peer in self.peers.clone() {
+                let known = self.known.entry(peer.clone()).or_default();
+                let Some(delta) = self.carts.delta_since(known) else {
+                    continue;
+                };
+                // Only the latest gossip needs acknowledging, since it has all the earlier ones
+                self.in_flight.retain(|_, (to, _)| *to != peer);
+                let msg_id = self.next_msg_id();
+                self.in_flight.insert(msg_id, (peer.clone(), delta.clone()));
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Gossip {
+                        msg_id,
+                        carts: delta,
+                    },
+                });
+            }
+            messages
+        }
+
+        fn handle_body(&mut self, src: &str, body: Body) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Add { msg_id, cart, item } => {
+                    self.carts.add(&self.id, cart, item);
+                    Body::AddOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Remove { msg_id, cart, item } => {
+                    // Removing an item that isn't in the cart is a no-op, not an error
+                    self.carts.remove(&cart, &item);
+                    Body::RemoveOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Clear { msg_id, cart } => {
+                    self.carts.clear(&cart);
+                    Body::ClearOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Read { msg_id, cart } => {
+                    let items = self.carts.get(&cart).into_iter().flat_map(|set| set.iter());
+                    let mut value: Vec<String> = items.cloned().collect();
+                    value.sort_unstable();
+                    Body::ReadOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        value,
+                    }
+                }
+                Body::Gossip { msg_id, carts } => {
+                    self.carts.merge(&carts);
+                    // Whatever it sent us, it has
+                    self.known.entry(src.to_string()).or_default().merge(&carts);
+                    Body::GossipOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::GossipOk { in_reply_to, .. } => {
+                    let (peer, sent) = self.in_flight.remove(&in_reply_to)?;
+                    self.known.entry(peer).or_default().merge(&sent);
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::Error { .. }
+                | Body::AddOk { .. }
+                | Body::RemoveOk { .. }
+                | Body::ClearOk { .. }
+                | Body::ReadOk { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, exchange};
+
+        fn init(id: &str) -> Node {
+            testing::init(Node::new(), id, 2)
+        }
+
+        fn send(node: &mut Node, body: Body) -> Body {
+            let dest = node.id.clone();
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body,
+            })
+            .remove(0)
+            .body
+        }
+
+        fn add(node: &mut Node, cart: &str, item: &str) {
+            let (cart, item) = (cart.to_string(), item.to_string());
+            send(
+                node,
+                Body::Add {
+                    msg_id: 2,
+                    cart,
+                    item,
+                },
+            );
+        }
+
+        fn remove(node: &mut Node, cart: &str, item: &str) {
+            let (cart, item) = (cart.to_string(), item.to_string());
+            send(
+                node,
+                Body::Remove {
+                    msg_id: 3,
+                    cart,
+                    item,
+                },
+            );
+        }
+
+        fn clear(node: &mut Node, cart: &str) {
+            let cart = cart.to_string();
+            send(node, Body::Clear { msg_id: 4, cart });
+        }
+
+        fn read(node: &mut Node, cart: &str) -> Vec<String> {
+            let cart = cart.to_string();
+            let Body::ReadOk { value, .. } = send(node, Body::Read { msg_id: 9, cart }) else {
+                panic!("expected read_ok");
+            };
+            value
+        }
+
+        #[test]
+        fn test_carts_converge_through_gossip() {
+            let (mut n1, mut n2) = (init("n1"), init("n2"));
+            add(&mut n1, "alice", "pear");
+            add(&mut n1, "alice", "apple");
+            add(&mut n2, "alice", "plum");
+            add(&mut n2, "bob", "fig");
+            remove(&mut n1, "alice", "pear");
+
+            exchange(&mut n1, &mut n2);
+            exchange(&mut n2, &mut n1);
+            for node in [&mut n1, &mut n2] {
+                assert_eq!(read(node, "alice"), vec!["apple", "plum"]);
+                assert_eq!(read(node, "bob"), vec!["fig"]);
+            }
+            // Both sides know the other is caught up, so there's nothing left to send
+            assert!(n1.gossip().is_empty());
+            assert!(n2.gossip().is_empty());
+        }
+
+        #[test]
+        fn test_concurrent_adds_survive_removes() {
+            let (mut n1, mut n2) = (init("n1"), init("n2"));
+            add(&mut n1, "alice", "pear");
+            add(&mut n1, "alice", "apple");
+            exchange(&mut n1, &mut n2);
+
+            // n2 takes out the pear and empties the cart, while n1 puts a pear and a plum in
+            remove(&mut n2, "alice", "pear");
+            clear(&mut n2, "alice");
+            add(&mut n1, "alice", "pear");
+            add(&mut n1, "alice", "plum");
+            exchange(&mut n1, &mut n2);
+            exchange(&mut n2, &mut n1);
+            assert_eq!(read(&mut n1, "alice"), vec!["pear", "plum"]);
+            assert_eq!(read(&mut n2, "alice"), vec!["pear", "plum"]);
+
+            // Clearing having seen everything empties it everywhere
+            clear(&mut n1, "alice");
+            exchange(&mut n1, &mut n2);
+            assert!(read(&mut n2, "alice").is_empty());
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::gossip(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,682 @@
+pub mod node {
+    use rand::seq::SliceRandom;
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{BTreeMap, HashMap};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// SWIM membership. Each protocol period a node pings one peer, taking them in a shuffled
+    /// round, and if no ack comes back in time asks a few others to ping it on its behalf, so
+    /// a single bad link doesn't get a node suspected. A peer nothing hears back from by the
+    /// end of the period is suspected, and declared dead if it doesn't refute that in time.
+    ///
+    /// Changes to membership aren't broadcast, but piggybacked on pings and acks a few times
+    /// each, so they spread like gossip. A node refutes a suspicion of itself by announcing
+    /// itself alive with a higher incarnation, which overrides anything said about an earlier
+    /// one, so a node wrongly declared dead comes back once it's heard from again.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        incarnation: u64,                  // Ours, bumped to refute suspicion of us
+        members: BTreeMap<String, Member>, // Everyone else, as we believe them to be
+        updates: HashMap<String, (Update, usize)>, // Left to piggyback, with how many more times
+        round: Vec<String>,                // Who's left to probe this round, last first
+        next_probe: Instant,
+        probe: Option<Probe>,
+        relays: HashMap<u64, Relay>, // Pings we sent for someone else, by msg_id
+    }
+
+    /// What we believe of a peer, and since when.
+    struct Member {
+        state: State,
+        incarnation: u64,
+        since: Instant,
+    }
+
+    /// The peer we're probing this period.
+    struct Probe {
+        target: String,
+        ids: Vec<u64>, // Our ping and ping_reqs, an ack to any of which will do
+        sent: Instant,
+        indirect: bool, // Whether we've asked others to ping it yet
+    }
+
+    /// A ping we sent on `requester`'s behalf, whose ack goes back as an answer to `msg_id`.
+    struct Relay {
+        requester: String,
+        msg_id: u64,
+        sent: Instant,
+    }
+
+    /// Ordered by which wins when two updates about the same incarnation disagree.
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+    #[serde(rename_all = "snake_case")]
+    enum State {
+        Alive,
+        Suspect,
+        Dead,
+    }
+
+    /// A change to a node's membership, as it's disseminated.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Update {
+        node: String,
+        state: State,
+        incarnation: u64,
+    }
+
+    /// Tunables, read from `SWIM_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between ticks, which start probes and time them and suspicions out.
+        pub tick_interval: Duration,
+        /// Time between probes, and how long a probe has to be answered before its target is
+        /// suspected.
+        pub protocol_period: Duration,
+        /// How long a ping goes unanswered before we ask others to ping its target for us.
+        pub ping_timeout: Duration,
+        /// How many others we ask.
+        pub indirect_probes: usize,
+        /// How long a suspected node has to refute it before it's declared dead.
+        pub suspicion_timeout: Duration,
+        /// Times each update is piggybacked, per doubling of the cluster's size.
+        pub retransmit_multiplier: usize,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                tick_interval: Duration::from_millis(50),
+                protocol_period: Duration::from_millis(500),
+                ping_timeout: Duration::from_millis(150),
+                indirect_probes: 3,
+                suspicion_timeout: Duration::from_millis(1500),
+                retransmit_multiplier: 3,
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                tick_interval: Duration::from_millis(env_or(
+                    "SWIM_TICK_INTERVAL_MS",
+                    default.tick_interval.as_millis() as u64,
+                )),
+                protocol_period: Duration::from_millis(env_or(
+                    "SWIM_PROTOCOL_PERIOD_MS",
+                    default.protocol_period.as_millis() as u64,
+                )),
+                ping_timeout: Duration::from_millis(env_or(
+                    "SWIM_PING_TIMEOUT_MS",
+                    default.ping_timeout.as_millis() as u64,
+                )),
+                indirect_probes: env_or("SWIM_INDIRECT_PROBES", default.indirect_probes),
+                suspicion_timeout: Duration::from_millis(env_or(
+                    "SWIM_SUSPICION_TIMEOUT_MS",
+                    default.suspicion_timeout.as_millis() as u64,
+                )),
+                retransmit_multiplier: env_or(
+                    "SWIM_RETRANSMIT_MULTIPLIER",
+                    default.retransmit_multiplier,
+                ),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+        },
+        /// Every node's state as we believe it, ourselves included.
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            members: BTreeMap<String, State>,
+        },
+        Ping {
+            msg_id: u64,
+            #[serde(default)]
+            updates: Vec<Update>,
+        },
+        /// Asks the recipient to ping `target`, and pass its ack back.
+        PingReq {
+            msg_id: u64,
+            target: String,
+            #[serde(default)]
+            updates: Vec<Update>,
+        },
+        Ack {
+            msg_id: u64,
+            in_reply_to: u64,
+            #[serde(default)]
+            updates: Vec<Update>,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                incarnation: 0,
+                members: BTreeMap::new(),
+                updates: HashMap::new(),
+                round: Vec::new(),
+                next_probe: Instant::now(),
+                probe: None,
+                relays: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "incarnation": self.incarnation,
+                "members": self.members.iter().map(|(id, member)| (id, format!("{:?}", member.state))).collect::<BTreeMap<_, _>>(),
+                "updates": self.updates.len(),
+                "round": self.round,
+                "probing": self.probe.as_ref().map(|probe| &probe.target),
+                "relays": self.relays.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            // Peers can finish their init and start probing before we get ours
+            let probe = matches!(
+                message.body,
+                Body::Ping { .. } | Body::PingReq { .. } | Body::Ack { .. }
+            );
+            if !self.initialized && probe {
+                return Vec::new();
+            }
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            // A node we've declared dead is told so, with what we reply, so it can refute it
+            if self
+                .members
+                .get(&message.src)
+                .is_some_and(|member| member.state == State::Dead)
+            {
+                self.declare(&message.src, State::Dead);
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Declares dead the suspects that haven't refuted it in time, then moves the probe
+        /// along: a ping that's gone unanswered gets others to try, a probe unanswered all
+        /// period gets its target suspected, and once a period is up the next one starts.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            let lapsed: Vec<String> = self
+                .members
+                .iter()
+                .filter(|(_, member)| member.state == State::Suspect)
+                .filter(|(_, member)| member.since.elapsed() >= self.config.suspicion_timeout)
+                .map(|(node, _)| node.clone())
+                .collect();
+            for node in lapsed {
+                self.declare(&node, State::Dead);
+            }
+
+            if let Some(probe) = &self.probe {
+                if probe.sent.elapsed() >= self.config.protocol_period {
+                    let target = probe.target.clone();
+                    self.probe = None;
+                    self.declare(&target, State::Suspect);
+                } else if !probe.indirect && probe.sent.elapsed() >= self.config.ping_timeout {
+                    self.ping_indirectly(&mut messages);
+                }
+            }
+            let period = self.config.protocol_period;
+            self.relays.retain(|_, relay| relay.sent.elapsed() < period);
+            if Instant::now() >= self.next_probe {
+                self.next_probe = Instant::now() + period;
+                self.start_probe(&mut messages);
+            }
+            messages
+        }
+
+        /// Pings the next peer this round, starting a new round, in a new order, once everyone's
+        /// had their turn. The dead are pinged too, so that once a partition heals, nodes on
+        /// either side hear from each other again.
+        fn start_probe(&mut self, messages: &mut Vec<Message>) {
+            if self.round.is_empty() {
+                self.round = self.members.keys().cloned().collect();
+                self.round.shuffle(&mut rand::thread_rng());
+            }
+            let Some(target) = self.round.pop() else {
+                return;
+            };
+            let msg_id = self.next_msg_id();
+            let updates = self.piggyback();
+            self.probe = Some(Probe {
+                target: target.clone(),
+                ids: vec![msg_id],
+                sent: Instant::now(),
+                indirect: false,
+            });
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: target,
+                body: Body::Ping { msg_id, updates },
+            });
+        }
+
+        /// Asks up to `indirect_probes` peers we think are alive to ping the probe's target.
+        fn ping_indirectly(&mut self, messages: &mut Vec<Message>) {
+            let Some(target) = self.probe.as_ref().map(|probe| probe.target.clone()) else {
+                return;
+            };
+            let alive: Vec<String> = self
+                .members
+                .iter()
+                .filter(|(node, member)| **node != target && member.state == State::Alive)
+                .map(|(node, _)| node.clone())
+                .collect();
+            let proxies: Vec<String> = alive
+                .choose_multiple(&mut rand::thread_rng(), self.config.indirect_probes)
+                .cloned()
+                .collect();
+            for proxy in proxies {
+                let msg_id = self.next_msg_id();
+                let updates = self.piggyback();
+                if let Some(probe) = &mut self.probe {
+                    probe.ids.push(msg_id);
+                    probe.indirect = true;
+                }
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: proxy,
+                    body: Body::PingReq {
+                        msg_id,
+                        target: target.clone(),
+                        updates,
+                    },
+                });
+            }
+        }
+
+        /// Updates a peer's state on our own authority, at the incarnation we know it by. An
+        /// update that's no news is still spread again.
+        fn declare(&mut self, node: &str, state: State) {
+            let Some(member) = self.members.get(node) else {
+                return;
+            };
+            let update = Update {
+                node: node.to_string(),
+                state,
+                incarnation: member.incarnation,
+            };
+            if member.state == state {
+                self.spread(update);
+            } else {
+                self.apply(update);
+            }
+        }
+
+        /// Takes in an update if it's newer than what we have, and passes it on. One about us
+        /// that isn't alive is refuted instead.
+        fn apply(&mut self, update: Update) {
+            if update.node == self.id {
+                if update.state != State::Alive && update.incarnation >= self.incarnation {
+                    self.incarnation = update.incarnation + 1;
+                    log::info!(
+                        "Refuting {:?} with incarnation {}",
+                        update.state,
+                        self.incarnation
+                    );
+                    self.spread(Update {
+                        node: self.id.clone(),
+                        state: State::Alive,
+                        incarnation: self.incarnation,
+                    });
+                }
+                return;
+            }
+            let Some(member) = self.members.get_mut(&update.node) else {
+                return;
+            };
+            if (update.incarnation, update.state) <= (member.incarnation, member.state) {
+                return;
+            }
+            log::info!(
+                "{} is {:?} at incarnation {}",
+                update.node,
+                update.state,
+                update.incarnation
+            );
+            member.state = update.state;
+            member.incarnation = update.incarnation;
+            member.since = Instant::now();
+            self.spread(update);
+        }
+
+        /// Queues an update to be piggybacked enough times to reach everyone, replacing any
+        /// older one about the same node.
+        fn spread(&mut self, update: Update) {
+            let cluster = self.members.len() + 1;
+            let doublings = cluster.next_power_of_two().trailing_zeros() as usize + 1;
+            let times = self.config.retransmit_multiplier * doublings;
+            self.updates.insert(update.node.clone(), (update, times));
+        }
+
+        /// The updates to send with a message, counting it against each.
+        fn piggyback(&mut self) -> Vec<Update> {
+            let mut updates: Vec<Update> = self
+                .updates
+                .values_mut()
+                .map(|(update, left)| {
+                    *left -= 1;
+                    update.clone()
+                })
+                .collect();
+            self.updates.retain(|_, (_, left)| *left > 0);
+            updates.sort_by(|a, b| a.node.cmp(&b.node));
+            updates
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            messages: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    let now = Instant::now();
+                    self.members = node_ids
+                        .into_iter()
+                        .filter(|id| *id != node_id)
+                        .map(|id| {
+                            let member = Member {
+                                state: State::Alive,
+                                incarnation: 0,
+                                since: now,
+                            };
+                            (id, member)
+                        })
+                        .collect();
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Read { msg_id } => {
+                    let mut members: BTreeMap<String, State> = self
+                        .members
+                        .iter()
+                        .map(|(node, member)| (node.clone(), member.state))
+                        .collect();
+                    members.insert(self.id.clone(), State::Alive);
+                    Some(Body::ReadOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        members,
+                    })
+                }
+                Body::Ping { msg_id, updates } => {
+                    for update in updates {
+                        self.apply(update);
+                    }
+                    Some(Body::Ack {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        updates: self.piggyback(),
+                    })
+                }
+                Body::PingReq {
+                    msg_id,
+                    target,
+                    updates,
+                } => {
+                    for update in updates {
+                        self.apply(update);
+                    }
+                    let ping_id = self.next_msg_id();
+                    let relay = Relay {
+                        requester: src.to_string(),
+                        msg_id,
+                        sent: Instant::now(),
+                    };
+                    self.relays.insert(ping_id, relay);
+                    messages.push(Message {
+                        src: self.id.clone(),
+                        dest: target,
+                        body: Body::Ping {
+                            msg_id: ping_id,
+                            updates: self.piggyback(),
+                        },
+                    });
+                    None
+                }
+                Body::Ack {
+                    in_reply_to,
+                    updates,
+                    ..
+                } => {
+                    for update in updates {
+                        self.apply(update);
+                    }
+                    if let Some(relay) = self.relays.remove(&in_reply_to) {
+                        let msg_id = self.next_msg_id();
+                        messages.push(Message {
+                            src: self.id.clone(),
+                            dest: relay.requester,
+                            body: Body::Ack {
+                                msg_id,
+                                in_reply_to: relay.msg_id,
+                                updates: self.piggyback(),
+                            },
+                        });
+                    } else if self
+                        .probe
+                        .as_ref()
+                        .is_some_and(|probe| probe.ids.contains(&in_reply_to))
+                    {
+                        self.probe = None;
+                    }
+                    None
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. } | Body::Error { .. } | Body::ReadOk { .. } => None,
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        /// n1 to n3, with `config`.
+        fn cluster(config: Config) -> Vec<Node> {
+            let node_ids: Vec<String> = (1..=3).map(|i| format!("n{}", i)).collect();
+            node_ids
+                .iter()
+                .map(|id| {
+                    let mut node = Node::new(config.clone());
+                    node.handle_message(Message {
+                        src: "c1".into(),
+                        dest: id.clone(),
+                        body: Body::Init {
+                            msg_id: 1,
+                            node_id: id.clone(),
+                            node_ids: node_ids.clone(),
+                        },
+                    });
+                    node
+                })
+                .collect()
+        }
+
+        /// Delivers `messages`, and everything sent in response, dropping whatever's sent
+        /// between the two nodes in `cut`.
+        fn deliver(nodes: &mut [Node], mut messages: Vec<Message>, cut: (&str, &str)) {
+            while !messages.is_empty() {
+                for message in std::mem::take(&mut messages) {
+                    let link = [message.src.as_str(), message.dest.as_str()];
+                    if link.contains(&cut.0) && link.contains(&cut.1) {
+                        continue;
+                    }
+                    let n: usize = message.dest[1..].parse().unwrap();
+                    messages.extend(nodes[n - 1].handle_message(message));
+                }
+            }
+        }
+
+        fn state(node: &Node, of: &str) -> State {
+            node.members[of].state
+        }
+
+        #[test]
+        fn test_others_ping_for_us_across_a_bad_link() {
+            let mut nodes = cluster(Config {
+                protocol_period: Duration::from_secs(3600),
+                ping_timeout: Duration::ZERO,
+                ..Config::default()
+            });
+            let ping = nodes[0].tick();
+            let target = ping[0].dest.clone();
+            let requests = nodes[0].tick();
+            assert!(matches!(requests[0].body, Body::PingReq { .. }));
+            assert_ne!(requests[0].dest, target);
+
+            // n1 can't reach its target itself, but the other node can
+            deliver(&mut nodes, requests, ("n1", &target));
+            assert!(nodes[0].probe.is_none());
+            assert_eq!(state(&nodes[0], &target), State::Alive);
+        }
+
+        #[test]
+        fn test_unanswered_nodes_are_suspected_then_declared_dead() {
+            let mut n1 = cluster(Config {
+                protocol_period: Duration::ZERO,
+                suspicion_timeout: Duration::from_secs(3600),
+                ..Config::default()
+            })
+            .remove(0);
+            let target = n1.tick()[0].dest.clone();
+            // Nothing answers, so the period ends with the target suspected, and another probed
+            let next = n1.tick();
+            assert_eq!(state(&n1, &target), State::Suspect);
+            assert_ne!(next[0].dest, target);
+            let Body::Ping { updates, .. } = &next[0].body else {
+                panic!("expected ping");
+            };
+            assert_eq!(updates[0].state, State::Suspect);
+
+            n1.config.suspicion_timeout = Duration::ZERO;
+            n1.tick();
+            assert_eq!(state(&n1, &target), State::Dead);
+        }
+
+        #[test]
+        fn test_suspicion_is_refuted() {
+            let mut nodes = cluster(Config::default());
+            nodes[2].declare("n1", State::Dead);
+            let updates = nodes[2].piggyback();
+            let ping = Message {
+                src: "n3".into(),
+                dest: "n1".into(),
+                body: Body::Ping { msg_id: 5, updates },
+            };
+            let ack = nodes[0].handle_message(ping);
+            assert_eq!(nodes[0].incarnation, 1);
+
+            // An alive at a later incarnation overrides even dead
+            nodes[2].handle_message(ack[0].clone());
+            assert_eq!(state(&nodes[2], "n1"), State::Alive);
+            assert_eq!(nodes[2].members["n1"].incarnation, 1);
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,469 @@
+pub mod node {
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{BTreeMap, HashMap};
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    /// Broadcasts delivered in the same order everywhere. Each broadcast is stamped with the
+    /// Lamport clock of the node it arrived at, and every node orders broadcasts by that
+    /// timestamp, then by node id to break ties.
+    ///
+    /// A broadcast is delivered once it's stable: every node has reported a clock at least as
+    /// late as its timestamp, along with everything it broadcast up to then, so nothing can
+    /// still turn up that sorts before it. Until then it waits, so reads only ever show a
+    /// prefix of the final order, and while any node is unreachable, that prefix stops growing.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        peers: Vec<String>,
+        clock: u64,
+        streams: HashMap<String, Vec<Stamped>>, // What each node has broadcast, ours included, in order
+        heard: HashMap<String, u64>,            // The latest clock each peer has reported
+        acked: HashMap<String, usize>,          // How much of our stream each peer has
+        pending: BTreeMap<(u64, String), usize>, // Broadcasts not yet stable, in delivery order
+        log: Vec<usize>,
+    }
+
+    /// Tunables, read from `TOTAL_ORDER_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between syncs with each peer.
+        pub sync_interval: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                sync_interval: Duration::from_millis(100),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                sync_interval: Duration::from_millis(env_or(
+                    "TOTAL_ORDER_SYNC_INTERVAL_MS",
+                    default.sync_interval.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    /// A broadcast and the timestamp it was given.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Stamped {
+        timestamp: u64,
+        message: usize,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Broadcast {
+            msg_id: u64,
+            message: usize,
+        },
+        BroadcastOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+        },
+        /// The delivered broadcasts, in the order every node delivers them.
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            messages: Vec<usize>,
+        },
+        /// Every node talks to every other, so the topology is ignored.
+        Topology {
+            msg_id: u64,
+        },
+        TopologyOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// The sender's broadcasts from index `from` on, and its clock once it had made them.
+        Sync {
+            msg_id: u64,
+            from: usize,
+            entries: Vec<Stamped>,
+            clock: u64,
+        },
+        /// How many of the sender's broadcasts we now have.
+        SyncOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            len: usize,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+    }
+
+    impl Default for Node {
+        fn default() -> Self {
+            Node::new()
+        }
+    }
+
+    impl Node {
+        pub fn new() -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                peers: Vec::new(),
+                clock: 0,
+                streams: HashMap::new(),
+                heard: HashMap::new(),
+                acked: HashMap::new(),
+                pending: BTreeMap::new(),
+                log: Vec::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "clock": self.clock,
+                "heard": self.heard,
+                "acked": self.acked,
+                "pending": self.pending.len(),
+                "log": self.log.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let Some(body) = self.handle_body(&message.src, message.body) else {
+                return Vec::new();
+            };
+            self.cur_id += 1;
+            vec![Message {
+                src: message.dest,
+                dest: message.src,
+                body,
+            }]
+        }
+
+        /// Sends each peer whatever of our stream it hasn't acknowledged, along with our
+        /// clock. This goes out even with nothing new, since peers can't deliver anything
+        /// until they've heard a late enough clock from us.
+        pub fn sync(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            for peer in self.peers.clone() {
+                let from = self.acked.get(&peer).copied().unwrap_or(0);
+                let entries = self.streams.get(&self.id).map_or(&[][..], |s| &s[from..]);
+                let entries = entries.to_vec();
+                let msg_id = self.next_msg_id();
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Sync {
+                        msg_id,
+                        from,
+                        entries,
+                        clock: self.clock,
+                    },
+                });
+            }
+            messages
+        }
+
+        /// Delivers every pending broadcast no node can still send anything to sort before.
+        fn deliver(&mut self) {
+            let stable = self
+                .peers
+                .iter()
+                .map(|peer| self.heard.get(peer).copied().unwrap_or(0))
+                .fold(self.clock, u64::min);
+            while let Some(entry) = self.pending.first_entry() {
+                if entry.key().0 > stable {
+                    break;
+                }
+                self.log.push(entry.remove());
+            }
+        }
+
+        fn handle_body(&mut self, src: &str, body: Body) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.peers = node_ids.into_iter().filter(|id| *id != node_id).collect();
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Broadcast { msg_id, message } => {
+                    self.clock += 1;
+                    let timestamp = self.clock;
+                    self.streams
+                        .entry(self.id.clone())
+                        .or_default()
+                        .push(Stamped { timestamp, message });
+                    self.pending.insert((timestamp, self.id.clone()), message);
+                    self.deliver();
+                    Body::BroadcastOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Read { msg_id } => Body::ReadOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: msg_id,
+                    messages: self.log.clone(),
+                },
+                Body::Topology { msg_id } => Body::TopologyOk {
+                    msg_id: self.cur_id,
+                    in_reply_to: msg_id,
+                },
+                Body::Sync {
+                    msg_id,
+                    from,
+                    entries,
+                    clock,
+                } => {
+                    self.clock = self.clock.max(clock);
+                    let stream = self.streams.entry(src.to_string()).or_default();
+                    // Anything after a gap would leave us thinking we had everything up to
+                    // `clock` when we don't, so it waits for a sync that fills the gap
+                    if from <= stream.len() {
+                        for entry in entries.into_iter().skip(stream.len() - from) {
+                            self.pending
+                                .insert((entry.timestamp, src.to_string()), entry.message);
+                            stream.push(entry);
+                        }
+                        let heard = self.heard.entry(src.to_string()).or_default();
+                        *heard = (*heard).max(clock);
+                    }
+                    let len = stream.len();
+                    self.deliver();
+                    Body::SyncOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        len,
+                    }
+                }
+                Body::SyncOk { len, .. } => {
+                    let acked = self.acked.entry(src.to_string()).or_default();
+                    *acked = (*acked).max(len);
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. }
+                | Body::Error { .. }
+                | Body::BroadcastOk { .. }
+                | Body::ReadOk { .. }
+                | Body::TopologyOk { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn cluster(count: usize) -> Vec<Node> {
+            let ids: Vec<String> = (1..=count).map(|i| format!("n{}", i)).collect();
+            ids.iter()
+                .map(|id| {
+                    let mut node = Node::new();
+                    node.handle_message(Message {
+                        src: "c1".into(),
+                        dest: id.clone(),
+                        body: Body::Init {
+                            msg_id: 1,
+                            node_id: id.clone(),
+                            node_ids: ids.clone(),
+                        },
+                    });
+                    node
+                })
+                .collect()
+        }
+
+        fn send(node: &mut Node, body: Body) -> Body {
+            let dest = node.id.clone();
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body,
+            })
+            .remove(0)
+            .body
+        }
+
+        fn broadcast(node: &mut Node, message: usize) {
+            let body = send(node, Body::Broadcast { msg_id: 2, message });
+            assert!(matches!(body, Body::BroadcastOk { .. }));
+        }
+
+        fn read(node: &mut Node) -> Vec<usize> {
+            let Body::ReadOk { messages, .. } = send(node, Body::Read { msg_id: 9 }) else {
+                panic!("expected read_ok");
+            };
+            messages
+        }
+
+        /// Delivers every message until none are left, last sent first, so later syncs
+        /// overtake earlier ones.
+        fn deliver(nodes: &mut [Node], mut messages: Vec<Message>) {
+            while let Some(message) = messages.pop() {
+                let to = nodes.iter_mut().find(|n| n.id == message.dest).unwrap();
+                messages.extend(to.handle_message(message));
+            }
+        }
+
+        fn round(nodes: &mut [Node]) {
+            let syncs: Vec<Message> = nodes.iter_mut().flat_map(|n| n.sync()).collect();
+            deliver(nodes, syncs);
+        }
+
+        #[test]
+        fn test_every_node_delivers_the_same_order() {
+            let mut nodes = cluster(3);
+            broadcast(&mut nodes[2], 30);
+            broadcast(&mut nodes[0], 10);
+            broadcast(&mut nodes[0], 11);
+            round(&mut nodes);
+            broadcast(&mut nodes[1], 20);
+            round(&mut nodes);
+            round(&mut nodes);
+
+            // Timestamp first, then node id
+            let expected = vec![10, 30, 11, 20];
+            for node in &mut nodes {
+                assert_eq!(read(node), expected);
+                assert!(node.pending.is_empty());
+            }
+        }
+
+        #[test]
+        fn test_nothing_is_delivered_until_every_node_is_heard() {
+            let mut nodes = cluster(3);
+            broadcast(&mut nodes[0], 1);
+            assert_eq!(read(&mut nodes[0]), Vec::<usize>::new());
+
+            // n3 is cut off, so n1 and n2 can't know it won't broadcast something earlier
+            let syncs: Vec<Message> = nodes[..2]
+                .iter_mut()
+                .flat_map(|n| n.sync())
+                .filter(|m| m.dest != "n3")
+                .collect();
+            deliver(&mut nodes, syncs);
+            assert_eq!(read(&mut nodes[1]), Vec::<usize>::new());
+
+            round(&mut nodes);
+            round(&mut nodes);
+            for node in &mut nodes {
+                assert_eq!(read(node), vec![1]);
+            }
+        }
+
+        #[test]
+        fn test_a_single_node_delivers_at_once() {
+            let mut nodes = cluster(1);
+            broadcast(&mut nodes[0], 5);
+            broadcast(&mut nodes[0], 6);
+            assert_eq!(read(&mut nodes[0]), vec![5, 6]);
+        }
+
+        #[test]
+        fn test_syncs_past_a_gap_wait() {
+            let mut nodes = cluster(2);
+            broadcast(&mut nodes[0], 1);
+            broadcast(&mut nodes[0], 2);
+            let mut sync = nodes[0].sync().remove(0);
+            let Body::Sync {
+                ref mut from,
+                ref mut entries,
+                ..
+            } = sync.body
+            else {
+                panic!("expected sync");
+            };
+            *from = 1;
+            entries.remove(0);
+            let Body::SyncOk { len, .. } = nodes[1].handle_message(sync).remove(0).body else {
+                panic!("expected sync_ok");
+            };
+            assert_eq!(len, 0);
+            assert!(nodes[1].pending.is_empty());
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::sync(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,9 @@
+use std::error::Error;
+use total_order_broadcast::node;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    logging::init()?;
+    let config = node::Config::from_env();
+    maelstrom::run(node::Node::new(), config.sync_interval).await
+}
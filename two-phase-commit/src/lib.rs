@@ -0,0 +1,1026 @@
+pub mod node {
+    use maelstrom::error::{TIMEOUT, TXN_CONFLICT};
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::{HashMap, HashSet};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// Transactions over registers sharded across every node, committed atomically by
+    /// two-phase commit. The node with the lowest id coordinates every transaction, and the
+    /// others forward clients' transactions to it. Every node, the coordinator included, is
+    /// a participant owning the keys that map to it.
+    ///
+    /// The coordinator asks each participant a transaction touches to prepare its part. A
+    /// participant votes yes by locking its keys, reading, and logging what it'll write; if
+    /// any key is locked by another transaction it votes no instead, rather than wait and
+    /// risk deadlock. The transaction commits only if every vote is yes, and the coordinator
+    /// logs that decision before telling anyone, including the client.
+    ///
+    /// Both roles log each step before acting on it, and everything else they hold is
+    /// rebuilt from those logs, so a node that restarted with its log intact would carry on:
+    /// the coordinator resends decisions until they're acknowledged and aborts whatever it
+    /// hadn't decided, and a participant left prepared asks the coordinator how things ended.
+    /// Maelstrom gives nodes nowhere durable to keep a log, so here they're only in memory.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        nodes: Vec<String>, // Every node, sorted, so all agree on the coordinator and owners
+        forwards: HashMap<u64, Waiting>, // Transactions sent to the coordinator, by msg_id
+        // As coordinator
+        coordinator_log: Vec<CoordinatorRecord>,
+        next_txn: u64,
+        txns: HashMap<u64, Coordinating>, // Transactions not yet acknowledged everywhere
+        decisions: HashMap<u64, bool>,    // Whether each decided transaction committed
+        // As participant
+        participant_log: Vec<ParticipantRecord>,
+        values: HashMap<u64, i64>,
+        locks: HashMap<u64, u64>, // The transaction holding each locked key
+        prepared: HashMap<u64, Prepared>, // Transactions voted for but not yet decided
+        outcomes: HashMap<u64, bool>, // Whether each finished transaction committed
+    }
+
+    /// A client's transaction, forwarded to the coordinator.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant,
+    }
+
+    /// A transaction the coordinator is seeing through.
+    struct Coordinating {
+        client: String,
+        client_msg_id: u64,
+        txn: Vec<Op>,
+        participants: Vec<String>,
+        votes: HashMap<String, Vec<Op>>, // Each yes vote, with the participant's reads
+        decision: Option<bool>,
+        acked: HashSet<String>, // Participants that have the decision
+        deadline: Instant,      // When it's aborted if still undecided
+    }
+
+    /// A participant's part of a transaction it's voted yes to.
+    struct Prepared {
+        txn: Vec<Op>,
+        status_due: Instant, // When to ask the coordinator how it ended
+    }
+
+    /// What the coordinator logs before acting on it.
+    #[derive(Debug, Clone, PartialEq)]
+    enum CoordinatorRecord {
+        Began {
+            txn_id: u64,
+            client: String,
+            client_msg_id: u64,
+            txn: Vec<Op>,
+            participants: Vec<String>,
+        },
+        Decided {
+            txn_id: u64,
+            commit: bool,
+        },
+        /// Every participant has the decision.
+        Ended {
+            txn_id: u64,
+        },
+    }
+
+    /// What a participant logs before acting on it.
+    #[derive(Debug, Clone, PartialEq)]
+    enum ParticipantRecord {
+        /// Our part of the transaction, with its reads filled in.
+        Prepared {
+            txn_id: u64,
+            txn: Vec<Op>,
+        },
+        Committed {
+            txn_id: u64,
+        },
+        Aborted {
+            txn_id: u64,
+        },
+    }
+
+    /// One micro-op of a transaction: `["r", key, null]`, answered with the value read, or
+    /// `["w", key, value]`.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Op(OpKind, u64, Option<i64>);
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum OpKind {
+        R,
+        W,
+    }
+
+    /// Tunables, read from `TWO_PC_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between ticks, which retry messages and time out transactions.
+        pub tick_interval: Duration,
+        /// How long the coordinator waits for votes before aborting.
+        pub prepare_timeout: Duration,
+        /// How long a prepared participant waits for a decision before asking for it.
+        pub status_timeout: Duration,
+        /// How long a forwarded transaction waits for the coordinator's answer.
+        pub request_timeout: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                tick_interval: Duration::from_millis(100),
+                prepare_timeout: Duration::from_millis(1000),
+                status_timeout: Duration::from_millis(1000),
+                request_timeout: Duration::from_millis(2000),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            let millis = |name, default: Duration| {
+                Duration::from_millis(env_or(name, default.as_millis() as u64))
+            };
+            Config {
+                tick_interval: millis("TWO_PC_TICK_INTERVAL_MS", default.tick_interval),
+                prepare_timeout: millis("TWO_PC_PREPARE_TIMEOUT_MS", default.prepare_timeout),
+                status_timeout: millis("TWO_PC_STATUS_TIMEOUT_MS", default.status_timeout),
+                request_timeout: millis("TWO_PC_REQUEST_TIMEOUT_MS", default.request_timeout),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Txn {
+            msg_id: u64,
+            txn: Vec<Op>,
+        },
+        /// The transaction with every read filled in.
+        TxnOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            txn: Vec<Op>,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// The ops of a transaction on keys the recipient owns.
+        Prepare {
+            msg_id: u64,
+            txn_id: u64,
+            txn: Vec<Op>,
+        },
+        /// The participant's vote, with its reads filled in if it's yes.
+        PrepareOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            txn_id: u64,
+            vote: bool,
+            txn: Vec<Op>,
+        },
+        Commit {
+            msg_id: u64,
+            txn_id: u64,
+        },
+        CommitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            txn_id: u64,
+        },
+        Abort {
+            msg_id: u64,
+            txn_id: u64,
+        },
+        AbortOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            txn_id: u64,
+        },
+        /// A prepared participant asking how a transaction ended, answered with a `Commit`
+        /// or `Abort`.
+        Status {
+            msg_id: u64,
+            txn_id: u64,
+        },
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                nodes: Vec::new(),
+                forwards: HashMap::new(),
+                coordinator_log: Vec::new(),
+                next_txn: 1,
+                txns: HashMap::new(),
+                decisions: HashMap::new(),
+                participant_log: Vec::new(),
+                values: HashMap::new(),
+                locks: HashMap::new(),
+                prepared: HashMap::new(),
+                outcomes: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "nodes": self.nodes,
+                "forwards": self.forwards.len(),
+                "txns": self.txns.len(),
+                "decisions": self.decisions.len(),
+                "prepared": self.prepared.len(),
+                "locks": self.locks.len(),
+                "values": self.values.len(),
+                "coordinator_log": self.coordinator_log.len(),
+                "participant_log": self.participant_log.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut outbox = Vec::new();
+            self.respond(message, &mut outbox);
+            self.route(outbox)
+        }
+
+        /// Handles a message, queuing whatever it sends, its reply first.
+        fn respond(&mut self, message: Message, outbox: &mut Vec<Message>) {
+            let start = outbox.len();
+            if let Some(body) = self.handle_body(&message.src, message.body, outbox) {
+                outbox.insert(
+                    start,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+        }
+
+        /// Handles every message we've sent ourselves, since the coordinator is a participant
+        /// too, and returns the rest.
+        fn route(&mut self, mut outbox: Vec<Message>) -> Vec<Message> {
+            let mut sent = Vec::new();
+            while !outbox.is_empty() {
+                let mut more = Vec::new();
+                for message in outbox {
+                    if message.dest == self.id {
+                        self.respond(message, &mut more);
+                    } else {
+                        sent.push(message);
+                    }
+                }
+                outbox = more;
+            }
+            sent
+        }
+
+        /// Retries whatever hasn't been answered, and gives up on what's waited too long.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let now = Instant::now();
+            let mut outbox = Vec::new();
+
+            let mut txn_ids: Vec<u64> = self.txns.keys().copied().collect();
+            txn_ids.sort_unstable();
+            for txn_id in txn_ids {
+                let txn = &self.txns[&txn_id];
+                match txn.decision {
+                    None if txn.deadline <= now => {
+                        log::info!("Aborting txn {}, still waiting on votes", txn_id);
+                        self.decide(txn_id, false, &mut outbox);
+                    }
+                    None => {
+                        let waiting: Vec<String> = txn
+                            .participants
+                            .iter()
+                            .filter(|p| !txn.votes.contains_key(*p))
+                            .cloned()
+                            .collect();
+                        for participant in waiting {
+                            self.send_prepare(txn_id, participant, &mut outbox);
+                        }
+                    }
+                    Some(commit) => {
+                        let waiting: Vec<String> = txn
+                            .participants
+                            .iter()
+                            .filter(|p| !txn.acked.contains(*p))
+                            .cloned()
+                            .collect();
+                        for participant in waiting {
+                            self.send_decision(txn_id, commit, participant, &mut outbox);
+                        }
+                    }
+                }
+            }
+
+            let due: Vec<u64> = self
+                .prepared
+                .iter()
+                .filter(|(_, prepared)| prepared.status_due <= now)
+                .map(|(txn_id, _)| *txn_id)
+                .collect();
+            for txn_id in due {
+                if let Some(prepared) = self.prepared.get_mut(&txn_id) {
+                    prepared.status_due = now + self.config.status_timeout;
+                }
+                let msg_id = self.next_msg_id();
+                outbox.push(Message {
+                    src: self.id.clone(),
+                    dest: self.coordinator().to_string(),
+                    body: Body::Status { msg_id, txn_id },
+                });
+            }
+
+            let expired: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            for msg_id in expired {
+                let waiting = self.forwards.remove(&msg_id).unwrap();
+                outbox.push(Message {
+                    src: self.id.clone(),
+                    dest: waiting.client,
+                    body: Body::Error {
+                        in_reply_to: waiting.msg_id,
+                        code: TIMEOUT,
+                        text: "timed out waiting on the coordinator".to_string(),
+                    },
+                });
+            }
+            self.route(outbox)
+        }
+
+        fn coordinator(&self) -> &str {
+            &self.nodes[0]
+        }
+
+        /// The node that owns `key`.
+        fn owner(&self, key: u64) -> &str {
+            &self.nodes[(key % self.nodes.len() as u64) as usize]
+        }
+
+        fn log_coordinator(&mut self, record: CoordinatorRecord) {
+            self.replay_coordinator(&record);
+            self.coordinator_log.push(record);
+        }
+
+        /// Applies a coordinator record to everything that's rebuilt from the log.
+        fn replay_coordinator(&mut self, record: &CoordinatorRecord) {
+            match record {
+                CoordinatorRecord::Began {
+                    txn_id,
+                    client,
+                    client_msg_id,
+                    txn,
+                    participants,
+                } => {
+                    self.next_txn = self.next_txn.max(txn_id + 1);
+                    self.txns.insert(
+                        *txn_id,
+                        Coordinating {
+                            client: client.clone(),
+                            client_msg_id: *client_msg_id,
+                            txn: txn.clone(),
+                            participants: participants.clone(),
+                            votes: HashMap::new(),
+                            decision: None,
+                            acked: HashSet::new(),
+                            deadline: Instant::now() + self.config.prepare_timeout,
+                        },
+                    );
+                }
+                CoordinatorRecord::Decided { txn_id, commit } => {
+                    self.decisions.insert(*txn_id, *commit);
+                    if let Some(txn) = self.txns.get_mut(txn_id) {
+                        txn.decision = Some(*commit);
+                    }
+                }
+                CoordinatorRecord::Ended { txn_id } => {
+                    self.txns.remove(txn_id);
+                }
+            }
+        }
+
+        fn log_participant(&mut self, record: ParticipantRecord) {
+            self.replay_participant(&record);
+            self.participant_log.push(record);
+        }
+
+        /// Applies a participant record to everything that's rebuilt from the log.
+        fn replay_participant(&mut self, record: &ParticipantRecord) {
+            match record {
+                ParticipantRecord::Prepared { txn_id, txn } => {
+                    for Op(_, key, _) in txn {
+                        self.locks.insert(*key, *txn_id);
+                    }
+                    let prepared = Prepared {
+                        txn: txn.clone(),
+                        status_due: Instant::now() + self.config.status_timeout,
+                    };
+                    self.prepared.insert(*txn_id, prepared);
+                }
+                ParticipantRecord::Committed { txn_id } => {
+                    if let Some(prepared) = self.prepared.remove(txn_id) {
+                        for Op(kind, key, value) in prepared.txn {
+                            if let (OpKind::W, Some(value)) = (kind, value) {
+                                self.values.insert(key, value);
+                            }
+                        }
+                    }
+                    self.locks.retain(|_, holder| holder != txn_id);
+                    self.outcomes.insert(*txn_id, true);
+                }
+                ParticipantRecord::Aborted { txn_id } => {
+                    self.prepared.remove(txn_id);
+                    self.locks.retain(|_, holder| holder != txn_id);
+                    self.outcomes.insert(*txn_id, false);
+                }
+            }
+        }
+
+        /// Starts coordinating a client's transaction.
+        fn begin(
+            &mut self,
+            client: &str,
+            client_msg_id: u64,
+            txn: Vec<Op>,
+            outbox: &mut Vec<Message>,
+        ) {
+            let txn_id = self.next_txn;
+            let mut participants: Vec<String> = Vec::new();
+            for Op(_, key, _) in &txn {
+                let owner = self.owner(*key).to_string();
+                if !participants.contains(&owner) {
+                    participants.push(owner);
+                }
+            }
+            self.log_coordinator(CoordinatorRecord::Began {
+                txn_id,
+                client: client.to_string(),
+                client_msg_id,
+                txn,
+                participants: participants.clone(),
+            });
+            if participants.is_empty() {
+                return self.decide(txn_id, true, outbox);
+            }
+            for participant in participants {
+                self.send_prepare(txn_id, participant, outbox);
+            }
+        }
+
+        fn send_prepare(&mut self, txn_id: u64, participant: String, outbox: &mut Vec<Message>) {
+            let txn = self.txns[&txn_id]
+                .txn
+                .iter()
+                .filter(|Op(_, key, _)| self.owner(*key) == participant)
+                .cloned()
+                .collect();
+            let msg_id = self.next_msg_id();
+            outbox.push(Message {
+                src: self.id.clone(),
+                dest: participant,
+                body: Body::Prepare {
+                    msg_id,
+                    txn_id,
+                    txn,
+                },
+            });
+        }
+
+        /// Logs the decision, then answers the client and tells every participant.
+        fn decide(&mut self, txn_id: u64, commit: bool, outbox: &mut Vec<Message>) {
+            self.log_coordinator(CoordinatorRecord::Decided { txn_id, commit });
+            let txn = &self.txns[&txn_id];
+            let (client, client_msg_id) = (txn.client.clone(), txn.client_msg_id);
+            let participants = txn.participants.clone();
+            let body = if commit {
+                // Each participant's reads, back in the order the client sent them
+                let mut votes: HashMap<&str, std::slice::Iter<Op>> = txn
+                    .votes
+                    .iter()
+                    .map(|(participant, ops)| (participant.as_str(), ops.iter()))
+                    .collect();
+                let done = txn
+                    .txn
+                    .iter()
+                    .map(|op| {
+                        let ops = votes.get_mut(self.owner(op.1));
+                        ops.and_then(|ops| ops.next()).unwrap_or(op).clone()
+                    })
+                    .collect();
+                Body::TxnOk {
+                    msg_id: self.next_msg_id(),
+                    in_reply_to: client_msg_id,
+                    txn: done,
+                }
+            } else {
+                Body::Error {
+                    in_reply_to: client_msg_id,
+                    code: TXN_CONFLICT,
+                    text: format!("txn {} aborted", txn_id),
+                }
+            };
+            outbox.push(Message {
+                src: self.id.clone(),
+                dest: client,
+                body,
+            });
+            for participant in participants {
+                self.send_decision(txn_id, commit, participant, outbox);
+            }
+        }
+
+        fn send_decision(
+            &mut self,
+            txn_id: u64,
+            commit: bool,
+            participant: String,
+            outbox: &mut Vec<Message>,
+        ) {
+            let msg_id = self.next_msg_id();
+            let body = match commit {
+                true => Body::Commit { msg_id, txn_id },
+                false => Body::Abort { msg_id, txn_id },
+            };
+            outbox.push(Message {
+                src: self.id.clone(),
+                dest: participant,
+                body,
+            });
+        }
+
+        /// Votes on our part of a transaction, preparing it if we can.
+        fn prepare(&mut self, txn_id: u64, txn: Vec<Op>) -> (bool, Vec<Op>) {
+            if let Some(prepared) = self.prepared.get(&txn_id) {
+                return (true, prepared.txn.clone());
+            }
+            if let Some(outcome) = self.outcomes.get(&txn_id) {
+                return (*outcome, Vec::new());
+            }
+            let locked = txn
+                .iter()
+                .any(|Op(_, key, _)| self.locks.get(key).is_some_and(|holder| *holder != txn_id));
+            if locked {
+                // Logged, so a resent prepare gets the same answer
+                self.log_participant(ParticipantRecord::Aborted { txn_id });
+                return (false, Vec::new());
+            }
+            let mut written: HashMap<u64, i64> = HashMap::new();
+            let txn: Vec<Op> = txn
+                .into_iter()
+                .map(|Op(kind, key, value)| match (kind, value) {
+                    (OpKind::R, _) => {
+                        let value = written.get(&key).or(self.values.get(&key));
+                        Op(kind, key, value.copied())
+                    }
+                    (OpKind::W, Some(value)) => {
+                        written.insert(key, value);
+                        Op(kind, key, Some(value))
+                    }
+                    (OpKind::W, None) => {
+                        log::warn!("Ignoring a write of nothing to {}", key);
+                        Op(kind, key, None)
+                    }
+                })
+                .collect();
+            self.log_participant(ParticipantRecord::Prepared {
+                txn_id,
+                txn: txn.clone(),
+            });
+            (true, txn)
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    mut node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    node_ids.sort();
+                    self.nodes = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    }
+                }
+                Body::Txn { msg_id, txn } if self.id == self.coordinator() => {
+                    self.begin(src, msg_id, txn, outbox);
+                    return None;
+                }
+                Body::Txn { msg_id, txn } => {
+                    let forward_id = self.next_msg_id();
+                    self.forwards.insert(
+                        forward_id,
+                        Waiting {
+                            client: src.to_string(),
+                            msg_id,
+                            deadline: Instant::now() + self.config.request_timeout,
+                        },
+                    );
+                    outbox.push(Message {
+                        src: self.id.clone(),
+                        dest: self.coordinator().to_string(),
+                        body: Body::Txn {
+                            msg_id: forward_id,
+                            txn,
+                        },
+                    });
+                    return None;
+                }
+                // The coordinator's answer to a transaction we forwarded
+                Body::TxnOk {
+                    in_reply_to, txn, ..
+                } => {
+                    let waiting = self.forwards.remove(&in_reply_to)?;
+                    let msg_id = self.next_msg_id();
+                    outbox.push(Message {
+                        src: self.id.clone(),
+                        dest: waiting.client,
+                        body: Body::TxnOk {
+                            msg_id,
+                            in_reply_to: waiting.msg_id,
+                            txn,
+                        },
+                    });
+                    return None;
+                }
+                Body::Error {
+                    in_reply_to,
+                    code,
+                    text,
+                } => {
+                    let waiting = self.forwards.remove(&in_reply_to)?;
+                    outbox.push(Message {
+                        src: self.id.clone(),
+                        dest: waiting.client,
+                        body: Body::Error {
+                            in_reply_to: waiting.msg_id,
+                            code,
+                            text,
+                        },
+                    });
+                    return None;
+                }
+                Body::Prepare {
+                    msg_id,
+                    txn_id,
+                    txn,
+                } => {
+                    let (vote, txn) = self.prepare(txn_id, txn);
+                    Body::PrepareOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        txn_id,
+                        vote,
+                        txn,
+                    }
+                }
+                Body::PrepareOk {
+                    txn_id, vote, txn, ..
+                } => {
+                    let coordinating = self.txns.get_mut(&txn_id)?;
+                    if coordinating.decision.is_some() {
+                        return None;
+                    }
+                    if !vote {
+                        self.decide(txn_id, false, outbox);
+                        return None;
+                    }
+                    coordinating.votes.insert(src.to_string(), txn);
+                    if coordinating.votes.len() == coordinating.participants.len() {
+                        self.decide(txn_id, true, outbox);
+                    }
+                    return None;
+                }
+                Body::Commit { msg_id, txn_id } => {
+                    if self.prepared.contains_key(&txn_id) {
+                        self.log_participant(ParticipantRecord::Committed { txn_id });
+                    }
+                    Body::CommitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        txn_id,
+                    }
+                }
+                Body::Abort { msg_id, txn_id } => {
+                    if !self.outcomes.contains_key(&txn_id) {
+                        self.log_participant(ParticipantRecord::Aborted { txn_id });
+                    }
+                    Body::AbortOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                        txn_id,
+                    }
+                }
+                Body::CommitOk { txn_id, .. } | Body::AbortOk { txn_id, .. } => {
+                    let coordinating = self.txns.get_mut(&txn_id)?;
+                    coordinating.acked.insert(src.to_string());
+                    if coordinating.acked.len() == coordinating.participants.len() {
+                        self.log_coordinator(CoordinatorRecord::Ended { txn_id });
+                    }
+                    return None;
+                }
+                Body::Status { txn_id, .. } => {
+                    let commit = match self.decisions.get(&txn_id) {
+                        Some(commit) => *commit,
+                        // Still undecided, and with one participant tired of waiting, so
+                        // abort it; that tells everyone
+                        None if self.txns.contains_key(&txn_id) => {
+                            self.decide(txn_id, false, outbox);
+                            return None;
+                        }
+                        // Nothing logged means it never began, so it can't have committed
+                        None => false,
+                    };
+                    match commit {
+                        true => Body::Commit {
+                            msg_id: self.cur_id,
+                            txn_id,
+                        },
+                        false => Body::Abort {
+                            msg_id: self.cur_id,
+                            txn_id,
+                        },
+                    }
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn cluster(count: usize, config: Config) -> Vec<Node> {
+            let ids: Vec<String> = (1..=count).map(|i| format!("n{}", i)).collect();
+            ids.iter()
+                .map(|id| {
+                    let mut node = Node::new(config.clone());
+                    node.handle_message(Message {
+                        src: "c1".into(),
+                        dest: id.clone(),
+                        body: Body::Init {
+                            msg_id: 1,
+                            node_id: id.clone(),
+                            node_ids: ids.clone(),
+                        },
+                    });
+                    node
+                })
+                .collect()
+        }
+
+        /// Delivers messages between nodes until none are left, returning what's sent to
+        /// clients.
+        fn deliver(nodes: &mut [Node], mut messages: Vec<Message>) -> Vec<Body> {
+            let mut replies = Vec::new();
+            while !messages.is_empty() {
+                let message = messages.remove(0);
+                match nodes.iter_mut().find(|n| n.id == message.dest) {
+                    Some(node) => messages.extend(node.handle_message(message)),
+                    None => replies.push(message.body),
+                }
+            }
+            replies
+        }
+
+        fn ops(txn: &str) -> Vec<Op> {
+            serde_json::from_str(txn).unwrap()
+        }
+
+        fn txn_message(to: &Node, msg_id: u64, txn: &str) -> Message {
+            Message {
+                src: "c1".into(),
+                dest: to.id.clone(),
+                body: Body::Txn {
+                    msg_id,
+                    txn: ops(txn),
+                },
+            }
+        }
+
+        /// Runs a transaction at `nodes[at]` to completion.
+        fn txn(nodes: &mut [Node], at: usize, txn: &str) -> Body {
+            let message = txn_message(&nodes[at], 2, txn);
+            deliver(nodes, vec![message]).remove(0)
+        }
+
+        /// Drops what a node remembers outside its logs and rebuilds it from them, as a
+        /// restart with the logs on disk would.
+        fn restart(node: &mut Node) {
+            node.txns.clear();
+            node.decisions.clear();
+            node.values.clear();
+            node.locks.clear();
+            node.prepared.clear();
+            node.outcomes.clear();
+            node.forwards.clear();
+            for record in node.coordinator_log.clone() {
+                node.replay_coordinator(&record);
+            }
+            for record in node.participant_log.clone() {
+                node.replay_participant(&record);
+            }
+        }
+
+        #[test]
+        fn test_txns_commit_across_participants() {
+            let mut nodes = cluster(3, Config::default());
+            let reply = txn(
+                &mut nodes,
+                1,
+                r#"[["w", 1, 10], ["w", 2, 20], ["w", 3, 30]]"#,
+            );
+            assert!(matches!(reply, Body::TxnOk { .. }), "{:?}", reply);
+            assert_eq!(nodes[0].values[&3], 30);
+            assert_eq!(nodes[1].values[&1], 10);
+            assert_eq!(nodes[2].values[&2], 20);
+
+            let Body::TxnOk { txn: done, .. } = txn(
+                &mut nodes,
+                2,
+                r#"[["r", 3, null], ["w", 1, 11], ["r", 1, null], ["r", 2, null]]"#,
+            ) else {
+                panic!("expected txn_ok");
+            };
+            assert_eq!(
+                done,
+                ops(r#"[["r", 3, 30], ["w", 1, 11], ["r", 1, 11], ["r", 2, 20]]"#)
+            );
+            // Everything's acknowledged, so nothing's left to retry
+            assert!(nodes[0].txns.is_empty());
+            assert!(nodes.iter_mut().all(|node| node.tick().is_empty()));
+        }
+
+        #[test]
+        fn test_conflicting_txns_abort() {
+            let mut nodes = cluster(2, Config::default());
+            // Both prepare at once, so whichever n2 sees second finds key 1 locked
+            let first = txn_message(&nodes[0], 2, r#"[["w", 1, 1], ["w", 2, 1]]"#);
+            let second = txn_message(&nodes[0], 3, r#"[["w", 1, 2]]"#);
+            let mut prepares = nodes[0].handle_message(first);
+            prepares.extend(nodes[0].handle_message(second));
+            let replies = deliver(&mut nodes, prepares);
+            let codes: Vec<Option<u64>> = replies
+                .iter()
+                .map(|body| match body {
+                    Body::Error { code, .. } => Some(*code),
+                    _ => None,
+                })
+                .collect();
+            assert_eq!(codes, vec![None, Some(TXN_CONFLICT)]);
+            assert_eq!(nodes[1].values[&1], 1);
+            assert!(nodes[1].locks.is_empty());
+        }
+
+        #[test]
+        fn test_coordinator_recovers_from_its_log() {
+            let mut nodes = cluster(2, Config::default());
+            // The commit to n2 is lost, and then n1 restarts
+            let message = txn_message(&nodes[0], 2, r#"[["w", 1, 5]]"#);
+            let prepare = nodes[0].handle_message(message);
+            let mut sent = nodes[1].handle_message(prepare[0].clone());
+            let sent = nodes[0].handle_message(sent.remove(0));
+            assert!(matches!(sent[0].body, Body::TxnOk { .. }));
+            restart(&mut nodes[0]);
+
+            // It resends the commit, not an abort, since it logged the decision
+            let retries = nodes[0].tick();
+            assert!(matches!(
+                retries[..],
+                [Message {
+                    body: Body::Commit { .. },
+                    ..
+                }]
+            ));
+            deliver(&mut nodes, retries);
+            assert_eq!(nodes[1].values[&1], 5);
+            assert!(nodes[0].txns.is_empty());
+        }
+
+        #[test]
+        fn test_prepared_participants_learn_the_outcome() {
+            let config = Config {
+                status_timeout: Duration::ZERO,
+                ..Config::default()
+            };
+            let mut nodes = cluster(2, config);
+            // n2 votes yes, but the vote is lost and n1 restarts before deciding
+            let message = txn_message(&nodes[0], 2, r#"[["w", 1, 5]]"#);
+            let prepare = nodes[0].handle_message(message);
+            nodes[1].handle_message(prepare[0].clone());
+            restart(&mut nodes[0]);
+            restart(&mut nodes[1]);
+            assert!(nodes[1].locks.contains_key(&1));
+
+            // n2 asks, which aborts it everywhere
+            let status = nodes[1].tick();
+            assert!(matches!(
+                status[..],
+                [Message {
+                    body: Body::Status { .. },
+                    ..
+                }]
+            ));
+            let replies = deliver(&mut nodes, status);
+            assert!(matches!(
+                replies[..],
+                [Body::Error {
+                    code: TXN_CONFLICT,
+                    ..
+                }]
+            ));
+            assert!(nodes[1].locks.is_empty());
+            assert!(nodes[1].values.is_empty());
+            assert!(nodes[0].txns.is_empty());
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,593 @@
+pub mod node {
+    use maelstrom::error::{MALFORMED_REQUEST, TIMEOUT};
+    use serde::{Deserialize, Serialize};
+    use serde_json::json;
+    use std::collections::{BTreeMap, HashMap};
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+
+    /// Runs every transaction on one leader, the first node in the init list, which makes
+    /// them strict serializable: the leader runs them one at a time, each against everything
+    /// before it. Elle flags any history where two reads of a list disagree on the order of
+    /// its elements, so appends can't be applied independently on each node the way the
+    /// rw-register's writes are.
+    ///
+    /// Followers forward transactions to the leader and relay its replies, and the leader
+    /// replicates every transaction's appends to them in order, so their lists are always
+    /// what the leader's were at some point.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        leader: String,
+        followers: Vec<String>, // Only set on the leader
+        lists: HashMap<u64, Vec<i64>>,
+        log: Vec<Appends>, // Every transaction's appends, in the order the leader ran them
+        acked: HashMap<String, u64>, // How much of the log each follower has applied
+        ahead: BTreeMap<u64, Appends>, // Replicated appends past a gap in what we've applied
+        forwards: HashMap<u64, Forward>, // Transactions sent to the leader, by msg_id
+    }
+
+    /// One transaction's appends, in the order it made them.
+    type Appends = Vec<(u64, i64)>;
+
+    /// A client's transaction forwarded to the leader.
+    struct Forward {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on the leader answering
+    }
+
+    /// One micro-op of a transaction: `["r", key, null]`, answered with the list read, or
+    /// `["append", key, value]`.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Op(OpKind, u64, Option<Value>);
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum OpKind {
+        R,
+        Append,
+    }
+
+    /// A value appended, or a list read.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(untagged)]
+    enum Value {
+        Element(i64),
+        List(Vec<i64>),
+    }
+
+    /// Tunables, read from `TXN_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// How long a follower waits on the leader to answer a forwarded transaction.
+        pub forward_timeout: Duration,
+        /// Time between resends of the log to followers that are behind.
+        pub retry_interval: Duration,
+        /// Most transactions sent to a follower at once.
+        pub max_batch: usize,
+        /// Whether followers run read-only transactions against their own lists. They're
+        /// always a prefix of the leader's log, so that's still serializable, but not strict
+        /// serializable, since a follower can be behind.
+        pub follower_reads: bool,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                forward_timeout: Duration::from_millis(1000),
+                retry_interval: Duration::from_millis(200),
+                max_batch: 64,
+                follower_reads: false,
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                forward_timeout: Duration::from_millis(env_or(
+                    "TXN_FORWARD_TIMEOUT_MS",
+                    default.forward_timeout.as_millis() as u64,
+                )),
+                retry_interval: Duration::from_millis(env_or(
+                    "TXN_RETRY_INTERVAL_MS",
+                    default.retry_interval.as_millis() as u64,
+                )),
+                max_batch: env_or("TXN_MAX_BATCH", default.max_batch),
+                follower_reads: env_or("TXN_FOLLOWER_READS", default.follower_reads),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// A client's transaction, or one a follower forwarded to the leader.
+        Txn {
+            msg_id: u64,
+            txn: Vec<Op>,
+        },
+        /// The transaction with every read filled in.
+        TxnOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            txn: Vec<Op>,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        /// The leader's log from `index` on, or as much of it as fits in a batch.
+        Replicate {
+            msg_id: u64,
+            index: u64,
+            entries: Vec<Appends>,
+        },
+        /// The follower has applied the first `applied` transactions of the log.
+        ReplicateOk {
+            in_reply_to: u64,
+            applied: u64,
+        },
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                leader: String::default(),
+                followers: Vec::new(),
+                lists: HashMap::new(),
+                log: Vec::new(),
+                acked: HashMap::new(),
+                ahead: BTreeMap::new(),
+                forwards: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        fn is_leader(&self) -> bool {
+            self.id == self.leader
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> serde_json::Value {
+            json!({
+                "id": self.id,
+                "leader": self.leader,
+                "followers": self.followers,
+                "lists": self.lists.len(),
+                "log": self.log.len(),
+                "acked": self.acked,
+                "ahead": self.ahead.len(),
+                "forwards": self.forwards.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, &message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Resends the log to followers that haven't acknowledged all of it, and gives up on
+        /// forwarded transactions the leader hasn't answered.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            for follower in self.followers.clone() {
+                self.replicate_to(&follower, &mut messages);
+            }
+            let now = Instant::now();
+            let mut expired: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, forward)| forward.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            expired.sort();
+            for msg_id in expired {
+                let forward = self.forwards.remove(&msg_id).unwrap();
+                // The leader may have run it and only the reply was lost, so this mustn't
+                // claim the transaction failed
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest: forward.client,
+                    body: Body::Error {
+                        in_reply_to: forward.msg_id,
+                        code: TIMEOUT,
+                        text: "timed out waiting on the leader".to_string(),
+                    },
+                });
+            }
+            messages
+        }
+
+        /// Sends `follower` the part of the log it hasn't acknowledged, if there is any.
+        fn replicate_to(&mut self, follower: &str, outbox: &mut Vec<Message>) {
+            let index = self.acked.get(follower).copied().unwrap_or_default();
+            if index >= self.log.len() as u64 {
+                return;
+            }
+            let entries: Vec<Appends> = self.log[index as usize..]
+                .iter()
+                .take(self.config.max_batch.max(1))
+                .cloned()
+                .collect();
+            let msg_id = self.next_msg_id();
+            outbox.push(Message {
+                src: self.id.clone(),
+                dest: follower.to_string(),
+                body: Body::Replicate {
+                    msg_id,
+                    index,
+                    entries,
+                },
+            });
+        }
+
+        /// The reason `txn` can't be run, if it can't: appends need an element, and reads
+        /// mustn't bring one.
+        fn malformed(txn: &[Op]) -> Option<String> {
+            txn.iter().find_map(|op| match op {
+                Op(OpKind::Append, key, None | Some(Value::List(_))) => {
+                    Some(format!("append to {} needs a single value", key))
+                }
+                Op(OpKind::R, key, Some(_)) => Some(format!("read of {} carries a value", key)),
+                _ => None,
+            })
+        }
+
+        /// Runs a transaction against our lists, all at once since nothing else runs in
+        /// between. Only the leader runs transactions with appends.
+        fn run(&mut self, txn: &[Op]) -> Vec<Op> {
+            let mut appends = Appends::new();
+            let done = txn
+                .iter()
+                .map(|op| match op {
+                    Op(OpKind::R, key, _) => {
+                        let list = self.lists.get(key).cloned().unwrap_or_default();
+                        Op(OpKind::R, *key, Some(Value::List(list)))
+                    }
+                    Op(OpKind::Append, key, Some(Value::Element(element))) => {
+                        self.lists.entry(*key).or_default().push(*element);
+                        appends.push((*key, *element));
+                        op.clone()
+                    }
+                    _ => unreachable!("malformed transactions aren't run"),
+                })
+                .collect();
+            if !appends.is_empty() {
+                self.log.push(appends);
+            }
+            done
+        }
+
+        /// Applies replicated transactions in log order, holding any that arrive past a gap.
+        fn apply(&mut self, index: u64, entries: &[Appends]) {
+            for (offset, appends) in entries.iter().enumerate() {
+                let index = index + offset as u64;
+                if index >= self.log.len() as u64 {
+                    self.ahead.insert(index, appends.clone());
+                }
+            }
+            while let Some(appends) = self.ahead.remove(&(self.log.len() as u64)) {
+                for (key, element) in &appends {
+                    self.lists.entry(*key).or_default().push(*element);
+                }
+                self.log.push(appends);
+            }
+            self.ahead
+                .retain(|index, _| *index >= self.log.len() as u64);
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: &Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.id = node_id.clone();
+                    logging::set_node(&self.id);
+                    self.leader = node_ids.first().unwrap_or(node_id).clone();
+                    if self.is_leader() {
+                        self.followers = node_ids
+                            .iter()
+                            .filter(|node| *node != node_id)
+                            .cloned()
+                            .collect();
+                    }
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: *msg_id,
+                    }
+                }
+                Body::Txn { msg_id, txn } => {
+                    if let Some(text) = Self::malformed(txn) {
+                        return Some(Body::Error {
+                            in_reply_to: *msg_id,
+                            code: MALFORMED_REQUEST,
+                            text,
+                        });
+                    }
+                    let read_only = txn.iter().all(|Op(kind, ..)| *kind == OpKind::R);
+                    let local = self.is_leader() || (read_only && self.config.follower_reads);
+                    if !local {
+                        let forward_id = self.next_msg_id();
+                        self.forwards.insert(
+                            forward_id,
+                            Forward {
+                                client: src.to_string(),
+                                msg_id: *msg_id,
+                                deadline: Instant::now() + self.config.forward_timeout,
+                            },
+                        );
+                        outbox.push(Message {
+                            src: self.id.clone(),
+                            dest: self.leader.clone(),
+                            body: Body::Txn {
+                                msg_id: forward_id,
+                                txn: txn.clone(),
+                            },
+                        });
+                        return None;
+                    }
+                    let txn = self.run(txn);
+                    for follower in self.followers.clone() {
+                        self.replicate_to(&follower, outbox);
+                    }
+                    Body::TxnOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: *msg_id,
+                        txn,
+                    }
+                }
+                // The leader's answer to a transaction we forwarded, which goes on to the client
+                Body::TxnOk {
+                    in_reply_to, txn, ..
+                } => {
+                    let forward = self.forwards.remove(in_reply_to)?;
+                    let msg_id = self.next_msg_id();
+                    outbox.push(Message {
+                        src: self.id.clone(),
+                        dest: forward.client,
+                        body: Body::TxnOk {
+                            msg_id,
+                            in_reply_to: forward.msg_id,
+                            txn: txn.clone(),
+                        },
+                    });
+                    return None;
+                }
+                Body::Error {
+                    in_reply_to,
+                    code,
+                    text,
+                } => {
+                    let forward = self.forwards.remove(in_reply_to)?;
+                    outbox.push(Message {
+                        src: self.id.clone(),
+                        dest: forward.client,
+                        body: Body::Error {
+                            in_reply_to: forward.msg_id,
+                            code: *code,
+                            text: text.clone(),
+                        },
+                    });
+                    return None;
+                }
+                Body::Replicate {
+                    msg_id,
+                    index,
+                    entries,
+                } => {
+                    self.apply(*index, entries);
+                    Body::ReplicateOk {
+                        in_reply_to: *msg_id,
+                        applied: self.log.len() as u64,
+                    }
+                }
+                Body::ReplicateOk { applied, .. } => {
+                    let acked = self.acked.entry(src.to_string()).or_default();
+                    *acked = (*acked).max(*applied);
+                    // Keep a follower that's far behind catching up without waiting on a tick
+                    if *applied < self.log.len() as u64 {
+                        self.replicate_to(src, outbox);
+                    }
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        fn init_with(id: &str, config: Config) -> Node {
+            let mut node = Node::new(config);
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest: id.into(),
+                body: Body::Init {
+                    msg_id: 1,
+                    node_id: id.into(),
+                    node_ids: vec!["n1".into(), "n2".into()],
+                },
+            });
+            node
+        }
+
+        fn txn_message(dest: &str, txn: &str) -> Message {
+            Message {
+                src: "c1".into(),
+                dest: dest.into(),
+                body: Body::Txn {
+                    msg_id: 9,
+                    txn: serde_json::from_str(txn).unwrap(),
+                },
+            }
+        }
+
+        #[test]
+        fn test_followers_forward_to_the_leader() {
+            let mut n1 = init_with("n1", Config::default());
+            let mut n2 = init_with("n2", Config::default());
+            let forwarded =
+                n2.handle_message(txn_message("n2", r#"[["append", 1, 3], ["r", 1, null]]"#));
+            assert_eq!(forwarded[0].dest, "n1");
+            let from_leader = n1.handle_message(forwarded[0].clone());
+            let mut replies = Vec::new();
+            for message in from_leader {
+                replies.extend(n2.handle_message(message));
+            }
+            let client_reply = replies.iter().find(|message| message.dest == "c1").unwrap();
+            assert_eq!(
+                serde_json::to_string(&client_reply.body).unwrap(),
+                r#"{"type":"txn_ok","msg_id":3,"in_reply_to":9,"txn":[["append",1,3],["r",1,[3]]]}"#
+            );
+            // The leader's appends reached n2 too
+            assert_eq!(n2.lists[&1], vec![3]);
+        }
+
+        #[test]
+        fn test_replication_fills_gaps_in_order() {
+            let config = Config {
+                follower_reads: true,
+                ..Config::default()
+            };
+            let mut n2 = init_with("n2", config);
+            let replicate = |index, entries: Vec<Appends>| Message {
+                src: "n1".into(),
+                dest: "n2".into(),
+                body: Body::Replicate {
+                    msg_id: 1,
+                    index,
+                    entries,
+                },
+            };
+            n2.handle_message(replicate(1, vec![vec![(1, 2)]]));
+            assert!(n2.lists.is_empty());
+            let acks = n2.handle_message(replicate(0, vec![vec![(1, 1)]]));
+            assert!(matches!(acks[0].body, Body::ReplicateOk { applied: 2, .. }));
+            let reads = n2.handle_message(txn_message("n2", r#"[["r", 1, null]]"#));
+            assert!(matches!(
+                &reads[0].body,
+                Body::TxnOk { txn, .. } if txn[0] == Op(OpKind::R, 1, Some(Value::List(vec![1, 2])))
+            ));
+        }
+
+        #[test]
+        fn test_malformed_and_unanswered_transactions() {
+            let config = Config {
+                forward_timeout: Duration::ZERO,
+                ..Config::default()
+            };
+            let mut n2 = init_with("n2", config);
+            let replies = n2.handle_message(txn_message("n2", r#"[["append", 1, null]]"#));
+            assert!(matches!(
+                replies[0].body,
+                Body::Error {
+                    code: MALFORMED_REQUEST,
+                    ..
+                }
+            ));
+            n2.handle_message(txn_message("n2", r#"[["append", 1, 1]]"#));
+            let replies = n2.tick();
+            assert!(matches!(
+                replies[..],
+                [Message {
+                    body: Body::Error {
+                        in_reply_to: 9,
+                        code: TIMEOUT,
+                        ..
+                    },
+                    ..
+                }]
+            ));
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
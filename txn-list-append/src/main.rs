@@ -0,0 +1,10 @@
+use std::error::Error;
+use txn_list_append::node;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    logging::init()?;
+    let config = node::Config::from_env();
+    let retry_interval = config.retry_interval;
+    maelstrom::run(node::Node::new(config), retry_interval).await
+}
@@ -0,0 +1,430 @@
+pub mod node {
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::Duration;
+
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        peers: Vec<String>, // Every other node, which each get every write
+        registers: HashMap<u64, Register>,
+        clock: u64, // Lamport clock, past every version we've made or seen
+        unacked: HashMap<u64, (String, Replicated)>, // Replication sent, by msg_id, to its peer
+    }
+
+    /// A register's value, and the version of the transaction that wrote it.
+    struct Register {
+        value: i64,
+        version: Version,
+    }
+
+    /// Orders transactions' writes the same way on every node: by Lamport clock, then by the
+    /// node that ran the transaction. Every write in a transaction shares its version, so two
+    /// transactions writing the same keys overwrite each other the same way on every key, and
+    /// a node that saw them in the other order ends up agreeing.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+    struct Version {
+        clock: u64,
+        node: String,
+    }
+
+    /// A transaction's writes, as sent to peers: each key's last value in it, applied all
+    /// together so no one reads only some of them.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Replicated {
+        version: Version,
+        writes: Vec<(u64, i64)>,
+    }
+
+    /// One micro-op of a transaction: `["r", key, null]`, answered with the value read, or
+    /// `["w", key, value]`.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    struct Op(OpKind, u64, Option<i64>);
+
+    #[derive(Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+    #[serde(rename_all = "lowercase")]
+    enum OpKind {
+        R,
+        W,
+    }
+
+    /// Tunables, read from `TXN_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// Time between resends of writes a peer hasn't acknowledged.
+        pub retry_interval: Duration,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                retry_interval: Duration::from_millis(200),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                retry_interval: Duration::from_millis(env_or(
+                    "TXN_RETRY_INTERVAL_MS",
+                    default.retry_interval.as_millis() as u64,
+                )),
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Txn {
+            msg_id: u64,
+            txn: Vec<Op>,
+        },
+        /// The transaction with every read filled in.
+        TxnOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            txn: Vec<Op>,
+        },
+        /// A transaction's writes, from the node that ran it.
+        Replicate {
+            msg_id: u64,
+            #[serde(flatten)]
+            replicated: Replicated,
+        },
+        ReplicateOk {
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+    }
+
+    impl Default for Node {
+        fn default() -> Self {
+            Node::new()
+        }
+    }
+
+    impl Node {
+        pub fn new() -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                peers: Vec::new(),
+                registers: HashMap::new(),
+                clock: 0,
+                unacked: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            json!({
+                "id": self.id,
+                "peers": self.peers,
+                "registers": self.registers.len(),
+                "clock": self.clock,
+                "unacked": self.unacked.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Sends every peer the writes it hasn't acknowledged again. Writes carry their
+        /// version, so a peer that gets them twice just ignores the second copy.
+        pub fn retry(&self) -> Vec<Message> {
+            let mut unacked: Vec<_> = self.unacked.iter().collect();
+            unacked.sort_by_key(|(msg_id, _)| **msg_id);
+            unacked
+                .into_iter()
+                .map(|(msg_id, (peer, replicated))| Message {
+                    src: self.id.clone(),
+                    dest: peer.clone(),
+                    body: Body::Replicate {
+                        msg_id: *msg_id,
+                        replicated: replicated.clone(),
+                    },
+                })
+                .collect()
+        }
+
+        /// Runs a transaction against our registers, all at once since nothing else runs
+        /// in between, and queues its writes for every peer.
+        fn run(&mut self, txn: &[Op], outbox: &mut Vec<Message>) -> Vec<Op> {
+            self.clock += 1;
+            let version = Version {
+                clock: self.clock,
+                node: self.id.clone(),
+            };
+            let mut writes: Vec<(u64, i64)> = Vec::new();
+            let done = txn
+                .iter()
+                .map(|Op(kind, key, value)| match (kind, value) {
+                    (OpKind::R, _) => Op(*kind, *key, self.read(*key)),
+                    (OpKind::W, Some(value)) => {
+                        self.write(*key, *value, &version);
+                        writes.retain(|(written, _)| written != key);
+                        writes.push((*key, *value));
+                        Op(*kind, *key, Some(*value))
+                    }
+                    (OpKind::W, None) => {
+                        log::warn!("Ignoring a write of nothing to {}", key);
+                        Op(*kind, *key, None)
+                    }
+                })
+                .collect();
+            if !writes.is_empty() {
+                self.replicate(Replicated { version, writes }, outbox);
+            }
+            done
+        }
+
+        fn read(&self, key: u64) -> Option<i64> {
+            self.registers.get(&key).map(|register| register.value)
+        }
+
+        /// Writes `value` unless the register holds a later transaction's write.
+        fn write(&mut self, key: u64, value: i64, version: &Version) {
+            match self.registers.get_mut(&key) {
+                Some(register) if register.version > *version => {}
+                Some(register) => {
+                    register.value = value;
+                    register.version = version.clone();
+                }
+                None => {
+                    self.registers.insert(
+                        key,
+                        Register {
+                            value,
+                            version: version.clone(),
+                        },
+                    );
+                }
+            }
+        }
+
+        fn replicate(&mut self, replicated: Replicated, outbox: &mut Vec<Message>) {
+            for peer in self.peers.clone() {
+                let msg_id = self.next_msg_id();
+                self.unacked
+                    .insert(msg_id, (peer.clone(), replicated.clone()));
+                outbox.push(Message {
+                    src: self.id.clone(),
+                    dest: peer,
+                    body: Body::Replicate {
+                        msg_id,
+                        replicated: replicated.clone(),
+                    },
+                });
+            }
+        }
+
+        fn handle_body(&mut self, body: &Body, outbox: &mut Vec<Message>) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.id = node_id.clone();
+                    logging::set_node(&self.id);
+                    self.peers = node_ids
+                        .iter()
+                        .filter(|node| *node != node_id)
+                        .cloned()
+                        .collect();
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: *msg_id,
+                    }
+                }
+                Body::Txn { msg_id, txn } => {
+                    let txn = self.run(txn, outbox);
+                    Body::TxnOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: *msg_id,
+                        txn,
+                    }
+                }
+                Body::Replicate { msg_id, replicated } => {
+                    self.clock = self.clock.max(replicated.version.clock);
+                    for (key, value) in &replicated.writes {
+                        self.write(*key, *value, &replicated.version);
+                    }
+                    Body::ReplicateOk {
+                        in_reply_to: *msg_id,
+                    }
+                }
+                Body::ReplicateOk { in_reply_to } => {
+                    self.unacked.remove(in_reply_to);
+                    return None;
+                }
+                // We shouldn't be receiving these
+                Body::InitOk { .. } | Body::Error { .. } | Body::TxnOk { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::error::TEMPORARILY_UNAVAILABLE;
+        use maelstrom::testing;
+
+        fn init(id: &str) -> Node {
+            testing::init(Node::new(), id, 2)
+        }
+
+        fn txn(node: &mut Node, txn: &str) -> (Vec<Op>, Vec<Message>) {
+            let dest = node.id.clone();
+            let mut messages = node.handle_message(Message {
+                src: "c1".into(),
+                dest,
+                body: Body::Txn {
+                    msg_id: 1,
+                    txn: serde_json::from_str(txn).unwrap(),
+                },
+            });
+            let Body::TxnOk { txn, .. } = messages.remove(0).body else {
+                panic!("expected txn_ok, got {:?}", messages);
+            };
+            (txn, messages)
+        }
+
+        #[test]
+        fn test_reads_see_earlier_writes() {
+            let mut n1 = init("n1");
+            let (done, _) = txn(&mut n1, r#"[["r", 1, null], ["w", 1, 5], ["r", 1, null]]"#);
+            assert_eq!(
+                serde_json::to_string(&done).unwrap(),
+                r#"[["r",1,null],["w",1,5],["r",1,5]]"#
+            );
+        }
+
+        #[test]
+        fn test_conflicting_writes_converge() {
+            let mut n1 = init("n1");
+            let mut n2 = init("n2");
+            let (_, from_n1) = txn(&mut n1, r#"[["w", 1, 1], ["w", 2, 1]]"#);
+            let (_, from_n2) = txn(&mut n2, r#"[["w", 1, 2], ["w", 2, 2]]"#);
+            // Each node sees the other's transaction after its own
+            n2.handle_message(from_n1[0].clone());
+            for ack in n1.handle_message(from_n2[0].clone()) {
+                n2.handle_message(ack);
+            }
+            for key in [1, 2] {
+                assert_eq!(n1.read(key), n2.read(key));
+            }
+            // And both pick the same transaction for every key
+            assert_eq!(n1.read(1), n1.read(2));
+            assert!(n2.retry().is_empty());
+            assert_eq!(n1.retry().len(), 1);
+        }
+
+        #[test]
+        fn test_txn_before_init_is_refused() {
+            let mut node = Node::new();
+            let replies = node.handle_message(Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Txn {
+                    msg_id: 3,
+                    txn: Vec::new(),
+                },
+            });
+            let [Message {
+                body: Body::Error {
+                    in_reply_to, code, ..
+                },
+                ..
+            }] = replies[..]
+            else {
+                panic!("expected an error, got {:?}", replies);
+            };
+            assert_eq!((in_reply_to, code), (3, TEMPORARILY_UNAVAILABLE));
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::retry(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
@@ -0,0 +1,9 @@
+use std::error::Error;
+use txn_rw_register::node;
+
+#[tokio::main]
+async fn main() -> Result<(), Box<dyn Error>> {
+    logging::init()?;
+    let config = node::Config::from_env();
+    maelstrom::run(node::Node::new(), config.retry_interval).await
+}
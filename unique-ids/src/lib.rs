@@ -0,0 +1,361 @@
+mod snowflake {
+    use std::time::{SystemTime, UNIX_EPOCH};
+
+    /// Milliseconds since the Unix epoch that ids count time from, 2024-01-01T00:00:00Z, so
+    /// the 41 timestamp bits last until 2093.
+    const EPOCH_MS: u64 = 1_704_067_200_000;
+
+    const NODE_BITS: u32 = 10;
+    const SEQUENCE_BITS: u32 = 12;
+    pub const MAX_NODE: u64 = (1 << NODE_BITS) - 1;
+    const MAX_SEQUENCE: u64 = (1 << SEQUENCE_BITS) - 1;
+
+    /// Hands out 64-bit ids laid out as a 41-bit millisecond timestamp, a 10-bit node number
+    /// and a 12-bit sequence within the millisecond. Ids from one node only ever go up, and
+    /// ids from different nodes never collide, so they sort by roughly when they were made
+    /// without any coordination.
+    ///
+    /// If the clock steps backwards, or a millisecond's sequence runs out, ids keep counting
+    /// up from the last timestamp used rather than waiting for the clock to catch up. That
+    /// borrows a little time from the future, which the clock pays back as it moves on.
+    pub struct Snowflake {
+        node: u64,
+        last_ms: u64,  // Timestamp of the last id, which the next can't go below
+        sequence: u64, // Sequence of the last id within last_ms
+    }
+
+    impl Snowflake {
+        pub fn new(node: u64) -> Self {
+            assert!(
+                node <= MAX_NODE,
+                "node number {} doesn't fit in an id",
+                node
+            );
+            Snowflake {
+                node,
+                last_ms: 0,
+                sequence: 0,
+            }
+        }
+
+        pub fn next_id(&mut self) -> u64 {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .map_or(0, |since| since.as_millis() as u64);
+            self.next_id_at(now.saturating_sub(EPOCH_MS))
+        }
+
+        /// The next id, with the clock reading `now_ms` since `EPOCH_MS`.
+        pub fn next_id_at(&mut self, now_ms: u64) -> u64 {
+            if now_ms > self.last_ms {
+                self.last_ms = now_ms;
+                self.sequence = 0;
+            } else {
+                if now_ms < self.last_ms {
+                    log::warn!(
+                        "Clock went back {}ms, counting on from the last id",
+                        self.last_ms - now_ms
+                    );
+                }
+                if self.sequence == MAX_SEQUENCE {
+                    self.last_ms += 1;
+                    self.sequence = 0;
+                } else {
+                    self.sequence += 1;
+                }
+            }
+            self.last_ms << (NODE_BITS + SEQUENCE_BITS) | self.node << SEQUENCE_BITS | self.sequence
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+
+        #[test]
+        fn test_ids_go_up_when_the_clock_goes_back() {
+            let mut ids = Snowflake::new(3);
+            let first = ids.next_id_at(1000);
+            let second = ids.next_id_at(1000);
+            let after_regression = ids.next_id_at(400);
+            let later = ids.next_id_at(1001);
+            assert!(first < second && second < after_regression && after_regression < later);
+        }
+
+        #[test]
+        fn test_sequence_overflow_borrows_the_next_millisecond() {
+            let mut ids = Snowflake::new(0);
+            let mut last = ids.next_id_at(5);
+            for _ in 0..MAX_SEQUENCE + 10 {
+                let id = ids.next_id_at(5);
+                assert!(id > last);
+                last = id;
+            }
+            assert_eq!(last >> (NODE_BITS + SEQUENCE_BITS), 6);
+        }
+
+        #[test]
+        fn test_nodes_never_collide() {
+            let mut n1 = Snowflake::new(1);
+            let mut n2 = Snowflake::new(2);
+            assert_ne!(n1.next_id_at(7), n2.next_id_at(7));
+        }
+    }
+}
+
+pub mod node {
+    use super::snowflake::{self, Snowflake};
+    use maelstrom::error::{
+        MALFORMED_REQUEST, NOT_SUPPORTED, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE,
+    };
+    use serde::{Deserialize, Serialize};
+
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        ids: Option<Snowflake>, // Set at init, once we know our node number
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Generate {
+            msg_id: u64,
+        },
+        GenerateOk {
+            id: u64,
+            in_reply_to: u64,
+            msg_id: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            text: String,
+        },
+    }
+
+    fn error(in_reply_to: u64, code: u64, text: String) -> Body {
+        Body::Error {
+            in_reply_to,
+            code,
+            text,
+        }
+    }
+
+    impl Default for Node {
+        fn default() -> Self {
+            Node::new()
+        }
+    }
+
+    impl Node {
+        pub fn new() -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                ids: None,
+            }
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            if !self.initialized {
+                match message.body {
+                    Body::Init { .. } => {}
+                    Body::Generate { msg_id } => {
+                        log::warn!("Received generate {} before init", msg_id);
+                        return vec![Message {
+                            src: message.dest,
+                            dest: message.src,
+                            body: error(
+                                msg_id,
+                                TEMPORARILY_UNAVAILABLE,
+                                "node is not initialized yet".to_string(),
+                            ),
+                        }];
+                    }
+                    _ => {
+                        log::warn!("Dropping {:?} received before init", message.body);
+                        return Vec::new();
+                    }
+                }
+            }
+            let Some(body) = self.handle_body(&message.body) else {
+                return Vec::new();
+            };
+            self.cur_id += 1;
+            vec![Message {
+                src: message.dest,
+                dest: message.src,
+                body,
+            }]
+        }
+
+        fn handle_body(&mut self, body: &Body) -> Option<Body> {
+            Some(match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    if self.initialized {
+                        log::warn!("Received init {} after already initializing", msg_id);
+                        return Some(error(
+                            *msg_id,
+                            PRECONDITION_FAILED,
+                            "node is already initialized".to_string(),
+                        ));
+                    }
+                    // Our place in the cluster is our node number, so no two nodes share one
+                    if node_ids.len() as u64 > snowflake::MAX_NODE + 1 {
+                        return Some(error(
+                            *msg_id,
+                            NOT_SUPPORTED,
+                            format!(
+                                "{} nodes can't all have their own node number, at most {} can",
+                                node_ids.len(),
+                                snowflake::MAX_NODE + 1
+                            ),
+                        ));
+                    }
+                    let Some(number) = node_ids.iter().position(|id| id == node_id) else {
+                        return Some(error(
+                            *msg_id,
+                            MALFORMED_REQUEST,
+                            format!("{} isn't in node_ids", node_id),
+                        ));
+                    };
+                    self.id = node_id.clone();
+                    logging::set_node(&self.id);
+                    self.ids = Some(Snowflake::new(number as u64));
+                    self.initialized = true;
+                    Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: *msg_id,
+                    }
+                }
+                Body::Generate { msg_id } => Body::GenerateOk {
+                    id: self.ids.as_mut()?.next_id(),
+                    in_reply_to: *msg_id,
+                    msg_id: self.cur_id,
+                },
+                // We shouldn't be receiving these
+                Body::InitOk { .. } | Body::GenerateOk { .. } | Body::Error { .. } => return None,
+            })
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use std::collections::HashSet;
+
+        fn init(id: &str) -> Node {
+            let mut node = Node::new();
+            node.handle_message(Message {
+                src: "c1".into(),
+                dest: id.into(),
+                body: Body::Init {
+                    msg_id: 1,
+                    node_id: id.into(),
+                    node_ids: vec!["n1".into(), "n2".into()],
+                },
+            });
+            node
+        }
+
+        #[test]
+        fn test_generated_ids_are_unique() {
+            let mut nodes = [init("n1"), init("n2")];
+            let mut seen = HashSet::new();
+            for msg_id in 0..1000 {
+                for node in &mut nodes {
+                    let dest = node.id.clone();
+                    let replies = node.handle_message(Message {
+                        src: "c1".into(),
+                        dest,
+                        body: Body::Generate { msg_id },
+                    });
+                    let Body::GenerateOk {
+                        id, in_reply_to, ..
+                    } = replies[0].body
+                    else {
+                        panic!("expected generate_ok, got {:?}", replies);
+                    };
+                    assert_eq!(in_reply_to, msg_id);
+                    assert!(seen.insert(id), "id {} was generated twice", id);
+                }
+            }
+        }
+
+        fn error_code(replies: &[Message]) -> Option<u64> {
+            match replies[..] {
+                [Message {
+                    body: Body::Error { code, .. },
+                    ..
+                }] => Some(code),
+                _ => None,
+            }
+        }
+
+        #[test]
+        fn test_bad_inits_are_refused() {
+            let mut node = Node::new();
+            let generate = || Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Generate { msg_id: 1 },
+            };
+            let init = |node_ids: Vec<String>| Message {
+                src: "c1".into(),
+                dest: "n1".into(),
+                body: Body::Init {
+                    msg_id: 2,
+                    node_id: "n1".into(),
+                    node_ids,
+                },
+            };
+            let generated = node.handle_message(generate());
+            assert_eq!(error_code(&generated), Some(TEMPORARILY_UNAVAILABLE));
+
+            let too_many = (0..=snowflake::MAX_NODE + 1).map(|i| format!("n{}", i));
+            let refused = node.handle_message(init(too_many.collect()));
+            assert_eq!(error_code(&refused), Some(NOT_SUPPORTED));
+            let refused = node.handle_message(init(vec!["n2".into()]));
+            assert_eq!(error_code(&refused), Some(MALFORMED_REQUEST));
+
+            let inited = node.handle_message(init(vec!["n1".into(), "n2".into()]));
+            assert!(matches!(inited[0].body, Body::InitOk { .. }));
+            let again = node.handle_message(init(vec!["n1".into()]));
+            assert_eq!(error_code(&again), Some(PRECONDITION_FAILED));
+            assert!(matches!(
+                node.handle_message(generate())[0].body,
+                Body::GenerateOk { .. }
+            ));
+        }
+    }
+}
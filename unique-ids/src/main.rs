@@ -0,0 +1,15 @@
+use std::error::Error;
+use unique_ids::node;
+
+fn main() -> Result<(), Box<dyn Error>> {
+    logging::init()?;
+    let mut node = node::Node::new();
+    let result = maelstrom::read_blocking(|m: node::Message| {
+        for message in logging::timed("handle_message", || node.handle_message(m)) {
+            maelstrom::write(&message)?;
+        }
+        Ok(())
+    });
+    logging::shutdown();
+    result
+}
@@ -1,7 +1,5 @@
 use serde::{Deserialize, Serialize};
 use std::error::Error;
-use std::io;
-use std::io::Write;
 
 #[derive(Debug, Deserialize, Serialize)]
 #[serde(rename_all = "snake_case")]
@@ -60,6 +58,7 @@ fn handle_body(body: Body) -> Body {
                 node_id,
                 node_ids
             );
+            logging::set_node(&node_id);
             Body::InitOk {
                 msg_id,
                 in_reply_to: msg_id,
@@ -85,21 +84,11 @@ fn handle_message(message: Message) -> Message {
 }
 
 fn main() -> Result<(), Box<dyn Error>> {
-    simple_logger::SimpleLogger::new().env().init()?;
-    let stdin = io::stdin().lock();
-    let mut stdout = io::stdout().lock();
-
-    let mut reader = serde_json::Deserializer::from_reader(stdin);
-    loop {
-        match Message::deserialize(&mut reader) {
-            Ok(m) => {
-                serde_json::to_writer(&mut stdout, &handle_message(m))?;
-                stdout.write_all(b"\n")?;
-            }
-            Err(e) => {
-                log::error!("Unable to parse: {}", e);
-                continue;
-            }
-        }
-    }
+    logging::init()?;
+    let result = maelstrom::read_blocking(|m: Message| {
+        maelstrom::write(&logging::timed("handle_message", || handle_message(m)))?;
+        Ok(())
+    });
+    logging::shutdown();
+    result
 }
@@ -0,0 +1,700 @@
+pub mod node {
+    use maelstrom::error::{
+        KEY_DOES_NOT_EXIST, PRECONDITION_FAILED, TEMPORARILY_UNAVAILABLE, TIMEOUT,
+    };
+    use serde::{Deserialize, Serialize};
+    use serde_json::{json, Value};
+    use std::collections::HashMap;
+    use std::str::FromStr;
+    use std::time::{Duration, Instant};
+    use vr::{NotPrimary, Outbox, StateMachine, Vr, VrMessage};
+
+    /// lin-kv's linearizable key/value store over Viewstamped Replication instead of Raft.
+    /// Every operation goes through the primary's log, and takes effect at a single point,
+    /// when it's committed, between its request and its reply. Backups forward requests to
+    /// the primary and relay its replies. Nothing is served while a majority is unreachable,
+    /// or while the nodes are changing views.
+    ///
+    /// The store is the same `StateMachine` lin-kv hands Raft, so the two nodes differ only
+    /// in the consensus underneath, and run the same workload side by side.
+    pub struct Node {
+        initialized: bool,
+        id: String,
+        cur_id: u64,
+        config: Config,
+        node_ids: Vec<String>,
+        vr: Option<Vr<Store>>, // Set at init, once we know the cluster
+        forwards: HashMap<u64, Waiting>, // Requests sent to the primary, by msg_id
+        proposals: HashMap<u64, Proposed>, // Requests we put in the log, by index
+    }
+
+    /// A request in the log, waiting on it to be applied.
+    struct Proposed {
+        view: u64, // The view it went in with, which the applied entry must have too
+        waiting: Waiting,
+    }
+
+    /// A client waiting on a reply to `msg_id`.
+    struct Waiting {
+        client: String,
+        msg_id: u64,
+        deadline: Instant, // When we give up on answering
+    }
+
+    /// The values every node applies the log to.
+    #[derive(Default)]
+    struct Store {
+        values: HashMap<String, Value>, // Keyed by each key's JSON, since keys can be any JSON
+    }
+
+    /// An operation as it goes in the log.
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "op")]
+    enum Op {
+        Read {
+            key: Value,
+        },
+        Write {
+            key: Value,
+            value: Value,
+        },
+        Cas {
+            key: Value,
+            from: Value,
+            to: Value,
+            create_if_not_exists: bool,
+        },
+    }
+
+    /// Tunables, read from `VR_KV_*` environment variables by `from_env`.
+    #[derive(Debug, Clone)]
+    pub struct Config {
+        /// How long a request waits on being committed, or on the primary to answer it if we
+        /// forwarded it, before we give up.
+        pub request_timeout: Duration,
+        /// Time between ticks, which drive VR's timers and time out requests.
+        pub tick_interval: Duration,
+        pub vr: vr::Config,
+    }
+
+    impl Default for Config {
+        fn default() -> Self {
+            Config {
+                request_timeout: Duration::from_millis(1000),
+                tick_interval: Duration::from_millis(50),
+                vr: vr::Config::default(),
+            }
+        }
+    }
+
+    impl Config {
+        pub fn from_env() -> Self {
+            let default = Config::default();
+            Config {
+                request_timeout: Duration::from_millis(env_or(
+                    "VR_KV_REQUEST_TIMEOUT_MS",
+                    default.request_timeout.as_millis() as u64,
+                )),
+                tick_interval: Duration::from_millis(env_or(
+                    "VR_KV_TICK_INTERVAL_MS",
+                    default.tick_interval.as_millis() as u64,
+                )),
+                vr: vr::Config {
+                    view_change_timeout: Duration::from_millis(env_or(
+                        "VR_KV_VIEW_CHANGE_TIMEOUT_MS",
+                        default.vr.view_change_timeout.as_millis() as u64,
+                    )),
+                    heartbeat_interval: Duration::from_millis(env_or(
+                        "VR_KV_HEARTBEAT_INTERVAL_MS",
+                        default.vr.heartbeat_interval.as_millis() as u64,
+                    )),
+                    max_batch: env_or("VR_KV_MAX_BATCH", default.vr.max_batch),
+                },
+            }
+        }
+    }
+
+    fn env_or<T: FromStr>(name: &str, default: T) -> T {
+        match std::env::var(name) {
+            Ok(val) => val.parse().unwrap_or_else(|_| {
+                log::warn!("Ignoring invalid value {:?} for {}", val, name);
+                default
+            }),
+            Err(_) => default,
+        }
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    pub struct Message {
+        src: String,
+        dest: String,
+        body: Body,
+    }
+
+    #[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+    #[serde(rename_all = "snake_case")]
+    #[serde(tag = "type")]
+    enum Body {
+        Init {
+            msg_id: u64,
+            node_id: String,
+            node_ids: Vec<String>,
+        },
+        InitOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Read {
+            msg_id: u64,
+            key: Value,
+        },
+        ReadOk {
+            msg_id: u64,
+            in_reply_to: u64,
+            value: Value,
+        },
+        Write {
+            msg_id: u64,
+            key: Value,
+            value: Value,
+        },
+        WriteOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        /// Sets `key` to `to` if it's `from`. A missing key fails, unless
+        /// `create_if_not_exists` is set, in which case it's created.
+        Cas {
+            msg_id: u64,
+            key: Value,
+            from: Value,
+            to: Value,
+            #[serde(default)]
+            create_if_not_exists: bool,
+        },
+        CasOk {
+            msg_id: u64,
+            in_reply_to: u64,
+        },
+        Error {
+            in_reply_to: u64,
+            code: u64,
+            #[serde(default)]
+            text: String,
+        },
+        #[serde(untagged)]
+        Vr(VrMessage<Op>),
+    }
+
+    impl Body {
+        /// The id of the request this replies to, if it's a reply.
+        fn in_reply_to(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::InitOk { in_reply_to, .. }
+                | Body::ReadOk { in_reply_to, .. }
+                | Body::WriteOk { in_reply_to, .. }
+                | Body::CasOk { in_reply_to, .. }
+                | Body::Error { in_reply_to, .. } => Some(in_reply_to),
+                _ => None,
+            }
+        }
+
+        fn msg_id(&mut self) -> Option<&mut u64> {
+            match self {
+                Body::Init { msg_id, .. }
+                | Body::InitOk { msg_id, .. }
+                | Body::Read { msg_id, .. }
+                | Body::ReadOk { msg_id, .. }
+                | Body::Write { msg_id, .. }
+                | Body::WriteOk { msg_id, .. }
+                | Body::Cas { msg_id, .. }
+                | Body::CasOk { msg_id, .. } => Some(msg_id),
+                _ => None,
+            }
+        }
+
+        /// The request as an operation for the log.
+        fn op(self) -> Option<Op> {
+            Some(match self {
+                Body::Read { key, .. } => Op::Read { key },
+                Body::Write { key, value, .. } => Op::Write { key, value },
+                Body::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                    ..
+                } => Op::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                },
+                _ => return None,
+            })
+        }
+    }
+
+    impl StateMachine for Store {
+        type Command = Op;
+        /// The reply, with its ids still to fill in.
+        type Output = Body;
+        type Snapshot = HashMap<String, Value>;
+
+        fn apply(&mut self, op: &Op) -> Body {
+            match op {
+                Op::Read { key } => match self.values.get(&key.to_string()) {
+                    Some(value) => Body::ReadOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                        value: value.clone(),
+                    },
+                    None => Body::Error {
+                        in_reply_to: 0,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("{} doesn't exist", key),
+                    },
+                },
+                Op::Write { key, value } => {
+                    self.values.insert(key.to_string(), value.clone());
+                    Body::WriteOk {
+                        msg_id: 0,
+                        in_reply_to: 0,
+                    }
+                }
+                Op::Cas {
+                    key,
+                    from,
+                    to,
+                    create_if_not_exists,
+                } => match self.values.get_mut(&key.to_string()) {
+                    Some(value) if value == from => {
+                        *value = to.clone();
+                        Body::CasOk {
+                            msg_id: 0,
+                            in_reply_to: 0,
+                        }
+                    }
+                    Some(value) => Body::Error {
+                        in_reply_to: 0,
+                        code: PRECONDITION_FAILED,
+                        text: format!("expected {}, but {} is {}", from, key, value),
+                    },
+                    None if *create_if_not_exists => {
+                        self.values.insert(key.to_string(), to.clone());
+                        Body::CasOk {
+                            msg_id: 0,
+                            in_reply_to: 0,
+                        }
+                    }
+                    None => Body::Error {
+                        in_reply_to: 0,
+                        code: KEY_DOES_NOT_EXIST,
+                        text: format!("{} doesn't exist", key),
+                    },
+                },
+            }
+        }
+
+        fn snapshot(&self) -> HashMap<String, Value> {
+            self.values.clone()
+        }
+
+        fn restore(&mut self, snapshot: HashMap<String, Value>) {
+            self.values = snapshot;
+        }
+    }
+
+    impl Node {
+        pub fn new(config: Config) -> Self {
+            Node {
+                initialized: false,
+                id: String::default(),
+                cur_id: 1,
+                config,
+                node_ids: Vec::new(),
+                vr: None,
+                forwards: HashMap::new(),
+                proposals: HashMap::new(),
+            }
+        }
+
+        fn next_msg_id(&mut self) -> u64 {
+            self.cur_id += 1;
+            self.cur_id - 1
+        }
+
+        /// What the node is up to, for logging on SIGUSR1 when a run looks stuck.
+        pub fn dump(&self) -> Value {
+            let vr = self.vr.as_ref().map(|vr| {
+                json!({
+                    "view": vr.view(),
+                    "primary": vr.primary(),
+                    "nodes": vr.nodes(),
+                    "op_number": vr.op_number(),
+                    "commit_number": vr.commit_number(),
+                })
+            });
+            json!({
+                "id": self.id,
+                "vr": vr,
+                "forwards": self.forwards.len(),
+                "proposals": self.proposals.len(),
+            })
+        }
+
+        pub fn handle_message(&mut self, message: Message) -> Vec<Message> {
+            let is_init = matches!(message.body, Body::Init { .. });
+            if let Some(refused) = maelstrom::check_init(&message, is_init, self.initialized) {
+                return refused;
+            }
+            let mut messages = Vec::new();
+            if let Some(body) = self.handle_body(&message.src, message.body, &mut messages) {
+                messages.insert(
+                    0,
+                    Message {
+                        src: message.dest,
+                        dest: message.src,
+                        body,
+                    },
+                );
+                self.cur_id += 1;
+            }
+            messages
+        }
+
+        /// Drives VR's timers, and gives up on requests that have waited too long.
+        pub fn tick(&mut self) -> Vec<Message> {
+            let mut messages = Vec::new();
+            let mut outbox = Vec::new();
+            if let Some(vr) = &mut self.vr {
+                vr.tick(&mut outbox);
+            }
+            self.send_vr(outbox, &mut messages);
+
+            let now = Instant::now();
+            let expired_forwards: Vec<u64> = self
+                .forwards
+                .iter()
+                .filter(|(_, waiting)| waiting.deadline <= now)
+                .map(|(msg_id, _)| *msg_id)
+                .collect();
+            let expired_proposals: Vec<u64> = self
+                .proposals
+                .iter()
+                .filter(|(_, proposed)| proposed.waiting.deadline <= now)
+                .map(|(index, _)| *index)
+                .collect();
+            let mut expired: Vec<Waiting> = expired_forwards
+                .iter()
+                .filter_map(|msg_id| self.forwards.remove(msg_id))
+                .chain(
+                    expired_proposals
+                        .iter()
+                        .filter_map(|index| self.proposals.remove(index))
+                        .map(|proposed| proposed.waiting),
+                )
+                .collect();
+            expired.sort_by_key(|waiting| waiting.msg_id);
+            for waiting in expired {
+                // It may yet be committed, or have been and only the reply was lost, so this
+                // mustn't claim the request failed
+                let error = Body::Error {
+                    in_reply_to: 0,
+                    code: TIMEOUT,
+                    text: "timed out waiting on the log".to_string(),
+                };
+                self.reply(waiting, error, &mut messages);
+            }
+            messages
+        }
+
+        /// Wraps VR's messages for the wire, and answers the clients whose requests it
+        /// has applied since.
+        fn send_vr(&mut self, outbox: Outbox<Store>, messages: &mut Vec<Message>) {
+            for (dest, message) in outbox {
+                messages.push(Message {
+                    src: self.id.clone(),
+                    dest,
+                    body: Body::Vr(message),
+                });
+            }
+            let Some(vr) = &mut self.vr else {
+                return;
+            };
+            for applied in vr.take_applied() {
+                let Some(proposed) = self.proposals.remove(&applied.index) else {
+                    continue;
+                };
+                let reply = if proposed.view == applied.view {
+                    applied.output
+                } else {
+                    // Another view's entry took its place, so it never will take effect
+                    Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "the view changed before it was committed".to_string(),
+                    }
+                };
+                self.reply(proposed.waiting, reply, messages);
+            }
+        }
+
+        fn reply(&mut self, waiting: Waiting, mut body: Body, messages: &mut Vec<Message>) {
+            if let Some(in_reply_to) = body.in_reply_to() {
+                *in_reply_to = waiting.msg_id;
+            }
+            let reply_id = self.next_msg_id();
+            if let Some(msg_id) = body.msg_id() {
+                *msg_id = reply_id;
+            }
+            messages.push(Message {
+                src: self.id.clone(),
+                dest: waiting.client,
+                body,
+            });
+        }
+
+        /// Proposes a client's request if we're the primary, or sends it on to the primary with an
+        /// id of our own, so we can tell which client its reply is for. Requests that already
+        /// came from another node aren't forwarded again, so they can't go round in circles.
+        fn request(&mut self, src: &str, mut body: Body, messages: &mut Vec<Message>) {
+            let Some(msg_id) = body.msg_id().copied() else {
+                return;
+            };
+            let waiting = Waiting {
+                client: src.to_string(),
+                msg_id,
+                deadline: Instant::now() + self.config.request_timeout,
+            };
+            let from_node = self.node_ids.iter().any(|node| node == src);
+            let Some(vr) = &mut self.vr else {
+                return;
+            };
+            let Some(op) = body.clone().op() else {
+                return;
+            };
+            let mut outbox = Vec::new();
+            match vr.propose(op, &mut outbox) {
+                Ok(proposal) => {
+                    let proposed = Proposed {
+                        view: proposal.view,
+                        waiting,
+                    };
+                    self.proposals.insert(proposal.index, proposed);
+                }
+                Err(NotPrimary(Some(primary))) if !from_node => {
+                    let forward_id = self.next_msg_id();
+                    if let Some(msg_id) = body.msg_id() {
+                        *msg_id = forward_id;
+                    }
+                    self.forwards.insert(forward_id, waiting);
+                    messages.push(Message {
+                        src: self.id.clone(),
+                        dest: primary,
+                        body,
+                    });
+                }
+                Err(_) => {
+                    let error = Body::Error {
+                        in_reply_to: 0,
+                        code: TEMPORARILY_UNAVAILABLE,
+                        text: "no primary to serve this".to_string(),
+                    };
+                    self.reply(waiting, error, messages);
+                }
+            }
+            self.send_vr(outbox, messages);
+        }
+
+        fn handle_body(
+            &mut self,
+            src: &str,
+            body: Body,
+            outbox: &mut Vec<Message>,
+        ) -> Option<Body> {
+            match body {
+                Body::Init {
+                    msg_id,
+                    node_id,
+                    node_ids,
+                } => {
+                    log::debug!(
+                        "Received init with id: {}, node_id: {}, and node_ids: {:?}",
+                        msg_id,
+                        node_id,
+                        node_ids
+                    );
+                    self.vr = Some(Vr::new(
+                        node_id.clone(),
+                        node_ids.clone(),
+                        Store::default(),
+                        self.config.vr.clone(),
+                    ));
+                    self.node_ids = node_ids;
+                    self.id = node_id;
+                    logging::set_node(&self.id);
+                    self.initialized = true;
+                    Some(Body::InitOk {
+                        msg_id: self.cur_id,
+                        in_reply_to: msg_id,
+                    })
+                }
+                Body::Read { .. } | Body::Write { .. } | Body::Cas { .. } => {
+                    self.request(src, body, outbox);
+                    None
+                }
+                Body::Vr(message) => {
+                    let mut vr_outbox = Vec::new();
+                    self.vr.as_mut()?.handle(src, message, &mut vr_outbox);
+                    self.send_vr(vr_outbox, outbox);
+                    None
+                }
+                // The primary's answer to a request we forwarded, which goes on to the client
+                mut reply => {
+                    let forward = self.forwards.remove(reply.in_reply_to()?)?;
+                    self.reply(forward, reply, outbox);
+                    None
+                }
+            }
+        }
+    }
+
+    #[cfg(test)]
+    mod tests {
+        use super::*;
+        use maelstrom::testing::{self, deliver, reply, request};
+        use serde_json::json;
+
+        /// Two nodes, n1 and n2, with n1 the primary of the first view.
+        fn cluster() -> Vec<Node> {
+            cluster_with(2, &[])
+        }
+
+        /// n1 to n`count`, with n1 the primary of the first view. The `eager` nodes move to
+        /// the next view whenever they tick as a backup.
+        fn cluster_with(count: usize, eager: &[&str]) -> Vec<Node> {
+            let nodes: Vec<Node> = testing::cluster(count, |id| {
+                let mut config = Config::default();
+                if eager.contains(&id) {
+                    config.vr.view_change_timeout = Duration::ZERO;
+                }
+                Node::new(config)
+            });
+            assert!(nodes[0].vr.as_ref().unwrap().is_primary());
+            nodes
+        }
+
+        #[test]
+        fn test_read_write_cas() {
+            let mut nodes = cluster();
+            let mut send = |body| {
+                let messages = vec![request("n1", body)];
+                reply(&deliver(&mut nodes, messages))
+            };
+            assert_eq!(
+                send(json!({"type": "read", "msg_id": 1, "key": 1}))["code"],
+                KEY_DOES_NOT_EXIST
+            );
+            send(json!({"type": "write", "msg_id": 2, "key": 1, "value": 3}));
+            assert_eq!(
+                send(json!({"type": "cas", "msg_id": 3, "key": 1, "from": 4, "to": 5}))["code"],
+                PRECONDITION_FAILED
+            );
+            assert_eq!(
+                send(json!({"type": "cas", "msg_id": 4, "key": 1, "from": 3, "to": 5}))["type"],
+                "cas_ok"
+            );
+            assert_eq!(
+                send(json!({"type": "read", "msg_id": 5, "key": 1}))["value"],
+                5
+            );
+            let created = json!({
+                "type": "cas", "msg_id": 6, "key": 2, "from": 0, "to": 1,
+                "create_if_not_exists": true
+            });
+            assert_eq!(send(created)["type"], "cas_ok");
+        }
+
+        #[test]
+        fn test_requests_are_forwarded_to_the_primary() {
+            let mut nodes = cluster();
+            let write = json!({"type": "write", "msg_id": 7, "key": "k", "value": [1]});
+            let relayed = deliver(&mut nodes, vec![request("n2", write)]);
+            assert_eq!(relayed.len(), 1);
+            assert_eq!(reply(&relayed)["type"], "write_ok");
+            assert_eq!(reply(&relayed)["in_reply_to"], 7);
+            let store = nodes[0].vr.as_ref().unwrap().state();
+            assert_eq!(store.values["\"k\""], json!([1]));
+        }
+
+        #[test]
+        fn test_the_next_view_keeps_what_the_last_one_wrote() {
+            let mut nodes = cluster_with(3, &["n3"]);
+            let write = json!({"type": "write", "msg_id": 7, "key": 1, "value": 2});
+            assert_eq!(
+                reply(&deliver(&mut nodes, vec![request("n1", write)]))["type"],
+                "write_ok"
+            );
+            let messages = nodes[2].tick();
+            deliver(&mut nodes, messages);
+            assert!(nodes[1].vr.as_ref().unwrap().is_primary());
+
+            let read = json!({"type": "read", "msg_id": 8, "key": 1});
+            let relayed = deliver(&mut nodes, vec![request("n1", read)]);
+            assert_eq!(reply(&relayed)["value"], 2);
+            assert!(!nodes[0].vr.as_ref().unwrap().is_primary());
+        }
+
+        #[test]
+        fn test_unanswered_requests_time_out() {
+            let mut nodes = cluster();
+            nodes[1].config.request_timeout = Duration::ZERO;
+            let read = json!({"type": "read", "msg_id": 7, "key": 1});
+            let forwarded = nodes[1].handle_message(request("n2", read));
+            assert_eq!(forwarded[0].dest, "n1");
+            let timed_out: Vec<Message> = nodes[1]
+                .tick()
+                .into_iter()
+                .filter(|message| message.dest == "c1")
+                .collect();
+            assert_eq!(reply(&timed_out)["code"], TIMEOUT);
+            assert_eq!(reply(&timed_out)["in_reply_to"], 7);
+        }
+
+        #[test]
+        fn test_requests_fail_during_a_view_change() {
+            let mut nodes = cluster_with(2, &["n2"]);
+            nodes[1].tick();
+            let read = json!({"type": "read", "msg_id": 7, "key": 1});
+            let failed = nodes[1].handle_message(request("n2", read));
+            assert_eq!(reply(&failed)["code"], TEMPORARILY_UNAVAILABLE);
+        }
+
+        #[test]
+        fn test_vr_messages_share_the_body() {
+            let message: Message = request(
+                "n2",
+                json!({"type": "commit", "view": 1, "commit_number": 3}),
+            );
+            assert!(matches!(message.body, Body::Vr(_)));
+        }
+    }
+}
+
+impl maelstrom::Node for node::Node {
+    type Message = node::Message;
+
+    fn handle_message(&mut self, message: node::Message) -> Vec<node::Message> {
+        node::Node::handle_message(self, message)
+    }
+
+    fn tick(&mut self) -> Vec<node::Message> {
+        node::Node::tick(self)
+    }
+
+    fn dump(&self) -> serde_json::Value {
+        node::Node::dump(self)
+    }
+}
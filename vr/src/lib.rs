@@ -0,0 +1,777 @@
+//! Viewstamped Replication over the Maelstrom message bus: a primary, fixed by the view
+//! number, orders operations into a log its backups replicate, and when it goes quiet the
+//! backups move to the next view, whose primary takes up the most up to date log a
+//! majority hands it. Every node applies the committed operations, in log order, to the
+//! same `StateMachine` Raft drives, so a host can run either underneath it.
+//!
+//! Hosts use it the way they use Raft: embed `VrMessage`s in their own message bodies,
+//! hand the ones they receive to `Vr::handle`, call `Vr::tick` regularly, and send on
+//! whatever lands in the outbox. Nothing is persisted, so there's no recovery protocol for
+//! a replica that restarts, and the log is never compacted; a replica that falls behind is
+//! sent the part of the log it's missing.
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::time::{Duration, Instant};
+
+pub use raft::StateMachine;
+
+/// Tunables for a VR replica.
+#[derive(Debug, Clone)]
+pub struct Config {
+    /// How long a backup waits to hear from the primary, or a view change to finish,
+    /// before moving to the next view, plus up to as long again at random.
+    pub view_change_timeout: Duration,
+    /// Time between a primary's prepares or commits to each backup, which keep it in
+    /// office and resend whatever a backup hasn't acknowledged.
+    pub heartbeat_interval: Duration,
+    /// Most operations sent in one prepare or new_state.
+    pub max_batch: usize,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            view_change_timeout: Duration::from_millis(500),
+            heartbeat_interval: Duration::from_millis(100),
+            max_batch: 64,
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct Entry<C> {
+    /// The view whose primary put it in the log.
+    pub view: u64,
+    pub command: C,
+}
+
+/// The messages VR replicas exchange, tagged by "type" like any Maelstrom body, carrying
+/// commands of type `C`. Operations are numbered from 1, and every run of `entries` ends
+/// at `op_number`.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq)]
+#[serde(rename_all = "snake_case")]
+#[serde(tag = "type")]
+pub enum VrMessage<C> {
+    /// Operations for a backup to append, and how far the log is committed.
+    Prepare {
+        view: u64,
+        op_number: u64,
+        entries: Vec<Entry<C>>,
+        commit_number: u64,
+    },
+    /// The backup's log is the primary's up to `op_number`.
+    PrepareOk {
+        view: u64,
+        op_number: u64,
+    },
+    /// How far the log is committed, for a backup that has everything.
+    Commit {
+        view: u64,
+        commit_number: u64,
+    },
+    StartViewChange {
+        view: u64,
+    },
+    /// A replica's log, for the new view's primary to choose from.
+    DoViewChange {
+        view: u64,
+        log: Vec<Entry<C>>,
+        last_normal_view: u64, // The last view it was in normal status in
+        commit_number: u64,
+    },
+    /// The new view's log, which every replica takes up.
+    StartView {
+        view: u64,
+        log: Vec<Entry<C>>,
+        commit_number: u64,
+    },
+    /// Asks for the operations after `op_number`, from a replica that's behind.
+    GetState {
+        view: u64,
+        op_number: u64,
+    },
+    NewState {
+        view: u64,
+        op_number: u64,
+        entries: Vec<Entry<C>>,
+        commit_number: u64,
+    },
+}
+
+/// Where a proposed command went. It took effect if the entry applied at `index` has the
+/// same `view`; if another view's entry is applied there, it never will.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Proposal {
+    pub index: u64,
+    pub view: u64,
+}
+
+/// A command's result, once it's been committed and applied.
+#[derive(Debug, Clone, PartialEq)]
+pub struct Applied<O> {
+    pub index: u64,
+    pub view: u64,
+    pub output: O,
+}
+
+/// A proposal made to a replica that isn't the primary, with who is, if it's in a view
+/// that's running.
+#[derive(Debug, Clone, PartialEq)]
+pub struct NotPrimary(pub Option<String>);
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Status {
+    Normal,
+    ViewChange,
+}
+
+/// A do_view_change's log, as the new primary weighs it.
+struct Candidate<C> {
+    log: Vec<Entry<C>>,
+    last_normal_view: u64,
+    commit_number: u64,
+}
+
+pub struct Vr<S: StateMachine> {
+    id: String,
+    nodes: Vec<String>, // Sorted, so every replica agrees on each view's primary
+    config: Config,
+    state: S,
+    view: u64,
+    status: Status,
+    last_normal_view: u64,
+    log: Vec<Entry<S::Command>>,      // Operation n is log[n - 1]
+    commit_number: u64,               // Every operation up to here is committed and applied
+    applied: Vec<Applied<S::Output>>, // Results not yet taken by the host
+    // As primary
+    acked: HashMap<String, u64>, // The last operation each backup is known to have
+    heartbeat_due: Instant,
+    // As a backup, or during a view change
+    deadline: Instant, // When we give up on the primary, or on the view change
+    start_view_changes: HashSet<String>, // Who else has started changing to `view`
+    sent_do_view_change: bool,
+    // As the next view's primary
+    do_view_changes: HashMap<String, Candidate<S::Command>>,
+}
+
+/// Messages for other replicas, with who each is for.
+pub type Outbox<S> = Vec<(String, VrMessage<<S as StateMachine>::Command>)>;
+
+impl<S: StateMachine> Vr<S> {
+    pub fn new(id: String, mut nodes: Vec<String>, state: S, config: Config) -> Self {
+        nodes.sort();
+        let now = Instant::now();
+        let mut vr = Vr {
+            id,
+            nodes,
+            config,
+            state,
+            view: 0,
+            status: Status::Normal,
+            last_normal_view: 0,
+            log: Vec::new(),
+            commit_number: 0,
+            applied: Vec::new(),
+            acked: HashMap::new(),
+            heartbeat_due: now,
+            deadline: now,
+            start_view_changes: HashSet::new(),
+            sent_do_view_change: false,
+            do_view_changes: HashMap::new(),
+        };
+        vr.reset_deadline();
+        vr
+    }
+
+    pub fn is_primary(&self) -> bool {
+        self.status == Status::Normal && self.primary_of(self.view) == self.id
+    }
+
+    /// The primary of our view, unless it's still being changed to.
+    pub fn primary(&self) -> Option<&str> {
+        (self.status == Status::Normal).then(|| self.primary_of(self.view))
+    }
+
+    pub fn nodes(&self) -> &[String] {
+        &self.nodes
+    }
+
+    pub fn view(&self) -> u64 {
+        self.view
+    }
+
+    pub fn commit_number(&self) -> u64 {
+        self.commit_number
+    }
+
+    pub fn state(&self) -> &S {
+        &self.state
+    }
+
+    /// Results of the commands applied since the last call, in log order.
+    pub fn take_applied(&mut self) -> Vec<Applied<S::Output>> {
+        std::mem::take(&mut self.applied)
+    }
+
+    /// Appends `command` to the log if we're the primary.
+    pub fn propose(
+        &mut self,
+        command: S::Command,
+        outbox: &mut Outbox<S>,
+    ) -> Result<Proposal, NotPrimary> {
+        if !self.is_primary() {
+            return Err(NotPrimary(self.primary().map(str::to_string)));
+        }
+        let entry = Entry {
+            view: self.view,
+            command,
+        };
+        self.log.push(entry.clone());
+        let prepare = VrMessage::Prepare {
+            view: self.view,
+            op_number: self.op_number(),
+            entries: vec![entry],
+            commit_number: self.commit_number,
+        };
+        for backup in self.backups() {
+            outbox.push((backup.clone(), prepare.clone()));
+        }
+        self.advance_commit();
+        Ok(Proposal {
+            index: self.op_number(),
+            view: self.view,
+        })
+    }
+
+    /// Sends heartbeats if we're the primary, or moves to the next view if the primary,
+    /// or the view change under way, has taken too long.
+    pub fn tick(&mut self, outbox: &mut Outbox<S>) {
+        let now = Instant::now();
+        if self.is_primary() {
+            if now >= self.heartbeat_due {
+                self.heartbeat(outbox);
+            }
+        } else if now >= self.deadline {
+            self.start_view_change(self.view + 1, outbox);
+        }
+    }
+
+    pub fn handle(&mut self, src: &str, message: VrMessage<S::Command>, outbox: &mut Outbox<S>) {
+        match message {
+            VrMessage::Prepare {
+                view,
+                op_number,
+                entries,
+                commit_number,
+            } => {
+                if !self.heard_from_primary(view) {
+                    return;
+                }
+                if self.append(op_number, entries) {
+                    self.commit(commit_number);
+                    self.prepare_ok(src, outbox);
+                } else {
+                    self.get_state(src, outbox);
+                }
+            }
+            VrMessage::PrepareOk { view, op_number } => {
+                if view != self.view || !self.is_primary() {
+                    return;
+                }
+                let acked = self.acked.entry(src.to_string()).or_default();
+                *acked = (*acked).max(op_number);
+                self.advance_commit();
+            }
+            VrMessage::Commit {
+                view,
+                commit_number,
+            } => {
+                if !self.heard_from_primary(view) {
+                    return;
+                }
+                if commit_number > self.op_number() {
+                    self.get_state(src, outbox);
+                }
+                self.commit(commit_number);
+            }
+            VrMessage::StartViewChange { view } => {
+                if view > self.view {
+                    self.start_view_change(view, outbox);
+                }
+                if view == self.view && self.status == Status::ViewChange {
+                    self.start_view_changes.insert(src.to_string());
+                    self.check_start_view_changes(outbox);
+                }
+            }
+            VrMessage::DoViewChange {
+                view,
+                log,
+                last_normal_view,
+                commit_number,
+            } => {
+                if view > self.view {
+                    self.start_view_change(view, outbox);
+                }
+                if view != self.view
+                    || self.status != Status::ViewChange
+                    || self.primary_of(view) != self.id
+                {
+                    return;
+                }
+                let candidate = Candidate {
+                    log,
+                    last_normal_view,
+                    commit_number,
+                };
+                self.do_view_changes.insert(src.to_string(), candidate);
+                self.check_do_view_changes(outbox);
+            }
+            VrMessage::StartView {
+                view,
+                log,
+                commit_number,
+            } => {
+                if view < self.view || (view == self.view && self.status == Status::Normal) {
+                    return;
+                }
+                log::info!("{} starting view {}", self.id, view);
+                self.enter_view(view);
+                // What wasn't committed may differ from ours, but what was is the same
+                self.log = log;
+                self.commit(commit_number);
+                if self.op_number() > self.commit_number {
+                    self.prepare_ok(src, outbox);
+                }
+            }
+            VrMessage::GetState { view, op_number } => {
+                if view != self.view || self.status != Status::Normal {
+                    return;
+                }
+                let entries: Vec<Entry<S::Command>> = self
+                    .log
+                    .iter()
+                    .skip(op_number as usize)
+                    .take(self.config.max_batch)
+                    .cloned()
+                    .collect();
+                outbox.push((
+                    src.to_string(),
+                    VrMessage::NewState {
+                        view,
+                        op_number: op_number + entries.len() as u64,
+                        entries,
+                        commit_number: self.commit_number,
+                    },
+                ));
+            }
+            VrMessage::NewState {
+                view,
+                op_number,
+                entries,
+                commit_number,
+            } => {
+                if view != self.view || self.status != Status::Normal {
+                    return;
+                }
+                if self.append(op_number, entries) {
+                    self.commit(commit_number);
+                    let primary = self.primary_of(view).to_string();
+                    self.prepare_ok(&primary, outbox);
+                }
+            }
+        }
+    }
+
+    /// How many operations are in the log.
+    pub fn op_number(&self) -> u64 {
+        self.log.len() as u64
+    }
+
+    fn majority(&self) -> usize {
+        self.nodes.len() / 2 + 1
+    }
+
+    fn primary_of(&self, view: u64) -> &str {
+        &self.nodes[(view % self.nodes.len() as u64) as usize]
+    }
+
+    fn backups(&self) -> impl Iterator<Item = &String> {
+        self.nodes.iter().filter(move |node| **node != self.id)
+    }
+
+    fn reset_deadline(&mut self) {
+        let timeout = self.config.view_change_timeout;
+        let jitter = rand::thread_rng().gen_range(0..=timeout.as_millis() as u64);
+        self.deadline = Instant::now() + timeout + Duration::from_millis(jitter);
+    }
+
+    /// Checks a message that only the primary of `view` sends, returning whether to act on
+    /// it. A message from a later view means that view started without us, so we join it,
+    /// keeping only what's committed, and ask for the rest of its log.
+    fn heard_from_primary(&mut self, view: u64) -> bool {
+        if view < self.view {
+            return false;
+        }
+        if view > self.view || self.status == Status::ViewChange {
+            log::info!("{} joining view {} late", self.id, view);
+            self.enter_view(view);
+            self.log.truncate(self.commit_number as usize);
+        }
+        self.reset_deadline();
+        true
+    }
+
+    fn enter_view(&mut self, view: u64) {
+        self.view = view;
+        self.status = Status::Normal;
+        self.last_normal_view = view;
+        self.start_view_changes.clear();
+        self.do_view_changes.clear();
+        self.sent_do_view_change = false;
+        self.acked.clear();
+        self.reset_deadline();
+    }
+
+    /// Appends the operations ending at `op_number` that we don't have yet, returning
+    /// whether we now have everything up to it. Within a view, operations we do have are
+    /// the same as the primary's, so they're left alone.
+    fn append(&mut self, op_number: u64, entries: Vec<Entry<S::Command>>) -> bool {
+        let first = op_number + 1 - entries.len() as u64;
+        if first > self.op_number() + 1 {
+            return false;
+        }
+        let have = (self.op_number() + 1 - first) as usize;
+        self.log.extend(entries.into_iter().skip(have));
+        true
+    }
+
+    fn prepare_ok(&self, primary: &str, outbox: &mut Outbox<S>) {
+        outbox.push((
+            primary.to_string(),
+            VrMessage::PrepareOk {
+                view: self.view,
+                op_number: self.op_number(),
+            },
+        ));
+    }
+
+    fn get_state(&self, src: &str, outbox: &mut Outbox<S>) {
+        outbox.push((
+            src.to_string(),
+            VrMessage::GetState {
+                view: self.view,
+                op_number: self.op_number(),
+            },
+        ));
+    }
+
+    /// Sends each backup the operations it hasn't acknowledged, up to a batch of them, or
+    /// just how far the log is committed if it has them all.
+    fn heartbeat(&mut self, outbox: &mut Outbox<S>) {
+        for backup in self.backups() {
+            let acked = self
+                .acked
+                .get(backup)
+                .copied()
+                .unwrap_or(self.commit_number);
+            let message = if acked < self.op_number() {
+                let entries: Vec<Entry<S::Command>> = self.log[acked as usize..]
+                    .iter()
+                    .take(self.config.max_batch)
+                    .cloned()
+                    .collect();
+                VrMessage::Prepare {
+                    view: self.view,
+                    op_number: acked + entries.len() as u64,
+                    entries,
+                    commit_number: self.commit_number,
+                }
+            } else {
+                VrMessage::Commit {
+                    view: self.view,
+                    commit_number: self.commit_number,
+                }
+            };
+            outbox.push((backup.clone(), message));
+        }
+        self.heartbeat_due = Instant::now() + self.config.heartbeat_interval;
+    }
+
+    /// Commits the latest operation a majority of replicas has, and everything before it.
+    fn advance_commit(&mut self) {
+        let mut ops: Vec<u64> = self
+            .backups()
+            .map(|backup| self.acked.get(backup).copied().unwrap_or(0))
+            .chain([self.op_number()])
+            .collect();
+        ops.sort_unstable_by(|a, b| b.cmp(a));
+        let committed = ops[self.majority() - 1].min(self.op_number());
+        self.commit(committed);
+    }
+
+    /// Applies everything up to `commit_number` that's in our log.
+    fn commit(&mut self, commit_number: u64) {
+        let commit_number = commit_number.min(self.op_number());
+        while self.commit_number < commit_number {
+            self.commit_number += 1;
+            let entry = &self.log[(self.commit_number - 1) as usize];
+            let output = self.state.apply(&entry.command);
+            self.applied.push(Applied {
+                index: self.commit_number,
+                view: entry.view,
+                output,
+            });
+        }
+    }
+
+    /// Stops serving `view - 1` and asks the others to move to `view`.
+    fn start_view_change(&mut self, view: u64, outbox: &mut Outbox<S>) {
+        log::info!("{} changing to view {}", self.id, view);
+        self.view = view;
+        self.status = Status::ViewChange;
+        self.start_view_changes.clear();
+        self.do_view_changes.clear();
+        self.sent_do_view_change = false;
+        self.acked.clear();
+        self.reset_deadline();
+        for node in self.backups() {
+            outbox.push((node.clone(), VrMessage::StartViewChange { view }));
+        }
+        self.check_start_view_changes(outbox);
+    }
+
+    /// Once a majority, us included, is changing views, hands our log to the new primary.
+    fn check_start_view_changes(&mut self, outbox: &mut Outbox<S>) {
+        if self.sent_do_view_change || self.start_view_changes.len() + 1 < self.majority() {
+            return;
+        }
+        self.sent_do_view_change = true;
+        let primary = self.primary_of(self.view).to_string();
+        if primary == self.id {
+            let candidate = Candidate {
+                log: self.log.clone(),
+                last_normal_view: self.last_normal_view,
+                commit_number: self.commit_number,
+            };
+            self.do_view_changes.insert(self.id.clone(), candidate);
+            self.check_do_view_changes(outbox);
+            return;
+        }
+        outbox.push((
+            primary,
+            VrMessage::DoViewChange {
+                view: self.view,
+                log: self.log.clone(),
+                last_normal_view: self.last_normal_view,
+                commit_number: self.commit_number,
+            },
+        ));
+    }
+
+    /// Once a majority, us included, has handed us its log, starts the view with the most
+    /// up to date of them: the one from the latest view that ran normally, and the longest
+    /// of those. Anything committed is in it, since a majority had it.
+    fn check_do_view_changes(&mut self, outbox: &mut Outbox<S>) {
+        if !self.do_view_changes.contains_key(&self.id)
+            || self.do_view_changes.len() < self.majority()
+        {
+            return;
+        }
+        let candidates = std::mem::take(&mut self.do_view_changes);
+        let commit_number = candidates
+            .values()
+            .map(|candidate| candidate.commit_number)
+            .max()
+            .unwrap_or(0);
+        let Some(chosen) = candidates
+            .into_values()
+            .max_by_key(|candidate| (candidate.last_normal_view, candidate.log.len()))
+        else {
+            return;
+        };
+        log::info!("{} is primary of view {}", self.id, self.view);
+        self.enter_view(self.view);
+        self.log = chosen.log;
+        self.commit(commit_number);
+        let start_view = VrMessage::StartView {
+            view: self.view,
+            log: self.log.clone(),
+            commit_number: self.commit_number,
+        };
+        for backup in self.backups() {
+            outbox.push((backup.clone(), start_view.clone()));
+        }
+        self.heartbeat_due = Instant::now() + self.config.heartbeat_interval;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Adds up every command, returning the running total.
+    #[derive(Default)]
+    struct Sum(u64);
+
+    impl StateMachine for Sum {
+        type Command = u64;
+        type Output = u64;
+        type Snapshot = u64;
+
+        fn apply(&mut self, command: &u64) -> u64 {
+            self.0 += command;
+            self.0
+        }
+
+        fn snapshot(&self) -> u64 {
+            self.0
+        }
+
+        fn restore(&mut self, snapshot: u64) {
+            self.0 = snapshot;
+        }
+    }
+
+    fn cluster() -> Vec<Vr<Sum>> {
+        let nodes: Vec<String> = vec!["n0".into(), "n1".into(), "n2".into()];
+        nodes
+            .iter()
+            .map(|id| Vr::new(id.clone(), nodes.clone(), Sum::default(), Config::default()))
+            .collect()
+    }
+
+    /// Delivers `outbox` from `src`, and everything sent in response, until the cluster's
+    /// quiet. Messages to or from nodes in `down` are dropped.
+    fn deliver(cluster: &mut [Vr<Sum>], src: usize, outbox: Outbox<Sum>, down: &[usize]) {
+        let mut in_flight: Vec<(usize, Outbox<Sum>)> = vec![(src, outbox)];
+        while let Some((src, outbox)) = in_flight.pop() {
+            for (dest, message) in outbox {
+                let dest: usize = dest[1..].parse().unwrap();
+                if down.contains(&src) || down.contains(&dest) {
+                    continue;
+                }
+                let mut replies = Vec::new();
+                cluster[dest].handle(&format!("n{}", src), message, &mut replies);
+                in_flight.push((dest, replies));
+            }
+        }
+    }
+
+    fn propose(cluster: &mut [Vr<Sum>], node: usize, command: u64, down: &[usize]) -> Proposal {
+        let mut outbox = Vec::new();
+        let proposal = cluster[node].propose(command, &mut outbox).unwrap();
+        deliver(cluster, node, outbox, down);
+        proposal
+    }
+
+    fn heartbeat(cluster: &mut [Vr<Sum>], node: usize, down: &[usize]) {
+        let mut outbox = Vec::new();
+        cluster[node].heartbeat(&mut outbox);
+        deliver(cluster, node, outbox, down);
+    }
+
+    /// Has `node` give up on view `view - 1`.
+    fn change_view(cluster: &mut [Vr<Sum>], node: usize, view: u64, down: &[usize]) {
+        let mut outbox = Vec::new();
+        cluster[node].start_view_change(view, &mut outbox);
+        deliver(cluster, node, outbox, down);
+    }
+
+    #[test]
+    fn test_operations_apply_everywhere_in_order() {
+        let mut cluster = cluster();
+        assert!(cluster[0].is_primary());
+        propose(&mut cluster, 0, 2, &[]);
+        let proposal = propose(&mut cluster, 0, 3, &[]);
+        // Backups hear of the last commit with the next heartbeat
+        heartbeat(&mut cluster, 0, &[]);
+        for vr in &mut cluster {
+            let applied = vr.take_applied();
+            let outputs: Vec<u64> = applied.iter().map(|a| a.output).collect();
+            assert_eq!(outputs, [2, 5]);
+            assert_eq!((applied[1].index, applied[1].view), (proposal.index, 0));
+        }
+        assert_eq!(
+            cluster[1].propose(1, &mut Vec::new()),
+            Err(NotPrimary(Some("n0".into())))
+        );
+    }
+
+    #[test]
+    fn test_a_view_change_keeps_what_was_committed() {
+        let mut cluster = cluster();
+        let kept = propose(&mut cluster, 0, 4, &[2]);
+        // n0 is cut off, so this never reaches a majority
+        let lost = propose(&mut cluster, 0, 7, &[1, 2]);
+        assert_eq!(cluster[0].commit_number(), kept.index);
+
+        change_view(&mut cluster, 2, 1, &[0]);
+        assert!(cluster[1].is_primary());
+        assert_eq!(cluster[2].primary(), Some("n1"));
+        let replaced = propose(&mut cluster, 1, 1, &[0]);
+        assert_eq!(replaced.index, lost.index);
+
+        // n0 comes back, hears from the new primary and takes up its log
+        heartbeat(&mut cluster, 1, &[]);
+        assert!(!cluster[0].is_primary());
+        assert_eq!(cluster[0].view(), 1);
+        let applied = cluster[0].take_applied();
+        let outputs: Vec<(u64, u64)> = applied.iter().map(|a| (a.view, a.output)).collect();
+        assert_eq!(outputs, [(0, 4), (1, 5)]);
+    }
+
+    #[test]
+    fn test_lagging_backups_catch_up_by_state_transfer() {
+        let mut cluster = cluster();
+        for command in 1..=3 {
+            propose(&mut cluster, 0, command, &[2]);
+        }
+        assert_eq!(cluster[2].commit_number(), 0);
+        // n2 can't append this without what it missed, so it asks for it
+        propose(&mut cluster, 0, 4, &[]);
+        assert_eq!(cluster[2].state().0, 6);
+        heartbeat(&mut cluster, 0, &[]);
+        assert_eq!(cluster[2].state().0, 10);
+        assert_eq!(cluster[2].commit_number(), 4);
+    }
+
+    #[test]
+    fn test_view_changes_need_a_majority() {
+        let mut cluster = cluster();
+        change_view(&mut cluster, 2, 1, &[0, 1]);
+        assert!(!cluster[1].is_primary());
+        assert_eq!(cluster[2].primary(), None);
+        assert!(cluster[2].propose(1, &mut Vec::new()).is_err());
+        // n0 carries on in view 0 until n2 can reach the others, and drags them along
+        assert!(cluster[0].is_primary());
+        change_view(&mut cluster, 2, 2, &[]);
+        assert!(cluster[2].is_primary());
+        assert_eq!(cluster[0].primary(), Some("n2"));
+        assert_eq!(cluster[1].view(), 2);
+    }
+
+    #[test]
+    fn test_message_format() {
+        let message: VrMessage<u64> = VrMessage::Prepare {
+            view: 1,
+            op_number: 3,
+            entries: vec![Entry {
+                view: 1,
+                command: 5,
+            }],
+            commit_number: 2,
+        };
+        let json = serde_json::to_value(&message).unwrap();
+        assert_eq!(json["type"], "prepare");
+        assert_eq!(
+            json["entries"],
+            serde_json::json!([{"view": 1, "command": 5}])
+        );
+        assert_eq!(
+            serde_json::from_value::<VrMessage<u64>>(json).unwrap(),
+            message
+        );
+    }
+}